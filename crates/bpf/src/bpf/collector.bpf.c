@@ -28,6 +28,9 @@ struct {
     __uint(value_size, sizeof(u32));
 } events SEC(".maps");
 
+// Every program outputs to events with BPF_F_CURRENT_CPU
+const volatile __u32 ring_index_semantics = RING_INDEX_CURRENT_CPU;
+
 // Timer firing state tracking
 enum timer_fire_state {
     TIMER_RESET = 0,
@@ -166,7 +169,8 @@ static __always_inline __u64 compute_delta(__u64 current, __u64 previous) {
 static __always_inline int send_perf_measurement(void *ctx, __u32 pid, __u64 cycles_delta, 
                                                __u64 instructions_delta, __u64 llc_misses_delta,
                                                __u64 cache_references_delta, __u64 time_delta_ns, __u64 timestamp,
-                                               __u32 is_context_switch, __u32 next_tgid)
+                                               __u32 is_context_switch, __u32 next_tgid,
+                                               __u32 prev_tgid, __u32 switch_reason)
 {
     struct perf_measurement_msg msg = {};
     
@@ -181,6 +185,8 @@ static __always_inline int send_perf_measurement(void *ctx, __u32 pid, __u64 cyc
     msg.time_delta_ns = time_delta_ns;
     msg.is_context_switch = is_context_switch;
     msg.next_tgid = next_tgid;
+    msg.prev_tgid = prev_tgid;
+    msg.switch_reason = switch_reason;
     
     // Skip the size field (first 4 bytes) when sending
     return bpf_perf_event_output(ctx, &events, BPF_F_CURRENT_CPU, 
@@ -239,7 +245,8 @@ static __always_inline int check_and_send_metadata(void *ctx, struct task_struct
 }
 
 // Collect and report performance measurements
-static __always_inline int collect_and_send_perf_measurements(void *ctx, struct task_struct *task, __u32 is_context_switch, __u32 next_tgid)
+static __always_inline int collect_and_send_perf_measurements(void *ctx, struct task_struct *task, __u32 is_context_switch, __u32 next_tgid,
+                                                              __u32 prev_tgid, __u32 switch_reason)
 {
     // Skip if null task
     if (!task)
@@ -297,7 +304,7 @@ static __always_inline int collect_and_send_perf_measurements(void *ctx, struct
         time_delta_ns = compute_delta(now, prev->timestamp);
         send_perf_measurement(ctx, pid, cycles_delta, instructions_delta, 
                               llc_misses_delta, cache_references_delta, time_delta_ns, now,
-                              is_context_switch, next_tgid);
+                              is_context_switch, next_tgid, prev_tgid, switch_reason);
     }
     prev->timestamp = now;
     
@@ -307,6 +314,7 @@ static __always_inline int collect_and_send_perf_measurements(void *ctx, struct
 SEC("tp_btf/sched_switch")
 int handle_sched_switch(u64 *ctx)
 {
+    bool preempt = (bool)ctx[0];
     struct task_struct *prev = (struct task_struct *)ctx[1];
     struct task_struct *next = (struct task_struct *)ctx[2];
     
@@ -318,12 +326,19 @@ int handle_sched_switch(u64 *ctx)
     if (next) {
         next_tgid = next->tgid;
     }
+
+    // Get previous task TGID and why it was switched out
+    __u32 prev_tgid = 0;
+    if (prev) {
+        prev_tgid = prev->tgid;
+    }
+    __u32 switch_reason = preempt ? SWITCH_REASON_PREEMPT : SWITCH_REASON_VOLUNTARY;
     
     // Check and send metadata if needed
     check_and_send_metadata(ctx, current_task);
     
     // Collect and send performance measurements (context switch event)
-    collect_and_send_perf_measurements(ctx, current_task, 1, next_tgid);
+    collect_and_send_perf_measurements(ctx, current_task, 1, next_tgid, prev_tgid, switch_reason);
     
     return 0;
 }
@@ -428,7 +443,7 @@ int handle_hrtimer_expire_exit(void *ctx)
     check_and_send_metadata(ctx, current_task);
 
     // Collect and send performance measurements before sending timer finished message (timer event)
-    collect_and_send_perf_measurements(ctx, current_task, 0, 0);
+    collect_and_send_perf_measurements(ctx, current_task, 0, 0, 0, 0);
     
     // Send the timer processing finished message
     send_timer_finished_processing(ctx);
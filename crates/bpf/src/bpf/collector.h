@@ -13,6 +13,21 @@ enum msg_type {
     MSG_TYPE_TIMER_MIGRATION_DETECTED = 5,
 };
 
+// Why a task was switched out, from the sched_switch preempt flag
+enum switch_reason {
+    SWITCH_REASON_VOLUNTARY = 0, // The task blocked or yielded
+    SWITCH_REASON_PREEMPT = 1,   // The task was preempted while runnable
+};
+
+// How programs pick the ring of the events map they output to, declared in
+// the ring_index_semantics read-only global and checked by the loader.
+// Values match RingIndexSemantics::code in perf_events.
+enum ring_index_semantics {
+    RING_INDEX_CURRENT_CPU = 0, // BPF_F_CURRENT_CPU
+    RING_INDEX_EXPLICIT = 1,    // An explicit CPU, possibly not the current one
+    RING_INDEX_CUSTOM = 2,      // Indices with a program-defined meaning
+};
+
 // Sample header structure that matches the one in reader.rs
 struct sample_header {
     __u32 size;      // Size field (filled by kernel)
@@ -51,6 +66,8 @@ struct perf_measurement_msg {
     __u64 time_delta_ns;         // Time delta in nanoseconds
     __u32 is_context_switch;     // 1 if context switch event, 0 if timer event
     __u32 next_tgid;             // Thread group ID of the process being context switched in. Only valid when is_context_switch == 1
+    __u32 prev_tgid;             // Thread group ID of the process being context switched out. Only valid when is_context_switch == 1
+    __u32 switch_reason;         // enum switch_reason. Only valid when is_context_switch == 1
 };
 
 // Structure for timer migration detection messages
@@ -16,6 +16,15 @@ enum sync_timer_mode {
 // Dummy instance to make skeleton generation work
 enum sync_timer_mode sync_timer_mode_ = 0;
 
+/* Whether initialization replaces or restarts the timer of a CPU */
+enum sync_timer_init_kind {
+    SYNC_TIMER_INIT_FRESH = 0,   // Delete any existing state and start over
+    SYNC_TIMER_INIT_RESTART = 1, // Cancel and re-arm an existing timer, e.g. after suspend
+};
+
+// Dummy instance to make skeleton generation work
+enum sync_timer_init_kind sync_timer_init_kind_ = 0;
+
 /* Error codes for sync timer initialization */
 enum sync_timer_init_error {
     SYNC_TIMER_SUCCESS = 0,
@@ -102,11 +111,50 @@ static __always_inline int __sync_timer_shared_callback(
     return 0;
 }
 
+/* Helper function to compute timer flags for an initialization mode */
+static __always_inline __u64 __sync_timer_mode_flags(__u8 init_mode) {
+    switch (init_mode) {
+        case SYNC_TIMER_MODE_MODERN:
+            return BPF_F_TIMER_ABS | BPF_F_TIMER_CPU_PIN;
+        case SYNC_TIMER_MODE_INTERMEDIATE:
+            return BPF_F_TIMER_ABS;
+        case SYNC_TIMER_MODE_LEGACY:
+        default:
+            return 0;  // No flags for legacy mode
+    }
+}
+
+/* Re-arm the existing timer of this CPU from the current time.
+ * The timer stays initialized, so this can run any number of times. */
+static __always_inline int __sync_timer_restart(struct sync_timer_state *state, __u8 init_mode) {
+    __u64 timer_flags = __sync_timer_mode_flags(init_mode);
+    __u64 now;
+    int ret;
+
+    /* Cancelling a timer that is not armed is a no-op */
+    bpf_timer_cancel(&state->timer);
+
+    now = bpf_ktime_get_ns();
+    state->timer_flags = timer_flags;
+    state->init_mode = init_mode;
+    state->last_tick = now / NSEC_PER_MSEC;
+    state->next_expected = __sync_timer_align_to_interval(now + NSEC_PER_MSEC, NSEC_PER_MSEC);
+
+    __u64 start_param = __sync_timer_compute_start_param(state->next_expected, timer_flags);
+    ret = bpf_timer_start(&state->timer, start_param, timer_flags);
+    if (ret < 0) {
+        return SYNC_TIMER_TIMER_START_FAILED;
+    }
+
+    return SYNC_TIMER_SUCCESS;
+}
+
 /* Shared timer initialization implementation */
 static __always_inline int __sync_timer_shared_init(
     void *timer_states_map,
     int (*timer_callback)(void *, int *, struct sync_timer_state *),
-    __u8 init_mode
+    __u8 init_mode,
+    __u8 init_kind
 ) {
     __u32 cpu = bpf_get_smp_processor_id();
     struct sync_timer_state *state;
@@ -114,22 +162,18 @@ static __always_inline int __sync_timer_shared_init(
     int ret;
 
     /* Pre-compute timer flags based on mode */
-    __u64 timer_flags;
-    switch (init_mode) {
-        case SYNC_TIMER_MODE_MODERN:
-            timer_flags = BPF_F_TIMER_ABS | BPF_F_TIMER_CPU_PIN;
-            break;
-        case SYNC_TIMER_MODE_INTERMEDIATE:
-            timer_flags = BPF_F_TIMER_ABS;
-            break;
-        case SYNC_TIMER_MODE_LEGACY:
-        default:
-            timer_flags = 0;  // No flags for legacy mode
-            break;
+    __u64 timer_flags = __sync_timer_mode_flags(init_mode);
+
+    state = bpf_map_lookup_elem(timer_states_map, &cpu);
+
+    /* Restart the existing timer in place. Without one, or if its timer was
+     * never initialized, fall through to a fresh initialization */
+    if (state && init_kind == SYNC_TIMER_INIT_RESTART &&
+        __sync_timer_restart(state, init_mode) == SYNC_TIMER_SUCCESS) {
+        return SYNC_TIMER_SUCCESS;
     }
 
     /* Check if timer state already exists for this CPU and remove it to start fresh */
-    state = bpf_map_lookup_elem(timer_states_map, &cpu);
     if (state) {
         /* Cancel any existing timer before removing the state */
         bpf_timer_cancel(&state->timer);
@@ -198,11 +242,13 @@ static int sync_timer_callback_##timer_name(void *map, int *key, struct sync_tim
 SEC("syscall") \
 int sync_timer_init_##timer_name(struct bpf_sock_addr *ctx) \
 { \
-    /* Extract mode from context_in if available, default to modern mode */ \
+    /* Extract mode and kind from context_in if available, default to a fresh modern init */ \
     __u8 init_mode = SYNC_TIMER_MODE_MODERN; \
+    __u8 init_kind = SYNC_TIMER_INIT_FRESH; \
     if (ctx && ctx->user_family == AF_INET) { \
-        /* Use the first byte of user_ip4 as the mode parameter */ \
+        /* Use the first byte of user_ip4 as the mode parameter, the second as the kind */ \
         init_mode = (__u8)(ctx->user_ip4 & 0xFF); \
+        init_kind = (__u8)((ctx->user_ip4 >> 8) & 0xFF); \
     } \
-    return __sync_timer_shared_init(&sync_timer_states_##timer_name, sync_timer_callback_##timer_name, init_mode); \
+    return __sync_timer_shared_init(&sync_timer_states_##timer_name, sync_timer_callback_##timer_name, init_mode, init_kind); \
 } 
\ No newline at end of file
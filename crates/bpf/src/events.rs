@@ -0,0 +1,321 @@
+//! Typed decoding of the messages the BPF programs write to the perf rings.
+//!
+//! [`CollectorEvent::decode`] maps a message type and payload to the matching
+//! message struct, checking that the payload is large enough. Messages are
+//! borrowed from the payload when it is suitably aligned and copied otherwise.
+//! [`subscribe_events`] hands decoded events to a subscriber's method.
+//! [`sample_layout`] describes the message structs for the startup layout check.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::mem::{offset_of, size_of};
+use std::rc::Rc;
+
+use log::error;
+use perf_events::{Dispatcher, SampleLayout};
+use thiserror::Error;
+
+use crate::bpf::types::sample_header;
+use crate::{
+    msg_type, PerfMeasurementMsg, TaskFreeMsg, TaskMetadataMsg, TimerFinishedProcessingMsg,
+    TimerMigrationMsg,
+};
+
+/// Every message type the BPF programs emit
+pub const MSG_TYPES: [msg_type; 5] = [
+    msg_type::MSG_TYPE_TASK_METADATA,
+    msg_type::MSG_TYPE_TASK_FREE,
+    msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING,
+    msg_type::MSG_TYPE_PERF_MEASUREMENT,
+    msg_type::MSG_TYPE_TIMER_MIGRATION_DETECTED,
+];
+
+/// Sizes of the message structs in collector.h
+pub const TASK_METADATA_MSG_SIZE: usize = 48;
+pub const TASK_FREE_MSG_SIZE: usize = 24;
+pub const TIMER_FINISHED_PROCESSING_MSG_SIZE: usize = 16;
+pub const PERF_MEASUREMENT_MSG_SIZE: usize = 80;
+pub const TIMER_MIGRATION_MSG_SIZE: usize = 24;
+
+/// Errors from decoding a ring message
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("unknown message type {0}")]
+    UnknownType(u32),
+
+    #[error("truncated message of type {msg_type}: {actual} bytes, expected {expected}")]
+    Truncated {
+        msg_type: u32,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// A decoded ring message
+#[derive(Debug, Clone)]
+pub enum CollectorEvent<'a> {
+    TaskMetadata(Cow<'a, TaskMetadataMsg>),
+    TaskFree(Cow<'a, TaskFreeMsg>),
+    PerfMeasurement(Cow<'a, PerfMeasurementMsg>),
+    TimerFinished(Cow<'a, TimerFinishedProcessingMsg>),
+    TimerMigration(Cow<'a, TimerMigrationMsg>),
+}
+
+impl<'a> CollectorEvent<'a> {
+    /// Decode a message of type `msg_type` from `payload`
+    pub fn decode(msg_type: u32, payload: &'a [u8]) -> Result<Self, DecodeError> {
+        let kind = MSG_TYPES
+            .into_iter()
+            .find(|kind| *kind as u32 == msg_type)
+            .ok_or(DecodeError::UnknownType(msg_type))?;
+
+        // Exhaustive, so a message type added to collector.h fails to compile
+        // until it is decoded here
+        Ok(match kind {
+            msg_type::MSG_TYPE_TASK_METADATA => Self::TaskMetadata(decode_msg(msg_type, payload)?),
+            msg_type::MSG_TYPE_TASK_FREE => Self::TaskFree(decode_msg(msg_type, payload)?),
+            msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING => {
+                Self::TimerFinished(decode_msg(msg_type, payload)?)
+            }
+            msg_type::MSG_TYPE_PERF_MEASUREMENT => {
+                Self::PerfMeasurement(decode_msg(msg_type, payload)?)
+            }
+            msg_type::MSG_TYPE_TIMER_MIGRATION_DETECTED => {
+                Self::TimerMigration(decode_msg(msg_type, payload)?)
+            }
+        })
+    }
+
+    /// The message type the event was decoded from
+    pub fn msg_type(&self) -> msg_type {
+        match self {
+            Self::TaskMetadata(_) => msg_type::MSG_TYPE_TASK_METADATA,
+            Self::TaskFree(_) => msg_type::MSG_TYPE_TASK_FREE,
+            Self::PerfMeasurement(_) => msg_type::MSG_TYPE_PERF_MEASUREMENT,
+            Self::TimerFinished(_) => msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING,
+            Self::TimerMigration(_) => msg_type::MSG_TYPE_TIMER_MIGRATION_DETECTED,
+        }
+    }
+}
+
+/// Layout of the messages the BPF programs write, checked by
+/// `Dispatcher::with_layout` before any are read
+pub fn sample_layout() -> SampleLayout {
+    let timestamp = offset_of!(sample_header, timestamp);
+    SampleLayout::default()
+        .with_message::<TaskMetadataMsg>(
+            "task_metadata_msg",
+            offset_of!(TaskMetadataMsg, header) + timestamp,
+            TASK_METADATA_MSG_SIZE,
+        )
+        .with_message::<TaskFreeMsg>(
+            "task_free_msg",
+            offset_of!(TaskFreeMsg, header) + timestamp,
+            TASK_FREE_MSG_SIZE,
+        )
+        .with_message::<TimerFinishedProcessingMsg>(
+            "timer_finished_processing_msg",
+            offset_of!(TimerFinishedProcessingMsg, header) + timestamp,
+            TIMER_FINISHED_PROCESSING_MSG_SIZE,
+        )
+        .with_message::<PerfMeasurementMsg>(
+            "perf_measurement_msg",
+            offset_of!(PerfMeasurementMsg, header) + timestamp,
+            PERF_MEASUREMENT_MSG_SIZE,
+        )
+        .with_message::<TimerMigrationMsg>(
+            "timer_migration_msg",
+            offset_of!(TimerMigrationMsg, header) + timestamp,
+            TIMER_MIGRATION_MSG_SIZE,
+        )
+}
+
+/// Subscribe `method` of `instance` to decoded events of type `msg_type`.
+/// Messages that fail to decode are logged and dropped.
+pub fn subscribe_events<T: 'static>(
+    dispatcher: &mut Dispatcher,
+    msg_type: msg_type,
+    instance: Rc<RefCell<T>>,
+    method: fn(&mut T, usize, CollectorEvent<'_>),
+) {
+    let msg_type = msg_type as u32;
+    dispatcher.subscribe(
+        msg_type,
+        move |ring_index, data| match CollectorEvent::decode(msg_type, data) {
+            Ok(event) => method(&mut instance.borrow_mut(), ring_index, event),
+            Err(e) => error!("Failed to decode event: {}", e),
+        },
+    );
+}
+
+/// Borrow a `T` from the start of `payload`, or copy it if `payload` is misaligned
+fn decode_msg<T: plain::Plain + Clone + Default>(
+    msg_type: u32,
+    payload: &[u8],
+) -> Result<Cow<'_, T>, DecodeError> {
+    if payload.len() < size_of::<T>() {
+        return Err(DecodeError::Truncated {
+            msg_type,
+            expected: size_of::<T>(),
+            actual: payload.len(),
+        });
+    }
+    match plain::from_bytes::<T>(payload) {
+        Ok(msg) => Ok(Cow::Borrowed(msg)),
+        Err(_) => {
+            let mut msg = T::default();
+            plain::copy_from_bytes(&mut msg, payload).map_err(|_| DecodeError::Truncated {
+                msg_type,
+                expected: size_of::<T>(),
+                actual: payload.len(),
+            })?;
+            Ok(Cow::Owned(msg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An 8-byte aligned buffer to lay out messages in
+    #[repr(C, align(8))]
+    struct Buffer([u8; 256]);
+
+    /// Copy `msg` into a buffer `offset` bytes past an 8-byte boundary
+    fn payload<T: plain::Plain>(msg: &T, offset: usize) -> (Buffer, usize) {
+        let bytes = unsafe { plain::as_bytes(msg) };
+        let mut buf = Buffer([0; 256]);
+        buf.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+        (buf, bytes.len())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut comm = [0; 16];
+        comm[..4].copy_from_slice(b"bash");
+        let metadata = TaskMetadataMsg {
+            pid: 42,
+            comm,
+            cgroup_id: 1234,
+            ..Default::default()
+        };
+        let free = TaskFreeMsg {
+            pid: 42,
+            ..Default::default()
+        };
+        let perf = PerfMeasurementMsg {
+            pid: 7,
+            cycles_delta: 1_000_000,
+            llc_misses_delta: 500,
+            ..Default::default()
+        };
+        let mut timer = TimerFinishedProcessingMsg::default();
+        timer.header.timestamp = 99;
+        let migration = TimerMigrationMsg {
+            expected_cpu: 1,
+            actual_cpu: 3,
+            ..Default::default()
+        };
+
+        // Aligned payloads are borrowed, misaligned ones copied
+        for (offset, borrowed) in [(0, true), (1, false)] {
+            let (buf, len) = payload(&metadata, offset);
+            let event = CollectorEvent::decode(1, &buf.0[offset..offset + len]).unwrap();
+            assert_eq!(event.msg_type(), msg_type::MSG_TYPE_TASK_METADATA);
+            match event {
+                CollectorEvent::TaskMetadata(msg) => {
+                    assert_eq!(matches!(msg, Cow::Borrowed(_)), borrowed);
+                    assert_eq!(msg.pid, 42);
+                    assert_eq!(&msg.comm[..4], b"bash");
+                    assert_eq!(msg.cgroup_id, 1234);
+                }
+                other => panic!("unexpected event {:?}", other),
+            }
+
+            let (buf, len) = payload(&free, offset);
+            match CollectorEvent::decode(2, &buf.0[offset..offset + len]).unwrap() {
+                CollectorEvent::TaskFree(msg) => assert_eq!(msg.pid, 42),
+                other => panic!("unexpected event {:?}", other),
+            }
+
+            let (buf, len) = payload(&timer, offset);
+            match CollectorEvent::decode(3, &buf.0[offset..offset + len]).unwrap() {
+                CollectorEvent::TimerFinished(msg) => assert_eq!(msg.header.timestamp, 99),
+                other => panic!("unexpected event {:?}", other),
+            }
+
+            let (buf, len) = payload(&perf, offset);
+            match CollectorEvent::decode(4, &buf.0[offset..offset + len]).unwrap() {
+                CollectorEvent::PerfMeasurement(msg) => {
+                    assert_eq!(msg.pid, 7);
+                    assert_eq!(msg.cycles_delta, 1_000_000);
+                    assert_eq!(msg.llc_misses_delta, 500);
+                }
+                other => panic!("unexpected event {:?}", other),
+            }
+
+            let (buf, len) = payload(&migration, offset);
+            match CollectorEvent::decode(5, &buf.0[offset..offset + len]).unwrap() {
+                CollectorEvent::TimerMigration(msg) => {
+                    assert_eq!((msg.expected_cpu, msg.actual_cpu), (1, 3));
+                }
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+
+        // Every message type maps back to itself
+        for kind in MSG_TYPES {
+            let buf = Buffer([0; 256]);
+            let event = CollectorEvent::decode(kind as u32, &buf.0).unwrap();
+            assert_eq!(event.msg_type(), kind);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let buf = Buffer([0; 256]);
+        assert_eq!(
+            CollectorEvent::decode(0, &buf.0).unwrap_err(),
+            DecodeError::UnknownType(0)
+        );
+        assert_eq!(
+            CollectorEvent::decode(77, &buf.0).unwrap_err(),
+            DecodeError::UnknownType(77)
+        );
+
+        let expected = size_of::<PerfMeasurementMsg>();
+        for offset in [0, 1] {
+            assert_eq!(
+                CollectorEvent::decode(4, &buf.0[offset..offset + expected - 1]).unwrap_err(),
+                DecodeError::Truncated {
+                    msg_type: 4,
+                    expected,
+                    actual: expected - 1,
+                }
+            );
+        }
+        assert!(matches!(
+            CollectorEvent::decode(1, &[]),
+            Err(DecodeError::Truncated { actual: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_sample_layout() {
+        assert_eq!(sample_layout().validate(), Ok(()));
+
+        // A message struct that changed in collector.h fails the check
+        let mut layout = sample_layout();
+        layout.messages[3].expected_size = PERF_MEASUREMENT_MSG_SIZE - 8;
+        assert_eq!(
+            layout.validate(),
+            Err(perf_events::LayoutError::MessageSize {
+                name: "perf_measurement_msg",
+                expected: PERF_MEASUREMENT_MSG_SIZE - 8,
+                actual: PERF_MEASUREMENT_MSG_SIZE,
+            })
+        );
+        assert!(Dispatcher::with_layout(&layout).is_err());
+    }
+}
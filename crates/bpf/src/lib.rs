@@ -1,10 +1,19 @@
 use anyhow::{anyhow, Context, Result};
+use collector_errors::{Coded, ErrorCode};
 use libbpf_rs::skel::{OpenSkel, Skel, SkelBuilder};
-use libbpf_rs::{set_print, OpenObject, PrintLevel};
-use perf_events::{Dispatcher, HardwareCounter, PerfMapReader};
+use libbpf_rs::{set_print, MapCore, OpenObject, PrintLevel};
+use perf_events::{
+    validate_perf_event_array, BatchSummary, CpuSetup, Dispatcher, HardwareCounter, PerfMapReader,
+    RingIndexSemantics, RingState, RingStats, Stats,
+};
+use std::ffi::OsStr;
+use std::fmt;
 use std::mem::MaybeUninit;
+use std::str::FromStr;
+use std::sync::Mutex;
 use std::time::Duration;
 
+pub mod events;
 pub mod sync_timer;
 
 // Include the generated skeletons
@@ -25,6 +34,14 @@ pub use bpf::types::{
     timer_migration_msg as TimerMigrationMsg,
 };
 
+/// Values of `PerfMeasurementMsg::switch_reason`, mirroring `enum switch_reason` in collector.h
+pub mod switch_reason {
+    /// The task blocked or yielded
+    pub const SWITCH_REASON_VOLUNTARY: u32 = 0;
+    /// The task was preempted while runnable
+    pub const SWITCH_REASON_PREEMPT: u32 = 1;
+}
+
 // Implement Plain for message types
 unsafe impl plain::Plain for TaskMetadataMsg {}
 unsafe impl plain::Plain for TaskFreeMsg {}
@@ -32,19 +49,218 @@ unsafe impl plain::Plain for TimerFinishedProcessingMsg {}
 unsafe impl plain::Plain for PerfMeasurementMsg {}
 unsafe impl plain::Plain for TimerMigrationMsg {}
 
-// Re-export important sync timer types
+/// Log the per-CPU ring setup as a table of CPU ranges sharing an outcome
+fn log_cpu_setup(setup: &[CpuSetup]) {
+    if setup.iter().all(|cpu| *cpu == CpuSetup::Ready) {
+        log::info!("Perf rings ready on all {} CPUs", setup.len());
+        return;
+    }
+
+    log::warn!("Perf rings set up on a subset of CPUs:");
+    let mut start = 0;
+    while start < setup.len() {
+        let end = start
+            + setup[start..]
+                .iter()
+                .take_while(|cpu| **cpu == setup[start])
+                .count();
+        let cpus = if end - start == 1 {
+            format!("{}", start)
+        } else {
+            format!("{}-{}", start, end - 1)
+        };
+        log::warn!("  CPU {:>9}: {}", cpus, setup[start]);
+        start = end;
+    }
+}
+
+// Re-export the event decoding and important sync timer types
+pub use events::{sample_layout, subscribe_events, CollectorEvent, DecodeError, MSG_TYPES};
 pub use sync_timer::SyncTimerError;
 
+/// Programs that attach to kernel events, in the order `attach()` attaches them
+pub const ATTACHABLE_PROGRAMS: [&str; 4] = [
+    "handle_sched_switch",
+    "handle_process_exit",
+    "handle_process_free",
+    "handle_hrtimer_expire_exit",
+];
+
+/// Perf event array maps the programs write to: the events output and the
+/// hardware counters, each with a u32 key and value per possible CPU
+pub const PERF_EVENT_ARRAY_MAPS: [&str; 5] = [
+    "events",
+    "cycles",
+    "instructions",
+    "llc_misses",
+    "cache_references",
+];
+
+/// Groups of BPF programs that can be enabled independently
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProgramGroup {
+    /// Hardware performance counters read by the measurement programs
+    Counters,
+    /// Context switch tracking
+    Sched,
+    /// Process exit and free tracking, so task metadata can be released
+    TaskLifecycle,
+    /// The synchronized per-CPU timer that closes each timeslot
+    SyncTimer,
+}
+
+impl ProgramGroup {
+    /// All groups, in the order they are listed in messages
+    pub const ALL: [ProgramGroup; 4] = [
+        ProgramGroup::Counters,
+        ProgramGroup::Sched,
+        ProgramGroup::TaskLifecycle,
+        ProgramGroup::SyncTimer,
+    ];
+
+    /// Name of the group on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            ProgramGroup::Counters => "counters",
+            ProgramGroup::Sched => "sched",
+            ProgramGroup::TaskLifecycle => "task_lifecycle",
+            ProgramGroup::SyncTimer => "sync_timer",
+        }
+    }
+
+    /// BPF programs in the group. Counters are perf event maps read by the
+    /// other groups' programs, so that group has no programs of its own.
+    pub fn programs(self) -> &'static [&'static str] {
+        match self {
+            ProgramGroup::Counters => &[],
+            ProgramGroup::Sched => &["handle_sched_switch"],
+            ProgramGroup::TaskLifecycle => &["handle_process_exit", "handle_process_free"],
+            ProgramGroup::SyncTimer => &["handle_hrtimer_expire_exit", "sync_timer_init_collect"],
+        }
+    }
+}
+
+impl fmt::Display for ProgramGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ProgramGroup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ProgramGroup::ALL
+            .into_iter()
+            .find(|group| group.name() == s)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown BPF program group {}, expected one of {}",
+                    s,
+                    ProgramGroup::ALL.map(ProgramGroup::name).join(", ")
+                )
+            })
+    }
+}
+
+/// Configuration for [`BpfLoader`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BpfLoaderConfig {
+    /// Program groups to load. Programs of other groups are neither loaded
+    /// nor attached, and their messages are never produced.
+    pub enabled_groups: Vec<ProgramGroup>,
+    /// CPUs to start the sync timer on, read from
+    /// `/sys/devices/system/cpu/online` when None
+    pub online_cpus: Option<Vec<usize>>,
+    /// Keep libbpf's output while loading, including the verifier log, out
+    /// of the console. If loading fails, the output is attached to the error
+    /// as a [`VerifierLog`] context.
+    pub capture_verifier_log: bool,
+    /// How the programs are expected to pick the ring they output to.
+    /// Loading fails if the BPF object declares otherwise.
+    pub ring_index_semantics: RingIndexSemantics,
+}
+
+impl Default for BpfLoaderConfig {
+    fn default() -> Self {
+        Self {
+            enabled_groups: ProgramGroup::ALL.to_vec(),
+            online_cpus: None,
+            capture_verifier_log: false,
+            ring_index_semantics: RingIndexSemantics::default(),
+        }
+    }
+}
+
+impl BpfLoaderConfig {
+    /// Whether the group's programs are loaded
+    pub fn is_enabled(&self, group: ProgramGroup) -> bool {
+        self.enabled_groups.contains(&group)
+    }
+
+    /// The groups of `required` that are not enabled
+    pub fn missing_groups(&self, required: &[ProgramGroup]) -> Vec<ProgramGroup> {
+        required
+            .iter()
+            .copied()
+            .filter(|group| !self.is_enabled(*group))
+            .collect()
+    }
+
+    /// Programs of the disabled groups
+    fn disabled_programs(&self) -> impl Iterator<Item = &'static str> + '_ {
+        ProgramGroup::ALL
+            .into_iter()
+            .filter(|group| !self.is_enabled(*group))
+            .flat_map(ProgramGroup::programs)
+            .copied()
+    }
+}
+
+/// libbpf output captured while loading with
+/// [`BpfLoaderConfig::capture_verifier_log`] set
+static CAPTURED_OUTPUT: Mutex<String> = Mutex::new(String::new());
+
+/// libbpf output, including the verifier log, of a failed load
+///
+/// Attached as context to the load error, so callers can find it with
+/// `downcast_ref` and store it. It displays as a summary only.
+#[derive(Debug)]
+pub struct VerifierLog(String);
+
+impl VerifierLog {
+    /// The captured output
+    pub fn text(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for VerifierLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BPF load failed, {} lines of libbpf output captured",
+            self.0.lines().count()
+        )
+    }
+}
+
 /// The BPF dispatcher to manage BPF program lifecycle
 pub struct BpfLoader {
     skel: bpf::CollectorSkel<'static>,
     dispatcher: Dispatcher,
     perf_map_reader: PerfMapReader,
+    config: BpfLoaderConfig,
 }
 
 impl BpfLoader {
-    /// Create a new BPF loader with initialized skeleton
+    /// Create a new BPF loader with initialized skeleton and all program groups
     pub fn new() -> Result<Self> {
+        Self::with_config(BpfLoaderConfig::default())
+    }
+
+    /// Create a new BPF loader, loading only the configured program groups
+    pub fn with_config(config: BpfLoaderConfig) -> Result<Self> {
         fn print_to_log(level: PrintLevel, msg: String) {
             match level {
                 PrintLevel::Debug => log::debug!("{}", msg),
@@ -53,69 +269,88 @@ impl BpfLoader {
             }
         }
 
-        set_print(Some((PrintLevel::Debug, print_to_log)));
-
-        // Load BPF program (non-verbose, use the log crate to print errors)
-        let skel_result = Self::load_skel(false);
-
-        if let Err(e) = skel_result {
-            log::error!("Failed to load BPF program: {}", e);
-            log::error!("Reloading with debug flag, for more information");
-
-            // Reload with debug flag (verbose, to always print the error to stderr)
-            let _ = Self::load_skel(true);
-
-            // Return the original error
-            return Err(e);
+        fn print_to_capture(_level: PrintLevel, msg: String) {
+            CAPTURED_OUTPUT.lock().unwrap().push_str(&msg);
         }
 
-        let mut skel = skel_result.expect("checked above that it's not an error");
+        set_print(Some((PrintLevel::Debug, print_to_log)));
 
-        // Initialize perf event rings for the hardware counters
-        if let Err(e) =
-            perf_events::open_perf_counter(&mut skel.maps.cycles, HardwareCounter::Cycles)
-        {
-            return Err(anyhow!("Failed to open cycles counter: {:?}", e));
-        }
+        // Misread timestamps and types would only show as garbage output, so
+        // check the message layout before loading anything
+        let dispatcher = Dispatcher::with_layout(&events::sample_layout()).context(Coded::new(
+            ErrorCode::BpfLoad,
+            "BPF message layout does not match what the reader expects",
+        ))?;
+
+        let mut skel = if config.capture_verifier_log {
+            // libbpf retries a failed program load to print its verifier log,
+            // which is captured rather than logged
+            set_print(Some((PrintLevel::Debug, print_to_capture)));
+            let skel_result = Self::load_skel(false, &config);
+            set_print(Some((PrintLevel::Debug, print_to_log)));
+
+            let output = std::mem::take(&mut *CAPTURED_OUTPUT.lock().unwrap());
+            skel_result.map_err(|e| e.context(VerifierLog(output)))?
+        } else {
+            // Load BPF program (non-verbose, use the log crate to print errors)
+            let skel_result = Self::load_skel(false, &config);
+
+            if let Err(e) = skel_result {
+                log::error!("Failed to load BPF program: {}", e);
+                log::error!("Reloading with debug flag, for more information");
+
+                // Reload with debug flag (verbose, to always print the error to stderr)
+                let _ = Self::load_skel(true, &config);
+
+                // Return the original error
+                return Err(e);
+            }
 
-        if let Err(e) = perf_events::open_perf_counter(
-            &mut skel.maps.instructions,
-            HardwareCounter::Instructions,
-        ) {
-            return Err(anyhow!("Failed to open instructions counter: {:?}", e));
-        }
+            skel_result.expect("checked above that it's not an error")
+        };
 
-        if let Err(e) =
-            perf_events::open_perf_counter(&mut skel.maps.llc_misses, HardwareCounter::LLCMisses)
-        {
-            return Err(anyhow!("Failed to open LLC misses counter: {:?}", e));
-        }
-
-        if let Err(e) = perf_events::open_perf_counter(
-            &mut skel.maps.cache_references,
-            HardwareCounter::CacheReferences,
-        ) {
-            return Err(anyhow!("Failed to open cache references counter: {:?}", e));
+        // Initialize perf event rings for the hardware counters. Without
+        // them, the measurement programs report zero counter deltas.
+        if config.is_enabled(ProgramGroup::Counters) {
+            Self::open_counters(&mut skel)?;
         }
 
         // Set up the perf map reader for the events map
         let buffer_pages = 32;
         let watermark_bytes = 0; // Wake up on every event
-        let perf_map_reader =
+        let mut perf_map_reader =
             PerfMapReader::new(&mut skel.maps.events, buffer_pages, watermark_bytes)
-                .map_err(|e| anyhow!("Failed to create PerfMapReader: {}", e))?;
-
-        // Create a dispatcher to handle events
-        let dispatcher = Dispatcher::new();
+                .context("Failed to create PerfMapReader")?;
+        perf_map_reader
+            .set_ring_index_semantics(config.ring_index_semantics.clone())
+            .context("Failed to create PerfMapReader")?;
+        log_cpu_setup(perf_map_reader.cpu_setup());
 
         Ok(Self {
             skel,
             dispatcher,
             perf_map_reader,
+            config,
         })
     }
 
-    fn load_skel(verbose: bool) -> Result<bpf::CollectorSkel<'static>> {
+    fn open_counters(skel: &mut bpf::CollectorSkel<'static>) -> Result<()> {
+        perf_events::open_perf_counter(&mut skel.maps.cycles, HardwareCounter::Cycles)
+            .context("Failed to open cycles counter")?;
+        perf_events::open_perf_counter(&mut skel.maps.instructions, HardwareCounter::Instructions)
+            .context("Failed to open instructions counter")?;
+        perf_events::open_perf_counter(&mut skel.maps.llc_misses, HardwareCounter::LLCMisses)
+            .context("Failed to open LLC misses counter")?;
+        perf_events::open_perf_counter(
+            &mut skel.maps.cache_references,
+            HardwareCounter::CacheReferences,
+        )
+        .context("Failed to open cache references counter")?;
+
+        Ok(())
+    }
+
+    fn load_skel(verbose: bool, config: &BpfLoaderConfig) -> Result<bpf::CollectorSkel<'static>> {
         let mut skel_builder = bpf::CollectorSkelBuilder::default();
         if verbose {
             skel_builder.obj_builder.debug(true);
@@ -128,10 +363,59 @@ impl BpfLoader {
         // 3. The memory will be reclaimed when the program exits
         let obj_ref = Box::leak(Box::new(MaybeUninit::<OpenObject>::uninit()));
 
-        let open_skel = skel_builder.open(obj_ref)?;
+        let mut open_skel = skel_builder
+            .open(obj_ref)
+            .context(Coded::new(ErrorCode::BpfLoad, "Failed to open BPF object"))?;
+
+        // Events read from the wrong ring would be attributed to the wrong
+        // CPU, so check the object outputs the way the reader expects
+        config
+            .ring_index_semantics
+            .check_declared(open_skel.maps.rodata_data.ring_index_semantics)
+            .context(Coded::new(
+                ErrorCode::BpfLoad,
+                "BPF programs output to rings differently than configured",
+            ))?;
+
+        // Programs of disabled groups are not loaded, which also spares their
+        // verifier cost; the skeleton's attach() skips programs not loaded
+        for name in config.disabled_programs() {
+            let progs = &mut open_skel.progs;
+            macro_rules! disable {
+                ($($prog:ident),*) => {
+                    match name {
+                        $(stringify!($prog) => progs.$prog.set_autoload(false),)*
+                        _ => unreachable!("program groups list skeleton programs"),
+                    }
+                };
+            }
+            disable!(
+                handle_sched_switch,
+                handle_process_exit,
+                handle_process_free,
+                handle_hrtimer_expire_exit,
+                sync_timer_init_collect
+            );
+        }
+
         open_skel
             .load()
-            .with_context(|| "Failed to load BPF program")
+            .context(Coded::new(ErrorCode::BpfLoad, "Failed to load BPF program"))
+    }
+
+    /// Open the BPF object without loading it into the kernel.
+    ///
+    /// This checks that the embedded object parses and that libbpf can prepare
+    /// it, without requiring privileges or attaching anything.
+    pub fn probe_open() -> Result<()> {
+        let skel_builder = bpf::CollectorSkelBuilder::default();
+        let mut obj = MaybeUninit::<OpenObject>::uninit();
+
+        let _open_skel = skel_builder
+            .open(&mut obj)
+            .context(Coded::new(ErrorCode::BpfLoad, "Failed to open BPF object"))?;
+
+        Ok(())
     }
 
     /// Get a reference to the perf events dispatcher
@@ -144,40 +428,222 @@ impl BpfLoader {
         &mut self.dispatcher
     }
 
+    /// Get the configuration the loader was created with
+    pub fn config(&self) -> &BpfLoaderConfig {
+        &self.config
+    }
+
     /// Initialize and start the sync timer
     pub fn start_sync_timer(&mut self) -> Result<()> {
-        sync_timer::initialize_sync_timer(&self.skel.progs.sync_timer_init_collect)
-            .map_err(|e| anyhow::anyhow!("Sync timer initialization failed: {}", e))
+        let online_cpus = self.sync_timer_cpus("start")?;
+        sync_timer::initialize_sync_timer(&self.skel.progs.sync_timer_init_collect, &online_cpus)
+            .context("Sync timer initialization failed")
+    }
+
+    /// Re-arm the sync timer on every core, e.g. after a suspend stopped it.
+    /// Safe to call while the timers are still running.
+    pub fn restart_sync_timer(&mut self) -> Result<()> {
+        let online_cpus = self.sync_timer_cpus("restart")?;
+        sync_timer::reinitialize(&self.skel.progs.sync_timer_init_collect, &online_cpus)
+            .context("Sync timer restart failed")
+    }
+
+    /// The cores to run the sync timer on, if its program group is enabled
+    fn sync_timer_cpus(&self, action: &str) -> Result<Vec<usize>> {
+        if !self.config.is_enabled(ProgramGroup::SyncTimer) {
+            return Err(anyhow!(
+                "Cannot {} the sync timer: the {} program group is disabled",
+                action,
+                ProgramGroup::SyncTimer
+            ));
+        }
+        match self.config.online_cpus {
+            Some(ref online_cpus) => Ok(online_cpus.clone()),
+            None => sync_timer::read_online_cpus()
+                .with_context(|| format!("Failed to {} the sync timer", action)),
+        }
     }
 
     /// Attach BPF programs
     pub fn attach(&mut self) -> Result<()> {
         // Attach all BPF programs
-        self.skel.attach()?;
+        self.skel.attach().context(Coded::new(
+            ErrorCode::BpfAttach,
+            "Failed to attach BPF programs",
+        ))?;
+
+        Ok(())
+    }
+
+    /// Attach only the named programs, leaving the rest detached
+    ///
+    /// Names are those in [`ATTACHABLE_PROGRAMS`]. All names are checked
+    /// before anything is attached, including that their program group is
+    /// enabled. Programs that are already attached stay attached.
+    pub fn attach_programs(&mut self, which: &[&str]) -> Result<()> {
+        if let Some(unknown) = which
+            .iter()
+            .find(|name| !ATTACHABLE_PROGRAMS.contains(name))
+        {
+            return Err(anyhow!(
+                "Unknown BPF program {}, expected one of {}",
+                unknown,
+                ATTACHABLE_PROGRAMS.join(", ")
+            ));
+        }
+        if let Some(disabled) = which
+            .iter()
+            .find(|name| self.config.disabled_programs().any(|prog| prog == **name))
+        {
+            return Err(anyhow!(
+                "BPF program {} was not loaded, its program group is disabled",
+                disabled
+            ));
+        }
+
+        let progs = &self.skel.progs;
+        let links = &mut self.skel.links;
+        for &name in which {
+            macro_rules! attach {
+                ($($prog:ident),*) => {
+                    match name {
+                        $(stringify!($prog) => {
+                            if links.$prog.is_none() {
+                                links.$prog = Some(progs.$prog.attach().with_context(|| {
+                                    Coded::new(
+                                        ErrorCode::BpfAttach,
+                                        format!("Failed to attach BPF program {}", name),
+                                    )
+                                })?);
+                            }
+                        })*
+                        _ => unreachable!("names are checked above"),
+                    }
+                };
+            }
+            attach!(
+                handle_sched_switch,
+                handle_process_exit,
+                handle_process_free,
+                handle_hrtimer_expire_exit
+            );
+        }
 
         Ok(())
     }
 
+    /// Names of the programs that are currently attached
+    pub fn attached_programs(&self) -> Vec<&'static str> {
+        let links = &self.skel.links;
+        let attached = [
+            links.handle_sched_switch.is_some(),
+            links.handle_process_exit.is_some(),
+            links.handle_process_free.is_some(),
+            links.handle_hrtimer_expire_exit.is_some(),
+        ];
+        ATTACHABLE_PROGRAMS
+            .iter()
+            .zip(attached)
+            .filter(|(_, attached)| *attached)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// Names of the maps in the loaded BPF object
+    pub fn map_names(&self) -> Vec<String> {
+        self.skel
+            .object()
+            .maps()
+            .map(|map| map.name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// Check that the maps in [`PERF_EVENT_ARRAY_MAPS`] were created as perf
+    /// event arrays with u32 keys and values and an entry per possible CPU.
+    ///
+    /// Meant to run before attaching, so a map the kernel created differently
+    /// than the object declares fails with every problem listed, rather than
+    /// as missing counters or events later.
+    pub fn validate_maps(&self) -> Result<()> {
+        let num_cpus = libbpf_rs::num_possible_cpus().context("Failed to count possible CPUs")?;
+        let maps: Vec<_> = self.skel.object().maps().collect();
+
+        let mut problems = Vec::new();
+        for name in PERF_EVENT_ARRAY_MAPS {
+            let Some(map) = maps.iter().find(|map| map.name() == OsStr::new(name)) else {
+                problems.push(format!("map {} is missing", name));
+                continue;
+            };
+            if let Err(e) = validate_perf_event_array(map, num_cpus) {
+                problems.push(e.to_string());
+                continue;
+            }
+            let u32_size = std::mem::size_of::<u32>() as u32;
+            if map.key_size() != u32_size || map.value_size() != u32_size {
+                problems.push(format!(
+                    "map {} has key size {} and value size {}, expected {} and {}",
+                    name,
+                    map.key_size(),
+                    map.value_size(),
+                    u32_size,
+                    u32_size
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("{}", problems.join("; ")).context(Coded::new(
+                ErrorCode::BpfLoad,
+                "BPF maps do not have the expected shape",
+            )))
+        }
+    }
+
     /// Poll the ring buffer for events
-    pub fn poll_events(&mut self, timeout_ms: u64) -> Result<()> {
+    ///
+    /// Dispatches all available events. `idle_sleep` is the maximum idle sleep:
+    /// the call sleeps that long only if no events were available, to avoid
+    /// busy-waiting, and returns immediately otherwise. Returns what the batch
+    /// consumed, so callers can adapt how often they poll.
+    pub fn poll_events(&mut self, idle_sleep: Duration) -> Result<BatchSummary> {
         // Get the reader from the map reader
         let reader_mut = self.perf_map_reader.reader_mut();
 
-        // Start a read batch
-        reader_mut.start()?;
+        // Run a read batch, sleeping only if it was empty
+        Ok(self.dispatcher.poll(reader_mut, idle_sleep)?)
+    }
+
+    /// Run one read batch without sleeping, dispatching all available events
+    ///
+    /// Returns the dispatcher statistics accumulated during this call, so
+    /// callers can report per-iteration counts without diffing
+    /// `Dispatcher::stats()` themselves.
+    pub fn poll_once(&mut self) -> Result<Stats> {
+        let reader_mut = self.perf_map_reader.reader_mut();
+        Ok(self.dispatcher.poll_once(reader_mut)?)
+    }
 
-        // Dispatch all available events
-        self.dispatcher.dispatch_all(reader_mut)?;
+    /// Get the CPU that events dispatched from `ring_index` belong to, or
+    /// None when ring indices are not CPUs
+    pub fn ring_cpu(&self, ring_index: usize) -> Option<usize> {
+        self.perf_map_reader.ring_cpu(ring_index)
+    }
 
-        // Finish the read batch
-        reader_mut.finish()?;
+    /// Get the per-CPU ring counters of the perf event reader
+    pub fn ring_stats(&self) -> &[RingStats] {
+        self.perf_map_reader.reader().ring_stats()
+    }
 
-        // Short sleep to avoid busy-waiting if requested
-        if timeout_ms > 0 {
-            std::thread::sleep(Duration::from_millis(timeout_ms));
-        }
+    /// Get the state of the per-CPU rings as of their last read batch
+    pub fn ring_states(&self) -> Vec<RingState> {
+        self.perf_map_reader.reader().ring_states()
+    }
 
-        Ok(())
+    /// Get the highest fill ratio across the per-CPU rings
+    pub fn max_ring_fill(&self) -> f64 {
+        self.perf_map_reader.reader().max_fill_ratio()
     }
 
     /// Get a reference to the BPF skeleton
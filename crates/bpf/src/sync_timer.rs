@@ -1,4 +1,5 @@
 use anyhow::Result;
+use collector_errors::{Classified, ErrorCode};
 use log::{debug, error, info, warn};
 use nix::sched::{sched_getaffinity, sched_getcpu, sched_setaffinity, CpuSet};
 use nix::unistd::Pid;
@@ -7,7 +8,7 @@ use std::io;
 use thiserror::Error;
 
 // Import the auto-generated enums from BPF skeleton
-use crate::bpf::types::{sync_timer_init_error, sync_timer_mode};
+use crate::bpf::types::{sync_timer_init_error, sync_timer_init_kind, sync_timer_mode};
 
 impl sync_timer_mode {
     fn description(&self) -> &'static str {
@@ -34,6 +35,12 @@ pub enum SyncTimerError {
     #[error("Failed to get CPU count")]
     CpuCountFailed(#[source] libbpf_rs::Error),
 
+    #[error("Failed to read online CPUs")]
+    OnlineCpusReadFailed(#[source] io::Error),
+
+    #[error("Failed to parse online CPU list: {}", value)]
+    OnlineCpusParseFailed { value: String },
+
     #[error("Failed to set CPU {} in CpuSet", cpu)]
     CpuSetFailed { cpu: usize },
 
@@ -98,7 +105,41 @@ pub enum SyncTimerError {
     AllMethodsFailed,
 }
 
+impl Classified for SyncTimerError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::SyncTimer
+    }
+}
+
 const TIMER_MIGRATION_SYSCTL_PATH: &str = "/proc/sys/kernel/timer_migration";
+const ONLINE_CPUS_PATH: &str = "/sys/devices/system/cpu/online";
+
+/// Parse a kernel CPU list such as `0-3,6,8-9` into the CPUs it contains
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+/// Read the CPUs currently online
+pub fn read_online_cpus() -> Result<Vec<usize>, SyncTimerError> {
+    let content =
+        fs::read_to_string(ONLINE_CPUS_PATH).map_err(SyncTimerError::OnlineCpusReadFailed)?;
+    parse_cpu_list(&content).ok_or_else(|| SyncTimerError::OnlineCpusParseFailed {
+        value: content.trim().to_string(),
+    })
+}
 
 /// Read the current value of kernel.timer_migration sysctl
 fn read_timer_migration_sysctl() -> Result<u8, SyncTimerError> {
@@ -119,8 +160,9 @@ fn write_timer_migration_sysctl(value: u8) -> Result<(), SyncTimerError> {
         .map_err(SyncTimerError::SysctlWriteFailed)
 }
 
-/// Initializes and starts a synchronized timer on all available CPU cores with three-way fallback support
+/// Initializes and starts a synchronized timer on the online CPU cores with three-way fallback support
 ///
+/// Possible cores missing from `online_cpus` are skipped rather than failed.
 /// This function attempts to initialize BPF timers using three different methods in order of preference:
 ///
 /// # Fallback Strategy
@@ -156,12 +198,49 @@ fn write_timer_migration_sysctl(value: u8) -> Result<(), SyncTimerError> {
 /// ```
 pub fn initialize_sync_timer(
     timer_init_prog: &libbpf_rs::ProgramMut,
+    online_cpus: &[usize],
 ) -> Result<(), SyncTimerError> {
     info!("Initializing synchronized timer on all cores...");
+    initialize_with_fallback(
+        timer_init_prog,
+        online_cpus,
+        sync_timer_init_kind::SYNC_TIMER_INIT_FRESH,
+    )
+}
 
+/// Re-arms the synchronized timer on the online CPU cores, e.g. after the
+/// system resumed from suspend and the timers stopped firing
+///
+/// Timers that are still armed are cancelled and started again from the
+/// current time, and cores without a timer get a fresh one, so this is safe
+/// to call any number of times. Uses the same fallback as
+/// [`initialize_sync_timer`].
+pub fn reinitialize(
+    timer_init_prog: &libbpf_rs::ProgramMut,
+    online_cpus: &[usize],
+) -> Result<(), SyncTimerError> {
+    info!("Restarting synchronized timer on all cores...");
+    initialize_with_fallback(
+        timer_init_prog,
+        online_cpus,
+        sync_timer_init_kind::SYNC_TIMER_INIT_RESTART,
+    )
+}
+
+/// Initialize timers with the first mode the kernel supports
+fn initialize_with_fallback(
+    timer_init_prog: &libbpf_rs::ProgramMut,
+    online_cpus: &[usize],
+    kind: sync_timer_init_kind,
+) -> Result<(), SyncTimerError> {
     // Try modern pinning first (kernel 6.7+)
     debug!("Attempting modern timer initialization with CPU pinning + absolute time...");
-    match initialize_timers_with_mode(timer_init_prog, sync_timer_mode::SYNC_TIMER_MODE_MODERN) {
+    match initialize_timers_with_mode(
+        timer_init_prog,
+        online_cpus,
+        sync_timer_mode::SYNC_TIMER_MODE_MODERN,
+        kind,
+    ) {
         Ok(()) => {
             info!(
                 "Successfully initialized timers using {}",
@@ -179,7 +258,9 @@ pub fn initialize_sync_timer(
     info!("Attempting intermediate timer initialization with absolute time only...");
     match initialize_timers_with_mode(
         timer_init_prog,
+        online_cpus,
         sync_timer_mode::SYNC_TIMER_MODE_INTERMEDIATE,
+        kind,
     ) {
         Ok(()) => {
             info!(
@@ -196,7 +277,12 @@ pub fn initialize_sync_timer(
 
     // Fall back to legacy method (kernel 5.15-6.3)
     info!("Attempting legacy timer initialization with relative time only...");
-    match initialize_timers_with_mode(timer_init_prog, sync_timer_mode::SYNC_TIMER_MODE_LEGACY) {
+    match initialize_timers_with_mode(
+        timer_init_prog,
+        online_cpus,
+        sync_timer_mode::SYNC_TIMER_MODE_LEGACY,
+        kind,
+    ) {
         Ok(()) => {
             info!(
                 "Successfully initialized timers using {}",
@@ -214,7 +300,9 @@ pub fn initialize_sync_timer(
 /// Initialize timers with specified mode
 fn initialize_timers_with_mode(
     timer_init_prog: &libbpf_rs::ProgramMut,
+    online_cpus: &[usize],
     mode: sync_timer_mode,
+    kind: sync_timer_init_kind,
 ) -> Result<(), SyncTimerError> {
     let mut original_migration = None;
 
@@ -240,7 +328,7 @@ fn initialize_timers_with_mode(
     }
 
     // Initialize timers on all cores
-    let result = initialize_timers_on_all_cores(timer_init_prog, mode);
+    let result = initialize_timers_on_all_cores(timer_init_prog, online_cpus, mode, kind);
 
     // Restore original timer migration setting if we changed it
     if let Some(original_value) = original_migration {
@@ -257,10 +345,45 @@ fn initialize_timers_with_mode(
     result
 }
 
+/// Cores by the outcome of initializing their timer
+#[derive(Debug, Default, PartialEq, Eq)]
+struct CoreOutcomes {
+    initialized: Vec<usize>,
+    /// Possible cores that were offline
+    skipped: Vec<usize>,
+    failed: Vec<usize>,
+}
+
+/// Run `init` on each of the possible cores that is online
+fn initialize_online_cores(
+    num_possible_cpus: usize,
+    online_cpus: &[usize],
+    mut init: impl FnMut(usize) -> Result<(), SyncTimerError>,
+) -> CoreOutcomes {
+    let mut outcomes = CoreOutcomes::default();
+    for cpu_id in 0..num_possible_cpus {
+        if !online_cpus.contains(&cpu_id) {
+            outcomes.skipped.push(cpu_id);
+        } else if let Err(e) = init(cpu_id) {
+            debug!(
+                "Timer initialization failed on core {} (this is one of multiple fallback attempts): {}",
+                cpu_id, e
+            );
+            outcomes.failed.push(cpu_id);
+        } else {
+            debug!("Timer initialization succeeded on core {}", cpu_id);
+            outcomes.initialized.push(cpu_id);
+        }
+    }
+    outcomes
+}
+
 /// Core timer initialization logic shared by all modes
 fn initialize_timers_on_all_cores(
     timer_init_prog: &libbpf_rs::ProgramMut,
+    online_cpus: &[usize],
     mode: sync_timer_mode,
+    kind: sync_timer_init_kind,
 ) -> Result<(), SyncTimerError> {
     // Get current thread's CPU affinity to restore it later
     let current_pid = Pid::from_raw(0); // 0 means the current thread
@@ -271,22 +394,18 @@ fn initialize_timers_on_all_cores(
     let num_possible_cpus =
         libbpf_rs::num_possible_cpus().map_err(SyncTimerError::CpuCountFailed)?;
 
-    debug!("Found {} CPU cores", num_possible_cpus);
-
-    // Track any failed initializations
-    let mut failed_cores = Vec::new();
+    debug!(
+        "Found {} CPU cores, using {} strategy",
+        num_possible_cpus,
+        mode.description()
+    );
 
-    // Initialize timer on each core sequentially
-    for cpu_id in 0..num_possible_cpus {
-        if let Err(e) = initialize_timer_on_core(timer_init_prog, cpu_id, current_pid, mode) {
-            debug!(
-                "Timer initialization failed on core {} with strategy {} (this is one of multiple fallback attempts): {}",
-                cpu_id, mode.description(), e
-            );
-            failed_cores.push(cpu_id);
-        } else {
-            debug!("Timer initialization succeeded on core {}", cpu_id);
-        }
+    // Initialize timer on each online core sequentially
+    let outcomes = initialize_online_cores(num_possible_cpus, online_cpus, |cpu_id| {
+        initialize_timer_on_core(timer_init_prog, cpu_id, current_pid, mode, kind)
+    });
+    if !outcomes.skipped.is_empty() {
+        info!("Skipping offline cores {:?}", outcomes.skipped);
     }
 
     // Restore original CPU affinity
@@ -294,17 +413,17 @@ fn initialize_timers_on_all_cores(
         .map_err(SyncTimerError::AffinityRestoreFailed)?;
 
     // Check if any cores failed initialization
-    if !failed_cores.is_empty() {
+    if !outcomes.failed.is_empty() {
         return Err(SyncTimerError::MultipleFailures {
-            failed_cores: failed_cores.clone(),
-            failed_count: failed_cores.len(),
-            total_count: num_possible_cpus,
+            failed_count: outcomes.failed.len(),
+            total_count: outcomes.failed.len() + outcomes.initialized.len(),
+            failed_cores: outcomes.failed,
         });
     }
 
     debug!(
         "Synchronized timer initialized on {} cores using {}",
-        num_possible_cpus,
+        outcomes.initialized.len(),
         mode.description()
     );
     Ok(())
@@ -316,6 +435,7 @@ fn initialize_timer_on_core(
     cpu_id: usize,
     current_pid: Pid,
     mode: sync_timer_mode,
+    kind: sync_timer_init_kind,
 ) -> Result<(), SyncTimerError> {
     // Create a CPU set with just this core
     let mut cpu_set = CpuSet::new();
@@ -349,19 +469,19 @@ fn initialize_timer_on_core(
         mode.description()
     );
 
-    // Create input context with mode parameter
+    // Create input context with mode and kind parameters
     // We'll use the bpf_sock_addr structure to pass the mode
     let mut context_in = [0u8; 16];
 
     // Set up the context to pass the mode parameter
     // We'll use the first 4 bytes to simulate user_family = AF_INET (2)
-    // and the next 4 bytes for user_ip4 containing our mode
+    // and the next 4 bytes for user_ip4 containing our mode and kind
     context_in[0] = 2; // AF_INET
     context_in[1] = 0;
     context_in[2] = 0;
     context_in[3] = 0;
     context_in[4] = mode as u8; // mode parameter in user_ip4
-    context_in[5] = 0;
+    context_in[5] = kind as u8; // kind parameter in user_ip4
     context_in[6] = 0;
     context_in[7] = 0;
 
@@ -403,3 +523,44 @@ fn initialize_timer_on_core(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,6,8-9\n"),
+            Some(vec![0, 1, 2, 3, 6, 8, 9])
+        );
+        assert_eq!(parse_cpu_list("0"), Some(vec![0]));
+        assert_eq!(parse_cpu_list(""), Some(vec![]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("0-x"), None);
+    }
+
+    #[test]
+    fn test_offline_cores_skipped() {
+        // Cores 2 and 5 are possible but offline, core 4 fails
+        let online_cpus = parse_cpu_list("0-1,3-4").unwrap();
+        let mut attempted = Vec::new();
+        let outcomes = initialize_online_cores(6, &online_cpus, |cpu| {
+            attempted.push(cpu);
+            match cpu {
+                4 => Err(SyncTimerError::TimerStartFailed { cpu }),
+                _ => Ok(()),
+            }
+        });
+
+        assert_eq!(attempted, vec![0, 1, 3, 4]);
+        assert_eq!(
+            outcomes,
+            CoreOutcomes {
+                initialized: vec![0, 1, 3],
+                skipped: vec![2, 5],
+                failed: vec![4],
+            }
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Attaching a subset of the collector's BPF programs.
+#![cfg(target_os = "linux")]
+
+use bpf::{BpfLoader, ATTACHABLE_PROGRAMS};
+
+#[test]
+#[ignore] // This test requires root, run with cargo test -- --ignored
+fn test_attach_programs_subset() {
+    let mut loader = BpfLoader::new().expect("Failed to load BPF programs");
+    assert!(loader.attached_programs().is_empty());
+
+    // Unknown names are rejected before anything is attached
+    assert!(loader
+        .attach_programs(&["handle_sched_switch", "no_such_program"])
+        .is_err());
+    assert!(loader.attached_programs().is_empty());
+
+    // Only the context switch tracepoint gets a link
+    loader
+        .attach_programs(&["handle_sched_switch"])
+        .expect("Failed to attach handle_sched_switch");
+    assert_eq!(loader.attached_programs(), vec!["handle_sched_switch"]);
+    assert!(loader.skel().links.handle_sched_switch.is_some());
+    assert!(loader.skel().links.handle_hrtimer_expire_exit.is_none());
+
+    // Attaching more keeps the existing links
+    loader
+        .attach_programs(&["handle_sched_switch", "handle_process_exit"])
+        .expect("Failed to attach handle_process_exit");
+    assert_eq!(
+        loader.attached_programs(),
+        vec!["handle_sched_switch", "handle_process_exit"]
+    );
+
+    // attach() still attaches everything
+    loader.attach().expect("Failed to attach all programs");
+    assert_eq!(loader.attached_programs(), ATTACHABLE_PROGRAMS.to_vec());
+}
@@ -0,0 +1,28 @@
+//! Checking the ring index semantics the BPF object declares against the
+//! configured ones.
+#![cfg(target_os = "linux")]
+
+use bpf::{BpfLoader, BpfLoaderConfig};
+use perf_events::RingIndexSemantics;
+
+#[test]
+#[ignore] // This test requires root, run with cargo test -- --ignored
+fn test_ring_index_semantics() {
+    // The collector's programs output to the current CPU's ring
+    let loader = BpfLoader::new().expect("Failed to load BPF programs");
+    assert_eq!(loader.ring_cpu(3), Some(3));
+    drop(loader);
+
+    let config = BpfLoaderConfig {
+        ring_index_semantics: RingIndexSemantics::Explicit,
+        ..Default::default()
+    };
+    let err = BpfLoader::with_config(config)
+        .err()
+        .expect("Loading with mismatched ring index semantics should fail");
+    assert!(
+        format!("{:#}", err).contains("index rings by current CPU"),
+        "{:#}",
+        err
+    );
+}
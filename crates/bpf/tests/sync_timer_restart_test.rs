@@ -0,0 +1,37 @@
+//! Restarting the sync timer while it is running, as after a suspend.
+#![cfg(target_os = "linux")]
+
+use bpf::{sync_timer, BpfLoader};
+use libbpf_rs::MapCore as _;
+
+#[test]
+#[ignore] // This test requires root, run with cargo test -- --ignored
+fn test_restart_sync_timer() {
+    let mut loader = BpfLoader::new().expect("Failed to load BPF programs");
+    let online_cpus = sync_timer::read_online_cpus().expect("Failed to read online CPUs");
+
+    // Restarting before the first start initializes the timers
+    loader
+        .restart_sync_timer()
+        .expect("Failed to restart timers that were never started");
+
+    loader
+        .start_sync_timer()
+        .expect("Failed to start sync timer");
+
+    // Restarting armed timers cancels and re-arms them, any number of times
+    for _ in 0..3 {
+        loader
+            .restart_sync_timer()
+            .expect("Failed to restart running sync timer");
+    }
+
+    // Every online core still has exactly one timer
+    let states = &loader.skel().maps.sync_timer_states_collect;
+    let mut cpus: Vec<usize> = states
+        .keys()
+        .map(|key| u32::from_ne_bytes(key[..4].try_into().unwrap()) as usize)
+        .collect();
+    cpus.sort_unstable();
+    assert_eq!(cpus, online_cpus);
+}
@@ -0,0 +1,24 @@
+//! Checking the shape of the collector's BPF maps after loading.
+#![cfg(target_os = "linux")]
+
+use bpf::{BpfLoader, PERF_EVENT_ARRAY_MAPS};
+
+#[test]
+#[ignore] // This test requires root, run with cargo test -- --ignored
+fn test_validate_maps() {
+    let loader = BpfLoader::new().expect("Failed to load BPF programs");
+
+    let names = loader.map_names();
+    for name in PERF_EVENT_ARRAY_MAPS {
+        assert!(
+            names.iter().any(|map| map == name),
+            "map {} not in {:?}",
+            name,
+            names
+        );
+    }
+
+    loader
+        .validate_maps()
+        .expect("Maps of a correctly built skeleton should validate");
+}
@@ -0,0 +1,88 @@
+//! Compares converting timeslots to record batches in one piece and in
+//! parallel shards.
+//!
+//! Run with `cargo bench -p collector`.
+
+use std::sync::Arc;
+
+use collector::attribution::{AttributionLayout, ContainerIdentity};
+use collector::cgroup_filter::ContainerInfo;
+use collector::cpu_throttle::CpuStat;
+use collector::metrics::Metric;
+use collector::task_metadata::TaskMetadata;
+use collector::timeslot_data::TimeslotData;
+use collector::timeslot_to_recordbatch_task::{create_timeslot_schema, timeslot_to_batch_sharded};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use nri::metadata::ContainerLimits;
+
+const TASKS: u32 = 50000;
+const CGROUPS: u32 = 500;
+
+/// A timeslot of `TASKS` tasks in `CGROUPS` cgroups, some with limits and
+/// throttling
+fn synthetic_timeslot(start_timestamp: u64) -> TimeslotData {
+    let mut timeslot = TimeslotData::new(start_timestamp);
+    for pid in 1..=TASKS {
+        let cgroup_id = u64::from(pid % CGROUPS);
+        let mut comm = [0u8; 16];
+        let name = format!("task{}", pid);
+        comm[..name.len()].copy_from_slice(name.as_bytes());
+        // Kernel threads have no metadata
+        let metadata = (pid % 10 != 0).then(|| TaskMetadata::new(pid, comm, cgroup_id));
+        let pid64 = u64::from(pid);
+        let metrics = Metric::from_deltas(pid64 * 3, pid64 * 5, pid64 % 17, pid64 % 31, 1000);
+        timeslot.update(pid, metadata, metrics);
+        if pid % 3 == 0 {
+            timeslot.set_container_pid(pid, pid % 100);
+        }
+    }
+    for cgroup_id in (0..u64::from(CGROUPS)).step_by(7) {
+        let info = ContainerInfo {
+            identity: ContainerIdentity {
+                pod_namespace: "prod".to_string(),
+                pod_name: format!("web-{}", cgroup_id),
+                container_name: "web".to_string(),
+                container_id: format!("c{}", cgroup_id),
+            },
+            limits: ContainerLimits {
+                cpu_quota: Some(50000),
+                cpu_period: Some(100000),
+                cpuset_cpus: Some("0-3".to_string()),
+                ..Default::default()
+            },
+        };
+        timeslot.containers.insert(cgroup_id, Arc::new(info));
+        timeslot.throttling.insert(
+            cgroup_id,
+            CpuStat {
+                nr_throttled: cgroup_id,
+                throttled_usec: cgroup_id * 10,
+            },
+        );
+    }
+    timeslot.lost_count = 3;
+    timeslot
+}
+
+fn bench_conversion(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let schema = create_timeslot_schema(AttributionLayout::default());
+    let mut group = c.benchmark_group("timeslot_conversion");
+    for shards in [1, 2, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("shards", shards), &shards, |b, &shards| {
+            b.iter_batched(
+                || synthetic_timeslot(1_000_000),
+                |timeslot| {
+                    runtime
+                        .block_on(timeslot_to_batch_sharded(timeslot, schema.clone(), shards))
+                        .unwrap()
+                },
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_conversion);
+criterion_main!(benches);
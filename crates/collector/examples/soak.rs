@@ -0,0 +1,930 @@
+//! Soak test of the collector under synthetic load.
+//!
+//! A deterministic fake event source stands in for the BPF programs: it writes
+//! task metadata, task exits, perf measurements and timer ticks into
+//! [`MemoryStorage`] perf rings, seeded so a failing run reproduces. A
+//! [`collector::Collection`] attached to the rings' [`Dispatcher`] turns them into
+//! timeslots, and the stream of its record batches is written as parquet to an
+//! in-memory object store.
+//!
+//! While the pipeline runs, the soak checks that:
+//!
+//! - every window (emitted timeslot) reaching the writer carries exactly the
+//!   counter totals generated for it, and no window with measurements is lost
+//! - the parquet files hold the same totals as the generated events
+//! - resident memory stays within a band of its level after warmup
+//! - no pipeline task fails or panics
+//!
+//! Generation waits while too many windows are in flight, so a slow pipeline
+//! slows the run down rather than losing windows. The run is configured from
+//! `SOAK_*` environment variables (see [`SoakConfig::from_env`]); debug
+//! builds cannot keep up with the default load. An hour of timeslots:
+//!
+//! ```text
+//! SOAK_SLOTS=3600000 SOAK_SEED=42 cargo run --release -p collector --example soak
+//! ```
+//!
+//! The example's tests include a short smoke run, without real-time pacing.
+
+use std::collections::BTreeMap;
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use arrow_array::cast::AsArray;
+use arrow_array::types::Int64Type;
+use arrow_array::RecordBatch;
+use futures::StreamExt;
+use log::info;
+use object_store::memory::InMemory;
+use object_store::ObjectStore;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::task::TaskTracker;
+
+use bpf::{msg_type, PerfMeasurementMsg, TaskFreeMsg, TaskMetadataMsg, TimerFinishedProcessingMsg};
+use collector::parquet_writer::{ParquetWriter, ParquetWriterConfig};
+use collector::parquet_writer_task::ParquetWriterTask;
+use collector::shutdown::{ShutdownReason, ShutdownToken};
+use collector::task_completion_handler::task_completion_handler;
+use collector::{Collector, CollectorConfig};
+use perf_events::{
+    collector_sample_payload, Dispatcher, MemoryStorage, PerfRing, Reader, Storage,
+    PERF_RECORD_SAMPLE,
+};
+
+/// Length of the collector's timeslots
+const TIMESLOT_SIZE_NS: u64 = 1_000_000;
+
+/// Offset of the timer tick that closes a timeslot on every CPU
+const TIMER_OFFSET_NS: u64 = 900_000;
+
+/// Measurements are spread over the part of the timeslot before the timer tick
+const MEASUREMENT_SPAN_NS: u64 = 800_000;
+
+/// One in this many measurements is for a task without metadata
+const UNKNOWN_TASK_RATIO: u64 = 20;
+
+/// First pid of tasks without metadata
+const UNKNOWN_PID_BASE: u32 = 1_000_000;
+
+/// Failure messages kept in the report; further failures are only counted
+const MAX_REPORTED_FAILURES: usize = 20;
+
+/// Capacity of the channel between the window check and the writer
+const WRITER_CHANNEL_CAPACITY: usize = 100;
+
+/// How long generation waits for the pipeline to take in-flight windows
+const LAG_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Configuration of a soak run
+#[derive(Debug, Clone)]
+pub struct SoakConfig {
+    /// Seed of the fake event source
+    pub seed: u64,
+    /// Number of timeslots to generate
+    pub slots: u64,
+    /// Generate timeslots at the rate they would occur, rather than as fast
+    /// as the pipeline takes them
+    pub realtime: bool,
+    /// Perf measurements per second of timeslots, across all CPUs
+    pub events_per_sec: u64,
+    /// Number of live fake tasks
+    pub num_tasks: usize,
+    /// Tasks exiting per second of timeslots, each replaced by a new task
+    pub churn_per_sec: f64,
+    /// Number of simulated CPUs, each with its own ring
+    pub num_cpus: usize,
+    /// Data pages per ring
+    pub ring_pages: u32,
+    /// Timeslots generated between reads of the rings
+    pub slots_per_step: u64,
+    /// Windows generated but not yet checked before generation waits, below
+    /// the capacity of the pipeline's channels
+    pub max_lag: usize,
+    /// Timeslots generated before the memory baseline is taken
+    pub warmup_slots: u64,
+    /// Allowed growth of resident memory over the baseline as a fraction,
+    /// None to skip the check
+    pub rss_band: Option<f64>,
+    /// Timeslots between stats reports
+    pub stats_every: u64,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0x5eed,
+            slots: 60_000,
+            realtime: true,
+            events_per_sec: 100_000,
+            num_tasks: 256,
+            churn_per_sec: 50.0,
+            num_cpus: 4,
+            ring_pages: 64,
+            slots_per_step: 10,
+            max_lag: 100,
+            warmup_slots: 10_000,
+            rss_band: Some(0.25),
+            stats_every: 10_000,
+        }
+    }
+}
+
+impl SoakConfig {
+    /// Defaults overridden by `SOAK_SEED`, `SOAK_SLOTS`, `SOAK_REALTIME`,
+    /// `SOAK_EVENTS_PER_SEC`, `SOAK_TASKS`, `SOAK_CHURN_PER_SEC`,
+    /// `SOAK_CPUS`, `SOAK_WARMUP_SLOTS` and `SOAK_RSS_BAND`
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            seed: env_or("SOAK_SEED", defaults.seed),
+            slots: env_or("SOAK_SLOTS", defaults.slots),
+            realtime: env_or("SOAK_REALTIME", defaults.realtime),
+            events_per_sec: env_or("SOAK_EVENTS_PER_SEC", defaults.events_per_sec),
+            num_tasks: env_or("SOAK_TASKS", defaults.num_tasks),
+            churn_per_sec: env_or("SOAK_CHURN_PER_SEC", defaults.churn_per_sec),
+            num_cpus: env_or("SOAK_CPUS", defaults.num_cpus),
+            warmup_slots: env_or("SOAK_WARMUP_SLOTS", defaults.warmup_slots),
+            rss_band: Some(env_or("SOAK_RSS_BAND", defaults.rss_band.unwrap_or(0.25))),
+            ..defaults
+        }
+    }
+}
+
+/// Parse an environment variable, falling back to `default` when unset or invalid
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// SplitMix64: small, fast and plenty random for synthetic load
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[low, high)`
+    fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low)
+    }
+
+    /// Uniform in `[0, 1)`
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A count averaging `rate`: its integer part, plus one with the
+    /// probability of its fractional part
+    fn count(&mut self, rate: f64) -> u64 {
+        rate as u64 + u64::from(self.unit() < rate.fract())
+    }
+}
+
+/// Counter totals of a set of perf measurements
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Totals {
+    pub cycles: u64,
+    pub instructions: u64,
+    pub llc_misses: u64,
+    pub cache_references: u64,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.cycles += other.cycles;
+        self.instructions += other.instructions;
+        self.llc_misses += other.llc_misses;
+        self.cache_references += other.cache_references;
+    }
+
+    fn is_zero(&self) -> bool {
+        *self == Totals::default()
+    }
+
+    /// Totals of the counter columns of a timeslot record batch
+    fn from_batch(batch: &RecordBatch) -> Self {
+        let sum = |name: &str| -> u64 {
+            batch.column_by_name(name).map_or(0, |column| {
+                column
+                    .as_primitive::<Int64Type>()
+                    .values()
+                    .iter()
+                    .map(|value| *value as u64)
+                    .sum()
+            })
+        };
+        Self {
+            cycles: sum("cycles"),
+            instructions: sum("instructions"),
+            llc_misses: sum("llc_misses"),
+            cache_references: sum("cache_references"),
+        }
+    }
+}
+
+/// Deterministic stand-in for the BPF programs.
+///
+/// Each 1ms timeslot starts with task churn on CPU 0, spreads perf
+/// measurements round robin over the CPUs, and ends with a timer tick on every
+/// CPU. Messages of each CPU are emitted in timestamp order, encoded as the
+/// BPF programs pass them to `bpf_perf_event_output`.
+pub struct FakeEventSource {
+    rng: Rng,
+    num_cpus: usize,
+    events_per_slot: f64,
+    churn_per_slot: f64,
+    tasks: Vec<u32>,
+    next_pid: u32,
+    slot: u64,
+}
+
+impl FakeEventSource {
+    pub fn new(config: &SoakConfig) -> Self {
+        let slots_per_sec = (1_000_000_000 / TIMESLOT_SIZE_NS) as f64;
+        Self {
+            rng: Rng(config.seed),
+            num_cpus: config.num_cpus.max(1),
+            events_per_slot: config.events_per_sec as f64 / slots_per_sec,
+            churn_per_slot: config.churn_per_sec / slots_per_sec,
+            tasks: Vec::new(),
+            next_pid: 1,
+            slot: 0,
+        }
+    }
+
+    /// Number of timeslots generated so far
+    pub fn slots(&self) -> u64 {
+        self.slot
+    }
+
+    /// Start a task, emitting its metadata
+    fn spawn_task(&mut self, timestamp: u64, emit: &mut dyn FnMut(usize, &[u8])) {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+
+        let mut comm = [0u8; 16];
+        comm[..9].copy_from_slice(b"soak-task");
+        let msg = TaskMetadataMsg {
+            pid,
+            comm,
+            cgroup_id: 1000 + u64::from(pid % 16),
+            ..Default::default()
+        };
+        emit(
+            0,
+            &collector_sample_payload(msg_type::MSG_TYPE_TASK_METADATA as u32, timestamp, &msg),
+        );
+        self.tasks.push(pid);
+    }
+
+    /// Generate the next timeslot, passing each (CPU, message) to `emit`.
+    ///
+    /// Returns the start timestamp of the window the timeslot is emitted as,
+    /// and the counter totals of its measurements.
+    pub fn next_slot(
+        &mut self,
+        num_tasks: usize,
+        emit: &mut dyn FnMut(usize, &[u8]),
+    ) -> (u64, Totals) {
+        let window = self.slot * TIMESLOT_SIZE_NS;
+        let base = window + TIMESLOT_SIZE_NS;
+
+        // Task churn: exits are replaced by new tasks
+        while self.tasks.len() < num_tasks {
+            self.spawn_task(base, emit);
+        }
+        for _ in 0..self.rng.count(self.churn_per_slot) {
+            if self.tasks.is_empty() {
+                break;
+            }
+            let index = self.rng.range(0, self.tasks.len() as u64) as usize;
+            let msg = TaskFreeMsg {
+                pid: self.tasks.swap_remove(index),
+                ..Default::default()
+            };
+            emit(
+                0,
+                &collector_sample_payload(msg_type::MSG_TYPE_TASK_FREE as u32, base, &msg),
+            );
+            self.spawn_task(base, emit);
+        }
+
+        // Measurements, spread evenly over the timeslot on each CPU
+        let mut totals = Totals::default();
+        let count = self.rng.count(self.events_per_slot);
+        let num_cpus = self.num_cpus as u64;
+        for i in 0..count {
+            let cpu = i % num_cpus;
+            let per_cpu = count / num_cpus + u64::from(cpu < count % num_cpus);
+            let timestamp = base + (i / num_cpus + 1) * MEASUREMENT_SPAN_NS / (per_cpu + 1);
+
+            let pid = if self.tasks.is_empty() || self.rng.range(0, UNKNOWN_TASK_RATIO) == 0 {
+                UNKNOWN_PID_BASE + self.rng.range(0, 16) as u32
+            } else {
+                self.tasks[self.rng.range(0, self.tasks.len() as u64) as usize]
+            };
+            let cycles = self.rng.range(1_000, 1_000_000);
+            let llc_misses = self.rng.range(0, cycles / 100);
+            let msg = PerfMeasurementMsg {
+                pid,
+                cycles_delta: cycles,
+                instructions_delta: self.rng.range(0, 3 * cycles),
+                llc_misses_delta: llc_misses,
+                cache_references_delta: llc_misses + self.rng.range(0, cycles / 10),
+                time_delta_ns: self.rng.range(1_000, MEASUREMENT_SPAN_NS),
+                ..Default::default()
+            };
+            totals.add(&Totals {
+                cycles: msg.cycles_delta,
+                instructions: msg.instructions_delta,
+                llc_misses: msg.llc_misses_delta,
+                cache_references: msg.cache_references_delta,
+            });
+            emit(
+                cpu as usize,
+                &collector_sample_payload(
+                    msg_type::MSG_TYPE_PERF_MEASUREMENT as u32,
+                    timestamp,
+                    &msg,
+                ),
+            );
+        }
+
+        self.tick(base, emit);
+        (window, totals)
+    }
+
+    /// Generate a timeslot with only timer ticks, so the last generated
+    /// timeslot is emitted
+    pub fn close(&mut self, emit: &mut dyn FnMut(usize, &[u8])) {
+        let base = (self.slot + 1) * TIMESLOT_SIZE_NS;
+        self.tick(base, emit);
+    }
+
+    /// Emit the timer tick that ends the timeslot on every CPU
+    fn tick(&mut self, base: u64, emit: &mut dyn FnMut(usize, &[u8])) {
+        let msg = TimerFinishedProcessingMsg::default();
+        for cpu in 0..self.num_cpus {
+            emit(
+                cpu,
+                &collector_sample_payload(
+                    msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING as u32,
+                    base + TIMER_OFFSET_NS,
+                    &msg,
+                ),
+            );
+        }
+        self.slot += 1;
+    }
+}
+
+/// Outcome of a soak run
+#[derive(Debug, Default)]
+pub struct SoakReport {
+    pub slots: u64,
+    pub events: u64,
+    pub windows_checked: u64,
+    pub rows_written: u64,
+    pub files_written: usize,
+    pub input_totals: Totals,
+    pub parquet_totals: Totals,
+    pub baseline_rss: Option<u64>,
+    pub max_rss: u64,
+    /// Most windows generated but not yet checked at once
+    pub max_lag: usize,
+    pub failure_count: u64,
+    /// The first failures, in the order they were found
+    pub failures: Vec<String>,
+}
+
+impl SoakReport {
+    pub fn passed(&self) -> bool {
+        self.failure_count == 0
+    }
+
+    fn fail(&mut self, failure: String) {
+        self.failure_count += 1;
+        if self.failures.len() < MAX_REPORTED_FAILURES {
+            self.failures.push(failure);
+        }
+    }
+
+    /// Pass/fail summary for humans
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "soak {}: {} timeslots, {} events, {} windows checked, {} rows in {} files, \
+             max rss {} bytes (baseline {:?}), max lag {} windows",
+            if self.passed() { "PASSED" } else { "FAILED" },
+            self.slots,
+            self.events,
+            self.windows_checked,
+            self.rows_written,
+            self.files_written,
+            self.max_rss,
+            self.baseline_rss,
+            self.max_lag,
+        );
+        if !self.passed() {
+            summary.push_str(&format!("\n{} failures:", self.failure_count));
+            for failure in &self.failures {
+                summary.push_str("\n  ");
+                summary.push_str(failure);
+            }
+        }
+        summary
+    }
+}
+
+/// Windows generated but not yet checked, and failures found while checking
+#[derive(Default)]
+struct WindowCheck {
+    expected: BTreeMap<u64, Totals>,
+    output: Totals,
+    report: SoakReport,
+}
+
+impl WindowCheck {
+    /// Check a record batch of the collection's stream against the window it came from
+    fn check_batch(&mut self, batch: &RecordBatch) {
+        if batch.num_rows() == 0 {
+            return;
+        }
+        let window = batch.column_by_name("start_time").map_or(0, |column| {
+            column.as_primitive::<Int64Type>().value(0) as u64
+        });
+        let totals = Totals::from_batch(batch);
+        self.output.add(&totals);
+        self.report.windows_checked += 1;
+
+        // Windows arrive in order, so earlier ones are complete
+        let later = self.expected.split_off(&window);
+        for (missing, expected) in std::mem::replace(&mut self.expected, later) {
+            if !expected.is_zero() {
+                self.report.fail(format!(
+                    "window {} with {:?} never reached the writer",
+                    missing, expected
+                ));
+            }
+        }
+
+        match self.expected.remove(&window) {
+            Some(expected) if expected == totals => {}
+            Some(expected) => self.report.fail(format!(
+                "window {}: generated {:?}, wrote {:?}",
+                window, expected, totals
+            )),
+            None => self.report.fail(format!(
+                "window {} was never generated or arrived twice",
+                window
+            )),
+        }
+    }
+
+    /// Fail for every window with measurements that never arrived
+    fn finish(&mut self) {
+        for (window, expected) in std::mem::take(&mut self.expected) {
+            if !expected.is_zero() {
+                self.report.fail(format!(
+                    "window {} with {:?} never reached the writer",
+                    window, expected
+                ));
+            }
+        }
+    }
+}
+
+/// Forward batches to the writer, checking each against its window
+async fn check_windows(
+    mut batches: ReceiverStream<RecordBatch>,
+    writer_sender: mpsc::Sender<RecordBatch>,
+    check: Arc<Mutex<WindowCheck>>,
+) -> Result<()> {
+    while let Some(batch) = batches.next().await {
+        check.lock().unwrap().check_batch(&batch);
+        if writer_sender.send(batch).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Rings written by the fake source and read by the collection
+struct Rings {
+    // Writing ends of the rings, over the same memory as the reader's
+    writers: Vec<PerfRing>,
+    reader: Reader,
+    dispatcher: Dispatcher,
+    // Memory of the rings, which must outlive them
+    _storage: Vec<MemoryStorage>,
+}
+
+impl Rings {
+    fn new(config: &SoakConfig) -> Result<Self> {
+        let mut storage = Vec::new();
+        let mut writers = Vec::new();
+        let mut reader = Reader::new();
+        for _ in 0..config.num_cpus.max(1) {
+            let mut ring_storage = MemoryStorage::new(config.ring_pages)?;
+            let page_size = ring_storage.page_size();
+            let (reading, writing) = unsafe {
+                (
+                    PerfRing::init_contiguous(
+                        ring_storage.data_mut(),
+                        config.ring_pages,
+                        page_size,
+                    )?,
+                    PerfRing::init_contiguous(
+                        ring_storage.data_mut(),
+                        config.ring_pages,
+                        page_size,
+                    )?,
+                )
+            };
+            reader.add_ring(reading)?;
+            writers.push(writing);
+            storage.push(ring_storage);
+        }
+
+        Ok(Self {
+            writers,
+            reader,
+            dispatcher: Dispatcher::new(),
+            _storage: storage,
+        })
+    }
+
+    /// Write the messages `generate` emits into the rings, then read and
+    /// dispatch them. Returns the number of messages written.
+    fn feed(&mut self, generate: impl FnOnce(&mut dyn FnMut(usize, &[u8]))) -> Result<u64> {
+        for writer in &mut self.writers {
+            writer.start_write_batch();
+        }
+
+        let mut written = 0;
+        let mut error = None;
+        {
+            let writers = &mut self.writers;
+            let mut emit = |cpu: usize, msg: &[u8]| {
+                if error.is_some() {
+                    return;
+                }
+                match writers[cpu].write(msg, PERF_RECORD_SAMPLE) {
+                    Ok(_) => written += 1,
+                    Err(e) => error = Some(anyhow!("writing to the ring of CPU {}: {}", cpu, e)),
+                }
+            };
+            generate(&mut emit);
+        }
+
+        for writer in &mut self.writers {
+            writer.finish_write_batch();
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        self.dispatcher.poll_once(&mut self.reader)?;
+        Ok(written)
+    }
+}
+
+/// Resident set size of this process in bytes
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64)
+}
+
+/// Run the collection under the configured load, checking invariants throughout
+pub async fn run_soak(config: SoakConfig) -> SoakReport {
+    let check = Arc::new(Mutex::new(WindowCheck::default()));
+    let store = Arc::new(InMemory::new());
+    match soak(&config, &check, store.clone()).await {
+        Ok(()) => {}
+        Err(e) => check.lock().unwrap().report.fail(format!("{:#}", e)),
+    }
+
+    let mut check = std::mem::take(&mut *check.lock().unwrap());
+    check.finish();
+    let mut report = check.report;
+
+    // The parquet files must hold everything that was generated
+    drain_parquet(store.as_ref(), &mut report).await;
+    if report.parquet_totals != report.input_totals {
+        report.fail(format!(
+            "parquet files hold {:?}, generated {:?}",
+            report.parquet_totals, report.input_totals
+        ));
+    }
+    if report.parquet_totals != check.output {
+        report.fail(format!(
+            "parquet files hold {:?}, windows carried {:?}",
+            report.parquet_totals, check.output
+        ));
+    }
+
+    info!("{}", report.summary());
+    report
+}
+
+/// Generate load and run the collection to completion, recording what was
+/// generated and any failures in `check`
+async fn soak(
+    config: &SoakConfig,
+    check: &Arc<Mutex<WindowCheck>>,
+    store: Arc<InMemory>,
+) -> Result<()> {
+    // Pipeline: rings -> collection -> record batches -> check -> writer
+    let task_tracker = TaskTracker::new();
+    let shutdown_token = ShutdownToken::new();
+    let (collection, batches) =
+        Collector::new(CollectorConfig::default()).run_streaming(&task_tracker, &shutdown_token)?;
+    let (writer_sender, writer_receiver) = mpsc::channel(WRITER_CHANNEL_CAPACITY);
+    let (_rotate_sender, rotate_receiver) = mpsc::channel(1);
+    let writer_config = ParquetWriterConfig {
+        storage_prefix: "soak-".to_string(),
+        file_size_limit: 1024 * 1024,
+        ..Default::default()
+    };
+    let writer = ParquetWriter::new(
+        store.clone(),
+        collection.pipeline.schema.clone(),
+        writer_config,
+    )?;
+    task_tracker.spawn(task_completion_handler(
+        check_windows(batches, writer_sender, check.clone()),
+        shutdown_token.clone(),
+        "WindowCheck",
+    ));
+    task_tracker.spawn(task_completion_handler(
+        ParquetWriterTask::new(writer, writer_receiver, rotate_receiver).run(),
+        shutdown_token.clone(),
+        "ParquetWriterTask",
+    ));
+    task_tracker.close();
+
+    let mut rings = Rings::new(config)?;
+    let collection = collection.attach(&mut rings.dispatcher, config.num_cpus.max(1));
+    let mut source = FakeEventSource::new(config);
+    let started = Instant::now();
+    let mut next_stats = config.stats_every;
+    let mut result = Ok(());
+    let mut drained = SoakReport::default();
+
+    while source.slots() < config.slots && !shutdown_token.is_cancelled() {
+        // Wait for the pipeline to take in-flight windows, rather than
+        // overflow its channels
+        loop {
+            let lag = {
+                let mut check = check.lock().unwrap();
+                let lag = check.expected.len();
+                check.report.max_lag = check.report.max_lag.max(lag);
+                lag
+            };
+            if lag + config.slots_per_step as usize <= config.max_lag
+                || shutdown_token.is_cancelled()
+            {
+                break;
+            }
+            tokio::time::sleep(LAG_POLL_INTERVAL).await;
+        }
+
+        let slots = config.slots_per_step.min(config.slots - source.slots());
+        let step = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            rings.feed(|emit| {
+                for _ in 0..slots {
+                    let (window, totals) = source.next_slot(config.num_tasks, emit);
+                    let mut check = check.lock().unwrap();
+                    check.report.input_totals.add(&totals);
+                    check.expected.insert(window, totals);
+                }
+            })
+        }));
+        let events = match step {
+            Ok(Ok(events)) => events,
+            Ok(Err(e)) => {
+                result = Err(e);
+                break;
+            }
+            Err(_) => {
+                result = Err(anyhow!("reading the rings panicked"));
+                break;
+            }
+        };
+
+        {
+            let mut check = check.lock().unwrap();
+            let report = &mut check.report;
+            report.slots = source.slots();
+            report.events += events;
+
+            let rss = resident_memory().unwrap_or(0);
+            report.max_rss = report.max_rss.max(rss);
+            if let Some(band) = config.rss_band {
+                match report.baseline_rss {
+                    None if report.slots >= config.warmup_slots => report.baseline_rss = Some(rss),
+                    Some(baseline) if rss as f64 > baseline as f64 * (1.0 + band) => {
+                        report.fail(format!(
+                            "resident memory {} bytes above the {} byte baseline by more than {}",
+                            rss, baseline, band
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+
+            if report.slots >= next_stats {
+                next_stats += config.stats_every;
+                info!(
+                    "soak: {:?} elapsed, {} timeslots, {} events, {} windows checked, rss {} bytes, {} failures",
+                    started.elapsed(),
+                    report.slots,
+                    report.events,
+                    report.windows_checked,
+                    rss,
+                    report.failure_count
+                );
+            }
+        }
+
+        // Finished files would otherwise pile up in the store's memory
+        drain_parquet(store.as_ref(), &mut drained).await;
+
+        // Generate timeslots at the rate they would occur, unless behind
+        let due = started + Duration::from_nanos(source.slots() * TIMESLOT_SIZE_NS);
+        if config.realtime && due > Instant::now() {
+            tokio::time::sleep_until(due).await;
+        } else {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    // Emit the last timeslot, then close the pipeline from the front
+    if result.is_ok() {
+        result = rings.feed(|emit| source.close(emit)).map(|_| ());
+    }
+    collection.shutdown();
+    drop(rings);
+    task_tracker.wait().await;
+
+    let mut check = check.lock().unwrap();
+    if let Some(ShutdownReason::Error { message, .. }) = shutdown_token.reason() {
+        check.report.fail(message);
+    }
+    check.report.parquet_totals = drained.parquet_totals;
+    check.report.rows_written = drained.rows_written;
+    check.report.files_written = drained.files_written;
+    for failure in drained.failures {
+        check.report.fail(failure);
+    }
+    result
+}
+
+/// Read and delete the finished parquet files in `store`, adding their
+/// counter totals, rows and count to `report`
+async fn drain_parquet(store: &dyn ObjectStore, report: &mut SoakReport) {
+    let metas: Vec<_> = store.list(None).collect().await;
+    for meta in metas {
+        let drained = async {
+            let location = meta?.location;
+            let bytes = store.get(&location).await?.bytes().await?;
+            for batch in ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()? {
+                let batch = batch?;
+                report.rows_written += batch.num_rows() as u64;
+                report.parquet_totals.add(&Totals::from_batch(&batch));
+            }
+            report.files_written += 1;
+            store.delete(&location).await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = drained.await {
+            report.fail(format!("reading parquet files: {:#}", e));
+        }
+    }
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+    let report = run_soak(SoakConfig::from_env()).await;
+    println!("{}", report.summary());
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use collector::metrics::Metric;
+    use collector::timeslot_data::TimeslotData;
+    use collector::timeslot_to_recordbatch_task::{create_timeslot_schema, timeslot_to_batch};
+
+    /// Every message a source emits over `slots` timeslots
+    fn emitted(config: &SoakConfig, slots: u64) -> Vec<(usize, Vec<u8>)> {
+        let mut source = FakeEventSource::new(config);
+        let mut messages = Vec::new();
+        for _ in 0..slots {
+            source.next_slot(config.num_tasks, &mut |cpu, msg| {
+                messages.push((cpu, msg.to_vec()))
+            });
+        }
+        messages
+    }
+
+    #[test]
+    fn test_source_is_deterministic() {
+        let config = SoakConfig {
+            events_per_sec: 50_000,
+            num_tasks: 16,
+            churn_per_sec: 2_000.0,
+            ..Default::default()
+        };
+        let messages = emitted(&config, 50);
+        assert_eq!(messages, emitted(&config, 50));
+
+        let reseeded = SoakConfig {
+            seed: config.seed + 1,
+            ..config.clone()
+        };
+        assert_ne!(messages, emitted(&reseeded, 50));
+
+        // Timestamps never go backwards on a CPU
+        let mut last = vec![0u64; config.num_cpus];
+        for (cpu, msg) in &messages {
+            let timestamp = u64::from_le_bytes(msg[4..12].try_into().unwrap());
+            assert!(timestamp >= last[*cpu]);
+            last[*cpu] = timestamp;
+        }
+    }
+
+    #[test]
+    fn test_window_check_detects_violations() {
+        let batch = |window: u64, cycles: u64| {
+            let mut timeslot = TimeslotData::new(window);
+            timeslot.update(1, None, Metric::from_deltas(cycles, 0, 0, 0, 1000));
+            timeslot_to_batch(timeslot, create_timeslot_schema(Default::default())).unwrap()
+        };
+        let totals = |cycles: u64| Totals {
+            cycles,
+            ..Default::default()
+        };
+
+        let mut check = WindowCheck::default();
+        for window in 0..4 {
+            check.expected.insert(window, totals(100));
+        }
+
+        check.check_batch(&batch(0, 100));
+        assert!(check.report.passed());
+
+        // Window 1 is skipped and window 2 lost a measurement
+        check.check_batch(&batch(2, 60));
+        assert_eq!(check.report.failure_count, 2);
+
+        // Window 2 again, then window 3 never arrives
+        check.check_batch(&batch(2, 100));
+        assert_eq!(check.report.failure_count, 3);
+        check.finish();
+        assert_eq!(check.report.failure_count, 4);
+        assert_eq!(check.report.windows_checked, 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_soak_smoke() {
+        let report = run_soak(SoakConfig {
+            slots: 500,
+            realtime: false,
+            events_per_sec: 20_000,
+            num_tasks: 64,
+            churn_per_sec: 500.0,
+            num_cpus: 2,
+            // Other tests share this process's memory
+            rss_band: None,
+            ..Default::default()
+        })
+        .await;
+
+        assert!(report.passed(), "{}", report.summary());
+        assert_eq!(report.slots, 500);
+        assert!(report.windows_checked >= report.slots - 1);
+        assert!(report.rows_written > 0);
+        assert_eq!(report.parquet_totals, report.input_totals);
+    }
+}
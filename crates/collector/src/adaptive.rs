@@ -0,0 +1,370 @@
+use std::fs;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+/// Highest degradation level, with every mitigation applied
+pub const MAX_DEGRADATION_LEVEL: usize = 4;
+
+/// Collection settings applied at a degradation level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mitigations {
+    /// Number of consecutive timeslots merged into each emitted row
+    pub slots_merged: u32,
+    /// Whether trace rows are emitted
+    pub trace_enabled: bool,
+    /// Extra delay between dispatch polls, so events are handled in larger batches
+    pub poll_delay: Duration,
+}
+
+impl Mitigations {
+    /// Mitigations are cumulative: each level keeps those of the level below
+    ///
+    /// 0. full fidelity
+    /// 1. merge 2 timeslots per row
+    /// 2. merge 4 timeslots per row
+    /// 3. stop emitting trace rows
+    /// 4. delay between dispatch polls
+    pub fn for_level(level: usize) -> Self {
+        Self {
+            slots_merged: match level {
+                0 => 1,
+                1 => 2,
+                _ => 4,
+            },
+            trace_enabled: level < 3,
+            poll_delay: if level >= 4 {
+                Duration::from_millis(20)
+            } else {
+                Duration::ZERO
+            },
+        }
+    }
+}
+
+/// Pressure observed over one sampling interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PressureSample {
+    /// CPU time used by the collector, as a fraction of one CPU
+    pub cpu_fraction: f64,
+    /// Highest fill ratio across the perf rings
+    pub ring_fill: f64,
+}
+
+/// Thresholds for moving between degradation levels
+///
+/// Pressure above either high threshold for `escalate_after` consecutive
+/// samples raises the level by one; pressure below both low thresholds for
+/// `recover_after` consecutive samples lowers it by one. Samples between the
+/// thresholds reset both streaks, so the level does not flap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveConfig {
+    /// CPU fraction of one core above which pressure is high
+    pub cpu_high: f64,
+    /// CPU fraction of one core below which pressure is low
+    pub cpu_low: f64,
+    /// Ring fill ratio above which pressure is high
+    pub fill_high: f64,
+    /// Ring fill ratio below which pressure is low
+    pub fill_low: f64,
+    /// Consecutive high samples before raising the level
+    pub escalate_after: u32,
+    /// Consecutive low samples before lowering the level
+    pub recover_after: u32,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        Self {
+            cpu_high: 0.5,
+            cpu_low: 0.25,
+            fill_high: 0.5,
+            fill_low: 0.1,
+            escalate_after: 3,
+            recover_after: 10,
+        }
+    }
+}
+
+/// A change of degradation level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub from: usize,
+    pub to: usize,
+}
+
+/// Raises and lowers the degradation level from periodic pressure samples
+pub struct AdaptiveController {
+    config: AdaptiveConfig,
+    level: usize,
+    max_level: usize,
+    transitions: usize,
+    high_streak: u32,
+    low_streak: u32,
+}
+
+impl AdaptiveController {
+    /// Create a controller starting at full fidelity
+    pub fn new(config: AdaptiveConfig) -> Self {
+        Self {
+            config,
+            level: 0,
+            max_level: 0,
+            transitions: 0,
+            high_streak: 0,
+            low_streak: 0,
+        }
+    }
+
+    /// Current degradation level
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Highest level reached so far
+    pub fn max_level(&self) -> usize {
+        self.max_level
+    }
+
+    /// Number of level changes so far
+    pub fn transitions(&self) -> usize {
+        self.transitions
+    }
+
+    /// Mitigations for the current level
+    pub fn mitigations(&self) -> Mitigations {
+        Mitigations::for_level(self.level())
+    }
+
+    /// Account for a pressure sample, returning the level change it caused, if any
+    pub fn observe(&mut self, sample: PressureSample) -> Option<Transition> {
+        let config = &self.config;
+        let high = sample.cpu_fraction >= config.cpu_high || sample.ring_fill >= config.fill_high;
+        let low = sample.cpu_fraction <= config.cpu_low && sample.ring_fill <= config.fill_low;
+
+        let from = self.level();
+        let to = if high {
+            self.low_streak = 0;
+            self.high_streak += 1;
+            if self.high_streak < config.escalate_after || from == MAX_DEGRADATION_LEVEL {
+                return None;
+            }
+            from + 1
+        } else if low {
+            self.high_streak = 0;
+            self.low_streak += 1;
+            if self.low_streak < config.recover_after || from == 0 {
+                return None;
+            }
+            from - 1
+        } else {
+            self.high_streak = 0;
+            self.low_streak = 0;
+            return None;
+        };
+
+        self.high_streak = 0;
+        self.low_streak = 0;
+        self.level = to;
+        self.max_level = self.max_level.max(to);
+        self.transitions += 1;
+
+        info!(
+            "Degradation level {} -> {} (collector CPU {:.0}%, ring fill {:.0}%): {:?}",
+            from,
+            to,
+            sample.cpu_fraction * 100.0,
+            sample.ring_fill * 100.0,
+            Mitigations::for_level(to)
+        );
+
+        Some(Transition { from, to })
+    }
+}
+
+/// Parse the total user and system CPU ticks from the contents of /proc/<pid>/stat
+fn parse_cpu_ticks(stat: &str) -> Option<u64> {
+    // The command name may contain spaces, so split after its closing paren.
+    // utime and stime are fields 14 and 15; the remainder starts at field 3.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    let mut fields = rest.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Measures the collector's own CPU usage from /proc/self/stat deltas
+pub struct SelfCpuSampler {
+    ticks_per_sec: f64,
+    last: Option<(Instant, u64)>,
+}
+
+impl Default for SelfCpuSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SelfCpuSampler {
+    pub fn new() -> Self {
+        let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        Self {
+            ticks_per_sec: if ticks_per_sec > 0 {
+                ticks_per_sec as f64
+            } else {
+                100.0
+            },
+            last: None,
+        }
+    }
+
+    /// CPU used since the previous call as a fraction of one CPU, or None on
+    /// the first call or if /proc/self/stat cannot be read
+    pub fn sample(&mut self) -> Option<f64> {
+        let ticks = parse_cpu_ticks(&fs::read_to_string("/proc/self/stat").ok()?)?;
+        let now = Instant::now();
+
+        let (last_time, last_ticks) = self.last.replace((now, ticks))?;
+        let elapsed = now.duration_since(last_time).as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        Some(ticks.saturating_sub(last_ticks) as f64 / self.ticks_per_sec / elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(cpu_fraction: f64, ring_fill: f64) -> PressureSample {
+        PressureSample {
+            cpu_fraction,
+            ring_fill,
+        }
+    }
+
+    /// Feed samples to the controller, returning the level after each one
+    fn levels(controller: &mut AdaptiveController, samples: &[PressureSample]) -> Vec<usize> {
+        samples
+            .iter()
+            .map(|&s| {
+                controller.observe(s);
+                controller.level()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_escalates_and_recovers_one_level_at_a_time() {
+        let config = AdaptiveConfig {
+            escalate_after: 2,
+            recover_after: 3,
+            ..Default::default()
+        };
+        let mut controller = AdaptiveController::new(config);
+
+        let high = sample(0.9, 0.0);
+        let low = sample(0.1, 0.0);
+
+        let mut transitions = Vec::new();
+        for s in [high; 12].into_iter().chain([low; 15]) {
+            if let Some(t) = controller.observe(s) {
+                transitions.push((t.from, t.to));
+            }
+        }
+
+        assert_eq!(
+            transitions,
+            vec![
+                (0, 1),
+                (1, 2),
+                (2, 3),
+                (3, 4),
+                (4, 3),
+                (3, 2),
+                (2, 1),
+                (1, 0)
+            ]
+        );
+        assert_eq!(controller.max_level(), MAX_DEGRADATION_LEVEL);
+        assert_eq!(controller.transitions(), 8);
+        assert_eq!(controller.level(), 0);
+    }
+
+    #[test]
+    fn test_ring_fill_alone_escalates() {
+        let mut controller = AdaptiveController::new(AdaptiveConfig {
+            escalate_after: 1,
+            ..Default::default()
+        });
+        assert_eq!(
+            controller.observe(sample(0.0, 0.8)),
+            Some(Transition { from: 0, to: 1 })
+        );
+    }
+
+    #[test]
+    fn test_hysteresis_between_thresholds() {
+        let config = AdaptiveConfig {
+            escalate_after: 2,
+            recover_after: 2,
+            ..Default::default()
+        };
+        let mut controller = AdaptiveController::new(config);
+        let high = sample(0.9, 0.0);
+        let mid = sample(0.4, 0.0);
+        let low = sample(0.1, 0.0);
+
+        // Interrupted streaks never change the level
+        assert_eq!(
+            levels(&mut controller, &[high, mid, high, mid, high]),
+            vec![0, 0, 0, 0, 0]
+        );
+
+        // Pressure easing to between the thresholds keeps the level
+        assert_eq!(
+            levels(&mut controller, &[high, mid, mid, mid, mid]),
+            vec![1, 1, 1, 1, 1]
+        );
+
+        // Recovery needs a full streak below the low thresholds
+        assert_eq!(
+            levels(&mut controller, &[low, mid, low, low]),
+            vec![1, 1, 1, 0]
+        );
+
+        // A high ring fill blocks recovery even when CPU usage is low
+        controller.observe(high);
+        controller.observe(high);
+        assert_eq!(
+            levels(&mut controller, &[sample(0.1, 0.3), sample(0.1, 0.3)]),
+            vec![1, 1]
+        );
+    }
+
+    #[test]
+    fn test_mitigations_are_cumulative() {
+        let levels: Vec<Mitigations> = (0..=MAX_DEGRADATION_LEVEL)
+            .map(Mitigations::for_level)
+            .collect();
+        assert_eq!(
+            levels.iter().map(|m| m.slots_merged).collect::<Vec<_>>(),
+            vec![1, 2, 4, 4, 4]
+        );
+        assert_eq!(
+            levels.iter().map(|m| m.trace_enabled).collect::<Vec<_>>(),
+            vec![true, true, true, false, false]
+        );
+        assert!(levels[..4].iter().all(|m| m.poll_delay.is_zero()));
+        assert!(!levels[4].poll_delay.is_zero());
+    }
+
+    #[test]
+    fn test_parse_cpu_ticks() {
+        let stat = "1234 (my (odd) comm) S 1 1234 1234 0 -1 4194560 500 0 0 0 150 25 0 0 20 0 1";
+        assert_eq!(parse_cpu_ticks(stat), Some(175));
+        assert_eq!(parse_cpu_ticks("1234 (truncated) S 1"), None);
+        assert!(SelfCpuSampler::new().sample().is_none());
+    }
+}
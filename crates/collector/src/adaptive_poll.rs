@@ -0,0 +1,164 @@
+//! How long the main loop sleeps when a poll finds the rings empty.
+//!
+//! By default every idle poll sleeps a fixed [`FIXED_POLL_SLEEP`]. With
+//! `--adaptive-poll` the sleep follows the last read batch instead: a batch
+//! that popped many events or left the rings filling up shortens it, so the
+//! next events are picked up sooner, while consecutive empty batches double
+//! it up to a cap, so an idle collector wakes up less often. The sleep only
+//! applies when a poll finds nothing to read; a busy loop never sleeps.
+
+use std::time::Duration;
+
+use perf_events::BatchSummary;
+
+/// Idle sleep between polls without `--adaptive-poll`
+pub const FIXED_POLL_SLEEP: Duration = Duration::from_millis(10);
+
+/// Bounds and load thresholds of the adaptive idle sleep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptivePollConfig {
+    /// Sleep after a fully loaded batch
+    pub min_sleep: Duration,
+    /// Longest sleep, reached after a run of empty batches
+    pub max_sleep: Duration,
+    /// Events popped in one batch that count as fully loaded
+    pub busy_events: u64,
+    /// Ring fill ratio at the end of a batch that counts as fully loaded
+    pub busy_fill: f64,
+}
+
+impl Default for AdaptivePollConfig {
+    fn default() -> Self {
+        Self {
+            min_sleep: Duration::from_millis(1),
+            max_sleep: Duration::from_millis(50),
+            busy_events: 4096,
+            busy_fill: 0.25,
+        }
+    }
+}
+
+/// The sleep to use after a batch summarized by `summary`, given the sleep
+/// used before it
+///
+/// Empty batches double the previous sleep. Otherwise the load of the batch,
+/// the larger of its events and its fill ratio relative to their busy
+/// thresholds, scales the sleep linearly from `max_sleep` at no load down to
+/// `min_sleep` at full load. The result is always within the bounds.
+pub fn next_sleep(
+    config: &AdaptivePollConfig,
+    previous: Duration,
+    summary: &BatchSummary,
+) -> Duration {
+    if summary.events_popped == 0 {
+        return previous
+            .saturating_mul(2)
+            .clamp(config.min_sleep, config.max_sleep);
+    }
+
+    let events_load = summary.events_popped as f64 / config.busy_events.max(1) as f64;
+    let fill_load = summary.max_fill_ratio / config.busy_fill;
+    let load = events_load.max(fill_load).clamp(0.0, 1.0);
+    let range = config.max_sleep.saturating_sub(config.min_sleep);
+    config.max_sleep - range.mul_f64(load)
+}
+
+/// Idle sleep that adapts to the batches the loop has read
+pub struct AdaptivePoll {
+    config: AdaptivePollConfig,
+    sleep: Duration,
+}
+
+impl AdaptivePoll {
+    /// Start from the fixed sleep, within the configured bounds
+    pub fn new(config: AdaptivePollConfig) -> Self {
+        Self {
+            sleep: FIXED_POLL_SLEEP.clamp(config.min_sleep, config.max_sleep),
+            config,
+        }
+    }
+
+    /// Sleep for the next poll, should it find the rings empty
+    pub fn sleep(&self) -> Duration {
+        self.sleep
+    }
+
+    /// Account for a finished batch, returning the new sleep
+    pub fn observe(&mut self, summary: &BatchSummary) -> Duration {
+        self.sleep = next_sleep(&self.config, self.sleep, summary);
+        self.sleep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(events_popped: u64, max_fill_ratio: f64) -> BatchSummary {
+        BatchSummary {
+            events_popped,
+            bytes_consumed: events_popped * 32,
+            rings_nonempty_at_finish: 0,
+            max_fill_ratio,
+        }
+    }
+
+    #[test]
+    fn test_response_curve() {
+        let config = AdaptivePollConfig::default();
+        let ms = Duration::from_millis;
+        let previous = ms(10);
+
+        // Full load on either measure gives the shortest sleep, and more
+        // than full load does not go below it
+        assert_eq!(next_sleep(&config, previous, &batch(4096, 0.0)), ms(1));
+        assert_eq!(next_sleep(&config, previous, &batch(100_000, 0.0)), ms(1));
+        assert_eq!(next_sleep(&config, previous, &batch(1, 0.25)), ms(1));
+        assert_eq!(next_sleep(&config, previous, &batch(1, 1.0)), ms(1));
+
+        // In between, the sleep follows the larger of the two loads
+        assert_eq!(
+            next_sleep(&config, previous, &batch(2048, 0.0)),
+            ms(25) + ms(1) / 2
+        );
+        assert_eq!(
+            next_sleep(&config, previous, &batch(1, 0.125)),
+            ms(25) + ms(1) / 2
+        );
+        assert_eq!(next_sleep(&config, previous, &batch(2048, 0.25)), ms(1));
+
+        // A single event barely shortens the longest sleep
+        let light = next_sleep(&config, previous, &batch(1, 0.0));
+        assert!(light < ms(50) && light > ms(49), "{:?}", light);
+    }
+
+    #[test]
+    fn test_idle_backoff() {
+        let config = AdaptivePollConfig::default();
+        let ms = Duration::from_millis;
+        let idle = batch(0, 0.0);
+
+        // Empty batches double the sleep up to the cap
+        assert_eq!(next_sleep(&config, ms(10), &idle), ms(20));
+        assert_eq!(next_sleep(&config, ms(40), &idle), ms(50));
+        assert_eq!(next_sleep(&config, ms(50), &idle), ms(50));
+        assert_eq!(next_sleep(&config, Duration::MAX, &idle), ms(50));
+        // and never start below the floor
+        assert_eq!(next_sleep(&config, Duration::ZERO, &idle), ms(1));
+
+        let mut poll = AdaptivePoll::new(config);
+        assert_eq!(poll.sleep(), FIXED_POLL_SLEEP);
+        let sleeps: Vec<Duration> = (0..4).map(|_| poll.observe(&idle)).collect();
+        assert_eq!(sleeps, vec![ms(20), ms(40), ms(50), ms(50)]);
+        assert_eq!(poll.observe(&batch(4096, 0.0)), ms(1));
+        assert_eq!(poll.sleep(), ms(1));
+        assert_eq!(poll.observe(&idle), ms(2));
+
+        // The fixed sleep is brought within narrower bounds
+        let narrow = AdaptivePoll::new(AdaptivePollConfig {
+            max_sleep: ms(5),
+            ..config
+        });
+        assert_eq!(narrow.sleep(), ms(5));
+    }
+}
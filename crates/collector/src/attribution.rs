@@ -0,0 +1,330 @@
+//! The identity of the container a row is attributed to.
+//!
+//! The timeslot and trace outputs carry the pod and container of each row's
+//! cgroup, as kept from NRI metadata for the containers `--cgroup-filter`
+//! follows. By default they are the fields of an `attribution` struct column,
+//! so consumers check a single field for unattributed rows: the struct is
+//! null on rows of cgroups that are not a known container, and its fields are
+//! null where the runtime left them empty, as for containers outside a pod.
+//! `--flat-attribution` writes the same fields as top-level columns.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow_array::builder::{NullBufferBuilder, StringBuilder};
+use arrow_array::{ArrayRef, StructArray};
+use arrow_schema::{DataType, Field, Fields, Schema};
+use nri::metadata::ContainerMetadata;
+
+use crate::cgroup_filter::ContainerInfo;
+
+/// Name of the struct column holding the identity fields
+pub const ATTRIBUTION_COLUMN: &str = "attribution";
+
+/// Identity fields, in struct and column order
+pub const ATTRIBUTION_FIELDS: [&str; 4] = [
+    "pod_namespace",
+    "pod_name",
+    "container_name",
+    "container_id",
+];
+
+/// How the identity fields are laid out in the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AttributionLayout {
+    /// Fields of the attribution struct column
+    #[default]
+    Nested,
+    /// Top-level columns
+    Flat,
+}
+
+impl AttributionLayout {
+    /// The layout of the identity fields in `schema`
+    pub fn of(schema: &Schema) -> Self {
+        if schema.index_of(ATTRIBUTION_COLUMN).is_ok() {
+            Self::Nested
+        } else {
+            Self::Flat
+        }
+    }
+
+    /// Schema fields holding the identity fields in this layout
+    pub fn fields(self) -> Vec<Field> {
+        let children: Vec<Field> = ATTRIBUTION_FIELDS
+            .iter()
+            .map(|name| Field::new(*name, DataType::Utf8, true))
+            .collect();
+        match self {
+            Self::Nested => vec![Field::new(
+                ATTRIBUTION_COLUMN,
+                DataType::Struct(Fields::from(children)),
+                true,
+            )],
+            Self::Flat => children,
+        }
+    }
+}
+
+/// The pod and container a cgroup belongs to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContainerIdentity {
+    pub pod_namespace: String,
+    pub pod_name: String,
+    pub container_name: String,
+    pub container_id: String,
+}
+
+impl ContainerIdentity {
+    /// The identity of the container `metadata` describes
+    pub fn from_metadata(metadata: &ContainerMetadata) -> Self {
+        Self {
+            pod_namespace: metadata.pod_namespace.clone(),
+            pod_name: metadata.pod_name.clone(),
+            container_name: metadata.container_name.clone(),
+            container_id: metadata.container_id.clone(),
+        }
+    }
+
+    /// The fields in [`ATTRIBUTION_FIELDS`] order
+    fn values(&self) -> [&str; 4] {
+        [
+            &self.pod_namespace,
+            &self.pod_name,
+            &self.container_name,
+            &self.container_id,
+        ]
+    }
+}
+
+/// Builds the identity columns of a batch, a row at a time
+pub struct AttributionBuilder {
+    layout: AttributionLayout,
+    fields: [StringBuilder; 4],
+    nulls: NullBufferBuilder,
+}
+
+impl AttributionBuilder {
+    pub fn new(layout: AttributionLayout, rows: usize) -> Self {
+        Self {
+            layout,
+            fields: std::array::from_fn(|_| StringBuilder::with_capacity(rows, rows * 16)),
+            nulls: NullBufferBuilder::new(rows),
+        }
+    }
+
+    /// Append a row of `identity`'s container, or an unattributed row if None
+    pub fn append(&mut self, identity: Option<&ContainerIdentity>) {
+        match identity {
+            Some(identity) => {
+                for (builder, value) in self.fields.iter_mut().zip(identity.values()) {
+                    // Runtimes leave the fields they do not know empty
+                    builder.append_option((!value.is_empty()).then_some(value));
+                }
+                self.nulls.append_non_null();
+            }
+            None => {
+                for builder in &mut self.fields {
+                    builder.append_null();
+                }
+                self.nulls.append_null();
+            }
+        }
+    }
+
+    /// Append the rows of `cgroup_ids`, attributed to the containers in
+    /// `containers`
+    pub fn append_cgroups(
+        &mut self,
+        cgroup_ids: impl Iterator<Item = Option<u64>>,
+        containers: &HashMap<u64, Arc<ContainerInfo>>,
+    ) {
+        for cgroup_id in cgroup_ids {
+            let info = cgroup_id.and_then(|cgroup_id| containers.get(&cgroup_id));
+            self.append(info.map(|info| &info.identity));
+        }
+    }
+
+    /// Push the identity columns to `arrays`
+    pub fn finish(mut self, arrays: &mut Vec<ArrayRef>) {
+        let columns: Vec<ArrayRef> = self
+            .fields
+            .iter_mut()
+            .map(|builder| Arc::new(builder.finish()) as ArrayRef)
+            .collect();
+        match self.layout {
+            AttributionLayout::Nested => {
+                let [field] = &AttributionLayout::Nested.fields()[..] else {
+                    unreachable!("the nested layout is a single column");
+                };
+                let DataType::Struct(children) = field.data_type().clone() else {
+                    unreachable!("the nested layout is a struct column");
+                };
+                let attribution = StructArray::new(children, columns, self.nulls.finish());
+                arrays.push(Arc::new(attribution));
+            }
+            AttributionLayout::Flat => arrays.extend(columns),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::{Array, Int32Array, RecordBatch};
+    use arrow_schema::SchemaRef;
+    use futures::StreamExt;
+    use object_store::{memory::InMemory, ObjectStore};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::parquet_writer::{ParquetWriter, ParquetWriterConfig};
+
+    fn identity(pod_name: &str) -> ContainerIdentity {
+        ContainerIdentity {
+            pod_namespace: "prod".to_string(),
+            pod_name: pod_name.to_string(),
+            container_name: "web".to_string(),
+            container_id: "c1".to_string(),
+        }
+    }
+
+    fn schema(layout: AttributionLayout) -> SchemaRef {
+        let mut fields = vec![Field::new("pid", DataType::Int32, false)];
+        fields.extend(layout.fields());
+        Arc::new(Schema::new(fields))
+    }
+
+    /// An attributed, an unattributed and a partially attributed row
+    fn batch(layout: AttributionLayout) -> RecordBatch {
+        let mut builder = AttributionBuilder::new(layout, 3);
+        builder.append(Some(&identity("web-0")));
+        builder.append(None);
+        builder.append(Some(&identity("")));
+        let mut arrays: Vec<ArrayRef> = vec![Arc::new(Int32Array::from(vec![1, 2, 3]))];
+        builder.finish(&mut arrays);
+        RecordBatch::try_new(schema(layout), arrays).unwrap()
+    }
+
+    /// Write `batch` with the collector's parquet writer and read it back
+    async fn parquet_round_trip(batch: &RecordBatch) -> RecordBatch {
+        let store = Arc::new(InMemory::new());
+        let writer_config = ParquetWriterConfig::builder()
+            .timestamp_column("pid")
+            .build()
+            .unwrap();
+        let mut writer = ParquetWriter::new(store.clone(), batch.schema(), writer_config).unwrap();
+        writer.write(batch.clone()).await.unwrap();
+        writer.close().await.unwrap();
+
+        let metas: Vec<_> = store.list(None).collect().await;
+        assert_eq!(metas.len(), 1);
+        let location = metas[0].as_ref().unwrap().location.clone();
+        let bytes = store.get(&location).await.unwrap().bytes().await.unwrap();
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .unwrap()
+            .build()
+            .unwrap();
+        let read = reader.next().unwrap().unwrap();
+        assert!(reader.next().is_none());
+        read
+    }
+
+    #[test]
+    fn test_layout_of_schema() {
+        for layout in [AttributionLayout::Nested, AttributionLayout::Flat] {
+            assert_eq!(AttributionLayout::of(&schema(layout)), layout);
+        }
+        let flat = schema(AttributionLayout::Flat);
+        let names: Vec<&str> = flat.fields()[1..]
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect();
+        assert_eq!(names, ATTRIBUTION_FIELDS);
+    }
+
+    #[test]
+    fn test_nested_rows() {
+        let batch = batch(AttributionLayout::Nested);
+        let attribution = batch.column(1).as_struct();
+        let valid: Vec<bool> = (0..3).map(|row| attribution.is_valid(row)).collect();
+        assert_eq!(valid, vec![true, false, true]);
+
+        // Fields the runtime left empty are null in an attributed row
+        let pod_name = attribution.column(1).as_string::<i32>();
+        assert_eq!(pod_name.value(0), "web-0");
+        assert!(pod_name.is_null(2));
+        assert_eq!(attribution.column(2).as_string::<i32>().value(2), "web");
+    }
+
+    #[test]
+    fn test_flat_rows() {
+        let batch = batch(AttributionLayout::Flat);
+        let pod_name = batch.column(2).as_string::<i32>();
+        assert_eq!(pod_name.value(0), "web-0");
+        assert!(pod_name.is_null(1));
+        assert!(pod_name.is_null(2));
+        assert!(batch.column(4).as_string::<i32>().is_null(1));
+        assert_eq!(batch.column(4).as_string::<i32>().value(2), "c1");
+    }
+
+    #[test]
+    fn test_rows_by_cgroup() {
+        let info = Arc::new(ContainerInfo {
+            identity: identity("web-0"),
+            limits: Default::default(),
+        });
+        let containers = HashMap::from([(100, info)]);
+        let mut builder = AttributionBuilder::new(AttributionLayout::Nested, 3);
+        builder.append_cgroups([Some(100), Some(200), None].into_iter(), &containers);
+        let mut arrays = Vec::new();
+        builder.finish(&mut arrays);
+        let attribution = arrays[0].as_struct();
+        assert!(attribution.is_valid(0));
+        assert!(attribution.is_null(1));
+        assert!(attribution.is_null(2));
+    }
+
+    #[tokio::test]
+    async fn test_attribution_round_trip() {
+        // Both layouts read back unchanged from parquet, including the null
+        // struct of the unattributed row
+        for layout in [AttributionLayout::Nested, AttributionLayout::Flat] {
+            let batch = batch(layout);
+            assert_eq!(parquet_round_trip(&batch).await, batch);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_nullable_children() {
+        // A required child under a nullable struct, null where the struct is
+        let children = Fields::from(vec![Field::new("pod_name", DataType::Utf8, false)]);
+        let mut nulls = NullBufferBuilder::new(3);
+        nulls.append_n_non_nulls(1);
+        nulls.append_null();
+        nulls.append_non_null();
+        let attribution = StructArray::try_new(
+            children.clone(),
+            vec![Arc::new(arrow_array::StringArray::from(vec!["web-0", "", "web-1"])) as ArrayRef],
+            nulls.finish(),
+        )
+        .unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("pid", DataType::Int32, false),
+            Field::new(ATTRIBUTION_COLUMN, DataType::Struct(children), true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3])),
+                Arc::new(attribution),
+            ],
+        )
+        .unwrap();
+        let read = parquet_round_trip(&batch).await;
+        assert_eq!(read.schema(), batch.schema());
+        let attribution = read.column(1).as_struct();
+        assert!(attribution.is_null(1));
+        assert_eq!(attribution.column(0).as_string::<i32>().value(2), "web-1");
+    }
+}
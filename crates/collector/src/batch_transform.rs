@@ -0,0 +1,399 @@
+//! Post-processing of record batches before they are written.
+//!
+//! Transforms run in order on every batch between the producing task and the
+//! parquet writer. They can change the schema, e.g. to drop columns for
+//! privacy or add a tenant column, but must declare the change up front
+//! through [`BatchTransform::output_schema`] so the writer is created with
+//! the final schema.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use arrow_array::RecordBatch;
+use arrow_schema::{Schema, SchemaRef};
+use log::warn;
+
+/// A step applied to each record batch before writing
+pub trait BatchTransform: Send {
+    /// Name used in logs
+    fn name(&self) -> &str;
+
+    /// Schema of the batches this transform produces from batches of `input`
+    fn output_schema(&self, input: SchemaRef) -> SchemaRef;
+
+    /// Transform a batch, returning None to drop it
+    fn transform(&mut self, batch: RecordBatch) -> Result<Option<RecordBatch>>;
+}
+
+/// What to do when a transform fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TransformErrorPolicy {
+    /// Drop the batch, count the error and keep going
+    Skip,
+    /// Stop writing, which shuts down collection
+    Abort,
+}
+
+/// An ordered list of transforms
+pub struct TransformChain {
+    transforms: Vec<Box<dyn BatchTransform>>,
+    /// Schema each transform is declared to produce
+    schemas: Vec<SchemaRef>,
+    policy: TransformErrorPolicy,
+    error_count: Arc<AtomicUsize>,
+}
+
+impl TransformChain {
+    /// Create a chain for batches of `input` schema, with no transforms
+    pub fn new(input: SchemaRef, policy: TransformErrorPolicy) -> Self {
+        Self {
+            transforms: Vec::new(),
+            schemas: vec![input],
+            policy,
+            error_count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Append a transform, applied after those already added
+    pub fn push(&mut self, transform: Box<dyn BatchTransform>) {
+        self.schemas
+            .push(transform.output_schema(self.output_schema()));
+        self.transforms.push(transform);
+    }
+
+    /// Schema of the batches entering the chain
+    pub fn input_schema(&self) -> SchemaRef {
+        self.schemas[0].clone()
+    }
+
+    /// Schema of the batches leaving the chain
+    pub fn output_schema(&self) -> SchemaRef {
+        self.schemas.last().unwrap().clone()
+    }
+
+    /// Names of the transforms, in the order they are applied
+    pub fn names(&self) -> Vec<&str> {
+        self.transforms
+            .iter()
+            .map(|transform| transform.name())
+            .collect()
+    }
+
+    /// Returns true if the chain has no transforms
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    /// Get a handle to the number of batches dropped due to transform errors
+    pub fn error_counter(&self) -> Arc<AtomicUsize> {
+        self.error_count.clone()
+    }
+
+    /// Run a batch through all transforms.
+    ///
+    /// Returns None if a transform dropped the batch, or if a transform
+    /// failed under the skip policy. Under the abort policy, transform
+    /// failures are returned as errors.
+    pub fn apply(&mut self, batch: RecordBatch) -> Result<Option<RecordBatch>> {
+        let mut batch = batch;
+        for (transform, schema) in self.transforms.iter_mut().zip(&self.schemas[1..]) {
+            let result = transform.transform(batch).and_then(|output| match output {
+                Some(output) if output.schema() != *schema => Err(anyhow!(
+                    "produced schema {:?}, declared {:?}",
+                    output.schema(),
+                    schema
+                )),
+                output => Ok(output),
+            });
+
+            batch = match result {
+                Ok(Some(output)) => output,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    let e = e.context(format!("Batch transform {} failed", transform.name()));
+                    return match self.policy {
+                        TransformErrorPolicy::Abort => Err(e),
+                        TransformErrorPolicy::Skip => {
+                            let errors = self.error_count.fetch_add(1, Ordering::Relaxed) + 1;
+                            warn!("{:#}, dropping batch ({} dropped so far)", e, errors);
+                            Ok(None)
+                        }
+                    };
+                }
+            };
+        }
+        Ok(Some(batch))
+    }
+}
+
+/// Removes columns by name
+pub struct DropColumns {
+    names: Vec<String>,
+}
+
+impl DropColumns {
+    /// Create a transform removing the columns with the given names
+    pub fn new(names: Vec<String>) -> Self {
+        Self { names }
+    }
+
+    /// Names that do not match any column of `schema`
+    pub fn missing_columns(&self, schema: &Schema) -> Vec<&str> {
+        self.names
+            .iter()
+            .filter(|name| schema.index_of(name).is_err())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Indices of the columns of `schema` to keep
+    fn kept_indices(&self, schema: &Schema) -> Vec<usize> {
+        schema
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| !self.names.contains(field.name()))
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
+
+impl BatchTransform for DropColumns {
+    fn name(&self) -> &str {
+        "drop-columns"
+    }
+
+    fn output_schema(&self, input: SchemaRef) -> SchemaRef {
+        let indices = self.kept_indices(&input);
+        Arc::new(
+            input
+                .project(&indices)
+                .expect("indices come from the schema"),
+        )
+    }
+
+    fn transform(&mut self, batch: RecordBatch) -> Result<Option<RecordBatch>> {
+        let indices = self.kept_indices(&batch.schema());
+        Ok(Some(batch.project(&indices)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::builder::{Int32Builder, StringBuilder};
+    use arrow_array::{ArrayRef, Int32Array};
+    use arrow_schema::{DataType, Field};
+    use futures::StreamExt;
+    use object_store::{memory::InMemory, ObjectStore};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use tokio::sync::mpsc;
+
+    use crate::parquet_writer::{ParquetWriter, ParquetWriterConfig};
+    use crate::parquet_writer_task::ParquetWriterTask;
+
+    fn input_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("pid", DataType::Int32, false),
+            Field::new("process_name", DataType::Utf8, true),
+            Field::new("cycles", DataType::Int32, false),
+        ]))
+    }
+
+    fn batch(rows: &[(i32, &str, i32)]) -> RecordBatch {
+        let mut pid = Int32Builder::new();
+        let mut name = StringBuilder::new();
+        let mut cycles = Int32Builder::new();
+        for &(p, n, c) in rows {
+            pid.append_value(p);
+            name.append_value(n);
+            cycles.append_value(c);
+        }
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(pid.finish()),
+            Arc::new(name.finish()),
+            Arc::new(cycles.finish()),
+        ];
+        RecordBatch::try_new(input_schema(), arrays).unwrap()
+    }
+
+    /// Drops rows with few cycles, and whole batches left empty. Expects
+    /// only the pid and cycles columns.
+    struct MinCycles(i32);
+
+    impl BatchTransform for MinCycles {
+        fn name(&self) -> &str {
+            "min-cycles"
+        }
+
+        fn output_schema(&self, input: SchemaRef) -> SchemaRef {
+            input
+        }
+
+        fn transform(&mut self, batch: RecordBatch) -> Result<Option<RecordBatch>> {
+            let column = |name| {
+                batch
+                    .column_by_name(name)
+                    .and_then(|column| column.as_any().downcast_ref::<Int32Array>())
+                    .ok_or_else(|| anyhow!("no Int32 column {}", name))
+            };
+            let (pids, cycles) = (column("pid")?, column("cycles")?);
+
+            let (kept_pids, kept_cycles): (Vec<i32>, Vec<i32>) = pids
+                .values()
+                .iter()
+                .zip(cycles.values())
+                .filter(|(_, &cycles)| cycles >= self.0)
+                .unzip();
+            if kept_pids.is_empty() {
+                return Ok(None);
+            }
+
+            let arrays: Vec<ArrayRef> = vec![
+                Arc::new(Int32Array::from(kept_pids)),
+                Arc::new(Int32Array::from(kept_cycles)),
+            ];
+            Ok(Some(RecordBatch::try_new(batch.schema(), arrays)?))
+        }
+    }
+
+    /// Declares a schema it does not produce
+    struct Liar;
+
+    impl BatchTransform for Liar {
+        fn name(&self) -> &str {
+            "liar"
+        }
+
+        fn output_schema(&self, _input: SchemaRef) -> SchemaRef {
+            Arc::new(Schema::empty())
+        }
+
+        fn transform(&mut self, batch: RecordBatch) -> Result<Option<RecordBatch>> {
+            Ok(Some(batch))
+        }
+    }
+
+    fn chain(policy: TransformErrorPolicy) -> TransformChain {
+        let mut chain = TransformChain::new(input_schema(), policy);
+        chain.push(Box::new(DropColumns::new(vec!["process_name".to_string()])));
+        chain.push(Box::new(MinCycles(100)));
+        chain
+    }
+
+    #[test]
+    fn test_chain_schema_and_filtering() {
+        let mut chain = chain(TransformErrorPolicy::Abort);
+        let names: Vec<String> = chain
+            .output_schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect();
+        assert_eq!(names, vec!["pid", "cycles"]);
+
+        let output = chain
+            .apply(batch(&[(1, "a", 50), (2, "b", 150), (3, "c", 250)]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.schema(), chain.output_schema());
+        let pids = output
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        assert_eq!(pids.values(), &[2, 3]);
+
+        // A batch with no rows left is dropped
+        assert!(chain.apply(batch(&[(4, "d", 10)])).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_error_policy() {
+        let mut skip = TransformChain::new(input_schema(), TransformErrorPolicy::Skip);
+        skip.push(Box::new(Liar));
+        let errors = skip.error_counter();
+        assert!(skip.apply(batch(&[(1, "a", 1)])).unwrap().is_none());
+        assert!(skip.apply(batch(&[(1, "a", 1)])).unwrap().is_none());
+        assert_eq!(errors.load(Ordering::Relaxed), 2);
+
+        let mut abort = TransformChain::new(input_schema(), TransformErrorPolicy::Abort);
+        abort.push(Box::new(Liar));
+        assert!(abort.apply(batch(&[(1, "a", 1)])).is_err());
+        assert_eq!(abort.error_counter().load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_drop_columns_missing() {
+        let drop = DropColumns::new(vec!["process_name".to_string(), "cmdline".to_string()]);
+        assert_eq!(drop.missing_columns(&input_schema()), vec!["cmdline"]);
+    }
+
+    #[tokio::test]
+    async fn test_transforms_end_to_end() {
+        let chain = chain(TransformErrorPolicy::Abort);
+        let store = Arc::new(InMemory::new());
+        let writer = ParquetWriter::new(
+            store.clone(),
+            chain.output_schema(),
+            ParquetWriterConfig::default(),
+        )
+        .unwrap();
+
+        let (batch_sender, batch_receiver) = mpsc::channel(10);
+        let (_rotate_sender, rotate_receiver) = mpsc::channel(1);
+        let mut task = ParquetWriterTask::new(writer, batch_receiver, rotate_receiver);
+        task.set_transforms(chain);
+        let handle = tokio::spawn(task.run());
+
+        batch_sender
+            .send(batch(&[(1, "secret", 500), (2, "secret", 5)]))
+            .await
+            .unwrap();
+        batch_sender.send(batch(&[(3, "secret", 1)])).await.unwrap();
+        batch_sender
+            .send(batch(&[(4, "secret", 400)]))
+            .await
+            .unwrap();
+        drop(batch_sender);
+        handle.await.unwrap().unwrap();
+
+        let files: Vec<_> = store.list(None).collect().await;
+        assert_eq!(files.len(), 1);
+        let bytes = store
+            .get(&files[0].as_ref().unwrap().location)
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let batches: Vec<RecordBatch> = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let rows: Vec<(i32, i32)> = batches
+            .iter()
+            .flat_map(|batch| {
+                assert!(batch.schema().index_of("process_name").is_err());
+                let pids = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .unwrap();
+                let cycles = batch
+                    .column(1)
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .unwrap();
+                (0..batch.num_rows())
+                    .map(|i| (pids.value(i), cycles.value(i)))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(rows, vec![(1, 500), (4, 400)]);
+    }
+}
@@ -1,9 +1,10 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use log::error;
+use bpf::{msg_type, BpfLoaderConfig, ProgramGroup, TimerMigrationMsg};
+use perf_events::Dispatcher;
 
-use bpf::{msg_type, BpfLoader, TimerMigrationMsg};
+use crate::hot_path_log::error;
 
 /// BPF Error Handler manages error-related BPF events like timer migration and lost samples
 pub struct BpfErrorHandler {
@@ -11,17 +12,19 @@ pub struct BpfErrorHandler {
 }
 
 impl BpfErrorHandler {
-    /// Create a new BpfErrorHandler and subscribe to error events
-    pub fn new(bpf_loader: &mut BpfLoader) -> Rc<RefCell<Self>> {
+    /// Create a new BpfErrorHandler subscribed to the errors the loaded
+    /// program groups can report
+    pub fn new(dispatcher: &mut Dispatcher, groups: &BpfLoaderConfig) -> Rc<RefCell<Self>> {
         let handler = Rc::new(RefCell::new(Self {}));
 
         // Subscribe to timer migration events
-        let dispatcher = bpf_loader.dispatcher_mut();
-        dispatcher.subscribe_method(
-            msg_type::MSG_TYPE_TIMER_MIGRATION_DETECTED as u32,
-            handler.clone(),
-            BpfErrorHandler::handle_timer_migration,
-        );
+        if groups.is_enabled(ProgramGroup::SyncTimer) {
+            dispatcher.subscribe_method(
+                msg_type::MSG_TYPE_TIMER_MIGRATION_DETECTED as u32,
+                handler.clone(),
+                BpfErrorHandler::handle_timer_migration,
+            );
+        }
 
         // Subscribe to lost samples events
         let handler_clone = handler.clone();
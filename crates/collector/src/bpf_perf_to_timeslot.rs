@@ -1,15 +1,21 @@
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use log::error;
 use tokio::sync::mpsc;
 
-use bpf::{msg_type, BpfLoader, PerfMeasurementMsg};
+use bpf::{msg_type, PerfMeasurementMsg};
+use perf_events::{Dispatcher, LostRecord};
 use plain;
 
 use crate::bpf_task_tracker::BpfTaskTracker;
-use crate::bpf_timeslot_tracker::BpfTimeslotTracker;
+use crate::bpf_timeslot_tracker::{BpfTimeslotTracker, TIMESLOT_SIZE_NS};
+use crate::cgroup_filter::CgroupFilter;
+use crate::hot_path_log::error;
 use crate::metrics::Metric;
+use crate::pid_namespace::PidNamespaceTranslator;
+use crate::processor_log::{LogRecord, ProcessorRecorder, TimeslotDigest};
 use crate::timeslot_data::TimeslotData;
 
 /// Handles BPF performance measurements and composes them into timeslots
@@ -22,12 +28,22 @@ pub struct BpfPerfToTimeslot {
     last_error_report: std::time::Instant,
     // Task tracker for metadata lookup
     task_tracker: Rc<RefCell<BpfTaskTracker>>,
+    // Optional log of emitted timeslots
+    recorder: Option<Rc<RefCell<ProcessorRecorder>>>,
+    // Number of timeslots merged into each emitted one
+    slots_per_output: u32,
+    // Optional translation of pids into their container's pid namespace
+    pid_translator: Option<Rc<RefCell<PidNamespaceTranslator>>>,
+    // Optional filter of the cgroups whose tasks are collected
+    cgroup_filter: Option<Rc<RefCell<CgroupFilter>>>,
+    // Optional flag of frozen container attribution, sampled as timeslots complete
+    attribution_stale: Option<Arc<AtomicBool>>,
 }
 
 impl BpfPerfToTimeslot {
     /// Create a new BpfPerfToTimeslot processor
     pub fn new(
-        bpf_loader: &mut BpfLoader,
+        dispatcher: &mut Dispatcher,
         timeslot_tracker: Rc<RefCell<BpfTimeslotTracker>>,
         task_tracker: Rc<RefCell<BpfTaskTracker>>,
         timeslot_tx: mpsc::Sender<TimeslotData>,
@@ -38,6 +54,11 @@ impl BpfPerfToTimeslot {
             error_counter: 0u64,
             last_error_report: std::time::Instant::now(),
             task_tracker,
+            recorder: None,
+            slots_per_output: 1,
+            pid_translator: None,
+            cgroup_filter: None,
+            attribution_stale: None,
         }));
 
         // Set up timeslot event subscription using subscribe_method
@@ -46,19 +67,53 @@ impl BpfPerfToTimeslot {
             .subscribe_method(processor.clone(), BpfPerfToTimeslot::on_new_timeslot);
 
         // Set up BPF event subscriptions
-        {
-            let dispatcher = bpf_loader.dispatcher_mut();
-
-            dispatcher.subscribe_method(
-                msg_type::MSG_TYPE_PERF_MEASUREMENT as u32,
-                processor.clone(),
-                BpfPerfToTimeslot::handle_perf_measurement,
-            );
-        }
+        dispatcher.subscribe_method(
+            msg_type::MSG_TYPE_PERF_MEASUREMENT as u32,
+            processor.clone(),
+            BpfPerfToTimeslot::handle_perf_measurement,
+        );
+        let lost_processor = processor.clone();
+        dispatcher.subscribe_lost_samples(move |ring_index, data| {
+            lost_processor
+                .borrow_mut()
+                .handle_lost_record(ring_index, data);
+        });
 
         processor
     }
 
+    /// Log every completed timeslot to the recorder
+    pub fn set_recorder(&mut self, recorder: Rc<RefCell<ProcessorRecorder>>) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Record each task's pid inside its container using the given translator
+    pub fn set_pid_translator(&mut self, translator: Rc<RefCell<PidNamespaceTranslator>>) {
+        self.pid_translator = Some(translator);
+    }
+
+    /// Only collect tasks in the cgroups the filter allows
+    pub fn set_cgroup_filter(&mut self, filter: Rc<RefCell<CgroupFilter>>) {
+        self.cgroup_filter = Some(filter);
+    }
+
+    /// Mark timeslots completed while `stale` is set as having stale
+    /// attribution
+    pub fn set_attribution_stale(&mut self, stale: Arc<AtomicBool>) {
+        self.attribution_stale = Some(stale);
+    }
+
+    /// Merge `slots` consecutive timeslots into each emitted timeslot
+    ///
+    /// Merged timeslots are aligned to multiples of `slots` timeslots, so a
+    /// merged timeslot never spans a group boundary and keeps the start
+    /// timestamp of its first timeslot. Only adjacent timeslots merge: a gap
+    /// ends the merged timeslot, so one with fewer than `slots` timeslots in
+    /// `slots_merged` is partial and covers exactly the time it reports.
+    pub fn set_slots_per_output(&mut self, slots: u32) {
+        self.slots_per_output = slots.max(1);
+    }
+
     /// Handle performance measurement events
     fn handle_perf_measurement(&mut self, _ring_index: usize, data: &[u8]) {
         let event: &PerfMeasurementMsg = match plain::from_bytes(data) {
@@ -81,16 +136,64 @@ impl BpfPerfToTimeslot {
         // Look up task metadata and update timeslot data
         let pid = event.pid;
         let metadata = self.task_tracker.borrow().lookup(pid).cloned();
+        if let Some(ref filter) = self.cgroup_filter {
+            // Tasks without metadata have no known cgroup
+            let allowed = metadata
+                .as_ref()
+                .is_some_and(|metadata| filter.borrow_mut().allows(metadata.cgroup_id));
+            if !allowed {
+                return;
+            }
+        }
         self.current_timeslot.update(pid, metadata, metric);
+
+        if let Some(ref translator) = self.pid_translator {
+            if let Some(container_pid) = translator.borrow_mut().container_pid(pid) {
+                self.current_timeslot.set_container_pid(pid, container_pid);
+            }
+        }
+    }
+
+    /// Count samples the kernel lost on a full ring in the current timeslot
+    fn handle_lost_record(&mut self, _ring_index: usize, data: &[u8]) {
+        match LostRecord::parse(data) {
+            Some(record) => self.current_timeslot.lost_count += record.lost,
+            None => error!("Failed to parse lost record of {} bytes", data.len()),
+        }
     }
 
     /// Handle new timeslot events
     fn on_new_timeslot(&mut self, _old_timeslot: u64, new_timeslot: u64) {
+        // While merging, keep accumulating while the new timeslot directly
+        // follows the current one and stays in its group
+        let group_size = TIMESLOT_SIZE_NS * u64::from(self.slots_per_output);
+        let next_slot = self.current_timeslot.start_timestamp
+            + TIMESLOT_SIZE_NS * u64::from(self.current_timeslot.slots_merged);
+        if self.slots_per_output > 1
+            && new_timeslot == next_slot
+            && new_timeslot / group_size == self.current_timeslot.start_timestamp / group_size
+        {
+            self.current_timeslot.slots_merged += 1;
+            return;
+        }
+
         // Create a new empty timeslot with the new timestamp
         let new_timeslot_data = TimeslotData::new(new_timeslot);
 
         // Take ownership of the current timeslot, replacing it with the new one
-        let completed_timeslot = std::mem::replace(&mut self.current_timeslot, new_timeslot_data);
+        let mut completed_timeslot =
+            std::mem::replace(&mut self.current_timeslot, new_timeslot_data);
+        if let Some(ref stale) = self.attribution_stale {
+            completed_timeslot.attribution_stale = stale.load(Ordering::Relaxed);
+        }
+
+        if let Some(ref recorder) = self.recorder {
+            recorder
+                .borrow_mut()
+                .record(&LogRecord::Timeslot(TimeslotDigest::from_timeslot(
+                    &completed_timeslot,
+                )));
+        }
 
         // Try to send the completed timeslot to the writer
         if let Some(ref sender) = self.timeslot_tx {
@@ -123,3 +226,105 @@ impl BpfPerfToTimeslot {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bpf::BpfLoaderConfig;
+
+    /// Drive the processor through timeslot changes, returning the
+    /// (start timestamp, slots merged) of every emitted timeslot
+    fn emitted(slots_per_output: u32, timeslots: &[u64]) -> Vec<(u64, u32)> {
+        let mut dispatcher = Dispatcher::new();
+        let timeslot_tracker =
+            BpfTimeslotTracker::new(&mut dispatcher, 1, &BpfLoaderConfig::default());
+        let task_tracker = BpfTaskTracker::new(
+            &mut dispatcher,
+            timeslot_tracker.clone(),
+            &BpfLoaderConfig::default(),
+        );
+        let (timeslot_tx, mut timeslot_rx) = mpsc::channel(timeslots.len() + 1);
+        let processor =
+            BpfPerfToTimeslot::new(&mut dispatcher, timeslot_tracker, task_tracker, timeslot_tx);
+        processor
+            .borrow_mut()
+            .set_slots_per_output(slots_per_output);
+
+        let mut old_timeslot = 0;
+        for &new_timeslot in timeslots {
+            processor
+                .borrow_mut()
+                .on_new_timeslot(old_timeslot, new_timeslot);
+            old_timeslot = new_timeslot;
+        }
+
+        let mut emitted = Vec::new();
+        while let Ok(timeslot) = timeslot_rx.try_recv() {
+            emitted.push((timeslot.start_timestamp, timeslot.slots_merged));
+        }
+        emitted
+    }
+
+    #[test]
+    fn test_merged_timeslots_stay_aligned() {
+        let ms = TIMESLOT_SIZE_NS;
+        let timeslots: Vec<u64> = (4..=12).map(|slot| slot * ms).collect();
+
+        // Without merging, every timeslot is emitted
+        assert_eq!(emitted(1, &timeslots).len(), timeslots.len());
+
+        // The initial empty timeslot is emitted first, then groups of 4 aligned slots
+        assert_eq!(
+            emitted(4, &timeslots),
+            vec![(0, 1), (4 * ms, 4), (8 * ms, 4)]
+        );
+
+        // Starting mid-group only merges the rest of that group
+        let timeslots: Vec<u64> = (6..=9).map(|slot| slot * ms).collect();
+        assert_eq!(emitted(4, &timeslots), vec![(0, 1), (6 * ms, 2)]);
+    }
+
+    #[test]
+    fn test_gap_ends_merged_timeslot() {
+        let ms = TIMESLOT_SIZE_NS;
+
+        // Any gap emits the partial merged timeslot, within a group or across
+        assert_eq!(
+            emitted(4, &[4 * ms, 6 * ms, 9 * ms, 13 * ms]),
+            vec![(0, 1), (4 * ms, 1), (6 * ms, 1), (9 * ms, 1)]
+        );
+        assert_eq!(
+            emitted(4, &[4 * ms, 5 * ms, 7 * ms, 8 * ms, 9 * ms]),
+            vec![(0, 1), (4 * ms, 2), (7 * ms, 1)]
+        );
+    }
+
+    #[test]
+    fn test_attribution_stale_sampled_on_completion() {
+        let mut dispatcher = Dispatcher::new();
+        let timeslot_tracker =
+            BpfTimeslotTracker::new(&mut dispatcher, 1, &BpfLoaderConfig::default());
+        let task_tracker = BpfTaskTracker::new(
+            &mut dispatcher,
+            timeslot_tracker.clone(),
+            &BpfLoaderConfig::default(),
+        );
+        let (timeslot_tx, mut timeslot_rx) = mpsc::channel(4);
+        let processor =
+            BpfPerfToTimeslot::new(&mut dispatcher, timeslot_tracker, task_tracker, timeslot_tx);
+        let stale = Arc::new(AtomicBool::new(false));
+        processor.borrow_mut().set_attribution_stale(stale.clone());
+
+        // Attribution freezes after the first timeslot completed, while it
+        // still waits in the channel
+        let ms = TIMESLOT_SIZE_NS;
+        processor.borrow_mut().on_new_timeslot(0, ms);
+        stale.store(true, Ordering::Relaxed);
+        processor.borrow_mut().on_new_timeslot(ms, 2 * ms);
+
+        let flags: Vec<bool> = std::iter::from_fn(|| timeslot_rx.try_recv().ok())
+            .map(|timeslot| timeslot.attribution_stale)
+            .collect();
+        assert_eq!(flags, vec![false, true]);
+    }
+}
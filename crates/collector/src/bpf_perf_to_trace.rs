@@ -1,35 +1,125 @@
 use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
 use anyhow::{anyhow, Result};
 use arrow_array::builder::{BooleanBuilder, Int32Builder, Int64Builder, StringBuilder};
-use arrow_array::{ArrayRef, RecordBatch};
+use arrow_array::{ArrayRef, Int64Array, RecordBatch};
 use arrow_schema::{DataType, Field, Schema, SchemaRef};
-use log::error;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::{self, error::TrySendError};
 
-use bpf::{msg_type, BpfLoader, PerfMeasurementMsg};
+use bpf::{msg_type, switch_reason, PerfMeasurementMsg};
+use perf_events::{Arena, Dispatcher, LostRecord, RingIndexSemantics};
 use plain;
 
+use crate::attribution::{AttributionBuilder, AttributionLayout};
 use crate::bpf_task_tracker::BpfTaskTracker;
+use crate::cgroup_filter::{CgroupFilter, ContainerTable};
+use crate::hot_path_log::{error, info};
+use crate::pid_namespace::PidNamespaceTranslator;
 
-/// Create the schema for trace record batches
-pub fn create_schema() -> SchemaRef {
-    Arc::new(Schema::new(vec![
+/// Version of the trace schema, bumped whenever columns change. Version 5
+/// nested process_name and container_pid in a struct. Version 6 attributes rows to their pod and
+/// container in the last columns, with process_name and container_pid back
+/// at the top level. Version 7 made pid and cgroup_id nullable, null on rows
+/// of lost samples. Version 8 made cpu_id nullable, null on rows from rings
+/// that are not CPUs.
+pub const TRACE_SCHEMA_VERSION: u32 = 8;
+
+/// Columns holding hardware counter deltas
+pub const COUNTER_COLUMNS: [&str; 4] = ["cycles", "instructions", "llc_misses", "cache_references"];
+
+/// Builder bytes a row takes besides its strings: seven Int64 and five Int32
+/// values, an offset for each of the two string columns, and a byte covering
+/// the boolean column and the validity bits of the nullable columns
+const ROW_FIXED_BYTES: usize = 7 * 8 + 5 * 4 + 2 * 4 + 1;
+
+/// Bounds on the rows and memory the trace path holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceLimits {
+    /// Rows after which a batch is cut
+    pub max_batch_rows: usize,
+    /// Estimated builder bytes after which a batch is cut
+    pub max_batch_bytes: usize,
+    /// Estimated bytes of the batch being built plus the batches waiting in the
+    /// channel, beyond which measurements are dropped
+    pub max_in_flight_bytes: usize,
+}
+
+impl Default for TraceLimits {
+    fn default() -> Self {
+        Self {
+            max_batch_rows: 32 * 1024,
+            max_batch_bytes: 16 * 1024 * 1024,
+            max_in_flight_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Memory estimates of the trace path, shared so they can be reported
+/// outside the polling thread
+#[derive(Debug, Default)]
+pub struct TraceMemory {
+    /// Estimated bytes appended to the builders of the current batch
+    pub builder_bytes: AtomicUsize,
+    /// Estimated bytes of the batches sent and not yet taken from the channel
+    pub queued_bytes: AtomicUsize,
+    /// Highest builder plus queued bytes seen
+    pub peak_bytes: AtomicUsize,
+    /// Measurements dropped because the in-flight limit was reached, because
+    /// the writer stopped, or with a batch the writer could not take
+    pub dropped_events: AtomicU64,
+    /// Batches that could not be handed to the writer, because the channel
+    /// was full or the writer had stopped
+    pub dropped_batches: AtomicU64,
+}
+
+/// Create the schema for trace record batches, with the container identity
+/// in `layout`
+pub fn create_schema(layout: AttributionLayout) -> SchemaRef {
+    let mut fields = vec![
         Field::new("timestamp", DataType::Int64, false),
-        Field::new("pid", DataType::Int32, false),
+        // Null on rows of lost samples, which no task is known for
+        Field::new("pid", DataType::Int32, true),
         Field::new("process_name", DataType::Utf8, true),
-        Field::new("cgroup_id", DataType::Int64, false),
-        Field::new("cpu_id", DataType::Int32, false),
+        Field::new("cgroup_id", DataType::Int64, true),
+        // Null on rows from rings whose index is not a CPU
+        Field::new("cpu_id", DataType::Int32, true),
         Field::new("cycles", DataType::Int64, false),
         Field::new("instructions", DataType::Int64, false),
         Field::new("llc_misses", DataType::Int64, false),
         Field::new("cache_references", DataType::Int64, false),
         Field::new("is_context_switch", DataType::Boolean, false),
         Field::new("next_tgid", DataType::Int32, true),
-    ]))
+        Field::new("container_pid", DataType::Int32, true),
+        Field::new("prev_tgid", DataType::Int32, true),
+        Field::new("switch_reason", DataType::Utf8, true),
+        // Set only on rows recording samples the kernel lost on a full ring
+        Field::new("lost_count", DataType::Int64, true),
+    ];
+    // Pod and container of the row's cgroup, null unless it is collected
+    fields.extend(layout.fields());
+    Arc::new(Schema::new(fields))
+}
+
+/// Name of a context switch reason in the switch_reason column
+fn switch_reason_name(reason: u32) -> Option<&'static str> {
+    match reason {
+        switch_reason::SWITCH_REASON_VOLUNTARY => Some("voluntary"),
+        switch_reason::SWITCH_REASON_PREEMPT => Some("preempt"),
+        _ => None,
+    }
+}
+
+/// The task's comm as a string in the arena, without trailing NULs
+fn comm_str<'a>(comm: &[u8], arena: &'a Arena) -> &'a str {
+    let comm = std::str::from_utf8(comm)
+        .unwrap_or("<invalid utf8>")
+        .trim_end_matches(char::from(0));
+    arena.alloc_str(comm)
 }
 
 /// Handles BPF performance measurements and outputs individual trace events
@@ -48,26 +138,51 @@ pub struct BpfPerfToTrace {
     cache_references_builder: Int64Builder,
     is_context_switch_builder: BooleanBuilder,
     next_tgid_builder: Int32Builder,
+    container_pid_builder: Int32Builder,
+    prev_tgid_builder: Int32Builder,
+    switch_reason_builder: StringBuilder,
+    lost_count_builder: Int64Builder,
+    // Last timestamp of a measurement on each ring, the time of lost records
+    last_timestamps: Vec<u64>,
     // Channel for sending completed record batches
     batch_tx: Option<mpsc::Sender<RecordBatch>>,
     // Task tracker for metadata lookup
     task_tracker: Rc<RefCell<BpfTaskTracker>>,
+    // Optional translation of pids into their container's pid namespace
+    pid_translator: Option<Rc<RefCell<PidNamespaceTranslator>>>,
+    // Optional filter of the cgroups whose tasks are collected
+    cgroup_filter: Option<Rc<RefCell<CgroupFilter>>>,
+    // Collected containers the rows are attributed to, looked up per batch
+    containers: Option<ContainerTable>,
+    // What ring indices mean, to attribute rows to CPUs
+    ring_index_semantics: RingIndexSemantics,
     // Timing for periodic flushes
     last_flush: Instant,
-    // Capacity tracking
-    capacity: usize,
+    // Batch and memory bounds
+    limits: TraceLimits,
     current_rows: usize,
+    // Estimated bytes of the current batch's builders
+    builder_bytes: usize,
+    // Estimated bytes of each batch sent, oldest first, while it may still be in the channel
+    sent_batches: VecDeque<usize>,
+    queued_bytes: usize,
+    // Shared copy of the estimates, and whether measurements are being dropped
+    memory: Arc<TraceMemory>,
+    dropping: bool,
+    // Whether measurements are turned into rows, and how many were skipped while not
+    enabled: bool,
+    skipped_events: u64,
 }
 
 impl BpfPerfToTrace {
-    /// Create a new BpfPerfToTrace processor
+    /// Create a new BpfPerfToTrace processor cutting batches at `capacity` rows
     pub fn new(
-        bpf_loader: &mut BpfLoader,
+        dispatcher: &mut Dispatcher,
         task_tracker: Rc<RefCell<BpfTaskTracker>>,
         batch_tx: mpsc::Sender<RecordBatch>,
         capacity: usize,
     ) -> Rc<RefCell<Self>> {
-        let schema = create_schema();
+        let schema = create_schema(AttributionLayout::default());
 
         let processor = Rc::new(RefCell::new(Self {
             schema: schema.clone(),
@@ -82,29 +197,117 @@ impl BpfPerfToTrace {
             cache_references_builder: Int64Builder::with_capacity(capacity),
             is_context_switch_builder: BooleanBuilder::with_capacity(capacity),
             next_tgid_builder: Int32Builder::with_capacity(capacity),
+            container_pid_builder: Int32Builder::with_capacity(capacity),
+            prev_tgid_builder: Int32Builder::with_capacity(capacity),
+            switch_reason_builder: StringBuilder::with_capacity(capacity, capacity * 9),
+            lost_count_builder: Int64Builder::with_capacity(capacity),
+            last_timestamps: Vec::new(),
             batch_tx: Some(batch_tx),
             task_tracker,
+            pid_translator: None,
+            cgroup_filter: None,
+            containers: None,
+            ring_index_semantics: RingIndexSemantics::default(),
             last_flush: Instant::now(),
-            capacity,
+            limits: TraceLimits {
+                max_batch_rows: capacity,
+                ..Default::default()
+            },
             current_rows: 0,
+            builder_bytes: 0,
+            sent_batches: VecDeque::new(),
+            queued_bytes: 0,
+            memory: Arc::new(TraceMemory::default()),
+            dropping: false,
+            enabled: true,
+            skipped_events: 0,
         }));
 
         // Set up BPF event subscriptions
-        {
-            let dispatcher = bpf_loader.dispatcher_mut();
+        dispatcher.subscribe_method_with_arena(
+            msg_type::MSG_TYPE_PERF_MEASUREMENT as u32,
+            processor.clone(),
+            BpfPerfToTrace::handle_perf_measurement,
+        );
+        let lost_processor = processor.clone();
+        dispatcher.subscribe_lost_samples(move |ring_index, data| {
+            lost_processor
+                .borrow_mut()
+                .handle_lost_record(ring_index, data);
+        });
 
-            dispatcher.subscribe_method(
-                msg_type::MSG_TYPE_PERF_MEASUREMENT as u32,
-                processor.clone(),
-                BpfPerfToTrace::handle_perf_measurement,
-            );
+        processor
+    }
+
+    /// Fill the container_pid column using the given translator
+    pub fn set_pid_translator(&mut self, translator: Rc<RefCell<PidNamespaceTranslator>>) {
+        self.pid_translator = Some(translator);
+    }
+
+    /// Bound the rows and memory of batches and of the channel
+    ///
+    /// Builders are allocated for `max_batch_rows` rows from the next batch on.
+    pub fn set_limits(&mut self, limits: TraceLimits) {
+        self.limits = limits;
+    }
+
+    /// Memory estimates, updated as rows are appended and batches sent
+    pub fn memory(&self) -> Arc<TraceMemory> {
+        self.memory.clone()
+    }
+
+    /// Only emit rows for tasks in the cgroups the filter allows
+    pub fn set_cgroup_filter(&mut self, filter: Rc<RefCell<CgroupFilter>>) {
+        self.cgroup_filter = Some(filter);
+    }
+
+    /// Lay the container identity out as `layout`, attributing rows to the
+    /// containers in `containers` if given
+    pub fn set_attribution(
+        &mut self,
+        layout: AttributionLayout,
+        containers: Option<ContainerTable>,
+    ) {
+        self.schema = create_schema(layout);
+        self.containers = containers;
+    }
+
+    /// Attribute rows to the CPU of their ring as `semantics` defines it
+    pub fn set_ring_index_semantics(&mut self, semantics: RingIndexSemantics) {
+        self.ring_index_semantics = semantics;
+    }
+
+    /// Enable or disable emitting trace rows
+    ///
+    /// Disabling flushes the rows built so far; measurements arriving while
+    /// disabled are dropped and counted.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled == self.enabled {
+            return;
         }
 
-        processor
+        if enabled {
+            info!(
+                "Resuming trace output, skipped {} measurements",
+                self.skipped_events
+            );
+            self.skipped_events = 0;
+        } else if let Err(e) = self.flush_batch() {
+            error!("Failed to flush trace batch: {}", e);
+        }
+        self.enabled = enabled;
     }
 
     /// Handle performance measurement events
-    fn handle_perf_measurement(&mut self, ring_index: usize, data: &[u8]) {
+    fn handle_perf_measurement(&mut self, ring_index: usize, data: &[u8], arena: &Arena) {
+        if !self.enabled {
+            self.skipped_events += 1;
+            return;
+        }
+        if !self.writer_running() {
+            return;
+        }
+
         let event: &PerfMeasurementMsg = match plain::from_bytes(data) {
             Ok(event) => event,
             Err(e) => {
@@ -113,28 +316,48 @@ impl BpfPerfToTrace {
             }
         };
 
+        // Look up task metadata for process name and cgroup_id, copying the
+        // name to the arena so the tracker is not borrowed while appending
+        let task = self
+            .task_tracker
+            .borrow()
+            .lookup(event.pid)
+            .map(|metadata| (comm_str(&metadata.comm, arena), metadata.cgroup_id));
+        if let Some(ref filter) = self.cgroup_filter {
+            // Tasks without metadata have no known cgroup
+            let allowed = task.is_some_and(|(_, cgroup_id)| filter.borrow_mut().allows(cgroup_id));
+            if !allowed {
+                return;
+            }
+        }
+
+        // Stop growing once the builders and the channel hold too much
+        let reason = (event.is_context_switch != 0)
+            .then(|| switch_reason_name(event.switch_reason))
+            .flatten();
+        let row_bytes =
+            ROW_FIXED_BYTES + task.map_or(0, |(comm, _)| comm.len()) + reason.map_or(0, str::len);
+        if !self.reserve_row(row_bytes) {
+            return;
+        }
+        self.ensure_ring(ring_index);
+        self.last_timestamps[ring_index] = event.header.timestamp;
+
         // Add event data to builders
         self.timestamp_builder
             .append_value(event.header.timestamp as i64);
         self.pid_builder.append_value(event.pid as i32);
 
-        // Look up task metadata for process name and cgroup_id
-        if let Some(metadata) = self.task_tracker.borrow().lookup(event.pid) {
-            // Convert bytes to string, trimming null bytes
-            let comm = std::str::from_utf8(&metadata.comm)
-                .unwrap_or("<invalid utf8>")
-                .trim_end_matches(char::from(0))
-                .to_string();
+        if let Some((comm, cgroup_id)) = task {
             self.process_name_builder.append_value(comm);
-            self.cgroup_id_builder
-                .append_value(metadata.cgroup_id as i64);
+            self.cgroup_id_builder.append_value(cgroup_id as i64);
         } else {
             self.process_name_builder.append_null();
             self.cgroup_id_builder.append_value(0); // Default value when no metadata available
         }
 
-        // Add CPU ID from ring index (ring index corresponds to CPU ID)
-        self.cpu_id_builder.append_value(ring_index as i32);
+        // Add the CPU the ring belongs to
+        self.append_cpu_id(ring_index);
 
         // Add performance counter deltas
         self.cycles_builder.append_value(event.cycles_delta as i64);
@@ -149,17 +372,116 @@ impl BpfPerfToTrace {
         self.is_context_switch_builder
             .append_value(event.is_context_switch != 0);
 
-        // Add next TGID field - only valid for context switch events, null for timer events
+        // Add next and previous TGID and the switch reason - only valid for
+        // context switch events, null for timer events
         if event.is_context_switch != 0 {
             self.next_tgid_builder.append_value(event.next_tgid as i32);
+            self.prev_tgid_builder.append_value(event.prev_tgid as i32);
+            self.switch_reason_builder.append_option(reason);
         } else {
             self.next_tgid_builder.append_null();
+            self.prev_tgid_builder.append_null();
+            self.switch_reason_builder.append_null();
         }
 
+        // Add the pid inside the task's container, when known
+        let container_pid = self
+            .pid_translator
+            .as_ref()
+            .and_then(|translator| translator.borrow_mut().container_pid(event.pid));
+        self.container_pid_builder
+            .append_option(container_pid.map(|pid| pid as i32));
+        self.lost_count_builder.append_null();
+
+        self.finish_row(row_bytes);
+    }
+
+    /// Handle a record of samples the kernel dropped because the ring was full
+    ///
+    /// The loss is emitted as a row with `lost_count` set, at the last
+    /// timestamp seen on the ring since the record carries none.
+    fn handle_lost_record(&mut self, ring_index: usize, data: &[u8]) {
+        if !self.enabled {
+            self.skipped_events += 1;
+            return;
+        }
+        if !self.writer_running() {
+            return;
+        }
+
+        let Some(record) = LostRecord::parse(data) else {
+            error!("Failed to parse lost record of {} bytes", data.len());
+            return;
+        };
+
+        if !self.reserve_row(ROW_FIXED_BYTES) {
+            return;
+        }
+        self.ensure_ring(ring_index);
+        self.timestamp_builder
+            .append_value(self.last_timestamps[ring_index] as i64);
+        self.pid_builder.append_null();
+        self.process_name_builder.append_null();
+        self.cgroup_id_builder.append_null();
+        self.append_cpu_id(ring_index);
+        self.cycles_builder.append_value(0);
+        self.instructions_builder.append_value(0);
+        self.llc_misses_builder.append_value(0);
+        self.cache_references_builder.append_value(0);
+        self.is_context_switch_builder.append_value(false);
+        self.next_tgid_builder.append_null();
+        self.prev_tgid_builder.append_null();
+        self.switch_reason_builder.append_null();
+        self.container_pid_builder.append_null();
+        self.lost_count_builder.append_value(record.lost as i64);
+
+        self.finish_row(ROW_FIXED_BYTES);
+    }
+
+    /// Append the CPU of the ring at `ring_index`, null if it is not a CPU
+    fn append_cpu_id(&mut self, ring_index: usize) {
+        let cpu = self.ring_index_semantics.ring_cpu(ring_index);
+        self.cpu_id_builder.append_option(cpu.map(|cpu| cpu as i32));
+    }
+
+    /// Grow the per-ring state to cover `ring_index`
+    fn ensure_ring(&mut self, ring_index: usize) {
+        if ring_index >= self.last_timestamps.len() {
+            self.last_timestamps.resize(ring_index + 1, 0);
+        }
+    }
+
+    /// Check that a row of `row_bytes` fits the in-flight limit, dropping it
+    /// and handing the rows built so far to the writer otherwise
+    fn reserve_row(&mut self, row_bytes: usize) -> bool {
+        self.update_queued_bytes();
+        if self.builder_bytes + self.queued_bytes + row_bytes > self.limits.max_in_flight_bytes {
+            // The writer frees memory as it drains the channel
+            if let Err(e) = self.flush_batch() {
+                error!("Failed to flush trace batch: {}", e);
+            }
+            self.drop_event();
+            return false;
+        }
+        if self.dropping {
+            self.dropping = false;
+            info!(
+                "Trace memory back under the limit, {} measurements dropped so far",
+                self.memory.dropped_events.load(Ordering::Relaxed)
+            );
+        }
+        true
+    }
+
+    /// Account for a row appended to the builders, flushing the batch when full
+    fn finish_row(&mut self, row_bytes: usize) {
         self.current_rows += 1;
+        self.builder_bytes += row_bytes;
+        self.publish_memory();
 
         // Check if we should flush
-        let should_flush_capacity = self.current_rows >= self.capacity;
+        let should_flush_capacity = self.current_rows >= self.limits.max_batch_rows
+            || self.builder_bytes >= self.limits.max_batch_bytes;
         let should_flush_time = self.last_flush.elapsed().as_secs() >= 1;
 
         if should_flush_capacity || should_flush_time {
@@ -169,6 +491,64 @@ impl BpfPerfToTrace {
         }
     }
 
+    /// Count a measurement dropped for lack of memory
+    fn drop_event(&mut self) {
+        self.memory.dropped_events.fetch_add(1, Ordering::Relaxed);
+        if !self.dropping {
+            self.dropping = true;
+            error!(
+                "Trace memory limit of {} bytes reached, dropping measurements",
+                self.limits.max_in_flight_bytes
+            );
+        }
+    }
+
+    /// Whether batches can still be sent. Once the writer stopped, or after
+    /// shutdown, measurements are dropped rather than built into batches.
+    fn writer_running(&mut self) -> bool {
+        if self.batch_tx.is_some() {
+            return true;
+        }
+        self.memory.dropped_events.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// Count a batch that could not be handed to the writer, and the
+    /// measurements it held
+    fn drop_batch(&mut self, rows: usize) {
+        self.memory.dropped_batches.fetch_add(1, Ordering::Relaxed);
+        self.memory
+            .dropped_events
+            .fetch_add(rows as u64, Ordering::Relaxed);
+    }
+
+    /// Forget the batches the writer has taken from the channel. The channel
+    /// is FIFO, so the batches still queued are the most recently sent.
+    fn update_queued_bytes(&mut self) {
+        let queued = self
+            .batch_tx
+            .as_ref()
+            .map_or(0, |sender| sender.max_capacity() - sender.capacity());
+        while self.sent_batches.len() > queued {
+            if let Some(bytes) = self.sent_batches.pop_front() {
+                self.queued_bytes -= bytes;
+            }
+        }
+    }
+
+    /// Copy the estimates to the shared [`TraceMemory`]
+    fn publish_memory(&self) {
+        self.memory
+            .builder_bytes
+            .store(self.builder_bytes, Ordering::Relaxed);
+        self.memory
+            .queued_bytes
+            .store(self.queued_bytes, Ordering::Relaxed);
+        self.memory
+            .peak_bytes
+            .fetch_max(self.builder_bytes + self.queued_bytes, Ordering::Relaxed);
+    }
+
     /// Flush current batch and send it
     fn flush_batch(&mut self) -> Result<()> {
         if self.current_rows == 0 {
@@ -176,11 +556,13 @@ impl BpfPerfToTrace {
         }
 
         // Finish building arrays
-        let arrays: Vec<ArrayRef> = vec![
+        let cgroup_ids = self.cgroup_id_builder.finish();
+        let attribution = self.attribution(&cgroup_ids);
+        let mut arrays: Vec<ArrayRef> = vec![
             Arc::new(self.timestamp_builder.finish()),
             Arc::new(self.pid_builder.finish()),
             Arc::new(self.process_name_builder.finish()),
-            Arc::new(self.cgroup_id_builder.finish()),
+            Arc::new(cgroup_ids),
             Arc::new(self.cpu_id_builder.finish()),
             Arc::new(self.cycles_builder.finish()),
             Arc::new(self.instructions_builder.finish()),
@@ -188,37 +570,80 @@ impl BpfPerfToTrace {
             Arc::new(self.cache_references_builder.finish()),
             Arc::new(self.is_context_switch_builder.finish()),
             Arc::new(self.next_tgid_builder.finish()),
+            Arc::new(self.container_pid_builder.finish()),
+            Arc::new(self.prev_tgid_builder.finish()),
+            Arc::new(self.switch_reason_builder.finish()),
+            Arc::new(self.lost_count_builder.finish()),
         ];
+        attribution.finish(&mut arrays);
 
         // Create record batch
         let batch = RecordBatch::try_new(self.schema.clone(), arrays)
             .map_err(|e| anyhow!("Failed to create trace RecordBatch: {}", e))?;
 
-        // Send the batch
-        if let Some(ref sender) = self.batch_tx {
-            if let Err(_) = sender.try_send(batch) {
-                error!("Failed to send trace batch: channel full or closed");
+        // Send the batch, accounting for it until the writer takes it
+        match self.batch_tx.as_ref().map(|sender| sender.try_send(batch)) {
+            Some(Ok(())) => {
+                self.sent_batches.push_back(self.builder_bytes);
+                self.queued_bytes += self.builder_bytes;
+            }
+            Some(Err(TrySendError::Full(batch))) => {
+                error!("Failed to send trace batch: channel full");
+                self.drop_batch(batch.num_rows());
             }
+            Some(Err(TrySendError::Closed(batch))) => {
+                // The writer finished, so it would not take later batches either
+                error!("Trace batch writer stopped, no longer producing batches");
+                self.batch_tx = None;
+                self.drop_batch(batch.num_rows());
+            }
+            None => self.drop_batch(self.current_rows),
         }
 
         // Reset builders and counters
-        self.timestamp_builder = Int64Builder::with_capacity(self.capacity);
-        self.pid_builder = Int32Builder::with_capacity(self.capacity);
-        self.process_name_builder = StringBuilder::with_capacity(self.capacity, self.capacity * 16);
-        self.cgroup_id_builder = Int64Builder::with_capacity(self.capacity);
-        self.cpu_id_builder = Int32Builder::with_capacity(self.capacity);
-        self.cycles_builder = Int64Builder::with_capacity(self.capacity);
-        self.instructions_builder = Int64Builder::with_capacity(self.capacity);
-        self.llc_misses_builder = Int64Builder::with_capacity(self.capacity);
-        self.cache_references_builder = Int64Builder::with_capacity(self.capacity);
-        self.is_context_switch_builder = BooleanBuilder::with_capacity(self.capacity);
-        self.next_tgid_builder = Int32Builder::with_capacity(self.capacity);
+        let capacity = self.limits.max_batch_rows;
+        self.timestamp_builder = Int64Builder::with_capacity(capacity);
+        self.pid_builder = Int32Builder::with_capacity(capacity);
+        self.process_name_builder = StringBuilder::with_capacity(capacity, capacity * 16);
+        self.cgroup_id_builder = Int64Builder::with_capacity(capacity);
+        self.cpu_id_builder = Int32Builder::with_capacity(capacity);
+        self.cycles_builder = Int64Builder::with_capacity(capacity);
+        self.instructions_builder = Int64Builder::with_capacity(capacity);
+        self.llc_misses_builder = Int64Builder::with_capacity(capacity);
+        self.cache_references_builder = Int64Builder::with_capacity(capacity);
+        self.is_context_switch_builder = BooleanBuilder::with_capacity(capacity);
+        self.next_tgid_builder = Int32Builder::with_capacity(capacity);
+        self.container_pid_builder = Int32Builder::with_capacity(capacity);
+        self.prev_tgid_builder = Int32Builder::with_capacity(capacity);
+        self.switch_reason_builder = StringBuilder::with_capacity(capacity, capacity * 9);
+        self.lost_count_builder = Int64Builder::with_capacity(capacity);
         self.current_rows = 0;
+        self.builder_bytes = 0;
         self.last_flush = Instant::now();
+        self.publish_memory();
 
         Ok(())
     }
 
+    /// The identity columns of the rows of `cgroup_ids`, looking the
+    /// containers of a batch up at once
+    fn attribution(&self, cgroup_ids: &Int64Array) -> AttributionBuilder {
+        let mut builder =
+            AttributionBuilder::new(AttributionLayout::of(&self.schema), cgroup_ids.len());
+        let containers = self
+            .containers
+            .as_ref()
+            .map_or_else(Default::default, |table| {
+                let ids: HashSet<u64> = cgroup_ids.values().iter().map(|&id| id as u64).collect();
+                table.get(&ids)
+            });
+        builder.append_cgroups(
+            cgroup_ids.iter().map(|id| id.map(|id| id as u64)),
+            &containers,
+        );
+        builder
+    }
+
     /// Shutdown the processor and close the batch channel
     pub fn shutdown(&mut self) {
         // Flush any remaining data
@@ -230,5 +655,475 @@ impl BpfPerfToTrace {
         if let Some(sender) = self.batch_tx.take() {
             drop(sender);
         }
+
+        let dropped = self.memory.dropped_batches.load(Ordering::Relaxed);
+        if dropped > 0 {
+            error!(
+                "{} trace batches could not be enqueued for the writer",
+                dropped
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, Int32Array, Int64Array, StringArray};
+    use bpf::BpfLoaderConfig;
+    use perf_events::{collector_sample_record, lost_record, PERF_RECORD_LOST, PERF_RECORD_SAMPLE};
+
+    use crate::attribution::ContainerIdentity;
+    use crate::bpf_timeslot_tracker::BpfTimeslotTracker;
+    use crate::cgroup_filter::ContainerInfo;
+    use crate::task_metadata::TaskMetadata;
+
+    /// A perf measurement from `pid`, as a context switch to `next_tgid` when
+    /// a switch reason is given
+    fn measurement(timestamp: u64, pid: u32, next_tgid: u32, reason: Option<u32>) -> Vec<u8> {
+        let mut msg = PerfMeasurementMsg {
+            pid,
+            ..Default::default()
+        };
+        if let Some(reason) = reason {
+            msg.is_context_switch = 1;
+            msg.next_tgid = next_tgid;
+            msg.prev_tgid = pid;
+            msg.switch_reason = reason;
+        }
+        collector_sample_record(msg_type::MSG_TYPE_PERF_MEASUREMENT as u32, timestamp, &msg)
+    }
+
+    #[test]
+    fn test_context_switch_columns() {
+        let mut dispatcher = Dispatcher::new();
+        let timeslot_tracker =
+            BpfTimeslotTracker::new(&mut dispatcher, 1, &BpfLoaderConfig::default());
+        let task_tracker = BpfTaskTracker::new(
+            &mut dispatcher,
+            timeslot_tracker,
+            &BpfLoaderConfig::default(),
+        );
+        let (batch_tx, mut batch_rx) = mpsc::channel(1);
+        let processor = BpfPerfToTrace::new(&mut dispatcher, task_tracker, batch_tx, 16);
+
+        let events = [
+            measurement(1000, 100, 200, Some(switch_reason::SWITCH_REASON_PREEMPT)),
+            measurement(2000, 200, 0, None),
+            measurement(3000, 200, 0, Some(switch_reason::SWITCH_REASON_VOLUNTARY)),
+        ];
+        let arena = Arena::new();
+        for event in &events {
+            processor
+                .borrow_mut()
+                .handle_perf_measurement(0, event, &arena);
+        }
+        processor.borrow_mut().shutdown();
+
+        let batch = batch_rx.try_recv().unwrap();
+        assert_eq!(batch.schema(), create_schema(AttributionLayout::default()));
+        assert_eq!(batch.num_rows(), 3);
+
+        let int_column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap()
+                .clone()
+        };
+        let next_tgid = int_column("next_tgid");
+        let prev_tgid = int_column("prev_tgid");
+        let reason = batch
+            .column_by_name("switch_reason")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+
+        assert_eq!(next_tgid.value(0), 200);
+        assert_eq!(prev_tgid.value(0), 100);
+        assert_eq!(reason.value(0), "preempt");
+
+        // Timer events leave the context switch columns null
+        assert!(next_tgid.is_null(1));
+        assert!(prev_tgid.is_null(1));
+        assert!(reason.is_null(1));
+
+        assert_eq!(next_tgid.value(2), 0);
+        assert_eq!(prev_tgid.value(2), 200);
+        assert_eq!(reason.value(2), "voluntary");
+    }
+
+    #[test]
+    fn test_process_name_from_arena() {
+        let mut dispatcher = Dispatcher::new();
+        let timeslot_tracker =
+            BpfTimeslotTracker::new(&mut dispatcher, 1, &BpfLoaderConfig::default());
+        let task_tracker = BpfTaskTracker::new(
+            &mut dispatcher,
+            timeslot_tracker,
+            &BpfLoaderConfig::default(),
+        );
+        let mut comm = [0u8; 16];
+        comm[..5].copy_from_slice(b"nginx");
+        task_tracker
+            .borrow_mut()
+            .add_task(TaskMetadata::new(100, comm, 42));
+        let (batch_tx, mut batch_rx) = mpsc::channel(1);
+        let processor = BpfPerfToTrace::new(&mut dispatcher, task_tracker, batch_tx, 16);
+
+        let mut arena = Arena::new();
+        for (timestamp, pid) in [(1000, 100), (2000, 300), (3000, 100)] {
+            let event = measurement(timestamp, pid, 0, None);
+            processor
+                .borrow_mut()
+                .handle_perf_measurement(0, &event, &arena);
+        }
+        // Only known tasks' names were copied to the arena
+        assert_eq!(arena.allocated_bytes(), 10);
+        arena.reset();
+        assert_eq!(arena.allocated_bytes(), 0);
+        processor.borrow_mut().shutdown();
+
+        let batch = batch_rx.try_recv().unwrap();
+        let names = batch
+            .column_by_name("process_name")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(names.value(0), "nginx");
+        assert!(names.is_null(1));
+        assert_eq!(names.value(2), "nginx");
+    }
+
+    #[test]
+    fn test_attribution_by_cgroup() {
+        let mut dispatcher = Dispatcher::new();
+        let timeslot_tracker =
+            BpfTimeslotTracker::new(&mut dispatcher, 1, &BpfLoaderConfig::default());
+        let task_tracker = BpfTaskTracker::new(
+            &mut dispatcher,
+            timeslot_tracker,
+            &BpfLoaderConfig::default(),
+        );
+        for (pid, cgroup_id) in [(100, 42), (200, 43)] {
+            task_tracker
+                .borrow_mut()
+                .add_task(TaskMetadata::new(pid, [0u8; 16], cgroup_id));
+        }
+        let containers = ContainerTable::default();
+        containers.set(
+            42,
+            ContainerInfo {
+                identity: ContainerIdentity {
+                    pod_namespace: "prod".to_string(),
+                    pod_name: "web-0".to_string(),
+                    container_name: "web".to_string(),
+                    container_id: "c1".to_string(),
+                },
+                limits: Default::default(),
+            },
+        );
+        let (batch_tx, mut batch_rx) = mpsc::channel(1);
+        let processor = BpfPerfToTrace::new(&mut dispatcher, task_tracker, batch_tx, 16);
+        processor
+            .borrow_mut()
+            .set_attribution(AttributionLayout::Flat, Some(containers));
+
+        let arena = Arena::new();
+        for (timestamp, pid) in [(1000, 100), (2000, 200), (3000, 300)] {
+            let event = measurement(timestamp, pid, 0, None);
+            processor
+                .borrow_mut()
+                .handle_perf_measurement(0, &event, &arena);
+        }
+        processor.borrow_mut().shutdown();
+
+        let batch = batch_rx.try_recv().unwrap();
+        assert_eq!(batch.schema(), create_schema(AttributionLayout::Flat));
+        let pod_name = batch
+            .column_by_name("pod_name")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        // Only the task in the collected container's cgroup is attributed
+        assert_eq!(pod_name.value(0), "web-0");
+        assert!(pod_name.is_null(1));
+        assert!(pod_name.is_null(2));
+    }
+
+    /// A processor knowing task 100 as "nginx", with the given limits
+    fn limited_processor(
+        batch_tx: mpsc::Sender<RecordBatch>,
+        limits: TraceLimits,
+    ) -> Rc<RefCell<BpfPerfToTrace>> {
+        let mut dispatcher = Dispatcher::new();
+        let timeslot_tracker =
+            BpfTimeslotTracker::new(&mut dispatcher, 1, &BpfLoaderConfig::default());
+        let task_tracker = BpfTaskTracker::new(
+            &mut dispatcher,
+            timeslot_tracker,
+            &BpfLoaderConfig::default(),
+        );
+        let mut comm = [0u8; 16];
+        comm[..5].copy_from_slice(b"nginx");
+        task_tracker
+            .borrow_mut()
+            .add_task(TaskMetadata::new(100, comm, 42));
+        let processor = BpfPerfToTrace::new(
+            &mut dispatcher,
+            task_tracker,
+            batch_tx,
+            limits.max_batch_rows,
+        );
+        processor.borrow_mut().set_limits(limits);
+        processor
+    }
+
+    /// Feed `count` measurements of task 100, every other one a context switch
+    /// when `switches` is set
+    fn storm(processor: &Rc<RefCell<BpfPerfToTrace>>, count: u64, switches: bool) {
+        let arena = Arena::new();
+        for i in 0..count {
+            let reason = (switches && i % 2 == 0).then_some(switch_reason::SWITCH_REASON_PREEMPT);
+            let event = measurement(1000 + i, 100, 200, reason);
+            processor
+                .borrow_mut()
+                .handle_perf_measurement(0, &event, &arena);
+        }
+    }
+
+    /// Bytes of the buffers holding a batch's values, offsets and validity
+    fn batch_data_bytes(batch: &RecordBatch) -> usize {
+        batch
+            .columns()
+            .iter()
+            .map(|column| {
+                let data = column.to_data();
+                data.buffers()
+                    .iter()
+                    .map(|buffer| buffer.len())
+                    .sum::<usize>()
+                    + data.nulls().map_or(0, |nulls| nulls.buffer().len())
+            })
+            .sum()
+    }
+
+    fn in_flight_bytes(memory: &TraceMemory) -> usize {
+        memory.builder_bytes.load(Ordering::Relaxed) + memory.queued_bytes.load(Ordering::Relaxed)
+    }
+
+    fn assert_close(estimate: usize, actual: usize) {
+        let error = estimate.abs_diff(actual) as f64 / actual as f64;
+        assert!(error < 0.1, "estimate {} vs actual {}", estimate, actual);
+    }
+
+    #[test]
+    fn test_batches_cut_by_memory() {
+        let (batch_tx, mut batch_rx) = mpsc::channel(16);
+        let processor = limited_processor(
+            batch_tx,
+            TraceLimits {
+                max_batch_rows: 1000,
+                max_batch_bytes: 1000,
+                max_in_flight_bytes: 1024 * 1024,
+            },
+        );
+
+        // Rows take 97 bytes with a switch reason and 90 without, so the byte
+        // limit cuts batches at 11 rows, well before the row limit
+        storm(&processor, 104, true);
+        let memory = processor.borrow().memory();
+        let queued = memory.queued_bytes.load(Ordering::Relaxed);
+        let building = memory.builder_bytes.load(Ordering::Relaxed);
+        assert_eq!(
+            building,
+            2 * (ROW_FIXED_BYTES + 5 + 7) + 3 * (ROW_FIXED_BYTES + 5)
+        );
+        processor.borrow_mut().shutdown();
+
+        let mut batches = Vec::new();
+        while let Ok(batch) = batch_rx.try_recv() {
+            batches.push(batch);
+        }
+        let rows: Vec<usize> = batches.iter().map(|batch| batch.num_rows()).collect();
+        assert_eq!(rows, [11, 11, 11, 11, 11, 11, 11, 11, 11, 5]);
+        assert_eq!(memory.dropped_events.load(Ordering::Relaxed), 0);
+
+        // Estimates track the memory the batches take
+        let (last, full) = batches.split_last().unwrap();
+        assert_close(queued, full.iter().map(batch_data_bytes).sum());
+        assert_close(building, batch_data_bytes(last));
+    }
+
+    #[test]
+    fn test_drops_beyond_in_flight_limit() {
+        let row_bytes = ROW_FIXED_BYTES + 5;
+        let (batch_tx, mut batch_rx) = mpsc::channel(3);
+        let processor = limited_processor(
+            batch_tx,
+            TraceLimits {
+                max_batch_rows: 4,
+                max_batch_bytes: 1024 * 1024,
+                max_in_flight_bytes: 10 * row_bytes,
+            },
+        );
+        let memory = processor.borrow().memory();
+
+        // Two batches wait in the channel and two rows are built; the next
+        // row hands those to the channel and everything after is dropped
+        storm(&processor, 100, false);
+        assert_eq!(memory.dropped_events.load(Ordering::Relaxed), 90);
+        assert_eq!(in_flight_bytes(&memory), 10 * row_bytes);
+        assert_eq!(memory.peak_bytes.load(Ordering::Relaxed), 10 * row_bytes);
+
+        let mut rows = Vec::new();
+        while let Ok(batch) = batch_rx.try_recv() {
+            rows.push(batch.num_rows());
+        }
+        assert_eq!(rows, [4, 4, 2]);
+
+        // Once the writer drains the channel, rows are accepted again
+        storm(&processor, 1, false);
+        assert_eq!(memory.dropped_events.load(Ordering::Relaxed), 90);
+        assert_eq!(in_flight_bytes(&memory), row_bytes);
+        processor.borrow_mut().shutdown();
+        assert_eq!(batch_rx.try_recv().unwrap().num_rows(), 1);
+    }
+
+    #[test]
+    fn test_batches_dropped_when_writer_stops() {
+        testing_logger::setup();
+        let (batch_tx, batch_rx) = mpsc::channel(1);
+        let processor = limited_processor(
+            batch_tx,
+            TraceLimits {
+                max_batch_rows: 4,
+                ..Default::default()
+            },
+        );
+        let memory = processor.borrow().memory();
+
+        // The first batch fills the channel, the next two find it full and
+        // their measurements are counted as dropped
+        storm(&processor, 12, false);
+        assert_eq!(memory.dropped_batches.load(Ordering::Relaxed), 2);
+        assert_eq!(memory.dropped_events.load(Ordering::Relaxed), 8);
+
+        // Once the writer stops, the batch being built is dropped and no
+        // further batches are produced
+        drop(batch_rx);
+        storm(&processor, 10, false);
+        assert_eq!(memory.dropped_batches.load(Ordering::Relaxed), 3);
+        assert_eq!(memory.dropped_events.load(Ordering::Relaxed), 18);
+        processor.borrow_mut().shutdown();
+        assert_eq!(memory.dropped_batches.load(Ordering::Relaxed), 3);
+
+        testing_logger::validate(|captured_logs| {
+            let bodies: Vec<&str> = captured_logs
+                .iter()
+                .filter(|log| log.level == log::Level::Error)
+                .map(|log| log.body.as_str())
+                .collect();
+            assert_eq!(
+                bodies,
+                [
+                    "Failed to send trace batch: channel full",
+                    "Failed to send trace batch: channel full",
+                    "Trace batch writer stopped, no longer producing batches",
+                    "3 trace batches could not be enqueued for the writer",
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_lost_record_row() {
+        let mut dispatcher = Dispatcher::new();
+        let timeslot_tracker =
+            BpfTimeslotTracker::new(&mut dispatcher, 2, &BpfLoaderConfig::default());
+        let task_tracker = BpfTaskTracker::new(
+            &mut dispatcher,
+            timeslot_tracker,
+            &BpfLoaderConfig::default(),
+        );
+        let (batch_tx, mut batch_rx) = mpsc::channel(1);
+        let processor = BpfPerfToTrace::new(&mut dispatcher, task_tracker, batch_tx, 16);
+
+        // A measurement on CPU 1, then the kernel reports 7 samples lost there
+        let event = measurement(5000, 100, 0, None);
+        dispatcher
+            .dispatch_record(1, PERF_RECORD_SAMPLE, &event)
+            .unwrap();
+        dispatcher
+            .dispatch_record(1, PERF_RECORD_LOST, &lost_record(1, 7))
+            .unwrap();
+        processor.borrow_mut().shutdown();
+
+        let batch = batch_rx.try_recv().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        let int64_column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap()
+                .clone()
+        };
+        let lost_count = int64_column("lost_count");
+        let timestamp = int64_column("timestamp");
+        let cpu_id = batch
+            .column_by_name("cpu_id")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+
+        // Measurements leave lost_count null
+        assert!(lost_count.is_null(0));
+
+        // The loss is a row of its own, at the CPU's last timestamp
+        assert_eq!(lost_count.value(1), 7);
+        assert_eq!(timestamp.value(1), 5000);
+        assert_eq!(cpu_id.value(1), 1);
+        assert_eq!(int64_column("cycles").value(1), 0);
+        assert!(batch.column_by_name("pid").unwrap().is_null(1));
+        assert!(int64_column("cgroup_id").is_null(1));
+    }
+
+    #[test]
+    fn test_cpu_id_follows_ring_index_semantics() {
+        let mut dispatcher = Dispatcher::new();
+        let timeslot_tracker =
+            BpfTimeslotTracker::new(&mut dispatcher, 2, &BpfLoaderConfig::default());
+        let task_tracker = BpfTaskTracker::new(
+            &mut dispatcher,
+            timeslot_tracker,
+            &BpfLoaderConfig::default(),
+        );
+        let (batch_tx, mut batch_rx) = mpsc::channel(1);
+        let processor = BpfPerfToTrace::new(&mut dispatcher, task_tracker, batch_tx, 16);
+        processor
+            .borrow_mut()
+            .set_ring_index_semantics(RingIndexSemantics::Custom(Default::default()));
+
+        // Neither the measurement nor the loss on ring 1 belong to a CPU
+        let event = measurement(5000, 100, 0, None);
+        dispatcher
+            .dispatch_record(1, PERF_RECORD_SAMPLE, &event)
+            .unwrap();
+        dispatcher
+            .dispatch_record(1, PERF_RECORD_LOST, &lost_record(1, 7))
+            .unwrap();
+        processor.borrow_mut().shutdown();
+
+        let batch = batch_rx.try_recv().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.column_by_name("cpu_id").unwrap().null_count(), 2);
     }
 }
@@ -1,11 +1,10 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use log::error;
-
 use crate::bpf_timeslot_tracker::BpfTimeslotTracker;
 use crate::task_metadata::{TaskCollection, TaskMetadata};
-use bpf::{msg_type, BpfLoader, TaskFreeMsg, TaskMetadataMsg};
+use bpf::{msg_type, subscribe_events, BpfLoaderConfig, CollectorEvent, ProgramGroup};
+use perf_events::Dispatcher;
 
 /// BPF Task Tracker manages task metadata and task free events
 pub struct BpfTaskTracker {
@@ -13,31 +12,35 @@ pub struct BpfTaskTracker {
 }
 
 impl BpfTaskTracker {
-    /// Create a new BpfTaskTracker and subscribe to task events
+    /// Create a new BpfTaskTracker and subscribe to task events. Task free
+    /// events are only subscribed to if the task lifecycle programs are
+    /// loaded; without them, metadata of exited tasks is never released.
     pub fn new(
-        bpf_loader: &mut BpfLoader,
+        dispatcher: &mut Dispatcher,
         timeslot_tracker: Rc<RefCell<BpfTimeslotTracker>>,
+        groups: &BpfLoaderConfig,
     ) -> Rc<RefCell<Self>> {
         let tracker = Rc::new(RefCell::new(Self {
             task_collection: TaskCollection::new(),
         }));
 
-        // Subscribe to task events
-        let dispatcher = bpf_loader.dispatcher_mut();
-
         // Subscribe to task metadata events
-        dispatcher.subscribe_method(
-            msg_type::MSG_TYPE_TASK_METADATA as u32,
+        subscribe_events(
+            dispatcher,
+            msg_type::MSG_TYPE_TASK_METADATA,
             tracker.clone(),
-            BpfTaskTracker::handle_task_metadata,
+            BpfTaskTracker::handle_event,
         );
 
         // Subscribe to task free events
-        dispatcher.subscribe_method(
-            msg_type::MSG_TYPE_TASK_FREE as u32,
-            tracker.clone(),
-            BpfTaskTracker::handle_task_free,
-        );
+        if groups.is_enabled(ProgramGroup::TaskLifecycle) {
+            subscribe_events(
+                dispatcher,
+                msg_type::MSG_TYPE_TASK_FREE,
+                tracker.clone(),
+                BpfTaskTracker::handle_event,
+            );
+        }
 
         // Subscribe to timeslot events for flush_removals maintenance
         timeslot_tracker
@@ -52,37 +55,35 @@ impl BpfTaskTracker {
         self.task_collection.lookup(pid)
     }
 
+    /// Number of tasks with metadata
+    pub fn task_count(&self) -> usize {
+        self.task_collection.task_count()
+    }
+
+    /// Add task metadata directly, as if reported by eBPF
+    #[cfg(test)]
+    pub fn add_task(&mut self, metadata: TaskMetadata) {
+        self.task_collection.add(metadata);
+    }
+
     /// Handle new timeslot events - triggers flush_removals maintenance
     fn on_new_timeslot(&mut self, _old_timeslot: u64, _new_timeslot: u64) {
         self.task_collection.flush_removals();
     }
 
-    /// Handle task metadata events
-    fn handle_task_metadata(&mut self, _ring_index: usize, data: &[u8]) {
-        let event: &TaskMetadataMsg = match plain::from_bytes(data) {
-            Ok(event) => event,
-            Err(e) => {
-                error!("Failed to parse task metadata event: {:?}", e);
-                return;
+    /// Handle task metadata and task free events
+    fn handle_event(&mut self, _ring_index: usize, event: CollectorEvent<'_>) {
+        match event {
+            CollectorEvent::TaskMetadata(event) => {
+                // Create task metadata and add to collection
+                let metadata = TaskMetadata::new(event.pid, event.comm, event.cgroup_id);
+                self.task_collection.add(metadata);
             }
-        };
-
-        // Create task metadata and add to collection
-        let metadata = TaskMetadata::new(event.pid, event.comm, event.cgroup_id);
-        self.task_collection.add(metadata);
-    }
-
-    /// Handle task free events
-    fn handle_task_free(&mut self, _ring_index: usize, data: &[u8]) {
-        let event: &TaskFreeMsg = match plain::from_bytes(data) {
-            Ok(event) => event,
-            Err(e) => {
-                error!("Failed to parse task free event: {:?}", e);
-                return;
+            CollectorEvent::TaskFree(event) => {
+                // Queue the task for removal
+                self.task_collection.queue_removal(event.pid);
             }
-        };
-
-        // Queue the task for removal
-        self.task_collection.queue_removal(event.pid);
+            _ => {}
+        }
     }
 }
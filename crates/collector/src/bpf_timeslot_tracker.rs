@@ -1,10 +1,15 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use log::error;
-use timeslot::MinTracker;
+use timeslot::{MinTracker, TrackerState};
 
-use bpf::{msg_type, BpfLoader, TimerFinishedProcessingMsg};
+use bpf::{msg_type, BpfLoaderConfig, ProgramGroup, TimerFinishedProcessingMsg};
+use perf_events::Dispatcher;
+
+use crate::hot_path_log::error;
+
+/// Duration of a timeslot in nanoseconds
+pub const TIMESLOT_SIZE_NS: u64 = 1_000_000;
 
 /// Callback type for new timeslot events
 /// Receives (old_timeslot, new_timeslot) where timeslot is the timestamp
@@ -18,21 +23,27 @@ pub struct BpfTimeslotTracker {
 }
 
 impl BpfTimeslotTracker {
-    /// Create a new BpfTimeslotTracker and subscribe to timer events
-    pub fn new(bpf_loader: &mut BpfLoader, num_cpus: usize) -> Rc<RefCell<Self>> {
+    /// Create a new BpfTimeslotTracker, subscribing to timer events only if
+    /// the sync timer programs are loaded. Without them, timeslots never advance.
+    pub fn new(
+        dispatcher: &mut Dispatcher,
+        num_cpus: usize,
+        groups: &BpfLoaderConfig,
+    ) -> Rc<RefCell<Self>> {
         let tracker = Rc::new(RefCell::new(Self {
-            min_tracker: MinTracker::new(1_000_000, num_cpus),
+            min_tracker: MinTracker::new(TIMESLOT_SIZE_NS, num_cpus),
             last_min_slot: None,
             subscribers: Vec::new(),
         }));
 
         // Subscribe to timer finished processing events
-        let dispatcher = bpf_loader.dispatcher_mut();
-        dispatcher.subscribe_method(
-            msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING as u32,
-            tracker.clone(),
-            BpfTimeslotTracker::handle_timer_finished_processing,
-        );
+        if groups.is_enabled(ProgramGroup::SyncTimer) {
+            dispatcher.subscribe_method(
+                msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING as u32,
+                tracker.clone(),
+                BpfTimeslotTracker::handle_timer_finished_processing,
+            );
+        }
 
         tracker
     }
@@ -56,6 +67,17 @@ impl BpfTimeslotTracker {
         self.subscribe(callback);
     }
 
+    /// Capture the min tracker's progress, to persist across restarts
+    pub fn snapshot(&self) -> TrackerState {
+        self.min_tracker.snapshot()
+    }
+
+    /// Continue from a restored min tracker. Must be called before any events are dispatched.
+    pub fn restore(&mut self, min_tracker: MinTracker) {
+        self.last_min_slot = min_tracker.get_min();
+        self.min_tracker = min_tracker;
+    }
+
     /// Handle timer finished processing events
     fn handle_timer_finished_processing(&mut self, ring_index: usize, data: &[u8]) {
         let event: &TimerFinishedProcessingMsg = match plain::from_bytes(data) {
@@ -0,0 +1,364 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use perf_events::HardwareCounter;
+use serde_json::{json, Map, Value};
+
+use crate::bpf_perf_to_trace::TRACE_SCHEMA_VERSION;
+use crate::cgroup_rollup_task::CGROUP_ROLLUP_SCHEMA_VERSION;
+use crate::container_events::CONTAINER_EVENTS_SCHEMA_VERSION;
+use crate::preflight::parse_lockdown;
+use crate::timeslot_to_recordbatch_task::TIMESLOT_SCHEMA_VERSION;
+use crate::window_rollup::WINDOW_ROLLUP_SCHEMA_VERSION;
+use crate::Command;
+
+/// Value reported for probes that could not be determined
+const UNKNOWN: &str = "unknown";
+
+/// Replacement for values of secret environment variables
+const REDACTED: &str = "<redacted>";
+
+/// Storage backends supported by `--storage-type`
+const STORAGE_BACKENDS: [&str; 3] = ["local", "s3", "memory"];
+
+/// Hardware counters the collector programs, with their report names
+pub(crate) const COUNTERS: [(&str, HardwareCounter); 4] = [
+    ("cycles", HardwareCounter::Cycles),
+    ("instructions", HardwareCounter::Instructions),
+    ("llc_misses", HardwareCounter::LLCMisses),
+    ("cache_references", HardwareCounter::CacheReferences),
+];
+
+/// Outcome of a single capability probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeResult {
+    Available,
+    Unavailable,
+    Unknown,
+}
+
+impl ProbeResult {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ProbeResult::Available => "available",
+            ProbeResult::Unavailable => "unavailable",
+            ProbeResult::Unknown => UNKNOWN,
+        }
+    }
+}
+
+/// Results of probing the host, gathered without loading or attaching BPF programs
+#[derive(Debug, Clone)]
+pub struct SystemProbes {
+    pub privileged: bool,
+    pub num_cpus: Option<usize>,
+    pub kernel_release: Option<String>,
+    pub perf_event_paranoid: Option<i32>,
+    pub btf: ProbeResult,
+    pub bpf_object: ProbeResult,
+    /// Active kernel lockdown mode, if the lockdown LSM is enabled
+    pub lockdown: Option<String>,
+    pub counters: Vec<(&'static str, ProbeResult)>,
+    /// Whether the runtime accepts connections on the NRI socket; `Unknown`
+    /// until probed with [`probe_nri_socket`]
+    pub nri: ProbeResult,
+}
+
+impl SystemProbes {
+    /// Probe the running system. Never requires root; probes that cannot be
+    /// answered without privileges degrade to `ProbeResult::Unknown`.
+    pub fn collect() -> Self {
+        let privileged = unsafe { libc::geteuid() } == 0;
+
+        let btf = if Path::new("/sys/kernel/btf/vmlinux").exists() {
+            ProbeResult::Available
+        } else {
+            ProbeResult::Unavailable
+        };
+
+        let bpf_object = match bpf::BpfLoader::probe_open() {
+            Ok(()) => ProbeResult::Available,
+            Err(_) => ProbeResult::Unavailable,
+        };
+
+        let counters = COUNTERS
+            .iter()
+            .map(|(name, counter)| (*name, probe_counter(*counter)))
+            .collect();
+
+        Self {
+            privileged,
+            num_cpus: libbpf_rs::num_possible_cpus().ok(),
+            kernel_release: read_trimmed("/proc/sys/kernel/osrelease"),
+            perf_event_paranoid: read_trimmed("/proc/sys/kernel/perf_event_paranoid")
+                .and_then(|v| v.parse().ok()),
+            btf,
+            bpf_object,
+            lockdown: read_trimmed("/sys/kernel/security/lockdown")
+                .and_then(|contents| parse_lockdown(&contents)),
+            counters,
+            nri: ProbeResult::Unknown,
+        }
+    }
+}
+
+/// Probe the container runtime's NRI socket by connecting to it, mapping
+/// permission errors to `Unknown`
+pub fn probe_nri_socket(path: &Path) -> ProbeResult {
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => ProbeResult::Available,
+        Err(e) => match e.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) => ProbeResult::Unknown,
+            Some(libc::ENOENT) | Some(libc::ECONNREFUSED) | Some(libc::ENOTSOCK) => {
+                ProbeResult::Unavailable
+            }
+            _ => ProbeResult::Unknown,
+        },
+    }
+}
+
+/// Probe a hardware counter, mapping permission errors to `Unknown`
+fn probe_counter(counter: HardwareCounter) -> ProbeResult {
+    match perf_events::probe_perf_counter(counter) {
+        Ok(()) => ProbeResult::Available,
+        Err(perf_events::PerfEventError::OpenError { source, .. }) => match source.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EPERM) => ProbeResult::Unknown,
+            Some(libc::ENOENT) | Some(libc::EOPNOTSUPP) | Some(libc::ENODEV) => {
+                ProbeResult::Unavailable
+            }
+            _ => ProbeResult::Unknown,
+        },
+        Err(_) => ProbeResult::Unknown,
+    }
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Whether an environment variable name looks like it holds a credential
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_uppercase();
+    ["SECRET", "TOKEN", "PASSWORD", "ACCESS_KEY", "CREDENTIAL"]
+        .iter()
+        .any(|marker| key.contains(marker))
+}
+
+/// Collect the storage-related environment variables the object store reads,
+/// redacting values that look like credentials
+fn storage_env(vars: impl IntoIterator<Item = (String, String)>) -> Map<String, Value> {
+    let mut env = Map::new();
+    for (key, value) in vars {
+        if !key.starts_with("AWS_") {
+            continue;
+        }
+        let value = if is_secret_key(&key) {
+            REDACTED.to_string()
+        } else {
+            value
+        };
+        env.insert(key, Value::String(value));
+    }
+    env
+}
+
+/// Build the capability report from parsed options, probe results and environment
+pub fn build_report(
+    opts: &Command,
+    probes: &SystemProbes,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Value {
+    let counters: Map<String, Value> = probes
+        .counters
+        .iter()
+        .map(|(name, result)| (name.to_string(), json!(result.as_str())))
+        .collect();
+
+    json!({
+        "build": {
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_sha": option_env!("GIT_SHA").unwrap_or(UNKNOWN),
+        },
+        "schemas": {
+            "timeslot": TIMESLOT_SCHEMA_VERSION,
+            "trace": TRACE_SCHEMA_VERSION,
+            "cgroup_rollup": CGROUP_ROLLUP_SCHEMA_VERSION,
+            "container_events": CONTAINER_EVENTS_SCHEMA_VERSION,
+            "window_rollup": WINDOW_ROLLUP_SCHEMA_VERSION,
+        },
+        "storage_backends": STORAGE_BACKENDS,
+        "features": {
+            "trace_mode": opts.trace,
+            "adaptive": opts.adaptive,
+            "pid_ns_translation": opts.translate_pid_ns,
+            "cgroup_throttling": opts.cgroup_throttling,
+            "cgroup_rollup": opts.cgroup_rollup,
+            "redaction": !opts.redact.is_empty(),
+            "noisy_neighbor_scores": opts.noisy_neighbor_scores,
+            "container_events": opts.cgroup_filter.is_some() && !opts.no_container_events,
+            "window_rollup": opts.window_rollup,
+        },
+        "system": {
+            "privileged": probes.privileged,
+            "num_cpus": probes.num_cpus,
+            "kernel_release": probes.kernel_release.as_deref().unwrap_or(UNKNOWN),
+            "perf_event_paranoid": probes.perf_event_paranoid,
+        },
+        "kernel_features": {
+            "btf": probes.btf.as_str(),
+            "bpf_object_open": probes.bpf_object.as_str(),
+            "lockdown": probes.lockdown.as_deref().unwrap_or(UNKNOWN),
+        },
+        "counters": counters,
+        "nri": {
+            "enabled": opts.cgroup_filter.is_some(),
+            "socket": opts.nri_socket,
+            "connection": probes.nri.as_str(),
+        },
+        "config": {
+            "duration": opts.duration,
+            "storage_type": opts.storage_type,
+            "prefix": opts.prefix,
+            "parquet_buffer_size": opts.parquet_buffer_size,
+            "parquet_file_size": opts.parquet_file_size,
+            "max_row_group_size": opts.max_row_group_size,
+            "parquet_writer_version": opts.parquet_writer_version.as_num(),
+            "parquet_part_size": opts.parquet_part_size,
+            "parquet_upload_concurrency": opts.parquet_upload_concurrency,
+            "parquet_background_close": opts.parquet_background_close,
+            "storage_quota": opts.storage_quota,
+            "disk_min_free_mb": opts.disk_min_free_mb,
+            "disk_min_free_percent": opts.disk_min_free_percent,
+            "reclaim_own_files": opts.reclaim_own_files,
+            "trace": opts.trace,
+            "trace_max_batch_mb": opts.trace_max_batch_mb,
+            "trace_max_memory_mb": opts.trace_max_memory_mb,
+            "redact": opts
+                .redact
+                .iter()
+                .map(|target| target.to_possible_value().unwrap().get_name().to_string())
+                .collect::<Vec<_>>(),
+            "hash_redacted": opts.hash_redacted,
+            "flat_attribution": opts.flat_attribution,
+            "bpf_program_groups": opts
+                .bpf_program_groups
+                .iter()
+                .map(|group| group.name())
+                .collect::<Vec<_>>(),
+            "storage_env": storage_env(vars),
+        },
+    })
+}
+
+/// Probe the system and build the capability report for the given options
+pub fn capabilities_report(opts: &Command) -> Value {
+    let mut probes = SystemProbes::collect();
+    probes.nri = probe_nri_socket(&opts.nri_socket);
+    build_report(opts, &probes, std::env::vars())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn test_probes() -> SystemProbes {
+        SystemProbes {
+            privileged: false,
+            num_cpus: Some(4),
+            kernel_release: None,
+            perf_event_paranoid: Some(2),
+            btf: ProbeResult::Available,
+            bpf_object: ProbeResult::Available,
+            lockdown: Some("none".to_string()),
+            counters: vec![
+                ("cycles", ProbeResult::Available),
+                ("llc_misses", ProbeResult::Unknown),
+            ],
+            nri: ProbeResult::Unavailable,
+        }
+    }
+
+    #[test]
+    fn test_report_structure() {
+        let opts = Command::parse_from(["collector", "--trace", "--storage-type", "s3"]);
+        let report = build_report(&opts, &test_probes(), Vec::new());
+
+        for key in [
+            "build",
+            "schemas",
+            "storage_backends",
+            "features",
+            "system",
+            "kernel_features",
+            "counters",
+            "nri",
+            "config",
+        ] {
+            assert!(report.get(key).is_some(), "missing key {}", key);
+        }
+
+        assert_eq!(report["build"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(report["schemas"]["timeslot"], TIMESLOT_SCHEMA_VERSION);
+        assert_eq!(report["schemas"]["trace"], TRACE_SCHEMA_VERSION);
+        assert_eq!(
+            report["schemas"]["cgroup_rollup"],
+            CGROUP_ROLLUP_SCHEMA_VERSION
+        );
+        assert_eq!(report["features"]["trace_mode"], true);
+        assert_eq!(report["system"]["kernel_release"], "unknown");
+        assert_eq!(report["kernel_features"]["lockdown"], "none");
+        assert_eq!(report["counters"]["cycles"], "available");
+        assert_eq!(report["counters"]["llc_misses"], "unknown");
+        assert_eq!(report["nri"]["enabled"], false);
+        assert_eq!(report["nri"]["socket"], "/var/run/nri/nri.sock");
+        assert_eq!(report["nri"]["connection"], "unavailable");
+        assert_eq!(report["config"]["storage_type"], "s3");
+        assert_eq!(report["config"]["storage_quota"], Value::Null);
+    }
+
+    #[test]
+    fn test_storage_env_redaction() {
+        let opts = Command::parse_from(["collector"]);
+        let vars = vec![
+            ("AWS_REGION".to_string(), "us-east-1".to_string()),
+            ("AWS_BUCKET_NAME".to_string(), "metrics".to_string()),
+            ("AWS_ACCESS_KEY_ID".to_string(), "AKIAEXAMPLE".to_string()),
+            ("AWS_SECRET_ACCESS_KEY".to_string(), "hunter2".to_string()),
+            ("AWS_SESSION_TOKEN".to_string(), "token".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let report = build_report(&opts, &test_probes(), vars);
+        let env = &report["config"]["storage_env"];
+
+        assert_eq!(env["AWS_REGION"], "us-east-1");
+        assert_eq!(env["AWS_BUCKET_NAME"], "metrics");
+        assert_eq!(env["AWS_ACCESS_KEY_ID"], REDACTED);
+        assert_eq!(env["AWS_SECRET_ACCESS_KEY"], REDACTED);
+        assert_eq!(env["AWS_SESSION_TOKEN"], REDACTED);
+        assert!(env.get("HOME").is_none());
+
+        let rendered = serde_json::to_string(&report).unwrap();
+        assert!(!rendered.contains("hunter2"));
+        assert!(!rendered.contains("AKIAEXAMPLE"));
+    }
+
+    #[test]
+    fn test_probe_nri_socket() {
+        let dir = std::env::temp_dir().join(format!("capabilities_nri_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("nri.sock");
+        assert_eq!(probe_nri_socket(&socket), ProbeResult::Unavailable);
+
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        assert_eq!(probe_nri_socket(&socket), ProbeResult::Available);
+
+        // A socket left behind by a runtime that exited refuses connections
+        drop(listener);
+        assert_eq!(probe_nri_socket(&socket), ProbeResult::Unavailable);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,731 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use tokio::sync::mpsc;
+
+use nri::metadata::{cgroup_dir, ContainerLimits, ContainerMetadata, MetadataMessage};
+
+use crate::attribution::ContainerIdentity;
+use crate::shutdown::ShutdownToken;
+
+/// How often the worker retries resolving cgroups that did not exist yet.
+/// NRI announces containers before the runtime creates their cgroup.
+const RESOLVE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which containers to collect, matched against NRI container metadata
+///
+/// Parsed from comma separated `key=value` terms, all of which must match:
+/// `namespace=<pod namespace>`, `pod=<pod name>` and `label.<key>=<value>`,
+/// e.g. `namespace=prod,label.app=web`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContainerSelector {
+    pub namespace: Option<String>,
+    pub pod: Option<String>,
+    pub labels: Vec<(String, String)>,
+}
+
+impl ContainerSelector {
+    /// Whether the container matches every term of the selector
+    pub fn matches(&self, metadata: &ContainerMetadata) -> bool {
+        self.namespace
+            .as_ref()
+            .is_none_or(|namespace| *namespace == metadata.pod_namespace)
+            && self
+                .pod
+                .as_ref()
+                .is_none_or(|pod| *pod == metadata.pod_name)
+            && self
+                .labels
+                .iter()
+                .all(|(key, value)| metadata.labels.get(key) == Some(value))
+    }
+}
+
+impl FromStr for ContainerSelector {
+    type Err = String;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let mut parsed = Self::default();
+        for term in selector.split(',').filter(|term| !term.is_empty()) {
+            let (key, value) = term
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{}'", term))?;
+            match key {
+                "namespace" => parsed.namespace = Some(value.to_string()),
+                "pod" => parsed.pod = Some(value.to_string()),
+                _ => match key.strip_prefix("label.") {
+                    Some(label) if !label.is_empty() => {
+                        parsed.labels.push((label.to_string(), value.to_string()))
+                    }
+                    _ => {
+                        return Err(format!(
+                            "unknown key '{}', expected namespace, pod or label.<key>",
+                            key
+                        ))
+                    }
+                },
+            }
+        }
+        if parsed == Self::default() {
+            return Err("empty container selector".to_string());
+        }
+        Ok(parsed)
+    }
+}
+
+/// Resolves container cgroup paths to cgroup ids, replaceable in tests
+pub trait CgroupResolver: Send + 'static {
+    /// Cgroup id (inode number in the cgroup filesystem) of the cgroup at `cgroup_path`
+    fn cgroup_id(&self, cgroup_path: &str) -> io::Result<u64>;
+}
+
+/// Resolves cgroup paths, including the systemd driver's `slice:prefix:name`
+/// form, under the cgroup v2 mount point
+pub struct FsCgroupResolver {
+    root: PathBuf,
+}
+
+impl FsCgroupResolver {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl CgroupResolver for FsCgroupResolver {
+    fn cgroup_id(&self, cgroup_path: &str) -> io::Result<u64> {
+        let path = cgroup_dir(cgroup_path, &self.root)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty cgroup path"))?;
+        Ok(fs::metadata(path)?.ino())
+    }
+}
+
+/// What the output records of a collected container: its identity and
+/// resource limits
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContainerInfo {
+    pub identity: ContainerIdentity,
+    pub limits: ContainerLimits,
+}
+
+impl ContainerInfo {
+    pub fn from_metadata(metadata: &ContainerMetadata) -> Self {
+        Self {
+            identity: ContainerIdentity::from_metadata(metadata),
+            limits: metadata.limits.clone(),
+        }
+    }
+}
+
+/// The collected containers by cgroup id, shared between the
+/// [`CgroupFilterWorker`] and the conversion of timeslots and trace events
+#[derive(Clone, Default)]
+pub struct ContainerTable(Arc<Mutex<HashMap<u64, Arc<ContainerInfo>>>>);
+
+impl ContainerTable {
+    /// Containers of those of `cgroup_ids` that are collected
+    pub fn get(&self, cgroup_ids: &HashSet<u64>) -> HashMap<u64, Arc<ContainerInfo>> {
+        let containers = self.0.lock().unwrap();
+        cgroup_ids
+            .iter()
+            .filter_map(|id| containers.get(id).map(|info| (*id, info.clone())))
+            .collect()
+    }
+
+    pub(crate) fn set(&self, cgroup_id: u64, info: ContainerInfo) {
+        self.0.lock().unwrap().insert(cgroup_id, Arc::new(info));
+    }
+
+    fn remove(&self, cgroup_id: u64) {
+        self.0.lock().unwrap().remove(&cgroup_id);
+    }
+
+    /// Drop the cgroups that are no longer collected
+    fn retain(&self, collected: &HashMap<u64, usize>) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|cgroup_id, _| collected.contains_key(cgroup_id));
+    }
+}
+
+/// Change to the set of cgroups whose events are collected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilterUpdate {
+    Add(u64),
+    Remove(u64),
+}
+
+/// Gates events on the polling thread by the cgroup of their task.
+///
+/// The set of allowed cgroups follows the containers matching a
+/// [`ContainerSelector`]. A [`CgroupFilterWorker`] tracks container metadata
+/// from NRI and sends changes, which are applied before each lookup.
+pub struct CgroupFilter {
+    active: HashSet<u64>,
+    update_rx: mpsc::UnboundedReceiver<FilterUpdate>,
+}
+
+impl CgroupFilter {
+    /// Create a filter and the worker that maintains it from `metadata_rx`
+    pub fn new<R: CgroupResolver>(
+        selector: ContainerSelector,
+        resolver: R,
+        metadata_rx: mpsc::Receiver<MetadataMessage>,
+    ) -> (Self, CgroupFilterWorker<R>) {
+        let (update_tx, update_rx) = mpsc::unbounded_channel();
+
+        let filter = Self {
+            active: HashSet::new(),
+            update_rx,
+        };
+        let worker = CgroupFilterWorker {
+            selector,
+            resolver,
+            metadata_rx,
+            update_tx,
+            containers: HashMap::new(),
+            unresolved: HashMap::new(),
+            references: HashMap::new(),
+            table: ContainerTable::default(),
+            event_forward: None,
+            frozen: None,
+        };
+        (filter, worker)
+    }
+
+    /// Whether events of tasks in the cgroup pass the filter
+    pub fn allows(&mut self, cgroup_id: u64) -> bool {
+        self.apply_updates();
+        self.active.contains(&cgroup_id)
+    }
+
+    /// Apply changes received from the worker
+    fn apply_updates(&mut self) {
+        while let Ok(update) = self.update_rx.try_recv() {
+            match update {
+                FilterUpdate::Add(cgroup_id) => self.active.insert(cgroup_id),
+                FilterUpdate::Remove(cgroup_id) => self.active.remove(&cgroup_id),
+            };
+        }
+    }
+}
+
+/// Worker task following NRI container metadata for a [`CgroupFilter`]
+pub struct CgroupFilterWorker<R: CgroupResolver> {
+    selector: ContainerSelector,
+    resolver: R,
+    metadata_rx: mpsc::Receiver<MetadataMessage>,
+    update_tx: mpsc::UnboundedSender<FilterUpdate>,
+    // Cgroup of each matching container
+    containers: HashMap<String, u64>,
+    // Cgroup paths and output info of matching containers whose cgroup is
+    // not resolved yet
+    unresolved: HashMap<String, (String, ContainerInfo)>,
+    // Number of matching containers in each allowed cgroup
+    references: HashMap<u64, usize>,
+    // The containers in each allowed cgroup
+    table: ContainerTable,
+    // Receives a copy of every metadata message, matching or not
+    event_forward: Option<mpsc::UnboundedSender<MetadataMessage>>,
+    // Set once attribution is frozen, when the metadata stream ending is
+    // expected
+    frozen: Option<Arc<AtomicBool>>,
+}
+
+impl<R: CgroupResolver> CgroupFilterWorker<R> {
+    /// Keep the identity and limits of the collected containers in `table`
+    pub fn set_containers(&mut self, table: ContainerTable) {
+        self.table = table;
+    }
+
+    /// Send a copy of every metadata message received to `forward`, such as
+    /// for the container events stream
+    pub fn set_event_forward(&mut self, forward: mpsc::UnboundedSender<MetadataMessage>) {
+        self.event_forward = Some(forward);
+    }
+
+    /// Keep the filter as it is when the metadata stream ends after `frozen`
+    /// is set, rather than failing
+    pub fn set_frozen(&mut self, frozen: Arc<AtomicBool>) {
+        self.frozen = Some(frozen);
+    }
+
+    /// Follow container metadata until shutdown or the metadata stream ends
+    pub async fn run(mut self, shutdown_token: ShutdownToken) -> Result<()> {
+        let mut retry = tokio::time::interval(RESOLVE_RETRY_INTERVAL);
+        loop {
+            tokio::select! {
+                message = self.metadata_rx.recv() => match message {
+                    Some(message) => self.handle_message(message),
+                    None if self.is_frozen() => {
+                        info!("Container metadata frozen, keeping the cgroup filter until shutdown");
+                        shutdown_token.cancelled().await;
+                        return Ok(());
+                    }
+                    None => return Err(anyhow!("container metadata stream ended")),
+                },
+                _ = retry.tick() => self.resolve_pending(),
+                _ = shutdown_token.cancelled() => {
+                    debug!("Cgroup filter worker cancelled");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn is_frozen(&self) -> bool {
+        self.frozen
+            .as_ref()
+            .is_some_and(|frozen| frozen.load(Ordering::Relaxed))
+    }
+
+    /// Update the allowed cgroups for a metadata change
+    fn handle_message(&mut self, message: MetadataMessage) {
+        if let Some(forward) = &self.event_forward {
+            // The receiver only goes away at shutdown
+            let _ = forward.send(message.clone());
+        }
+
+        match message {
+            MetadataMessage::Add(container_id, metadata) => {
+                if self.selector.matches(&metadata) {
+                    let info = ContainerInfo::from_metadata(&metadata);
+                    self.unresolved
+                        .insert(container_id.clone(), (metadata.cgroup_path, info));
+                    self.resolve(&container_id);
+                } else {
+                    // The container may have stopped matching after an update
+                    self.remove(&container_id);
+                }
+            }
+            MetadataMessage::Remove(container_id) => self.remove(&container_id),
+            // Containers keep running while the plugin reconnects; the new
+            // connection synchronizes them again
+            MetadataMessage::Shutdown => {
+                info!("NRI connection closed, keeping the cgroup filter until it reconnects")
+            }
+            MetadataMessage::Synchronized(listed) => self.sweep(&listed),
+            // Lifecycle hints only matter to the container events output
+            MetadataMessage::Lifecycle(..) => {}
+        }
+    }
+
+    /// Stop collecting containers the runtime did not list when it
+    /// synchronized, which went away while the plugin was disconnected
+    fn sweep(&mut self, listed: &[String]) {
+        let listed: HashSet<&String> = listed.iter().collect();
+        let gone: Vec<String> = self
+            .containers
+            .keys()
+            .chain(self.unresolved.keys())
+            .filter(|container_id| !listed.contains(container_id))
+            .cloned()
+            .collect();
+        for container_id in gone {
+            info!(
+                "Container {} is gone after synchronizing, no longer collecting it",
+                container_id
+            );
+            self.remove(&container_id);
+        }
+        self.table.retain(&self.references);
+    }
+
+    /// Retry resolving containers whose cgroup did not exist yet
+    fn resolve_pending(&mut self) {
+        let pending: Vec<String> = self.unresolved.keys().cloned().collect();
+        for container_id in pending {
+            self.resolve(&container_id);
+        }
+    }
+
+    /// Allow the cgroup of an unresolved container if it exists now
+    fn resolve(&mut self, container_id: &str) {
+        let Some((cgroup_path, _)) = self.unresolved.get(container_id) else {
+            return;
+        };
+        let cgroup_id = match self.resolver.cgroup_id(cgroup_path) {
+            Ok(cgroup_id) => cgroup_id,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!(
+                    "Cannot resolve cgroup {} of container {}: {}",
+                    cgroup_path, container_id, e
+                );
+                self.unresolved.remove(container_id);
+                return;
+            }
+        };
+        if let Some((_, info)) = self.unresolved.remove(container_id) {
+            // Updates to a container's limits arrive with the same cgroup
+            self.table.set(cgroup_id, info);
+        }
+
+        match self.containers.insert(container_id.to_string(), cgroup_id) {
+            Some(previous) if previous == cgroup_id => return,
+            Some(previous) => self.release(previous),
+            None => {}
+        }
+        let references = self.references.entry(cgroup_id).or_insert(0);
+        *references += 1;
+        if *references == 1 {
+            debug!(
+                "Collecting cgroup {} of container {}",
+                cgroup_id, container_id
+            );
+            let _ = self.update_tx.send(FilterUpdate::Add(cgroup_id));
+        }
+    }
+
+    /// Stop collecting a container
+    fn remove(&mut self, container_id: &str) {
+        self.unresolved.remove(container_id);
+        if let Some(cgroup_id) = self.containers.remove(container_id) {
+            self.release(cgroup_id);
+        }
+    }
+
+    /// Drop a container's reference to its cgroup, disallowing it with the last one
+    fn release(&mut self, cgroup_id: u64) {
+        if let Some(references) = self.references.get_mut(&cgroup_id) {
+            *references -= 1;
+            if *references == 0 {
+                self.references.remove(&cgroup_id);
+                self.table.remove(cgroup_id);
+                debug!("No longer collecting cgroup {}", cgroup_id);
+                let _ = self.update_tx.send(FilterUpdate::Remove(cgroup_id));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    use bpf::{msg_type, PerfMeasurementMsg, TaskMetadataMsg, TimerFinishedProcessingMsg};
+    use perf_events::{collector_sample_record, Dispatcher, PERF_RECORD_SAMPLE};
+
+    use crate::bpf_perf_to_timeslot::BpfPerfToTimeslot;
+    use crate::bpf_task_tracker::BpfTaskTracker;
+    use crate::bpf_timeslot_tracker::{BpfTimeslotTracker, TIMESLOT_SIZE_NS};
+
+    /// Resolves paths from a map the test can change
+    #[derive(Clone, Default)]
+    struct FakeResolver(Arc<Mutex<HashMap<String, u64>>>);
+
+    impl FakeResolver {
+        fn create(&self, cgroup_path: &str, cgroup_id: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(cgroup_path.to_string(), cgroup_id);
+        }
+    }
+
+    impl CgroupResolver for FakeResolver {
+        fn cgroup_id(&self, cgroup_path: &str) -> io::Result<u64> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(cgroup_path)
+                .copied()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
+    fn container(id: &str, namespace: &str, app: &str) -> MetadataMessage {
+        MetadataMessage::Add(
+            id.to_string(),
+            ContainerMetadata {
+                container_id: id.to_string(),
+                pod_name: format!("{}-pod", id),
+                pod_namespace: namespace.to_string(),
+                pod_uid: format!("{}-uid", id),
+                container_name: id.to_string(),
+                cgroup_path: format!("/kubepods/{}", id),
+                cgroup_id: None,
+                pid: None,
+                labels: HashMap::from([("app".to_string(), app.to_string())]),
+                annotations: HashMap::new(),
+                limits: ContainerLimits::default(),
+            },
+        )
+    }
+
+    /// Cgroups the filter allows after applying pending updates
+    fn active(filter: &mut CgroupFilter) -> HashSet<u64> {
+        filter.apply_updates();
+        filter.active.clone()
+    }
+
+    fn remove(id: &str) -> MetadataMessage {
+        MetadataMessage::Remove(id.to_string())
+    }
+
+    fn filter_with_worker(
+        selector: &str,
+    ) -> (CgroupFilter, CgroupFilterWorker<FakeResolver>, FakeResolver) {
+        let resolver = FakeResolver::default();
+        let (_metadata_tx, metadata_rx) = mpsc::channel(1);
+        let (filter, worker) =
+            CgroupFilter::new(selector.parse().unwrap(), resolver.clone(), metadata_rx);
+        (filter, worker, resolver)
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        let selector: ContainerSelector =
+            "namespace=prod,pod=web-0,label.app.kubernetes.io/name=web"
+                .parse()
+                .unwrap();
+        assert_eq!(selector.namespace.as_deref(), Some("prod"));
+        assert_eq!(selector.pod.as_deref(), Some("web-0"));
+        assert_eq!(
+            selector.labels,
+            vec![("app.kubernetes.io/name".to_string(), "web".to_string())]
+        );
+
+        assert!("".parse::<ContainerSelector>().is_err());
+        assert!("namespace".parse::<ContainerSelector>().is_err());
+        assert!("node=a".parse::<ContainerSelector>().is_err());
+        assert!("label.=a".parse::<ContainerSelector>().is_err());
+    }
+
+    #[test]
+    fn test_active_set_follows_metadata() {
+        let (mut filter, mut worker, resolver) = filter_with_worker("namespace=prod,label.app=web");
+        for (id, cgroup_id) in [("web1", 1), ("web2", 2), ("db", 3), ("dev", 4)] {
+            resolver.create(&format!("/kubepods/{}", id), cgroup_id);
+        }
+
+        worker.handle_message(container("web1", "prod", "web"));
+        worker.handle_message(container("web2", "prod", "web"));
+        worker.handle_message(container("db", "prod", "db"));
+        worker.handle_message(container("dev", "dev", "web"));
+        assert_eq!(active(&mut filter), HashSet::from([1, 2]));
+
+        // Removed containers stop passing, as do ones updated to no longer match
+        worker.handle_message(remove("web1"));
+        worker.handle_message(container("web2", "prod", "canary"));
+        assert!(active(&mut filter).is_empty());
+
+        // A container whose cgroup appears later is picked up on retry
+        worker.handle_message(container("web3", "prod", "web"));
+        assert!(active(&mut filter).is_empty());
+        resolver.create("/kubepods/web3", 5);
+        worker.resolve_pending();
+        assert_eq!(active(&mut filter), HashSet::from([5]));
+
+        // Removing it before the cgroup appears forgets it
+        worker.handle_message(container("web4", "prod", "web"));
+        worker.handle_message(remove("web4"));
+        resolver.create("/kubepods/web4", 6);
+        worker.resolve_pending();
+        assert_eq!(active(&mut filter), HashSet::from([5]));
+
+        // Disconnects keep the set
+        worker.handle_message(MetadataMessage::Shutdown);
+        assert_eq!(active(&mut filter), HashSet::from([5]));
+    }
+
+    #[tokio::test]
+    async fn test_frozen_metadata_end() {
+        // The metadata stream of the helper ends right away
+        let (_filter, worker, _resolver) = filter_with_worker("label.app=web");
+        assert!(worker.run(ShutdownToken::new()).await.is_err());
+
+        // Once frozen, the worker keeps the filter until shutdown
+        let (mut filter, mut worker, resolver) = filter_with_worker("label.app=web");
+        resolver.create("/kubepods/web", 1);
+        worker.handle_message(container("web", "prod", "web"));
+        let frozen = Arc::new(AtomicBool::new(true));
+        worker.set_frozen(frozen);
+        let shutdown_token = ShutdownToken::new();
+        let run = tokio::spawn(worker.run(shutdown_token.clone()));
+        tokio::task::yield_now().await;
+        assert!(!run.is_finished());
+        shutdown_token.cancel(crate::shutdown::ShutdownReason::Duration);
+        run.await.unwrap().unwrap();
+        assert_eq!(active(&mut filter), HashSet::from([1]));
+    }
+
+    #[test]
+    fn test_limits_follow_metadata() {
+        let (_filter, mut worker, resolver) = filter_with_worker("label.app=web");
+        let table = ContainerTable::default();
+        worker.set_containers(table.clone());
+        resolver.create("/kubepods/web1", 1);
+
+        let with_memory_limit = |memory_limit| {
+            let mut message = container("web1", "prod", "web");
+            if let MetadataMessage::Add(_, ref mut metadata) = message {
+                metadata.limits.memory_limit = Some(memory_limit);
+            }
+            message
+        };
+        worker.handle_message(with_memory_limit(1 << 30));
+        let ids = HashSet::from([1, 2]);
+        assert_eq!(table.get(&ids)[&1].limits.memory_limit, Some(1 << 30));
+        assert_eq!(table.get(&ids)[&1].identity.pod_namespace, "prod");
+        assert_eq!(table.get(&ids).len(), 1);
+
+        // An update changes the limits in place
+        worker.handle_message(with_memory_limit(ContainerLimits::UNLIMITED));
+        assert_eq!(
+            table.get(&ids)[&1].limits.memory_limit,
+            Some(ContainerLimits::UNLIMITED)
+        );
+
+        worker.handle_message(remove("web1"));
+        assert!(table.get(&ids).is_empty());
+    }
+
+    #[test]
+    fn test_resync_sweeps_gone_containers() {
+        let (mut filter, mut worker, resolver) = filter_with_worker("label.app=web");
+        let table = ContainerTable::default();
+        worker.set_containers(table.clone());
+        for (id, cgroup_id) in [("web1", 1), ("web2", 2)] {
+            resolver.create(&format!("/kubepods/{}", id), cgroup_id);
+        }
+        let synchronized = |ids: &[&str]| {
+            MetadataMessage::Synchronized(ids.iter().map(|id| id.to_string()).collect())
+        };
+        worker.handle_message(container("web1", "prod", "web"));
+        worker.handle_message(container("web2", "prod", "web"));
+        // Not resolved yet when the connection drops
+        worker.handle_message(container("web3", "prod", "web"));
+        worker.handle_message(synchronized(&["web1", "web2", "web3"]));
+        assert_eq!(active(&mut filter), HashSet::from([1, 2]));
+
+        // Web1 and web3 go away while disconnected: the new connection only
+        // lists web2
+        worker.handle_message(MetadataMessage::Shutdown);
+        worker.handle_message(container("web2", "prod", "web"));
+        assert_eq!(active(&mut filter), HashSet::from([1, 2]));
+        worker.handle_message(synchronized(&["web2"]));
+        assert_eq!(active(&mut filter), HashSet::from([2]));
+        assert_eq!(
+            table
+                .get(&HashSet::from([1, 2]))
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+        resolver.create("/kubepods/web3", 3);
+        worker.resolve_pending();
+        assert_eq!(active(&mut filter), HashSet::from([2]));
+
+        // Listed containers stay even if their Add was dropped on a full channel
+        worker.handle_message(synchronized(&["web2", "other"]));
+        assert_eq!(active(&mut filter), HashSet::from([2]));
+    }
+
+    #[test]
+    fn test_shared_cgroup_stays_until_last_container() {
+        let (mut filter, mut worker, resolver) = filter_with_worker("label.app=web");
+        resolver.create("/kubepods/a", 7);
+        resolver.create("/kubepods/b", 7);
+
+        worker.handle_message(container("a", "prod", "web"));
+        worker.handle_message(container("b", "prod", "web"));
+        worker.handle_message(remove("a"));
+        assert!(filter.allows(7));
+        worker.handle_message(remove("b"));
+        assert!(!filter.allows(7));
+    }
+
+    #[test]
+    fn test_filter_gates_measurements() {
+        let (filter, mut worker, resolver) = filter_with_worker("label.app=web");
+        let filter = Rc::new(RefCell::new(filter));
+        resolver.create("/kubepods/web", 100);
+        resolver.create("/kubepods/db", 200);
+
+        let mut dispatcher = Dispatcher::new();
+        let config = bpf::BpfLoaderConfig::default();
+        let timeslot_tracker = BpfTimeslotTracker::new(&mut dispatcher, 1, &config);
+        let task_tracker = BpfTaskTracker::new(&mut dispatcher, timeslot_tracker.clone(), &config);
+        let (timeslot_tx, mut timeslot_rx) = mpsc::channel(16);
+        let processor =
+            BpfPerfToTimeslot::new(&mut dispatcher, timeslot_tracker, task_tracker, timeslot_tx);
+        processor.borrow_mut().set_cgroup_filter(filter.clone());
+
+        let mut dispatch = |msg: Vec<u8>| {
+            dispatcher
+                .dispatch_record(0, PERF_RECORD_SAMPLE, &msg)
+                .unwrap()
+        };
+
+        // Tasks 1 and 2 run in the web and db containers' cgroups, task 3 has no metadata
+        for (pid, cgroup_id) in [(1, 100), (2, 200)] {
+            let task = TaskMetadataMsg {
+                pid,
+                cgroup_id,
+                ..Default::default()
+            };
+            dispatch(collector_sample_record(
+                msg_type::MSG_TYPE_TASK_METADATA as u32,
+                1,
+                &task,
+            ));
+        }
+
+        // Measure every task within a timeslot, returning the pids collected
+        let mut timestamp = 0;
+        let mut measure = || {
+            timestamp += TIMESLOT_SIZE_NS;
+            for pid in [1, 2, 3] {
+                let measurement = PerfMeasurementMsg {
+                    pid,
+                    cycles_delta: 10,
+                    ..Default::default()
+                };
+                dispatch(collector_sample_record(
+                    msg_type::MSG_TYPE_PERF_MEASUREMENT as u32,
+                    timestamp + 100,
+                    &measurement,
+                ));
+            }
+            let timer = TimerFinishedProcessingMsg::default();
+            dispatch(collector_sample_record(
+                msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING as u32,
+                timestamp + TIMESLOT_SIZE_NS / 2,
+                &timer,
+            ));
+
+            let mut pids = Vec::new();
+            while let Ok(timeslot) = timeslot_rx.try_recv() {
+                pids.extend(timeslot.tasks.keys().copied());
+            }
+            pids.sort();
+            pids
+        };
+
+        // Nothing matches yet
+        assert!(measure().is_empty());
+
+        // Only the web container's task passes once it starts
+        worker.handle_message(container("web", "prod", "web"));
+        assert_eq!(measure(), vec![1]);
+
+        // The db container is relabelled into the selection and web stops
+        worker.handle_message(container("db", "prod", "web"));
+        worker.handle_message(remove("web"));
+        assert_eq!(measure(), vec![2]);
+        assert_eq!(active(&mut filter.borrow_mut()), HashSet::from([200]));
+    }
+}
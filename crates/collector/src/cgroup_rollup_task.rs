@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use arrow_array::builder::{Int32Builder, Int64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use tokio::sync::mpsc;
+
+use crate::cgroup_filter::ContainerTable;
+use crate::metrics::Metric;
+use crate::timeslot_data::TimeslotData;
+use crate::timeslot_to_recordbatch_task::{drop_pending_timeslots, LimitColumnsBuilder};
+
+/// Version of the cgroup rollup schema, bumped whenever columns change.
+/// Version 2 added the lost_count and container limit columns, with cgroup_id
+/// nullable, null on the lost samples row.
+pub const CGROUP_ROLLUP_SCHEMA_VERSION: u32 = 2;
+
+/// Create the schema for cgroup rollup record batches
+pub fn create_cgroup_rollup_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("start_time", DataType::Int64, false),
+        // Tasks without metadata are rolled up into cgroup 0
+        Field::new("cgroup_id", DataType::Int64, true),
+        Field::new("task_count", DataType::Int32, false),
+        Field::new("cycles", DataType::Int64, false),
+        Field::new("instructions", DataType::Int64, false),
+        Field::new("llc_misses", DataType::Int64, false),
+        Field::new("cache_references", DataType::Int64, false),
+        Field::new("duration", DataType::Int64, false),
+        Field::new("slots_merged", DataType::Int32, false),
+        // Samples lost on full rings, only on the lost samples row
+        Field::new("lost_count", DataType::Int64, true),
+        // Limits of the cgroup's container, null unless it is collected
+        Field::new("cpu_quota_usec", DataType::Int64, true),
+        Field::new("cpu_period_usec", DataType::Int64, true),
+        Field::new("cpu_shares", DataType::Int64, true),
+        Field::new("cpuset_cpus", DataType::Utf8, true),
+        Field::new("cpuset_mems", DataType::Utf8, true),
+        Field::new("memory_limit_bytes", DataType::Int64, true),
+    ]))
+}
+
+/// Sums of the tasks of a cgroup in a timeslot
+#[derive(Debug, Default)]
+pub struct CgroupTotals {
+    pub task_count: u32,
+    pub metrics: Metric,
+}
+
+/// Sum the metrics of a timeslot's tasks by cgroup, ordered by cgroup id
+pub fn rollup(timeslot: &TimeslotData) -> BTreeMap<u64, CgroupTotals> {
+    let mut totals: BTreeMap<u64, CgroupTotals> = BTreeMap::new();
+    for (_, task_data) in timeslot.iter_tasks() {
+        let cgroup_id = task_data
+            .metadata
+            .as_ref()
+            .map_or(0, |metadata| metadata.cgroup_id);
+        let cgroup = totals.entry(cgroup_id).or_default();
+        cgroup.task_count += 1;
+        cgroup.metrics.add(&task_data.metrics);
+    }
+    totals
+}
+
+/// Convert a TimeslotData to an Arrow RecordBatch with a row per cgroup, and
+/// a row of its lost samples if any
+pub fn rollup_to_batch(timeslot: &TimeslotData, schema: SchemaRef) -> Result<RecordBatch> {
+    let totals = rollup(timeslot);
+    let lost_row = timeslot.lost_count > 0;
+    let rows = totals.len() + usize::from(lost_row);
+
+    let mut start_time_builder = Int64Builder::with_capacity(rows);
+    let mut cgroup_id_builder = Int64Builder::with_capacity(rows);
+    let mut task_count_builder = Int32Builder::with_capacity(rows);
+    let mut cycles_builder = Int64Builder::with_capacity(rows);
+    let mut instructions_builder = Int64Builder::with_capacity(rows);
+    let mut llc_misses_builder = Int64Builder::with_capacity(rows);
+    let mut cache_references_builder = Int64Builder::with_capacity(rows);
+    let mut duration_builder = Int64Builder::with_capacity(rows);
+    let mut slots_merged_builder = Int32Builder::with_capacity(rows);
+    let mut lost_count_builder = Int64Builder::with_capacity(rows);
+    let mut limits_builder = LimitColumnsBuilder::with_capacity(rows);
+
+    for (cgroup_id, cgroup) in totals {
+        start_time_builder.append_value(timeslot.start_timestamp as i64);
+        cgroup_id_builder.append_value(cgroup_id as i64);
+        task_count_builder.append_value(cgroup.task_count as i32);
+        cycles_builder.append_value(cgroup.metrics.cycles as i64);
+        instructions_builder.append_value(cgroup.metrics.instructions as i64);
+        llc_misses_builder.append_value(cgroup.metrics.llc_misses as i64);
+        cache_references_builder.append_value(cgroup.metrics.cache_references as i64);
+        duration_builder.append_value(cgroup.metrics.time_ns as i64);
+        slots_merged_builder.append_value(timeslot.slots_merged as i32);
+        lost_count_builder.append_null();
+        let container = timeslot.containers.get(&cgroup_id);
+        limits_builder.append(container.map(|info| &info.limits));
+    }
+
+    // Samples lost on full rings are a row of their own, not attributed to a cgroup
+    if lost_row {
+        start_time_builder.append_value(timeslot.start_timestamp as i64);
+        cgroup_id_builder.append_null();
+        task_count_builder.append_value(0);
+        cycles_builder.append_value(0);
+        instructions_builder.append_value(0);
+        llc_misses_builder.append_value(0);
+        cache_references_builder.append_value(0);
+        duration_builder.append_value(0);
+        slots_merged_builder.append_value(timeslot.slots_merged as i32);
+        lost_count_builder.append_value(timeslot.lost_count as i64);
+        limits_builder.append(None);
+    }
+
+    let mut arrays: Vec<ArrayRef> = vec![
+        Arc::new(start_time_builder.finish()),
+        Arc::new(cgroup_id_builder.finish()),
+        Arc::new(task_count_builder.finish()),
+        Arc::new(cycles_builder.finish()),
+        Arc::new(instructions_builder.finish()),
+        Arc::new(llc_misses_builder.finish()),
+        Arc::new(cache_references_builder.finish()),
+        Arc::new(duration_builder.finish()),
+        Arc::new(slots_merged_builder.finish()),
+        Arc::new(lost_count_builder.finish()),
+    ];
+    limits_builder.finish(&mut arrays);
+
+    RecordBatch::try_new(schema, arrays).map_err(|e| anyhow!("Failed to create RecordBatch: {}", e))
+}
+
+/// Worker task rolling timeslots up into per-cgroup record batches, a compact
+/// alternative to [`TimeslotToRecordBatchTask`](crate::timeslot_to_recordbatch_task::TimeslotToRecordBatchTask)
+pub struct CgroupRollupTask {
+    timeslot_receiver: mpsc::Receiver<TimeslotData>,
+    batch_sender: mpsc::Sender<RecordBatch>,
+    schema: SchemaRef,
+    timeslot_count: Arc<AtomicUsize>,
+    dropped_batches: Arc<AtomicUsize>,
+    containers: Option<ContainerTable>,
+}
+
+impl CgroupRollupTask {
+    /// Create a new CgroupRollupTask with pre-configured channels
+    pub fn new(
+        timeslot_receiver: mpsc::Receiver<TimeslotData>,
+        batch_sender: mpsc::Sender<RecordBatch>,
+    ) -> Self {
+        Self {
+            timeslot_receiver,
+            batch_sender,
+            schema: create_cgroup_rollup_schema(),
+            timeslot_count: Arc::new(AtomicUsize::new(0)),
+            dropped_batches: Arc::new(AtomicUsize::new(0)),
+            containers: None,
+        }
+    }
+
+    /// Add the limits of the containers in `containers` to their cgroup's rows
+    pub fn set_containers(&mut self, containers: ContainerTable) {
+        self.containers = Some(containers);
+    }
+
+    /// Get the schema for the record batches this task produces
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Get a handle to the number of timeslots rolled up so far
+    pub fn timeslot_counter(&self) -> Arc<AtomicUsize> {
+        self.timeslot_count.clone()
+    }
+
+    /// Get a handle to the number of batches lost because the writer stopped
+    pub fn dropped_batch_counter(&self) -> Arc<AtomicUsize> {
+        self.dropped_batches.clone()
+    }
+
+    /// Run the task, processing timeslots until the input channel is closed
+    pub async fn run(mut self) -> Result<()> {
+        while let Some(mut timeslot) = self.timeslot_receiver.recv().await {
+            if let Some(ref containers) = self.containers {
+                timeslot.containers = containers.get(&timeslot.cgroup_ids());
+            }
+            let batch = rollup_to_batch(&timeslot, self.schema.clone())?;
+            self.timeslot_count.fetch_add(1, Ordering::Relaxed);
+
+            if self.batch_sender.send(batch).await.is_err() {
+                let dropped = drop_pending_timeslots(&mut self.timeslot_receiver);
+                self.dropped_batches.fetch_add(dropped, Ordering::Relaxed);
+                log::error!(
+                    "Batch receiver dropped, {} rollup batches could not be enqueued",
+                    dropped
+                );
+                return Ok(());
+            }
+        }
+
+        log::debug!("Timeslot channel closed, shutting down rollup task");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, Int32Array, Int64Array, StringArray};
+    use nri::metadata::ContainerLimits;
+
+    use crate::attribution::ContainerIdentity;
+    use crate::cgroup_filter::ContainerInfo;
+    use crate::task_metadata::TaskMetadata;
+
+    fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> &'a T {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<T>()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rollup_sums_by_cgroup() {
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel(10);
+        let (batch_sender, mut batch_receiver) = mpsc::channel(10);
+        let task = CgroupRollupTask::new(timeslot_receiver, batch_sender);
+        let schema = task.schema();
+        let timeslot_counter = task.timeslot_counter();
+        let task_handle = tokio::spawn(task.run());
+
+        // Two tasks in cgroup 200, one in cgroup 100, and one without metadata
+        let mut timeslot = TimeslotData::new(2500000);
+        for (pid, cgroup_id, cycles, instructions, llc_misses) in [
+            (1, 200, 1000, 2000, 10),
+            (2, 100, 300, 400, 5),
+            (3, 200, 5000, 6000, 20),
+        ] {
+            let metadata = Some(TaskMetadata::new(pid, [0u8; 16], cgroup_id));
+            let metrics = Metric::from_deltas(cycles, instructions, llc_misses, 0, 1000);
+            timeslot.update(pid, metadata, metrics);
+        }
+        // Further events of a task add to its cgroup
+        timeslot.update(1, None, Metric::from_deltas(1, 1, 1, 0, 1000));
+        timeslot.update(4, None, Metric::from_deltas(7, 8, 9, 0, 1000));
+        timeslot.slots_merged = 2;
+        timeslot_sender.send(timeslot).await.unwrap();
+
+        let batch = batch_receiver.recv().await.unwrap();
+        assert_eq!(batch.schema(), schema);
+        assert_eq!(
+            column::<Int64Array>(&batch, "cgroup_id").values(),
+            &[0, 100, 200]
+        );
+        assert_eq!(
+            column::<Int32Array>(&batch, "task_count").values(),
+            &[1, 1, 2]
+        );
+        assert_eq!(
+            column::<Int64Array>(&batch, "cycles").values(),
+            &[7, 300, 6001]
+        );
+        assert_eq!(
+            column::<Int64Array>(&batch, "instructions").values(),
+            &[8, 400, 8001]
+        );
+        assert_eq!(
+            column::<Int64Array>(&batch, "llc_misses").values(),
+            &[9, 5, 31]
+        );
+        assert_eq!(
+            column::<Int64Array>(&batch, "duration").values(),
+            &[1000, 1000, 3000]
+        );
+        assert!(column::<Int64Array>(&batch, "start_time")
+            .values()
+            .iter()
+            .all(|&start_time| start_time == 2500000));
+        assert!(column::<Int32Array>(&batch, "slots_merged")
+            .values()
+            .iter()
+            .all(|&slots| slots == 2));
+
+        drop(timeslot_sender);
+        task_handle.await.unwrap().unwrap();
+        assert_eq!(timeslot_counter.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_lost_samples_and_limits() {
+        let mut timeslot = TimeslotData::new(1500000);
+        for (pid, cgroup_id) in [(1, 100), (2, 200)] {
+            let metadata = Some(TaskMetadata::new(pid, [0u8; 16], cgroup_id));
+            timeslot.update(pid, metadata, Metric::from_deltas(1000, 2000, 30, 0, 1000));
+        }
+        timeslot.containers.insert(
+            100,
+            Arc::new(ContainerInfo {
+                identity: ContainerIdentity::default(),
+                limits: ContainerLimits {
+                    cpu_quota: Some(50000),
+                    cpuset_cpus: Some("0-3".to_string()),
+                    ..Default::default()
+                },
+            }),
+        );
+        timeslot.lost_count = 9;
+
+        let batch = rollup_to_batch(&timeslot, create_cgroup_rollup_schema()).unwrap();
+        assert_eq!(batch.num_rows(), 3);
+
+        // Limits go on the rows of collected containers
+        let cpu_quota = column::<Int64Array>(&batch, "cpu_quota_usec");
+        assert_eq!(cpu_quota.value(0), 50000);
+        assert!(cpu_quota.is_null(1));
+        let cpuset_cpus = column::<StringArray>(&batch, "cpuset_cpus");
+        assert_eq!(cpuset_cpus.value(0), "0-3");
+        assert!(cpuset_cpus.is_null(1));
+
+        // Lost samples are a last row without a cgroup
+        let cgroup_ids = column::<Int64Array>(&batch, "cgroup_id");
+        let lost_count = column::<Int64Array>(&batch, "lost_count");
+        assert!(lost_count.is_null(0) && lost_count.is_null(1));
+        assert!(cgroup_ids.is_null(2));
+        assert_eq!(lost_count.value(2), 9);
+        assert_eq!(column::<Int32Array>(&batch, "task_count").value(2), 0);
+        assert_eq!(column::<Int64Array>(&batch, "cycles").value(2), 0);
+        assert!(cpu_quota.is_null(2));
+    }
+}
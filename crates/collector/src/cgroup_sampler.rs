@@ -0,0 +1,409 @@
+//! Sampling of cgroup v2 interface files for container cgroups.
+//!
+//! Timeslot rows identify tasks by cgroup id, which on cgroup v2 is the inode
+//! number of the cgroup's directory. [`CgroupSampler`] resolves those ids to
+//! container cgroup directories by walking the cgroup filesystem, keeps the
+//! interface files it reads open across ticks, and bounds the number of
+//! cgroupfs accesses per tick so hosts with many containers are sampled in
+//! rotation rather than all at once. The resolved container directories are
+//! cached, and the hierarchy is walked again for new cgroup ids at most once
+//! per rescan interval.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+/// Default location of the cgroup v2 hierarchy
+pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Number of cgroup ids known not to be containers before the cache is reset
+const MAX_NON_CONTAINERS: usize = 4096;
+
+/// Default minimum time between walks of the hierarchy
+pub const DEFAULT_RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Prefixes of the scope units container runtimes create under systemd
+const CONTAINER_SCOPE_PREFIXES: [&str; 4] = ["cri-containerd-", "crio-", "docker-", "libpod-"];
+
+/// Returns true if `name`, the last component of a cgroup path, is a
+/// container's cgroup
+///
+/// Recognizes the scopes created with the systemd cgroup driver
+/// (`cri-containerd-<id>.scope`) and the bare 64 hex digit container ids
+/// used with the cgroupfs driver.
+pub fn is_container_cgroup(name: &str) -> bool {
+    if let Some(unit) = name.strip_suffix(".scope") {
+        return CONTAINER_SCOPE_PREFIXES
+            .iter()
+            .any(|prefix| unit.starts_with(prefix));
+    }
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Result of reading a cgroup interface file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CgroupRead {
+    /// The file's contents
+    Contents(String),
+    /// The cgroup exists but does not have the file, e.g. on older kernels
+    /// or when the controller providing it is not enabled
+    MissingFile,
+    /// The cgroup was removed
+    Gone,
+}
+
+/// Cgroups to handle in one tick
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Selection {
+    /// Container cgroups to sample this tick
+    pub sample: Vec<u64>,
+    /// Container cgroups found removed since the last tick
+    pub removed: Vec<u64>,
+}
+
+/// Resolves and reads container cgroups, within a per-tick access budget
+pub struct CgroupSampler {
+    root: PathBuf,
+    max_per_tick: usize,
+    /// Container cgroup directories by cgroup id
+    paths: HashMap<u64, PathBuf>,
+    /// Cgroup ids found not to be containers
+    non_containers: HashSet<u64>,
+    /// Open interface files by cgroup id and file name
+    files: HashMap<(u64, &'static str), File>,
+    /// Last cgroup id sampled, to continue the rotation from
+    last_sampled: u64,
+    /// Last absent cgroup id checked for removal
+    last_checked: u64,
+    /// Minimum time between walks of the hierarchy
+    rescan_interval: Duration,
+    /// When the hierarchy was last walked
+    last_rescan: Option<Instant>,
+}
+
+impl CgroupSampler {
+    /// Create a sampler for the hierarchy mounted at `root`, accessing at
+    /// most `max_per_tick` cgroups per tick
+    pub fn new(root: impl Into<PathBuf>, max_per_tick: usize) -> Self {
+        Self {
+            root: root.into(),
+            max_per_tick: max_per_tick.max(1),
+            paths: HashMap::new(),
+            non_containers: HashSet::new(),
+            files: HashMap::new(),
+            last_sampled: 0,
+            last_checked: 0,
+            rescan_interval: DEFAULT_RESCAN_INTERVAL,
+            last_rescan: None,
+        }
+    }
+
+    /// Walk the hierarchy for new cgroup ids at most once per `interval`
+    pub fn with_rescan_interval(mut self, interval: Duration) -> Self {
+        self.rescan_interval = interval;
+        self
+    }
+
+    /// Choose the cgroups to handle this tick, given the cgroup ids of the
+    /// tasks in the tick.
+    ///
+    /// Container cgroups among `cgroup_ids` are sampled in rotation, up to
+    /// the per-tick budget. Budget left over goes to checking whether known
+    /// containers absent from the tick were removed; removed ones are
+    /// forgotten and reported so callers can drop their state. Cgroup ids
+    /// not resolved yet wait for the next rescan.
+    pub fn select(&mut self, cgroup_ids: &HashSet<u64>) -> Selection {
+        let rescan_due = self
+            .last_rescan
+            .is_none_or(|last| last.elapsed() >= self.rescan_interval);
+        if rescan_due
+            && cgroup_ids
+                .iter()
+                .any(|id| !self.paths.contains_key(id) && !self.non_containers.contains(id))
+        {
+            self.rescan(cgroup_ids);
+        }
+
+        let mut present: Vec<u64> = cgroup_ids
+            .iter()
+            .copied()
+            .filter(|id| self.paths.contains_key(id))
+            .collect();
+        let sample = rotate(&mut present, self.last_sampled, self.max_per_tick);
+        if let Some(&last) = sample.last() {
+            self.last_sampled = last;
+        }
+
+        let budget = self.max_per_tick - sample.len();
+        let mut absent: Vec<u64> = self
+            .paths
+            .keys()
+            .copied()
+            .filter(|id| !cgroup_ids.contains(id))
+            .collect();
+        let mut removed = Vec::new();
+        for id in rotate(&mut absent, self.last_checked, budget) {
+            self.last_checked = id;
+            if !self.paths[&id].exists() {
+                self.forget(id);
+                removed.push(id);
+            }
+        }
+
+        Selection { sample, removed }
+    }
+
+    /// Read interface file `name` of container cgroup `cgroup_id`.
+    ///
+    /// The file is kept open for later ticks. A cgroup found removed is
+    /// forgotten.
+    pub fn read(&mut self, cgroup_id: u64, name: &'static str) -> io::Result<CgroupRead> {
+        let Some(dir) = self.paths.get(&cgroup_id) else {
+            return Ok(CgroupRead::Gone);
+        };
+
+        let file = match self.files.entry((cgroup_id, name)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match File::open(dir.join(name)) {
+                Ok(file) => entry.insert(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    if dir.exists() {
+                        return Ok(CgroupRead::MissingFile);
+                    }
+                    self.forget(cgroup_id);
+                    return Ok(CgroupRead::Gone);
+                }
+                Err(e) => return Err(e),
+            },
+        };
+
+        match read_all(file) {
+            Ok(contents) => Ok(CgroupRead::Contents(contents)),
+            // Files of a removed cgroup fail with ENODEV
+            Err(e)
+                if e.raw_os_error() == Some(libc::ENODEV)
+                    || e.kind() == io::ErrorKind::NotFound =>
+            {
+                self.forget(cgroup_id);
+                Ok(CgroupRead::Gone)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Number of container cgroups currently known
+    #[cfg(test)]
+    pub fn container_count(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Drop the path and open files of a cgroup
+    fn forget(&mut self, cgroup_id: u64) {
+        self.paths.remove(&cgroup_id);
+        self.files.retain(|(id, _), _| *id != cgroup_id);
+    }
+
+    /// Walk the hierarchy to resolve unknown cgroup ids
+    fn rescan(&mut self, cgroup_ids: &HashSet<u64>) {
+        self.last_rescan = Some(Instant::now());
+        let mut found = 0;
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                // Cgroups can be removed while walking
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if !metadata.is_dir() {
+                    continue;
+                }
+                let path = entry.path();
+                let is_container = entry.file_name().to_str().is_some_and(is_container_cgroup);
+                if is_container && !self.paths.contains_key(&metadata.ino()) {
+                    self.paths.insert(metadata.ino(), path.clone());
+                    found += 1;
+                }
+                pending.push(path);
+            }
+        }
+
+        if self.non_containers.len() >= MAX_NON_CONTAINERS {
+            self.non_containers.clear();
+        }
+        for &id in cgroup_ids {
+            if !self.paths.contains_key(&id) {
+                self.non_containers.insert(id);
+            }
+        }
+        debug!(
+            "Scanned {} for container cgroups, found {} new",
+            self.root.display(),
+            found
+        );
+    }
+}
+
+/// Take up to `limit` ids, continuing in id order after `last` and wrapping around
+fn rotate(ids: &mut [u64], last: u64, limit: usize) -> Vec<u64> {
+    ids.sort_unstable();
+    let start = ids.partition_point(|&id| id <= last);
+    ids[start..]
+        .iter()
+        .chain(&ids[..start])
+        .take(limit)
+        .copied()
+        .collect()
+}
+
+/// Read a file from the start, without moving a shared offset
+fn read_all(file: &File) -> io::Result<String> {
+    let mut contents = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = file.read_at(&mut buf, contents.len() as u64)?;
+        if len == 0 {
+            break;
+        }
+        contents.extend_from_slice(&buf[..len]);
+    }
+    String::from_utf8(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A cgroup hierarchy in a temporary directory
+    pub(crate) struct FakeCgroupFs {
+        pub root: PathBuf,
+    }
+
+    impl FakeCgroupFs {
+        pub fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "collector-cgroupfs-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Self { root }
+        }
+
+        /// Create a cgroup directory and return its cgroup id
+        pub fn add_cgroup(&self, path: &str) -> u64 {
+            let dir = self.root.join(path);
+            fs::create_dir_all(&dir).unwrap();
+            fs::metadata(&dir).unwrap().ino()
+        }
+
+        pub fn write(&self, path: &str, name: &str, contents: &str) {
+            fs::write(self.root.join(path).join(name), contents).unwrap();
+        }
+
+        pub fn remove_cgroup(&self, path: &str) {
+            fs::remove_dir_all(self.root.join(path)).unwrap();
+        }
+    }
+
+    impl Drop for FakeCgroupFs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    const POD: &str = "kubepods.slice/kubepods-pod1.slice";
+
+    fn container(name: &str) -> String {
+        format!("{}/cri-containerd-{}.scope", POD, name)
+    }
+
+    #[test]
+    fn test_is_container_cgroup() {
+        assert!(is_container_cgroup("cri-containerd-abc.scope"));
+        assert!(is_container_cgroup("docker-abc.scope"));
+        assert!(is_container_cgroup(&"a1".repeat(32)));
+        assert!(!is_container_cgroup("kubepods-pod1.slice"));
+        assert!(!is_container_cgroup("sshd.service"));
+        assert!(!is_container_cgroup("session-3.scope"));
+    }
+
+    #[test]
+    fn test_select_resolves_and_rotates() {
+        let cgroupfs = FakeCgroupFs::new("rotate");
+        let ids: Vec<u64> = ["a", "b", "c"]
+            .iter()
+            .map(|name| cgroupfs.add_cgroup(&container(name)))
+            .collect();
+        let pod = cgroupfs.add_cgroup(POD);
+        let mut sampler = CgroupSampler::new(&cgroupfs.root, 2);
+
+        let tick: HashSet<u64> = ids.iter().copied().chain([pod]).collect();
+        let mut sorted = ids.clone();
+        sorted.sort_unstable();
+
+        // Only containers are sampled, two per tick, continuing where the last tick stopped
+        assert_eq!(sampler.select(&tick).sample, sorted[..2]);
+        assert_eq!(sampler.select(&tick).sample, vec![sorted[2], sorted[0]]);
+        assert_eq!(sampler.container_count(), 3);
+    }
+
+    #[test]
+    fn test_read_and_removal() {
+        let cgroupfs = FakeCgroupFs::new("read");
+        let a = cgroupfs.add_cgroup(&container("a"));
+        let b = cgroupfs.add_cgroup(&container("b"));
+        cgroupfs.write(&container("a"), "cpu.stat", "nr_periods 1\n");
+        let mut sampler = CgroupSampler::new(&cgroupfs.root, 8);
+        sampler.select(&HashSet::from([a, b]));
+
+        assert_eq!(
+            sampler.read(a, "cpu.stat").unwrap(),
+            CgroupRead::Contents("nr_periods 1\n".to_string())
+        );
+        assert_eq!(
+            sampler.read(b, "cpu.stat").unwrap(),
+            CgroupRead::MissingFile
+        );
+
+        // A removed cgroup absent from the tick is found by the spare budget
+        cgroupfs.remove_cgroup(&container("b"));
+        let selection = sampler.select(&HashSet::from([a]));
+        assert_eq!(selection.sample, vec![a]);
+        assert_eq!(selection.removed, vec![b]);
+        assert_eq!(sampler.read(b, "cpu.stat").unwrap(), CgroupRead::Gone);
+        assert_eq!(sampler.container_count(), 1);
+    }
+
+    #[test]
+    fn test_rescan_interval() {
+        let cgroupfs = FakeCgroupFs::new("rescan");
+        let a = cgroupfs.add_cgroup(&container("a"));
+        let mut sampler =
+            CgroupSampler::new(&cgroupfs.root, 8).with_rescan_interval(Duration::from_secs(3600));
+        assert_eq!(sampler.select(&HashSet::from([a])).sample, vec![a]);
+
+        // A new container waits for the next rescan instead of walking the
+        // hierarchy on every tick
+        let b = cgroupfs.add_cgroup(&container("b"));
+        assert_eq!(sampler.select(&HashSet::from([a, b])).sample, vec![a]);
+        assert_eq!(sampler.container_count(), 1);
+
+        sampler.rescan_interval = Duration::ZERO;
+        let mut sample = sampler.select(&HashSet::from([a, b])).sample;
+        sample.sort_unstable();
+        let mut expected = vec![a, b];
+        expected.sort_unstable();
+        assert_eq!(sample, expected);
+    }
+}
@@ -0,0 +1,616 @@
+//! Collection from the BPF programs.
+//!
+//! [`Collector::run_streaming`] starts the output pipeline and returns a
+//! [`Collection`] along with the stream of the pipeline's record batches.
+//! [`Collection::run`] then loads the BPF programs, feeds their events to the
+//! processor and polls them until shutdown, while [`Collection::attach`]
+//! feeds the processor from rings the caller polls instead. The collector
+//! binary hands the stream to the parquet writer; applications embedding the
+//! collector consume it themselves.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use arrow_array::RecordBatch;
+use bpf::{BpfLoader, BpfLoaderConfig, ProgramGroup};
+use log::{error, info, warn};
+use perf_events::{Dispatcher, RingIndexSemantics, RingStats, Stats};
+use timeslot::{MinTracker, TrackerState};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::task::TaskTracker;
+
+use crate::adaptive::{AdaptiveConfig, AdaptiveController, PressureSample, SelfCpuSampler};
+use crate::adaptive_poll::{AdaptivePoll, AdaptivePollConfig, FIXED_POLL_SLEEP};
+use crate::bpf_perf_to_trace::{TraceLimits, TraceMemory};
+use crate::cgroup_filter::CgroupFilter;
+use crate::debug_endpoint::{self, ChannelProbe, DebugSnapshots};
+use crate::error_code::error_code;
+use crate::event_capture::CaptureControl;
+use crate::metrics::{ErrorCounts, WriterMemoryGauge};
+use crate::perf_event_processor::{PerfEventProcessor, ProcessorMode};
+use crate::pid_namespace::PidNamespaceTranslator;
+use crate::pipeline::{Pipeline, PipelineConfig};
+use crate::processor_log::ProcessorRecorder;
+use crate::sd_notify::SdNotifier;
+use crate::shutdown::{ShutdownReason, ShutdownToken};
+use crate::state_file::monotonic_now_ns;
+use crate::suspend_monitor::{ClockReading, SuspendMonitor, SuspendMonitorConfig};
+use crate::timeslot_data::TimeslotData;
+
+/// How often adaptive collection samples CPU usage and ring fill
+const ADAPTIVE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the collector loads, outputs and how it polls
+#[derive(Default)]
+pub struct CollectorConfig {
+    /// What the pipeline outputs, and its optional stages
+    pub pipeline: PipelineConfig,
+    /// BPF program groups to load
+    pub bpf: BpfLoaderConfig,
+    /// Bounds on the rows and memory of trace output
+    pub trace_limits: TraceLimits,
+    /// Degrades collection under CPU pressure, if set
+    pub adaptive: Option<AdaptiveConfig>,
+    /// Adapts the sleep between idle polls to the load, instead of a fixed
+    /// sleep, if set
+    pub adaptive_poll: Option<AdaptivePollConfig>,
+    /// When to restart a sync timer that stalled across a system suspend
+    pub suspend_monitor: SuspendMonitorConfig,
+}
+
+/// Entry point of collection
+pub struct Collector {
+    config: CollectorConfig,
+}
+
+impl Collector {
+    /// Create a collector producing the output selected by `config`
+    pub fn new(config: CollectorConfig) -> Self {
+        Self { config }
+    }
+
+    /// Start the pipeline, spawning its tasks on `task_tracker`, and return
+    /// the collection feeding it along with the stream of its record
+    /// batches. The stream ends once the collection stops and the remaining
+    /// batches are read.
+    pub fn run_streaming(
+        mut self,
+        task_tracker: &TaskTracker,
+        shutdown_token: &ShutdownToken,
+    ) -> Result<(Collection, ReceiverStream<RecordBatch>)> {
+        let pipeline_config = std::mem::take(&mut self.config.pipeline);
+        let (pipeline, batch_receiver) =
+            Pipeline::build(pipeline_config, task_tracker, shutdown_token)?;
+        let collection = Collection {
+            pipeline,
+            config: self.config,
+            recorder: None,
+            pid_translator: None,
+            cgroup_filter: None,
+            capture_control: None,
+            debug_snapshots: None,
+            sd_notifier: None,
+            error_counts: None,
+            saved_timeslots: None,
+        };
+        Ok((collection, ReceiverStream::new(batch_receiver)))
+    }
+}
+
+/// Collection into a running pipeline, waiting to load the BPF programs
+pub struct Collection {
+    /// The pipeline the processor feeds
+    pub pipeline: Pipeline,
+    config: CollectorConfig,
+    recorder: Option<ProcessorRecorder>,
+    pid_translator: Option<PidNamespaceTranslator>,
+    cgroup_filter: Option<CgroupFilter>,
+    capture_control: Option<CaptureControl>,
+    debug_snapshots: Option<(Arc<DebugSnapshots>, Option<Arc<WriterMemoryGauge>>)>,
+    sd_notifier: Option<SdNotifier>,
+    error_counts: Option<Arc<ErrorCounts>>,
+    saved_timeslots: Option<(TrackerState, u64)>,
+}
+
+/// A collection subscribed to the rings of a dispatcher
+pub struct AttachedCollection {
+    processor: Rc<RefCell<PerfEventProcessor>>,
+    channel_probes: Vec<ChannelProbe>,
+    config: CollectorConfig,
+    capture_control: Option<CaptureControl>,
+    debug_snapshots: Option<(Arc<DebugSnapshots>, Option<Arc<WriterMemoryGauge>>)>,
+    sd_notifier: Option<SdNotifier>,
+    error_counts: Option<Arc<ErrorCounts>>,
+}
+
+impl AttachedCollection {
+    /// Send what the processor still holds, such as the current timeslot.
+    /// The pipeline's input closes once the dispatcher is dropped.
+    pub fn shutdown(&self) {
+        self.processor.borrow_mut().shutdown();
+    }
+}
+
+/// What a collection saw, once it stopped
+pub struct CollectionReport {
+    /// Dispatcher counters over the whole collection
+    pub dispatcher_stats: Stats,
+    /// Counters of each ring
+    pub ring_stats: Vec<RingStats>,
+    /// What the ring indices of the counters mean
+    pub ring_index_semantics: RingIndexSemantics,
+    /// Memory of the trace path, in trace mode
+    pub trace_memory: Option<Arc<TraceMemory>>,
+    /// The controller that degraded collection, if adaptive
+    pub adaptive: Option<AdaptiveController>,
+    /// Progress of the timeslot tracker, to continue from after a restart
+    pub timeslot_tracker: TrackerState,
+}
+
+impl Collection {
+    /// Insert a stage between the processor and the conversion of
+    /// timeslots. Returns the receiver of the processor's timeslots and the
+    /// sender of the conversion, or None when the pipeline outputs a trace.
+    pub fn insert_timeslot_stage(
+        &mut self,
+        capacity: usize,
+    ) -> Option<(mpsc::Receiver<TimeslotData>, mpsc::Sender<TimeslotData>)> {
+        let ProcessorMode::Timeslot(conversion_sender) = &mut self.pipeline.processor_mode else {
+            return None;
+        };
+        let (stage_sender, stage_receiver) = mpsc::channel(capacity);
+        let conversion_sender = std::mem::replace(conversion_sender, stage_sender);
+        Some((stage_receiver, conversion_sender))
+    }
+
+    /// Log every event consumed by the processor, and the timeslots it emits
+    pub fn set_recorder(&mut self, recorder: ProcessorRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /// Translate pids into their container's pid namespace
+    pub fn set_pid_translator(&mut self, translator: PidNamespaceTranslator) {
+        self.pid_translator = Some(translator);
+    }
+
+    /// Only collect the cgroups `filter` follows
+    pub fn set_cgroup_filter(&mut self, filter: CgroupFilter) {
+        self.cgroup_filter = Some(filter);
+    }
+
+    /// Start and stop raw event captures as `control` is toggled
+    pub fn set_capture_control(&mut self, control: CaptureControl) {
+        self.capture_control = Some(control);
+    }
+
+    /// Publish snapshots of the polling loop's state when `snapshots` asks
+    /// for them, including the memory of the writer consuming the stream
+    pub fn set_debug_snapshots(
+        &mut self,
+        snapshots: Arc<DebugSnapshots>,
+        writer_memory: Option<Arc<WriterMemoryGauge>>,
+    ) {
+        self.debug_snapshots = Some((snapshots, writer_memory));
+    }
+
+    /// Tell systemd when collection is up, polling, and stopping
+    pub fn set_sd_notifier(&mut self, notifier: SdNotifier) {
+        self.sd_notifier = Some(notifier);
+    }
+
+    /// Count the errors collection carries on after, such as failing to
+    /// restart a stalled sync timer, in `counts`
+    pub fn set_error_counts(&mut self, counts: Arc<ErrorCounts>) {
+        self.error_counts = Some(counts);
+    }
+
+    /// Continue timeslot tracking from a previous run's snapshot, unless it
+    /// is older than `max_staleness_slots` or was taken on another machine
+    pub fn restore_timeslot_tracker(&mut self, snapshot: TrackerState, max_staleness_slots: u64) {
+        self.saved_timeslots = Some((snapshot, max_staleness_slots));
+    }
+
+    /// Feed the pipeline from the rings `dispatcher` reads, indexed by CPU,
+    /// instead of the BPF programs. The caller writes and polls the rings,
+    /// such as a load generator standing in for the BPF programs; only the
+    /// program groups of the BPF configuration apply.
+    pub fn attach(self, dispatcher: &mut Dispatcher, num_cpus: usize) -> AttachedCollection {
+        let Self {
+            pipeline,
+            config,
+            recorder,
+            pid_translator,
+            cgroup_filter,
+            capture_control,
+            debug_snapshots,
+            sd_notifier,
+            error_counts,
+            saved_timeslots,
+        } = self;
+
+        // Create PerfEventProcessor with the appropriate mode
+        let processor = PerfEventProcessor::with_dispatcher(
+            dispatcher,
+            num_cpus,
+            pipeline.processor_mode,
+            recorder.map(|recorder| Rc::new(RefCell::new(recorder))),
+            &config.bpf,
+        );
+
+        if let Some(translator) = pid_translator {
+            let translator = Rc::new(RefCell::new(translator));
+            PidNamespaceTranslator::attach(&translator, dispatcher);
+            processor.borrow_mut().set_pid_translator(translator);
+        }
+        if let Some(filter) = cgroup_filter {
+            processor
+                .borrow_mut()
+                .set_cgroup_filter(Rc::new(RefCell::new(filter)));
+        }
+        processor
+            .borrow_mut()
+            .set_trace_limits(config.trace_limits.clone());
+        let (layout, containers) = pipeline.trace_attribution;
+        processor
+            .borrow_mut()
+            .set_trace_attribution(layout, containers);
+        if let Some(stale) = pipeline.attribution_stale {
+            processor.borrow_mut().set_attribution_stale(stale);
+        }
+
+        // Continue timeslot tracking from the previous run, if it is recent enough
+        if let Some((snapshot, max_staleness_slots)) = saved_timeslots {
+            restore_timeslot_tracker(
+                &mut processor.borrow_mut(),
+                snapshot,
+                num_cpus,
+                max_staleness_slots,
+            );
+        }
+
+        AttachedCollection {
+            processor,
+            channel_probes: pipeline.channel_probes,
+            config,
+            capture_control,
+            debug_snapshots,
+            sd_notifier,
+            error_counts,
+        }
+    }
+
+    /// Load and attach the BPF programs, then poll their events until
+    /// `shutdown_token` is cancelled. A polling error cancels the token;
+    /// loading and attaching errors are returned.
+    pub async fn run(self, shutdown_token: &ShutdownToken) -> Result<CollectionReport> {
+        let num_cpus = libbpf_rs::num_possible_cpus()?;
+
+        if !self.config.bpf.is_enabled(ProgramGroup::TaskLifecycle) {
+            warn!(
+                "The {} program group is disabled, metadata of exited tasks will not be released",
+                ProgramGroup::TaskLifecycle
+            );
+        }
+
+        // Create a BPF loader with the selected program groups
+        let mut bpf_loader = BpfLoader::with_config(self.config.bpf.clone())?;
+
+        // Initialize the sync timer
+        let mut suspend_monitor = None;
+        if bpf_loader.config().is_enabled(ProgramGroup::SyncTimer) {
+            bpf_loader.start_sync_timer()?;
+
+            // Count timer messages, to restart the timer if a suspend stops it
+            let timer_messages = Rc::new(Cell::new(0u64));
+            let counter = timer_messages.clone();
+            bpf_loader.dispatcher_mut().subscribe(
+                bpf::msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING as u32,
+                move |_ring_index, _data| counter.set(counter.get() + 1),
+            );
+            suspend_monitor = Some((
+                SuspendMonitor::new(self.config.suspend_monitor.clone()),
+                timer_messages,
+            ));
+        }
+
+        // The dispatcher resyncs rings the writer overwrote, which can deliver
+        // measurements again that timeslots would double count
+        bpf_loader
+            .dispatcher_mut()
+            .enable_dedup(bpf::msg_type::MSG_TYPE_PERF_MEASUREMENT as u32);
+
+        let AttachedCollection {
+            processor,
+            channel_probes,
+            config,
+            capture_control,
+            debug_snapshots,
+            mut sd_notifier,
+            error_counts,
+        } = self.attach(bpf_loader.dispatcher_mut(), num_cpus);
+        let trace_memory = processor.borrow().trace_memory();
+
+        // Catch maps the kernel created differently than declared before attaching
+        bpf_loader.validate_maps()?;
+
+        // Attach BPF programs
+        bpf_loader.attach()?;
+
+        // Sample pressure periodically and degrade collection when overloaded
+        let mut adaptive = config.adaptive.map(|adaptive_config| {
+            let mut cpu_sampler = SelfCpuSampler::new();
+            cpu_sampler.sample();
+            (
+                AdaptiveController::new(adaptive_config),
+                cpu_sampler,
+                Instant::now(),
+            )
+        });
+        let mut poll_delay = Duration::ZERO;
+        let mut adaptive_poll = config.adaptive_poll.map(AdaptivePoll::new);
+
+        info!("Collection started.");
+
+        // Tell systemd we are up, when running as a notify service
+        if let Some(notifier) = &sd_notifier {
+            notifier.ready();
+        }
+
+        // Run BPF polling in the calling task until signaled to stop
+        loop {
+            // Check if we should shutdown
+            if shutdown_token.is_cancelled() {
+                break;
+            }
+
+            // Poll for events, sleeping when idle: a fixed 10ms, or as long as
+            // recent batches suggest with adaptive polling
+            let idle_sleep = adaptive_poll
+                .as_ref()
+                .map_or(FIXED_POLL_SLEEP, AdaptivePoll::sleep);
+            match bpf_loader.poll_events(idle_sleep) {
+                Ok(summary) => {
+                    if let Some(adaptive_poll) = adaptive_poll.as_mut() {
+                        adaptive_poll.observe(&summary);
+                    }
+                }
+                Err(e) => {
+                    // Log error directly and cancel shutdown token
+                    error!("BPF polling error: {}", e);
+                    shutdown_token.cancel(ShutdownReason::Error {
+                        message: format!("BPF polling error: {}", e),
+                        code: error_code(&e),
+                    });
+                    break;
+                }
+            }
+
+            if let Some(control) = &capture_control {
+                control.poll(bpf_loader.dispatcher_mut());
+            }
+
+            // Timers can stop firing after the system resumes from suspend
+            if let Some((monitor, timer_messages)) = suspend_monitor.as_mut() {
+                if let Some(suspended) = monitor.check(ClockReading::now(), timer_messages.get()) {
+                    warn!(
+                        "Sync timer stalled after the system was suspended for {:?}, restarting it",
+                        suspended
+                    );
+                    match bpf_loader.restart_sync_timer() {
+                        Ok(()) => info!("Sync timer recovered from a {:?} suspend", suspended),
+                        Err(e) => {
+                            error!("Failed to restart sync timer: {:#}", e);
+                            if let (Some(counts), Some(code)) = (&error_counts, error_code(&e)) {
+                                counts.record(code);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Snapshots are taken between read batches, when asked for
+            if let Some((snapshots, writer_memory)) =
+                debug_snapshots.as_ref().filter(|(s, _)| s.requested())
+            {
+                snapshots.publish(debug_endpoint::capture(
+                    &bpf_loader.ring_states(),
+                    bpf_loader.dispatcher(),
+                    &processor.borrow(),
+                    &channel_probes,
+                    writer_memory.as_deref(),
+                    adaptive.as_ref().map(|(controller, _, _)| controller),
+                ));
+            }
+
+            if let Some((controller, cpu_sampler, last_sample)) = adaptive.as_mut() {
+                if last_sample.elapsed() >= ADAPTIVE_SAMPLE_INTERVAL {
+                    *last_sample = Instant::now();
+                    if let Some(cpu_fraction) = cpu_sampler.sample() {
+                        let sample = PressureSample {
+                            cpu_fraction,
+                            ring_fill: bpf_loader.max_ring_fill(),
+                        };
+                        if controller.observe(sample).is_some() {
+                            let mitigations = controller.mitigations();
+                            processor.borrow_mut().apply_mitigations(&mitigations);
+                            poll_delay = mitigations.poll_delay;
+                        }
+                    }
+                }
+            }
+
+            // Only a completed poll cycle pets the watchdog, so a wedged loop
+            // gets restarted by systemd
+            if let Some(notifier) = sd_notifier.as_mut() {
+                notifier.cycle_completed();
+            }
+
+            // Drive the tokio runtime forward, waiting between polls when degraded
+            if poll_delay.is_zero() {
+                tokio::task::yield_now().await;
+            } else {
+                tokio::time::sleep(poll_delay).await;
+            }
+        }
+
+        if let Some(notifier) = &sd_notifier {
+            notifier.stopping();
+        }
+
+        // Clean up: shutdown the processor
+        processor.borrow_mut().shutdown();
+
+        let timeslot_tracker = processor.borrow().timeslot_snapshot();
+        Ok(CollectionReport {
+            dispatcher_stats: bpf_loader.dispatcher().stats(),
+            ring_stats: bpf_loader.ring_stats().to_vec(),
+            ring_index_semantics: bpf_loader.config().ring_index_semantics.clone(),
+            trace_memory,
+            adaptive: adaptive.map(|(controller, _, _)| controller),
+            timeslot_tracker,
+        })
+    }
+}
+
+/// Restore the timeslot tracker from a snapshot, logging whether it was applied
+fn restore_timeslot_tracker(
+    processor: &mut PerfEventProcessor,
+    snapshot: TrackerState,
+    num_cpus: usize,
+    max_staleness_slots: u64,
+) {
+    let expected = processor.timeslot_snapshot();
+    if snapshot.cpu_timestamps.len() != num_cpus
+        || snapshot.time_slot_size != expected.time_slot_size
+    {
+        info!(
+            "Rejected timeslot tracker snapshot: it has {} CPUs and {}ns slots, expected {} CPUs and {}ns slots",
+            snapshot.cpu_timestamps.len(),
+            snapshot.time_slot_size,
+            num_cpus,
+            expected.time_slot_size
+        );
+        return;
+    }
+
+    match MinTracker::restore_checked(snapshot, monotonic_now_ns(), max_staleness_slots) {
+        Ok(min_tracker) => {
+            info!(
+                "Restored timeslot tracker snapshot, minimum slot {:?}",
+                min_tracker.get_min()
+            );
+            processor.restore_timeslot_tracker(min_tracker);
+        }
+        Err(e) => info!("Rejected timeslot tracker snapshot: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    use crate::metrics::Metric;
+    use crate::task_metadata::TaskMetadata;
+
+    #[tokio::test]
+    async fn test_streaming_collection() {
+        for (cgroup_rollup, rows) in [(false, 3), (true, 2)] {
+            let task_tracker = TaskTracker::new();
+            let shutdown_token = ShutdownToken::new();
+            let config = CollectorConfig {
+                pipeline: PipelineConfig {
+                    cgroup_rollup,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let (collection, stream) = Collector::new(config)
+                .run_streaming(&task_tracker, &shutdown_token)
+                .unwrap();
+            task_tracker.close();
+            let pipeline = collection.pipeline;
+            let ProcessorMode::Timeslot(timeslot_sender) = pipeline.processor_mode else {
+                panic!("Expected timeslot output");
+            };
+
+            // A short collection: three tasks in two cgroups over two timeslots
+            for start_timestamp in [1_000_000, 2_000_000] {
+                let mut timeslot = TimeslotData::new(start_timestamp);
+                for (pid, cgroup_id) in [(1, 100), (2, 100), (3, 200)] {
+                    timeslot.update(
+                        pid,
+                        Some(TaskMetadata::new(pid, [0u8; 16], cgroup_id)),
+                        Metric::from_deltas(1000, 2000, 10, 20, 1000),
+                    );
+                }
+                timeslot_sender.send(timeslot).await.unwrap();
+            }
+            drop(timeslot_sender);
+
+            let batches: Vec<RecordBatch> = stream.collect().await;
+            assert_eq!(batches.len(), 2);
+            for batch in &batches {
+                assert_eq!(batch.schema(), pipeline.schema);
+                assert_eq!(batch.num_rows(), rows);
+            }
+            task_tracker.wait().await;
+            assert_eq!(
+                pipeline
+                    .timeslot_counter
+                    .unwrap()
+                    .load(std::sync::atomic::Ordering::Relaxed),
+                2
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inserted_timeslot_stage() {
+        let task_tracker = TaskTracker::new();
+        let shutdown_token = ShutdownToken::new();
+        let (mut collection, stream) = Collector::new(CollectorConfig::default())
+            .run_streaming(&task_tracker, &shutdown_token)
+            .unwrap();
+        task_tracker.close();
+        let (mut stage_receiver, conversion_sender) = collection.insert_timeslot_stage(1).unwrap();
+        let ProcessorMode::Timeslot(timeslot_sender) = collection.pipeline.processor_mode else {
+            panic!("Expected timeslot output");
+        };
+
+        // The processor's timeslots reach the stage, which passes them on
+        timeslot_sender
+            .send(TimeslotData::new(1_000_000))
+            .await
+            .unwrap();
+        drop(timeslot_sender);
+        while let Some(timeslot) = stage_receiver.recv().await {
+            conversion_sender.send(timeslot).await.unwrap();
+        }
+        drop(conversion_sender);
+
+        let batches: Vec<RecordBatch> = stream.collect().await;
+        assert_eq!(batches.len(), 1);
+        task_tracker.wait().await;
+    }
+
+    #[tokio::test]
+    async fn test_no_timeslot_stage_in_trace_mode() {
+        let task_tracker = TaskTracker::new();
+        let config = CollectorConfig {
+            pipeline: PipelineConfig {
+                trace: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut collection, _stream) = Collector::new(config)
+            .run_streaming(&task_tracker, &ShutdownToken::new())
+            .unwrap();
+        assert!(collection.insert_timeslot_stage(1).is_none());
+    }
+}
@@ -0,0 +1,697 @@
+//! Container lifecycle events as rows of a separate, low-volume output.
+//!
+//! Every NRI metadata message becomes a row, stamped with the time the
+//! collector received it, so the containers behind the metrics can be
+//! analyzed offline. Rows are written in batches, cut after a number of rows
+//! or once the oldest pending row is old enough.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use arrow_array::builder::{Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use nri::metadata::{ContainerMetadata, LifecycleHint, MetadataMessage};
+use tokio::sync::mpsc;
+
+use crate::redaction::{Redact, RedactionTarget};
+
+/// Version of the container events schema, bumped whenever columns change
+pub const CONTAINER_EVENTS_SCHEMA_VERSION: u32 = 1;
+
+/// Create the schema for container event record batches
+pub fn create_container_events_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("timestamp", DataType::Int64, false),
+        Field::new("event_type", DataType::Utf8, false),
+        Field::new("container_id", DataType::Utf8, false),
+        // Identity and limits are null when removing a container never added
+        Field::new("container_name", DataType::Utf8, true),
+        Field::new("pod_name", DataType::Utf8, true),
+        Field::new("pod_namespace", DataType::Utf8, true),
+        Field::new("pod_uid", DataType::Utf8, true),
+        Field::new("cgroup_path", DataType::Utf8, true),
+        Field::new("cpu_quota", DataType::Int64, true),
+        Field::new("cpu_period", DataType::Int64, true),
+        Field::new("memory_limit", DataType::Int64, true),
+        Field::new("cpuset_cpus", DataType::Utf8, true),
+        // JSON object of the selected labels the container has
+        Field::new("labels", DataType::Utf8, true),
+    ]))
+}
+
+/// Redaction of the `targets` held in the container events output, which
+/// keeps pod labels in its labels column. None if no target is in the output.
+pub fn events_redaction(targets: &[RedactionTarget], key: Option<[u8; 32]>) -> Option<Redact> {
+    let targets: Vec<RedactionTarget> = targets
+        .iter()
+        .copied()
+        .filter(|target| *target == RedactionTarget::PodLabels)
+        .collect();
+    (!targets.is_empty())
+        .then(|| Redact::new(targets, key).with_column(RedactionTarget::PodLabels, "labels"))
+}
+
+/// What happened to a container.
+///
+/// Added, updated and removed follow the container's metadata. Metadata
+/// messages do not say which NRI request produced them, so a container's
+/// first message is taken as its addition and later ones as updates. After
+/// the plugin reconnects, the runtime synchronizes running containers again,
+/// which shows up as updates.
+///
+/// Created, started and stopped are the lifecycle hints of the NRI requests
+/// themselves, recorded as rows of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerEventType {
+    Added,
+    Updated,
+    Removed,
+    Created,
+    Started,
+    Stopped,
+}
+
+impl ContainerEventType {
+    /// Value of the event_type column
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContainerEventType::Added => "added",
+            ContainerEventType::Updated => "updated",
+            ContainerEventType::Removed => "removed",
+            ContainerEventType::Created => "created",
+            ContainerEventType::Started => "started",
+            ContainerEventType::Stopped => "stopped",
+        }
+    }
+}
+
+impl From<LifecycleHint> for ContainerEventType {
+    fn from(hint: LifecycleHint) -> Self {
+        match hint {
+            LifecycleHint::Created => ContainerEventType::Created,
+            LifecycleHint::Started => ContainerEventType::Started,
+            LifecycleHint::Stopped => ContainerEventType::Stopped,
+        }
+    }
+}
+
+/// Configuration of the container events output
+#[derive(Debug, Clone)]
+pub struct ContainerEventsConfig {
+    /// Cut a batch once it has this many rows
+    pub max_rows: usize,
+    /// Cut a batch once its oldest row is this old
+    pub max_age: Duration,
+    /// Labels copied into the labels column, when the container has them
+    pub labels: Vec<String>,
+}
+
+impl Default for ContainerEventsConfig {
+    fn default() -> Self {
+        Self {
+            max_rows: 1000,
+            max_age: Duration::from_secs(30),
+            labels: Vec::new(),
+        }
+    }
+}
+
+/// A row waiting to be written
+struct EventRow {
+    timestamp: i64,
+    event_type: ContainerEventType,
+    container_id: String,
+    metadata: Option<ContainerMetadata>,
+}
+
+/// Turns metadata messages into rows and cuts them into batches
+pub struct ContainerEventRecorder {
+    config: ContainerEventsConfig,
+    schema: SchemaRef,
+    // Latest metadata of each container added and not yet removed, so
+    // removals carry the container's identity
+    containers: HashMap<String, ContainerMetadata>,
+    rows: Vec<EventRow>,
+    // When the oldest pending row was recorded
+    oldest: Option<Instant>,
+}
+
+impl ContainerEventRecorder {
+    pub fn new(config: ContainerEventsConfig) -> Self {
+        Self {
+            config,
+            schema: create_container_events_schema(),
+            containers: HashMap::new(),
+            rows: Vec::new(),
+            oldest: None,
+        }
+    }
+
+    /// Get the schema for the record batches this recorder produces
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Add a row for a message received at `received`, returning a batch if
+    /// this fills one
+    pub fn record(
+        &mut self,
+        message: MetadataMessage,
+        received: SystemTime,
+        now: Instant,
+    ) -> Result<Option<RecordBatch>> {
+        let timestamp = received
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as i64);
+        let row = match message {
+            MetadataMessage::Add(container_id, metadata) => {
+                let event_type = match self
+                    .containers
+                    .insert(container_id.clone(), metadata.clone())
+                {
+                    Some(_) => ContainerEventType::Updated,
+                    None => ContainerEventType::Added,
+                };
+                EventRow {
+                    timestamp,
+                    event_type,
+                    container_id,
+                    metadata: Some(metadata),
+                }
+            }
+            MetadataMessage::Remove(container_id) => {
+                let metadata = self.containers.remove(&container_id);
+                EventRow {
+                    timestamp,
+                    event_type: ContainerEventType::Removed,
+                    container_id,
+                    metadata,
+                }
+            }
+            MetadataMessage::Lifecycle(container_id, hint) => {
+                let metadata = self.containers.get(&container_id).cloned();
+                EventRow {
+                    timestamp,
+                    event_type: hint.into(),
+                    container_id,
+                    metadata,
+                }
+            }
+            // Containers keep running while the plugin reconnects, and
+            // synchronizing adds them again
+            MetadataMessage::Shutdown | MetadataMessage::Synchronized(_) => return Ok(None),
+        };
+
+        self.rows.push(row);
+        self.oldest.get_or_insert(now);
+        if self.rows.len() >= self.config.max_rows {
+            return self.take_batch().map(Some);
+        }
+        Ok(None)
+    }
+
+    /// When the pending rows must be cut into a batch, if there are any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.oldest.map(|oldest| oldest + self.config.max_age)
+    }
+
+    /// Cut the pending rows into a batch if the oldest is old enough
+    pub fn take_due(&mut self, now: Instant) -> Result<Option<RecordBatch>> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => self.take_batch().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Cut the pending rows into a batch regardless of age, if there are any
+    pub fn take_pending(&mut self) -> Result<Option<RecordBatch>> {
+        if self.rows.is_empty() {
+            return Ok(None);
+        }
+        self.take_batch().map(Some)
+    }
+
+    /// Convert the pending rows to a record batch
+    fn take_batch(&mut self) -> Result<RecordBatch> {
+        let rows = std::mem::take(&mut self.rows);
+        self.oldest = None;
+
+        let mut timestamp_builder = Int64Builder::with_capacity(rows.len());
+        let mut event_type_builder = StringBuilder::new();
+        let mut container_id_builder = StringBuilder::new();
+        let mut container_name_builder = StringBuilder::new();
+        let mut pod_name_builder = StringBuilder::new();
+        let mut pod_namespace_builder = StringBuilder::new();
+        let mut pod_uid_builder = StringBuilder::new();
+        let mut cgroup_path_builder = StringBuilder::new();
+        let mut cpu_quota_builder = Int64Builder::with_capacity(rows.len());
+        let mut cpu_period_builder = Int64Builder::with_capacity(rows.len());
+        let mut memory_limit_builder = Int64Builder::with_capacity(rows.len());
+        let mut cpuset_cpus_builder = StringBuilder::new();
+        let mut labels_builder = StringBuilder::new();
+
+        for row in &rows {
+            timestamp_builder.append_value(row.timestamp);
+            event_type_builder.append_value(row.event_type.as_str());
+            container_id_builder.append_value(&row.container_id);
+
+            let metadata = row.metadata.as_ref();
+            container_name_builder.append_option(metadata.map(|m| &m.container_name));
+            pod_name_builder.append_option(metadata.map(|m| &m.pod_name));
+            pod_namespace_builder.append_option(metadata.map(|m| &m.pod_namespace));
+            pod_uid_builder.append_option(metadata.map(|m| &m.pod_uid));
+            cgroup_path_builder.append_option(metadata.map(|m| &m.cgroup_path));
+
+            let limits = metadata.map(|m| &m.limits);
+            cpu_quota_builder.append_option(limits.and_then(|l| l.cpu_quota));
+            cpu_period_builder.append_option(limits.and_then(|l| l.cpu_period).map(|p| p as i64));
+            memory_limit_builder.append_option(limits.and_then(|l| l.memory_limit));
+            cpuset_cpus_builder.append_option(limits.and_then(|l| l.cpuset_cpus.as_ref()));
+
+            labels_builder.append_option(metadata.map(|m| self.selected_labels(m)));
+        }
+
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(timestamp_builder.finish()),
+            Arc::new(event_type_builder.finish()),
+            Arc::new(container_id_builder.finish()),
+            Arc::new(container_name_builder.finish()),
+            Arc::new(pod_name_builder.finish()),
+            Arc::new(pod_namespace_builder.finish()),
+            Arc::new(pod_uid_builder.finish()),
+            Arc::new(cgroup_path_builder.finish()),
+            Arc::new(cpu_quota_builder.finish()),
+            Arc::new(cpu_period_builder.finish()),
+            Arc::new(memory_limit_builder.finish()),
+            Arc::new(cpuset_cpus_builder.finish()),
+            Arc::new(labels_builder.finish()),
+        ];
+
+        RecordBatch::try_new(self.schema.clone(), arrays)
+            .map_err(|e| anyhow!("Failed to create RecordBatch: {}", e))
+    }
+
+    /// The configured labels the container has, as a JSON object
+    fn selected_labels(&self, metadata: &ContainerMetadata) -> String {
+        let labels: BTreeMap<&str, &str> = self
+            .config
+            .labels
+            .iter()
+            .filter_map(|key| {
+                metadata
+                    .labels
+                    .get(key)
+                    .map(|value| (key.as_str(), value.as_str()))
+            })
+            .collect();
+        serde_json::to_string(&labels).unwrap_or_default()
+    }
+}
+
+/// Worker task writing container events received from the cgroup filter's
+/// metadata stream as record batches
+pub struct ContainerEventsTask {
+    metadata_rx: mpsc::UnboundedReceiver<MetadataMessage>,
+    batch_sender: mpsc::Sender<RecordBatch>,
+    recorder: ContainerEventRecorder,
+}
+
+impl ContainerEventsTask {
+    /// Create a new ContainerEventsTask with pre-configured channels
+    pub fn new(
+        config: ContainerEventsConfig,
+        metadata_rx: mpsc::UnboundedReceiver<MetadataMessage>,
+        batch_sender: mpsc::Sender<RecordBatch>,
+    ) -> Self {
+        Self {
+            metadata_rx,
+            batch_sender,
+            recorder: ContainerEventRecorder::new(config),
+        }
+    }
+
+    /// Get the schema for the record batches this task produces
+    pub fn schema(&self) -> SchemaRef {
+        self.recorder.schema()
+    }
+
+    /// Run the task until the metadata stream closes, then write the
+    /// remaining rows
+    pub async fn run(mut self) -> Result<()> {
+        loop {
+            let deadline = self.recorder.deadline();
+            let batch = tokio::select! {
+                message = self.metadata_rx.recv() => match message {
+                    Some(message) => {
+                        self.recorder
+                            .record(message, SystemTime::now(), Instant::now())?
+                    }
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()),
+                    if deadline.is_some() =>
+                {
+                    self.recorder.take_due(Instant::now())?
+                }
+            };
+
+            if let Some(batch) = batch {
+                if self.batch_sender.send(batch).await.is_err() {
+                    log::error!("Batch receiver dropped, stopping container events");
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(batch) = self.recorder.take_pending()? {
+            if self.batch_sender.send(batch).await.is_err() {
+                log::error!("Batch receiver dropped, last container events not written");
+            }
+        }
+        log::debug!("Metadata stream closed, shutting down container events task");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, Int64Array, StringArray};
+    use futures::StreamExt;
+    use nri::metadata::ContainerLimits;
+    use object_store::memory::InMemory;
+    use object_store::ObjectStore;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::batch_transform::{TransformChain, TransformErrorPolicy};
+    use crate::parquet_writer::{ParquetWriter, ParquetWriterConfig};
+    use crate::parquet_writer_task::ParquetWriterTask;
+
+    fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> &'a T {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<T>()
+            .unwrap()
+    }
+
+    fn strings(batch: &RecordBatch, name: &str) -> Vec<Option<String>> {
+        column::<StringArray>(batch, name)
+            .iter()
+            .map(|value| value.map(str::to_string))
+            .collect()
+    }
+
+    fn add(id: &str, memory_limit: i64) -> MetadataMessage {
+        MetadataMessage::Add(
+            id.to_string(),
+            ContainerMetadata {
+                container_id: id.to_string(),
+                pod_name: format!("{}-pod", id),
+                pod_namespace: "prod".to_string(),
+                pod_uid: format!("{}-uid", id),
+                container_name: id.to_string(),
+                cgroup_path: format!("/kubepods/{}", id),
+                cgroup_id: None,
+                pid: None,
+                labels: HashMap::from([
+                    ("app".to_string(), "web".to_string()),
+                    ("secret".to_string(), "x".to_string()),
+                ]),
+                annotations: HashMap::new(),
+                limits: ContainerLimits {
+                    cpu_period: Some(100_000),
+                    memory_limit: Some(memory_limit),
+                    ..Default::default()
+                },
+            },
+        )
+    }
+
+    #[test]
+    fn test_lifecycle_rows() {
+        let config = ContainerEventsConfig {
+            labels: vec!["app".to_string(), "team".to_string()],
+            ..Default::default()
+        };
+        let mut recorder = ContainerEventRecorder::new(config);
+        let now = Instant::now();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        for (secs, message) in [
+            (1, add("c1", 1 << 30)),
+            (2, add("c1", 2 << 30)),
+            (3, MetadataMessage::Shutdown),
+            (4, MetadataMessage::Remove("c1".to_string())),
+            (5, MetadataMessage::Remove("unknown".to_string())),
+        ] {
+            assert!(recorder.record(message, at(secs), now).unwrap().is_none());
+        }
+        let batch = recorder.take_pending().unwrap().unwrap();
+        assert_eq!(batch.schema(), create_container_events_schema());
+        assert_eq!(
+            column::<Int64Array>(&batch, "timestamp").values(),
+            &[1_000_000_000, 2_000_000_000, 4_000_000_000, 5_000_000_000]
+        );
+        assert_eq!(
+            strings(&batch, "event_type"),
+            ["added", "updated", "removed", "removed"].map(|t| Some(t.to_string()))
+        );
+        assert_eq!(
+            strings(&batch, "container_id"),
+            ["c1", "c1", "c1", "unknown"].map(|t| Some(t.to_string()))
+        );
+
+        // The removal of c1 carries its latest metadata
+        let memory_limit = column::<Int64Array>(&batch, "memory_limit");
+        assert_eq!(memory_limit.value(1), 2 << 30);
+        assert_eq!(memory_limit.value(2), 2 << 30);
+        assert!(memory_limit.is_null(3));
+        assert_eq!(strings(&batch, "pod_name")[2].as_deref(), Some("c1-pod"));
+        assert_eq!(strings(&batch, "pod_name")[3], None);
+        assert!(column::<Int64Array>(&batch, "cpu_quota").is_null(0));
+
+        // Only the selected labels are kept
+        assert_eq!(
+            strings(&batch, "labels")[0].as_deref(),
+            Some(r#"{"app":"web"}"#)
+        );
+
+        assert!(recorder.take_pending().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_lifecycle_hint_rows() {
+        let mut recorder = ContainerEventRecorder::new(ContainerEventsConfig::default());
+        let now = Instant::now();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let hint = |id: &str, hint| MetadataMessage::Lifecycle(id.to_string(), hint);
+
+        for (secs, message) in [
+            (1, add("c1", 1 << 30)),
+            (1, hint("c1", LifecycleHint::Created)),
+            (2, hint("c1", LifecycleHint::Started)),
+            (3, hint("c1", LifecycleHint::Stopped)),
+            (3, MetadataMessage::Remove("c1".to_string())),
+            (4, hint("unknown", LifecycleHint::Started)),
+        ] {
+            assert!(recorder.record(message, at(secs), now).unwrap().is_none());
+        }
+        let batch = recorder.take_pending().unwrap().unwrap();
+        assert_eq!(
+            strings(&batch, "event_type"),
+            ["added", "created", "started", "stopped", "removed", "started"]
+                .map(|t| Some(t.to_string()))
+        );
+
+        // Hints carry the metadata known for the container at the time
+        let pod_names = strings(&batch, "pod_name");
+        assert!(pod_names[..5]
+            .iter()
+            .all(|name| name.as_deref() == Some("c1-pod")));
+        assert_eq!(pod_names[5], None);
+    }
+
+    #[test]
+    fn test_batch_cutting() {
+        let config = ContainerEventsConfig {
+            max_rows: 3,
+            max_age: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let mut recorder = ContainerEventRecorder::new(config);
+        let start = Instant::now();
+        let received = SystemTime::now();
+
+        // Cut by count
+        assert!(recorder
+            .record(add("c1", 1), received, start)
+            .unwrap()
+            .is_none());
+        assert!(recorder
+            .record(add("c2", 1), received, start)
+            .unwrap()
+            .is_none());
+        let batch = recorder.record(add("c3", 1), received, start).unwrap();
+        assert_eq!(batch.unwrap().num_rows(), 3);
+        assert_eq!(recorder.deadline(), None);
+
+        // Cut by the age of the oldest row
+        let later = start + Duration::from_secs(10);
+        recorder.record(add("c4", 1), received, later).unwrap();
+        recorder
+            .record(add("c5", 1), received, later + Duration::from_secs(20))
+            .unwrap();
+        assert_eq!(recorder.deadline(), Some(later + Duration::from_secs(30)));
+        assert!(recorder
+            .take_due(later + Duration::from_secs(29))
+            .unwrap()
+            .is_none());
+        let batch = recorder.take_due(later + Duration::from_secs(30)).unwrap();
+        assert_eq!(batch.unwrap().num_rows(), 2);
+        assert!(recorder
+            .take_due(later + Duration::from_secs(60))
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_task_writes_events() {
+        let (metadata_tx, metadata_rx) = mpsc::unbounded_channel();
+        let (batch_sender, mut batch_receiver) = mpsc::channel(10);
+        let config = ContainerEventsConfig {
+            max_rows: 2,
+            max_age: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let task = ContainerEventsTask::new(config, metadata_rx, batch_sender);
+        let schema = task.schema();
+        let task_handle = tokio::spawn(task.run());
+
+        // Two rows fill a batch
+        metadata_tx.send(add("c1", 1)).unwrap();
+        metadata_tx.send(add("c2", 1)).unwrap();
+        let mut batches = vec![batch_receiver.recv().await.unwrap()];
+        assert_eq!(batches[0].num_rows(), 2);
+
+        // A single row is cut once it is old enough
+        metadata_tx.send(add("c1", 2)).unwrap();
+        metadata_tx.send(MetadataMessage::Shutdown).unwrap();
+        batches.push(batch_receiver.recv().await.unwrap());
+        assert_eq!(batches[1].num_rows(), 1);
+
+        // The rest is written when the metadata stream closes
+        metadata_tx
+            .send(MetadataMessage::Remove("c2".to_string()))
+            .unwrap();
+        drop(metadata_tx);
+        task_handle.await.unwrap().unwrap();
+        while let Some(batch) = batch_receiver.recv().await {
+            batches.push(batch);
+        }
+        assert_eq!(batches.len(), 3);
+
+        // The batches make a file of their own
+        let store = Arc::new(InMemory::new());
+        let writer_config = ParquetWriterConfig::builder()
+            .storage_prefix("node-container-events-")
+            .timestamp_column("timestamp")
+            .build()
+            .unwrap();
+        let mut writer = ParquetWriter::new(store.clone(), schema, writer_config).unwrap();
+        for batch in batches {
+            writer.write(batch).await.unwrap();
+        }
+        writer.close().await.unwrap();
+
+        let metas: Vec<_> = store.list(None).collect().await;
+        assert_eq!(metas.len(), 1);
+        let location = metas[0].as_ref().unwrap().location.clone();
+        assert!(location.to_string().starts_with("node-container-events-"));
+        let bytes = store.get(&location).await.unwrap().bytes().await.unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .unwrap()
+            .build()
+            .unwrap();
+        let event_types: Vec<_> = reader
+            .flat_map(|batch| strings(&batch.unwrap(), "event_type"))
+            .collect();
+        assert_eq!(
+            event_types,
+            ["added", "added", "updated", "removed"].map(|t| Some(t.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redacted_labels() {
+        for key in [None, Some([5u8; 32])] {
+            let (metadata_tx, metadata_rx) = mpsc::unbounded_channel();
+            let (batch_sender, batch_receiver) = mpsc::channel(10);
+            let config = ContainerEventsConfig {
+                labels: vec!["app".to_string(), "secret".to_string()],
+                ..Default::default()
+            };
+            let task = ContainerEventsTask::new(config, metadata_rx, batch_sender);
+
+            // Comm and cmdline are not in this output, only pod labels apply
+            assert!(events_redaction(&[RedactionTarget::Comm], key).is_none());
+            let mut transforms = TransformChain::new(task.schema(), TransformErrorPolicy::Abort);
+            transforms.push(Box::new(
+                events_redaction(&[RedactionTarget::Comm, RedactionTarget::PodLabels], key)
+                    .unwrap(),
+            ));
+
+            let store = Arc::new(InMemory::new());
+            let writer = ParquetWriter::new(
+                store.clone(),
+                transforms.output_schema(),
+                Default::default(),
+            )
+            .unwrap();
+            let (_rotate_sender, rotate_receiver) = mpsc::channel(1);
+            let mut writer_task = ParquetWriterTask::new(writer, batch_receiver, rotate_receiver);
+            writer_task.set_transforms(transforms);
+            let writer_handle = tokio::spawn(writer_task.run());
+            let task_handle = tokio::spawn(task.run());
+
+            metadata_tx.send(add("c1", 1)).unwrap();
+            metadata_tx.send(add("c2", 1)).unwrap();
+            drop(metadata_tx);
+            task_handle.await.unwrap().unwrap();
+            writer_handle.await.unwrap().unwrap();
+
+            let metas: Vec<_> = store.list(None).collect().await;
+            assert_eq!(metas.len(), 1);
+            let location = metas[0].as_ref().unwrap().location.clone();
+            let bytes = store.get(&location).await.unwrap().bytes().await.unwrap();
+            for plaintext in [&b"\"app\""[..], b"web", b"\"secret\""] {
+                assert!(!bytes
+                    .windows(plaintext.len())
+                    .any(|window| window == plaintext));
+            }
+
+            let batch = ParquetRecordBatchReaderBuilder::try_new(bytes)
+                .unwrap()
+                .build()
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap();
+            let labels = strings(&batch, "labels");
+            match key {
+                None => assert_eq!(labels, vec![None, None]),
+                Some(_) => {
+                    // The same labels hash the same
+                    assert_eq!(labels[0], labels[1]);
+                    assert_eq!(labels[0].as_ref().unwrap().len(), 64);
+                }
+            }
+            // The rest of the row is untouched
+            assert_eq!(strings(&batch, "pod_name")[0].as_deref(), Some("c1-pod"));
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! CFS throttling of container cgroups, from cgroup v2 `cpu.stat`.
+//!
+//! A container hitting its `cpu.max` quota is throttled: its tasks wait until
+//! the next period even when CPUs are idle. The PMU counters do not show
+//! this, so a [`CpuThrottleWorker`] samples `nr_throttled` and
+//! `throttled_usec` of the containers seen in recent timeslots on an interval
+//! of its own, off the timeslot conversion, and the next timeslot of each
+//! container reports how much they grew since its previous sample.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+use tokio::time::MissedTickBehavior;
+
+use crate::cgroup_sampler::{CgroupRead, CgroupSampler};
+use crate::shutdown::ShutdownToken;
+
+/// Default interval between throttling samples
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Throttling counters from a cgroup's cpu.stat
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuStat {
+    /// Number of periods in which the cgroup was throttled
+    pub nr_throttled: u64,
+    /// Total time the cgroup was throttled, in microseconds
+    pub throttled_usec: u64,
+}
+
+impl CpuStat {
+    /// Parse the throttling counters out of cpu.stat contents, None if they
+    /// are missing
+    pub fn parse(contents: &str) -> Option<Self> {
+        let mut nr_throttled = None;
+        let mut throttled_usec = None;
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            match key {
+                "nr_throttled" => nr_throttled = value.parse().ok(),
+                "throttled_usec" => throttled_usec = value.parse().ok(),
+                _ => {}
+            }
+        }
+        Some(Self {
+            nr_throttled: nr_throttled?,
+            throttled_usec: throttled_usec?,
+        })
+    }
+
+    /// Growth of the counters since `earlier`
+    pub fn since(&self, earlier: &CpuStat) -> CpuStat {
+        CpuStat {
+            nr_throttled: self.nr_throttled.saturating_sub(earlier.nr_throttled),
+            throttled_usec: self.throttled_usec.saturating_sub(earlier.throttled_usec),
+        }
+    }
+
+    /// Sum of two growths
+    fn add(&self, other: &CpuStat) -> CpuStat {
+        CpuStat {
+            nr_throttled: self.nr_throttled + other.nr_throttled,
+            throttled_usec: self.throttled_usec + other.throttled_usec,
+        }
+    }
+}
+
+/// Samples container throttling, reading cpu.stat of each container
+pub struct CpuThrottleSampler {
+    sampler: CgroupSampler,
+    /// Counters at each container's previous sample
+    baselines: HashMap<u64, CpuStat>,
+    /// Containers found removed since the last call to `take_removed`
+    removed: Vec<u64>,
+    warned_missing: bool,
+}
+
+impl CpuThrottleSampler {
+    /// Create a throttling sampler reading cgroups through `sampler`
+    pub fn new(sampler: CgroupSampler) -> Self {
+        Self {
+            sampler,
+            baselines: HashMap::new(),
+            removed: Vec::new(),
+            warned_missing: false,
+        }
+    }
+
+    /// Containers found removed by the samples since the previous call
+    pub fn take_removed(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.removed)
+    }
+
+    /// Sample the container cgroups among `cgroup_ids`, returning the
+    /// throttling deltas since their previous sample by cgroup id.
+    ///
+    /// Containers sampled for the first time only record a baseline, so
+    /// they have no delta until their next sample.
+    pub fn sample(&mut self, cgroup_ids: &HashSet<u64>) -> HashMap<u64, CpuStat> {
+        let selection = self.sampler.select(cgroup_ids);
+        for id in &selection.removed {
+            self.baselines.remove(id);
+        }
+        self.removed.extend(&selection.removed);
+
+        let mut deltas = HashMap::new();
+        for id in selection.sample {
+            let stat = match self.sampler.read(id, "cpu.stat") {
+                Ok(CgroupRead::Contents(contents)) => CpuStat::parse(&contents),
+                Ok(CgroupRead::MissingFile) => None,
+                Ok(CgroupRead::Gone) => {
+                    self.baselines.remove(&id);
+                    self.removed.push(id);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to read cpu.stat of cgroup {}: {}", id, e);
+                    continue;
+                }
+            };
+
+            let Some(stat) = stat else {
+                if !self.warned_missing {
+                    warn!("cpu.stat has no throttling counters, throttling columns will be null");
+                    self.warned_missing = true;
+                }
+                continue;
+            };
+
+            if let Some(baseline) = self.baselines.insert(id, stat) {
+                deltas.insert(id, stat.since(&baseline));
+            }
+        }
+
+        deltas
+    }
+
+    /// Number of containers with a baseline
+    #[cfg(test)]
+    pub fn tracked_count(&self) -> usize {
+        self.baselines.len()
+    }
+}
+
+#[derive(Default)]
+struct ThrottleState {
+    /// Cgroups seen in timeslots since the last sample
+    seen: HashSet<u64>,
+    /// Growth sampled for each container and not yet taken by a timeslot
+    deltas: HashMap<u64, CpuStat>,
+}
+
+/// Throttling growth sampled by a [`CpuThrottleWorker`], shared with the
+/// timeslot conversion
+#[derive(Clone, Default)]
+pub struct ThrottleTable(Arc<Mutex<ThrottleState>>);
+
+impl ThrottleTable {
+    /// Note the cgroups of a timeslot for the next sample, and take the
+    /// growth sampled for them since their previous timeslot
+    pub fn take(&self, cgroup_ids: &HashSet<u64>) -> HashMap<u64, CpuStat> {
+        let mut state = self.0.lock().unwrap();
+        state.seen.extend(cgroup_ids);
+        cgroup_ids
+            .iter()
+            .filter_map(|id| state.deltas.remove(id).map(|delta| (*id, delta)))
+            .collect()
+    }
+
+    fn take_seen(&self) -> HashSet<u64> {
+        std::mem::take(&mut self.0.lock().unwrap().seen)
+    }
+
+    fn record(&self, deltas: HashMap<u64, CpuStat>, removed: &[u64]) {
+        let mut state = self.0.lock().unwrap();
+        for (id, delta) in deltas {
+            let total = state.deltas.entry(id).or_default();
+            *total = total.add(&delta);
+        }
+        for id in removed {
+            state.deltas.remove(id);
+        }
+    }
+}
+
+/// Worker task sampling throttling for a [`ThrottleTable`] on an interval.
+/// The cgroupfs reads run on the blocking pool.
+pub struct CpuThrottleWorker {
+    sampler: Arc<Mutex<CpuThrottleSampler>>,
+    table: ThrottleTable,
+    interval: Duration,
+}
+
+impl CpuThrottleWorker {
+    /// Create a worker sampling through `sampler` every `interval`, and the
+    /// table timeslots take its samples from
+    pub fn new(sampler: CpuThrottleSampler, interval: Duration) -> (Self, ThrottleTable) {
+        let table = ThrottleTable::default();
+        let worker = Self {
+            sampler: Arc::new(Mutex::new(sampler)),
+            table: table.clone(),
+            // Tokio intervals must not be zero
+            interval: interval.max(Duration::from_millis(1)),
+        };
+        (worker, table)
+    }
+
+    /// Sample until shutdown
+    pub async fn run(self, shutdown_token: ShutdownToken) -> Result<()> {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = shutdown_token.cancelled() => break,
+                _ = ticker.tick() => self.sample().await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Sample the containers seen in timeslots since the previous sample
+    async fn sample(&self) -> Result<()> {
+        let cgroup_ids = self.table.take_seen();
+        if cgroup_ids.is_empty() {
+            return Ok(());
+        }
+        let sampler = self.sampler.clone();
+        let (deltas, removed) = tokio::task::spawn_blocking(move || {
+            let mut sampler = sampler.lock().unwrap();
+            (sampler.sample(&cgroup_ids), sampler.take_removed())
+        })
+        .await?;
+        self.table.record(deltas, &removed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgroup_sampler::tests::FakeCgroupFs;
+
+    fn cpu_stat(nr_throttled: u64, throttled_usec: u64) -> String {
+        format!(
+            "usage_usec 1000\nuser_usec 600\nsystem_usec 400\nnr_periods 50\nnr_throttled {}\nthrottled_usec {}\n",
+            nr_throttled, throttled_usec
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            CpuStat::parse(&cpu_stat(3, 1500)),
+            Some(CpuStat {
+                nr_throttled: 3,
+                throttled_usec: 1500
+            })
+        );
+        // Without the cpu controller, cpu.stat only has usage
+        assert_eq!(CpuStat::parse("usage_usec 1000\nuser_usec 600\n"), None);
+    }
+
+    #[test]
+    fn test_deltas_across_ticks() {
+        let cgroupfs = FakeCgroupFs::new("throttle");
+        let a_path = "kubepods.slice/cri-containerd-a.scope";
+        let b_path = "kubepods.slice/cri-containerd-b.scope";
+        let a = cgroupfs.add_cgroup(a_path);
+        let b = cgroupfs.add_cgroup(b_path);
+        let host = cgroupfs.add_cgroup("system.slice/sshd.service");
+        cgroupfs.write(a_path, "cpu.stat", &cpu_stat(10, 5000));
+        cgroupfs.write(b_path, "cpu.stat", &cpu_stat(2, 800));
+
+        let mut throttle = CpuThrottleSampler::new(CgroupSampler::new(&cgroupfs.root, 16));
+        let tick = HashSet::from([a, b, host]);
+
+        // First tick records baselines only
+        assert!(throttle.sample(&tick).is_empty());
+        assert_eq!(throttle.tracked_count(), 2);
+
+        // Second tick reports growth since the first
+        cgroupfs.write(a_path, "cpu.stat", &cpu_stat(13, 6500));
+        cgroupfs.write(b_path, "cpu.stat", &cpu_stat(2, 800));
+        let deltas = throttle.sample(&tick);
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(
+            deltas[&a],
+            CpuStat {
+                nr_throttled: 3,
+                throttled_usec: 1500
+            }
+        );
+        assert_eq!(deltas[&b], CpuStat::default());
+        assert!(!deltas.contains_key(&host));
+
+        // B goes away between ticks and its baseline is dropped
+        cgroupfs.remove_cgroup(b_path);
+        cgroupfs.write(a_path, "cpu.stat", &cpu_stat(14, 7000));
+        let deltas = throttle.sample(&HashSet::from([a, host]));
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(
+            deltas[&a],
+            CpuStat {
+                nr_throttled: 1,
+                throttled_usec: 500
+            }
+        );
+        assert_eq!(throttle.tracked_count(), 1);
+    }
+
+    #[test]
+    fn test_missing_counters() {
+        testing_logger::setup();
+        let cgroupfs = FakeCgroupFs::new("throttle-missing");
+        let a_path = "cri-containerd-a.scope";
+        let b_path = "cri-containerd-b.scope";
+        let a = cgroupfs.add_cgroup(a_path);
+        let b = cgroupfs.add_cgroup(b_path);
+        cgroupfs.write(a_path, "cpu.stat", "usage_usec 1000\n");
+
+        let mut throttle = CpuThrottleSampler::new(CgroupSampler::new(&cgroupfs.root, 16));
+        for _ in 0..3 {
+            assert!(throttle.sample(&HashSet::from([a, b])).is_empty());
+        }
+        assert_eq!(throttle.tracked_count(), 0);
+
+        testing_logger::validate(|logs| {
+            let warnings: Vec<_> = logs
+                .iter()
+                .filter(|log| log.level == log::Level::Warn)
+                .collect();
+            assert_eq!(warnings.len(), 1);
+        });
+    }
+
+    #[tokio::test]
+    async fn test_worker_feeds_timeslots() {
+        let cgroupfs = FakeCgroupFs::new("throttle-worker");
+        let a_path = "kubepods.slice/cri-containerd-a.scope";
+        let b_path = "kubepods.slice/cri-containerd-b.scope";
+        let a = cgroupfs.add_cgroup(a_path);
+        let b = cgroupfs.add_cgroup(b_path);
+        cgroupfs.write(a_path, "cpu.stat", &cpu_stat(10, 5000));
+        cgroupfs.write(b_path, "cpu.stat", &cpu_stat(2, 800));
+
+        let sampler = CpuThrottleSampler::new(CgroupSampler::new(&cgroupfs.root, 16));
+        let (worker, table) = CpuThrottleWorker::new(sampler, DEFAULT_SAMPLE_INTERVAL);
+
+        // Only containers seen in a timeslot are sampled
+        worker.sample().await.unwrap();
+        assert_eq!(worker.sampler.lock().unwrap().tracked_count(), 0);
+        assert!(table.take(&HashSet::from([a, b])).is_empty());
+        worker.sample().await.unwrap();
+        assert_eq!(worker.sampler.lock().unwrap().tracked_count(), 2);
+
+        // Growth goes to the next timeslot of each container, and waits
+        // for it while the container is absent from timeslots
+        cgroupfs.write(a_path, "cpu.stat", &cpu_stat(13, 6500));
+        cgroupfs.write(b_path, "cpu.stat", &cpu_stat(3, 900));
+        assert!(table.take(&HashSet::from([a, b])).is_empty());
+        worker.sample().await.unwrap();
+        assert_eq!(
+            table.take(&HashSet::from([a])),
+            HashMap::from([(
+                a,
+                CpuStat {
+                    nr_throttled: 3,
+                    throttled_usec: 1500
+                }
+            )])
+        );
+
+        // The growth of a container removed before its next timeslot is dropped
+        assert!(table.0.lock().unwrap().deltas.contains_key(&b));
+        cgroupfs.remove_cgroup(b_path);
+        table.take(&HashSet::from([a]));
+        worker.sample().await.unwrap();
+        assert!(!table.0.lock().unwrap().deltas.contains_key(&b));
+    }
+
+    #[tokio::test]
+    async fn test_worker_stops_on_shutdown() {
+        let cgroupfs = FakeCgroupFs::new("throttle-shutdown");
+        let sampler = CpuThrottleSampler::new(CgroupSampler::new(&cgroupfs.root, 16));
+        let (worker, _table) = CpuThrottleWorker::new(sampler, Duration::from_millis(1));
+        let shutdown_token = ShutdownToken::new();
+        let handle = tokio::spawn(worker.run(shutdown_token.clone()));
+        shutdown_token.cancel(crate::shutdown::ShutdownReason::Duration);
+        handle.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,729 @@
+//! Live inspection of the pipeline's internal state.
+//!
+//! With `--enable-debug-endpoint`, `GET /debug/state` returns a JSON snapshot
+//! of the perf rings, the dispatcher, the processor, the channels between
+//! tasks and, with `--adaptive`, the current degradation level. That state belongs to the polling loop, so the server cannot read
+//! it directly: a request raises a flag that the loop checks after each poll
+//! cycle, and the loop publishes a snapshot taken between read batches.
+//!
+//! Taking a snapshot is cheap. Rings report the positions cached by their
+//! last batch rather than reading the memory shared with the kernel, and no
+//! lock is held while a snapshot is taken or serialized.
+//!
+//! The server closes connections whose request does not arrive in time, and
+//! closes new connections unanswered while too many are open, so idle clients
+//! cannot hold its tasks (see [`DebugEndpointConfig`]).
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use perf_events::{CaptureState, CaptureStatus, Dispatcher, RingState};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify, Semaphore};
+
+use crate::adaptive::AdaptiveController;
+use crate::metrics::{WriterMemory, WriterMemoryGauge};
+use crate::perf_event_processor::PerfEventProcessor;
+use crate::shutdown::ShutdownToken;
+
+/// Path of the state snapshot
+pub const DEBUG_STATE_PATH: &str = "/debug/state";
+
+/// How long a request waits for the polling loop to publish a snapshot
+pub const DEBUG_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a client has to send its request before the connection is closed
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections served at once
+const MAX_CONNECTIONS: usize = 16;
+
+/// Largest request head the server reads
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Timeouts and limits of the debug endpoint
+#[derive(Debug, Clone)]
+pub struct DebugEndpointConfig {
+    /// How long a request waits for the polling loop to publish a snapshot
+    pub snapshot_timeout: Duration,
+    /// How long a client has to send its request
+    pub read_timeout: Duration,
+    /// Connections served at once; further connections are closed unanswered
+    pub max_connections: usize,
+}
+
+impl Default for DebugEndpointConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_timeout: DEBUG_SNAPSHOT_TIMEOUT,
+            read_timeout: REQUEST_READ_TIMEOUT,
+            max_connections: MAX_CONNECTIONS,
+        }
+    }
+}
+
+/// Snapshot of the pipeline's state
+#[derive(Debug, Clone, Serialize)]
+pub struct DebugState {
+    /// When the snapshot was taken, in milliseconds since the Unix epoch
+    pub captured_at_ms: u64,
+    /// Perf rings, in CPU order
+    pub rings: Vec<RingDebugState>,
+    pub dispatcher: DispatcherDebugState,
+    pub processor: ProcessorDebugState,
+    /// Channels between the pipeline's tasks
+    pub channels: Vec<ChannelDebugState>,
+    /// Memory the parquet writer holds for the file it is writing, as of
+    /// its last write
+    pub writer_memory: Option<WriterMemory>,
+    /// Adaptive collection, if enabled
+    pub degradation: Option<DegradationDebugState>,
+}
+
+/// State of a perf ring as of its last read batch
+#[derive(Debug, Clone, Serialize)]
+pub struct RingDebugState {
+    /// Position of the next record to read
+    pub head: u64,
+    /// Writer's position when the last batch started
+    pub tail: u64,
+    pub size: u64,
+    pub fill_ratio: f64,
+    /// Timestamp of the ring's next record in the merge order
+    pub next_timestamp: Option<u64>,
+    pub records: u64,
+    pub lost_samples: u64,
+    /// Times the ring was resynced after the writer overwrote unread records
+    pub overwritten: u64,
+    /// Times the ring's unread data was skipped over a corrupt record header
+    pub corrupt: u64,
+}
+
+impl From<&RingState> for RingDebugState {
+    fn from(state: &RingState) -> Self {
+        Self {
+            head: state.positions.head,
+            tail: state.positions.tail,
+            size: state.positions.size,
+            fill_ratio: state.positions.fill_ratio(),
+            next_timestamp: state.next_timestamp,
+            records: state.stats.records,
+            lost_samples: state.stats.lost_samples,
+            overwritten: state.stats.overwritten,
+            corrupt: state.stats.corrupt,
+        }
+    }
+}
+
+/// Dispatcher counters
+#[derive(Debug, Clone, Serialize)]
+pub struct DispatcherDebugState {
+    pub samples_processed: usize,
+    pub lost_events_processed: usize,
+    pub callback_errors: usize,
+    pub dropped_messages: usize,
+    pub chunk_errors: usize,
+    pub duplicates_dropped: usize,
+    pub unknown_records: usize,
+    /// Messages delivered to subscribers, by message type
+    pub delivered_by_type: BTreeMap<u32, u64>,
+    /// Raw event capture in progress, if any
+    pub capture: Option<CaptureDebugState>,
+}
+
+impl From<&Dispatcher> for DispatcherDebugState {
+    fn from(dispatcher: &Dispatcher) -> Self {
+        let stats = dispatcher.stats();
+        Self {
+            samples_processed: stats.samples_processed,
+            lost_events_processed: stats.lost_events_processed,
+            callback_errors: stats.callback_errors,
+            dropped_messages: stats.dropped_messages,
+            chunk_errors: stats.chunk_errors,
+            duplicates_dropped: stats.duplicates_dropped,
+            unknown_records: stats.unknown_records,
+            delivered_by_type: dispatcher.delivered_by_type().into_iter().collect(),
+            capture: dispatcher
+                .capture_status()
+                .as_ref()
+                .map(CaptureDebugState::from),
+        }
+    }
+}
+
+/// Progress of a raw event capture
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureDebugState {
+    pub path: String,
+    /// "active", or why the capture stopped
+    pub state: String,
+    pub events: u64,
+    pub bytes: u64,
+}
+
+impl From<&CaptureStatus> for CaptureDebugState {
+    fn from(status: &CaptureStatus) -> Self {
+        let state = match &status.state {
+            CaptureState::Active => "active".to_string(),
+            CaptureState::EventLimit => "event_limit".to_string(),
+            CaptureState::ByteLimit => "byte_limit".to_string(),
+            CaptureState::Disabled => "disabled".to_string(),
+            CaptureState::Failed(e) => format!("failed: {}", e),
+        };
+        Self {
+            path: status.path.display().to_string(),
+            state,
+            events: status.events,
+            bytes: status.bytes,
+        }
+    }
+}
+
+/// State of the event processor
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessorDebugState {
+    /// Entries in the task metadata map
+    pub task_metadata: usize,
+    pub timeslot_size_ns: u64,
+    /// Start of the timeslot each CPU last reported, None before its first report
+    pub cpu_timeslots: Vec<Option<u64>>,
+}
+
+impl From<&PerfEventProcessor> for ProcessorDebugState {
+    fn from(processor: &PerfEventProcessor) -> Self {
+        let tracker = processor.timeslot_snapshot();
+        let slot_size = tracker.time_slot_size.max(1);
+        Self {
+            task_metadata: processor.task_metadata_count(),
+            timeslot_size_ns: tracker.time_slot_size,
+            cpu_timeslots: tracker
+                .cpu_timestamps
+                .iter()
+                .map(|timestamp| timestamp.map(|timestamp| timestamp - timestamp % slot_size))
+                .collect(),
+        }
+    }
+}
+
+/// Where adaptive collection stands
+#[derive(Debug, Clone, Serialize)]
+pub struct DegradationDebugState {
+    /// Current degradation level, 0 at full fidelity
+    pub level: usize,
+    /// Highest level reached so far
+    pub max_level: usize,
+    /// Number of level changes so far
+    pub transitions: usize,
+}
+
+impl From<&AdaptiveController> for DegradationDebugState {
+    fn from(controller: &AdaptiveController) -> Self {
+        Self {
+            level: controller.level(),
+            max_level: controller.max_level(),
+            transitions: controller.transitions(),
+        }
+    }
+}
+
+/// Depth of a channel between tasks
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelDebugState {
+    pub name: &'static str,
+    /// Messages waiting in the channel
+    pub depth: usize,
+    pub capacity: usize,
+    /// Whether the receiver is gone, or every sender
+    pub closed: bool,
+}
+
+/// Reads the depth of a channel without keeping it open
+pub struct ChannelProbe {
+    state: Box<dyn Fn() -> ChannelDebugState>,
+}
+
+impl ChannelProbe {
+    /// Probe the channel of `sender`. The probe holds a weak sender, so it
+    /// does not delay the receiver seeing the channel close.
+    pub fn new<T: 'static>(name: &'static str, sender: &mpsc::Sender<T>) -> Self {
+        let capacity = sender.max_capacity();
+        let sender = sender.downgrade();
+        Self {
+            state: Box::new(move || match sender.upgrade() {
+                Some(sender) if !sender.is_closed() => ChannelDebugState {
+                    name,
+                    depth: capacity - sender.capacity(),
+                    capacity,
+                    closed: false,
+                },
+                _ => ChannelDebugState {
+                    name,
+                    depth: 0,
+                    capacity,
+                    closed: true,
+                },
+            }),
+        }
+    }
+
+    /// The channel's current depth
+    pub fn state(&self) -> ChannelDebugState {
+        (self.state)()
+    }
+}
+
+/// Take a snapshot of the pipeline's state, between read batches
+pub fn capture(
+    rings: &[RingState],
+    dispatcher: &Dispatcher,
+    processor: &PerfEventProcessor,
+    channels: &[ChannelProbe],
+    writer_memory: Option<&WriterMemoryGauge>,
+    adaptive: Option<&AdaptiveController>,
+) -> DebugState {
+    DebugState {
+        captured_at_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64),
+        rings: rings.iter().map(RingDebugState::from).collect(),
+        dispatcher: DispatcherDebugState::from(dispatcher),
+        processor: ProcessorDebugState::from(processor),
+        channels: channels.iter().map(ChannelProbe::state).collect(),
+        writer_memory: writer_memory.map(WriterMemoryGauge::load),
+        degradation: adaptive.map(DegradationDebugState::from),
+    }
+}
+
+/// Hands snapshots from the polling loop to the requests waiting for them
+#[derive(Default)]
+pub struct DebugSnapshots {
+    requested: AtomicBool,
+    latest: Mutex<Option<Arc<DebugState>>>,
+    published: Notify,
+}
+
+impl DebugSnapshots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a request is waiting for a snapshot
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Publish a snapshot to the waiting requests
+    pub fn publish(&self, state: DebugState) {
+        let state = Arc::new(state);
+        *self.latest.lock().unwrap() = Some(state);
+        self.requested.store(false, Ordering::Release);
+        self.published.notify_waiters();
+    }
+
+    /// Ask the polling loop for a snapshot, waiting up to `timeout` for it.
+    ///
+    /// Returns the snapshot and whether it was taken for this request. If the
+    /// loop does not respond in time, returns the previous snapshot, if any.
+    pub async fn request(&self, timeout: Duration) -> Option<(Arc<DebugState>, bool)> {
+        let published = self.published.notified();
+        tokio::pin!(published);
+        published.as_mut().enable();
+        self.requested.store(true, Ordering::Release);
+
+        let fresh = tokio::time::timeout(timeout, published).await.is_ok();
+        let latest = self.latest.lock().unwrap().clone();
+        latest.map(|state| (state, fresh))
+    }
+}
+
+/// A snapshot as served, marked stale when the polling loop did not respond
+#[derive(Serialize)]
+struct DebugResponse<'a> {
+    stale: bool,
+    #[serde(flatten)]
+    state: &'a DebugState,
+}
+
+/// Serve `GET /debug/state` on `listener` until shutdown
+pub async fn serve_debug_endpoint(
+    listener: TcpListener,
+    snapshots: Arc<DebugSnapshots>,
+    config: DebugEndpointConfig,
+    shutdown_token: ShutdownToken,
+) -> Result<()> {
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                // Idle clients cannot hold more than their share of tasks
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    warn!("Debug endpoint busy, closing connection from {}", peer);
+                    continue;
+                };
+                debug!("Debug endpoint connection from {}", peer);
+                let snapshots = snapshots.clone();
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, &snapshots, &config).await {
+                        warn!("Debug endpoint request failed: {}", e);
+                    }
+                    drop(permit);
+                });
+            }
+            _ = shutdown_token.cancelled() => {
+                debug!("Debug endpoint cancelled");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a request head from `stream`, up to [`MAX_REQUEST_BYTES`]
+async fn read_request(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(request)
+}
+
+/// Answer a single request, then close the connection
+async fn handle_connection(
+    mut stream: TcpStream,
+    snapshots: &DebugSnapshots,
+    config: &DebugEndpointConfig,
+) -> Result<()> {
+    let request = tokio::time::timeout(config.read_timeout, read_request(&mut stream))
+        .await
+        .map_err(|_| anyhow!("no request within {:?}", config.read_timeout))??;
+
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(request_line)
+        .unwrap_or_default()
+        .split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(DEBUG_STATE_PATH)) => {
+            match snapshots.request(config.snapshot_timeout).await {
+                Some((state, fresh)) => {
+                    let response = DebugResponse {
+                        stale: !fresh,
+                        state: &state,
+                    };
+                    ("200 OK", serde_json::to_string(&response)?)
+                }
+                None => (
+                    "503 Service Unavailable",
+                    r#"{"error":"polling loop did not respond"}"#.to_string(),
+                ),
+            }
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            r#"{"error":"method not allowed"}"#.to_string(),
+        ),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bpf::{msg_type, BpfLoaderConfig, TaskMetadataMsg};
+    use perf_events::{write_collector_sample, PerfRing, Reader};
+    use serde_json::Value;
+
+    use crate::adaptive::{AdaptiveConfig, PressureSample};
+    use crate::perf_event_processor::ProcessorMode;
+    use crate::timeslot_data::TimeslotData;
+
+    /// Send `GET path` to the endpoint, returning the status code and body
+    async fn get(addr: std::net::SocketAddr, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    async fn start_server(
+        config: DebugEndpointConfig,
+    ) -> (std::net::SocketAddr, Arc<DebugSnapshots>, ShutdownToken) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let snapshots = Arc::new(DebugSnapshots::new());
+        let shutdown_token = ShutdownToken::new();
+        tokio::spawn(serve_debug_endpoint(
+            listener,
+            snapshots.clone(),
+            config,
+            shutdown_token.clone(),
+        ));
+        (addr, snapshots, shutdown_token)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_snapshot_during_dispatch() {
+        let (addr, snapshots, shutdown_token) = start_server(DebugEndpointConfig {
+            snapshot_timeout: Duration::from_secs(5),
+            ..Default::default()
+        })
+        .await;
+
+        // A dispatch loop over two rings feeding a processor
+        let page_size = 4096u64;
+        let n_pages = 4u32;
+        let mut buffers: Vec<Vec<u8>> = (0..2)
+            .map(|_| vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize])
+            .collect();
+        let mut reader = Reader::new();
+        let mut writers = Vec::new();
+        for buffer in buffers.iter_mut() {
+            reader
+                .add_ring(unsafe { PerfRing::init_contiguous(buffer, n_pages, page_size).unwrap() })
+                .unwrap();
+            writers.push(unsafe { PerfRing::init_contiguous(buffer, n_pages, page_size).unwrap() });
+        }
+        let mut dispatcher = Dispatcher::new();
+        let (timeslot_sender, _timeslot_receiver) = mpsc::channel::<TimeslotData>(10);
+        let channels = vec![ChannelProbe::new("timeslots", &timeslot_sender)];
+        let processor = PerfEventProcessor::with_dispatcher(
+            &mut dispatcher,
+            2,
+            ProcessorMode::Timeslot(timeslot_sender),
+            None,
+            &BpfLoaderConfig::default(),
+        );
+
+        // Requests run on other threads while the loop keeps dispatching
+        let requests = tokio::spawn(async move {
+            let requests = (0..8).map(|_| get(addr, DEBUG_STATE_PATH));
+            futures::future::join_all(requests).await
+        });
+        let mut pid = 0;
+        let dispatch_loop = async {
+            while !requests.is_finished() {
+                for writer in writers.iter_mut() {
+                    pid += 1;
+                    let msg = TaskMetadataMsg {
+                        pid,
+                        cgroup_id: 7,
+                        ..Default::default()
+                    };
+                    writer.start_write_batch();
+                    write_collector_sample(
+                        writer,
+                        msg_type::MSG_TYPE_TASK_METADATA as u32,
+                        u64::from(pid),
+                        &msg,
+                    )
+                    .unwrap();
+                    writer.finish_write_batch();
+                }
+                reader.start().unwrap();
+                dispatcher.dispatch_all(&mut reader).unwrap();
+                reader.finish().unwrap();
+
+                if snapshots.requested() {
+                    snapshots.publish(capture(
+                        &reader.ring_states(),
+                        &dispatcher,
+                        &processor.borrow(),
+                        &channels,
+                        None,
+                        None,
+                    ));
+                }
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), dispatch_loop)
+            .await
+            .expect("dispatch loop and snapshots deadlocked");
+        let responses = requests.await.unwrap();
+
+        for (status, body) in responses {
+            assert_eq!(status, 200);
+            let state: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(state["stale"], false);
+            assert!(state["captured_at_ms"].as_u64().unwrap() > 0);
+
+            let rings = state["rings"].as_array().unwrap();
+            assert_eq!(rings.len(), 2);
+            for ring in rings {
+                assert_eq!(ring["size"], page_size * u64::from(n_pages));
+                // Snapshots are taken between batches, when rings are drained
+                assert_eq!(ring["head"], ring["tail"]);
+                assert_eq!(ring["next_timestamp"], Value::Null);
+                assert!(ring["records"].as_u64().unwrap() > 0);
+            }
+
+            let dispatcher = &state["dispatcher"];
+            let metadata_type = (msg_type::MSG_TYPE_TASK_METADATA as u32).to_string();
+            let delivered = dispatcher["delivered_by_type"][&metadata_type]
+                .as_u64()
+                .unwrap();
+            assert!(delivered > 0);
+            assert_eq!(dispatcher["samples_processed"], delivered);
+
+            let processor = &state["processor"];
+            assert_eq!(processor["task_metadata"], delivered);
+            assert_eq!(processor["cpu_timeslots"].as_array().unwrap().len(), 2);
+
+            assert_eq!(state["degradation"], Value::Null);
+            assert_eq!(
+                state["channels"],
+                serde_json::json!([
+                    {"name": "timeslots", "depth": 0, "capacity": 10, "closed": false}
+                ])
+            );
+        }
+        shutdown_token.cancel(crate::shutdown::ShutdownReason::Duration);
+    }
+
+    #[test]
+    fn test_degradation_state() {
+        let mut controller = AdaptiveController::new(AdaptiveConfig {
+            escalate_after: 1,
+            recover_after: 1,
+            ..Default::default()
+        });
+        let state = |controller: &AdaptiveController| {
+            serde_json::to_value(DegradationDebugState::from(controller)).unwrap()
+        };
+        assert_eq!(
+            state(&controller),
+            serde_json::json!({"level": 0, "max_level": 0, "transitions": 0})
+        );
+
+        // The snapshot follows the controller's transitions
+        let high = PressureSample {
+            cpu_fraction: 0.9,
+            ring_fill: 0.0,
+        };
+        let low = PressureSample {
+            cpu_fraction: 0.0,
+            ring_fill: 0.0,
+        };
+        controller.observe(high).unwrap();
+        assert_eq!(
+            state(&controller),
+            serde_json::json!({"level": 1, "max_level": 1, "transitions": 1})
+        );
+        controller.observe(low).unwrap();
+        assert_eq!(
+            state(&controller),
+            serde_json::json!({"level": 0, "max_level": 1, "transitions": 2})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_and_unknown_requests() {
+        let (addr, snapshots, _shutdown_token) = start_server(DebugEndpointConfig {
+            snapshot_timeout: Duration::from_millis(50),
+            ..Default::default()
+        })
+        .await;
+
+        // Without a polling loop there is nothing to serve
+        let (status, _) = get(addr, DEBUG_STATE_PATH).await;
+        assert_eq!(status, 503);
+        assert!(snapshots.requested());
+
+        let (status, _) = get(addr, "/metrics").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections() {
+        let (addr, _snapshots, _shutdown_token) = start_server(DebugEndpointConfig {
+            snapshot_timeout: Duration::from_millis(10),
+            read_timeout: Duration::from_millis(200),
+            max_connections: 2,
+        })
+        .await;
+
+        // Two clients that never send a request take every connection
+        let mut idle = Vec::new();
+        for _ in 0..2 {
+            idle.push(TcpStream::connect(addr).await.unwrap());
+        }
+
+        // Further connections are closed without an answer
+        let mut rejected = TcpStream::connect(addr).await.unwrap();
+        let mut response = Vec::new();
+        let _ = rejected.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+
+        // The idle clients are closed once their time to send a request is up
+        for mut stream in idle {
+            let mut response = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await
+                .expect("idle connection was not closed")
+                .unwrap();
+            assert!(response.is_empty());
+        }
+
+        // Their connections are released, though possibly just after they close
+        for _ in 0..100 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\n\r\n", DEBUG_STATE_PATH).as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response).await;
+            if !response.is_empty() {
+                assert!(response.starts_with("HTTP/1.1 503"));
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("connections were not released");
+    }
+
+    #[test]
+    fn test_channel_probe() {
+        let (sender, mut receiver) = mpsc::channel::<u32>(4);
+        let probe = ChannelProbe::new("numbers", &sender);
+        sender.try_send(1).unwrap();
+        sender.try_send(2).unwrap();
+        let state = probe.state();
+        assert_eq!((state.depth, state.capacity, state.closed), (2, 4, false));
+
+        // The probe does not keep the channel open
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert!(receiver.try_recv().is_err());
+        assert!(probe.state().closed);
+    }
+}
@@ -0,0 +1,576 @@
+//! Free space guard for the local storage backend.
+//!
+//! With local storage a runaway collector could fill the filesystem it writes
+//! to. The guard checks the filesystem's free space before each new parquet
+//! file and periodically while writing. Below the minimum the writer stops
+//! creating files, like when the storage quota is reached, until free space
+//! is back above the recovery threshold. Optionally the guard deletes the
+//! oldest parquet files this writer closed to get there. Files of earlier
+//! runs, or of other collectors sharing the prefix, are never deleted.
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+/// Free space thresholds of the disk guard
+#[derive(Debug, Clone)]
+pub struct DiskGuardConfig {
+    /// Stop creating files when fewer MB than this are available, 0 to disable
+    pub min_free_mb: u64,
+    /// Stop creating files when less than this percentage of the filesystem
+    /// is available, 0 to disable
+    pub min_free_percent: f64,
+    /// Resume once at least this many MB are available
+    pub recovery_free_mb: u64,
+    /// Resume once at least this percentage of the filesystem is available
+    pub recovery_free_percent: f64,
+    /// Delete the oldest files closed by this writer while free space is
+    /// below the recovery threshold
+    pub reclaim_own_files: bool,
+    /// How often free space is checked while writing
+    pub check_interval: Duration,
+}
+
+impl Default for DiskGuardConfig {
+    fn default() -> Self {
+        Self {
+            min_free_mb: 512,
+            min_free_percent: 2.0,
+            recovery_free_mb: 1024,
+            recovery_free_percent: 5.0,
+            reclaim_own_files: false,
+            check_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Space on a filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsSpace {
+    /// Bytes available to unprivileged users
+    pub available_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl FsSpace {
+    fn available_mb(&self) -> u64 {
+        self.available_bytes / (1024 * 1024)
+    }
+
+    fn available_percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+        self.available_bytes as f64 * 100.0 / self.total_bytes as f64
+    }
+
+    fn has_at_least(&self, mb: u64, percent: f64) -> bool {
+        self.available_mb() >= mb && self.available_percent() >= percent
+    }
+}
+
+/// Source of filesystem space, so tests can simulate a filling disk
+pub trait FsStats: Send {
+    fn space(&self, path: &Path) -> io::Result<FsSpace>;
+}
+
+/// Reads filesystem space with statvfs(2)
+pub struct Statvfs;
+
+impl FsStats for Statvfs {
+    fn space(&self, path: &Path) -> io::Result<FsSpace> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fragment_size = stat.f_frsize as u64;
+        Ok(FsSpace {
+            available_bytes: stat.f_bavail as u64 * fragment_size,
+            total_bytes: stat.f_blocks as u64 * fragment_size,
+        })
+    }
+}
+
+/// A file deleted to reclaim space
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReclaimedFile {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Result of a free space check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskCheck {
+    pub space: FsSpace,
+    /// Whether free space is below the threshold, and files must not be created
+    pub low: bool,
+    /// Whether `low` changed with this check
+    pub changed: bool,
+}
+
+/// Tracks free space on the filesystem holding the collector's local files
+pub struct DiskGuard {
+    config: DiskGuardConfig,
+    /// Root of the local object store
+    root: PathBuf,
+    /// Directory the parquet files are written to
+    dir: PathBuf,
+    /// Files closed by this writer and not yet reclaimed, oldest first
+    own_files: VecDeque<PathBuf>,
+    fs_stats: Box<dyn FsStats>,
+    low: bool,
+    last_check: Option<Instant>,
+}
+
+impl DiskGuard {
+    /// Guard the files written with `storage_prefix` by a local object store
+    /// rooted at `root`
+    pub fn new(config: DiskGuardConfig, root: &Path, storage_prefix: &str) -> Self {
+        Self::with_fs_stats(config, root, storage_prefix, Box::new(Statvfs))
+    }
+
+    /// Like [`DiskGuard::new`], reading filesystem space from `fs_stats`
+    pub fn with_fs_stats(
+        config: DiskGuardConfig,
+        root: &Path,
+        storage_prefix: &str,
+        fs_stats: Box<dyn FsStats>,
+    ) -> Self {
+        let dir = match storage_prefix.rsplit_once('/') {
+            Some((dir, _)) => root.join(dir),
+            None => root.to_path_buf(),
+        };
+        Self {
+            config,
+            root: root.to_path_buf(),
+            dir,
+            own_files: VecDeque::new(),
+            fs_stats,
+            low: false,
+            last_check: None,
+        }
+    }
+
+    /// Note a file the writer closed at `object_path`, so it can be reclaimed
+    pub fn track_file(&mut self, object_path: &str) {
+        if self.config.reclaim_own_files {
+            self.own_files.push_back(self.root.join(object_path));
+        }
+    }
+
+    /// Whether the last check found free space below the threshold
+    pub fn is_low(&self) -> bool {
+        self.low
+    }
+
+    /// Whether the check interval passed since the last check
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.last_check
+            .is_none_or(|last| now.duration_since(last) >= self.config.check_interval)
+    }
+
+    /// Check free space, switching to low below the minimum and back once
+    /// above the recovery threshold
+    pub fn check(&mut self) -> io::Result<DiskCheck> {
+        self.last_check = Some(Instant::now());
+        let space = self.fs_stats.space(&self.dir)?;
+        let was_low = self.low;
+        self.low = if was_low {
+            !self.is_recovered(&space)
+        } else {
+            !space.has_at_least(self.config.min_free_mb, self.config.min_free_percent)
+        };
+
+        let changed = self.low != was_low;
+        if changed && self.low {
+            error!(
+                "Free space on {} is low ({} MB, {:.1}% available), not creating new files",
+                self.dir.display(),
+                space.available_mb(),
+                space.available_percent()
+            );
+        } else if changed {
+            info!(
+                "Free space on {} recovered ({} MB, {:.1}% available), resuming writes",
+                self.dir.display(),
+                space.available_mb(),
+                space.available_percent()
+            );
+        }
+
+        Ok(DiskCheck {
+            space,
+            low: self.low,
+            changed,
+        })
+    }
+
+    fn is_recovered(&self, space: &FsSpace) -> bool {
+        space.has_at_least(
+            self.config.recovery_free_mb.max(self.config.min_free_mb),
+            self.config
+                .recovery_free_percent
+                .max(self.config.min_free_percent),
+        )
+    }
+
+    /// Delete the oldest files closed by this writer until free space is
+    /// above the recovery threshold, if enabled and space is low.
+    ///
+    /// Only files passed to [`DiskGuard::track_file`] are deleted, so files
+    /// of earlier runs and other writers under the prefix stay untouched.
+    pub fn reclaim(&mut self) -> io::Result<Vec<ReclaimedFile>> {
+        let mut reclaimed = Vec::new();
+        if !self.low || !self.config.reclaim_own_files {
+            return Ok(reclaimed);
+        }
+
+        while let Some(path) = self.own_files.pop_front() {
+            let bytes = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    self.own_files.push_front(path);
+                    return Err(e);
+                }
+            };
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    warn!("Failed to reclaim {}: {}", path.display(), e);
+                    continue;
+                }
+            }
+            warn!("Reclaimed {} bytes by deleting {}", bytes, path.display());
+            reclaimed.push(ReclaimedFile { path, bytes });
+
+            if !self.check()?.low {
+                break;
+            }
+        }
+
+        Ok(reclaimed)
+    }
+}
+
+/// Whether `name` is a parquet file name the writer generates for
+/// `file_prefix`: the prefix, a `%Y%m%dT%H%M%SZ` timestamp, '-', a decimal
+/// sequence number and '-', 8 lowercase hex digits and `.parquet`. Names
+/// written before files had sequence numbers lack them.
+pub fn is_own_file(name: &str, file_prefix: &str) -> bool {
+    let Some(rest) = name.strip_prefix(file_prefix) else {
+        return false;
+    };
+    let Some(stem) = rest.strip_suffix(".parquet") else {
+        return false;
+    };
+    let bytes = stem.as_bytes();
+    if bytes.len() < 25 {
+        return false;
+    }
+
+    let digits = |bytes: &[u8]| !bytes.is_empty() && bytes.iter().all(u8::is_ascii_digit);
+    let (timestamp, rest) = bytes.split_at(17);
+    let id = match rest.iter().position(|&b| b == b'-') {
+        Some(dash) if digits(&rest[..dash]) => &rest[dash + 1..],
+        Some(_) => return false,
+        None => rest,
+    };
+    digits(&timestamp[0..8])
+        && timestamp[8] == b'T'
+        && digits(&timestamp[9..15])
+        && timestamp[15] == b'Z'
+        && timestamp[16] == b'-'
+        && id.len() == 8
+        && id
+            .iter()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(b))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Filesystem space set by the test
+    #[derive(Clone)]
+    pub(crate) struct FakeFsStats {
+        pub space: Arc<Mutex<FsSpace>>,
+    }
+
+    impl FakeFsStats {
+        pub fn new(available_mb: u64, total_mb: u64) -> Self {
+            let fake = Self {
+                space: Arc::new(Mutex::new(FsSpace {
+                    available_bytes: 0,
+                    total_bytes: 0,
+                })),
+            };
+            fake.set(available_mb, total_mb);
+            fake
+        }
+
+        pub fn set(&self, available_mb: u64, total_mb: u64) {
+            *self.space.lock().unwrap() = FsSpace {
+                available_bytes: available_mb * 1024 * 1024,
+                total_bytes: total_mb * 1024 * 1024,
+            };
+        }
+
+        pub fn free_mb(&self, mb: u64) {
+            self.space.lock().unwrap().available_bytes += mb * 1024 * 1024;
+        }
+    }
+
+    impl FsStats for FakeFsStats {
+        fn space(&self, _path: &Path) -> io::Result<FsSpace> {
+            Ok(*self.space.lock().unwrap())
+        }
+    }
+
+    /// A fake filesystem that frees the size of each deleted file
+    struct FreeingFsStats {
+        fake: FakeFsStats,
+        dir: PathBuf,
+        /// Bytes in the directory at the last call
+        used: Mutex<u64>,
+    }
+
+    impl FsStats for FreeingFsStats {
+        fn space(&self, _path: &Path) -> io::Result<FsSpace> {
+            let used: u64 = fs::read_dir(&self.dir)?
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .sum();
+            let mut last = self.used.lock().unwrap();
+            if used < *last {
+                self.fake.space.lock().unwrap().available_bytes += *last - used;
+            }
+            *last = used;
+            self.fake.space(&self.dir)
+        }
+    }
+
+    pub(crate) struct TempDir {
+        pub path: PathBuf,
+    }
+
+    impl TempDir {
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "collector-disk-guard-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self { path }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn config() -> DiskGuardConfig {
+        DiskGuardConfig {
+            min_free_mb: 100,
+            min_free_percent: 0.0,
+            recovery_free_mb: 200,
+            recovery_free_percent: 0.0,
+            reclaim_own_files: false,
+            check_interval: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn test_own_file_names() {
+        let prefix = "metrics-node1";
+        assert!(is_own_file(
+            "metrics-node120240102T030405Z-000007-0a1b2c3d.parquet",
+            prefix
+        ));
+        // Written before files had sequence numbers
+        assert!(is_own_file(
+            "metrics-node120240102T030405Z-0a1b2c3d.parquet",
+            prefix
+        ));
+
+        for name in [
+            // Other prefixes, including one extending ours
+            "metrics-node220240102T030405Z-0a1b2c3d.parquet",
+            "metrics-node1220240102T030405Z-0a1b2c3d.parquet",
+            "other-node120240102T030405Z-0a1b2c3d.parquet",
+            // Run summaries and other files under the prefix
+            "metrics-node120240102T030405Z-0a1b2c3d.summary.json",
+            "metrics-node120240102T030405Z-0a1b2c3d.parquet.tmp",
+            "metrics-node1.parquet",
+            // Malformed timestamps and ids
+            "metrics-node12024010T030405Z-0a1b2c3d.parquet",
+            "metrics-node120240102-030405Z-0a1b2c3d.parquet",
+            "metrics-node120240102T030405Z-0A1B2C3D.parquet",
+            "metrics-node120240102T030405Z-0a1b2c3.parquet",
+            "metrics-node120240102T030405Z-0a1b2c3d4.parquet",
+            "metrics-node120240102T030405Z_0a1b2c3d.parquet",
+            "metrics-node120240102T030405Z--0a1b2c3d.parquet",
+            "metrics-node120240102T030405Z-00a7-0a1b2c3d.parquet",
+            "metrics-node120240102T030405Z-000007-0a1b2c3d4.parquet",
+        ] {
+            assert!(!is_own_file(name, prefix), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_low_and_recovery() {
+        let dir = TempDir::new("recovery");
+        let fs_stats = FakeFsStats::new(1000, 10000);
+        let mut guard =
+            DiskGuard::with_fs_stats(config(), &dir.path, "m-", Box::new(fs_stats.clone()));
+
+        assert!(guard.is_due(Instant::now()));
+        let check = guard.check().unwrap();
+        assert!(!check.low && !check.changed);
+        assert!(!guard.is_due(Instant::now()));
+        assert!(guard.is_due(Instant::now() + Duration::from_secs(5)));
+
+        fs_stats.set(99, 10000);
+        let check = guard.check().unwrap();
+        assert!(check.low && check.changed);
+        assert_eq!(check.space.available_bytes, 99 * 1024 * 1024);
+
+        // Above the minimum but below the recovery threshold stays low
+        fs_stats.set(150, 10000);
+        let check = guard.check().unwrap();
+        assert!(check.low && !check.changed);
+
+        fs_stats.set(200, 10000);
+        let check = guard.check().unwrap();
+        assert!(!check.low && check.changed);
+        assert!(!guard.is_low());
+
+        // The percentage threshold applies on its own
+        let mut guard = DiskGuard::with_fs_stats(
+            DiskGuardConfig {
+                min_free_mb: 0,
+                min_free_percent: 5.0,
+                recovery_free_percent: 10.0,
+                ..config()
+            },
+            &dir.path,
+            "m-",
+            Box::new(fs_stats.clone()),
+        );
+        fs_stats.set(400, 10000);
+        assert!(guard.check().unwrap().low);
+        fs_stats.set(900, 10000);
+        assert!(guard.check().unwrap().low);
+        fs_stats.set(1000, 10000);
+        assert!(!guard.check().unwrap().low);
+    }
+
+    #[test]
+    fn test_reclaim_oldest_own_files() {
+        let dir = TempDir::new("reclaim");
+        let sub = dir.path.join("out");
+        fs::create_dir_all(&sub).unwrap();
+        let mb = vec![0u8; 1024 * 1024];
+        let own = [
+            "m-node20240101T000003Z-00000003.parquet",
+            "m-node20240101T000001Z-00000001.parquet",
+            "m-node20240101T000002Z-00000002.parquet",
+        ];
+        let foreign = [
+            // Written by an earlier run or another collector with the prefix
+            "m-node20240101T000000Z-00000000.parquet",
+            "m-node20240101T000000Z-00000000.summary.json",
+            "m-other20240101T000000Z-00000000.parquet",
+            "notes.txt",
+        ];
+        for name in own.iter().chain(foreign.iter()) {
+            fs::write(sub.join(name), &mb[..]).unwrap();
+        }
+
+        let fake = FakeFsStats::new(50, 10000);
+        let fs_stats = FreeingFsStats {
+            fake: fake.clone(),
+            dir: sub.clone(),
+            used: Mutex::new(0),
+        };
+        let mut guard = DiskGuard::with_fs_stats(
+            DiskGuardConfig {
+                min_free_mb: 100,
+                recovery_free_mb: 101,
+                reclaim_own_files: true,
+                ..config()
+            },
+            &dir.path,
+            "out/m-node",
+            Box::new(fs_stats),
+        );
+        for name in [own[1], own[2], own[0]] {
+            guard.track_file(&format!("out/{}", name));
+        }
+
+        // Nothing is deleted while space is above the minimum
+        fake.set(150, 10000);
+        assert!(!guard.check().unwrap().low);
+        assert!(guard.reclaim().unwrap().is_empty());
+
+        // 99 MB available: deleting the two oldest files frees 2 MB, enough to recover
+        fake.set(99, 10000);
+        assert!(guard.check().unwrap().low);
+        let reclaimed = guard.reclaim().unwrap();
+        assert_eq!(
+            reclaimed,
+            vec![
+                ReclaimedFile {
+                    path: sub.join(own[1]),
+                    bytes: mb.len() as u64,
+                },
+                ReclaimedFile {
+                    path: sub.join(own[2]),
+                    bytes: mb.len() as u64,
+                },
+            ]
+        );
+        assert!(!guard.is_low());
+        assert!(sub.join(own[0]).exists());
+
+        // Deleting every own file is not enough: the rest stay untouched
+        fake.set(10, 10000);
+        assert!(guard.check().unwrap().low);
+        assert_eq!(guard.reclaim().unwrap().len(), 1);
+        assert!(guard.is_low());
+        for name in foreign {
+            assert!(sub.join(name).exists(), "{}", name);
+        }
+        assert!(guard.reclaim().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reclaim_disabled() {
+        let dir = TempDir::new("no-reclaim");
+        let name = "m-20240101T000001Z-00000001.parquet";
+        fs::write(dir.path.join(name), b"data").unwrap();
+
+        let fs_stats = FakeFsStats::new(10, 10000);
+        let mut guard =
+            DiskGuard::with_fs_stats(config(), &dir.path, "m-", Box::new(fs_stats.clone()));
+        assert!(guard.check().unwrap().low);
+        assert!(guard.reclaim().unwrap().is_empty());
+        assert!(dir.path.join(name).exists());
+
+        fs_stats.free_mb(500);
+        assert!(!guard.check().unwrap().low);
+    }
+}
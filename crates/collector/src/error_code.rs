@@ -0,0 +1,83 @@
+//! Error codes for the errors tasks fail with.
+//!
+//! Tasks return `anyhow` errors. The crates they call keep their own error
+//! types, which are [`Classified`], and failures without such a type carry a
+//! [`Coded`] error or context. [`error_code`] finds the code in an error's
+//! chain, so shutdown reasons and the run summary can report it.
+
+use std::error::Error as StdError;
+
+use bpf::SyncTimerError;
+use collector_errors::{Classified, Coded, ErrorCode};
+use nri::multiplex::MuxError;
+use nri::reconnect::ReconnectError;
+use perf_events::{
+    DispatchError, PerfEventError, PerfMapError, PerfRingError, ReaderError, StorageError,
+};
+
+/// The code of `error`, if it or an error in its chain has one.
+///
+/// A [`Coded`] context takes precedence over the error it wraps, otherwise
+/// the outermost classified error in the chain decides.
+pub fn error_code(error: &anyhow::Error) -> Option<ErrorCode> {
+    if let Some(coded) = error.downcast_ref::<Coded>() {
+        return Some(coded.code());
+    }
+    error.chain().find_map(classify)
+}
+
+/// The code of a single error in a chain, if its type is classified
+fn classify(error: &(dyn StdError + 'static)) -> Option<ErrorCode> {
+    fn code<E: Classified + StdError + 'static>(
+        error: &(dyn StdError + 'static),
+    ) -> Option<ErrorCode> {
+        error.downcast_ref::<E>().map(E::code)
+    }
+
+    code::<Coded>(error)
+        .or_else(|| code::<DispatchError>(error))
+        .or_else(|| code::<ReaderError>(error))
+        .or_else(|| code::<PerfRingError>(error))
+        .or_else(|| code::<PerfMapError>(error))
+        .or_else(|| code::<PerfEventError>(error))
+        .or_else(|| code::<StorageError>(error))
+        .or_else(|| code::<SyncTimerError>(error))
+        .or_else(|| code::<MuxError>(error))
+        .or_else(|| code::<ReconnectError>(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_error_code_from_chain() {
+        // Typed errors keep their class through `?` and added context
+        let err = anyhow::Error::from(DispatchError::RingError(PerfRingError::Overwritten))
+            .context("polling failed");
+        assert_eq!(error_code(&err), Some(ErrorCode::RingLost));
+
+        let err = Err::<(), _>(ReaderError::NoRings)
+            .context("Failed to start reader")
+            .unwrap_err();
+        assert_eq!(error_code(&err), Some(ErrorCode::Internal));
+
+        // A code given as context overrides the one of the error it wraps
+        let err = Err::<(), _>(PerfRingError::Overwritten)
+            .context(Coded::new(ErrorCode::StorageWrite, "Failed to write batch"))
+            .context("writer task")
+            .unwrap_err();
+        assert_eq!(error_code(&err), Some(ErrorCode::StorageWrite));
+        assert_eq!(
+            err.root_cause().to_string(),
+            PerfRingError::Overwritten.to_string()
+        );
+
+        // Coded errors, and errors without a class
+        let err = anyhow::Error::from(Coded::new(ErrorCode::Internal, "no writer"));
+        assert_eq!(error_code(&err), Some(ErrorCode::Internal));
+        assert_eq!(error_code(&anyhow!("something else")), None);
+    }
+}
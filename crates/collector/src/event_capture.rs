@@ -0,0 +1,318 @@
+//! Capture of raw BPF messages to a file, toggled by SIGUSR2.
+//!
+//! With `--capture-spec <file>`, SIGUSR2 reads a JSON [`CaptureRequest`] from
+//! the file and starts capturing the messages it selects, as the dispatcher
+//! receives them. A second SIGUSR2 stops a capture still in progress. The
+//! spec is read again for every capture, so it can be edited in between.
+//!
+//! With `--redact comm`, captured task metadata carries the redacted comm, as
+//! the output does. Captures stop on their own at their limits. The files are
+//! read with [`perf_events::read_capture`] and replay through the dispatcher
+//! like a processor log.
+
+use std::mem::offset_of;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bpf::{msg_type, PerfMeasurementMsg, TaskFreeMsg, TaskMetadataMsg};
+use log::{error, info, warn};
+use perf_events::{
+    CaptureFilter, CaptureSpec, CaptureState, CaptureStatus, Dispatcher, SampleHeader,
+};
+use serde::Deserialize;
+
+/// Rewrites a raw message in place before it is written out, such as to
+/// redact its identifying fields
+pub type MessageRedaction = Arc<dyn Fn(&mut [u8]) + Send + Sync>;
+
+/// What to capture, as read from the spec file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureRequest {
+    /// File to write the capture to
+    pub path: PathBuf,
+    /// Message types to capture, or every type if empty
+    #[serde(default)]
+    pub msg_types: Vec<u32>,
+    pub max_events: Option<u64>,
+    pub max_bytes: Option<u64>,
+    /// Capture only the messages of this pid. Messages without a pid, such
+    /// as timer ticks, are left out.
+    pub pid: Option<u32>,
+}
+
+impl CaptureRequest {
+    /// Read the request from a JSON file
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read capture spec {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid capture spec {}", path.display()))
+    }
+
+    /// The dispatcher's spec for the request, with its defaults for the
+    /// limits not given
+    pub fn into_spec(self) -> CaptureSpec {
+        let mut spec = CaptureSpec::new(self.path);
+        spec.msg_types = self.msg_types;
+        if let Some(max_events) = self.max_events {
+            spec.max_events = max_events;
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            spec.max_bytes = max_bytes;
+        }
+        spec.pid_filter = self.pid.map(|pid| {
+            Box::new(move |data: &[u8]| message_pid(data) == Some(pid)) as CaptureFilter
+        });
+        spec
+    }
+}
+
+/// The pid a message is about, for the message types that carry one
+fn message_pid(data: &[u8]) -> Option<u32> {
+    let header: &SampleHeader = plain::from_bytes(data).ok()?;
+    let offset = match header.type_ {
+        t if t == msg_type::MSG_TYPE_TASK_METADATA as u32 => offset_of!(TaskMetadataMsg, pid),
+        t if t == msg_type::MSG_TYPE_TASK_FREE as u32 => offset_of!(TaskFreeMsg, pid),
+        t if t == msg_type::MSG_TYPE_PERF_MEASUREMENT as u32 => {
+            offset_of!(PerfMeasurementMsg, pid)
+        }
+        _ => return None,
+    };
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+/// Starts and stops captures on the polling loop when SIGUSR2 arrives
+pub struct CaptureControl {
+    spec_path: PathBuf,
+    toggle: Arc<AtomicBool>,
+    redaction: Option<MessageRedaction>,
+}
+
+impl CaptureControl {
+    pub fn new(spec_path: impl Into<PathBuf>) -> Self {
+        Self {
+            spec_path: spec_path.into(),
+            toggle: Arc::new(AtomicBool::new(false)),
+            redaction: None,
+        }
+    }
+
+    /// Redact every captured message with `redaction`
+    pub fn set_redaction(&mut self, redaction: MessageRedaction) {
+        self.redaction = Some(redaction);
+    }
+
+    /// The flag the signal handler raises to start or stop a capture
+    pub fn toggle(&self) -> Arc<AtomicBool> {
+        self.toggle.clone()
+    }
+
+    /// Apply a pending toggle, and close a capture that reached its limits.
+    /// Called by the polling loop between read batches.
+    pub fn poll(&self, dispatcher: &mut Dispatcher) {
+        if dispatcher
+            .capture_status()
+            .is_some_and(|status| status.state != CaptureState::Active)
+        {
+            if let Some(status) = dispatcher.disable_capture() {
+                log_finished(&status);
+            }
+        }
+
+        if !self.toggle.swap(false, Ordering::AcqRel) {
+            return;
+        }
+
+        if let Some(status) = dispatcher.disable_capture() {
+            log_finished(&status);
+            return;
+        }
+
+        let request = match CaptureRequest::load(&self.spec_path) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Not starting capture: {:#}", e);
+                return;
+            }
+        };
+        let path = request.path.clone();
+        let mut spec = request.into_spec();
+        if let Some(redaction) = self.redaction.clone() {
+            spec.rewrite = Some(Box::new(move |data: &mut [u8]| redaction(data)));
+        }
+        match dispatcher.enable_capture(spec) {
+            Ok(()) => info!("Capturing raw events to {}", path.display()),
+            Err(e) => error!("Failed to start capture to {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn log_finished(status: &CaptureStatus) {
+    let reason = match &status.state {
+        CaptureState::Active | CaptureState::Disabled => "stopped",
+        CaptureState::EventLimit => "reached its event limit",
+        CaptureState::ByteLimit => "reached its size limit",
+        CaptureState::Failed(e) => {
+            warn!("Capture to {} failed: {}", status.path.display(), e);
+            "failed"
+        }
+    };
+    info!(
+        "Capture to {} {} after {} events, {} bytes",
+        status.path.display(),
+        reason,
+        status.events,
+        status.bytes
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bpf::TimerFinishedProcessingMsg;
+    use perf_events::{collector_sample_record, read_capture, PERF_RECORD_SAMPLE};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("event_capture_{}_{}", std::process::id(), name))
+    }
+
+    fn perf_measurement(pid: u32, timestamp: u64) -> Vec<u8> {
+        let msg = PerfMeasurementMsg {
+            pid,
+            ..Default::default()
+        };
+        collector_sample_record(msg_type::MSG_TYPE_PERF_MEASUREMENT as u32, timestamp, &msg)
+    }
+
+    #[test]
+    fn test_pid_filter() {
+        let request: CaptureRequest =
+            serde_json::from_str(r#"{"path": "/tmp/capture.bin", "pid": 42, "max_events": 5}"#)
+                .unwrap();
+        assert_eq!(request.max_bytes, None);
+        let spec = request.into_spec();
+        assert_eq!(spec.max_events, 5);
+        assert_eq!(spec.max_bytes, CaptureSpec::new("").max_bytes);
+        let filter = spec.pid_filter.unwrap();
+
+        assert!(filter(&perf_measurement(42, 1)));
+        assert!(!filter(&perf_measurement(7, 1)));
+        let free = TaskFreeMsg {
+            pid: 42,
+            ..Default::default()
+        };
+        assert!(filter(&collector_sample_record(
+            msg_type::MSG_TYPE_TASK_FREE as u32,
+            1,
+            &free
+        )));
+        let tick = collector_sample_record(
+            msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING as u32,
+            1,
+            &TimerFinishedProcessingMsg::default(),
+        );
+        assert!(!filter(&tick));
+
+        assert!(serde_json::from_str::<CaptureRequest>(r#"{"path": "x", "pids": [1]}"#).is_err());
+    }
+
+    #[test]
+    fn test_toggle_and_auto_stop() {
+        let spec_path = temp_path("spec.json");
+        let capture_path = temp_path("capture.bin");
+        fs::write(
+            &spec_path,
+            format!(
+                r#"{{"path": {:?}, "msg_types": [{}], "max_events": 2}}"#,
+                capture_path,
+                msg_type::MSG_TYPE_PERF_MEASUREMENT as u32
+            ),
+        )
+        .unwrap();
+
+        let control = CaptureControl::new(&spec_path);
+        let mut dispatcher = Dispatcher::new();
+        control.poll(&mut dispatcher);
+        assert_eq!(dispatcher.capture_status(), None);
+
+        // The first toggle starts a capture, which stops at its limit
+        control.toggle().store(true, Ordering::Release);
+        control.poll(&mut dispatcher);
+        assert!(dispatcher.capture_status().is_some());
+        for timestamp in 0..5 {
+            dispatcher
+                .dispatch_record(0, PERF_RECORD_SAMPLE, &perf_measurement(1, timestamp))
+                .unwrap();
+        }
+        assert_eq!(
+            dispatcher.capture_status().unwrap().state,
+            CaptureState::EventLimit
+        );
+        control.poll(&mut dispatcher);
+        assert_eq!(dispatcher.capture_status(), None);
+        assert_eq!(
+            read_capture(&fs::read(&capture_path).unwrap())
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // A toggle during a capture stops it
+        control.toggle().store(true, Ordering::Release);
+        control.poll(&mut dispatcher);
+        dispatcher
+            .dispatch_record(1, PERF_RECORD_SAMPLE, &perf_measurement(1, 10))
+            .unwrap();
+        control.toggle().store(true, Ordering::Release);
+        control.poll(&mut dispatcher);
+        assert_eq!(dispatcher.capture_status(), None);
+        let records = read_capture(&fs::read(&capture_path).unwrap()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ring_index, 1);
+
+        // A bad spec leaves capture off
+        fs::write(&spec_path, "{").unwrap();
+        control.toggle().store(true, Ordering::Release);
+        control.poll(&mut dispatcher);
+        assert_eq!(dispatcher.capture_status(), None);
+
+        fs::remove_file(&spec_path).unwrap();
+        fs::remove_file(&capture_path).unwrap();
+    }
+
+    #[test]
+    fn test_redacted_capture() {
+        let spec_path = temp_path("redacted_spec.json");
+        let capture_path = temp_path("redacted_capture.bin");
+        fs::write(&spec_path, format!(r#"{{"path": {:?}}}"#, capture_path)).unwrap();
+
+        // Messages are captured with the pid zeroed
+        let mut control = CaptureControl::new(&spec_path);
+        control.set_redaction(Arc::new(|data: &mut [u8]| {
+            let offset = offset_of!(PerfMeasurementMsg, pid);
+            data[offset..offset + 4].fill(0);
+        }));
+        let mut dispatcher = Dispatcher::new();
+        control.toggle().store(true, Ordering::Release);
+        control.poll(&mut dispatcher);
+        dispatcher
+            .dispatch_record(0, PERF_RECORD_SAMPLE, &perf_measurement(42, 1))
+            .unwrap();
+        control.toggle().store(true, Ordering::Release);
+        control.poll(&mut dispatcher);
+
+        let records = read_capture(&fs::read(&capture_path).unwrap()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(message_pid(&records[0].data), Some(0));
+
+        fs::remove_file(&spec_path).unwrap();
+        fs::remove_file(&capture_path).unwrap();
+    }
+}
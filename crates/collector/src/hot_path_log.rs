@@ -0,0 +1,12 @@
+//! Log macros for the event processing hot path
+//!
+//! With the `tracing` feature these are tracing's macros, so events are
+//! attributed to the perf_events dispatch span they happen in. tracing's `log`
+//! compatibility turns them into log records while no tracing subscriber is
+//! installed, so env_logger setups keep receiving them.
+
+#[cfg(feature = "tracing")]
+pub use tracing::{error, info};
+
+#[cfg(not(feature = "tracing"))]
+pub use log::{error, info};
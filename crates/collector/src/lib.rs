@@ -0,0 +1,59 @@
+//! # Collector
+//!
+//! Collects per-task memory and CPU metrics with eBPF and perf counters.
+//!
+//! The collector binary writes its output to Parquet files in local or object
+//! storage. Applications embedding the collector use [`Collector`] instead,
+//! to consume the output record batches as a stream.
+
+// Configuration of collection and its optional stages
+pub mod adaptive;
+pub mod adaptive_poll;
+pub mod cgroup_filter;
+pub mod cgroup_sampler;
+pub mod cpu_throttle;
+pub mod noisy_neighbor;
+pub mod pid_namespace;
+pub mod state_file;
+pub mod suspend_monitor;
+
+// Outputs and their schemas
+pub mod attribution;
+pub mod bpf_perf_to_trace;
+pub mod cgroup_rollup_task;
+pub mod metrics;
+pub mod task_metadata;
+pub mod timeslot_data;
+pub mod timeslot_to_recordbatch_task;
+
+// Consumers of the record batch stream
+pub mod batch_transform;
+pub mod disk_guard;
+pub mod parquet_writer;
+pub mod parquet_writer_task;
+pub mod storage_quota;
+
+// Operating a collection
+pub mod debug_endpoint;
+pub mod error_code;
+pub mod event_capture;
+pub mod processor_log;
+pub mod sd_notify;
+pub mod shutdown;
+pub mod task_completion_handler;
+
+mod bpf_error_handler;
+mod bpf_perf_to_timeslot;
+mod bpf_task_tracker;
+mod bpf_timeslot_tracker;
+mod collection;
+mod hot_path_log;
+mod perf_event_processor;
+mod pipeline;
+mod wall_clock;
+
+pub use collection::{
+    AttachedCollection, Collection, CollectionReport, Collector, CollectorConfig,
+};
+pub use perf_event_processor::check_program_groups;
+pub use pipeline::{Pipeline, PipelineConfig};
@@ -1,39 +1,76 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use arrow_array::RecordBatch;
-use bpf::BpfLoader;
-use clap::Parser;
+use arrow_schema::SchemaRef;
+use bpf::{BpfLoaderConfig, ProgramGroup};
+use clap::{Parser, ValueEnum};
 use env_logger;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use nri::metadata::default_event_mask;
+use nri::types::Event;
+use nri::NRI;
 use object_store::ObjectStore;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use timeslot::TrackerState;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
-use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 use uuid::Uuid;
 
-// Import local modules
-mod bpf_error_handler;
-mod bpf_perf_to_timeslot;
-mod bpf_perf_to_trace;
-mod bpf_task_tracker;
-mod bpf_timeslot_tracker;
-mod metrics;
-mod parquet_writer;
-mod parquet_writer_task;
-mod perf_event_processor;
-mod task_completion_handler;
-mod task_metadata;
-mod timeslot_data;
-mod timeslot_to_recordbatch_task;
-
-use parquet_writer::{ParquetWriter, ParquetWriterConfig};
+// Modules of the binary; the rest of the collector is in the library
+mod capabilities;
+mod container_events;
+mod memory_store;
+mod nri_shutdown;
+mod plan;
+mod preflight;
+mod redaction;
+mod run_summary;
+mod schema_dump;
+mod window_rollup;
+
+use collector::{
+    adaptive, adaptive_poll, attribution, batch_transform, bpf_perf_to_trace, cgroup_filter,
+    cgroup_rollup_task, cgroup_sampler, cpu_throttle, debug_endpoint, disk_guard, error_code,
+    event_capture, metrics, noisy_neighbor, parquet_writer, parquet_writer_task, pid_namespace,
+    processor_log, sd_notify, shutdown, state_file, storage_quota, suspend_monitor,
+    task_completion_handler, timeslot_data, timeslot_to_recordbatch_task,
+};
+
+use adaptive::AdaptiveConfig;
+use adaptive_poll::AdaptivePollConfig;
+use attribution::AttributionLayout;
+use batch_transform::{DropColumns, TransformChain, TransformErrorPolicy};
+use bpf_perf_to_trace::TraceLimits;
+use cgroup_filter::{CgroupFilter, ContainerSelector, ContainerTable, FsCgroupResolver};
+use cgroup_sampler::CgroupSampler;
+use collector::{Collector, CollectorConfig, PipelineConfig};
+use container_events::{ContainerEventsConfig, ContainerEventsTask};
+use cpu_throttle::CpuThrottleSampler;
+use debug_endpoint::{serve_debug_endpoint, DebugEndpointConfig, DebugSnapshots};
+use disk_guard::DiskGuard;
+use error_code::error_code;
+use event_capture::{CaptureControl, MessageRedaction};
+use metrics::ErrorCounts;
+use noisy_neighbor::{NoisyNeighborConfig, ScoreWeights};
+use nri_shutdown::{NriShutdownHandler, NriShutdownPolicy};
+use parquet_writer::ParquetWriter;
 use parquet_writer_task::ParquetWriterTask;
-use perf_event_processor::{PerfEventProcessor, ProcessorMode};
+use pid_namespace::{FsProcReader, PidNamespaceTranslator};
+use plan::Plan;
+use preflight::Preflight;
+use processor_log::ProcessorRecorder;
+use redaction::{Redact, RedactionTarget};
+use run_summary::{DegradationSummary, RunSummary, TraceMemorySummary};
+use sd_notify::SdNotifier;
+use shutdown::{drain_tasks, duration_timeout_handler, ShutdownReason, ShutdownToken};
+use state_file::CollectorState;
+use storage_quota::{QuotaManager, QuotaStream};
+use suspend_monitor::SuspendMonitorConfig;
 use task_completion_handler::task_completion_handler;
-use timeslot_data::TimeslotData;
-use timeslot_to_recordbatch_task::TimeslotToRecordBatchTask;
+use timeslot_to_recordbatch_task::PartialTimeslotPolicy;
+use window_rollup::{TimeslotTee, WindowRollupTask};
 
 /// Linux process monitoring tool
 #[derive(Debug, Parser)]
@@ -46,10 +83,14 @@ struct Command {
     #[arg(short, long, default_value = "0")]
     duration: u64,
 
-    /// Storage type (local or s3)
+    /// Storage type (local, s3, or memory for testing)
     #[arg(long, default_value = "local")]
     storage_type: String,
 
+    /// For testing with --storage-type memory: copy the files written to this directory once collection finished
+    #[arg(long)]
+    memory_dump_dir: Option<std::path::PathBuf>,
+
     /// Prefix for storage path
     #[arg(short, long, default_value = "unvariance-metrics-")]
     prefix: String,
@@ -66,45 +107,337 @@ struct Command {
     #[arg(long, default_value = "1048576")]
     max_row_group_size: usize,
 
-    /// Maximum total bytes to write to object store
+    /// Size of each part when uploading Parquet files in parts (bytes)
+    #[arg(long, default_value = "10485760")] // 10MB
+    parquet_part_size: usize,
+
+    /// Maximum number of parts of a Parquet file uploaded concurrently
+    #[arg(long, default_value = "8")]
+    parquet_upload_concurrency: usize,
+
+    /// Finish uploading rotated Parquet files in the background while the
+    /// next file is written
+    #[arg(long, default_value = "false")]
+    parquet_background_close: bool,
+
+    /// Columns to remove from the output before writing, e.g. process_name for privacy
+    #[arg(long, value_delimiter = ',')]
+    drop_columns: Vec<String>,
+
+    /// Write the pod_namespace, pod_name, container_name and container_id of collected containers as top-level columns instead of fields of an attribution struct
+    #[arg(long)]
+    flat_attribution: bool,
+
+    /// Values to redact from the output before writing: comm, cmdline, pod_labels (in the container events output). Comm is also redacted in raw event captures and the processor log
+    #[arg(long, value_enum, value_delimiter = ',')]
+    redact: Vec<RedactionTarget>,
+
+    /// Replace redacted values with a keyed BLAKE3 hash instead of null, so they can still be joined
+    #[arg(long, requires = "redact", requires = "redaction_key_file")]
+    hash_redacted: bool,
+
+    /// File with the secret key for --hash-redacted; the key is never written to the output
+    #[arg(long, requires = "hash_redacted")]
+    redaction_key_file: Option<String>,
+
+    /// What to do with a batch when an output transform fails: skip it, or abort collection
+    #[arg(long, value_enum, default_value = "skip")]
+    transform_error_policy: TransformErrorPolicy,
+
+    /// Parquet format version to write (1.0 or 2.0)
+    #[arg(long, default_value = "1.0", value_parser = parquet_writer::parse_writer_version)]
+    parquet_writer_version: parquet::file::properties::WriterVersion,
+
+    /// Maximum total bytes to write to object store, shared by every output. As it is approached, outputs stop in order: trace, container events, window rollup, timeslots
     #[arg(long)]
     storage_quota: Option<usize>,
 
+    /// Bytes of the storage quota kept for an output however much the others write, as <stream>=<bytes> with stream trace, container-events, window-rollup or timeslots; may be repeated
+    #[arg(long, value_delimiter = ',', value_parser = storage_quota::parse_stream_setting::<usize>, requires = "storage_quota")]
+    storage_quota_reserve: Vec<(QuotaStream, usize)>,
+
+    /// Weight of an output's share of the storage quota kept from lower priority outputs, as <stream>=<weight>; outputs weigh 1 by default
+    #[arg(long, value_delimiter = ',', value_parser = storage_quota::parse_stream_setting::<u32>, requires = "storage_quota")]
+    storage_quota_weight: Vec<(QuotaStream, u32)>,
+
+    /// Carry the bytes counted against the storage quota across restarts while this value stays the same, e.g. a date or deployment id. Without it, or when it changes, the quota counts from zero
+    #[arg(long, requires = "state_file")]
+    storage_quota_epoch: Option<String>,
+
+    /// With local storage, stop creating files when the filesystem has fewer MB available (0 to disable)
+    #[arg(long, default_value = "512")]
+    disk_min_free_mb: u64,
+
+    /// With local storage, stop creating files when less than this percentage of the filesystem is available (0 to disable)
+    #[arg(long, default_value = "2")]
+    disk_min_free_percent: f64,
+
+    /// Resume writing once the filesystem has at least this many MB available
+    #[arg(long, default_value = "1024")]
+    disk_recovery_free_mb: u64,
+
+    /// Resume writing once at least this percentage of the filesystem is available
+    #[arg(long, default_value = "5")]
+    disk_recovery_free_percent: f64,
+
+    /// When local storage runs low on space, delete the oldest parquet files this collector wrote under the prefix until it recovers
+    #[arg(long)]
+    reclaim_own_files: bool,
+
     /// Enable trace mode (outputs individual events instead of aggregated timeslots)
     #[arg(long, default_value = "false")]
     trace: bool,
+
+    /// Estimated size at which a trace batch is cut, even below its row count (MB)
+    #[arg(long, default_value = "16")]
+    trace_max_batch_mb: usize,
+
+    /// Estimated memory of the trace batch being built plus those waiting to be written, beyond which trace mode drops measurements (MB)
+    #[arg(long, default_value = "256")]
+    trace_max_memory_mb: usize,
+
+    /// BPF program groups to load: counters, sched, task_lifecycle, sync_timer
+    #[arg(long, value_delimiter = ',', default_values_t = ProgramGroup::ALL)]
+    bpf_program_groups: Vec<ProgramGroup>,
+
+    /// Record every event consumed by the processor, and the timeslots it emits, to this file for replay in tests
+    #[arg(long, conflicts_with = "trace")]
+    record_processor_log: Option<std::path::PathBuf>,
+
+    /// Maximum size of the processor log before it is rotated (bytes)
+    #[arg(long, default_value = "67108864")] // 64MB
+    processor_log_size: u64,
+
+    /// Local file for state kept across restarts, such as timeslot tracking progress and the bytes counted against the storage quota
+    #[arg(long)]
+    state_file: Option<std::path::PathBuf>,
+
+    /// Maximum age of restored timeslot tracking state (in 1ms timeslots)
+    #[arg(long, default_value = "10000")]
+    state_max_staleness_slots: u64,
+
+    /// Timer intervals without ticks after a system suspend before the sync timer is restarted
+    #[arg(long, default_value = "1000")]
+    suspend_stall_intervals: u64,
+
+    /// Lower collection overhead under CPU pressure by merging timeslots, pausing trace output and polling less often
+    #[arg(long, conflicts_with = "adaptive_poll")]
+    adaptive: bool,
+
+    /// Collector CPU usage, as a fraction of one CPU, above which adaptive collection degrades
+    #[arg(long, default_value = "0.5")]
+    adaptive_cpu_high: f64,
+
+    /// Collector CPU usage, as a fraction of one CPU, below which adaptive collection recovers
+    #[arg(long, default_value = "0.25")]
+    adaptive_cpu_low: f64,
+
+    /// Sleep between idle polls for longer while collection is idle and shorter while it is busy, instead of a fixed 10ms
+    #[arg(long)]
+    adaptive_poll: bool,
+
+    /// Add the pid inside its container of each task in the containers followed by --cgroup-filter, read from /proc/<pid>/status, as a container_pid column
+    #[arg(long, requires = "cgroup_filter")]
+    translate_pid_ns: bool,
+
+    /// Add each container's CFS throttling growth, read from cgroup cpu.stat, as nr_throttled_delta and throttled_usec_delta columns
+    #[arg(long, conflicts_with = "trace")]
+    cgroup_throttling: bool,
+
+    /// What to do with the first timeslot, which collection started partway through: mark its rows with the partial column, or drop it
+    #[arg(long, value_enum, default_value = "mark", conflicts_with_all = ["trace", "cgroup_rollup"])]
+    partial_timeslots: PartialTimeslotPolicy,
+
+    /// Shards converting each timeslot to a record batch in parallel, defaults to min(4, CPUs / 8)
+    #[arg(long, conflicts_with = "trace")]
+    conversion_threads: Option<usize>,
+
+    /// Write a row per cgroup and timeslot with the summed counters of its tasks, instead of a row per task
+    #[arg(long, conflicts_with_all = ["trace", "cgroup_throttling", "noisy_neighbor_scores"])]
+    cgroup_rollup: bool,
+
+    /// Score containers online by how much they disturb their neighbors, reporting the top scores in the run summary
+    #[arg(long, conflicts_with = "trace")]
+    noisy_neighbor_scores: bool,
+
+    /// Weights of the LLC miss share, cycle share and co-located throttling terms of the noisy neighbor score
+    #[arg(long, value_delimiter = ',', default_values_t = [0.5, 0.3, 0.2], requires = "noisy_neighbor_scores")]
+    noisy_neighbor_weights: Vec<f64>,
+
+    /// Half-life of noisy neighbor scores (in 1ms timeslots)
+    #[arg(long, default_value = "1000", requires = "noisy_neighbor_scores")]
+    noisy_neighbor_half_life_slots: f64,
+
+    /// Number of highest scoring containers reported
+    #[arg(long, default_value = "10", requires = "noisy_neighbor_scores")]
+    noisy_neighbor_top_k: usize,
+
+    /// Mount point of the cgroup v2 hierarchy
+    #[arg(long, default_value = cgroup_sampler::DEFAULT_CGROUP_ROOT)]
+    cgroup_root: std::path::PathBuf,
+
+    /// Maximum number of cgroups read per throttling sample, larger container sets are sampled in rotation
+    #[arg(long, default_value = "64")]
+    cgroup_max_per_tick: usize,
+
+    /// Interval between samples of container throttling with --cgroup-throttling (in milliseconds)
+    #[arg(long, default_value = "100")]
+    cgroup_sample_interval_ms: u64,
+
+    /// Only collect containers matching this selector, e.g. namespace=prod,pod=web-0,label.app=web. Follows containers as they start and stop using NRI, attributes rows to their pod and container, and adds their resource limits to timeslot rows
+    #[arg(long)]
+    cgroup_filter: Option<ContainerSelector>,
+
+    /// NRI socket of the container runtime, used by --cgroup-filter
+    #[arg(long, default_value = "/var/run/nri/nri.sock")]
+    nri_socket: std::path::PathBuf,
+
+    /// What to do when the container runtime shuts the NRI plugin down: continue, freeze-attribution to keep the last known containers and set attribution_stale on rows from then on, reconnect, or exit
+    #[arg(
+        long,
+        value_enum,
+        default_value = "continue",
+        requires = "cgroup_filter"
+    )]
+    on_nri_shutdown: NriShutdownPolicy,
+
+    /// Do not write the lifecycle events of containers followed with --cgroup-filter to their own files under <prefix><node>container-events-
+    #[arg(long, requires = "cgroup_filter")]
+    no_container_events: bool,
+
+    /// Container labels copied into container event rows
+    #[arg(long, value_delimiter = ',', requires = "cgroup_filter")]
+    container_event_labels: Vec<String>,
+
+    /// Also write each cgroup's sums over wall-clock aligned windows to their own files under <prefix><node>window-rollup-, for long retention
+    #[arg(long, conflicts_with = "trace")]
+    window_rollup: bool,
+
+    /// Length of the window rollup's windows (seconds)
+    #[arg(long, default_value = "60", requires = "window_rollup")]
+    window_rollup_secs: u64,
+
+    /// Maximum size of each window rollup file before rotation (bytes)
+    #[arg(long, default_value = "16777216", requires = "window_rollup")] // 16MB
+    window_rollup_file_size: usize,
+
+    /// Maximum total bytes of window rollup files written to object store
+    #[arg(long, requires = "window_rollup")]
+    window_rollup_storage_quota: Option<usize>,
+
+    /// Maximum time to wait for tasks to finish at shutdown before cancelling them (seconds)
+    #[arg(long, default_value = "30")]
+    shutdown_drain_timeout: u64,
+
+    /// Serve a JSON snapshot of ring, dispatcher and processor internals at /debug/state
+    #[arg(long)]
+    enable_debug_endpoint: bool,
+
+    /// Address the debug endpoint listens on
+    #[arg(
+        long,
+        default_value = "127.0.0.1:6060",
+        requires = "enable_debug_endpoint"
+    )]
+    debug_endpoint_addr: String,
+
+    /// JSON spec of the raw event capture that SIGUSR2 starts and stops
+    #[arg(long)]
+    capture_spec: Option<std::path::PathBuf>,
+
+    /// Load the BPF programs even if the kernel looks unsupported, keeping the verifier log for the run summary
+    #[arg(long)]
+    force_load: bool,
+
+    /// Print a JSON report of build info, probed capabilities and resolved configuration, then exit
+    #[arg(long)]
+    capabilities_json: bool,
+
+    /// Write the Arrow schema of the selected output, after --redact and --drop-columns, as JSON to this file, then exit
+    #[arg(long)]
+    dump_schema: Option<std::path::PathBuf>,
+
+    /// Check every option and print the outputs, storage, BPF program groups and quotas the collector would use as JSON, then exit without loading BPF programs or touching storage
+    #[arg(long)]
+    dry_run: bool,
+
+    /// With --dry-run, also probe the kernel and report whether the BPF programs would be loaded
+    #[arg(long, requires = "dry_run")]
+    plan_probe: bool,
 }
 
-/// Duration timeout handler - exits when duration completes or cancellation token is triggered
-async fn duration_timeout_handler(
-    duration: Duration,
-    cancellation_token: CancellationToken,
-) -> Result<()> {
-    // Wait for either duration timeout or cancellation
-    tokio::select! {
-        _ = tokio::time::sleep(duration) => {
-            debug!("Duration timeout reached");
+/// How long to wait for the run summary to be written before giving up
+const RUN_SUMMARY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Build the noisy neighbor scoring configuration from the command line
+fn noisy_neighbor_config(opts: &Command) -> Result<NoisyNeighborConfig> {
+    let [llc_misses, cycles, throttling] = opts.noisy_neighbor_weights[..] else {
+        return Err(anyhow!(
+            "--noisy-neighbor-weights takes 3 weights, got {}",
+            opts.noisy_neighbor_weights.len()
+        ));
+    };
+    if opts.noisy_neighbor_half_life_slots <= 0.0 {
+        return Err(anyhow!("--noisy-neighbor-half-life-slots must be positive"));
+    }
+    Ok(NoisyNeighborConfig {
+        weights: ScoreWeights {
+            llc_misses,
+            cycles,
+            throttling,
+        },
+        half_life_slots: opts.noisy_neighbor_half_life_slots,
+        top_k: opts.noisy_neighbor_top_k,
+    })
+}
+
+/// Write the timeslot tracker snapshot to the state file, keeping its other entries
+fn save_timeslot_tracker(path: &std::path::Path, snapshot: TrackerState) {
+    let mut state = match CollectorState::load(path) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Replacing unreadable state file: {:#}", e);
+            CollectorState::default()
         }
-        _ = cancellation_token.cancelled() => {
-            debug!("Duration timeout handler cancelled");
+    };
+    state.min_tracker = Some(snapshot);
+
+    match state.save(path) {
+        Ok(()) => debug!("Saved timeslot tracker snapshot to {}", path.display()),
+        Err(e) => error!("Failed to save collector state: {:#}", e),
+    }
+}
+
+/// Write the bytes counted against the storage quota in `epoch` to the state file, keeping its other entries
+fn save_quota_usage(path: &std::path::Path, quota: &QuotaManager, epoch: Option<&str>) {
+    let mut state = match CollectorState::load(path) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Replacing unreadable state file: {:#}", e);
+            CollectorState::default()
         }
+    };
+    state.storage_quota = Some(quota.state(epoch));
+
+    match state.save(path) {
+        Ok(()) => debug!("Saved storage quota usage to {}", path.display()),
+        Err(e) => error!("Failed to save collector state: {:#}", e),
     }
-    Ok(())
 }
 
 /// Signal handler for SIGTERM and SIGINT - triggers cancellation when received
-async fn signal_handler(cancellation_token: CancellationToken) -> Result<()> {
+async fn signal_handler(cancellation_token: ShutdownToken) -> Result<()> {
     let mut sigterm = signal(SignalKind::terminate())?;
     let mut sigint = signal(SignalKind::interrupt())?;
 
     tokio::select! {
         _ = sigterm.recv() => {
             debug!("Received SIGTERM, triggering shutdown");
-            cancellation_token.cancel();
+            cancellation_token.cancel(ShutdownReason::Signal("SIGTERM".to_string()));
         }
         _ = sigint.recv() => {
             debug!("Received SIGINT, triggering shutdown");
-            cancellation_token.cancel();
+            cancellation_token.cancel(ShutdownReason::Signal("SIGINT".to_string()));
         }
         _ = cancellation_token.cancelled() => {
             debug!("Signal handler cancelled");
@@ -116,7 +449,7 @@ async fn signal_handler(cancellation_token: CancellationToken) -> Result<()> {
 /// SIGUSR1 rotation handler - sends rotation signals when SIGUSR1 is received
 async fn rotation_handler(
     rotate_sender: mpsc::Sender<()>,
-    cancellation_token: CancellationToken,
+    cancellation_token: ShutdownToken,
 ) -> Result<()> {
     let mut sigusr1 = signal(SignalKind::user_defined1())?;
 
@@ -139,6 +472,127 @@ async fn rotation_handler(
     Ok(())
 }
 
+/// SIGUSR2 capture handler - starts or stops a raw event capture on the
+/// polling loop when SIGUSR2 is received
+async fn capture_toggle_handler(
+    toggle: Arc<AtomicBool>,
+    cancellation_token: ShutdownToken,
+) -> Result<()> {
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+
+    loop {
+        tokio::select! {
+            _ = sigusr2.recv() => {
+                debug!("Received SIGUSR2, toggling raw event capture");
+                toggle.store(true, Ordering::Release);
+            }
+            _ = cancellation_token.cancelled() => {
+                debug!("Capture toggle handler cancelled");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name of the output the options select, as used in logs and the run summary
+fn output_name(opts: &Command) -> &'static str {
+    if opts.trace {
+        "trace"
+    } else if opts.cgroup_rollup {
+        "cgroup_rollup"
+    } else {
+        "timeslot"
+    }
+}
+
+/// Whether the options write the lifecycle events of followed containers
+fn writes_container_events(opts: &Command) -> bool {
+    opts.cgroup_filter.is_some() && !opts.no_container_events
+}
+
+/// Layout of the container identity in timeslot and trace rows
+fn attribution_layout(opts: &Command) -> AttributionLayout {
+    if opts.flat_attribution {
+        AttributionLayout::Flat
+    } else {
+        AttributionLayout::Nested
+    }
+}
+
+/// The key hashing redacted values, if the options give one
+fn redaction_key(opts: &Command) -> Result<Option<[u8; 32]>> {
+    opts.redaction_key_file
+        .as_deref()
+        .map(redaction::read_redaction_key)
+        .transpose()
+}
+
+/// Redaction of the raw messages that outputs such as captures keep
+fn message_redaction(opts: &Command) -> Result<Option<MessageRedaction>> {
+    Ok(Redact::new(opts.redact.clone(), redaction_key(opts)?).message_redaction())
+}
+
+/// The transforms the options apply to output batches of `schema`, checking
+/// that the columns they name exist
+fn build_transforms(
+    opts: &Command,
+    schema: SchemaRef,
+    counters_enabled: bool,
+) -> Result<TransformChain> {
+    let output_name = output_name(opts);
+    let mut transforms = TransformChain::new(schema, opts.transform_error_policy);
+    if !opts.redact.is_empty() {
+        let redact = Redact::new(opts.redact.clone(), redaction_key(opts)?);
+        // Targets only in the container events output are redacted there
+        let missing: Vec<RedactionTarget> = redact
+            .missing_targets(&transforms.output_schema())
+            .into_iter()
+            .filter(|target| {
+                !writes_container_events(opts)
+                    || container_events::events_redaction(&[*target], None).is_none()
+            })
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "--redact targets not in the {} output: {}",
+                output_name,
+                missing
+                    .iter()
+                    .map(|target| format!(
+                        "{} (column {})",
+                        target.to_possible_value().unwrap().get_name(),
+                        target.column()
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        transforms.push(Box::new(redact));
+    }
+    // Without counters, trace rows would report zero deltas, so leave the columns out
+    let mut dropped = opts.drop_columns.clone();
+    if opts.trace && !counters_enabled {
+        for column in bpf_perf_to_trace::COUNTER_COLUMNS {
+            if !dropped.iter().any(|name| name == column) {
+                dropped.push(column.to_string());
+            }
+        }
+    }
+    if !dropped.is_empty() {
+        let drop_columns = DropColumns::new(dropped);
+        let missing = drop_columns.missing_columns(&transforms.output_schema());
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "--drop-columns names unknown columns: {}",
+                missing.join(", ")
+            ));
+        }
+        transforms.push(Box::new(drop_columns));
+    }
+    Ok(transforms)
+}
+
 // Create object store based on storage type
 fn create_object_storage(storage_type: &str) -> Result<Arc<dyn ObjectStore>> {
     match storage_type.to_lowercase().as_str() {
@@ -147,6 +601,10 @@ fn create_object_storage(storage_type: &str) -> Result<Arc<dyn ObjectStore>> {
             let s3 = object_store::aws::AmazonS3Builder::from_env().build()?;
             Ok(Arc::new(s3))
         }
+        memory_store::MEMORY_STORAGE_TYPE => {
+            warn!("Keeping output in memory, which is meant for testing only");
+            Ok(Arc::new(object_store::memory::InMemory::new()))
+        }
         "local" | _ => {
             debug!("Creating local filesystem object store");
             let local = object_store::local::LocalFileSystem::new();
@@ -175,8 +633,62 @@ async fn main() -> Result<()> {
 
     let opts = Command::parse();
 
+    if opts.capabilities_json {
+        let report = capabilities::capabilities_report(&opts);
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    if let Some(path) = &opts.dump_schema {
+        let (schema, version) = schema_dump::output_schema(&opts);
+        let counters_enabled = BpfLoaderConfig {
+            enabled_groups: opts.bpf_program_groups.clone(),
+            ..Default::default()
+        }
+        .is_enabled(ProgramGroup::Counters);
+        let transforms = build_transforms(&opts, schema, counters_enabled)?;
+        schema_dump::dump_schema(
+            path,
+            &transforms.output_schema(),
+            output_name(&opts),
+            version,
+        )?;
+        info!(
+            "Wrote the {} schema to {}",
+            output_name(&opts),
+            path.display()
+        );
+        return Ok(());
+    }
+
+    if opts.dry_run {
+        let node_id = get_node_identity();
+        let num_cpus = libbpf_rs::num_possible_cpus()?;
+        let mut plan = Plan::new(&opts, &node_id, num_cpus)?.to_json(&opts);
+        if opts.plan_probe {
+            let probes = capabilities::SystemProbes::collect();
+            plan["probes"] = plan::probe_json(&probes, &Preflight::run(&probes, opts.force_load));
+        }
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+        return Ok(());
+    }
+
+    // Check the kernel before loading anything, so an unsupported one gets a
+    // single clear error rather than pages of verifier output
+    let preflight = Preflight::run(&capabilities::SystemProbes::collect(), opts.force_load);
+    if let Some(code) = preflight.exit_code() {
+        eprintln!("Error: {}", preflight.error_message());
+        std::process::exit(code);
+    }
+    for problem in preflight.problems() {
+        warn!("Loading despite {} because of --force-load", problem);
+    }
+
     debug!("Starting collector with options: {:?}", opts);
 
+    let started_at = chrono::Utc::now();
+    let run_id = run_summary::generate_run_id();
+
     // Get node identity for file path
     let node_id = get_node_identity();
 
@@ -186,67 +698,183 @@ async fn main() -> Result<()> {
     // Determine the number of available CPUs
     let num_cpus = libbpf_rs::num_possible_cpus()?;
 
-    // Compose storage prefix with node identity
-    let storage_prefix = format!("{}{}", opts.prefix, node_id);
-
-    // Create CPU count metadata for parquet files
-    let cpu_metadata = vec![parquet::file::metadata::KeyValue {
-        key: "num_cpus".to_string(),
-        value: Some(num_cpus.to_string()),
-    }];
-
-    // Create ParquetWriterConfig with the storage prefix and metadata
-    let config = ParquetWriterConfig {
-        storage_prefix,
-        buffer_size: opts.parquet_buffer_size,
-        file_size_limit: opts.parquet_file_size,
-        max_row_group_size: opts.max_row_group_size,
-        storage_quota: opts.storage_quota,
-        key_value_metadata: Some(cpu_metadata),
-    };
+    // Resolve the settings of every stage, reporting all invalid options at once
+    let Plan {
+        output: output_name,
+        writer: config,
+        transforms,
+        bpf: mut bpf_config,
+        conversion_threads,
+        noisy_neighbors,
+        window_rollup,
+        container_events: container_events_writer_config,
+        disk_guard: disk_guard_config,
+        quota,
+        ..
+    } = Plan::new(&opts, &node_id, num_cpus)?;
+
+    // Count the bytes written before a restart in the same epoch against the quota
+    if let Some(ref path) = opts.state_file {
+        match CollectorState::load(path) {
+            Ok(CollectorState {
+                storage_quota: Some(usage),
+                ..
+            }) => {
+                if quota.restore(&usage, opts.storage_quota_epoch.as_deref()) {
+                    info!("Restored storage quota usage: {:?}", usage.closed_bytes);
+                } else {
+                    info!(
+                        "Not restoring storage quota usage of epoch {:?}, counting from zero",
+                        usage.epoch
+                    );
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("Not restoring storage quota usage: {:#}", e),
+        }
+    }
+
+    // Keep the run summary under the same prefix as the parquet files
+    let summary_path = run_summary::summary_path(&config.storage_prefix, &run_id);
 
-    // Create channels for the pipeline
-    let (batch_sender, batch_receiver) = mpsc::channel::<RecordBatch>(1000);
+    // Create the channel rotating the parquet files on SIGUSR1
     let (rotate_sender, rotate_receiver) = mpsc::channel::<()>(1);
 
     // Create shutdown token and task tracker
-    let shutdown_token = CancellationToken::new();
+    let shutdown_token = ShutdownToken::new();
     let task_tracker = TaskTracker::new();
 
-    // Configure processor mode and schema based on trace flag
-    let (processor_mode, schema) = if opts.trace {
-        // Trace mode: direct RecordBatch output
-        let schema = crate::bpf_perf_to_trace::create_schema();
-        (ProcessorMode::Trace(batch_sender), schema)
-    } else {
-        // Timeslot mode: aggregated output with conversion
-        let (timeslot_sender, timeslot_receiver) = mpsc::channel::<TimeslotData>(1000);
+    bpf_config.capture_verifier_log = preflight.capture_verifier_log();
 
-        // Create the conversion task and get schema
-        let conversion_task = TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
-        let schema = conversion_task.schema();
+    // Identity and limits of the filtered containers, kept from NRI metadata
+    let containers = ContainerTable::default();
+    let (shutdown_notice_sender, shutdown_notices) = mpsc::unbounded_channel();
+    let mut nri_shutdown_handler = NriShutdownHandler::new(opts.on_nri_shutdown, shutdown_notices);
+    let attribution_stale = nri_shutdown_handler.attribution_stale();
 
-        // Spawn the conversion task
-        task_tracker.spawn(task_completion_handler(
-            conversion_task.run(),
-            shutdown_token.clone(),
-            "TimeslotToRecordBatchTask",
-        ));
-
-        (ProcessorMode::Timeslot(timeslot_sender), schema)
+    // Configure the output, the BPF programs and how they are polled
+    let collector_config = CollectorConfig {
+        pipeline: PipelineConfig {
+            trace: opts.trace,
+            cgroup_rollup: opts.cgroup_rollup,
+            conversion_threads,
+            partial_timeslots: opts.partial_timeslots,
+            throttle_sampler: opts.cgroup_throttling.then(|| {
+                CpuThrottleSampler::new(CgroupSampler::new(
+                    &opts.cgroup_root,
+                    opts.cgroup_max_per_tick,
+                ))
+            }),
+            throttle_interval: Duration::from_millis(opts.cgroup_sample_interval_ms),
+            containers: opts.cgroup_filter.is_some().then(|| containers.clone()),
+            attribution: attribution_layout(&opts),
+            attribution_stale: opts
+                .cgroup_filter
+                .is_some()
+                .then(|| attribution_stale.clone()),
+            noisy_neighbors,
+        },
+        bpf: bpf_config,
+        trace_limits: TraceLimits {
+            max_batch_bytes: opts.trace_max_batch_mb * 1024 * 1024,
+            max_in_flight_bytes: opts.trace_max_memory_mb * 1024 * 1024,
+            ..Default::default()
+        },
+        adaptive: opts.adaptive.then(|| AdaptiveConfig {
+            cpu_high: opts.adaptive_cpu_high,
+            cpu_low: opts.adaptive_cpu_low,
+            ..Default::default()
+        }),
+        adaptive_poll: opts.adaptive_poll.then(AdaptivePollConfig::default),
+        suspend_monitor: SuspendMonitorConfig {
+            stall_intervals: opts.suspend_stall_intervals,
+            ..Default::default()
+        },
     };
+    let (mut collection, batches) =
+        Collector::new(collector_config).run_streaming(&task_tracker, &shutdown_token)?;
+    let schema = collection.pipeline.schema.clone();
+    let timeslot_counter = collection.pipeline.timeslot_counter.clone();
+    let dropped_batches = collection.pipeline.dropped_batches.clone();
+    let noisy_neighbors = collection.pipeline.noisy_neighbors.clone();
+
+    // Tee timeslots into the window rollup, which has its own writer
+    if let Some((mut rollup_config, rollup_writer_config)) = window_rollup {
+        if let Some((tee_receiver, timeslot_sender)) = collection.insert_timeslot_stage(1000) {
+            let (totals_sender, totals_receiver) = mpsc::channel(1000);
+            let (rollup_batch_sender, rollup_batch_receiver) = mpsc::channel::<RecordBatch>(100);
+            let tee = TimeslotTee::new(tee_receiver, timeslot_sender, totals_sender);
+            rollup_config.clock_offset_ns = Some(window_rollup::wall_clock_offset_ns());
+            let rollup_task =
+                WindowRollupTask::new(rollup_config, totals_receiver, rollup_batch_sender)?;
+            info!(
+                "Writing {}s window rollups with prefix: {}",
+                opts.window_rollup_secs, rollup_writer_config.storage_prefix
+            );
+            let rollup_writer =
+                ParquetWriter::new(store.clone(), rollup_task.schema(), rollup_writer_config)?;
+            // The rollup files rotate on their own size limit, not on SIGUSR1
+            let (_, rollup_rotate_receiver) = mpsc::channel::<()>(1);
+            task_tracker.spawn(task_completion_handler(
+                ParquetWriterTask::new(
+                    rollup_writer,
+                    rollup_batch_receiver,
+                    rollup_rotate_receiver,
+                )
+                .run(),
+                shutdown_token.clone(),
+                "WindowRollupWriterTask",
+            ));
+            task_tracker.spawn(task_completion_handler(
+                rollup_task.run(),
+                shutdown_token.clone(),
+                "WindowRollupTask",
+            ));
+            task_tracker.spawn(task_completion_handler(
+                tee.run(),
+                shutdown_token.clone(),
+                "TimeslotTee",
+            ));
+        }
+    }
 
     // Create the ParquetWriter with the appropriate schema
     debug!(
         "Writing {} data to {} storage with prefix: {}",
-        if opts.trace { "trace" } else { "timeslot" },
-        &opts.storage_type,
-        &config.storage_prefix
+        output_name, &opts.storage_type, &config.storage_prefix
     );
-    let writer = ParquetWriter::new(store, schema, config)?;
+    // The plan's transforms take the schema the options select, see schema_dump::output_schema
+    debug_assert_eq!(schema, transforms.input_schema());
+
+    // Keep local storage from filling the filesystem
+    let disk_guard = disk_guard_config.map(|disk_guard_config| {
+        DiskGuard::new(
+            disk_guard_config,
+            std::path::Path::new("/"),
+            &config.storage_prefix,
+        )
+    });
+
+    let mut writer = ParquetWriter::new(store.clone(), transforms.output_schema(), config)?;
+    if let Some(disk_guard) = disk_guard {
+        writer.set_disk_guard(disk_guard);
+    }
+
+    // Collect file and quota notifications for the run summary
+    let (writer_notify_sender, mut writer_notify_receiver) = mpsc::unbounded_channel();
+    writer.set_notifier(writer_notify_sender);
+    let writer_memory = writer.memory_gauge();
 
     // Create ParquetWriterTask with pre-configured channels
-    let writer_task = ParquetWriterTask::new(writer, batch_receiver, rotate_receiver);
+    let transform_errors = transforms.error_counter();
+    // Count the errors the collector carries on after, for the run summary
+    let error_counts = Arc::new(ErrorCounts::default());
+
+    let mut writer_task = ParquetWriterTask::new(writer, batches, rotate_receiver);
+    writer_task.set_error_counts(error_counts.clone());
+    if !transforms.is_empty() {
+        writer_task.set_transforms(transforms);
+    }
 
     // Spawn the writer task with completion handler using task tracker
     task_tracker.spawn(task_completion_handler(
@@ -281,48 +909,284 @@ async fn main() -> Result<()> {
         "RotationHandler",
     ));
 
-    // Close the tracker since we've added all tasks
-    task_tracker.close();
+    // Start and stop raw event captures on SIGUSR2
+    if let Some(ref spec_path) = opts.capture_spec {
+        let mut control = CaptureControl::new(spec_path);
+        if let Some(redaction) = message_redaction(&opts)? {
+            control.set_redaction(redaction);
+        }
+        task_tracker.spawn(task_completion_handler(
+            capture_toggle_handler(control.toggle(), shutdown_token.clone()),
+            shutdown_token.clone(),
+            "CaptureToggleHandler",
+        ));
+        collection.set_capture_control(control);
+    }
 
-    // Create a BPF loader with the specified verbosity
-    let mut bpf_loader = BpfLoader::new()?;
+    // Read container pids from /proc on a worker task, off the polling loop
+    if opts.translate_pid_ns {
+        let (translator, worker) = PidNamespaceTranslator::new(FsProcReader);
+        task_tracker.spawn(task_completion_handler(
+            worker.run(),
+            shutdown_token.clone(),
+            "PidNamespaceWorker",
+        ));
+        collection.set_pid_translator(translator);
+    }
 
-    // Initialize the sync timer
-    bpf_loader.start_sync_timer()?;
+    // Follow the containers matching the filter through NRI container metadata
+    let nri_task = match opts.cgroup_filter {
+        Some(ref selector) => {
+            // Limit changes arrive in container updates
+            let mut events = default_event_mask();
+            events.set(&[Event::UPDATE_CONTAINER]);
+            // Container events also record when containers start
+            let lifecycle_hints = container_events_writer_config.is_some();
+            if lifecycle_hints {
+                events.set(&[Event::START_CONTAINER]);
+            }
+            let (nri_task, metadata_rx, subscription) = NRI::builder("memory-collector", "10")
+                .event_mask(events)
+                .shutdown_notifier(shutdown_notice_sender)
+                .lifecycle_hints(lifecycle_hints)
+                .run(&opts.nri_socket);
+            nri_shutdown_handler.set_nri_task(nri_task.abort_handle());
+            nri_shutdown_handler.set_reconnect(move || subscription.reconnect());
+            task_tracker.spawn(task_completion_handler(
+                nri_shutdown_handler.run(shutdown_token.clone()),
+                shutdown_token.clone(),
+                "NriShutdownHandler",
+            ));
+            let (filter, mut worker) = CgroupFilter::new(
+                selector.clone(),
+                FsCgroupResolver::new(&opts.cgroup_root),
+                metadata_rx,
+            );
+            worker.set_containers(containers);
+            worker.set_frozen(attribution_stale);
+            if let Some(writer_config) = container_events_writer_config {
+                let (event_sender, event_receiver) = mpsc::unbounded_channel();
+                let (events_batch_sender, events_batch_receiver) =
+                    mpsc::channel::<RecordBatch>(100);
+                worker.set_event_forward(event_sender);
+                let config = ContainerEventsConfig {
+                    labels: opts.container_event_labels.clone(),
+                    ..Default::default()
+                };
+                let events_task =
+                    ContainerEventsTask::new(config, event_receiver, events_batch_sender);
+                info!(
+                    "Writing container events with prefix: {}",
+                    writer_config.storage_prefix
+                );
+                // Pod labels leave the process redacted, like the main output
+                let mut events_transforms =
+                    TransformChain::new(events_task.schema(), opts.transform_error_policy);
+                if let Some(redact) =
+                    container_events::events_redaction(&opts.redact, redaction_key(&opts)?)
+                {
+                    events_transforms.push(Box::new(redact));
+                }
+                let events_writer = ParquetWriter::new(
+                    store.clone(),
+                    events_transforms.output_schema(),
+                    writer_config,
+                )?;
+                // The events files are not rotated on SIGUSR1
+                let (_, events_rotate_receiver) = mpsc::channel::<()>(1);
+                let mut events_writer_task = ParquetWriterTask::new(
+                    events_writer,
+                    events_batch_receiver,
+                    events_rotate_receiver,
+                );
+                events_writer_task.set_error_counts(error_counts.clone());
+                if !events_transforms.is_empty() {
+                    events_writer_task.set_transforms(events_transforms);
+                }
+                task_tracker.spawn(task_completion_handler(
+                    events_writer_task.run(),
+                    shutdown_token.clone(),
+                    "ContainerEventsWriterTask",
+                ));
+                task_tracker.spawn(task_completion_handler(
+                    events_task.run(),
+                    shutdown_token.clone(),
+                    "ContainerEventsTask",
+                ));
+            }
+            task_tracker.spawn(task_completion_handler(
+                worker.run(shutdown_token.clone()),
+                shutdown_token.clone(),
+                "CgroupFilterWorker",
+            ));
+            info!("Collecting containers matching {:?}", selector);
+            collection.set_cgroup_filter(filter);
+            Some(nri_task)
+        }
+        None => None,
+    };
 
-    // Create PerfEventProcessor with the appropriate mode
-    let processor = PerfEventProcessor::new(&mut bpf_loader, num_cpus, processor_mode);
+    // Serve snapshots of the polling loop's state, if asked to
+    if opts.enable_debug_endpoint {
+        let listener = tokio::net::TcpListener::bind(&opts.debug_endpoint_addr)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to bind debug endpoint to {}: {}",
+                    opts.debug_endpoint_addr,
+                    e
+                )
+            })?;
+        warn!(
+            "Debug endpoint exposes collector internals on http://{}{}",
+            opts.debug_endpoint_addr,
+            debug_endpoint::DEBUG_STATE_PATH
+        );
+        let snapshots = Arc::new(DebugSnapshots::new());
+        task_tracker.spawn(task_completion_handler(
+            serve_debug_endpoint(
+                listener,
+                snapshots.clone(),
+                DebugEndpointConfig::default(),
+                shutdown_token.clone(),
+            ),
+            shutdown_token.clone(),
+            "DebugEndpoint",
+        ));
+        collection.set_debug_snapshots(snapshots, Some(writer_memory));
+    }
 
-    // Attach BPF programs
-    bpf_loader.attach()?;
+    // Close the tracker since we've added all tasks
+    task_tracker.close();
 
-    info!("Collection started.");
+    // Open the processor log if recording was requested
+    if let Some(ref path) = opts.record_processor_log {
+        let mut recorder = ProcessorRecorder::create(path, opts.processor_log_size, num_cpus)?;
+        if let Some(redaction) = message_redaction(&opts)? {
+            recorder.set_redaction(redaction);
+        }
+        info!("Recording processor log to {}", path.display());
+        collection.set_recorder(recorder);
+    }
 
-    // Run BPF polling in the main thread until signaled to stop
-    loop {
-        // Check if we should shutdown
-        if shutdown_token.is_cancelled() {
-            break;
+    // Continue timeslot tracking from the previous run, if it is recent enough
+    if let Some(ref path) = opts.state_file {
+        match CollectorState::load(path) {
+            Ok(CollectorState {
+                min_tracker: Some(snapshot),
+                ..
+            }) => collection.restore_timeslot_tracker(snapshot, opts.state_max_staleness_slots),
+            Ok(_) => info!("No timeslot tracker snapshot in state file, starting fresh"),
+            Err(e) => error!("Not restoring collector state: {:#}", e),
         }
+    }
 
-        // Poll for events with a 10ms timeout
-        if let Err(e) = bpf_loader.poll_events(10) {
-            // Log error directly and cancel shutdown token
-            error!("BPF polling error: {}", e);
-            shutdown_token.cancel();
-            break;
+    // Tell systemd we are up, when running as a notify service
+    if let Some(notifier) = SdNotifier::from_env() {
+        collection.set_sd_notifier(notifier);
+    }
+    collection.set_error_counts(error_counts.clone());
+
+    // Load the BPF programs and poll them in the main thread until signaled to stop
+    let report = match collection.run(&shutdown_token).await {
+        Ok(report) => report,
+        Err(e) => {
+            // Keep the captured verifier log in the run summary (best-effort)
+            if let Some(log) = e.downcast_ref::<bpf::VerifierLog>() {
+                let mut summary = RunSummary::new(&run_id, &node_id, output_name, started_at);
+                summary.preflight_problems = preflight
+                    .problems()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect();
+                summary.verifier_log = Some(log.text().to_string());
+                summary.finish(Some(ShutdownReason::Error {
+                    message: format!("{:#}", e),
+                    code: error_code(&e),
+                }));
+                match run_summary::write_run_summary(
+                    store.as_ref(),
+                    &summary_path,
+                    &summary,
+                    RUN_SUMMARY_TIMEOUT,
+                )
+                .await
+                {
+                    Ok(()) => error!("BPF load failed, verifier log written to {}", summary_path),
+                    Err(write_err) => error!("Failed to write run summary: {}", write_err),
+                }
+            }
+            return Err(e);
         }
+    };
 
-        // Drive the tokio runtime forward
-        tokio::task::yield_now().await;
+    // Persist timeslot tracking progress on a clean shutdown
+    if let Some(ref path) = opts.state_file {
+        if !matches!(shutdown_token.reason(), Some(ShutdownReason::Error { .. })) {
+            save_timeslot_tracker(path, report.timeslot_tracker);
+        }
     }
 
-    // Clean up: shutdown the processor
-    processor.borrow_mut().shutdown();
+    // The NRI plugin reconnects until stopped
+    if let Some(nri_task) = nri_task {
+        nri_task.abort();
+    }
 
     // Clean up: wait for all tasks to complete
     debug!("Waiting for all tasks to complete...");
-    task_tracker.wait().await;
+    drain_tasks(
+        &task_tracker,
+        &shutdown_token,
+        Duration::from_secs(opts.shutdown_drain_timeout),
+    )
+    .await;
+
+    // Every file is closed, so the quota usage is final
+    if let Some(ref path) = opts.state_file {
+        save_quota_usage(path, &quota, opts.storage_quota_epoch.as_deref());
+    }
+
+    // Write the run summary (best-effort)
+    let mut summary = RunSummary::new(&run_id, &node_id, output_name, started_at);
+    summary.preflight_problems = preflight
+        .problems()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    summary.drain_writer_notifications(&mut writer_notify_receiver);
+    summary.timeslots = timeslot_counter.map(|counter| counter.load(Ordering::Relaxed));
+    summary.batches_dropped = dropped_batches.map(|counter| counter.load(Ordering::Relaxed));
+    summary.transform_errors = transform_errors.load(Ordering::Relaxed);
+    summary.set_error_counts(&error_counts);
+    summary.noisy_neighbors = noisy_neighbors.map(|top| top.lock().unwrap().clone());
+    summary.trace_memory = report
+        .trace_memory
+        .map(|memory| TraceMemorySummary::from(memory.as_ref()));
+    summary.set_dispatcher_stats(report.dispatcher_stats);
+    summary.set_ring_stats(&report.ring_stats, &report.ring_index_semantics);
+    summary.degradation = report.adaptive.map(|controller| DegradationSummary {
+        level: controller.level(),
+        max_level: controller.max_level(),
+        transitions: controller.transitions(),
+    });
+    summary.finish(shutdown_token.reason());
+    match run_summary::write_run_summary(
+        store.as_ref(),
+        &summary_path,
+        &summary,
+        RUN_SUMMARY_TIMEOUT,
+    )
+    .await
+    {
+        Ok(()) => debug!("Wrote run summary to {}", summary_path),
+        Err(e) => error!("Failed to write run summary: {}", e),
+    }
+
+    // Hand the in-memory output to the test harness
+    if let Some(dir) = &opts.memory_dump_dir {
+        let files = memory_store::dump_store(store.as_ref(), dir).await?;
+        info!("Copied {} files from memory to {}", files, dir.display());
+    }
 
     info!("Shutdown complete");
     Ok(())
@@ -0,0 +1,75 @@
+//! In-memory storage, for running the whole collector binary in tests.
+//!
+//! `--storage-type memory` keeps every file the collector writes in an
+//! `object_store::memory::InMemory` store, so integration tests run the
+//! binary without touching the filesystem or S3. The store grows without
+//! bound and is lost at exit, so this mode is for testing only. To let the
+//! test harness read the output, `--memory-dump-dir` copies the files to a
+//! directory, under their object paths, once collection has finished.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use object_store::ObjectStore;
+
+/// Value of `--storage-type` selecting the in-memory store
+pub const MEMORY_STORAGE_TYPE: &str = "memory";
+
+/// Copy every object in `store` to a file under `dir`, named by its path,
+/// returning the number of files written
+pub async fn dump_store(store: &dyn ObjectStore, dir: &Path) -> Result<usize> {
+    let objects: Vec<_> = store
+        .list(None)
+        .try_collect()
+        .await
+        .context("Failed to list the in-memory store")?;
+    for meta in &objects {
+        let bytes = store
+            .get(&meta.location)
+            .await?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to read {}", meta.location))?;
+        let path = dir.join(meta.location.as_ref());
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        std::fs::write(&path, bytes)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(objects.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+    use object_store::path::Path as ObjectPath;
+
+    #[tokio::test]
+    async fn test_dump_store() {
+        let store = InMemory::new();
+        store
+            .put(&ObjectPath::from("metrics-node.parquet"), "rows".into())
+            .await
+            .unwrap();
+        store
+            .put(&ObjectPath::from("runs/node/summary.json"), "{}".into())
+            .await
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("memory_store_dump_{}", std::process::id()));
+        assert_eq!(dump_store(&store, &dir).await.unwrap(), 2);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("metrics-node.parquet")).unwrap(),
+            "rows"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("runs/node/summary.json")).unwrap(),
+            "{}"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
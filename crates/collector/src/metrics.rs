@@ -1,3 +1,10 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use collector_errors::ErrorCode;
+use serde::Serialize;
+
 /// Metrics structure to hold performance measurements collected from eBPF
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Metric {
@@ -40,3 +47,70 @@ impl Metric {
         }
     }
 }
+
+/// Memory held by a parquet writer for the file it is writing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct WriterMemory {
+    /// Encoded bytes of the row group in progress, as the writer reports them
+    pub encoder_in_progress: usize,
+    /// Arrow memory of accepted batches whose rows are not yet in a flushed
+    /// row group
+    pub accepted_unflushed: usize,
+    /// Compressed bytes of the row groups flushed to the file
+    pub flushed_compressed: usize,
+}
+
+impl WriterMemory {
+    /// Memory held for rows not yet flushed, compared against the buffer size
+    pub fn buffered(&self) -> usize {
+        self.encoder_in_progress + self.accepted_unflushed
+    }
+}
+
+/// Latest [`WriterMemory`] of a writer, readable from other tasks
+#[derive(Debug, Default)]
+pub struct WriterMemoryGauge {
+    encoder_in_progress: AtomicUsize,
+    accepted_unflushed: AtomicUsize,
+    flushed_compressed: AtomicUsize,
+}
+
+impl WriterMemoryGauge {
+    pub fn store(&self, memory: &WriterMemory) {
+        self.encoder_in_progress
+            .store(memory.encoder_in_progress, Ordering::Relaxed);
+        self.accepted_unflushed
+            .store(memory.accepted_unflushed, Ordering::Relaxed);
+        self.flushed_compressed
+            .store(memory.flushed_compressed, Ordering::Relaxed);
+    }
+
+    /// The last stored figures. Each is read separately, so they may come
+    /// from consecutive updates.
+    pub fn load(&self) -> WriterMemory {
+        WriterMemory {
+            encoder_in_progress: self.encoder_in_progress.load(Ordering::Relaxed),
+            accepted_unflushed: self.accepted_unflushed.load(Ordering::Relaxed),
+            flushed_compressed: self.flushed_compressed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Errors the collector carried on after, counted by their code. Errors that
+/// end the run are reported by the shutdown reason instead.
+#[derive(Debug, Default)]
+pub struct ErrorCounts {
+    counts: Mutex<BTreeMap<ErrorCode, u64>>,
+}
+
+impl ErrorCounts {
+    /// Count an error of class `code`
+    pub fn record(&self, code: ErrorCode) {
+        *self.counts.lock().unwrap().entry(code).or_default() += 1;
+    }
+
+    /// The counts so far, of the codes recorded at least once
+    pub fn snapshot(&self) -> BTreeMap<ErrorCode, u64> {
+        self.counts.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,289 @@
+//! Online noisy neighbor scores for containers.
+//!
+//! Each timeslot, every container gets a raw score from its share of the
+//! node's activity in that slot:
+//!
+//! ```text
+//! raw = w_llc * llc_share + w_cycles * cycles_share
+//!     + w_throttling * cycles_share * others_throttled_share
+//! ```
+//!
+//! where `llc_share` and `cycles_share` are the container's fraction of all
+//! LLC misses and cycles measured in the slot, and `others_throttled_share` is
+//! the fraction of the slot's CFS throttled time that fell on *other*
+//! containers. The throttling term is zero unless throttling is sampled.
+//!
+//! The score is an exponentially decayed average of the raw scores, with a
+//! configurable half-life in timeslots. Containers that stop running decay
+//! towards zero and are forgotten once their score is negligible.
+//!
+//! Limitations: shares are relative to the node, so a container alone on an
+//! idle node scores high without hurting anyone. Throttling is attributed to
+//! every container running in the slot in proportion to its cycles, whether
+//! or not it caused it. Tasks without metadata (kernel threads, tasks seen
+//! before their metadata) are not attributed to any container.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::timeslot_data::TimeslotData;
+
+/// Scores below this are dropped, bounding the number of tracked containers
+const MIN_TRACKED_SCORE: f64 = 1e-6;
+
+/// Weights of the terms in the raw score
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreWeights {
+    pub llc_misses: f64,
+    pub cycles: f64,
+    pub throttling: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            llc_misses: 0.5,
+            cycles: 0.3,
+            throttling: 0.2,
+        }
+    }
+}
+
+/// Configuration for [`NoisyNeighborScorer`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoisyNeighborConfig {
+    pub weights: ScoreWeights,
+    /// Timeslots after which a container's past raw scores count half
+    pub half_life_slots: f64,
+    /// Number of containers reported
+    pub top_k: usize,
+}
+
+impl Default for NoisyNeighborConfig {
+    fn default() -> Self {
+        Self {
+            weights: ScoreWeights::default(),
+            half_life_slots: 1000.0,
+            top_k: 10,
+        }
+    }
+}
+
+/// A container's current score
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContainerScore {
+    pub cgroup_id: u64,
+    pub score: f64,
+}
+
+/// Activity of a container within one timeslot
+#[derive(Default)]
+struct ContainerActivity {
+    llc_misses: u64,
+    cycles: u64,
+    throttled_usec: u64,
+}
+
+/// Maintains decayed noisy neighbor scores from the timeslot stream
+pub struct NoisyNeighborScorer {
+    config: NoisyNeighborConfig,
+    scores: HashMap<u64, f64>,
+    top: Arc<Mutex<Vec<ContainerScore>>>,
+}
+
+impl NoisyNeighborScorer {
+    /// Create a scorer with no history
+    pub fn new(config: NoisyNeighborConfig) -> Self {
+        Self {
+            config,
+            scores: HashMap::new(),
+            top: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Get a handle to the top scores, updated after every timeslot
+    pub fn top_scores(&self) -> Arc<Mutex<Vec<ContainerScore>>> {
+        self.top.clone()
+    }
+
+    /// Update the scores with a timeslot
+    pub fn observe(&mut self, timeslot: &TimeslotData) {
+        let mut activity: HashMap<u64, ContainerActivity> = HashMap::new();
+        for task in timeslot.tasks.values() {
+            let Some(ref metadata) = task.metadata else {
+                continue;
+            };
+            let container = activity.entry(metadata.cgroup_id).or_default();
+            container.llc_misses += task.metrics.llc_misses;
+            container.cycles += task.metrics.cycles;
+        }
+        for (cgroup_id, stat) in &timeslot.throttling {
+            if let Some(container) = activity.get_mut(cgroup_id) {
+                container.throttled_usec = stat.throttled_usec;
+            }
+        }
+
+        let total_llc: u64 = activity.values().map(|c| c.llc_misses).sum();
+        let total_cycles: u64 = activity.values().map(|c| c.cycles).sum();
+        let total_throttled: u64 = activity.values().map(|c| c.throttled_usec).sum();
+        let share = |part: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                part as f64 / total as f64
+            }
+        };
+
+        // Merged timeslots decay as much as the slots they stand for
+        let decay = 0.5f64.powf(timeslot.slots_merged as f64 / self.config.half_life_slots);
+        for score in self.scores.values_mut() {
+            *score *= decay;
+        }
+
+        let weights = self.config.weights;
+        for (cgroup_id, container) in &activity {
+            let cycles_share = share(container.cycles, total_cycles);
+            let others_throttled =
+                share(total_throttled - container.throttled_usec, total_throttled);
+            let raw = weights.llc_misses * share(container.llc_misses, total_llc)
+                + weights.cycles * cycles_share
+                + weights.throttling * cycles_share * others_throttled;
+            *self.scores.entry(*cgroup_id).or_insert(0.0) += (1.0 - decay) * raw;
+        }
+        self.scores.retain(|_, score| *score >= MIN_TRACKED_SCORE);
+
+        *self.top.lock().unwrap() = self.top_k();
+    }
+
+    /// The highest scoring containers, best first, at most `top_k` of them
+    pub fn top_k(&self) -> Vec<ContainerScore> {
+        let mut scores: Vec<ContainerScore> = self
+            .scores
+            .iter()
+            .map(|(&cgroup_id, &score)| ContainerScore { cgroup_id, score })
+            .collect();
+        scores.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then(a.cgroup_id.cmp(&b.cgroup_id))
+        });
+        scores.truncate(self.config.top_k);
+        scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu_throttle::CpuStat;
+    use crate::metrics::Metric;
+    use crate::task_metadata::TaskMetadata;
+
+    /// A timeslot with one task per (cgroup id, llc misses, cycles)
+    fn timeslot(containers: &[(u64, u64, u64)]) -> TimeslotData {
+        let mut timeslot = TimeslotData::new(0);
+        for (i, &(cgroup_id, llc_misses, cycles)) in containers.iter().enumerate() {
+            let pid = i as u32 + 1;
+            timeslot.update(
+                pid,
+                Some(TaskMetadata::new(pid, [0; 16], cgroup_id)),
+                Metric::from_deltas(cycles, 0, llc_misses, 0, 1_000_000),
+            );
+        }
+        timeslot
+    }
+
+    fn score(scorer: &NoisyNeighborScorer, cgroup_id: u64) -> f64 {
+        scorer.scores.get(&cgroup_id).copied().unwrap_or(0.0)
+    }
+
+    #[test]
+    fn test_score_ordering() {
+        let mut scorer = NoisyNeighborScorer::new(NoisyNeighborConfig {
+            half_life_slots: 10.0,
+            ..Default::default()
+        });
+        for _ in 0..20 {
+            // Container 1 thrashes the cache, 2 is busy, 3 is nearly idle
+            scorer.observe(&timeslot(&[
+                (1, 9000, 3000),
+                (2, 900, 6000),
+                (3, 100, 1000),
+            ]));
+        }
+
+        let top: Vec<u64> = scorer.top_k().iter().map(|s| s.cgroup_id).collect();
+        assert_eq!(top, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_throttling_of_others() {
+        let mut scorer = NoisyNeighborScorer::new(NoisyNeighborConfig::default());
+
+        // Same activity, but container 2 is throttled while 1 runs
+        let mut slot = timeslot(&[(1, 500, 5000), (2, 500, 5000)]);
+        slot.throttling.insert(
+            2,
+            CpuStat {
+                nr_throttled: 1,
+                throttled_usec: 800,
+            },
+        );
+        scorer.observe(&slot);
+
+        assert!(score(&scorer, 1) > score(&scorer, 2));
+    }
+
+    #[test]
+    fn test_decay_over_quiet_slots() {
+        let mut scorer = NoisyNeighborScorer::new(NoisyNeighborConfig {
+            half_life_slots: 4.0,
+            ..Default::default()
+        });
+        for _ in 0..8 {
+            scorer.observe(&timeslot(&[(1, 1000, 1000), (2, 1000, 1000)]));
+        }
+        let busy = score(&scorer, 1);
+
+        // Container 1 goes quiet; its score halves every half-life
+        for _ in 0..4 {
+            scorer.observe(&timeslot(&[(2, 1000, 1000)]));
+        }
+        assert!((score(&scorer, 1) - busy / 2.0).abs() < 1e-9);
+
+        // A merged timeslot decays as much as the slots it stands for
+        let before = score(&scorer, 1);
+        let mut merged = timeslot(&[(2, 1000, 1000)]);
+        merged.slots_merged = 4;
+        scorer.observe(&merged);
+        assert!((score(&scorer, 1) - before / 2.0).abs() < 1e-9);
+
+        // Eventually it is forgotten
+        for _ in 0..200 {
+            scorer.observe(&timeslot(&[(2, 1000, 1000)]));
+        }
+        assert_eq!(scorer.scores.len(), 1);
+    }
+
+    #[test]
+    fn test_top_k_bound() {
+        let mut scorer = NoisyNeighborScorer::new(NoisyNeighborConfig {
+            half_life_slots: 1.0,
+            top_k: 5,
+            ..Default::default()
+        });
+        let containers: Vec<(u64, u64, u64)> = (1..=50).map(|id| (id, id * 10, id * 100)).collect();
+        scorer.observe(&timeslot(&containers));
+
+        let top = scorer.top_scores();
+        let top = top.lock().unwrap();
+        assert_eq!(top.len(), 5);
+        assert_eq!(
+            top.iter().map(|s| s.cgroup_id).collect::<Vec<_>>(),
+            vec![50, 49, 48, 47, 46]
+        );
+        assert_eq!(scorer.scores.len(), 50);
+    }
+}
@@ -0,0 +1,275 @@
+//! What the collector does when the container runtime shuts the NRI plugin
+//! down.
+//!
+//! The runtime calls the plugin's Shutdown RPC when it is stopping or NRI is
+//! disabled. Container metadata stops updating from then on, so new
+//! containers are not collected and stopped ones are not released. The
+//! plugin signals a [`ShutdownNotice`] as the RPC arrives, and the
+//! [`NriShutdownHandler`] responds according to `--on-nri-shutdown`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::{info, warn};
+use nri::metadata::ShutdownNotice;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::shutdown::{ShutdownReason, ShutdownToken};
+
+/// How the collector responds to the runtime shutting the NRI plugin down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NriShutdownPolicy {
+    /// Log it and carry on; the plugin reconnects if the connection closes
+    #[default]
+    Continue,
+    /// Stop following the runtime, keep the last known container metadata,
+    /// and set the attribution_stale column on rows from then on
+    FreezeAttribution,
+    /// Close the connection and reconnect, synchronizing the metadata again
+    Reconnect,
+    /// Stop the collector gracefully
+    Exit,
+}
+
+/// Applies the [`NriShutdownPolicy`] to the shutdown notices of the plugin
+pub struct NriShutdownHandler {
+    policy: NriShutdownPolicy,
+    notices: mpsc::UnboundedReceiver<ShutdownNotice>,
+    attribution_stale: Arc<AtomicBool>,
+    nri_task: Option<AbortHandle>,
+    reconnect: Option<Box<dyn Fn() + Send>>,
+    shutdowns: usize,
+}
+
+impl NriShutdownHandler {
+    /// Create a handler applying `policy` to the notices received on `notices`
+    pub fn new(
+        policy: NriShutdownPolicy,
+        notices: mpsc::UnboundedReceiver<ShutdownNotice>,
+    ) -> Self {
+        Self {
+            policy,
+            notices,
+            attribution_stale: Arc::new(AtomicBool::new(false)),
+            nri_task: None,
+            reconnect: None,
+            shutdowns: 0,
+        }
+    }
+
+    /// The task keeping the plugin connected, stopped when attribution freezes
+    pub fn set_nri_task(&mut self, nri_task: AbortHandle) {
+        self.nri_task = Some(nri_task);
+    }
+
+    /// How to make the plugin reconnect, e.g. `Subscription::reconnect`
+    pub fn set_reconnect(&mut self, reconnect: impl Fn() + Send + 'static) {
+        self.reconnect = Some(Box::new(reconnect));
+    }
+
+    /// Get a handle to the flag set once attribution is frozen
+    pub fn attribution_stale(&self) -> Arc<AtomicBool> {
+        self.attribution_stale.clone()
+    }
+
+    /// Respond to notices until shutdown, or until the policy stops the
+    /// collector
+    pub async fn run(mut self, shutdown_token: ShutdownToken) -> Result<()> {
+        loop {
+            tokio::select! {
+                notice = self.notices.recv() => match notice {
+                    Some(notice) => self.handle_notice(notice, &shutdown_token),
+                    // The plugin is gone along with its notifier, so nothing
+                    // more can arrive
+                    None => {
+                        shutdown_token.cancelled().await;
+                        return Ok(());
+                    }
+                },
+                _ = shutdown_token.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    fn handle_notice(&mut self, notice: ShutdownNotice, shutdown_token: &ShutdownToken) {
+        self.shutdowns += 1;
+        match self.policy {
+            NriShutdownPolicy::Continue => {
+                info!(
+                    "The container runtime shut the NRI plugin down ({} times so far), continuing",
+                    self.shutdowns
+                );
+            }
+            NriShutdownPolicy::FreezeAttribution => {
+                if !self.attribution_stale.swap(true, Ordering::Relaxed) {
+                    warn!("The container runtime shut the NRI plugin down, freezing container attribution");
+                    if let Some(nri_task) = self.nri_task.take() {
+                        nri_task.abort();
+                    }
+                }
+            }
+            NriShutdownPolicy::Reconnect => {
+                warn!("The container runtime shut the NRI plugin down, reconnecting");
+                if let Some(reconnect) = &self.reconnect {
+                    reconnect();
+                }
+            }
+            NriShutdownPolicy::Exit => {
+                warn!("The container runtime shut the NRI plugin down, stopping the collector");
+                shutdown_token.cancel(ShutdownReason::NriShutdown {
+                    received_at: notice
+                        .received_at
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|elapsed| elapsed.as_secs())
+                        .unwrap_or_default(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    use nri::api::Empty;
+    use nri::api_ttrpc::PluginClient;
+    use nri::metadata::MetadataMessage;
+    use nri::multiplex::{Mux, PLUGIN_SERVICE_CONN};
+    use nri::NRI;
+    use tokio::task::JoinHandle;
+    use ttrpc::context::Context;
+    use ttrpc::r#async::transport::Socket;
+
+    /// A handler for `policy`, notified by a metadata plugin that a mock
+    /// runtime shuts down, whose plugin server and reconnections are
+    /// observable
+    struct Harness {
+        runtime: PluginClient,
+        _runtime_mux: Mux,
+        _nri: NRI,
+        _metadata: mpsc::Receiver<MetadataMessage>,
+        server: JoinHandle<Result<()>>,
+        stale: Arc<AtomicBool>,
+        reconnects: Arc<AtomicUsize>,
+        shutdown_token: ShutdownToken,
+        handler: JoinHandle<Result<()>>,
+    }
+
+    impl Harness {
+        async fn start(policy: NriShutdownPolicy) -> Self {
+            let (notices, notice_rx) = mpsc::unbounded_channel();
+            let (plugin, metadata) = NRI::builder("collector", "10")
+                .shutdown_notifier(notices)
+                .metadata_plugin();
+            let (runtime_stream, plugin_stream) = tokio::io::duplex(1024);
+            let (nri, server) = NRI::new(plugin_stream, plugin, "collector", "10")
+                .await
+                .unwrap();
+
+            // The runtime's end of the plugin service
+            let runtime_mux = Mux::new(runtime_stream);
+            let plugin_socket = runtime_mux.open(PLUGIN_SERVICE_CONN).await.unwrap();
+            let runtime =
+                PluginClient::new(ttrpc::r#async::Client::new(Socket::new(plugin_socket)));
+
+            let mut handler = NriShutdownHandler::new(policy, notice_rx);
+            handler.set_nri_task(server.abort_handle());
+            let reconnects = Arc::new(AtomicUsize::new(0));
+            let counter = reconnects.clone();
+            handler.set_reconnect(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+            let stale = handler.attribution_stale();
+            let shutdown_token = ShutdownToken::new();
+            let handler = tokio::spawn(handler.run(shutdown_token.clone()));
+            Self {
+                runtime,
+                _runtime_mux: runtime_mux,
+                _nri: nri,
+                _metadata: metadata,
+                server,
+                stale,
+                reconnects,
+                shutdown_token,
+                handler,
+            }
+        }
+
+        /// Call the plugin's Shutdown RPC, as the runtime does when stopping
+        async fn shutdown_rpc(&self) {
+            self.runtime
+                .shutdown(Context::default(), &Empty::default())
+                .await
+                .unwrap();
+            // Let the handler act on the notice
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        /// Stop the collector, returning whether the plugin server was still
+        /// running
+        async fn finish(self) -> bool {
+            let running = !self.server.is_finished();
+            self.shutdown_token.cancel(ShutdownReason::Duration);
+            self.handler.await.unwrap().unwrap();
+            self.server.abort();
+            running
+        }
+    }
+
+    #[tokio::test]
+    async fn test_continue() {
+        let harness = Harness::start(NriShutdownPolicy::default()).await;
+        harness.shutdown_rpc().await;
+        harness.shutdown_rpc().await;
+        assert!(!harness.stale.load(Ordering::Relaxed));
+        assert_eq!(harness.reconnects.load(Ordering::Relaxed), 0);
+        assert!(!harness.shutdown_token.is_cancelled());
+        assert!(harness.finish().await);
+    }
+
+    #[tokio::test]
+    async fn test_freeze_attribution() {
+        let harness = Harness::start(NriShutdownPolicy::FreezeAttribution).await;
+        assert!(!harness.stale.load(Ordering::Relaxed));
+        harness.shutdown_rpc().await;
+        assert!(harness.stale.load(Ordering::Relaxed));
+        assert_eq!(harness.reconnects.load(Ordering::Relaxed), 0);
+        assert!(!harness.shutdown_token.is_cancelled());
+        // The plugin stops following the runtime
+        assert!(!harness.finish().await);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let harness = Harness::start(NriShutdownPolicy::Reconnect).await;
+        harness.shutdown_rpc().await;
+        harness.shutdown_rpc().await;
+        assert_eq!(harness.reconnects.load(Ordering::Relaxed), 2);
+        assert!(!harness.stale.load(Ordering::Relaxed));
+        assert!(!harness.shutdown_token.is_cancelled());
+        assert!(harness.finish().await);
+    }
+
+    #[tokio::test]
+    async fn test_exit() {
+        let before = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let harness = Harness::start(NriShutdownPolicy::Exit).await;
+        harness.shutdown_rpc().await;
+        assert!(harness.shutdown_token.is_cancelled());
+        assert!(matches!(
+            harness.shutdown_token.reason(),
+            Some(ShutdownReason::NriShutdown { received_at }) if received_at >= before
+        ));
+        assert!(!harness.stale.load(Ordering::Relaxed));
+        assert!(harness.finish().await);
+    }
+}
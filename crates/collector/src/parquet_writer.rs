@@ -1,25 +1,38 @@
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::Instant;
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Context, Result};
+use arrow_array::cast::AsArray;
+use arrow_array::types::Int64Type;
 use arrow_array::RecordBatch;
-use arrow_schema::SchemaRef;
-use chrono::Utc;
-use log::{debug, info};
-use object_store::{path::Path, ObjectStore};
+use arrow_schema::{DataType, SchemaRef};
+use chrono::DateTime;
+use collector_errors::{Coded, ErrorCode};
+use log::{debug, info, warn};
+use object_store::{buffered::BufWriter, path::Path, ObjectStore};
 use parquet::arrow::arrow_writer::ArrowWriterOptions;
 use parquet::arrow::async_writer::{AsyncArrowWriter, ParquetObjectWriter};
 use parquet::basic::Compression;
 use parquet::file::metadata::KeyValue;
-use parquet::file::properties::WriterProperties;
+use parquet::file::properties::{WriterProperties, WriterVersion, DEFAULT_WRITER_VERSION};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
+use crate::disk_guard::DiskGuard;
+use crate::metrics::{ErrorCounts, WriterMemory, WriterMemoryGauge};
+use crate::storage_quota::QuotaHandle;
+use crate::wall_clock::{ClockStep, WallClock};
+
 /// Configuration for the parquet writer
 pub struct ParquetWriterConfig {
     /// Path prefix to use within the storage location
     /// This will be directly prepended to filenames without adding separators
     /// Include any needed separators (like "/" or "-") at the end if desired
     pub storage_prefix: String,
-    /// Maximum buffer size before flushing to storage (bytes)
+    /// Maximum memory held for unflushed rows, encoded and as Arrow batches,
+    /// before flushing a row group (bytes)
     pub buffer_size: usize,
     /// Maximum file size before rotation (bytes)
     pub file_size_limit: usize,
@@ -27,8 +40,28 @@ pub struct ParquetWriterConfig {
     pub max_row_group_size: usize,
     /// Optional total storage quota (bytes)
     pub storage_quota: Option<usize>,
+    /// Stream of a shared quota manager the writer counts its bytes
+    /// against, in place of a quota of its own of `storage_quota`
+    pub quota: Option<QuotaHandle>,
     /// Optional key-value metadata to include in parquet files
     pub key_value_metadata: Option<Vec<KeyValue>>,
+    /// Parquet format version to write. Version 2.0 allows newer encodings
+    /// and data page v2, but some readers only support 1.0
+    pub writer_version: WriterVersion,
+    /// Int64 column whose range is written as `min_timestamp` and
+    /// `max_timestamp` metadata when each file is closed. Files without the
+    /// column get no range.
+    pub timestamp_column: String,
+    /// Size of each part of a multipart upload (bytes). Files smaller than
+    /// this are uploaded in a single request
+    pub multipart_part_size: usize,
+    /// Maximum number of parts of a file uploaded concurrently
+    pub multipart_max_concurrency: usize,
+    /// Close rotated files on a background task, so the next file accepts
+    /// batches while the previous upload completes. Until the close
+    /// completes, the previous file's size is counted against the quota
+    /// as estimated when it was rotated.
+    pub background_close: bool,
 }
 
 impl Default for ParquetWriterConfig {
@@ -39,11 +72,207 @@ impl Default for ParquetWriterConfig {
             file_size_limit: 1024 * 1024 * 1024, // 1GB
             max_row_group_size: 1024 * 1024,     // Default max row group size
             storage_quota: None,
+            quota: None,
             key_value_metadata: None,
+            writer_version: DEFAULT_WRITER_VERSION,
+            timestamp_column: "timestamp".to_string(),
+            multipart_part_size: 10 * 1024 * 1024, // 10MB
+            multipart_max_concurrency: 8,
+            background_close: false,
+        }
+    }
+}
+
+impl ParquetWriterConfig {
+    /// Start building a config from the defaults
+    pub fn builder() -> ParquetWriterConfigBuilder {
+        ParquetWriterConfigBuilder::default()
+    }
+}
+
+/// Builder for [`ParquetWriterConfig`] that checks the settings are
+/// consistent before creating the config
+#[derive(Default)]
+pub struct ParquetWriterConfigBuilder {
+    config: ParquetWriterConfig,
+}
+
+impl ParquetWriterConfigBuilder {
+    /// Set the path prefix prepended to filenames
+    pub fn storage_prefix(mut self, storage_prefix: impl Into<String>) -> Self {
+        self.config.storage_prefix = storage_prefix.into();
+        self
+    }
+
+    /// Set the buffer size before flushing to storage (bytes)
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.config.buffer_size = buffer_size;
+        self
+    }
+
+    /// Set the file size before rotation (bytes)
+    pub fn file_size_limit(mut self, file_size_limit: usize) -> Self {
+        self.config.file_size_limit = file_size_limit;
+        self
+    }
+
+    /// Set the maximum row group size (number of rows)
+    pub fn max_row_group_size(mut self, max_row_group_size: usize) -> Self {
+        self.config.max_row_group_size = max_row_group_size;
+        self
+    }
+
+    /// Set the total storage quota (bytes), or None for no quota
+    pub fn storage_quota(mut self, storage_quota: Option<usize>) -> Self {
+        self.config.storage_quota = storage_quota;
+        self
+    }
+
+    /// Count the writer's bytes against a stream of a shared quota manager,
+    /// or None for a quota of its own
+    pub fn quota(mut self, quota: Option<QuotaHandle>) -> Self {
+        self.config.quota = quota;
+        self
+    }
+
+    /// Set the key-value metadata included in parquet files
+    pub fn key_value_metadata(mut self, key_value_metadata: Vec<KeyValue>) -> Self {
+        self.config.key_value_metadata = Some(key_value_metadata);
+        self
+    }
+
+    /// Set the parquet format version to write
+    pub fn writer_version(mut self, writer_version: WriterVersion) -> Self {
+        self.config.writer_version = writer_version;
+        self
+    }
+
+    /// Set the Int64 column whose range is written as file metadata
+    pub fn timestamp_column(mut self, timestamp_column: impl Into<String>) -> Self {
+        self.config.timestamp_column = timestamp_column.into();
+        self
+    }
+
+    /// Set the size of each part of a multipart upload (bytes)
+    pub fn multipart_part_size(mut self, multipart_part_size: usize) -> Self {
+        self.config.multipart_part_size = multipart_part_size;
+        self
+    }
+
+    /// Set the number of parts of a file uploaded concurrently
+    pub fn multipart_max_concurrency(mut self, multipart_max_concurrency: usize) -> Self {
+        self.config.multipart_max_concurrency = multipart_max_concurrency;
+        self
+    }
+
+    /// Set whether rotated files are closed on a background task
+    pub fn background_close(mut self, background_close: bool) -> Self {
+        self.config.background_close = background_close;
+        self
+    }
+
+    /// Check the settings and create the config
+    ///
+    /// Fails if a size or count is zero, or if the buffer is larger than
+    /// the file size limit, since files would then only rotate on flush.
+    pub fn build(self) -> Result<ParquetWriterConfig> {
+        let config = self.config;
+        for (name, value) in [
+            ("buffer_size", config.buffer_size),
+            ("file_size_limit", config.file_size_limit),
+            ("max_row_group_size", config.max_row_group_size),
+            ("multipart_part_size", config.multipart_part_size),
+            (
+                "multipart_max_concurrency",
+                config.multipart_max_concurrency,
+            ),
+        ] {
+            if value == 0 {
+                bail!("{} must be greater than zero", name);
+            }
         }
+        if config.buffer_size > config.file_size_limit {
+            bail!(
+                "buffer_size ({}) must not exceed file_size_limit ({})",
+                config.buffer_size,
+                config.file_size_limit
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// Parse a parquet format version given as "1.0" or "2.0"
+///
+/// The library's names ("PARQUET_1_0", "PARQUET_2_0") are accepted too.
+pub fn parse_writer_version(version: &str) -> Result<WriterVersion, String> {
+    match version {
+        "1.0" | "1" => Ok(WriterVersion::PARQUET_1_0),
+        "2.0" | "2" => Ok(WriterVersion::PARQUET_2_0),
+        other => other.parse().map_err(|_| {
+            format!(
+                "invalid parquet writer version '{}', expected 1.0 or 2.0",
+                other
+            )
+        }),
     }
 }
 
+/// Events reported by the writer to an optional notification channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriterNotification {
+    /// A parquet file was finished and uploaded
+    FileClosed {
+        path: String,
+        rows: usize,
+        bytes: usize,
+    },
+    /// The storage quota was reached and further writes are dropped
+    QuotaReached,
+    /// Free space on the local filesystem fell below the disk guard's
+    /// threshold, and writes are dropped until it recovers
+    DiskSpaceLow { available_bytes: u64 },
+    /// Free space recovered and writes resumed
+    DiskSpaceRecovered,
+    /// A file written by the collector was deleted to reclaim space
+    FileReclaimed { path: String, bytes: u64 },
+    /// The realtime clock was stepped by `delta_ns`, negative when
+    /// backwards. Files switch to the new clock from the next rotation.
+    ClockStep { delta_ns: i64 },
+}
+
+/// Bytes produced by a writer, as counted against the storage quota
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeStats {
+    /// Bytes in files that were closed
+    pub closed_files_size: usize,
+    /// Bytes in row groups already flushed to the current file
+    pub flushed_row_groups_size: usize,
+    /// Bytes buffered in memory for the current file
+    pub in_memory_size: usize,
+    /// Bytes reserved for a file still being closed in the background
+    pub closing_file_size: usize,
+    /// Sum of the above, the value compared against the storage quota
+    pub total_size: usize,
+    /// Path of the file being written, if any
+    pub current_file_path: Option<String>,
+}
+
+/// A parquet file whose writer was finished
+struct ClosedFile {
+    path: Option<Path>,
+    row_groups: usize,
+    rows: usize,
+    bytes: usize,
+}
+
+/// A parquet file being closed on a background task
+struct PendingClose {
+    handle: JoinHandle<Result<ClosedFile>>,
+    /// Bytes counted against the quota until the close completes
+    reserved_size: usize,
+}
+
 /// Handles writing record batches to parquet files in object storage
 pub struct ParquetWriter {
     store: Arc<dyn ObjectStore>,
@@ -51,13 +280,42 @@ pub struct ParquetWriter {
     current_writer: Option<AsyncArrowWriter<ParquetObjectWriter>>,
     current_file_path: Option<Path>,
 
-    // Size tracking
-    closed_files_size: usize,
+    // Size tracking, closed files counted by the quota
+    quota: QuotaHandle,
     flushed_row_groups_size: usize,
     flushed_row_groups_count: usize,
     in_memory_size: usize,
 
+    // Rows and Arrow memory of each batch accepted by the current writer
+    // whose rows are not all in flushed row groups, oldest first
+    unflushed_batches: VecDeque<(usize, usize)>,
+    memory_gauge: Arc<WriterMemoryGauge>,
+
+    // Range of timestamps written to the current file
+    timestamp_range: Option<(i64, i64)>,
+
+    // Previous file, if it is still being closed in the background
+    pending_close: Option<PendingClose>,
+
     config: ParquetWriterConfig,
+
+    // Optional channel for file and quota notifications
+    notifier: Option<mpsc::UnboundedSender<WriterNotification>>,
+
+    // Optional free space guard for local storage
+    disk_guard: Option<DiskGuard>,
+
+    // Optional counts of the errors the writer carried on after
+    error_counts: Option<Arc<ErrorCounts>>,
+
+    // Wall clock naming files, switched to a stepped clock on rotation
+    wall_clock: WallClock,
+    // Sequence number of the next file, so names never collide
+    file_sequence: u64,
+    // Seconds in the name of the previous file, which later names never precede
+    last_file_secs: i64,
+    // Clock steps seen while writing the current file
+    clock_steps: Vec<ClockStep>,
 }
 
 impl ParquetWriter {
@@ -67,16 +325,40 @@ impl ParquetWriter {
         schema: SchemaRef,
         config: ParquetWriterConfig,
     ) -> Result<Self> {
+        Self::with_wall_clock(store, schema, config, WallClock::system())
+    }
+
+    /// Creates a new ParquetWriter naming its files from `wall_clock`
+    pub fn with_wall_clock(
+        store: Arc<dyn ObjectStore>,
+        schema: SchemaRef,
+        config: ParquetWriterConfig,
+        wall_clock: WallClock,
+    ) -> Result<Self> {
+        let quota = config.quota.clone().unwrap_or_else(|| {
+            QuotaHandle::standalone(config.storage_prefix.clone(), config.storage_quota)
+        });
         let mut writer = Self {
             store,
             schema,
             current_writer: None,
             current_file_path: None,
-            closed_files_size: 0,
+            quota,
             flushed_row_groups_size: 0,
             flushed_row_groups_count: 0,
             in_memory_size: 0,
+            unflushed_batches: VecDeque::new(),
+            memory_gauge: Arc::new(WriterMemoryGauge::default()),
+            timestamp_range: None,
+            pending_close: None,
             config,
+            notifier: None,
+            disk_guard: None,
+            error_counts: None,
+            wall_clock,
+            file_sequence: 0,
+            last_file_secs: i64::MIN,
+            clock_steps: Vec::new(),
         };
 
         // Create initial file
@@ -85,9 +367,65 @@ impl ParquetWriter {
         Ok(writer)
     }
 
-    /// Generate a new file path with timestamp and UUID
-    fn generate_file_path(&self) -> Path {
-        let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    /// Send notifications about closed files and quota state to the given channel
+    pub fn set_notifier(&mut self, notifier: mpsc::UnboundedSender<WriterNotification>) {
+        self.notifier = Some(notifier);
+    }
+
+    /// Count the errors the writer carries on after, such as failing to
+    /// read free space, in `counts`
+    pub fn set_error_counts(&mut self, counts: Arc<ErrorCounts>) {
+        self.error_counts = Some(counts);
+    }
+
+    /// Count an error the writer carried on after
+    fn record_error(&self, code: ErrorCode) {
+        if let Some(counts) = &self.error_counts {
+            counts.record(code);
+        }
+    }
+
+    /// Stop creating files while the guard reports low free space. Takes
+    /// effect from the next write.
+    pub fn set_disk_guard(&mut self, guard: DiskGuard) {
+        self.disk_guard = Some(guard);
+    }
+
+    /// Note a step of the realtime clock in the current file's metadata
+    fn record_clock_step(&mut self, step: ClockStep) {
+        warn!(
+            "Realtime clock stepped by {}ms, file names follow it from the next file",
+            step.delta_ns / 1_000_000
+        );
+        self.clock_steps.push(step);
+        self.notify(WriterNotification::ClockStep {
+            delta_ns: step.delta_ns,
+        });
+    }
+
+    /// Send a notification if a channel is configured. A closed channel is ignored.
+    fn notify(&self, notification: WriterNotification) {
+        if let Some(notifier) = &self.notifier {
+            let _ = notifier.send(notification);
+        }
+    }
+
+    /// Generate a new file path with timestamp, sequence number and UUID.
+    /// The timestamp never precedes the previous file's, even when the clock
+    /// was stepped backwards, so names sort in the order files were created.
+    fn generate_file_path(&mut self) -> Path {
+        let secs = self
+            .wall_clock
+            .now_ns()
+            .div_euclid(1_000_000_000)
+            .max(self.last_file_secs);
+        self.last_file_secs = secs;
+        let timestamp = DateTime::from_timestamp(secs, 0)
+            .unwrap_or_default()
+            .format("%Y%m%dT%H%M%SZ")
+            .to_string();
+        let sequence = self.file_sequence;
+        self.file_sequence += 1;
         let uuid = Uuid::new_v4()
             .to_string()
             .chars()
@@ -96,8 +434,8 @@ impl ParquetWriter {
 
         // Include the prefix from config directly in the filename
         let filename = format!(
-            "{}{}-{}.parquet",
-            self.config.storage_prefix, timestamp, uuid
+            "{}{}-{:06}-{}.parquet",
+            self.config.storage_prefix, timestamp, sequence, uuid
         );
 
         Path::from(filename)
@@ -108,9 +446,11 @@ impl ParquetWriter {
         // Close the current writer if it exists
         if self.current_writer.is_some() {
             // error if we try to create a new file while there is an open writer
-            return Err(anyhow!(
-                "Cannot create new file while there is an open writer"
-            ));
+            return Err(Coded::new(
+                ErrorCode::Internal,
+                "Cannot create new file while there is an open writer",
+            )
+            .into());
         }
 
         // Check quota before creating a new file
@@ -119,6 +459,17 @@ impl ParquetWriter {
             return Ok(());
         }
 
+        // Check free space before creating a new file
+        if !self.check_disk_space() {
+            debug!("Not creating new file: disk space low");
+            return Ok(());
+        }
+
+        // Follow a stepped clock from the new file on
+        if let Some(step) = self.wall_clock.rotate() {
+            self.record_clock_step(step);
+        }
+
         // Generate new file path
         let path = self.generate_file_path();
 
@@ -127,17 +478,29 @@ impl ParquetWriter {
             .set_compression(Compression::SNAPPY)
             .set_max_row_group_size(self.config.max_row_group_size)
             .set_key_value_metadata(self.config.key_value_metadata.clone())
+            .set_writer_version(self.config.writer_version)
             .build();
 
-        let object_writer = ParquetObjectWriter::new(self.store.clone(), path.clone());
+        let buf_writer = BufWriter::with_capacity(
+            self.store.clone(),
+            path.clone(),
+            self.config.multipart_part_size,
+        )
+        .with_max_concurrency(self.config.multipart_max_concurrency);
+        let object_writer = ParquetObjectWriter::from_buf_writer(buf_writer);
 
         let options = ArrowWriterOptions::new().with_properties(props);
         let writer =
-            AsyncArrowWriter::try_new_with_options(object_writer, self.schema.clone(), options)?;
+            AsyncArrowWriter::try_new_with_options(object_writer, self.schema.clone(), options)
+                .context(Coded::new(
+                    ErrorCode::StorageWrite,
+                    "Failed to create parquet writer",
+                ))?;
 
         // Store the writer and path
         self.current_writer = Some(writer);
         self.current_file_path = Some(path.clone());
+        self.timestamp_range = None;
 
         debug!("Created new parquet writer for path: {}", path);
 
@@ -147,16 +510,155 @@ impl ParquetWriter {
         Ok(())
     }
 
-    /// Checks if we've exceeded our storage quota
+    /// Bytes reserved for the file being closed in the background, if any
+    fn closing_file_size(&self) -> usize {
+        self.pending_close
+            .as_ref()
+            .map_or(0, |pending| pending.reserved_size)
+    }
+
+    /// Bytes written or buffered in files not closed yet
+    fn pending_size(&self) -> usize {
+        self.closing_file_size() + self.flushed_row_groups_size + self.in_memory_size
+    }
+
+    /// Total bytes written or buffered across all files
+    fn total_size(&self) -> usize {
+        self.quota.closed_size() + self.pending_size()
+    }
+
+    /// Report the bytes written so far and the file currently being written
+    pub fn size_stats(&self) -> SizeStats {
+        SizeStats {
+            closed_files_size: self.quota.closed_size(),
+            flushed_row_groups_size: self.flushed_row_groups_size,
+            in_memory_size: self.in_memory_size,
+            closing_file_size: self.closing_file_size(),
+            total_size: self.total_size(),
+            current_file_path: self
+                .current_writer
+                .as_ref()
+                .and(self.current_file_path.as_ref())
+                .map(|p| p.to_string()),
+        }
+    }
+
+    /// Report the memory held for the current file.
+    ///
+    /// The encoder's in-progress size alone understates memory use: the
+    /// Arrow batches behind the rows it has not flushed are counted here
+    /// too, by their `get_array_memory_size()`. This remains an estimate.
+    /// Batches still queued in the channel to the writer are not counted,
+    /// arrays sharing buffers are counted in full for each batch, and the
+    /// memory of a batch whose rows were partly flushed is split in
+    /// proportion to its rows.
+    pub fn memory_breakdown(&self) -> WriterMemory {
+        WriterMemory {
+            encoder_in_progress: self.in_memory_size,
+            accepted_unflushed: self.unflushed_batches.iter().map(|(_, bytes)| bytes).sum(),
+            flushed_compressed: self.flushed_row_groups_size,
+        }
+    }
+
+    /// A handle to the writer's latest memory breakdown, updated after every
+    /// write and flush
+    pub fn memory_gauge(&self) -> Arc<WriterMemoryGauge> {
+        self.memory_gauge.clone()
+    }
+
+    /// Forget the batches whose rows were all flushed, leaving those behind
+    /// the writer's `in_progress_rows` unflushed rows
+    fn release_flushed_batches(&mut self, in_progress_rows: usize) {
+        let accepted_rows: usize = self.unflushed_batches.iter().map(|(rows, _)| rows).sum();
+        let mut flushed_rows = accepted_rows.saturating_sub(in_progress_rows);
+        while flushed_rows > 0 {
+            let Some((rows, bytes)) = self.unflushed_batches.front_mut() else {
+                break;
+            };
+            if *rows <= flushed_rows {
+                flushed_rows -= *rows;
+                self.unflushed_batches.pop_front();
+            } else {
+                *bytes -= *bytes * flushed_rows / *rows;
+                *rows -= flushed_rows;
+                flushed_rows = 0;
+            }
+        }
+    }
+
+    /// Checks if the quota still allows writing
     fn is_below_quota(&self) -> bool {
-        if let Some(quota) = self.config.storage_quota {
-            let total_size =
-                self.closed_files_size + self.flushed_row_groups_size + self.in_memory_size;
-            if total_size >= quota {
-                return false;
+        self.quota.allowance(self.pending_size()) > 0
+    }
+
+    /// Check free space with the disk guard, if any, reporting changes.
+    /// Returns whether new files may be created. When free space cannot be
+    /// read, the previous state is kept.
+    fn check_disk_space(&mut self) -> bool {
+        let Some(guard) = &mut self.disk_guard else {
+            return true;
+        };
+        let check = match guard.check() {
+            Ok(check) => check,
+            Err(e) => {
+                warn!("Failed to check free disk space: {}", e);
+                let low = guard.is_low();
+                self.record_error(ErrorCode::Io);
+                return !low;
+            }
+        };
+        if check.changed {
+            self.notify(if check.low {
+                WriterNotification::DiskSpaceLow {
+                    available_bytes: check.space.available_bytes,
+                }
+            } else {
+                WriterNotification::DiskSpaceRecovered
+            });
+        }
+        !check.low
+    }
+
+    /// Check free space when the guard's interval has passed. While it is
+    /// low, finish the current file, like when the quota is reached, and
+    /// reclaim space if enabled. Once it recovers, open a new file.
+    /// Returns whether batches can be written.
+    async fn guard_disk_space(&mut self) -> Result<bool> {
+        let Some(guard) = &self.disk_guard else {
+            return Ok(true);
+        };
+        if !guard.is_due(Instant::now()) {
+            return Ok(!guard.is_low());
+        }
+
+        if !self.check_disk_space() {
+            // Reclaiming must not delete a file still being written
+            self.close_writer().await?;
+
+            let Some(guard) = &mut self.disk_guard else {
+                return Ok(false);
+            };
+            let reclaimed = guard
+                .reclaim()
+                .context(Coded::new(ErrorCode::Io, "Failed to reclaim disk space"))?;
+            let recovered = !guard.is_low();
+            for file in reclaimed {
+                self.quota.record_deleted(file.bytes as usize);
+                self.notify(WriterNotification::FileReclaimed {
+                    path: file.path.display().to_string(),
+                    bytes: file.bytes,
+                });
+            }
+            if !recovered {
+                return Ok(false);
             }
+            self.notify(WriterNotification::DiskSpaceRecovered);
         }
-        true
+
+        if self.current_writer.is_none() {
+            self.create_new_file()?;
+        }
+        Ok(self.current_writer.is_some())
     }
 
     /// Update the size tracking from the current writer
@@ -181,15 +683,35 @@ impl ParquetWriter {
 
             // Update in-memory size from writer
             self.in_memory_size = writer.in_progress_size();
+            let in_progress_rows = writer.in_progress_rows();
+            self.release_flushed_batches(in_progress_rows);
         } else {
             // No writer, reset all sizes
             self.flushed_row_groups_size = 0;
             self.flushed_row_groups_count = 0;
             self.in_memory_size = 0;
+            self.unflushed_batches.clear();
         }
+        self.memory_gauge.store(&self.memory_breakdown());
         Ok(())
     }
 
+    /// Widen the current file's timestamp range with the batch's timestamps
+    fn track_timestamps(&mut self, batch: &RecordBatch) {
+        let Some(column) = batch.column_by_name(&self.config.timestamp_column) else {
+            return;
+        };
+        if column.data_type() != &DataType::Int64 {
+            return;
+        }
+        for timestamp in column.as_primitive::<Int64Type>().iter().flatten() {
+            self.timestamp_range = Some(match self.timestamp_range {
+                Some((min, max)) => (min.min(timestamp), max.max(timestamp)),
+                None => (timestamp, timestamp),
+            });
+        }
+    }
+
     /// Check if we should rotate the file based on size
     async fn maybe_rotate_file(&mut self) -> Result<()> {
         let current_file_size = self.flushed_row_groups_size + self.in_memory_size;
@@ -203,8 +725,7 @@ impl ParquetWriter {
                 self.in_memory_size,
                 self.config.file_size_limit
             );
-            self.close_writer().await?;
-            self.create_new_file()?;
+            self.rotate().await?;
         }
 
         Ok(())
@@ -212,42 +733,52 @@ impl ParquetWriter {
 
     /// Write a record batch to the parquet file
     pub async fn write(&mut self, batch: RecordBatch) -> Result<()> {
+        self.reap_pending_close().await?;
+
+        if let Some(step) = self.wall_clock.sample() {
+            self.record_clock_step(step);
+        }
+
         // Skip writing if we've exceeded quota
         if !self.is_below_quota() {
             return Ok(());
         }
 
+        // Skip writing while free space is low
+        if !self.guard_disk_space().await? {
+            return Ok(());
+        }
+
         if let Some(writer) = &mut self.current_writer {
             // Write the batch
-            writer.write(&batch).await?;
+            writer.write(&batch).await.context(Coded::new(
+                ErrorCode::StorageWrite,
+                "Failed to write record batch",
+            ))?;
+            self.unflushed_batches
+                .push_back((batch.num_rows(), batch.get_array_memory_size()));
+            self.track_timestamps(&batch);
 
             // Update size tracking
             self.update_current_writer_size()?;
 
             // did we exceed the quota?
             if !self.is_below_quota() {
-                info!("Exceeded storage quota, stopping writes");
-                // close the writer
-                self.close_writer().await?;
-
-                // the actual written size might be a bit less than the quota, but now this triggered, we're done writing.
-                // force the sizes to be equal to the quota so is_below_quota returns false
-                if let Some(quota) = self.config.storage_quota {
-                    self.closed_files_size = quota;
-                }
-                return Ok(());
+                return self.stop_at_quota().await;
             }
 
-            // Check if we need to flush based on buffer size
-            if self.in_memory_size >= self.config.buffer_size {
-                info!("Flushing due to buffer size: {}, buffer size limit: {} (previously flushed {} in {} row groups)", self.in_memory_size, self.config.buffer_size, self.flushed_row_groups_size, self.flushed_row_groups_count);
+            // Check if we need to flush based on the memory held for
+            // unflushed rows
+            let memory = self.memory_breakdown();
+            if memory.buffered() >= self.config.buffer_size {
+                info!("Flushing due to buffer size: {} ({} encoded, {} in batches), buffer size limit: {} (previously flushed {} in {} row groups)", memory.buffered(), memory.encoder_in_progress, memory.accepted_unflushed, self.config.buffer_size, self.flushed_row_groups_size, self.flushed_row_groups_count);
                 self.flush().await?;
             }
 
             // Check if we need to rotate the file
             self.maybe_rotate_file().await?;
         } else {
-            return Err(anyhow!("No writer available"));
+            return Err(Coded::new(ErrorCode::Internal, "No writer available").into());
         }
 
         Ok(())
@@ -256,7 +787,10 @@ impl ParquetWriter {
     /// Flush any pending data
     pub async fn flush(&mut self) -> Result<()> {
         if let Some(writer) = &mut self.current_writer {
-            writer.flush().await?;
+            writer.flush().await.context(Coded::new(
+                ErrorCode::StorageWrite,
+                "Failed to flush row group",
+            ))?;
             self.update_current_writer_size()?;
         }
         Ok(())
@@ -265,35 +799,196 @@ impl ParquetWriter {
     /// Close the writer, finishing the Parquet file
     pub async fn close(mut self) -> Result<()> {
         debug!("Closing ParquetWriter instance");
+        self.finalize_all().await
+    }
+
+    /// Flush and close the current file, returning once every file written
+    /// so far is complete in the object store.
+    ///
+    /// Files closed on rotation were already confirmed when they were closed,
+    /// or are waited for here if closed in the background, so only the
+    /// current file remains. No new file is opened afterwards.
+    pub async fn finalize_all(&mut self) -> Result<()> {
+        self.flush().await?;
         self.close_writer().await
     }
 
+    /// Check that the object store holds the complete file at `path`
+    async fn confirm_upload(
+        store: &dyn ObjectStore,
+        path: &Path,
+        expected_size: usize,
+    ) -> Result<()> {
+        let meta = store.head(path).await.map_err(|e| {
+            Coded::new(
+                ErrorCode::StorageWrite,
+                format!(
+                    "Parquet file '{}' is missing from the object store after closing: {}",
+                    path, e
+                ),
+            )
+        })?;
+        if meta.size != expected_size {
+            return Err(Coded::new(
+                ErrorCode::StorageWrite,
+                format!(
+                    "Parquet file '{}' has {} bytes in the object store, expected {}",
+                    path, meta.size, expected_size
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Take the current writer, adding the file's timestamp range and wall
+    /// clock to its metadata. `wall_clock_offset_ns` converts the file's
+    /// CLOCK_MONOTONIC timestamps to wall-clock time, and `clock_steps` lists
+    /// the steps of the realtime clock seen while writing the file, as
+    /// comma-separated `monotonic_ns:delta_ns` pairs.
+    fn take_writer(&mut self) -> Option<AsyncArrowWriter<ParquetObjectWriter>> {
+        let mut writer = self.current_writer.take()?;
+        writer.append_key_value_metadata(KeyValue::new(
+            "wall_clock_offset_ns".to_string(),
+            self.wall_clock.offset_ns().to_string(),
+        ));
+        if !self.clock_steps.is_empty() {
+            let steps: Vec<String> = self
+                .clock_steps
+                .drain(..)
+                .map(|step| format!("{}:{}", step.monotonic_ns, step.delta_ns))
+                .collect();
+            writer.append_key_value_metadata(KeyValue::new(
+                "clock_steps".to_string(),
+                steps.join(","),
+            ));
+        }
+        if let Some((min, max)) = self.timestamp_range.take() {
+            writer.append_key_value_metadata(KeyValue::new(
+                "min_timestamp".to_string(),
+                min.to_string(),
+            ));
+            writer.append_key_value_metadata(KeyValue::new(
+                "max_timestamp".to_string(),
+                max.to_string(),
+            ));
+        }
+        Some(writer)
+    }
+
+    /// Finish the Parquet file written by `writer` and check it is complete
+    /// in the object store
+    async fn finish_file(
+        store: Arc<dyn ObjectStore>,
+        mut writer: AsyncArrowWriter<ParquetObjectWriter>,
+        path: Option<Path>,
+    ) -> Result<ClosedFile> {
+        // Completing the writer waits for the upload to finish, including
+        // any multipart parts still in flight
+        let metadata = writer.finish().await.context(Coded::new(
+            ErrorCode::StorageWrite,
+            "Failed to finish parquet file",
+        ))?;
+        if let Some(path) = &path {
+            Self::confirm_upload(store.as_ref(), path, writer.bytes_written()).await?;
+        }
+
+        // Size the file from the metadata
+        let mut bytes = 0;
+        for row_group in &metadata.row_groups {
+            if let Some(size) = row_group.total_compressed_size {
+                bytes += size as usize;
+            }
+        }
+
+        Ok(ClosedFile {
+            path,
+            row_groups: metadata.row_groups.len(),
+            rows: metadata
+                .row_groups
+                .iter()
+                .map(|rg| rg.num_rows as usize)
+                .sum(),
+            bytes,
+        })
+    }
+
+    /// Count a closed file against the quota and report it
+    fn record_closed_file(&mut self, file: ClosedFile) {
+        let path = file.path.map(|p| p.to_string()).unwrap_or_default();
+        debug!(
+            "Closed parquet file at path '{}' with {} row groups, {} rows",
+            path, file.row_groups, file.rows
+        );
+
+        self.quota.record_closed(file.bytes);
+        if let Some(guard) = &mut self.disk_guard {
+            guard.track_file(&path);
+        }
+        self.notify(WriterNotification::FileClosed {
+            path,
+            rows: file.rows,
+            bytes: file.bytes,
+        });
+    }
+
+    /// Wait for the file being closed in the background, if any
+    async fn wait_pending_close(&mut self) -> Result<()> {
+        if let Some(pending) = self.pending_close.take() {
+            let file = pending.handle.await.map_err(|e| {
+                Coded::new(
+                    ErrorCode::Internal,
+                    format!("Background close of parquet file failed: {}", e),
+                )
+            })??;
+            self.record_closed_file(file);
+        }
+        Ok(())
+    }
+
+    /// Account for a background close that already completed, without waiting
+    async fn reap_pending_close(&mut self) -> Result<()> {
+        if self
+            .pending_close
+            .as_ref()
+            .is_some_and(|pending| pending.handle.is_finished())
+        {
+            self.wait_pending_close().await?;
+        }
+        Ok(())
+    }
+
     /// Close the writer, finishing the Parquet file
     async fn close_writer(&mut self) -> Result<()> {
-        if let Some(writer) = self.current_writer.take() {
-            let metadata = writer.close().await?;
-
-            // Log the metadata details
-            debug!(
-                "Closed parquet file at path '{}' with {} row groups, {} rows",
-                self.current_file_path
-                    .as_ref()
-                    .map(|p| p.to_string())
-                    .unwrap_or_default(),
-                metadata.row_groups.len(),
-                metadata
-                    .row_groups
-                    .iter()
-                    .map(|rg| rg.num_rows)
-                    .sum::<i64>()
-            );
+        self.wait_pending_close().await?;
+        if let Some(writer) = self.take_writer() {
+            let file =
+                Self::finish_file(self.store.clone(), writer, self.current_file_path.clone())
+                    .await?;
+            self.record_closed_file(file);
+        }
 
-            // Update closed files size from the metadata
-            for row_group in &metadata.row_groups {
-                if let Some(size) = row_group.total_compressed_size {
-                    self.closed_files_size += size as usize;
-                }
-            }
+        self.update_current_writer_size()?;
+
+        Ok(())
+    }
+
+    /// Close the writer on a background task, reserving the file's current
+    /// size against the quota until the close completes. At most one file is
+    /// closed in the background; an earlier close is waited for first.
+    async fn close_writer_in_background(&mut self) -> Result<()> {
+        self.wait_pending_close().await?;
+        if let Some(writer) = self.take_writer() {
+            let reserved_size = self.flushed_row_groups_size + self.in_memory_size;
+            let handle = tokio::spawn(Self::finish_file(
+                self.store.clone(),
+                writer,
+                self.current_file_path.clone(),
+            ));
+            self.pending_close = Some(PendingClose {
+                handle,
+                reserved_size,
+            });
         }
 
         self.update_current_writer_size()?;
@@ -301,29 +996,179 @@ impl ParquetWriter {
         Ok(())
     }
 
+    /// Close the writer before opening the next file
+    async fn close_for_rotation(&mut self) -> Result<()> {
+        if self.config.background_close {
+            self.close_writer_in_background().await
+        } else {
+            self.close_writer().await
+        }
+    }
+
+    /// Close the writer for good once the quota is used up
+    async fn stop_at_quota(&mut self) -> Result<()> {
+        info!(
+            "Exceeded storage quota of the {} stream, stopping writes",
+            self.quota.name()
+        );
+        let used = self.total_size();
+        // close the writer
+        self.close_writer().await?;
+
+        // the actual written size might differ a bit from the quota, but now this triggered, we're done writing.
+        // the quota counts what was used, up to the quota, and denies further writes
+        self.quota.exhaust(used);
+        self.notify(WriterNotification::QuotaReached);
+        Ok(())
+    }
+
     /// Rotate the current parquet file, closing the current one and creating a new one
     pub async fn rotate(&mut self) -> Result<()> {
         debug!("Rotating parquet file");
         // Close the current writer
-        self.close_writer().await?;
+        self.close_for_rotation().await?;
         // Create a new file (this will check quota)
         self.create_new_file()?;
+        // The closed file may have taken the rest of the quota
+        if self.current_writer.is_none() && !self.is_below_quota() {
+            return self.stop_at_quota().await;
+        }
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use anyhow::anyhow;
     use arrow_array::{
         builder::{BooleanBuilder, Float64Builder, Int32Builder, StringBuilder},
-        ArrayRef,
+        ArrayRef, Float64Array, Int64Array,
     };
     use arrow_schema::{DataType, Field, Schema};
+    use async_trait::async_trait;
+    use futures::stream::BoxStream;
     use futures::StreamExt;
     use object_store::memory::InMemory;
+    use object_store::{
+        GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, PutMultipartOpts,
+        PutOptions, PutPayload, PutResult,
+    };
     use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
 
     use super::*;
+    use crate::disk_guard::is_own_file;
+    use crate::storage_quota::{QuotaManager, QuotaStream, StreamQuota};
+    use crate::wall_clock::tests::FakeClocks;
+
+    /// In-memory store whose single-request uploads wait for a permit from
+    /// `gate` and then `delay`, and which counts multipart uploads
+    #[derive(Debug)]
+    struct SlowStore {
+        inner: InMemory,
+        gate: Arc<Semaphore>,
+        delay: Duration,
+        multipart_uploads: AtomicUsize,
+    }
+
+    impl SlowStore {
+        fn new(permits: usize, delay: Duration) -> Self {
+            Self {
+                inner: InMemory::new(),
+                gate: Arc::new(Semaphore::new(permits)),
+                delay,
+                multipart_uploads: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl std::fmt::Display for SlowStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "SlowStore({})", self.inner)
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for SlowStore {
+        async fn put_opts(
+            &self,
+            location: &Path,
+            payload: PutPayload,
+            opts: PutOptions,
+        ) -> object_store::Result<PutResult> {
+            let _permit = self.gate.acquire().await.unwrap();
+            tokio::time::sleep(self.delay).await;
+            self.inner.put_opts(location, payload, opts).await
+        }
+
+        async fn put_multipart_opts(
+            &self,
+            location: &Path,
+            opts: PutMultipartOpts,
+        ) -> object_store::Result<Box<dyn MultipartUpload>> {
+            self.multipart_uploads.fetch_add(1, Ordering::Relaxed);
+            self.inner.put_multipart_opts(location, opts).await
+        }
+
+        async fn get_opts(
+            &self,
+            location: &Path,
+            options: GetOptions,
+        ) -> object_store::Result<GetResult> {
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &Path) -> object_store::Result<()> {
+            self.inner.delete(location).await
+        }
+
+        fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&Path>,
+        ) -> object_store::Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    /// Row counts and row group bytes of the parquet files in `store`
+    async fn stored_files(store: &dyn ObjectStore) -> Vec<(usize, usize)> {
+        let mut files = Vec::new();
+        let metas: Vec<_> = store.list(None).collect().await;
+        for meta in metas {
+            let bytes = store
+                .get(&meta.unwrap().location)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap();
+            let reader_builder = ParquetRecordBatchReaderBuilder::try_new(bytes).unwrap();
+            let metadata = reader_builder.metadata();
+            files.push((
+                metadata.file_metadata().num_rows() as usize,
+                metadata
+                    .row_groups()
+                    .iter()
+                    .map(|rg| rg.compressed_size() as usize)
+                    .sum(),
+            ));
+        }
+        files
+    }
 
     /// Create a simple test schema with multiple data types
     fn create_test_schema() -> SchemaRef {
@@ -456,6 +1301,46 @@ mod tests {
         assert_eq!(active_array.value(1), false);
     }
 
+    #[tokio::test]
+    async fn test_finalize_all() {
+        let schema = create_test_schema();
+        let test_batch = create_test_batch(schema.clone()).unwrap();
+
+        let memory_storage = Arc::new(InMemory::new());
+        let mut writer = ParquetWriter::new(
+            memory_storage.clone(),
+            schema.clone(),
+            ParquetWriterConfig::default(),
+        )
+        .unwrap();
+
+        for _ in 0..3 {
+            writer.write(test_batch.clone()).await.unwrap();
+        }
+        let path = writer.size_stats().current_file_path.unwrap();
+        writer.finalize_all().await.unwrap();
+
+        // Nothing is left open, and the file in the store is complete
+        let stats = writer.size_stats();
+        assert_eq!(stats.current_file_path, None);
+        assert_eq!(stats.in_memory_size, 0);
+        assert!(writer.write(test_batch.clone()).await.is_err());
+
+        let bytes = memory_storage
+            .get(&Path::from(path))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .unwrap()
+            .build()
+            .unwrap();
+        let rows: usize = reader.map(|batch| batch.unwrap().num_rows()).sum();
+        assert_eq!(rows, 3 * test_batch.num_rows());
+    }
+
     #[tokio::test]
     async fn test_file_rotation() {
         // Create test schema
@@ -470,6 +1355,7 @@ mod tests {
             max_row_group_size: 10,  // Small row group size
             storage_quota: None,
             key_value_metadata: None,
+            ..Default::default()
         };
 
         let mut writer =
@@ -534,6 +1420,191 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_size_stats() {
+        let schema = create_test_schema();
+        let batch = create_test_batch(schema.clone()).unwrap();
+        let quota = 4_000;
+        let config = ParquetWriterConfig {
+            storage_prefix: "test-".to_string(),
+            buffer_size: 1_000,
+            file_size_limit: 2_000,
+            storage_quota: Some(quota),
+            ..Default::default()
+        };
+        let mut writer = ParquetWriter::new(Arc::new(InMemory::new()), schema, config).unwrap();
+
+        let stats = writer.size_stats();
+        assert_eq!(stats.total_size, 0);
+        let first_path = stats.current_file_path.clone().unwrap();
+        assert!(first_path.starts_with("test-"));
+
+        // Write until the quota stops the writer, checking the reported sizes
+        // against the quota decision after every batch
+        let mut saw_flushed = false;
+        let mut saw_rotation = false;
+        for _ in 0..10_000 {
+            writer.write(batch.clone()).await.unwrap();
+            let stats = writer.size_stats();
+
+            assert_eq!(
+                stats.total_size,
+                stats.closed_files_size
+                    + stats.closing_file_size
+                    + stats.flushed_row_groups_size
+                    + stats.in_memory_size
+            );
+            assert_eq!(writer.is_below_quota(), stats.total_size < quota);
+            saw_flushed |= stats.flushed_row_groups_size > 0;
+            saw_rotation |= stats.closed_files_size > 0 && stats.current_file_path.is_some();
+
+            if !writer.is_below_quota() {
+                break;
+            }
+        }
+
+        assert!(saw_flushed, "expected row groups to be flushed");
+        assert!(saw_rotation, "expected the file to be rotated");
+
+        // Once the quota is reached no file is open and the total sits at the quota
+        let stats = writer.size_stats();
+        assert_eq!(stats.total_size, quota);
+        assert_eq!(stats.current_file_path, None);
+    }
+
+    #[tokio::test]
+    async fn test_shared_quota() {
+        let schema = create_test_schema();
+        let batch = create_test_batch(schema.clone()).unwrap();
+        let quota = QuotaManager::new(Some(12_000));
+        let writer = |stream: QuotaStream, reserved| {
+            let config = ParquetWriterConfig {
+                storage_prefix: format!("{}-", stream.name()),
+                buffer_size: 1_000,
+                file_size_limit: 2_000,
+                quota: Some(
+                    quota
+                        .register(
+                            stream,
+                            StreamQuota {
+                                reserved,
+                                ..Default::default()
+                            },
+                        )
+                        .unwrap(),
+                ),
+                ..Default::default()
+            };
+            ParquetWriter::new(Arc::new(InMemory::new()), schema.clone(), config).unwrap()
+        };
+        let mut trace = writer(QuotaStream::Trace, 0);
+        let mut timeslots = writer(QuotaStream::Timeslots, 4_000);
+
+        // Both write until the quota stops them, the trace first
+        let mut trace_writes = 0;
+        let mut timeslot_writes = 0;
+        while timeslots.is_below_quota() {
+            if trace.is_below_quota() {
+                trace.write(batch.clone()).await.unwrap();
+                trace_writes += 1;
+            }
+            timeslots.write(batch.clone()).await.unwrap();
+            timeslot_writes += 1;
+        }
+        assert!(!trace.is_below_quota());
+        assert!(trace_writes < timeslot_writes);
+
+        // The trace kept to its half of the shared budget, give or take its
+        // last write, and the timeslots got their reservation and the rest
+        let trace_size = trace.size_stats().total_size;
+        let timeslots_size = timeslots.size_stats().total_size;
+        assert!(trace_size < 4_500, "trace wrote {}", trace_size);
+        assert!(timeslots_size > 7_500, "timeslots wrote {}", timeslots_size);
+        assert_eq!(
+            quota.state(None).closed_bytes.values().sum::<usize>(),
+            trace_size + timeslots_size
+        );
+    }
+
+    /// A single Int64 column batch of `rows` rows
+    fn int64_batch(schema: SchemaRef, rows: i64) -> RecordBatch {
+        let values: Vec<i64> = (0..rows).collect();
+        RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(values)) as ArrayRef]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_memory_breakdown() {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "value",
+            DataType::Int64,
+            false,
+        )]));
+        let batch = int64_batch(schema.clone(), 100);
+        let batch_memory = batch.get_array_memory_size();
+
+        // Accepted batches are counted until their rows are flushed, and a
+        // batch split across row groups keeps the share of its unflushed rows
+        let config = ParquetWriterConfig {
+            storage_prefix: "test-".to_string(),
+            max_row_group_size: 150,
+            ..Default::default()
+        };
+        let mut writer =
+            ParquetWriter::new(Arc::new(InMemory::new()), schema.clone(), config).unwrap();
+        writer.write(batch.clone()).await.unwrap();
+        let memory = writer.memory_breakdown();
+        assert_eq!(memory.accepted_unflushed, batch_memory);
+        assert_eq!(memory.flushed_compressed, 0);
+        assert!(memory.encoder_in_progress > 0);
+        assert_eq!(writer.memory_gauge().load(), memory);
+
+        writer.write(batch.clone()).await.unwrap();
+        let memory = writer.memory_breakdown();
+        assert!(memory.flushed_compressed > 0);
+        assert_eq!(memory.accepted_unflushed, batch_memory - batch_memory / 2);
+
+        writer.flush().await.unwrap();
+        let memory = writer.memory_breakdown();
+        assert_eq!(memory.accepted_unflushed, 0);
+        assert_eq!(memory.encoder_in_progress, 0);
+        assert_eq!(writer.memory_gauge().load(), memory);
+
+        // Size the buffer to hold three batches along with their encoded
+        // rows, which the encoded rows alone never reach
+        let config = ParquetWriterConfig {
+            storage_prefix: "test-".to_string(),
+            ..Default::default()
+        };
+        let mut reference =
+            ParquetWriter::new(Arc::new(InMemory::new()), schema.clone(), config).unwrap();
+        for _ in 0..3 {
+            reference.write(batch.clone()).await.unwrap();
+        }
+        let encoded = reference.memory_breakdown().encoder_in_progress;
+        let buffer_size = encoded + batch_memory * 3;
+
+        // Counting the batches flushes once the third one is accepted, where
+        // the encoder's in-progress size alone would not have
+        let config = ParquetWriterConfig {
+            storage_prefix: "test-".to_string(),
+            buffer_size,
+            ..Default::default()
+        };
+        let mut writer = ParquetWriter::new(Arc::new(InMemory::new()), schema, config).unwrap();
+        for _ in 0..2 {
+            writer.write(batch.clone()).await.unwrap();
+            assert_eq!(writer.memory_breakdown().flushed_compressed, 0);
+        }
+        assert_eq!(
+            writer.memory_breakdown().accepted_unflushed,
+            batch_memory * 2
+        );
+        writer.write(batch).await.unwrap();
+        let memory = writer.memory_breakdown();
+        assert!(memory.flushed_compressed > 0);
+        assert_eq!(memory.buffered(), 0);
+    }
+
     #[tokio::test]
     async fn test_key_value_metadata() {
         // Create test schema and data
@@ -561,6 +1632,7 @@ mod tests {
             max_row_group_size: 1024 * 1024,
             storage_quota: None,
             key_value_metadata: Some(metadata.clone()),
+            ..Default::default()
         };
 
         let mut writer =
@@ -619,4 +1691,561 @@ mod tests {
             "collection_version value should match"
         );
     }
+
+    #[tokio::test]
+    async fn test_timestamp_range_metadata() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("timestamp", DataType::Int64, false),
+            Field::new("value", DataType::Float64, false),
+        ]));
+        let batch = |timestamps: Vec<i64>| {
+            let values = vec![1.0; timestamps.len()];
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int64Array::from(timestamps)),
+                    Arc::new(Float64Array::from(values)),
+                ],
+            )
+            .unwrap()
+        };
+
+        let memory_storage = Arc::new(InMemory::new());
+        let mut writer = ParquetWriter::new(
+            memory_storage.clone(),
+            schema.clone(),
+            ParquetWriterConfig::default(),
+        )
+        .unwrap();
+        let path = writer.size_stats().current_file_path.unwrap();
+        writer
+            .write(batch(vec![3_000, 1_500, 2_000]))
+            .await
+            .unwrap();
+        writer.write(batch(vec![9_000, 4_000])).await.unwrap();
+        writer.close().await.unwrap();
+
+        let bytes = memory_storage
+            .get(&Path::from(path))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let reader_builder = ParquetRecordBatchReaderBuilder::try_new(bytes).unwrap();
+        let kv_map = reader_builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata()
+            .cloned()
+            .unwrap();
+        let value = |key: &str| {
+            kv_map
+                .iter()
+                .find(|kv| kv.key == key)
+                .and_then(|kv| kv.value.clone())
+        };
+        assert_eq!(value("min_timestamp").as_deref(), Some("1500"));
+        assert_eq!(value("max_timestamp").as_deref(), Some("9000"));
+
+        // Files without the timestamp column carry no range
+        let schema = create_test_schema();
+        let mut writer = ParquetWriter::new(
+            memory_storage.clone(),
+            schema.clone(),
+            ParquetWriterConfig::default(),
+        )
+        .unwrap();
+        let path = writer.size_stats().current_file_path.unwrap();
+        writer
+            .write(create_test_batch(schema).unwrap())
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+
+        let bytes = memory_storage
+            .get(&Path::from(path))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let reader_builder = ParquetRecordBatchReaderBuilder::try_new(bytes).unwrap();
+        let kv_metadata = reader_builder
+            .metadata()
+            .file_metadata()
+            .key_value_metadata();
+        assert!(kv_metadata.is_none_or(|kv| kv.iter().all(|kv| kv.key != "min_timestamp")));
+    }
+
+    #[tokio::test]
+    async fn test_clock_steps() {
+        const SEC: i64 = 1_000_000_000;
+        let schema = create_test_schema();
+        let batch = create_test_batch(schema.clone()).unwrap();
+
+        // 2024-01-01T00:00:00Z, 100s after boot
+        let clocks = FakeClocks::new(100 * SEC as u64, 1_704_067_200 * SEC);
+        let wall_clock = WallClock::new(Box::new(clocks.clone()), Duration::from_secs(1));
+        let store = Arc::new(InMemory::new());
+        let config = ParquetWriterConfig {
+            storage_prefix: "m-".to_string(),
+            ..Default::default()
+        };
+        let mut writer =
+            ParquetWriter::with_wall_clock(store.clone(), schema, config, wall_clock).unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        writer.set_notifier(sender);
+
+        // Stepped back an hour while writing the first file
+        writer.write(batch.clone()).await.unwrap();
+        clocks.advance(10 * SEC as u64);
+        clocks.step(-3600 * SEC);
+        writer.write(batch.clone()).await.unwrap();
+
+        // The next file follows the stepped clock, but its name does not go back
+        writer.rotate().await.unwrap();
+        writer.write(batch.clone()).await.unwrap();
+
+        // Stepped forward two hours, first seen when rotating
+        clocks.advance(5 * SEC as u64);
+        clocks.step(7200 * SEC);
+        writer.rotate().await.unwrap();
+        writer.write(batch).await.unwrap();
+        writer.close().await.unwrap();
+
+        let mut files = Vec::new();
+        let metas: Vec<_> = store.list(None).collect().await;
+        for meta in metas {
+            let location = meta.unwrap().location;
+            let bytes = store.get(&location).await.unwrap().bytes().await.unwrap();
+            let reader_builder = ParquetRecordBatchReaderBuilder::try_new(bytes).unwrap();
+            let kv_metadata = reader_builder
+                .metadata()
+                .file_metadata()
+                .key_value_metadata()
+                .cloned()
+                .unwrap();
+            let value = |key: &str| {
+                kv_metadata
+                    .iter()
+                    .find(|kv| kv.key == key)
+                    .and_then(|kv| kv.value.clone())
+            };
+            let name = location.to_string();
+            assert!(is_own_file(&name, "m-"), "{}", name);
+            files.push((
+                name[..26].to_string(),
+                value("wall_clock_offset_ns").unwrap(),
+                value("clock_steps"),
+            ));
+        }
+        files.sort();
+
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "m-20240101T000000Z-000000-".to_string(),
+                    (1_704_067_100 * SEC).to_string(),
+                    Some(format!("{}:{}", 110 * SEC, -3600 * SEC)),
+                ),
+                (
+                    "m-20240101T000000Z-000001-".to_string(),
+                    (1_704_063_500 * SEC).to_string(),
+                    None,
+                ),
+                (
+                    "m-20240101T010015Z-000002-".to_string(),
+                    (1_704_070_700 * SEC).to_string(),
+                    Some(format!("{}:{}", 115 * SEC, 7200 * SEC)),
+                ),
+            ]
+        );
+
+        let mut steps = Vec::new();
+        while let Ok(notification) = receiver.try_recv() {
+            if let WriterNotification::ClockStep { delta_ns } = notification {
+                steps.push(delta_ns);
+            }
+        }
+        assert_eq!(steps, vec![-3600 * SEC, 7200 * SEC]);
+    }
+
+    #[tokio::test]
+    async fn test_background_close_overlaps_writes() {
+        let schema = create_test_schema();
+        let batch = create_test_batch(schema.clone()).unwrap();
+
+        // Uploads are held back until the test opens the gate
+        let store = Arc::new(SlowStore::new(0, Duration::ZERO));
+        let config = ParquetWriterConfig {
+            background_close: true,
+            ..Default::default()
+        };
+        let mut writer = ParquetWriter::new(store.clone(), schema.clone(), config).unwrap();
+
+        writer.write(batch.clone()).await.unwrap();
+        let reserved = writer.size_stats().in_memory_size;
+        tokio::time::timeout(Duration::from_secs(5), writer.rotate())
+            .await
+            .expect("rotation should not wait for the upload")
+            .unwrap();
+
+        // The new file accepts batches while the old one is still uploading,
+        // and the old file's size stays reserved against the quota
+        for _ in 0..3 {
+            tokio::time::timeout(Duration::from_secs(5), writer.write(batch.clone()))
+                .await
+                .expect("write should not wait for the upload")
+                .unwrap();
+        }
+        let stats = writer.size_stats();
+        assert_eq!(stats.closing_file_size, reserved);
+        assert_eq!(stats.closed_files_size, 0);
+        assert!(stored_files(store.as_ref()).await.is_empty());
+
+        store.gate.add_permits(Semaphore::MAX_PERMITS);
+        writer.finalize_all().await.unwrap();
+
+        let stats = writer.size_stats();
+        assert_eq!(stats.closing_file_size, 0);
+        let mut files = stored_files(store.as_ref()).await;
+        files.sort();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, batch.num_rows());
+        assert_eq!(files[1].0, 3 * batch.num_rows());
+        assert_eq!(stats.closed_files_size, files[0].1 + files[1].1);
+
+        // Without background close, rotation waits for the upload
+        let store = Arc::new(SlowStore::new(0, Duration::ZERO));
+        let mut writer =
+            ParquetWriter::new(store.clone(), schema, ParquetWriterConfig::default()).unwrap();
+        writer.write(batch).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), writer.rotate())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_background_close_respects_quota() {
+        let schema = create_test_schema();
+        let batch = create_test_batch(schema.clone()).unwrap();
+        let quota = 4_000;
+
+        let store = Arc::new(SlowStore::new(
+            Semaphore::MAX_PERMITS,
+            Duration::from_millis(20),
+        ));
+        let config = ParquetWriterConfig {
+            storage_prefix: "test-".to_string(),
+            file_size_limit: 2_000,
+            storage_quota: Some(quota),
+            background_close: true,
+            ..Default::default()
+        };
+        let mut writer = ParquetWriter::new(store.clone(), schema, config).unwrap();
+
+        let mut saw_closing = false;
+        for _ in 0..10_000 {
+            writer.write(batch.clone()).await.unwrap();
+            let stats = writer.size_stats();
+            assert_eq!(
+                stats.total_size,
+                stats.closed_files_size
+                    + stats.closing_file_size
+                    + stats.flushed_row_groups_size
+                    + stats.in_memory_size
+            );
+            saw_closing |= stats.closing_file_size > 0;
+            if !writer.is_below_quota() {
+                break;
+            }
+        }
+        assert!(
+            saw_closing,
+            "expected a file to be closed in the background"
+        );
+        writer.finalize_all().await.unwrap();
+
+        // The files in the store fit in the quota
+        let files = stored_files(store.as_ref()).await;
+        assert!(files.len() > 1);
+        let stored: usize = files.iter().map(|(_, bytes)| bytes).sum();
+        assert!(stored <= quota, "{} bytes stored, quota {}", stored, quota);
+        assert_eq!(writer.size_stats().total_size, quota);
+    }
+
+    #[tokio::test]
+    async fn test_disk_guard_stops_and_resumes() {
+        use crate::disk_guard::tests::{FakeFsStats, TempDir};
+        use crate::disk_guard::{DiskGuard, DiskGuardConfig};
+
+        let dir = TempDir::new("writer");
+        let schema = create_test_schema();
+        let batch = create_test_batch(schema.clone()).unwrap();
+        let store =
+            Arc::new(object_store::local::LocalFileSystem::new_with_prefix(&dir.path).unwrap());
+        let config = ParquetWriterConfig {
+            storage_prefix: "out/test-".to_string(),
+            ..Default::default()
+        };
+        let mut writer = ParquetWriter::new(store, schema, config).unwrap();
+        let (notify_sender, mut notify_receiver) = mpsc::unbounded_channel();
+        writer.set_notifier(notify_sender);
+
+        let fs_stats = FakeFsStats::new(1000, 10000);
+        writer.set_disk_guard(DiskGuard::with_fs_stats(
+            DiskGuardConfig {
+                min_free_mb: 100,
+                min_free_percent: 0.0,
+                recovery_free_mb: 200,
+                recovery_free_percent: 0.0,
+                reclaim_own_files: true,
+                check_interval: Duration::ZERO,
+            },
+            &dir.path,
+            "out/test-",
+            Box::new(fs_stats.clone()),
+        ));
+        let mut notifications = || {
+            let mut received = Vec::new();
+            while let Ok(notification) = notify_receiver.try_recv() {
+                received.push(notification);
+            }
+            received
+        };
+
+        writer.write(batch.clone()).await.unwrap();
+        let first = writer.size_stats().current_file_path.unwrap();
+        assert!(notifications().is_empty());
+
+        // Low space finishes the current file, then reclaims it since space
+        // stays low
+        fs_stats.set(50, 10000);
+        writer.write(batch.clone()).await.unwrap();
+        assert_eq!(writer.size_stats().current_file_path, None);
+        let received = notifications();
+        assert!(matches!(
+            &received[..],
+            [
+                WriterNotification::DiskSpaceLow { available_bytes },
+                WriterNotification::FileClosed { path, rows: 2, .. },
+                WriterNotification::FileReclaimed { path: reclaimed, .. },
+            ] if *available_bytes == 50 * 1024 * 1024
+                && *path == first
+                && *reclaimed == dir.path.join(&first).display().to_string()
+        ));
+        assert!(!dir.path.join(&first).exists());
+        // The reclaimed file no longer counts against the quota
+        assert_eq!(writer.size_stats().closed_files_size, 0);
+
+        // Above the minimum but below the recovery threshold, batches are still dropped
+        fs_stats.set(150, 10000);
+        writer.write(batch.clone()).await.unwrap();
+        assert_eq!(writer.size_stats().current_file_path, None);
+        assert!(notifications().is_empty());
+
+        // Recovery opens a new file for the batch
+        fs_stats.set(250, 10000);
+        writer.write(batch.clone()).await.unwrap();
+        let second = writer.size_stats().current_file_path.unwrap();
+        assert_ne!(second, first);
+        assert_eq!(
+            notifications(),
+            vec![WriterNotification::DiskSpaceRecovered]
+        );
+
+        writer.finalize_all().await.unwrap();
+        assert!(matches!(
+            &notifications()[..],
+            [WriterNotification::FileClosed { path, rows: 2, .. }] if *path == second
+        ));
+
+        // Rotation does not create a file while space is low
+        fs_stats.set(50, 10000);
+        writer.rotate().await.unwrap();
+        assert_eq!(writer.size_stats().current_file_path, None);
+        assert!(matches!(
+            &notifications()[..],
+            [WriterNotification::DiskSpaceLow { .. }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_disk_space_check_failure_keeps_writing() {
+        use crate::disk_guard::{DiskGuard, DiskGuardConfig, FsSpace, FsStats};
+
+        struct FailingFsStats;
+
+        impl FsStats for FailingFsStats {
+            fn space(&self, _path: &std::path::Path) -> std::io::Result<FsSpace> {
+                Err(std::io::Error::from_raw_os_error(libc::EIO))
+            }
+        }
+
+        let schema = create_test_schema();
+        let batch = create_test_batch(schema.clone()).unwrap();
+        let mut writer =
+            ParquetWriter::new(Arc::new(InMemory::new()), schema, Default::default()).unwrap();
+        writer.set_disk_guard(DiskGuard::with_fs_stats(
+            DiskGuardConfig {
+                check_interval: Duration::ZERO,
+                ..Default::default()
+            },
+            std::path::Path::new("/"),
+            "test-",
+            Box::new(FailingFsStats),
+        ));
+        let counts = Arc::new(ErrorCounts::default());
+        writer.set_error_counts(counts.clone());
+
+        writer.write(batch.clone()).await.unwrap();
+        writer.rotate().await.unwrap();
+        writer.write(batch).await.unwrap();
+        assert!(writer.size_stats().current_file_path.is_some());
+        writer.finalize_all().await.unwrap();
+
+        // Each failed check is counted as an I/O error
+        let counts = counts.snapshot();
+        assert_eq!(counts.keys().collect::<Vec<_>>(), vec![&ErrorCode::Io]);
+    }
+
+    #[tokio::test]
+    async fn test_multipart_upload() {
+        let schema = create_test_schema();
+        let store = Arc::new(SlowStore::new(Semaphore::MAX_PERMITS, Duration::ZERO));
+        let config = ParquetWriterConfig {
+            buffer_size: 10_000,
+            multipart_part_size: 5 * 1024,
+            multipart_max_concurrency: 2,
+            ..Default::default()
+        };
+        let mut writer = ParquetWriter::new(store.clone(), schema.clone(), config).unwrap();
+
+        // Distinct values, so the file does not compress below a part
+        for batch_index in 0..20 {
+            let mut id_builder = Int32Builder::with_capacity(1000);
+            let mut name_builder = StringBuilder::with_capacity(1000, 16_000);
+            let mut value_builder = Float64Builder::with_capacity(1000);
+            let mut active_builder = BooleanBuilder::with_capacity(1000);
+            for i in 0..1000 {
+                let id = batch_index * 1000 + i;
+                id_builder.append_value(id);
+                name_builder.append_value(format!("user_{}", id));
+                value_builder.append_value(id as f64 * 1.5);
+                active_builder.append_value(id % 3 == 0);
+            }
+            let arrays: Vec<ArrayRef> = vec![
+                Arc::new(id_builder.finish()),
+                Arc::new(name_builder.finish()),
+                Arc::new(value_builder.finish()),
+                Arc::new(active_builder.finish()),
+            ];
+            let batch = RecordBatch::try_new(schema.clone(), arrays).unwrap();
+            writer.write(batch).await.unwrap();
+        }
+        writer.close().await.unwrap();
+
+        // The file was large enough to be uploaded in parts, and reads back whole
+        assert_eq!(store.multipart_uploads.load(Ordering::Relaxed), 1);
+        let files = stored_files(store.as_ref()).await;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, 20 * 1000);
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let config = ParquetWriterConfig::builder()
+            .storage_prefix("out/test-")
+            .buffer_size(1_000)
+            .file_size_limit(2_000)
+            .storage_quota(Some(10_000))
+            .writer_version(WriterVersion::PARQUET_2_0)
+            .background_close(true)
+            .build()
+            .unwrap();
+        assert_eq!(config.storage_prefix, "out/test-");
+        assert_eq!(config.buffer_size, 1_000);
+        assert_eq!(config.file_size_limit, 2_000);
+        assert_eq!(config.storage_quota, Some(10_000));
+        assert_eq!(config.writer_version, WriterVersion::PARQUET_2_0);
+        assert!(config.background_close);
+        // Unset fields keep their defaults
+        let defaults = ParquetWriterConfig::default();
+        assert_eq!(config.max_row_group_size, defaults.max_row_group_size);
+        assert_eq!(config.timestamp_column, defaults.timestamp_column);
+
+        let err = ParquetWriterConfig::builder()
+            .buffer_size(2_001)
+            .file_size_limit(2_000)
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("file_size_limit"));
+
+        assert!(ParquetWriterConfig::builder()
+            .multipart_max_concurrency(0)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_writer_version() {
+        assert_eq!(parse_writer_version("1.0"), Ok(WriterVersion::PARQUET_1_0));
+        assert_eq!(parse_writer_version("2.0"), Ok(WriterVersion::PARQUET_2_0));
+        assert_eq!(
+            parse_writer_version("PARQUET_2_0"),
+            Ok(WriterVersion::PARQUET_2_0)
+        );
+        assert!(parse_writer_version("3.0").is_err());
+        assert!(parse_writer_version("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_writer_versions_round_trip() {
+        let schema = create_test_schema();
+        let test_batch = create_test_batch(schema.clone()).unwrap();
+
+        for (version, expected_format) in [
+            (WriterVersion::PARQUET_1_0, 1),
+            (WriterVersion::PARQUET_2_0, 2),
+        ] {
+            let memory_storage = Arc::new(InMemory::new());
+            let config = ParquetWriterConfig {
+                writer_version: version,
+                ..Default::default()
+            };
+            let mut writer =
+                ParquetWriter::new(memory_storage.clone(), schema.clone(), config).unwrap();
+            writer.write(test_batch.clone()).await.unwrap();
+            writer.close().await.unwrap();
+
+            let files: Vec<_> = memory_storage.list(None).collect().await;
+            assert_eq!(files.len(), 1, "Expected exactly one parquet file");
+            let file_path = &files[0].as_ref().unwrap().location;
+            let bytes = memory_storage
+                .get(file_path)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap();
+
+            let reader_builder = ParquetRecordBatchReaderBuilder::try_new(bytes).unwrap();
+            assert_eq!(
+                reader_builder.metadata().file_metadata().version(),
+                expected_format
+            );
+
+            let batches: Vec<RecordBatch> = reader_builder
+                .build()
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(batches, vec![test_batch.clone()]);
+        }
+    }
 }
@@ -1,39 +1,72 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use arrow_array::RecordBatch;
+use futures::StreamExt;
 use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 
+use crate::batch_transform::TransformChain;
+use crate::error_code::error_code;
+use crate::metrics::ErrorCounts;
 use crate::parquet_writer::ParquetWriter;
 
 /// Worker task for processing record batches and writing them to parquet
 pub struct ParquetWriterTask {
-    batch_receiver: mpsc::Receiver<RecordBatch>,
+    batches: ReceiverStream<RecordBatch>,
     writer: ParquetWriter,
     rotate_receiver: mpsc::Receiver<()>,
+    transforms: Option<TransformChain>,
+    error_counts: Option<Arc<ErrorCounts>>,
 }
 
 impl ParquetWriterTask {
-    /// Create a new ParquetWriterTask with pre-configured channels
+    /// Create a new ParquetWriterTask writing `batches`, from a channel or the
+    /// stream of a [`crate::Collector`]
     pub fn new(
         writer: ParquetWriter,
-        batch_receiver: mpsc::Receiver<RecordBatch>,
+        batches: impl Into<ReceiverStream<RecordBatch>>,
         rotate_receiver: mpsc::Receiver<()>,
     ) -> Self {
         Self {
-            batch_receiver,
+            batches: batches.into(),
             writer,
             rotate_receiver,
+            transforms: None,
+            error_counts: None,
         }
     }
 
+    /// Count the errors the task and its writer carry on after, such as a
+    /// failed rotation, in `counts`
+    pub fn set_error_counts(&mut self, counts: Arc<ErrorCounts>) {
+        self.writer.set_error_counts(counts.clone());
+        self.error_counts = Some(counts);
+    }
+
+    /// Run each batch through `transforms` before writing it
+    ///
+    /// The writer must have been created with the chain's output schema.
+    pub fn set_transforms(&mut self, transforms: TransformChain) {
+        self.transforms = Some(transforms);
+    }
+
     /// Run the task, processing record batches until the channel is closed
     pub async fn run(mut self) -> Result<()> {
         loop {
             tokio::select! {
-                batch_result = self.batch_receiver.recv() => {
+                batch_result = self.batches.next() => {
                     match batch_result {
                         Some(batch) => {
-                            // Write the batch
-                            self.writer.write(batch).await?;
+                            let batch = match self.transforms {
+                                Some(ref mut transforms) => transforms.apply(batch)?,
+                                None => Some(batch),
+                            };
+
+                            // Write the batch, unless a transform dropped it
+                            if let Some(batch) = batch {
+                                self.writer.write(batch).await?;
+                            }
                         }
                         None => {
                             // Channel closed - pipeline shutting down
@@ -46,8 +79,19 @@ impl ParquetWriterTask {
                     // Rotation signal received
                     if let Err(e) = self.writer.rotate().await {
                         log::warn!("Failed to rotate parquet file: {}", e);
+                        if let (Some(counts), Some(code)) = (&self.error_counts, error_code(&e)) {
+                            counts.record(code);
+                        }
                     } else {
-                        log::info!("Parquet file rotated successfully");
+                        let stats = self.writer.size_stats();
+                        log::info!(
+                            "Parquet file rotated successfully, now writing {}; {} bytes total ({} in closed files, {} flushed, {} in memory)",
+                            stats.current_file_path.as_deref().unwrap_or("<none>"),
+                            stats.total_size,
+                            stats.closed_files_size,
+                            stats.flushed_row_groups_size,
+                            stats.in_memory_size
+                        );
                     }
                 }
             }
@@ -1,18 +1,62 @@
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
+use anyhow::{anyhow, Result};
 use arrow_array::RecordBatch;
 use tokio::sync::mpsc;
 
-use bpf::BpfLoader;
+use bpf::{BpfLoaderConfig, ProgramGroup};
+use perf_events::Dispatcher;
+use timeslot::{MinTracker, TrackerState};
 
+use crate::adaptive::Mitigations;
+use crate::attribution::AttributionLayout;
 use crate::bpf_error_handler::BpfErrorHandler;
 use crate::bpf_perf_to_timeslot::BpfPerfToTimeslot;
-use crate::bpf_perf_to_trace::BpfPerfToTrace;
+use crate::bpf_perf_to_trace::{BpfPerfToTrace, TraceLimits, TraceMemory};
 use crate::bpf_task_tracker::BpfTaskTracker;
 use crate::bpf_timeslot_tracker::BpfTimeslotTracker;
+use crate::cgroup_filter::{CgroupFilter, ContainerTable};
+use crate::pid_namespace::PidNamespaceTranslator;
+use crate::processor_log::ProcessorRecorder;
 use crate::timeslot_data::TimeslotData;
 
+/// Program groups timeslot mode cannot do without: the sync timer closes each
+/// timeslot, and the counters are what timeslots aggregate
+pub const TIMESLOT_PROGRAM_GROUPS: [ProgramGroup; 2] =
+    [ProgramGroup::SyncTimer, ProgramGroup::Counters];
+
+/// Check that the enabled program groups produce what the output mode needs
+pub fn check_program_groups(groups: &BpfLoaderConfig, trace: bool) -> Result<()> {
+    if trace {
+        // Trace rows come from context switches and timer ticks
+        if !groups.is_enabled(ProgramGroup::Sched) && !groups.is_enabled(ProgramGroup::SyncTimer) {
+            return Err(anyhow!(
+                "Trace mode requires the {} or {} program group",
+                ProgramGroup::Sched,
+                ProgramGroup::SyncTimer
+            ));
+        }
+        return Ok(());
+    }
+
+    let missing = groups.missing_groups(&TIMESLOT_PROGRAM_GROUPS);
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Timeslot mode requires the {} program groups, missing: {}",
+            TIMESLOT_PROGRAM_GROUPS.map(ProgramGroup::name).join(", "),
+            missing
+                .iter()
+                .map(|group| group.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    Ok(())
+}
+
 /// Enum for selecting processor mode and channel type
 pub enum ProcessorMode {
     Timeslot(mpsc::Sender<TimeslotData>),
@@ -22,65 +66,171 @@ pub enum ProcessorMode {
 // Application coordinator for BPF components with dual mode support
 pub struct PerfEventProcessor {
     // BPF timeslot tracker
-    _timeslot_tracker: Rc<RefCell<BpfTimeslotTracker>>,
+    timeslot_tracker: Rc<RefCell<BpfTimeslotTracker>>,
     // BPF error handler
     _error_handler: Rc<RefCell<BpfErrorHandler>>,
     // BPF task tracker
-    _task_tracker: Rc<RefCell<BpfTaskTracker>>,
+    task_tracker: Rc<RefCell<BpfTaskTracker>>,
     // Processors (exactly one will be Some based on mode)
     _perf_to_timeslot: Option<Rc<RefCell<BpfPerfToTimeslot>>>,
     _perf_to_trace: Option<Rc<RefCell<BpfPerfToTrace>>>,
+    // Optional log of consumed events and emitted timeslots
+    recorder: Option<Rc<RefCell<ProcessorRecorder>>>,
+    // Optional translation of pids into their container's pid namespace
+    pid_translator: Option<Rc<RefCell<PidNamespaceTranslator>>>,
 }
 
 impl PerfEventProcessor {
-    // Create a new PerfEventProcessor with mode-specific configuration
-    pub fn new(
-        bpf_loader: &mut BpfLoader,
+    // Create a PerfEventProcessor subscribed to the given dispatcher. When a
+    // recorder is given, every consumed event and emitted timeslot is logged.
+    // Only messages the enabled program groups produce are subscribed to.
+    pub fn with_dispatcher(
+        dispatcher: &mut Dispatcher,
         num_cpus: usize,
         mode: ProcessorMode,
+        recorder: Option<Rc<RefCell<ProcessorRecorder>>>,
+        groups: &BpfLoaderConfig,
     ) -> Rc<RefCell<Self>> {
+        // Attach the recorder first, so inputs are logged before their outputs
+        if let Some(ref recorder) = recorder {
+            ProcessorRecorder::attach(recorder, dispatcher);
+        }
+
         // Create BpfTimeslotTracker (always present)
-        let timeslot_tracker = BpfTimeslotTracker::new(bpf_loader, num_cpus);
+        let timeslot_tracker = BpfTimeslotTracker::new(dispatcher, num_cpus, groups);
 
         // Create BpfErrorHandler
-        let error_handler = BpfErrorHandler::new(bpf_loader);
+        let error_handler = BpfErrorHandler::new(dispatcher, groups);
 
         // Create BpfTaskTracker with timeslot tracker reference
-        let task_tracker = BpfTaskTracker::new(bpf_loader, timeslot_tracker.clone());
+        let task_tracker = BpfTaskTracker::new(dispatcher, timeslot_tracker.clone(), groups);
 
         // Create mode-specific processor
         let (perf_to_timeslot, perf_to_trace) = match mode {
             ProcessorMode::Timeslot(timeslot_tx) => {
                 // Create timeslot composition processor
                 let perf_to_timeslot = BpfPerfToTimeslot::new(
-                    bpf_loader,
+                    dispatcher,
                     timeslot_tracker.clone(),
                     task_tracker.clone(),
                     timeslot_tx,
                 );
+                if let Some(ref recorder) = recorder {
+                    perf_to_timeslot.borrow_mut().set_recorder(recorder.clone());
+                }
                 (Some(perf_to_timeslot), None)
             }
             ProcessorMode::Trace(batch_tx) => {
                 // Create trace processor with default capacity of 1000 rows
                 let perf_to_trace = BpfPerfToTrace::new(
-                    bpf_loader,
+                    dispatcher,
                     task_tracker.clone(),
                     batch_tx,
                     32 * 1024, // Default batch capacity
                 );
+                perf_to_trace
+                    .borrow_mut()
+                    .set_ring_index_semantics(groups.ring_index_semantics.clone());
                 (None, Some(perf_to_trace))
             }
         };
 
-        let processor = Rc::new(RefCell::new(Self {
-            _timeslot_tracker: timeslot_tracker,
+        Rc::new(RefCell::new(Self {
+            timeslot_tracker,
             _error_handler: error_handler,
-            _task_tracker: task_tracker,
+            task_tracker,
             _perf_to_timeslot: perf_to_timeslot,
             _perf_to_trace: perf_to_trace,
-        }));
+            recorder,
+            pid_translator: None,
+        }))
+    }
+
+    // Snapshot of the timeslot tracker's progress, to persist across restarts
+    pub fn timeslot_snapshot(&self) -> TrackerState {
+        self.timeslot_tracker.borrow().snapshot()
+    }
 
-        processor
+    // Number of tasks in the task metadata map
+    pub fn task_metadata_count(&self) -> usize {
+        self.task_tracker.borrow().task_count()
+    }
+
+    // Continue timeslot tracking from a tracker restored at startup
+    pub fn restore_timeslot_tracker(&mut self, min_tracker: MinTracker) {
+        self.timeslot_tracker.borrow_mut().restore(min_tracker);
+    }
+
+    // Add container pids to the output of the active processor
+    pub fn set_pid_translator(&mut self, translator: Rc<RefCell<PidNamespaceTranslator>>) {
+        if let Some(ref timeslot_proc) = self._perf_to_timeslot {
+            timeslot_proc
+                .borrow_mut()
+                .set_pid_translator(translator.clone());
+        }
+        if let Some(ref trace_proc) = self._perf_to_trace {
+            trace_proc
+                .borrow_mut()
+                .set_pid_translator(translator.clone());
+        }
+        self.pid_translator = Some(translator);
+    }
+
+    // Restrict the active processor to tasks in the cgroups the filter allows
+    pub fn set_cgroup_filter(&mut self, filter: Rc<RefCell<CgroupFilter>>) {
+        if let Some(ref timeslot_proc) = self._perf_to_timeslot {
+            timeslot_proc.borrow_mut().set_cgroup_filter(filter.clone());
+        }
+        if let Some(ref trace_proc) = self._perf_to_trace {
+            trace_proc.borrow_mut().set_cgroup_filter(filter);
+        }
+    }
+
+    // Bound the batches and memory of trace mode
+    pub fn set_trace_limits(&mut self, limits: TraceLimits) {
+        if let Some(ref trace_proc) = self._perf_to_trace {
+            trace_proc.borrow_mut().set_limits(limits);
+        }
+    }
+
+    // Lay out and attribute trace rows to containers, in trace mode
+    pub fn set_trace_attribution(
+        &mut self,
+        layout: AttributionLayout,
+        containers: Option<ContainerTable>,
+    ) {
+        if let Some(ref trace_proc) = self._perf_to_trace {
+            trace_proc.borrow_mut().set_attribution(layout, containers);
+        }
+    }
+
+    // Mark timeslots completed while `stale` is set as having stale
+    // attribution, in timeslot mode
+    pub fn set_attribution_stale(&mut self, stale: Arc<AtomicBool>) {
+        if let Some(ref timeslot_proc) = self._perf_to_timeslot {
+            timeslot_proc.borrow_mut().set_attribution_stale(stale);
+        }
+    }
+
+    // Memory estimates of trace mode, None in timeslot mode
+    pub fn trace_memory(&self) -> Option<Arc<TraceMemory>> {
+        self._perf_to_trace
+            .as_ref()
+            .map(|trace_proc| trace_proc.borrow().memory())
+    }
+
+    // Apply the adaptive controller's mitigations to the active processor
+    pub fn apply_mitigations(&mut self, mitigations: &Mitigations) {
+        if let Some(ref timeslot_proc) = self._perf_to_timeslot {
+            timeslot_proc
+                .borrow_mut()
+                .set_slots_per_output(mitigations.slots_merged);
+        }
+        if let Some(ref trace_proc) = self._perf_to_trace {
+            trace_proc
+                .borrow_mut()
+                .set_enabled(mitigations.trace_enabled);
+        }
     }
 
     // Shutdown the processor and close all channels
@@ -92,5 +242,85 @@ impl PerfEventProcessor {
         if let Some(ref trace_proc) = self._perf_to_trace {
             trace_proc.borrow_mut().shutdown();
         }
+        if let Some(ref recorder) = self.recorder {
+            recorder.borrow_mut().flush();
+        }
+        if let Some(ref translator) = self.pid_translator {
+            translator.borrow_mut().shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bpf::msg_type;
+
+    fn groups(enabled: &[ProgramGroup]) -> BpfLoaderConfig {
+        BpfLoaderConfig {
+            enabled_groups: enabled.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_program_groups() {
+        use ProgramGroup::*;
+
+        // (enabled groups, trace mode, valid)
+        let cases: &[(&[ProgramGroup], bool, bool)] = &[
+            (&ProgramGroup::ALL, false, true),
+            (&ProgramGroup::ALL, true, true),
+            (&[Counters, SyncTimer], false, true),
+            (&[Counters, Sched, TaskLifecycle], false, false),
+            (&[Sched, TaskLifecycle, SyncTimer], false, false),
+            (&[], false, false),
+            (&[Sched], true, true),
+            (&[SyncTimer], true, true),
+            (&[Counters, TaskLifecycle], true, false),
+            (&[], true, false),
+        ];
+        for (enabled, trace, valid) in cases {
+            assert_eq!(
+                check_program_groups(&groups(enabled), *trace).is_ok(),
+                *valid,
+                "groups {:?}, trace {}",
+                enabled,
+                trace
+            );
+        }
+    }
+
+    #[test]
+    fn test_disabled_groups_not_subscribed() {
+        let subscribed = |enabled: &[ProgramGroup]| {
+            let (batch_tx, _batch_rx) = mpsc::channel(1);
+            let mut dispatcher = Dispatcher::new();
+            let _processor = PerfEventProcessor::with_dispatcher(
+                &mut dispatcher,
+                1,
+                ProcessorMode::Trace(batch_tx),
+                None,
+                &groups(enabled),
+            );
+            [
+                msg_type::MSG_TYPE_PERF_MEASUREMENT,
+                msg_type::MSG_TYPE_TASK_METADATA,
+                msg_type::MSG_TYPE_TASK_FREE,
+                msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING,
+                msg_type::MSG_TYPE_TIMER_MIGRATION_DETECTED,
+            ]
+            .map(|message_type| dispatcher.has_subscribers(message_type as u32))
+        };
+
+        assert_eq!(subscribed(&ProgramGroup::ALL), [true; 5]);
+        assert_eq!(
+            subscribed(&[ProgramGroup::Sched]),
+            [true, true, false, false, false]
+        );
+        assert_eq!(
+            subscribed(&[ProgramGroup::Sched, ProgramGroup::TaskLifecycle]),
+            [true, true, true, false, false]
+        );
     }
 }
@@ -0,0 +1,620 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+use bpf::{msg_type, subscribe_events, CollectorEvent};
+use perf_events::Dispatcher;
+
+/// Source of /proc/<pid> contents, replaceable in tests
+pub trait ProcReader: Send + Sync + 'static {
+    /// Contents of /proc/<pid>/status
+    fn read_status(&self, pid: u32) -> io::Result<String>;
+
+    /// Contents of /proc/<pid>/stat
+    fn read_stat(&self, pid: u32) -> io::Result<String>;
+}
+
+/// Reads the host's /proc
+pub struct FsProcReader;
+
+impl ProcReader for FsProcReader {
+    fn read_status(&self, pid: u32) -> io::Result<String> {
+        fs::read_to_string(format!("/proc/{}/status", pid))
+    }
+
+    fn read_stat(&self, pid: u32) -> io::Result<String> {
+        fs::read_to_string(format!("/proc/{}/stat", pid))
+    }
+}
+
+/// Parse the NSpid line of /proc/<pid>/status: the pid in each nested pid
+/// namespace, from the host namespace to the innermost one
+fn parse_nspid(status: &str) -> Option<Vec<u32>> {
+    let line = status.lines().find(|line| line.starts_with("NSpid:"))?;
+    line["NSpid:".len()..]
+        .split_whitespace()
+        .map(|pid| pid.parse().ok())
+        .collect()
+}
+
+/// Parse the process start time (field 22, in clock ticks after boot) from /proc/<pid>/stat
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // The command name may contain spaces, so split after its closing paren.
+    // The remainder starts at field 3.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Result of reading a process's namespace pids from /proc
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ReadOutcome {
+    /// The process identified by `start_time` has this innermost pid, or None
+    /// if it runs in the host pid namespace
+    Translated {
+        start_time: u64,
+        container_pid: Option<u32>,
+    },
+    /// The process exited before it could be read
+    Exited,
+    /// The pid was reused by another process while being read
+    Raced,
+}
+
+/// Read the innermost namespace pid of `pid`.
+///
+/// The start time is read before and after the status, so a pid that exits
+/// and is reused between the reads is detected rather than attributed to
+/// the wrong process.
+fn read_translation(reader: &impl ProcReader, pid: u32) -> ReadOutcome {
+    let read_start_time = || {
+        reader
+            .read_stat(pid)
+            .ok()
+            .and_then(|stat| parse_start_time(&stat))
+    };
+
+    let Some(start_time) = read_start_time() else {
+        return ReadOutcome::Exited;
+    };
+    let Some(nspids) = reader.read_status(pid).ok().and_then(|s| parse_nspid(&s)) else {
+        return ReadOutcome::Exited;
+    };
+    match read_start_time() {
+        Some(after) if after == start_time => {}
+        Some(_) => return ReadOutcome::Raced,
+        None => return ReadOutcome::Exited,
+    }
+
+    // A single entry means the process lives in the host namespace
+    let container_pid = match nspids.as_slice() {
+        [_host, .., innermost] => Some(*innermost),
+        _ => None,
+    };
+    ReadOutcome::Translated {
+        start_time,
+        container_pid,
+    }
+}
+
+/// Number of requests and answers that can be queued between the translator
+/// and its worker. Lookups beyond that are retried on the next sample.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Default number of pids whose translation is kept
+pub const DEFAULT_CACHE_CAPACITY: usize = 16384;
+
+/// Request for the worker to translate a host pid
+struct TranslationRequest {
+    pid: u32,
+    request_id: u64,
+}
+
+/// Worker's answer to a [`TranslationRequest`]
+struct TranslationResult {
+    pid: u32,
+    request_id: u64,
+    outcome: ReadOutcome,
+}
+
+/// Translates host pids to the pids seen inside their container.
+///
+/// Only tasks of the containers followed through NRI are looked up; callers
+/// filter samples by cgroup before asking for a translation.
+///
+/// Lookups never touch /proc: a pid seen for the first time is queued for
+/// the [`PidNamespaceWorker`] and reported as unknown until the worker's
+/// answer arrives. Translations are cached by host pid and process start
+/// time, so a reused pid is never given the previous process's translation.
+/// BPF reports of a freed task or a new task under the same pid drop which
+/// process holds the pid, and lost records drop it for every pid, so the
+/// next lookup reads the start time again.
+pub struct PidNamespaceTranslator {
+    // Innermost namespace pid by (host pid, start time)
+    cache: HashMap<(u32, u64), Option<u32>>,
+    // Start time of the process holding each host pid, or None if it exited
+    // before it could be read
+    holders: HashMap<u32, Option<u64>>,
+    capacity: usize,
+    // Pids with a read in flight, by the id of the current request
+    pending: HashMap<u32, u64>,
+    next_request_id: u64,
+    request_tx: Option<mpsc::Sender<TranslationRequest>>,
+    result_rx: mpsc::Receiver<TranslationResult>,
+}
+
+impl PidNamespaceTranslator {
+    /// Create a translator and the worker that performs its /proc reads
+    pub fn new<R: ProcReader>(reader: R) -> (Self, PidNamespaceWorker<R>) {
+        Self::with_capacity(reader, DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Like [`PidNamespaceTranslator::new`], keeping the translations of at
+    /// most `capacity` pids
+    pub fn with_capacity<R: ProcReader>(
+        reader: R,
+        capacity: usize,
+    ) -> (Self, PidNamespaceWorker<R>) {
+        let (request_tx, request_rx) = mpsc::channel(QUEUE_CAPACITY);
+        let (result_tx, result_rx) = mpsc::channel(QUEUE_CAPACITY);
+
+        let translator = Self {
+            cache: HashMap::new(),
+            holders: HashMap::new(),
+            capacity: capacity.max(1),
+            pending: HashMap::new(),
+            next_request_id: 0,
+            request_tx: Some(request_tx),
+            result_rx,
+        };
+        let worker = PidNamespaceWorker {
+            reader: Arc::new(reader),
+            request_rx,
+            result_tx,
+        };
+        (translator, worker)
+    }
+
+    /// Subscribe to task events to invalidate translations of exited tasks
+    pub fn attach(translator: &Rc<RefCell<Self>>, dispatcher: &mut Dispatcher) {
+        subscribe_events(
+            dispatcher,
+            msg_type::MSG_TYPE_TASK_METADATA,
+            translator.clone(),
+            Self::handle_event,
+        );
+        subscribe_events(
+            dispatcher,
+            msg_type::MSG_TYPE_TASK_FREE,
+            translator.clone(),
+            Self::handle_event,
+        );
+        let translator = translator.clone();
+        dispatcher.subscribe_lost_samples(move |_ring_index, _data| {
+            translator.borrow_mut().handle_lost_records()
+        });
+    }
+
+    /// Innermost namespace pid of `pid`, or None if it is not known yet or the
+    /// task is in the host pid namespace
+    pub fn container_pid(&mut self, pid: u32) -> Option<u32> {
+        self.apply_results();
+
+        if let Some(holder) = self.holders.get(&pid) {
+            return holder.and_then(|start_time| self.cache.get(&(pid, start_time)).copied()?);
+        }
+
+        // Pid 0 is the idle task, which has no /proc entry
+        if pid != 0 && !self.pending.contains_key(&pid) {
+            if let Some(ref request_tx) = self.request_tx {
+                let request_id = self.next_request_id;
+                self.next_request_id += 1;
+                // A full queue is retried on a later sample of the pid
+                if request_tx
+                    .try_send(TranslationRequest { pid, request_id })
+                    .is_ok()
+                {
+                    self.pending.insert(pid, request_id);
+                }
+            }
+        }
+        None
+    }
+
+    /// Stop requesting translations, letting the worker exit
+    pub fn shutdown(&mut self) {
+        self.request_tx.take();
+    }
+
+    /// Apply answers received from the worker
+    fn apply_results(&mut self) {
+        while let Ok(result) = self.result_rx.try_recv() {
+            // Ignore answers to requests made before the pid was invalidated
+            if self.pending.get(&result.pid) != Some(&result.request_id) {
+                continue;
+            }
+            self.pending.remove(&result.pid);
+
+            match result.outcome {
+                ReadOutcome::Translated {
+                    start_time,
+                    container_pid,
+                } => {
+                    self.make_room();
+                    self.cache.insert((result.pid, start_time), container_pid);
+                    self.holders.insert(result.pid, Some(start_time));
+                }
+                // Rows of an exited process stay untranslated; a new process
+                // reusing the pid triggers a new task metadata event
+                ReadOutcome::Exited => {
+                    self.make_room();
+                    self.holders.insert(result.pid, None);
+                }
+                // Retry on the next lookup
+                ReadOutcome::Raced => {}
+            }
+        }
+    }
+
+    /// Keep the cache within its capacity. Dropped translations are read
+    /// again on the next lookup of their pid.
+    fn make_room(&mut self) {
+        if self.holders.len() >= self.capacity || self.cache.len() >= self.capacity {
+            self.holders.clear();
+            self.cache.clear();
+        }
+    }
+
+    /// Forget which process holds a pid, and any answer still in flight for it
+    fn invalidate(&mut self, pid: u32) {
+        if let Some(Some(start_time)) = self.holders.remove(&pid) {
+            self.cache.remove(&(pid, start_time));
+        }
+        self.pending.remove(&pid);
+    }
+
+    /// The lost records may have held task events for any pid. Translations
+    /// stay cached by start time, and are used again once a new read shows
+    /// the same process still holds the pid.
+    fn handle_lost_records(&mut self) {
+        self.holders.clear();
+        self.pending.clear();
+    }
+
+    /// A new task may have taken over the pid, or the task holding it exited
+    fn handle_event(&mut self, _ring_index: usize, event: CollectorEvent<'_>) {
+        match event {
+            CollectorEvent::TaskMetadata(event) => self.invalidate(event.pid),
+            CollectorEvent::TaskFree(event) => self.invalidate(event.pid),
+            _ => {}
+        }
+    }
+}
+
+/// Worker task reading /proc for a [`PidNamespaceTranslator`], off the polling thread
+pub struct PidNamespaceWorker<R: ProcReader> {
+    reader: Arc<R>,
+    request_rx: mpsc::Receiver<TranslationRequest>,
+    result_tx: mpsc::Sender<TranslationResult>,
+}
+
+impl<R: ProcReader> PidNamespaceWorker<R> {
+    /// Run the worker until the translator shuts down
+    pub async fn run(mut self) -> Result<()> {
+        while let Some(request) = self.request_rx.recv().await {
+            // The reads block on procfs, so they run on the blocking pool
+            let reader = self.reader.clone();
+            let result =
+                tokio::task::spawn_blocking(move || Self::translate(&*reader, request)).await?;
+            // The translator may already be gone during shutdown
+            if self.result_tx.send(result).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn translate(reader: &R, request: TranslationRequest) -> TranslationResult {
+        TranslationResult {
+            pid: request.pid,
+            request_id: request.request_id,
+            outcome: read_translation(reader, request.pid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// (start time, NSpid chain) of each fake process
+    type FakeProcesses = HashMap<u32, (u64, Vec<u32>)>;
+
+    /// Fake /proc shared between a test and the worker
+    #[derive(Clone, Default)]
+    struct FakeProc {
+        processes: Arc<Mutex<FakeProcesses>>,
+    }
+
+    impl FakeProc {
+        fn spawn(&self, pid: u32, start_time: u64, nspids: &[u32]) {
+            self.processes
+                .lock()
+                .unwrap()
+                .insert(pid, (start_time, nspids.to_vec()));
+        }
+
+        fn exit(&self, pid: u32) {
+            self.processes.lock().unwrap().remove(&pid);
+        }
+
+        fn get(&self, pid: u32) -> io::Result<(u64, Vec<u32>)> {
+            self.processes
+                .lock()
+                .unwrap()
+                .get(&pid)
+                .cloned()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
+    impl ProcReader for FakeProc {
+        fn read_status(&self, pid: u32) -> io::Result<String> {
+            let (_, nspids) = self.get(pid)?;
+            let nspids: Vec<String> = nspids.iter().map(|p| p.to_string()).collect();
+            Ok(format!(
+                "Name:\tfake\nTgid:\t{}\nNgid:\t0\nPid:\t{}\nNSpid:\t{}\n",
+                pid,
+                pid,
+                nspids.join("\t")
+            ))
+        }
+
+        fn read_stat(&self, pid: u32) -> io::Result<String> {
+            let (start_time, _) = self.get(pid)?;
+            let mut fields = vec!["0"; 18];
+            let start_time = start_time.to_string();
+            fields.push(&start_time);
+            Ok(format!("{} (fake proc) S {} 0 0", pid, fields.join(" ")))
+        }
+    }
+
+    /// Process every request the translator has queued
+    fn run_worker(worker: &mut PidNamespaceWorker<FakeProc>) {
+        while let Ok(request) = worker.request_rx.try_recv() {
+            let result = PidNamespaceWorker::translate(&*worker.reader, request);
+            worker.result_tx.try_send(result).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_nspid() {
+        let status = "Name:\tbash\nPid:\t4242\nNSpid:\t4242\nPPid:\t1\n";
+        assert_eq!(parse_nspid(status), Some(vec![4242]));
+
+        // Container inside a container
+        let status = "Name:\tnginx\nNSpid:\t4242\t87\t1\n";
+        assert_eq!(parse_nspid(status), Some(vec![4242, 87, 1]));
+
+        assert_eq!(parse_nspid("Name:\tbash\n"), None);
+        assert_eq!(parse_nspid("NSpid:\t12\tx\n"), None);
+    }
+
+    #[test]
+    fn test_parse_start_time() {
+        let fake = FakeProc::default();
+        fake.spawn(7, 123456, &[7]);
+        let stat = fake.read_stat(7).unwrap();
+        assert_eq!(parse_start_time(&stat), Some(123456));
+        assert_eq!(parse_start_time("7 (a) b) S 1"), None);
+    }
+
+    #[test]
+    fn test_read_translation() {
+        let fake = FakeProc::default();
+        fake.spawn(100, 5, &[100]);
+        fake.spawn(200, 6, &[200, 12]);
+        fake.spawn(300, 7, &[300, 40, 3]);
+
+        assert_eq!(
+            read_translation(&fake, 100),
+            ReadOutcome::Translated {
+                start_time: 5,
+                container_pid: None
+            }
+        );
+        assert_eq!(
+            read_translation(&fake, 200),
+            ReadOutcome::Translated {
+                start_time: 6,
+                container_pid: Some(12)
+            }
+        );
+        assert_eq!(
+            read_translation(&fake, 300),
+            ReadOutcome::Translated {
+                start_time: 7,
+                container_pid: Some(3)
+            }
+        );
+        assert_eq!(read_translation(&fake, 400), ReadOutcome::Exited);
+    }
+
+    #[test]
+    fn test_pid_reused_during_read() {
+        /// Reports a different start time on each stat read
+        struct Reusing(Mutex<u64>);
+
+        impl ProcReader for Reusing {
+            fn read_status(&self, _pid: u32) -> io::Result<String> {
+                Ok("NSpid:\t9\t1\n".to_string())
+            }
+
+            fn read_stat(&self, pid: u32) -> io::Result<String> {
+                let mut start_time = self.0.lock().unwrap();
+                *start_time += 1;
+                let fields = vec!["0"; 18].join(" ");
+                Ok(format!("{} (x) S {} {}", pid, fields, start_time))
+            }
+        }
+
+        assert_eq!(
+            read_translation(&Reusing(Mutex::new(0)), 9),
+            ReadOutcome::Raced
+        );
+    }
+
+    #[test]
+    fn test_lookup_is_cached() {
+        let fake = FakeProc::default();
+        fake.spawn(200, 6, &[200, 12]);
+        let (mut translator, mut worker) = PidNamespaceTranslator::new(fake.clone());
+
+        // Unknown until the worker answers, and requested only once
+        assert_eq!(translator.container_pid(200), None);
+        assert_eq!(translator.container_pid(200), None);
+        assert_eq!(worker.request_rx.len(), 1);
+        run_worker(&mut worker);
+
+        assert_eq!(translator.container_pid(200), Some(12));
+
+        // Served from the cache, even once /proc changes
+        fake.exit(200);
+        assert_eq!(translator.container_pid(200), Some(12));
+        assert!(worker.request_rx.is_empty());
+
+        // The idle task is never looked up
+        assert_eq!(translator.container_pid(0), None);
+        assert!(worker.request_rx.is_empty());
+    }
+
+    #[test]
+    fn test_exit_and_pid_reuse() {
+        let fake = FakeProc::default();
+        let (mut translator, mut worker) = PidNamespaceTranslator::new(fake.clone());
+
+        // The process exits before the worker reads it
+        fake.spawn(300, 7, &[300, 5]);
+        assert_eq!(translator.container_pid(300), None);
+        fake.exit(300);
+        run_worker(&mut worker);
+        assert_eq!(translator.container_pid(300), None);
+        assert!(worker.request_rx.is_empty());
+
+        // A new process reuses the pid; the new task invalidates the entry
+        fake.spawn(300, 9, &[300, 77]);
+        translator.invalidate(300);
+        assert_eq!(translator.container_pid(300), None);
+        run_worker(&mut worker);
+        assert_eq!(translator.container_pid(300), Some(77));
+    }
+
+    #[test]
+    fn test_stale_answer_is_ignored() {
+        let fake = FakeProc::default();
+        fake.spawn(400, 1, &[400, 2]);
+        let (mut translator, mut worker) = PidNamespaceTranslator::new(fake.clone());
+
+        assert_eq!(translator.container_pid(400), None);
+        run_worker(&mut worker);
+
+        // The task is freed before the answer is applied
+        translator.invalidate(400);
+        fake.spawn(400, 3, &[400]);
+        assert_eq!(translator.container_pid(400), None);
+        run_worker(&mut worker);
+
+        // Only the answer for the new process is used
+        assert_eq!(translator.container_pid(400), None);
+        assert_eq!(translator.holders.get(&400), Some(&Some(3)));
+        assert_eq!(translator.cache.get(&(400, 1)), None);
+        assert_eq!(translator.cache.get(&(400, 3)), Some(&None));
+    }
+
+    #[test]
+    fn test_reuse_after_lost_records() {
+        let fake = FakeProc::default();
+        fake.spawn(500, 1, &[500, 2]);
+        let (mut translator, mut worker) = PidNamespaceTranslator::new(fake.clone());
+
+        assert_eq!(translator.container_pid(500), None);
+        run_worker(&mut worker);
+        assert_eq!(translator.container_pid(500), Some(2));
+
+        // The pid is reused, and the task events saying so are lost
+        fake.exit(500);
+        fake.spawn(500, 4, &[500, 8]);
+        translator.handle_lost_records();
+        assert_eq!(translator.container_pid(500), None);
+        run_worker(&mut worker);
+        assert_eq!(translator.container_pid(500), Some(8));
+
+        // The earlier process is still cached by its own start time
+        assert_eq!(translator.cache.get(&(500, 1)), Some(&Some(2)));
+    }
+
+    #[test]
+    fn test_cache_is_bounded() {
+        let fake = FakeProc::default();
+        for pid in 1..=10 {
+            fake.spawn(pid, 1, &[pid, 1]);
+        }
+        let (mut translator, mut worker) = PidNamespaceTranslator::with_capacity(fake.clone(), 4);
+
+        for pid in 1..=10 {
+            translator.container_pid(pid);
+            run_worker(&mut worker);
+            assert_eq!(translator.container_pid(pid), Some(1));
+            assert!(translator.holders.len() <= 4);
+            assert!(translator.cache.len() <= 4);
+        }
+    }
+
+    #[test]
+    fn test_request_queue_is_bounded() {
+        let (mut translator, worker) = PidNamespaceTranslator::new(FakeProc::default());
+
+        for pid in 1..=2 * QUEUE_CAPACITY as u32 {
+            assert_eq!(translator.container_pid(pid), None);
+        }
+        assert_eq!(worker.request_rx.len(), QUEUE_CAPACITY);
+        assert_eq!(translator.pending.len(), QUEUE_CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn test_worker_answers() {
+        let fake = FakeProc::default();
+        fake.spawn(600, 1, &[600, 3]);
+        let (mut translator, worker) = PidNamespaceTranslator::new(fake);
+        let handle = tokio::spawn(worker.run());
+
+        assert_eq!(translator.container_pid(600), None);
+        let mut container_pid = None;
+        for _ in 0..100 {
+            container_pid = translator.container_pid(600);
+            if container_pid.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(container_pid, Some(3));
+
+        translator.shutdown();
+        assert!(handle.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_worker_exits_on_shutdown() {
+        let (mut translator, worker) = PidNamespaceTranslator::new(FakeProc::default());
+        let handle = tokio::spawn(worker.run());
+        translator.shutdown();
+        assert!(handle.await.unwrap().is_ok());
+        assert_eq!(translator.container_pid(1), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! The stages turning processor output into record batches.
+//!
+//! [`Pipeline::build`] spawns the conversion task of the selected output and
+//! returns the [`ProcessorMode`] the processor feeds, along with the receiver
+//! the batches come out of. [`crate::Collector`] streams them.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use arrow_array::RecordBatch;
+use arrow_schema::SchemaRef;
+use tokio::sync::mpsc;
+use tokio_util::task::TaskTracker;
+
+use crate::attribution::AttributionLayout;
+use crate::cgroup_filter::ContainerTable;
+use crate::cgroup_rollup_task::CgroupRollupTask;
+use crate::cpu_throttle::{CpuThrottleSampler, CpuThrottleWorker};
+use crate::debug_endpoint::ChannelProbe;
+use crate::noisy_neighbor::{ContainerScore, NoisyNeighborConfig, NoisyNeighborScorer};
+use crate::perf_event_processor::ProcessorMode;
+use crate::shutdown::ShutdownToken;
+use crate::task_completion_handler::task_completion_handler;
+use crate::timeslot_data::TimeslotData;
+use crate::timeslot_to_recordbatch_task::{PartialTimeslotPolicy, TimeslotToRecordBatchTask};
+
+/// Capacity of the timeslot and record batch channels
+const CHANNEL_CAPACITY: usize = 1000;
+
+/// What the pipeline outputs, and the optional stages of timeslot output
+#[derive(Default)]
+pub struct PipelineConfig {
+    /// Output individual events instead of aggregated timeslots
+    pub trace: bool,
+    /// Output a row per cgroup and timeslot instead of a row per task
+    pub cgroup_rollup: bool,
+    /// Shards converting each timeslot to a record batch, 0 for one
+    pub conversion_threads: usize,
+    /// Whether the first timeslot is marked partial or dropped
+    pub partial_timeslots: PartialTimeslotPolicy,
+    /// Adds the throttling of container cgroups to timeslot rows
+    pub throttle_sampler: Option<CpuThrottleSampler>,
+    /// Interval between throttling samples
+    pub throttle_interval: Duration,
+    /// Attributes timeslot and trace rows to the collected containers, and
+    /// adds their resource limits to timeslot rows
+    pub containers: Option<ContainerTable>,
+    /// Layout of the container identity in timeslot and trace rows
+    pub attribution: AttributionLayout,
+    /// Marks the rows of timeslots completed while set as having stale
+    /// attribution
+    pub attribution_stale: Option<Arc<AtomicBool>>,
+    /// Scores containers by how much they disturb their neighbors
+    pub noisy_neighbors: Option<NoisyNeighborConfig>,
+}
+
+/// A running pipeline, waiting for the processor's output
+pub struct Pipeline {
+    /// Where the processor sends its output
+    pub(crate) processor_mode: ProcessorMode,
+    /// Schema of the batches the pipeline produces
+    pub schema: SchemaRef,
+    /// Number of timeslots converted, in timeslot modes
+    pub timeslot_counter: Option<Arc<AtomicUsize>>,
+    /// Number of batches lost because their consumer stopped, in timeslot modes
+    pub dropped_batches: Option<Arc<AtomicUsize>>,
+    /// Highest noisy neighbor scores, if scoring is enabled
+    pub noisy_neighbors: Option<Arc<Mutex<Vec<ContainerScore>>>>,
+    /// Probes of the pipeline's channels for the debug endpoint
+    pub(crate) channel_probes: Vec<ChannelProbe>,
+    /// Layout of the container identity in trace rows, and the containers
+    /// they are attributed to
+    pub(crate) trace_attribution: (AttributionLayout, Option<ContainerTable>),
+    /// Flag the processor samples as each timeslot completes, in timeslot mode
+    pub(crate) attribution_stale: Option<Arc<AtomicBool>>,
+}
+
+impl Pipeline {
+    /// Spawn the pipeline's tasks on `task_tracker`, returning the pipeline
+    /// and the receiver of its batches. The tasks stop once the processor
+    /// drops its sender.
+    pub(crate) fn build(
+        config: PipelineConfig,
+        task_tracker: &TaskTracker,
+        shutdown_token: &ShutdownToken,
+    ) -> Result<(Self, mpsc::Receiver<RecordBatch>)> {
+        let (batch_sender, batch_receiver) = mpsc::channel::<RecordBatch>(CHANNEL_CAPACITY);
+        let mut channel_probes = vec![ChannelProbe::new("record_batches", &batch_sender)];
+
+        if config.trace {
+            // Trace mode: direct RecordBatch output
+            let pipeline = Self {
+                processor_mode: ProcessorMode::Trace(batch_sender),
+                schema: crate::bpf_perf_to_trace::create_schema(config.attribution),
+                timeslot_counter: None,
+                dropped_batches: None,
+                noisy_neighbors: None,
+                channel_probes,
+                trace_attribution: (config.attribution, config.containers),
+                attribution_stale: None,
+            };
+            return Ok((pipeline, batch_receiver));
+        }
+
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel::<TimeslotData>(CHANNEL_CAPACITY);
+        channel_probes.push(ChannelProbe::new("timeslots", &timeslot_sender));
+
+        let pipeline = if config.cgroup_rollup {
+            // Rollup mode: timeslots summed per cgroup
+            let mut rollup_task = CgroupRollupTask::new(timeslot_receiver, batch_sender);
+            if let Some(containers) = config.containers {
+                rollup_task.set_containers(containers);
+            }
+            let pipeline = Self {
+                processor_mode: ProcessorMode::Timeslot(timeslot_sender),
+                schema: rollup_task.schema(),
+                timeslot_counter: Some(rollup_task.timeslot_counter()),
+                dropped_batches: Some(rollup_task.dropped_batch_counter()),
+                noisy_neighbors: None,
+                channel_probes,
+                trace_attribution: Default::default(),
+                attribution_stale: None,
+            };
+            task_tracker.spawn(task_completion_handler(
+                rollup_task.run(),
+                shutdown_token.clone(),
+                "CgroupRollupTask",
+            ));
+            pipeline
+        } else {
+            // Timeslot mode: aggregated output with conversion
+            let mut conversion_task =
+                TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
+            conversion_task.set_parallelism(config.conversion_threads);
+            conversion_task.set_partial_policy(config.partial_timeslots);
+            if let Some(sampler) = config.throttle_sampler {
+                // Sampled on a worker of its own, off the conversion
+                let (worker, throttling) =
+                    CpuThrottleWorker::new(sampler, config.throttle_interval);
+                task_tracker.spawn(task_completion_handler(
+                    worker.run(shutdown_token.clone()),
+                    shutdown_token.clone(),
+                    "CpuThrottleWorker",
+                ));
+                conversion_task.set_throttling(throttling);
+            }
+            conversion_task.set_attribution_layout(config.attribution);
+            if let Some(containers) = config.containers {
+                conversion_task.set_containers(containers);
+            }
+            let noisy_neighbors = config.noisy_neighbors.map(|scorer_config| {
+                let scorer = NoisyNeighborScorer::new(scorer_config);
+                let top_scores = scorer.top_scores();
+                conversion_task.set_scorer(scorer);
+                top_scores
+            });
+            let pipeline = Self {
+                processor_mode: ProcessorMode::Timeslot(timeslot_sender),
+                schema: conversion_task.schema(),
+                timeslot_counter: Some(conversion_task.timeslot_counter()),
+                dropped_batches: Some(conversion_task.dropped_batch_counter()),
+                noisy_neighbors,
+                channel_probes,
+                trace_attribution: Default::default(),
+                attribution_stale: config.attribution_stale,
+            };
+            task_tracker.spawn(task_completion_handler(
+                conversion_task.run(),
+                shutdown_token.clone(),
+                "TimeslotToRecordBatchTask",
+            ));
+            pipeline
+        };
+
+        Ok((pipeline, batch_receiver))
+    }
+}
@@ -0,0 +1,604 @@
+//! The collector's effective configuration, resolved without running it.
+//!
+//! A [`Plan`] holds the settings every stage is built from: the writers of
+//! each output and where their files go, the transforms of the main output,
+//! the BPF program groups to load, quotas and rotation. Building one loads
+//! no BPF programs, opens no perf events and creates no storage, so
+//! `--dry-run` can print it as JSON for any host, and `main` builds the
+//! same plan to set the collector up. Every option is checked before an
+//! error is returned, so a dry run reports all the problems at once.
+//! `--plan-probe` adds the host probes and preflight verdict to the output.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use bpf::{BpfLoaderConfig, ProgramGroup};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::batch_transform::TransformChain;
+use crate::capabilities::{SystemProbes, COUNTERS};
+use crate::container_events::{create_container_events_schema, CONTAINER_EVENTS_SCHEMA_VERSION};
+use crate::disk_guard::DiskGuardConfig;
+use crate::memory_store::MEMORY_STORAGE_TYPE;
+use crate::noisy_neighbor::NoisyNeighborConfig;
+use crate::parquet_writer::ParquetWriterConfig;
+use crate::preflight::Preflight;
+use crate::schema_dump::{output_schema, schema_json};
+use crate::storage_quota::{QuotaHandle, QuotaManager, QuotaStream, StreamQuota};
+use crate::timeslot_to_recordbatch_task::default_conversion_parallelism;
+use crate::window_rollup::{WindowRollup, WindowRollupConfig, WINDOW_ROLLUP_SCHEMA_VERSION};
+use crate::{
+    build_transforms, noisy_neighbor_config, output_name, writes_container_events, Command,
+};
+use collector::check_program_groups;
+
+/// Settings the collector's stages are built from
+pub struct Plan {
+    /// Name of the main output
+    pub output: &'static str,
+    /// Schema version of the main output, before transforms
+    pub schema_version: u32,
+    /// Writer of the main output
+    pub writer: ParquetWriterConfig,
+    /// Transforms applied to the main output before writing
+    pub transforms: TransformChain,
+    /// BPF program groups to load
+    pub bpf: BpfLoaderConfig,
+    /// Shards converting each timeslot to a record batch
+    pub conversion_threads: usize,
+    /// Noisy neighbor scoring, with --noisy-neighbor-scores
+    pub noisy_neighbors: Option<NoisyNeighborConfig>,
+    /// Window rollup and the writer of its files, with --window-rollup
+    pub window_rollup: Option<(WindowRollupConfig, ParquetWriterConfig)>,
+    /// Writer of container lifecycle events, with --cgroup-filter
+    pub container_events: Option<ParquetWriterConfig>,
+    /// Free space thresholds, with local storage
+    pub disk_guard: Option<DiskGuardConfig>,
+    /// The storage quota shared by the writers of every output
+    pub quota: Arc<QuotaManager>,
+}
+
+/// Add `stream` to `quota` with its --storage-quota-reserve and
+/// --storage-quota-weight settings, and `limit` bytes at most
+fn register_stream(
+    quota: &Arc<QuotaManager>,
+    opts: &Command,
+    stream: QuotaStream,
+    limit: Option<usize>,
+) -> Result<QuotaHandle> {
+    let defaults = StreamQuota::default();
+    quota.register(
+        stream,
+        StreamQuota {
+            reserved: stream_setting(&opts.storage_quota_reserve, stream)
+                .unwrap_or(defaults.reserved),
+            weight: stream_setting(&opts.storage_quota_weight, stream).unwrap_or(defaults.weight),
+            limit,
+        },
+    )
+}
+
+/// The last value given for `stream` in `settings`
+fn stream_setting<T: Copy>(settings: &[(QuotaStream, T)], stream: QuotaStream) -> Option<T> {
+    settings
+        .iter()
+        .rev()
+        .find(|(configured, _)| *configured == stream)
+        .map(|(_, value)| *value)
+}
+
+/// Keep the value of `result`, or record its error in `problems`
+fn check<T>(problems: &mut Vec<String>, result: Result<T>) -> Option<T> {
+    result.map_err(|e| problems.push(format!("{:#}", e))).ok()
+}
+
+impl Plan {
+    /// Resolve the settings for `opts` on a node named `node_id` with
+    /// `num_cpus` possible CPUs. The error lists every problem found.
+    pub fn new(opts: &Command, node_id: &str, num_cpus: usize) -> Result<Self> {
+        let mut problems = Vec::new();
+
+        // Every output's files go under the node's prefix
+        let storage_prefix = format!("{}{}", opts.prefix, node_id);
+        let cpu_metadata = vec![parquet::file::metadata::KeyValue {
+            key: "num_cpus".to_string(),
+            value: Some(num_cpus.to_string()),
+        }];
+
+        // Every output's writer counts against the global quota
+        let quota = QuotaManager::new(opts.storage_quota);
+        let main_stream = if opts.trace {
+            QuotaStream::Trace
+        } else {
+            QuotaStream::Timeslots
+        };
+        let main_quota = register_stream(&quota, opts, main_stream, None);
+        let main_quota = check(&mut problems, main_quota);
+
+        let writer = ParquetWriterConfig::builder()
+            .storage_prefix(storage_prefix.clone())
+            .buffer_size(opts.parquet_buffer_size)
+            .file_size_limit(opts.parquet_file_size)
+            .max_row_group_size(opts.max_row_group_size)
+            .quota(main_quota)
+            .key_value_metadata(cpu_metadata.clone())
+            .writer_version(opts.parquet_writer_version)
+            .timestamp_column(if opts.trace {
+                "timestamp"
+            } else {
+                "start_time"
+            })
+            .multipart_part_size(opts.parquet_part_size)
+            .multipart_max_concurrency(opts.parquet_upload_concurrency)
+            .background_close(opts.parquet_background_close)
+            .build()
+            .context("Invalid parquet writer settings");
+        let writer = check(&mut problems, writer);
+
+        // Load only the BPF programs asked for, if the output mode can do without the rest
+        let bpf = BpfLoaderConfig {
+            enabled_groups: opts.bpf_program_groups.clone(),
+            ..Default::default()
+        };
+        check(&mut problems, check_program_groups(&bpf, opts.trace));
+
+        let (schema, schema_version) = output_schema(opts);
+        let transforms = build_transforms(opts, schema, bpf.is_enabled(ProgramGroup::Counters));
+        let transforms = check(&mut problems, transforms);
+
+        let noisy_neighbors = opts
+            .noisy_neighbor_scores
+            .then(|| noisy_neighbor_config(opts))
+            .transpose();
+        let noisy_neighbors = check(&mut problems, noisy_neighbors);
+
+        let window_rollup = opts
+            .window_rollup
+            .then(|| {
+                let config = WindowRollupConfig {
+                    window: std::time::Duration::from_secs(opts.window_rollup_secs),
+                    clock_offset_ns: None,
+                };
+                WindowRollup::new(config.clone()).context("Invalid --window-rollup-secs")?;
+                let writer = ParquetWriterConfig::builder()
+                    .storage_prefix(format!("{}window-rollup-", storage_prefix))
+                    .buffer_size(opts.parquet_buffer_size.min(opts.window_rollup_file_size))
+                    .file_size_limit(opts.window_rollup_file_size)
+                    .quota(Some(register_stream(
+                        &quota,
+                        opts,
+                        QuotaStream::WindowRollup,
+                        opts.window_rollup_storage_quota,
+                    )?))
+                    .key_value_metadata(cpu_metadata)
+                    .writer_version(opts.parquet_writer_version)
+                    .timestamp_column("window_start")
+                    .build()
+                    .context("Invalid window rollup writer settings")?;
+                Ok((config, writer))
+            })
+            .transpose();
+        let window_rollup = check(&mut problems, window_rollup);
+
+        let writes_container_events = writes_container_events(opts);
+        let container_events = writes_container_events
+            .then(|| {
+                ParquetWriterConfig::builder()
+                    .storage_prefix(format!("{}container-events-", storage_prefix))
+                    .quota(Some(register_stream(
+                        &quota,
+                        opts,
+                        QuotaStream::ContainerEvents,
+                        None,
+                    )?))
+                    .writer_version(opts.parquet_writer_version)
+                    .timestamp_column("timestamp")
+                    .build()
+                    .context("Invalid container events writer settings")
+            })
+            .transpose();
+        let container_events = check(&mut problems, container_events);
+
+        // Settings for outputs this run does not write are likely mistakes
+        let written = [
+            (main_stream, true),
+            (QuotaStream::WindowRollup, opts.window_rollup),
+            (QuotaStream::ContainerEvents, writes_container_events),
+        ];
+        let unwritten: BTreeSet<QuotaStream> = opts
+            .storage_quota_reserve
+            .iter()
+            .map(|(stream, _)| *stream)
+            .chain(opts.storage_quota_weight.iter().map(|(stream, _)| *stream))
+            .filter(|stream| !written.contains(&(*stream, true)))
+            .collect();
+        for stream in unwritten {
+            problems.push(format!(
+                "Storage quota settings of {}, which this run does not write",
+                stream.name()
+            ));
+        }
+
+        let storage_type = opts.storage_type.to_lowercase();
+        if opts.memory_dump_dir.is_some() && storage_type != MEMORY_STORAGE_TYPE {
+            problems.push(format!(
+                "--memory-dump-dir requires --storage-type {}",
+                MEMORY_STORAGE_TYPE
+            ));
+        }
+
+        // Any storage type other than s3 and memory is local, see create_object_storage
+        let local_storage = storage_type != "s3" && storage_type != MEMORY_STORAGE_TYPE;
+        let disk_guard = local_storage.then(|| DiskGuardConfig {
+            min_free_mb: opts.disk_min_free_mb,
+            min_free_percent: opts.disk_min_free_percent,
+            recovery_free_mb: opts.disk_recovery_free_mb,
+            recovery_free_percent: opts.disk_recovery_free_percent,
+            reclaim_own_files: opts.reclaim_own_files,
+            ..Default::default()
+        });
+
+        match (
+            writer,
+            transforms,
+            noisy_neighbors,
+            window_rollup,
+            container_events,
+        ) {
+            (
+                Some(writer),
+                Some(transforms),
+                Some(noisy_neighbors),
+                Some(window_rollup),
+                Some(container_events),
+            ) if problems.is_empty() => Ok(Self {
+                output: output_name(opts),
+                schema_version,
+                writer,
+                transforms,
+                bpf,
+                conversion_threads: opts
+                    .conversion_threads
+                    .unwrap_or_else(|| default_conversion_parallelism(num_cpus)),
+                noisy_neighbors,
+                window_rollup,
+                container_events,
+                disk_guard,
+                quota,
+            }),
+            _ => Err(anyhow!(
+                "Invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            )),
+        }
+    }
+
+    /// The JSON description of the plan printed by `--dry-run`
+    pub fn to_json(&self, opts: &Command) -> Value {
+        let mut outputs = vec![output_json(
+            schema_json(
+                &self.transforms.output_schema(),
+                self.output,
+                self.schema_version,
+            ),
+            &self.writer,
+            true,
+        )];
+        outputs[0]["transforms"] = json!(self.transforms.names());
+        if let Some((config, writer)) = &self.window_rollup {
+            let mut output = output_json(
+                schema_json(
+                    &crate::window_rollup::create_window_rollup_schema(),
+                    "window_rollup",
+                    WINDOW_ROLLUP_SCHEMA_VERSION,
+                ),
+                writer,
+                false,
+            );
+            output["window_secs"] = json!(config.window.as_secs());
+            outputs.push(output);
+        }
+        if let Some(writer) = &self.container_events {
+            outputs.push(output_json(
+                schema_json(
+                    &create_container_events_schema(),
+                    "container_events",
+                    CONTAINER_EVENTS_SCHEMA_VERSION,
+                ),
+                writer,
+                false,
+            ));
+        }
+
+        let program_groups: Vec<Value> = ProgramGroup::ALL
+            .iter()
+            .map(|&group| {
+                json!({
+                    "name": group.name(),
+                    "enabled": self.bpf.is_enabled(group),
+                    "programs": group.programs(),
+                })
+            })
+            .collect();
+        let counters: Vec<&str> = if self.bpf.is_enabled(ProgramGroup::Counters) {
+            COUNTERS.iter().map(|(name, _)| *name).collect()
+        } else {
+            Vec::new()
+        };
+
+        json!({
+            "outputs": outputs,
+            "storage": {
+                "type": opts.storage_type,
+                "quota": self.quota.global(),
+                "disk_guard": self.disk_guard.as_ref().map(|config| json!({
+                    "min_free_mb": config.min_free_mb,
+                    "min_free_percent": config.min_free_percent,
+                    "recovery_free_mb": config.recovery_free_mb,
+                    "recovery_free_percent": config.recovery_free_percent,
+                    "reclaim_own_files": config.reclaim_own_files,
+                    "check_interval_secs": config.check_interval.as_secs(),
+                })),
+            },
+            "bpf": {
+                "program_groups": program_groups,
+                "counters": counters,
+            },
+            "pipeline": {
+                "conversion_threads": self.conversion_threads,
+                "partial_timeslots": (self.output == "timeslot").then(|| {
+                    opts.partial_timeslots.to_possible_value().unwrap().get_name().to_string()
+                }),
+                "translate_pid_ns": opts.translate_pid_ns,
+                "cgroup_throttling": opts.cgroup_throttling,
+                "cgroup_filter": opts.cgroup_filter.as_ref().map(|selector| json!({
+                    "namespace": selector.namespace,
+                    "pod": selector.pod,
+                    "labels": selector.labels,
+                })),
+                "on_nri_shutdown": opts.cgroup_filter.is_some().then(|| {
+                    opts.on_nri_shutdown.to_possible_value().unwrap().get_name().to_string()
+                }),
+                "noisy_neighbors": self.noisy_neighbors.as_ref().map(|config| json!({
+                    "weights": [
+                        config.weights.llc_misses,
+                        config.weights.cycles,
+                        config.weights.throttling,
+                    ],
+                    "half_life_slots": config.half_life_slots,
+                    "top_k": config.top_k,
+                })),
+                "adaptive": opts.adaptive,
+                "adaptive_poll": opts.adaptive_poll,
+                "duration_secs": opts.duration,
+            },
+        })
+    }
+}
+
+/// The JSON description of an output: its schema, where its files go, and
+/// how they are rotated and capped
+fn output_json(schema: Value, writer: &ParquetWriterConfig, rotates_on_sigusr1: bool) -> Value {
+    let mut output = schema;
+    output["storage_prefix"] = json!(writer.storage_prefix);
+    output["timestamp_column"] = json!(writer.timestamp_column);
+    output["writer_version"] = json!(writer.writer_version.as_num());
+    output["buffer_size"] = json!(writer.buffer_size);
+    output["max_row_group_size"] = json!(writer.max_row_group_size);
+    output["storage_quota"] = json!(writer.storage_quota);
+    output["quota"] = json!(writer.quota.as_ref().map(|quota| {
+        let settings = quota.settings();
+        json!({
+            "stream": quota.stream().name(),
+            "reserved": settings.reserved,
+            "weight": settings.weight,
+        })
+    }));
+    output["rotation"] = json!({
+        "file_size_limit": writer.file_size_limit,
+        "on_sigusr1": rotates_on_sigusr1,
+    });
+    output
+}
+
+/// The host probes and the preflight verdict, for `--plan-probe`
+pub fn probe_json(probes: &SystemProbes, preflight: &Preflight) -> Value {
+    json!({
+        "num_cpus": probes.num_cpus,
+        "kernel_release": probes.kernel_release,
+        "counters": probes
+            .counters
+            .iter()
+            .map(|(name, result)| (name.to_string(), json!(result.as_str())))
+            .collect::<serde_json::Map<_, _>>(),
+        "preflight": {
+            "problems": preflight
+                .problems()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            "would_load": preflight.exit_code().is_none(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::path::Path;
+
+    /// Golden plans of representative option sets, by file name
+    const CASES: [(&str, &[&str]); 7] = [
+        ("default.json", &[]),
+        (
+            "trace_s3.json",
+            &[
+                "--trace",
+                "--storage-type",
+                "s3",
+                "--bpf-program-groups",
+                "sched,task_lifecycle,sync_timer",
+            ],
+        ),
+        (
+            "cgroup_rollup_quota.json",
+            &[
+                "--cgroup-rollup",
+                "--storage-quota",
+                "1000000000",
+                "--parquet-file-size",
+                "268435456",
+                "--drop-columns",
+                "cache_references",
+            ],
+        ),
+        (
+            "window_rollup_redacted.json",
+            &[
+                "--window-rollup",
+                "--window-rollup-secs",
+                "300",
+                "--window-rollup-storage-quota",
+                "50000000",
+                "--redact",
+                "comm",
+                "--flat-attribution",
+                "--noisy-neighbor-scores",
+            ],
+        ),
+        (
+            "cgroup_filter.json",
+            &[
+                "--cgroup-filter",
+                "namespace=prod,label.app=web",
+                "--parquet-writer-version",
+                "2.0",
+                "--reclaim-own-files",
+            ],
+        ),
+        (
+            "shared_quota.json",
+            &[
+                "--window-rollup",
+                "--storage-quota",
+                "1000000000",
+                "--storage-quota-reserve",
+                "window-rollup=100000000",
+                "--storage-quota-weight",
+                "timeslots=4",
+            ],
+        ),
+        (
+            "memory_storage.json",
+            &[
+                "--storage-type",
+                "memory",
+                "--memory-dump-dir",
+                "/tmp/collector-output",
+            ],
+        ),
+    ];
+
+    fn golden_path(name: &str) -> String {
+        format!("{}/testdata/plans/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// The plan of `args`, for a fixed node and CPU count
+    fn plan_json(args: &[&str]) -> Value {
+        let opts = Command::parse_from(std::iter::once("collector").chain(args.iter().copied()));
+        let plan = Plan::new(&opts, "node-a", 16).unwrap();
+        plan.to_json(&opts)
+    }
+
+    #[test]
+    fn test_plans_match_golden_files() {
+        for (name, args) in CASES {
+            let golden: Value =
+                serde_json::from_str(&std::fs::read_to_string(golden_path(name)).unwrap()).unwrap();
+            assert_eq!(plan_json(args), golden, "plan differs from {}", name);
+        }
+    }
+
+    #[test]
+    fn test_all_problems_reported() {
+        let opts = Command::parse_from([
+            "collector",
+            "--bpf-program-groups",
+            "counters",
+            "--drop-columns",
+            "no_such_column",
+            "--parquet-part-size",
+            "0",
+            "--window-rollup",
+            "--window-rollup-secs",
+            "0",
+            "--memory-dump-dir",
+            "/tmp/collector-output",
+        ]);
+        let message = Plan::new(&opts, "node-a", 16).err().unwrap().to_string();
+        let problems: Vec<&str> = message.split("\n  - ").collect();
+        assert_eq!(problems.len(), 6, "{}", message);
+        assert_eq!(problems[0], "Invalid configuration:");
+        assert!(problems[1].starts_with("Invalid parquet writer settings"));
+        assert!(problems[2].starts_with("Timeslot mode requires"));
+        assert!(problems[3].contains("no_such_column"));
+        assert!(problems[4].starts_with("Invalid --window-rollup-secs"));
+        assert!(problems[5].starts_with("--memory-dump-dir requires"));
+    }
+
+    #[test]
+    fn test_quota_problems() {
+        let opts = Command::parse_from([
+            "collector",
+            "--storage-quota",
+            "1000",
+            "--storage-quota-reserve",
+            "timeslots=2000,trace=1",
+        ]);
+        let message = Plan::new(&opts, "node-a", 16).err().unwrap().to_string();
+        let problems: Vec<&str> = message.split("\n  - ").collect();
+        assert_eq!(problems.len(), 3, "{}", message);
+        assert!(problems[1].starts_with("Storage quota reservations (2000 bytes) exceed"));
+        assert_eq!(
+            problems[2],
+            "Storage quota settings of trace, which this run does not write"
+        );
+    }
+
+    #[test]
+    fn test_pod_labels_redaction() {
+        // Pod labels are only in the container events output
+        let opts = Command::parse_from(["collector", "--redact", "pod-labels"]);
+        let message = Plan::new(&opts, "node-a", 16).err().unwrap().to_string();
+        assert!(
+            message.contains("--redact targets not in the timeslot output: pod-labels"),
+            "{}",
+            message
+        );
+
+        let opts = Command::parse_from([
+            "collector",
+            "--cgroup-filter",
+            "namespace=prod",
+            "--container-event-labels",
+            "app,team",
+            "--redact",
+            "pod-labels",
+        ]);
+        assert!(Plan::new(&opts, "node-a", 16).is_ok());
+    }
+
+    /// Rewrite the golden files after an intentional change in the plan
+    #[test]
+    #[ignore]
+    fn regenerate_golden_files() {
+        std::fs::create_dir_all(Path::new(&golden_path("")).parent().unwrap()).unwrap();
+        for (name, args) in CASES {
+            let contents = serde_json::to_string_pretty(&plan_json(args)).unwrap();
+            std::fs::write(golden_path(name), contents + "\n").unwrap();
+        }
+    }
+}
@@ -0,0 +1,322 @@
+//! Kernel checks run before the BPF programs are loaded.
+//!
+//! On an unsupported kernel, loading fails with pages of verifier output that
+//! do not say what is wrong. These checks compare the probed kernel against
+//! what the collector needs, so it can exit with a single error naming the
+//! problem instead. `--force-load` proceeds anyway, and the loader then keeps
+//! its output out of the console for the run summary.
+
+use std::fmt;
+
+use crate::capabilities::{ProbeResult, SystemProbes};
+
+/// Oldest kernel the BPF programs are known to load on
+pub const MIN_KERNEL_VERSION: KernelVersion = KernelVersion::new(5, 15, 0);
+
+/// Exit code when the kernel is too old or lacks a required feature
+pub const EXIT_UNSUPPORTED_KERNEL: i32 = 3;
+
+/// Exit code when kernel lockdown blocks the reads the programs rely on
+pub const EXIT_KERNEL_LOCKDOWN: i32 = 4;
+
+/// Lockdown mode that blocks `bpf_probe_read` of kernel memory
+const LOCKDOWN_CONFIDENTIALITY: &str = "confidentiality";
+
+/// A kernel version, as the leading numbers of a release string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl KernelVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parse the version of a release such as "5.15.0-91-generic" or
+    /// "6.1.55+". A missing patch level is taken as 0.
+    pub fn parse(release: &str) -> Option<Self> {
+        let mut numbers = release.split('.').map(|part| {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .map_or(part, |end| &part[..end]);
+            digits.parse::<u32>().ok()
+        });
+        let major = numbers.next()??;
+        let minor = numbers.next()??;
+        let patch = numbers.next().flatten().unwrap_or(0);
+        Some(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.patch != 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reason the collector is not expected to work on the running kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreflightProblem {
+    /// The kernel is older than [`MIN_KERNEL_VERSION`]
+    KernelTooOld { release: String },
+    /// A kernel feature the programs need is missing
+    MissingFeature(&'static str),
+    /// Kernel lockdown in the given mode blocks `bpf_probe_read`
+    Lockdown(String),
+}
+
+impl fmt::Display for PreflightProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreflightProblem::KernelTooOld { release } => write!(
+                f,
+                "kernel {} is older than the required {}",
+                release, MIN_KERNEL_VERSION
+            ),
+            PreflightProblem::MissingFeature(feature) => write!(f, "missing {}", feature),
+            PreflightProblem::Lockdown(mode) => write!(
+                f,
+                "kernel lockdown is in {} mode, which blocks bpf_probe_read",
+                mode
+            ),
+        }
+    }
+}
+
+/// Outcome of the checks, and whether to load the programs regardless
+#[derive(Debug, Clone)]
+pub struct Preflight {
+    problems: Vec<PreflightProblem>,
+    force_load: bool,
+}
+
+impl Preflight {
+    /// Check the probe results. Probes that could not be answered are not
+    /// treated as problems.
+    pub fn run(probes: &SystemProbes, force_load: bool) -> Self {
+        let mut problems = Vec::new();
+
+        if let Some(release) = &probes.kernel_release {
+            if KernelVersion::parse(release).is_some_and(|version| version < MIN_KERNEL_VERSION) {
+                problems.push(PreflightProblem::KernelTooOld {
+                    release: release.clone(),
+                });
+            }
+        }
+
+        if probes.btf == ProbeResult::Unavailable {
+            problems.push(PreflightProblem::MissingFeature(
+                "BTF type information (/sys/kernel/btf/vmlinux)",
+            ));
+        }
+
+        if probes.lockdown.as_deref() == Some(LOCKDOWN_CONFIDENTIALITY) {
+            problems.push(PreflightProblem::Lockdown(
+                LOCKDOWN_CONFIDENTIALITY.to_string(),
+            ));
+        }
+
+        Self {
+            problems,
+            force_load,
+        }
+    }
+
+    /// The problems found
+    pub fn problems(&self) -> &[PreflightProblem] {
+        &self.problems
+    }
+
+    /// The code to exit with instead of loading, or None to proceed.
+    ///
+    /// An unsupported kernel takes precedence over lockdown, since it would
+    /// fail even without lockdown.
+    pub fn exit_code(&self) -> Option<i32> {
+        if self.force_load || self.problems.is_empty() {
+            return None;
+        }
+
+        let unsupported = self
+            .problems
+            .iter()
+            .any(|problem| !matches!(problem, PreflightProblem::Lockdown(_)));
+        Some(if unsupported {
+            EXIT_UNSUPPORTED_KERNEL
+        } else {
+            EXIT_KERNEL_LOCKDOWN
+        })
+    }
+
+    /// Whether the loader should keep its output, including the verifier
+    /// log, for the run summary rather than print it
+    pub fn capture_verifier_log(&self) -> bool {
+        self.force_load
+    }
+
+    /// A single line describing every problem, for when the collector exits
+    pub fn error_message(&self) -> String {
+        let problems = self
+            .problems
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ");
+        format!(
+            "Unsupported system: {}. The collector requires Linux {} or newer with BTF \
+             and without lockdown in confidentiality mode. Pass --force-load to try anyway.",
+            problems, MIN_KERNEL_VERSION
+        )
+    }
+}
+
+/// The active mode of a `/sys/kernel/security/lockdown` file, the bracketed
+/// one in e.g. "none integrity [confidentiality]"
+pub fn parse_lockdown(contents: &str) -> Option<String> {
+    let start = contents.find('[')? + 1;
+    let end = start + contents[start..].find(']')?;
+    Some(contents[start..end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Command;
+    use clap::Parser;
+
+    fn probes(release: Option<&str>, btf: ProbeResult, lockdown: Option<&str>) -> SystemProbes {
+        SystemProbes {
+            privileged: true,
+            num_cpus: Some(4),
+            kernel_release: release.map(str::to_string),
+            perf_event_paranoid: Some(2),
+            btf,
+            bpf_object: ProbeResult::Available,
+            lockdown: lockdown.map(str::to_string),
+            counters: Vec::new(),
+            nri: ProbeResult::Unknown,
+        }
+    }
+
+    #[test]
+    fn test_kernel_version() {
+        let parse = |release| KernelVersion::parse(release).unwrap();
+        assert_eq!(parse("5.15.0-91-generic"), KernelVersion::new(5, 15, 0));
+        assert_eq!(parse("6.1.55+"), KernelVersion::new(6, 1, 55));
+        assert_eq!(parse("6.8"), KernelVersion::new(6, 8, 0));
+        assert_eq!(parse("5.4.0-rc3"), KernelVersion::new(5, 4, 0));
+        assert_eq!(KernelVersion::parse("unknown"), None);
+        assert_eq!(KernelVersion::parse("6"), None);
+
+        // Compared numerically, not as strings
+        assert!(parse("5.10.200") < MIN_KERNEL_VERSION);
+        assert!(parse("5.9.0") < parse("5.10.0"));
+        assert!(parse("5.15.0") >= MIN_KERNEL_VERSION);
+        assert!(parse("6.0.1") > MIN_KERNEL_VERSION);
+        assert_eq!(MIN_KERNEL_VERSION.to_string(), "5.15");
+        assert_eq!(KernelVersion::new(6, 1, 55).to_string(), "6.1.55");
+    }
+
+    #[test]
+    fn test_exit_code_selection() {
+        let supported = Preflight::run(
+            &probes(Some("6.8.0"), ProbeResult::Available, Some("none")),
+            false,
+        );
+        assert!(supported.problems().is_empty());
+        assert_eq!(supported.exit_code(), None);
+
+        // Unknown probe results do not block loading
+        let unknown = Preflight::run(&probes(None, ProbeResult::Unknown, None), false);
+        assert_eq!(unknown.exit_code(), None);
+
+        let old = Preflight::run(
+            &probes(Some("5.10.0-28-amd64"), ProbeResult::Unavailable, None),
+            false,
+        );
+        assert_eq!(
+            old.problems(),
+            &[
+                PreflightProblem::KernelTooOld {
+                    release: "5.10.0-28-amd64".to_string()
+                },
+                PreflightProblem::MissingFeature("BTF type information (/sys/kernel/btf/vmlinux)"),
+            ]
+        );
+        assert_eq!(old.exit_code(), Some(EXIT_UNSUPPORTED_KERNEL));
+        let message = old.error_message();
+        assert!(message.contains("5.10.0-28-amd64"));
+        assert!(message.contains("requires Linux 5.15"));
+        assert!(!message.contains('\n'));
+
+        let locked = Preflight::run(
+            &probes(
+                Some("6.8.0"),
+                ProbeResult::Available,
+                Some("confidentiality"),
+            ),
+            false,
+        );
+        assert_eq!(locked.exit_code(), Some(EXIT_KERNEL_LOCKDOWN));
+        assert!(locked.error_message().contains("bpf_probe_read"));
+
+        // Integrity mode does not block reads
+        let integrity = Preflight::run(
+            &probes(Some("6.8.0"), ProbeResult::Available, Some("integrity")),
+            false,
+        );
+        assert_eq!(integrity.exit_code(), None);
+
+        // An old kernel takes precedence over lockdown
+        let both = Preflight::run(
+            &probes(
+                Some("5.4.0"),
+                ProbeResult::Available,
+                Some("confidentiality"),
+            ),
+            false,
+        );
+        assert_eq!(both.problems().len(), 2);
+        assert_eq!(both.exit_code(), Some(EXIT_UNSUPPORTED_KERNEL));
+    }
+
+    #[test]
+    fn test_force_load() {
+        let old = probes(Some("5.10.0"), ProbeResult::Available, None);
+
+        let opts = Command::parse_from(["collector"]);
+        let preflight = Preflight::run(&old, opts.force_load);
+        assert_eq!(preflight.exit_code(), Some(EXIT_UNSUPPORTED_KERNEL));
+        assert!(!preflight.capture_verifier_log());
+
+        // Forcing keeps the problems for the summary, but does not exit
+        let opts = Command::parse_from(["collector", "--force-load"]);
+        let preflight = Preflight::run(&old, opts.force_load);
+        assert_eq!(preflight.problems().len(), 1);
+        assert_eq!(preflight.exit_code(), None);
+        assert!(preflight.capture_verifier_log());
+    }
+
+    #[test]
+    fn test_parse_lockdown() {
+        assert_eq!(
+            parse_lockdown("none integrity [confidentiality]\n").as_deref(),
+            Some("confidentiality")
+        );
+        assert_eq!(
+            parse_lockdown("[none] integrity confidentiality").as_deref(),
+            Some("none")
+        );
+        assert_eq!(parse_lockdown(""), None);
+    }
+}
@@ -0,0 +1,795 @@
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use log::{error, info};
+
+use bpf::MSG_TYPES;
+use perf_events::{fnv1a, Dispatcher, SampleHeader};
+
+use crate::event_capture::MessageRedaction;
+use crate::timeslot_data::TimeslotData;
+
+/// Magic bytes at the start of every processor log file
+const LOG_MAGIC: &[u8; 8] = b"PEPLOG01";
+
+/// Size of the file header: magic followed by the number of CPUs
+const HEADER_SIZE: u64 = LOG_MAGIC.len() as u64 + 4;
+
+/// Payloads up to this size are recorded in full, larger ones only as a hash
+pub const MAX_FULL_PAYLOAD: usize = 256;
+
+// Record tags
+const TAG_SAMPLE: u8 = 1;
+const TAG_LOST: u8 = 2;
+const TAG_TIMESLOT: u8 = 3;
+
+// Payload kinds
+const PAYLOAD_FULL: u8 = 0;
+const PAYLOAD_HASH: u8 = 1;
+
+/// Event payload as stored in the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordedPayload {
+    /// The complete payload
+    Full(Vec<u8>),
+    /// Length and FNV-1a hash of a payload too large to store
+    Hash { len: u32, hash: u64 },
+}
+
+impl RecordedPayload {
+    /// Keep small payloads in full and hash larger ones
+    pub fn from_data(data: &[u8]) -> Self {
+        if data.len() <= MAX_FULL_PAYLOAD {
+            RecordedPayload::Full(data.to_vec())
+        } else {
+            RecordedPayload::Hash {
+                len: data.len() as u32,
+                hash: fnv1a(data),
+            }
+        }
+    }
+}
+
+/// Totals of a single task in an emitted timeslot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskDigest {
+    pub pid: u32,
+    /// Cgroup of the task, 0 if it had no metadata
+    pub cgroup_id: u64,
+    pub cycles: u64,
+    pub instructions: u64,
+    pub llc_misses: u64,
+    pub cache_references: u64,
+    pub time_ns: u64,
+}
+
+/// Comparable form of an emitted timeslot, with tasks sorted by PID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeslotDigest {
+    pub start_timestamp: u64,
+    pub tasks: Vec<TaskDigest>,
+}
+
+impl TimeslotDigest {
+    /// Summarize a timeslot
+    pub fn from_timeslot(timeslot: &TimeslotData) -> Self {
+        let mut tasks: Vec<TaskDigest> = timeslot
+            .iter_tasks()
+            .map(|(pid, task)| TaskDigest {
+                pid: *pid,
+                cgroup_id: task.metadata.as_ref().map_or(0, |m| m.cgroup_id),
+                cycles: task.metrics.cycles,
+                instructions: task.metrics.instructions,
+                llc_misses: task.metrics.llc_misses,
+                cache_references: task.metrics.cache_references,
+                time_ns: task.metrics.time_ns,
+            })
+            .collect();
+        tasks.sort_by_key(|task| task.pid);
+
+        Self {
+            start_timestamp: timeslot.start_timestamp,
+            tasks,
+        }
+    }
+}
+
+/// A single entry of the processor log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogRecord {
+    /// A sample consumed by the processor
+    Sample {
+        cpu: u32,
+        msg_type: u32,
+        timestamp: u64,
+        payload: RecordedPayload,
+    },
+    /// A lost record consumed by the processor
+    Lost { cpu: u32, payload: RecordedPayload },
+    /// A timeslot emitted by the processor
+    Timeslot(TimeslotDigest),
+}
+
+impl LogRecord {
+    /// Append the little-endian encoding of the record to `out`
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            LogRecord::Sample {
+                cpu,
+                msg_type,
+                timestamp,
+                payload,
+            } => {
+                out.push(TAG_SAMPLE);
+                out.extend_from_slice(&cpu.to_le_bytes());
+                out.extend_from_slice(&msg_type.to_le_bytes());
+                out.extend_from_slice(&timestamp.to_le_bytes());
+                encode_payload(payload, out);
+            }
+            LogRecord::Lost { cpu, payload } => {
+                out.push(TAG_LOST);
+                out.extend_from_slice(&cpu.to_le_bytes());
+                encode_payload(payload, out);
+            }
+            LogRecord::Timeslot(digest) => {
+                out.push(TAG_TIMESLOT);
+                out.extend_from_slice(&digest.start_timestamp.to_le_bytes());
+                out.extend_from_slice(&(digest.tasks.len() as u32).to_le_bytes());
+                for task in &digest.tasks {
+                    out.extend_from_slice(&task.pid.to_le_bytes());
+                    for value in [
+                        task.cgroup_id,
+                        task.cycles,
+                        task.instructions,
+                        task.llc_misses,
+                        task.cache_references,
+                        task.time_ns,
+                    ] {
+                        out.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn encode_payload(payload: &RecordedPayload, out: &mut Vec<u8>) {
+    match payload {
+        RecordedPayload::Full(data) => {
+            out.push(PAYLOAD_FULL);
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(data);
+        }
+        RecordedPayload::Hash { len, hash } => {
+            out.push(PAYLOAD_HASH);
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(&hash.to_le_bytes());
+        }
+    }
+}
+
+/// Appends the events consumed by the processor, and the timeslots it emits,
+/// to a compact binary log for later replay.
+///
+/// The log is bounded: when the file would grow past `max_bytes` it is
+/// renamed to `<path>.1`, replacing the previous rotation, and a new file is
+/// started. Disk usage therefore stays below about twice `max_bytes`. If
+/// writing fails, recording stops and the collector carries on.
+pub struct ProcessorRecorder {
+    path: PathBuf,
+    max_bytes: u64,
+    num_cpus: u32,
+    // None once recording stopped after an IO error
+    writer: Option<BufWriter<File>>,
+    // Bytes written to the current file, including the header
+    written: u64,
+    // Scratch buffer for encoding records
+    buf: Vec<u8>,
+    // Applied to sample payloads before they are recorded
+    redaction: Option<MessageRedaction>,
+    // Scratch buffer for redacted payloads
+    redacted: Vec<u8>,
+}
+
+impl ProcessorRecorder {
+    /// Create the log file at `path`, truncating any existing one
+    pub fn create(path: impl Into<PathBuf>, max_bytes: u64, num_cpus: usize) -> io::Result<Self> {
+        let mut recorder = Self {
+            path: path.into(),
+            max_bytes,
+            num_cpus: num_cpus as u32,
+            writer: None,
+            written: 0,
+            buf: Vec::new(),
+            redaction: None,
+            redacted: Vec::new(),
+        };
+        recorder.open_file()?;
+        Ok(recorder)
+    }
+
+    /// Redact every recorded sample payload with `redaction`
+    pub fn set_redaction(&mut self, redaction: MessageRedaction) {
+        self.redaction = Some(redaction);
+    }
+
+    /// Subscribe the recorder to all events the processor consumes.
+    ///
+    /// Attach before the processor's components so that each input is logged
+    /// ahead of the outputs it triggers.
+    pub fn attach(recorder: &Rc<RefCell<Self>>, dispatcher: &mut Dispatcher) {
+        // The processor consumes every message type
+        for msg_type in MSG_TYPES {
+            dispatcher.subscribe_method(
+                msg_type as u32,
+                recorder.clone(),
+                ProcessorRecorder::record_sample,
+            );
+        }
+
+        let recorder = recorder.clone();
+        dispatcher.subscribe_lost_samples(move |ring_index, data| {
+            recorder.borrow_mut().record(&LogRecord::Lost {
+                cpu: ring_index as u32,
+                payload: RecordedPayload::from_data(data),
+            });
+        });
+    }
+
+    /// Append a record to the log
+    pub fn record(&mut self, record: &LogRecord) {
+        if self.writer.is_none() {
+            return;
+        }
+
+        if let Err(e) = self.write_record(record) {
+            error!(
+                "Failed to write processor log {}, recording stopped: {}",
+                self.path.display(),
+                e
+            );
+            self.writer = None;
+        }
+    }
+
+    /// Flush buffered records to the file
+    pub fn flush(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = writer.flush() {
+                error!(
+                    "Failed to flush processor log {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    fn record_sample(&mut self, ring_index: usize, data: &[u8]) {
+        let (msg_type, timestamp) = match plain::from_bytes::<SampleHeader>(data) {
+            Ok(header) => (header.type_, header.timestamp),
+            Err(_) => (0, 0),
+        };
+        let payload = match &self.redaction {
+            Some(redaction) => {
+                self.redacted.clear();
+                self.redacted.extend_from_slice(data);
+                redaction(&mut self.redacted);
+                RecordedPayload::from_data(&self.redacted)
+            }
+            None => RecordedPayload::from_data(data),
+        };
+
+        self.record(&LogRecord::Sample {
+            cpu: ring_index as u32,
+            msg_type,
+            timestamp,
+            payload,
+        });
+    }
+
+    fn write_record(&mut self, record: &LogRecord) -> io::Result<()> {
+        self.buf.clear();
+        record.encode(&mut self.buf);
+
+        // Rotate, unless the file only holds the header
+        if self.written + self.buf.len() as u64 > self.max_bytes && self.written > HEADER_SIZE {
+            self.rotate()?;
+        }
+
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_all(&self.buf)?;
+            self.written += self.buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        fs::rename(&self.path, self.rotated_path())?;
+        info!("Rotated processor log {}", self.path.display());
+        self.open_file()
+    }
+
+    fn open_file(&mut self) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        writer.write_all(LOG_MAGIC)?;
+        writer.write_all(&self.num_cpus.to_le_bytes())?;
+        self.writer = Some(writer);
+        self.written = HEADER_SIZE;
+        Ok(())
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        rotated.into()
+    }
+}
+
+/// Contents of a processor log file
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ProcessorLog {
+    pub num_cpus: usize,
+    pub records: Vec<LogRecord>,
+}
+
+/// Parse a processor log
+#[cfg(test)]
+pub fn read_processor_log(data: &[u8]) -> anyhow::Result<ProcessorLog> {
+    use anyhow::{anyhow, bail};
+
+    struct Cursor<'a>(&'a [u8]);
+
+    impl<'a> Cursor<'a> {
+        fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+            if self.0.len() < n {
+                return Err(anyhow!("processor log truncated"));
+            }
+            let (head, tail) = self.0.split_at(n);
+            self.0 = tail;
+            Ok(head)
+        }
+
+        fn u8(&mut self) -> anyhow::Result<u8> {
+            Ok(self.take(1)?[0])
+        }
+
+        fn u32(&mut self) -> anyhow::Result<u32> {
+            Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+        }
+
+        fn u64(&mut self) -> anyhow::Result<u64> {
+            Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+        }
+
+        fn payload(&mut self) -> anyhow::Result<RecordedPayload> {
+            let kind = self.u8()?;
+            let len = self.u32()?;
+            match kind {
+                PAYLOAD_FULL => Ok(RecordedPayload::Full(self.take(len as usize)?.to_vec())),
+                PAYLOAD_HASH => Ok(RecordedPayload::Hash {
+                    len,
+                    hash: self.u64()?,
+                }),
+                _ => Err(anyhow!("unknown payload kind {}", kind)),
+            }
+        }
+    }
+
+    let mut cursor = Cursor(data);
+    if cursor.take(LOG_MAGIC.len())? != LOG_MAGIC {
+        bail!("not a processor log");
+    }
+    let num_cpus = cursor.u32()? as usize;
+
+    let mut records = Vec::new();
+    while !cursor.0.is_empty() {
+        let record = match cursor.u8()? {
+            TAG_SAMPLE => LogRecord::Sample {
+                cpu: cursor.u32()?,
+                msg_type: cursor.u32()?,
+                timestamp: cursor.u64()?,
+                payload: cursor.payload()?,
+            },
+            TAG_LOST => LogRecord::Lost {
+                cpu: cursor.u32()?,
+                payload: cursor.payload()?,
+            },
+            TAG_TIMESLOT => {
+                let start_timestamp = cursor.u64()?;
+                let count = cursor.u32()?;
+                let mut tasks = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    tasks.push(TaskDigest {
+                        pid: cursor.u32()?,
+                        cgroup_id: cursor.u64()?,
+                        cycles: cursor.u64()?,
+                        instructions: cursor.u64()?,
+                        llc_misses: cursor.u64()?,
+                        cache_references: cursor.u64()?,
+                        time_ns: cursor.u64()?,
+                    });
+                }
+                LogRecord::Timeslot(TimeslotDigest {
+                    start_timestamp,
+                    tasks,
+                })
+            }
+            tag => bail!("unknown record tag {}", tag),
+        };
+        records.push(record);
+    }
+
+    Ok(ProcessorLog { num_cpus, records })
+}
+
+/// Build a processor log from a dispatcher capture (see
+/// [`perf_events::Dispatcher::enable_capture`]), so its messages can be
+/// replayed. Captures hold samples only, without lost records or timeslots.
+#[cfg(test)]
+pub fn processor_log_from_capture(data: &[u8], num_cpus: usize) -> anyhow::Result<ProcessorLog> {
+    let records = perf_events::read_capture(data)?
+        .into_iter()
+        .map(|record| {
+            let (msg_type, timestamp) = match plain::from_bytes::<SampleHeader>(&record.data) {
+                Ok(header) => (header.type_, header.timestamp),
+                Err(_) => (0, 0),
+            };
+            LogRecord::Sample {
+                cpu: record.ring_index as u32,
+                msg_type,
+                timestamp,
+                payload: RecordedPayload::Full(record.data),
+            }
+        })
+        .collect();
+
+    Ok(ProcessorLog { num_cpus, records })
+}
+
+/// Timeslots from a log, and those a fresh processor emitted when replaying it
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ReplayResult {
+    pub recorded: Vec<TimeslotDigest>,
+    pub replayed: Vec<TimeslotDigest>,
+}
+
+/// Feed the recorded events, in order, through a new timeslot-mode processor
+/// and collect the timeslots it emits alongside the recorded ones.
+///
+/// Fails if the log holds hashed payloads, since those cannot be replayed.
+#[cfg(test)]
+pub fn replay_processor_log(log: &ProcessorLog) -> anyhow::Result<ReplayResult> {
+    use anyhow::bail;
+    use bpf::BpfLoaderConfig;
+    use perf_events::{PERF_RECORD_LOST, PERF_RECORD_SAMPLE};
+    use tokio::sync::mpsc;
+
+    use crate::perf_event_processor::{PerfEventProcessor, ProcessorMode};
+
+    let recorded: Vec<TimeslotDigest> = log
+        .records
+        .iter()
+        .filter_map(|record| match record {
+            LogRecord::Timeslot(digest) => Some(digest.clone()),
+            _ => None,
+        })
+        .collect();
+
+    // Leave room for every timeslot so none are dropped, plus one to notice
+    // extras. Logs built from captures record no timeslots, so size for the
+    // most their events could emit.
+    let capacity = recorded.len().max(log.records.len());
+    let (timeslot_tx, mut timeslot_rx) = mpsc::channel(capacity + 1);
+    let mut dispatcher = Dispatcher::new();
+    let processor = PerfEventProcessor::with_dispatcher(
+        &mut dispatcher,
+        log.num_cpus,
+        ProcessorMode::Timeslot(timeslot_tx),
+        None,
+        &BpfLoaderConfig::default(),
+    );
+
+    for record in &log.records {
+        let (cpu, record_type, payload) = match record {
+            LogRecord::Sample { cpu, payload, .. } => (*cpu, PERF_RECORD_SAMPLE, payload),
+            LogRecord::Lost { cpu, payload } => (*cpu, PERF_RECORD_LOST, payload),
+            LogRecord::Timeslot(_) => continue,
+        };
+        let data = match payload {
+            RecordedPayload::Full(data) => data,
+            RecordedPayload::Hash { len, .. } => {
+                bail!("cannot replay a {} byte payload recorded as a hash", len)
+            }
+        };
+        dispatcher.dispatch_record(cpu as usize, record_type, data)?;
+    }
+    processor.borrow_mut().shutdown();
+
+    let mut replayed = Vec::new();
+    while let Ok(timeslot) = timeslot_rx.try_recv() {
+        replayed.push(TimeslotDigest::from_timeslot(&timeslot));
+    }
+
+    Ok(ReplayResult { recorded, replayed })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use bpf::{
+        msg_type, BpfLoaderConfig, PerfMeasurementMsg, TaskFreeMsg, TaskMetadataMsg,
+        TimerFinishedProcessingMsg,
+    };
+    use perf_events::collector_sample_record;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::perf_event_processor::{PerfEventProcessor, ProcessorMode};
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/processor_log_fake_source.bin"
+    );
+
+    fn perf_measurement(pid: u32, cycles: u64, timestamp: u64) -> Vec<u8> {
+        let msg = PerfMeasurementMsg {
+            pid,
+            cycles_delta: cycles,
+            instructions_delta: cycles * 2,
+            llc_misses_delta: cycles / 100,
+            cache_references_delta: cycles / 10,
+            time_delta_ns: 250_000,
+            ..Default::default()
+        };
+        collector_sample_record(msg_type::MSG_TYPE_PERF_MEASUREMENT as u32, timestamp, &msg)
+    }
+
+    /// Deterministic stand-in for the BPF programs: three tasks on two CPUs,
+    /// two perf measurements per CPU and a timer tick on each CPU per 1ms
+    /// timeslot, with one task exiting halfway through.
+    fn fake_source(slots: u64) -> Vec<(usize, Vec<u8>)> {
+        let mut events = Vec::new();
+
+        for (cpu, pid, cgroup_id) in [(0, 100, 1000), (1, 200, 2000), (1, 300, 3000)] {
+            let mut comm = [0u8; 16];
+            comm[..4].copy_from_slice(b"task");
+            let msg = TaskMetadataMsg {
+                pid,
+                comm,
+                cgroup_id,
+                ..Default::default()
+            };
+            events.push((
+                cpu,
+                collector_sample_record(msg_type::MSG_TYPE_TASK_METADATA as u32, 1, &msg),
+            ));
+        }
+
+        for slot in 0..slots {
+            let base = (slot + 1) * 1_000_000;
+            events.push((0, perf_measurement(100, 1000 + slot, base + 100_000)));
+            events.push((1, perf_measurement(200, 2000 + slot, base + 200_000)));
+            if slot < slots / 2 {
+                events.push((1, perf_measurement(300, 3000 + slot, base + 300_000)));
+            } else if slot == slots / 2 {
+                let msg = TaskFreeMsg {
+                    pid: 300,
+                    ..Default::default()
+                };
+                events.push((
+                    1,
+                    collector_sample_record(msg_type::MSG_TYPE_TASK_FREE as u32, base, &msg),
+                ));
+            }
+            // Perf measurements for a task without metadata
+            events.push((0, perf_measurement(400, 10, base + 400_000)));
+
+            for cpu in 0..2 {
+                let msg = TimerFinishedProcessingMsg::default();
+                events.push((
+                    cpu,
+                    collector_sample_record(
+                        msg_type::MSG_TYPE_TIMER_FINISHED_PROCESSING as u32,
+                        base + 900_000,
+                        &msg,
+                    ),
+                ));
+            }
+        }
+
+        events
+    }
+
+    /// Run the fake source through a processor with a recorder attached,
+    /// returning the timeslots the processor emitted
+    fn record_fake_source(path: &Path, max_bytes: u64, slots: u64) -> Vec<TimeslotDigest> {
+        let num_cpus = 2;
+        let recorder = Rc::new(RefCell::new(
+            ProcessorRecorder::create(path, max_bytes, num_cpus).unwrap(),
+        ));
+        let (timeslot_tx, mut timeslot_rx) = mpsc::channel(slots as usize + 1);
+        let mut dispatcher = Dispatcher::new();
+        let processor = PerfEventProcessor::with_dispatcher(
+            &mut dispatcher,
+            num_cpus,
+            ProcessorMode::Timeslot(timeslot_tx),
+            Some(recorder),
+            &BpfLoaderConfig::default(),
+        );
+
+        for (cpu, data) in fake_source(slots) {
+            dispatcher
+                .dispatch_record(cpu, perf_events::PERF_RECORD_SAMPLE, &data)
+                .unwrap();
+        }
+        processor.borrow_mut().shutdown();
+
+        let mut emitted = Vec::new();
+        while let Ok(timeslot) = timeslot_rx.try_recv() {
+            emitted.push(TimeslotDigest::from_timeslot(&timeslot));
+        }
+        emitted
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("processor_log_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = temp_path("roundtrip");
+        let emitted = record_fake_source(&path, u64::MAX, 8);
+
+        let log = read_processor_log(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(log.num_cpus, 2);
+        // Every input is recorded, and outputs follow the events that caused them
+        let samples = log
+            .records
+            .iter()
+            .filter(|r| matches!(r, LogRecord::Sample { .. }))
+            .count();
+        assert_eq!(samples, fake_source(8).len());
+        assert!(matches!(log.records.last(), Some(LogRecord::Timeslot(_))));
+
+        let result = replay_processor_log(&log).unwrap();
+        assert_eq!(result.recorded, emitted);
+        assert_eq!(result.replayed, result.recorded);
+        assert_eq!(result.recorded.len(), 8);
+
+        // The freed task's metadata survives until the timeslot after its release
+        let last = result.recorded.last().unwrap();
+        let pids: Vec<u32> = last.tasks.iter().map(|t| t.pid).collect();
+        assert_eq!(pids, vec![100, 200, 400]);
+        assert_eq!(last.tasks[2].cgroup_id, 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_bounds_log_size() {
+        let path = temp_path("rotation");
+        let max_bytes = 2048;
+        record_fake_source(&path, max_bytes, 50);
+
+        let rotated = PathBuf::from(format!("{}.1", path.display()));
+        assert!(fs::metadata(&path).unwrap().len() <= max_bytes);
+        assert!(fs::metadata(&rotated).unwrap().len() <= max_bytes);
+
+        // Both files parse on their own
+        let current = read_processor_log(&fs::read(&path).unwrap()).unwrap();
+        let previous = read_processor_log(&fs::read(&rotated).unwrap()).unwrap();
+        assert!(!current.records.is_empty());
+        assert!(!previous.records.is_empty());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+
+    #[test]
+    fn test_large_payload_is_hashed() {
+        let data = vec![7u8; MAX_FULL_PAYLOAD + 1];
+        let payload = RecordedPayload::from_data(&data);
+        assert_eq!(
+            payload,
+            RecordedPayload::Hash {
+                len: data.len() as u32,
+                hash: fnv1a(&data),
+            }
+        );
+
+        let log = ProcessorLog {
+            num_cpus: 1,
+            records: vec![LogRecord::Sample {
+                cpu: 0,
+                msg_type: msg_type::MSG_TYPE_PERF_MEASUREMENT as u32,
+                timestamp: 0,
+                payload,
+            }],
+        };
+        assert!(replay_processor_log(&log).is_err());
+    }
+
+    #[test]
+    fn test_replay_capture() {
+        let log_path = temp_path("capture_reference");
+        let capture_path = temp_path("capture");
+        let emitted = record_fake_source(&log_path, u64::MAX, 8);
+
+        // Capture the same traffic from a dispatcher without subscribers
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .enable_capture(perf_events::CaptureSpec::new(&capture_path))
+            .unwrap();
+        for (cpu, data) in fake_source(8) {
+            dispatcher
+                .dispatch_record(cpu, perf_events::PERF_RECORD_SAMPLE, &data)
+                .unwrap();
+        }
+        let status = dispatcher.disable_capture().unwrap();
+        assert_eq!(status.events, fake_source(8).len() as u64);
+
+        let log = processor_log_from_capture(&fs::read(&capture_path).unwrap(), 2).unwrap();
+        let result = replay_processor_log(&log).unwrap();
+        assert!(result.recorded.is_empty());
+        assert_eq!(result.replayed, emitted);
+
+        fs::remove_file(&log_path).unwrap();
+        fs::remove_file(&capture_path).unwrap();
+    }
+
+    #[test]
+    fn test_redacted_samples() {
+        let path = temp_path("redacted");
+        let pid = std::mem::offset_of!(PerfMeasurementMsg, pid);
+        let mut recorder = ProcessorRecorder::create(&path, u64::MAX, 1).unwrap();
+        recorder.set_redaction(Arc::new(move |data: &mut [u8]| {
+            data[pid..pid + 4].fill(0);
+        }));
+        let data = perf_measurement(42, 10, 1);
+        recorder.record_sample(0, &data);
+        recorder.flush();
+
+        // The sample is recorded as redacted, with its header intact
+        let log = read_processor_log(&fs::read(&path).unwrap()).unwrap();
+        let mut expected = data.clone();
+        expected[pid..pid + 4].fill(0);
+        assert_eq!(
+            log.records,
+            vec![LogRecord::Sample {
+                cpu: 0,
+                msg_type: msg_type::MSG_TYPE_PERF_MEASUREMENT as u32,
+                timestamp: 1,
+                payload: RecordedPayload::Full(expected),
+            }]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_fixture() {
+        let log = read_processor_log(&fs::read(FIXTURE).unwrap()).unwrap();
+        let result = replay_processor_log(&log).unwrap();
+
+        assert_eq!(result.recorded.len(), 8);
+        assert_eq!(result.replayed, result.recorded);
+    }
+
+    /// Regenerate the fixture after an intentional change in processor output
+    #[test]
+    #[ignore]
+    fn regenerate_fixture() {
+        record_fake_source(Path::new(FIXTURE), u64::MAX, 8);
+    }
+}
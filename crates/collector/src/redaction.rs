@@ -0,0 +1,438 @@
+//! Redaction of identifying values before output leaves the node.
+//!
+//! Some environments may not export process names or pod labels. The
+//! [`Redact`] transform either nulls out those columns, or replaces each value
+//! with a keyed BLAKE3 hash so rows can still be grouped and joined by it
+//! without revealing the plaintext. The key is read from a file and only ever
+//! held in memory; hashes are stable for a given key.
+//!
+//! Outputs that keep raw BPF messages, such as event captures, redact the
+//! comm of task metadata messages in place: zeroed, or the start of its hash
+//! as far as the fixed-size field holds it.
+
+use std::mem::offset_of;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use arrow_array::builder::StringBuilder;
+use arrow_array::{new_null_array, Array, ArrayRef, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use bpf::{msg_type, TaskMetadataMsg};
+use perf_events::SampleHeader;
+
+use crate::batch_transform::BatchTransform;
+use crate::event_capture::MessageRedaction;
+
+/// Context string for deriving the hash key from the key file contents
+const KEY_DERIVATION_CONTEXT: &str = "memory-collector 2025-06 redaction key";
+
+/// Size of the comm field of task metadata messages, including its NUL
+const COMM_LEN: usize = 16;
+
+/// Values that can be redacted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RedactionTarget {
+    /// Process command names
+    Comm,
+    /// Process command lines
+    Cmdline,
+    /// Kubernetes pod labels
+    PodLabels,
+}
+
+impl RedactionTarget {
+    /// Name of the column holding the target's values
+    pub fn column(self) -> &'static str {
+        match self {
+            RedactionTarget::Comm => "process_name",
+            RedactionTarget::Cmdline => "cmdline",
+            RedactionTarget::PodLabels => "pod_labels",
+        }
+    }
+}
+
+/// Read the hashing key from `path`, deriving a 32-byte key from its contents
+pub fn read_redaction_key(path: &str) -> Result<[u8; 32]> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read redaction key {}", path))?;
+    if contents.is_empty() {
+        return Err(anyhow!("Redaction key file {} is empty", path));
+    }
+    Ok(blake3::derive_key(KEY_DERIVATION_CONTEXT, &contents))
+}
+
+/// Replaces the values of redacted string columns with nulls or keyed hashes
+pub struct Redact {
+    targets: Vec<RedactionTarget>,
+    /// Key for hashing values; values are nulled when None
+    key: Option<[u8; 32]>,
+    /// Targets held in a column other than [`RedactionTarget::column`]
+    renamed: Vec<(RedactionTarget, &'static str)>,
+}
+
+impl Redact {
+    /// Create a transform redacting `targets`, hashing with `key` if given
+    pub fn new(targets: Vec<RedactionTarget>, key: Option<[u8; 32]>) -> Self {
+        Self {
+            targets,
+            key,
+            renamed: Vec::new(),
+        }
+    }
+
+    /// Redact `target` in the column named `column`, for outputs that hold
+    /// its values under another name
+    pub fn with_column(mut self, target: RedactionTarget, column: &'static str) -> Self {
+        self.renamed.retain(|(renamed, _)| *renamed != target);
+        self.renamed.push((target, column));
+        self
+    }
+
+    /// Name of the column holding the values of `target`
+    fn column(&self, target: RedactionTarget) -> &'static str {
+        self.renamed
+            .iter()
+            .find(|(renamed, _)| *renamed == target)
+            .map_or(target.column(), |(_, column)| column)
+    }
+
+    /// Targets without a string column in `schema`
+    pub fn missing_targets(&self, schema: &Schema) -> Vec<RedactionTarget> {
+        self.targets
+            .iter()
+            .copied()
+            .filter(|target| {
+                schema
+                    .field_with_name(self.column(*target))
+                    .map_or(true, |field| field.data_type() != &DataType::Utf8)
+            })
+            .collect()
+    }
+
+    /// Whether the column named `name` is redacted
+    fn is_redacted(&self, name: &str) -> bool {
+        self.targets
+            .iter()
+            .any(|target| self.column(*target) == name)
+    }
+
+    /// Redact the values of a string column
+    fn redact_column(&self, column: &ArrayRef) -> Result<ArrayRef> {
+        let Some(key) = &self.key else {
+            return Ok(new_null_array(&DataType::Utf8, column.len()));
+        };
+
+        let values = column
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .ok_or_else(|| anyhow!("expected a string column, got {}", column.data_type()))?;
+        let mut builder = StringBuilder::with_capacity(values.len(), values.len() * 64);
+        for value in values {
+            builder.append_option(
+                value.map(|value| blake3::keyed_hash(key, value.as_bytes()).to_hex()),
+            );
+        }
+        Ok(Arc::new(builder.finish()))
+    }
+
+    /// Redaction of raw task metadata messages, if comm is redacted
+    pub fn message_redaction(&self) -> Option<MessageRedaction> {
+        if !self.targets.contains(&RedactionTarget::Comm) {
+            return None;
+        }
+        let key = self.key;
+        Some(Arc::new(move |data: &mut [u8]| {
+            redact_message_comm(data, key.as_ref())
+        }))
+    }
+}
+
+/// Redact the comm of a task metadata message in place. Hashed comms keep
+/// the first characters of the hash in the `process_name` column.
+fn redact_message_comm(data: &mut [u8], key: Option<&[u8; 32]>) {
+    let Ok(header) = plain::from_bytes::<SampleHeader>(data) else {
+        return;
+    };
+    if header.type_ != msg_type::MSG_TYPE_TASK_METADATA as u32 {
+        return;
+    }
+    let offset = offset_of!(TaskMetadataMsg, comm);
+    let Some(comm) = data.get_mut(offset..offset + COMM_LEN) else {
+        return;
+    };
+
+    // Hash the value as the process_name column holds it
+    let hash = key.map(|key| {
+        let name = std::str::from_utf8(comm)
+            .unwrap_or("<invalid utf8>")
+            .trim_end_matches(char::from(0));
+        blake3::keyed_hash(key, name.as_bytes()).to_hex()
+    });
+    comm.fill(0);
+    if let Some(hash) = hash {
+        comm[..COMM_LEN - 1].copy_from_slice(&hash.as_str().as_bytes()[..COMM_LEN - 1]);
+    }
+}
+
+impl BatchTransform for Redact {
+    fn name(&self) -> &str {
+        "redact"
+    }
+
+    fn output_schema(&self, input: SchemaRef) -> SchemaRef {
+        // Redacted columns may hold nulls, whatever their original nullability
+        let fields: Vec<Field> = input
+            .fields()
+            .iter()
+            .map(|field| {
+                let field = field.as_ref().clone();
+                if self.is_redacted(field.name()) {
+                    field.with_nullable(true)
+                } else {
+                    field
+                }
+            })
+            .collect();
+        Arc::new(Schema::new_with_metadata(fields, input.metadata().clone()))
+    }
+
+    fn transform(&mut self, batch: RecordBatch) -> Result<Option<RecordBatch>> {
+        let schema = self.output_schema(batch.schema());
+        let columns = batch
+            .schema()
+            .fields()
+            .iter()
+            .zip(batch.columns())
+            .map(|(field, column)| {
+                if self.is_redacted(field.name()) {
+                    self.redact_column(column)
+                } else {
+                    Ok(column.clone())
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(RecordBatch::try_new(schema, columns)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::builder::Int64Builder;
+    use arrow_array::Int64Array;
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::parquet_writer::{ParquetWriter, ParquetWriterConfig};
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("process_name", DataType::Utf8, false),
+            Field::new("cycles", DataType::Int64, false),
+        ]))
+    }
+
+    fn batch(names: &[&str]) -> RecordBatch {
+        let mut name_builder = StringBuilder::new();
+        let mut cycles_builder = Int64Builder::new();
+        for (i, name) in names.iter().enumerate() {
+            name_builder.append_value(name);
+            cycles_builder.append_value(1000 * i as i64);
+        }
+        RecordBatch::try_new(
+            schema(),
+            vec![
+                Arc::new(name_builder.finish()),
+                Arc::new(cycles_builder.finish()),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn strings(batch: &RecordBatch, name: &str) -> Vec<Option<String>> {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .iter()
+            .map(|value| value.map(str::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn test_missing_targets() {
+        let redact = Redact::new(
+            vec![
+                RedactionTarget::Comm,
+                RedactionTarget::Cmdline,
+                RedactionTarget::PodLabels,
+            ],
+            None,
+        );
+        assert_eq!(
+            redact.missing_targets(&schema()),
+            vec![RedactionTarget::Cmdline, RedactionTarget::PodLabels]
+        );
+    }
+
+    #[test]
+    fn test_renamed_column() {
+        let labels_schema = Arc::new(Schema::new(vec![Field::new(
+            "labels",
+            DataType::Utf8,
+            false,
+        )]));
+        let redact = Redact::new(vec![RedactionTarget::PodLabels], None);
+        assert_eq!(
+            redact.missing_targets(&labels_schema),
+            vec![RedactionTarget::PodLabels]
+        );
+
+        let mut redact = redact.with_column(RedactionTarget::PodLabels, "labels");
+        assert!(redact.missing_targets(&labels_schema).is_empty());
+        let batch = RecordBatch::try_new(
+            labels_schema.clone(),
+            vec![Arc::new(StringArray::from(vec![r#"{"team":"payments"}"#]))],
+        )
+        .unwrap();
+        let redacted = redact.transform(batch).unwrap().unwrap();
+        assert_eq!(strings(&redacted, "labels"), vec![None]);
+    }
+
+    #[test]
+    fn test_hashes_are_keyed_and_deterministic() {
+        let input = batch(&["nginx", "postgres", "nginx"]);
+        let mut redact = Redact::new(vec![RedactionTarget::Comm], Some([7u8; 32]));
+        let first = redact.transform(input.clone()).unwrap().unwrap();
+        let second = redact.transform(input.clone()).unwrap().unwrap();
+
+        let hashes = strings(&first, "process_name");
+        assert_eq!(hashes, strings(&second, "process_name"));
+        assert_eq!(hashes[0], hashes[2]);
+        assert_ne!(hashes[0], hashes[1]);
+        assert!(!hashes.contains(&Some("nginx".to_string())));
+
+        // A different key gives unrelated hashes
+        let mut other_key = Redact::new(vec![RedactionTarget::Comm], Some([8u8; 32]));
+        let other = other_key.transform(input.clone()).unwrap().unwrap();
+        assert_ne!(strings(&other, "process_name")[0], hashes[0]);
+
+        // Numeric columns are untouched
+        assert_eq!(
+            first.column_by_name("cycles"),
+            input.column_by_name("cycles")
+        );
+    }
+
+    fn task_metadata(comm: &[u8]) -> Vec<u8> {
+        let mut msg = TaskMetadataMsg {
+            pid: 42,
+            ..Default::default()
+        };
+        msg.comm[..comm.len()].copy_from_slice(comm);
+        perf_events::collector_sample_record(msg_type::MSG_TYPE_TASK_METADATA as u32, 1, &msg)
+    }
+
+    fn message_comm(data: &[u8]) -> &[u8] {
+        let offset = offset_of!(TaskMetadataMsg, comm);
+        &data[offset..offset + COMM_LEN]
+    }
+
+    #[test]
+    fn test_message_redaction() {
+        let pid = offset_of!(TaskMetadataMsg, pid);
+        let original = task_metadata(b"nginx");
+        assert!(Redact::new(vec![RedactionTarget::Cmdline], None)
+            .message_redaction()
+            .is_none());
+
+        // Without a key, the comm is zeroed and the rest is kept
+        let redaction = Redact::new(vec![RedactionTarget::Comm], None)
+            .message_redaction()
+            .unwrap();
+        let mut data = original.clone();
+        redaction(&mut data);
+        assert_eq!(message_comm(&data), &[0u8; COMM_LEN]);
+        assert_eq!(data[pid..pid + 4], original[pid..pid + 4]);
+
+        // With a key, the comm holds the start of the column's hash
+        let mut redact = Redact::new(vec![RedactionTarget::Comm], Some([7u8; 32]));
+        let hash = strings(
+            &redact.transform(batch(&["nginx"])).unwrap().unwrap(),
+            "process_name",
+        )[0]
+        .clone()
+        .unwrap();
+        let mut data = original.clone();
+        redact.message_redaction().unwrap()(&mut data);
+        assert_eq!(
+            &message_comm(&data)[..COMM_LEN - 1],
+            &hash.as_bytes()[..COMM_LEN - 1]
+        );
+        assert_eq!(message_comm(&data)[COMM_LEN - 1], 0);
+
+        // Other messages are left alone
+        let free = perf_events::collector_sample_record(
+            msg_type::MSG_TYPE_TASK_FREE as u32,
+            1,
+            &bpf::TaskFreeMsg::default(),
+        );
+        let mut data = free.clone();
+        redaction(&mut data);
+        assert_eq!(data, free);
+    }
+
+    #[tokio::test]
+    async fn test_no_plaintext_in_parquet() {
+        for key in [None, Some([3u8; 32])] {
+            let mut redact = Redact::new(vec![RedactionTarget::Comm], key);
+            let output_schema = redact.output_schema(schema());
+
+            let store = Arc::new(InMemory::new());
+            let mut writer =
+                ParquetWriter::new(store.clone(), output_schema, ParquetWriterConfig::default())
+                    .unwrap();
+            let path = writer.size_stats().current_file_path.unwrap();
+            let redacted = redact
+                .transform(batch(&["secret-daemon", "secret-daemon"]))
+                .unwrap()
+                .unwrap();
+            writer.write(redacted).await.unwrap();
+            writer.close().await.unwrap();
+
+            let bytes = store
+                .get(&Path::from(path))
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap();
+            assert!(!bytes.windows(6).any(|window| window == b"secret"));
+
+            let batch = ParquetRecordBatchReaderBuilder::try_new(bytes)
+                .unwrap()
+                .build()
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap();
+            let names = strings(&batch, "process_name");
+            match key {
+                None => assert_eq!(names, vec![None, None]),
+                Some(_) => {
+                    assert_eq!(names[0], names[1]);
+                    assert_eq!(names[0].as_ref().unwrap().len(), 64);
+                }
+            }
+            let cycles = batch
+                .column_by_name("cycles")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap();
+            assert_eq!(cycles.values(), &[0, 1000]);
+        }
+    }
+}
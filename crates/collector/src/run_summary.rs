@@ -0,0 +1,562 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use collector_errors::ErrorCode;
+use object_store::{path::Path, ObjectStore, PutPayload};
+use perf_events::{RingIndexSemantics, RingStats, Stats};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::bpf_perf_to_trace::TraceMemory;
+use crate::metrics::ErrorCounts;
+use crate::noisy_neighbor::ContainerScore;
+use crate::parquet_writer::WriterNotification;
+use crate::shutdown::ShutdownReason;
+
+/// A parquet file produced during the run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileSummary {
+    pub path: String,
+    pub rows: usize,
+    pub bytes: usize,
+}
+
+/// Dispatcher counters at the end of the run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DispatcherCounters {
+    pub samples_processed: usize,
+    pub lost_events_processed: usize,
+    pub callback_errors: usize,
+    pub dropped_messages: usize,
+    pub chunk_errors: usize,
+    pub duplicates_dropped: usize,
+    pub unknown_records: usize,
+}
+
+impl From<Stats> for DispatcherCounters {
+    fn from(stats: Stats) -> Self {
+        Self {
+            samples_processed: stats.samples_processed,
+            lost_events_processed: stats.lost_events_processed,
+            callback_errors: stats.callback_errors,
+            dropped_messages: stats.dropped_messages,
+            chunk_errors: stats.chunk_errors,
+            duplicates_dropped: stats.duplicates_dropped,
+            unknown_records: stats.unknown_records,
+        }
+    }
+}
+
+/// Lost record counters for a single ring
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CpuLoss {
+    pub ring: usize,
+    /// CPU the ring belongs to, null when ring indices are not CPUs
+    pub cpu: Option<usize>,
+    pub lost_records: u64,
+    pub lost_samples: u64,
+}
+
+/// Adaptive collection degradation over the run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DegradationSummary {
+    /// Level at the end of the run
+    pub level: usize,
+    pub max_level: usize,
+    pub transitions: usize,
+}
+
+/// Memory bounding of the trace path over the run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TraceMemorySummary {
+    /// Highest estimated bytes held by trace batches being built or waiting to be written
+    pub peak_bytes: usize,
+    /// Measurements dropped because that estimate reached its limit, because
+    /// the writer stopped, or with a batch the writer could not take
+    pub dropped_events: u64,
+    /// Batches that could not be handed to the writer
+    pub dropped_batches: u64,
+}
+
+impl From<&TraceMemory> for TraceMemorySummary {
+    fn from(memory: &TraceMemory) -> Self {
+        Self {
+            peak_bytes: memory.peak_bytes.load(Ordering::Relaxed),
+            dropped_events: memory.dropped_events.load(Ordering::Relaxed),
+            dropped_batches: memory.dropped_batches.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Summary of what a collector run produced, written to the object store on shutdown
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub node_id: String,
+    /// Output mode, "timeslot", "cgroup_rollup" or "trace"
+    pub mode: String,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+    pub shutdown_reason: Option<ShutdownReason>,
+    pub files: Vec<FileSummary>,
+    pub files_written: usize,
+    pub rows_written: usize,
+    pub bytes_written: usize,
+    /// Number of timeslots converted, absent in trace mode
+    pub timeslots: Option<usize>,
+    /// Timeslot batches lost because the writer stopped before taking them,
+    /// absent in trace mode
+    pub batches_dropped: Option<usize>,
+    /// Batches dropped because an output transform failed
+    pub transform_errors: usize,
+    pub quota_reached: bool,
+    /// When the storage quota was first reached
+    pub quota_reached_at: Option<String>,
+    /// Times free space on the local filesystem fell below the disk guard's threshold
+    pub disk_space_low_events: usize,
+    /// Files deleted by the disk guard to reclaim space
+    pub files_reclaimed: usize,
+    pub bytes_reclaimed: u64,
+    /// Steps of the realtime clock seen by the writer
+    pub clock_steps: usize,
+    /// Errors the collector carried on after, by code
+    pub recovered_errors: BTreeMap<ErrorCode, u64>,
+    pub dispatcher: DispatcherCounters,
+    pub total_lost_samples: u64,
+    /// Per-ring lost counters, only for rings that lost records
+    pub lost_per_cpu: Vec<CpuLoss>,
+    /// Adaptive collection levels, absent unless adaptive collection is enabled
+    pub degradation: Option<DegradationSummary>,
+    /// Highest noisy neighbor scores at the end of the run, absent unless scoring is enabled
+    pub noisy_neighbors: Option<Vec<ContainerScore>>,
+    /// Trace path memory, absent in timeslot mode
+    pub trace_memory: Option<TraceMemorySummary>,
+    /// Kernel problems found before loading, which `--force-load` overrode
+    pub preflight_problems: Vec<String>,
+    /// libbpf output of a failed load under `--force-load`
+    pub verifier_log: Option<String>,
+}
+
+impl RunSummary {
+    /// Create an empty summary for a run that started at `started_at`
+    pub fn new(run_id: &str, node_id: &str, mode: &str, started_at: DateTime<Utc>) -> Self {
+        Self {
+            run_id: run_id.to_string(),
+            node_id: node_id.to_string(),
+            mode: mode.to_string(),
+            started_at: started_at.to_rfc3339(),
+            ended_at: None,
+            shutdown_reason: None,
+            files: Vec::new(),
+            files_written: 0,
+            rows_written: 0,
+            bytes_written: 0,
+            timeslots: None,
+            batches_dropped: None,
+            transform_errors: 0,
+            quota_reached: false,
+            quota_reached_at: None,
+            disk_space_low_events: 0,
+            files_reclaimed: 0,
+            bytes_reclaimed: 0,
+            clock_steps: 0,
+            recovered_errors: BTreeMap::new(),
+            dispatcher: DispatcherCounters::default(),
+            total_lost_samples: 0,
+            lost_per_cpu: Vec::new(),
+            degradation: None,
+            noisy_neighbors: None,
+            trace_memory: None,
+            preflight_problems: Vec::new(),
+            verifier_log: None,
+        }
+    }
+
+    /// Account for a single writer notification
+    pub fn add_writer_notification(&mut self, notification: WriterNotification) {
+        match notification {
+            WriterNotification::FileClosed { path, rows, bytes } => {
+                self.files_written += 1;
+                self.rows_written += rows;
+                self.bytes_written += bytes;
+                self.files.push(FileSummary { path, rows, bytes });
+            }
+            WriterNotification::QuotaReached => {
+                // Later notices of the same quota keep the first time
+                if !self.quota_reached {
+                    self.quota_reached = true;
+                    self.quota_reached_at = Some(Utc::now().to_rfc3339());
+                }
+            }
+            WriterNotification::DiskSpaceLow { .. } => {
+                self.disk_space_low_events += 1;
+            }
+            WriterNotification::DiskSpaceRecovered => {}
+            WriterNotification::ClockStep { .. } => {
+                self.clock_steps += 1;
+            }
+            WriterNotification::FileReclaimed { bytes, .. } => {
+                self.files_reclaimed += 1;
+                self.bytes_reclaimed += bytes;
+            }
+        }
+    }
+
+    /// Account for all notifications currently queued on the writer's channel
+    pub fn drain_writer_notifications(
+        &mut self,
+        receiver: &mut mpsc::UnboundedReceiver<WriterNotification>,
+    ) {
+        while let Ok(notification) = receiver.try_recv() {
+            self.add_writer_notification(notification);
+        }
+    }
+
+    /// Record the errors counted in `counts`
+    pub fn set_error_counts(&mut self, counts: &ErrorCounts) {
+        self.recovered_errors = counts.snapshot();
+    }
+
+    /// Record the dispatcher counters
+    pub fn set_dispatcher_stats(&mut self, stats: Stats) {
+        self.dispatcher = stats.into();
+    }
+
+    /// Record lost counters from the reader's per-ring stats, attributing
+    /// rings to CPUs as `semantics` defines
+    pub fn set_ring_stats(&mut self, rings: &[RingStats], semantics: &RingIndexSemantics) {
+        self.total_lost_samples = rings.iter().map(|r| r.lost_samples).sum();
+        self.lost_per_cpu = rings
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.lost_records > 0)
+            .map(|(ring, r)| CpuLoss {
+                ring,
+                cpu: semantics.ring_cpu(ring),
+                lost_records: r.lost_records,
+                lost_samples: r.lost_samples,
+            })
+            .collect();
+    }
+
+    /// Mark the run as ended now, with the given shutdown reason
+    pub fn finish(&mut self, reason: Option<ShutdownReason>) {
+        self.ended_at = Some(Utc::now().to_rfc3339());
+        self.shutdown_reason = reason;
+    }
+}
+
+/// Generate a run identifier from the current time and a random suffix
+pub fn generate_run_id() -> String {
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    let uuid = uuid::Uuid::new_v4()
+        .to_string()
+        .chars()
+        .take(8)
+        .collect::<String>();
+    format!("{}-{}", timestamp, uuid)
+}
+
+/// Object store path of the summary for a run
+pub fn summary_path(storage_prefix: &str, run_id: &str) -> Path {
+    Path::from(format!("{}{}.summary.json", storage_prefix, run_id))
+}
+
+/// Write the summary as JSON, giving up after `timeout` so an unresponsive
+/// store cannot hang shutdown
+pub async fn write_run_summary(
+    store: &dyn ObjectStore,
+    path: &Path,
+    summary: &RunSummary,
+    timeout: Duration,
+) -> Result<()> {
+    let payload = PutPayload::from(serde_json::to_vec_pretty(summary)?);
+
+    tokio::time::timeout(timeout, store.put(path, payload))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "Timed out writing run summary to {} after {:?}",
+                path,
+                timeout
+            )
+        })??;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use arrow_array::RecordBatch;
+    use object_store::memory::InMemory;
+    use serde_json::Value;
+    use tokio_util::task::TaskTracker;
+
+    use super::*;
+    use crate::metrics::Metric;
+    use crate::parquet_writer::{ParquetWriter, ParquetWriterConfig};
+    use crate::parquet_writer_task::ParquetWriterTask;
+    use crate::shutdown::ShutdownToken;
+    use crate::task_completion_handler::task_completion_handler;
+    use crate::timeslot_data::TimeslotData;
+    use crate::timeslot_to_recordbatch_task::TimeslotToRecordBatchTask;
+
+    #[tokio::test]
+    async fn test_summary_for_duration_run() {
+        let store = Arc::new(InMemory::new());
+        let shutdown_token = ShutdownToken::new();
+        let task_tracker = TaskTracker::new();
+        let started_at = Utc::now();
+
+        // Pipeline: timeslots -> record batches -> parquet writer
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel::<TimeslotData>(10);
+        let (batch_sender, batch_receiver) = mpsc::channel::<RecordBatch>(10);
+        let (_rotate_sender, rotate_receiver) = mpsc::channel::<()>(1);
+        let (notify_sender, mut notify_receiver) = mpsc::unbounded_channel();
+
+        let conversion_task = TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
+        let timeslot_counter = conversion_task.timeslot_counter();
+        let config = ParquetWriterConfig {
+            storage_prefix: "test-node-".to_string(),
+            ..Default::default()
+        };
+        let mut writer =
+            ParquetWriter::new(store.clone(), conversion_task.schema(), config).unwrap();
+        writer.set_notifier(notify_sender);
+
+        task_tracker.spawn(task_completion_handler(
+            conversion_task.run(),
+            shutdown_token.clone(),
+            "TimeslotToRecordBatchTask",
+        ));
+        task_tracker.spawn(task_completion_handler(
+            ParquetWriterTask::new(writer, batch_receiver, rotate_receiver).run(),
+            shutdown_token.clone(),
+            "ParquetWriterTask",
+        ));
+        task_tracker.spawn(task_completion_handler(
+            crate::shutdown::duration_timeout_handler(
+                Duration::from_millis(50),
+                shutdown_token.clone(),
+            ),
+            shutdown_token.clone(),
+            "DurationTimeoutHandler",
+        ));
+        task_tracker.close();
+
+        // Three timeslots with two tasks each
+        for slot in 0..3u64 {
+            let mut timeslot = TimeslotData::new(slot * 1_000_000);
+            timeslot.update(100, None, Metric::from_deltas(10, 20, 1, 2, 1000));
+            timeslot.update(200, None, Metric::from_deltas(30, 40, 3, 4, 2000));
+            timeslot_sender.send(timeslot).await.unwrap();
+        }
+
+        // Run until the duration handler fires, then close the pipeline
+        shutdown_token.cancelled().await;
+        drop(timeslot_sender);
+        task_tracker.wait().await;
+
+        let mut summary = RunSummary::new("run-1", "test-node", "timeslot", started_at);
+        summary.drain_writer_notifications(&mut notify_receiver);
+        summary.timeslots = Some(timeslot_counter.load(Ordering::Relaxed));
+        summary.set_dispatcher_stats(Stats {
+            samples_processed: 42,
+            dropped_messages: 1,
+            ..Default::default()
+        });
+        summary.set_ring_stats(
+            &[
+                RingStats::default(),
+                RingStats {
+                    records: 10,
+                    lost_records: 2,
+                    lost_samples: 7,
+                    ..Default::default()
+                },
+            ],
+            &RingIndexSemantics::default(),
+        );
+        summary.finish(shutdown_token.reason());
+
+        let path = summary_path("test-node-", "run-1");
+        write_run_summary(store.as_ref(), &path, &summary, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        // Read the summary back from the store
+        assert_eq!(path.to_string(), "test-node-run-1.summary.json");
+        let bytes = store.get(&path).await.unwrap().bytes().await.unwrap();
+        let json: Value = serde_json::from_slice(&bytes).unwrap();
+
+        assert_eq!(json["run_id"], "run-1");
+        assert_eq!(json["mode"], "timeslot");
+        assert_eq!(json["shutdown_reason"]["kind"], "duration");
+        assert_eq!(json["files_written"], 1);
+        assert_eq!(json["rows_written"], 6);
+        assert_eq!(json["files"][0]["rows"], 6);
+        assert!(json["bytes_written"].as_u64().unwrap() > 0);
+        assert!(json["files"][0]["path"]
+            .as_str()
+            .unwrap()
+            .starts_with("test-node-"));
+        assert_eq!(json["timeslots"], 3);
+        assert_eq!(json["quota_reached"], false);
+        assert!(json["quota_reached_at"].is_null());
+        assert_eq!(json["disk_space_low_events"], 0);
+        assert_eq!(json["files_reclaimed"], 0);
+        assert_eq!(json["clock_steps"], 0);
+        assert_eq!(json["dispatcher"]["samples_processed"], 42);
+        assert_eq!(json["dispatcher"]["dropped_messages"], 1);
+        assert_eq!(json["total_lost_samples"], 7);
+        assert_eq!(json["lost_per_cpu"].as_array().unwrap().len(), 1);
+        assert_eq!(json["lost_per_cpu"][0]["ring"], 1);
+        assert_eq!(json["lost_per_cpu"][0]["cpu"], 1);
+        assert_eq!(json["lost_per_cpu"][0]["lost_records"], 2);
+        assert!(json["ended_at"].is_string());
+        assert!(json["degradation"].is_null());
+        assert!(json["trace_memory"].is_null());
+    }
+
+    #[test]
+    fn test_quota_reached_once() {
+        let mut summary = RunSummary::new("run-1", "test-node", "timeslot", Utc::now());
+        summary.add_writer_notification(WriterNotification::QuotaReached);
+        let reached_at = summary.quota_reached_at.clone();
+        assert!(summary.quota_reached);
+        assert!(reached_at.is_some());
+
+        std::thread::sleep(Duration::from_millis(2));
+        summary.add_writer_notification(WriterNotification::QuotaReached);
+        assert_eq!(summary.quota_reached_at, reached_at);
+    }
+
+    #[test]
+    fn test_lost_rings_without_cpus() {
+        // Rings with indices of their own meaning are reported without a CPU
+        let mut summary = RunSummary::new("run-1", "test-node", "timeslot", Utc::now());
+        let rings = [RingStats {
+            lost_records: 1,
+            lost_samples: 3,
+            ..Default::default()
+        }];
+        summary.set_ring_stats(&rings, &RingIndexSemantics::Custom(Default::default()));
+        assert_eq!(
+            summary.lost_per_cpu,
+            vec![CpuLoss {
+                ring: 0,
+                cpu: None,
+                lost_records: 1,
+                lost_samples: 3,
+            }]
+        );
+        assert_eq!(summary.total_lost_samples, 3);
+    }
+
+    #[tokio::test]
+    async fn test_summary_records_error_code() {
+        // The writer's files go under a path that is a regular file, so
+        // uploading them fails
+        let dir = std::env::temp_dir().join(format!("run_summary_error_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("blocked"), b"").unwrap();
+        let store = Arc::new(object_store::local::LocalFileSystem::new_with_prefix(&dir).unwrap());
+        let shutdown_token = ShutdownToken::new();
+        let started_at = Utc::now();
+
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel::<TimeslotData>(10);
+        let (batch_sender, batch_receiver) = mpsc::channel::<RecordBatch>(10);
+        let (_rotate_sender, rotate_receiver) = mpsc::channel::<()>(1);
+        let conversion_task = TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
+        let config = ParquetWriterConfig {
+            storage_prefix: "blocked/test-node-".to_string(),
+            ..Default::default()
+        };
+        let writer = ParquetWriter::new(store, conversion_task.schema(), config).unwrap();
+
+        let conversion = tokio::spawn(conversion_task.run());
+        let mut timeslot = TimeslotData::new(0);
+        timeslot.update(100, None, Metric::from_deltas(10, 20, 1, 2, 1000));
+        timeslot_sender.send(timeslot).await.unwrap();
+        drop(timeslot_sender);
+
+        // Closing the writer when the pipeline ends fails the task
+        task_completion_handler(
+            ParquetWriterTask::new(writer, batch_receiver, rotate_receiver).run(),
+            shutdown_token.clone(),
+            "ParquetWriterTask",
+        )
+        .await;
+        conversion.await.unwrap().unwrap();
+
+        let mut summary = RunSummary::new("run-1", "test-node", "timeslot", started_at);
+        summary.finish(shutdown_token.reason());
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["shutdown_reason"]["kind"], "error");
+        assert_eq!(json["shutdown_reason"]["detail"]["code"], "storage_write");
+        assert!(json["shutdown_reason"]["detail"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("ParquetWriterTask failed with error"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_summary_counts_recovered_errors() {
+        // Uploads under the blocked path fail, so rotating the file does
+        let dir =
+            std::env::temp_dir().join(format!("run_summary_recovered_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("blocked"), b"").unwrap();
+        let store = Arc::new(object_store::local::LocalFileSystem::new_with_prefix(&dir).unwrap());
+
+        let (batch_sender, batch_receiver) = mpsc::channel::<RecordBatch>(1);
+        let (rotate_sender, rotate_receiver) = mpsc::channel::<()>(1);
+        let config = ParquetWriterConfig {
+            storage_prefix: "blocked/test-node-".to_string(),
+            ..Default::default()
+        };
+        let schema = Arc::new(arrow_schema::Schema::new(vec![arrow_schema::Field::new(
+            "timestamp",
+            arrow_schema::DataType::Int64,
+            false,
+        )]));
+        let writer = ParquetWriter::new(store, schema, config).unwrap();
+        let counts = Arc::new(ErrorCounts::default());
+        let mut writer_task = ParquetWriterTask::new(writer, batch_receiver, rotate_receiver);
+        writer_task.set_error_counts(counts.clone());
+        let task = tokio::spawn(writer_task.run());
+
+        // The failed rotation is counted, and the writer carries on
+        rotate_sender.send(()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while counts.snapshot().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!task.is_finished());
+        drop(batch_sender);
+        let _ = task.await.unwrap();
+
+        let mut summary = RunSummary::new("run-1", "test-node", "timeslot", Utc::now());
+        summary.set_error_counts(&counts);
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(
+            json["recovered_errors"],
+            serde_json::json!({"storage_write": 1})
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+//! Writing the output schema to a file for downstream consumers.
+//!
+//! `--dump-schema <path>` writes the Arrow schema of the selected output as
+//! JSON and exits, so readers can be generated ahead of time. Each field's
+//! `data_type` is the Arrow type's display form, which
+//! `arrow_schema::DataType` parses back with `FromStr`. Struct fields, such
+//! as the attribution column, are `Struct` with their children in `fields`.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use serde_json::{json, Value};
+
+use crate::bpf_perf_to_trace::{self, TRACE_SCHEMA_VERSION};
+use crate::cgroup_rollup_task::{create_cgroup_rollup_schema, CGROUP_ROLLUP_SCHEMA_VERSION};
+use crate::timeslot_to_recordbatch_task::{create_timeslot_schema, TIMESLOT_SCHEMA_VERSION};
+use crate::{attribution_layout, Command};
+
+/// The schema of the output the options select, before transforms, and its
+/// version
+pub fn output_schema(opts: &Command) -> (SchemaRef, u32) {
+    if opts.trace {
+        (
+            bpf_perf_to_trace::create_schema(attribution_layout(opts)),
+            TRACE_SCHEMA_VERSION,
+        )
+    } else if opts.cgroup_rollup {
+        (create_cgroup_rollup_schema(), CGROUP_ROLLUP_SCHEMA_VERSION)
+    } else {
+        (
+            create_timeslot_schema(attribution_layout(opts)),
+            TIMESLOT_SCHEMA_VERSION,
+        )
+    }
+}
+
+/// The JSON form of a field
+fn field_json(field: &Field) -> Value {
+    let mut value = json!({
+        "name": field.name(),
+        "data_type": field.data_type().to_string(),
+        "nullable": field.is_nullable(),
+        "metadata": field.metadata(),
+    });
+    // The display form of a struct type does not parse back
+    if let DataType::Struct(children) = field.data_type() {
+        value["data_type"] = json!("Struct");
+        value["fields"] = children.iter().map(|child| field_json(child)).collect();
+    }
+    value
+}
+
+/// The JSON form of `schema`, labeled with its output and version
+pub fn schema_json(schema: &Schema, output: &str, version: u32) -> Value {
+    let fields: Vec<Value> = schema
+        .fields()
+        .iter()
+        .map(|field| field_json(field))
+        .collect();
+
+    json!({
+        "output": output,
+        "schema_version": version,
+        "fields": fields,
+        "metadata": schema.metadata(),
+    })
+}
+
+/// Write the JSON form of `schema` to `path`
+pub fn dump_schema(path: &Path, schema: &Schema, output: &str, version: u32) -> Result<()> {
+    let contents = serde_json::to_string_pretty(&schema_json(schema, output, version))?;
+    std::fs::write(path, contents)
+        .with_context(|| format!("Failed to write schema to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use arrow_schema::Fields;
+    use clap::Parser;
+
+    /// Read the fields listed in `value` back from their JSON form
+    fn parse_fields(value: &Value) -> Vec<Field> {
+        value["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| {
+                let metadata: HashMap<String, String> =
+                    serde_json::from_value(field["metadata"].clone()).unwrap();
+                let data_type = match field["data_type"].as_str().unwrap() {
+                    "Struct" => DataType::Struct(Fields::from(parse_fields(field))),
+                    data_type => DataType::from_str(data_type).unwrap(),
+                };
+                Field::new(
+                    field["name"].as_str().unwrap(),
+                    data_type,
+                    field["nullable"].as_bool().unwrap(),
+                )
+                .with_metadata(metadata)
+            })
+            .collect()
+    }
+
+    /// Read a schema back from its JSON form
+    fn parse_schema(value: &Value) -> Schema {
+        let fields = parse_fields(value);
+        let metadata: HashMap<String, String> =
+            serde_json::from_value(value["metadata"].clone()).unwrap();
+        Schema::new(fields).with_metadata(metadata)
+    }
+
+    #[test]
+    fn test_dump_schema_round_trip() {
+        let path = std::env::temp_dir().join(format!("schema_dump_{}.json", std::process::id()));
+
+        for (args, output, version) in [
+            (vec!["collector"], "timeslot", TIMESLOT_SCHEMA_VERSION),
+            (vec!["collector", "--trace"], "trace", TRACE_SCHEMA_VERSION),
+            (
+                vec!["collector", "--flat-attribution"],
+                "timeslot",
+                TIMESLOT_SCHEMA_VERSION,
+            ),
+            (
+                vec!["collector", "--cgroup-rollup"],
+                "cgroup_rollup",
+                CGROUP_ROLLUP_SCHEMA_VERSION,
+            ),
+        ] {
+            let opts = Command::parse_from(args);
+            let (schema, schema_version) = output_schema(&opts);
+            assert_eq!(schema_version, version);
+            dump_schema(&path, &schema, output, schema_version).unwrap();
+
+            let value: Value =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(value["output"], output);
+            assert_eq!(value["schema_version"], version);
+            assert_eq!(parse_schema(&value), *schema);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
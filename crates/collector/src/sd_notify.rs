@@ -0,0 +1,283 @@
+//! Minimal systemd notification protocol support.
+//!
+//! When systemd starts the collector with `Type=notify` it passes a datagram
+//! socket in `NOTIFY_SOCKET`; we report `READY=1` once collection starts and
+//! `STOPPING=1` when shutdown begins. With `WatchdogSec=` set, systemd also
+//! passes `WATCHDOG_USEC` and restarts the service unless it receives
+//! `WATCHDOG=1` within that interval. Pings are only sent from completed
+//! poll cycles, so a wedged polling loop stops petting the watchdog.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+/// Where notifications are sent
+enum NotifyAddr {
+    Path(String),
+    /// Linux abstract socket, given in NOTIFY_SOCKET with a leading '@'
+    Abstract(String),
+}
+
+/// Sends state notifications and watchdog pings to systemd
+pub struct SdNotifier {
+    socket: UnixDatagram,
+    addr: NotifyAddr,
+    /// Minimum time between watchdog pings, None when the watchdog is disabled
+    ping_interval: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl SdNotifier {
+    /// Create a notifier from the environment systemd sets up.
+    ///
+    /// Returns None when NOTIFY_SOCKET is not set, i.e. when not running
+    /// under systemd with notification support.
+    pub fn from_env() -> Option<Self> {
+        let socket = std::env::var("NOTIFY_SOCKET").ok()?;
+        let ping_interval = ping_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+
+        match Self::new(&socket, ping_interval) {
+            Ok(notifier) => {
+                debug!(
+                    "Notifying systemd on {}, watchdog ping interval {:?}",
+                    socket, ping_interval
+                );
+                Some(notifier)
+            }
+            Err(e) => {
+                warn!("Not notifying systemd on {}: {}", socket, e);
+                None
+            }
+        }
+    }
+
+    /// Create a notifier sending to `socket`, pinging the watchdog at most
+    /// once per `ping_interval`
+    pub fn new(socket: &str, ping_interval: Option<Duration>) -> io::Result<Self> {
+        let addr = match socket.strip_prefix('@') {
+            Some(name) => NotifyAddr::Abstract(name.to_string()),
+            None if socket.starts_with('/') => NotifyAddr::Path(socket.to_string()),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "NOTIFY_SOCKET must be an absolute path or start with '@'",
+                ))
+            }
+        };
+
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            ping_interval,
+            last_ping: None,
+        })
+    }
+
+    /// Report that startup finished and collection is running
+    pub fn ready(&self) {
+        self.send_logged("READY=1");
+    }
+
+    /// Report that shutdown has begun
+    pub fn stopping(&self) {
+        self.send_logged("STOPPING=1");
+    }
+
+    /// Record that a full poll and dispatch cycle completed, pinging the
+    /// watchdog if the last ping was at least a ping interval ago
+    pub fn cycle_completed(&mut self) {
+        self.cycle_completed_at(Instant::now());
+    }
+
+    fn cycle_completed_at(&mut self, now: Instant) {
+        let Some(interval) = self.ping_interval else {
+            return;
+        };
+        if let Some(last_ping) = self.last_ping {
+            if now.saturating_duration_since(last_ping) < interval {
+                return;
+            }
+        }
+
+        self.last_ping = Some(now);
+        self.send_logged("WATCHDOG=1");
+    }
+
+    fn send_logged(&self, state: &str) {
+        if let Err(e) = self.send(state) {
+            warn!("Failed to notify systemd of {}: {}", state, e);
+        }
+    }
+
+    fn send(&self, state: &str) -> io::Result<()> {
+        match &self.addr {
+            NotifyAddr::Path(path) => self.socket.send_to(state.as_bytes(), path)?,
+            NotifyAddr::Abstract(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                self.socket.send_to_addr(state.as_bytes(), &addr)?
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Watchdog ping interval from WATCHDOG_USEC and WATCHDOG_PID.
+///
+/// Pings are sent at half the timeout, as systemd recommends. The watchdog is
+/// disabled if the timeout is missing or invalid, or if WATCHDOG_PID names
+/// another process.
+fn ping_interval(
+    watchdog_usec: Option<&str>,
+    watchdog_pid: Option<&str>,
+    pid: u32,
+) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+
+    let usec: u64 = watchdog_usec?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A bound datagram socket standing in for systemd
+    struct FakeSystemd {
+        socket: UnixDatagram,
+        path: PathBuf,
+    }
+
+    impl FakeSystemd {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "collector-sd-notify-{}-{}.sock",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            Self { socket, path }
+        }
+
+        fn notifier(&self, ping_interval: Option<Duration>) -> SdNotifier {
+            SdNotifier::new(self.path.to_str().unwrap(), ping_interval).unwrap()
+        }
+
+        /// All messages received so far
+        fn received(&self) -> Vec<String> {
+            let mut messages = Vec::new();
+            let mut buf = [0u8; 256];
+            while let Ok(len) = self.socket.recv(&mut buf) {
+                messages.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+            }
+            messages
+        }
+    }
+
+    impl Drop for FakeSystemd {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn test_ping_interval() {
+        assert_eq!(
+            ping_interval(Some("10000000"), None, 42),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            ping_interval(Some("10000000"), Some("42"), 42),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(ping_interval(Some("10000000"), Some("43"), 42), None);
+        assert_eq!(ping_interval(Some("0"), None, 42), None);
+        assert_eq!(ping_interval(Some("soon"), None, 42), None);
+        assert_eq!(ping_interval(None, None, 42), None);
+    }
+
+    #[test]
+    fn test_relative_socket_rejected() {
+        assert!(SdNotifier::new("notify.sock", None).is_err());
+    }
+
+    #[test]
+    fn test_message_sequence() {
+        let systemd = FakeSystemd::new("sequence");
+        let interval = Duration::from_secs(5);
+        let mut notifier = systemd.notifier(Some(interval));
+
+        let start = Instant::now();
+        notifier.ready();
+
+        // Cycles completing within an interval of the last ping are not sent
+        notifier.cycle_completed_at(start);
+        notifier.cycle_completed_at(start + Duration::from_secs(1));
+        notifier.cycle_completed_at(start + Duration::from_secs(5));
+        notifier.cycle_completed_at(start + Duration::from_secs(9));
+        notifier.cycle_completed_at(start + Duration::from_secs(10));
+        notifier.stopping();
+
+        assert_eq!(
+            systemd.received(),
+            vec![
+                "READY=1",
+                "WATCHDOG=1",
+                "WATCHDOG=1",
+                "WATCHDOG=1",
+                "STOPPING=1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stalled_loop_stops_pinging() {
+        let systemd = FakeSystemd::new("stalled");
+        let interval = Duration::from_millis(5);
+        let mut notifier = systemd.notifier(Some(interval));
+
+        // A healthy loop pings every interval
+        for _ in 0..3 {
+            notifier.cycle_completed();
+            std::thread::sleep(interval);
+        }
+        assert_eq!(systemd.received(), vec!["WATCHDOG=1"; 3]);
+
+        // A stalled loop completes no cycles, so no pings are sent however
+        // much time passes
+        std::thread::sleep(interval * 4);
+        assert!(systemd.received().is_empty());
+
+        // Pings resume as soon as a cycle completes again
+        notifier.cycle_completed();
+        assert_eq!(systemd.received(), vec!["WATCHDOG=1"]);
+    }
+
+    #[test]
+    fn test_watchdog_disabled() {
+        let systemd = FakeSystemd::new("disabled");
+        let mut notifier = systemd.notifier(None);
+
+        notifier.ready();
+        notifier.cycle_completed();
+        notifier.stopping();
+
+        assert_eq!(systemd.received(), vec!["READY=1", "STOPPING=1"]);
+    }
+}
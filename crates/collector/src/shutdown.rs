@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use collector_errors::ErrorCode;
+use serde::Serialize;
+use tokio::task::AbortHandle;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use tokio_util::task::TaskTracker;
+
+/// Why the collector stopped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum ShutdownReason {
+    /// A termination signal was received
+    Signal(String),
+    /// The configured run duration elapsed
+    Duration,
+    /// A task failed or panicked, with the failure's code if it has one
+    Error {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+    },
+    /// A task finished on its own
+    TaskCompleted(String),
+    /// The container runtime shut the NRI plugin down, at the given Unix
+    /// time in seconds, with `--on-nri-shutdown exit`
+    NriShutdown { received_at: u64 },
+}
+
+/// Cancellation token that also records the reason for the first cancellation,
+/// and the tasks that still have to finish before shutdown completes
+#[derive(Clone, Default)]
+pub struct ShutdownToken {
+    token: CancellationToken,
+    reason: Arc<OnceLock<ShutdownReason>>,
+    running: Arc<Mutex<HashMap<u64, (String, AbortHandle)>>>,
+    next_task_id: Arc<AtomicU64>,
+}
+
+impl ShutdownToken {
+    /// Create a new, uncancelled token
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the token. Only the reason given by the first caller is kept.
+    pub fn cancel(&self, reason: ShutdownReason) {
+        let _ = self.reason.set(reason);
+        self.token.cancel();
+    }
+
+    /// Returns true if the token has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Wait until the token is cancelled
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.token.cancelled()
+    }
+
+    /// The reason recorded by the first cancellation, if any
+    pub fn reason(&self) -> Option<ShutdownReason> {
+        self.reason.get().cloned()
+    }
+
+    /// Record a running task, returning an id to unregister it with
+    pub fn register_task(&self, name: &str, abort: AbortHandle) -> u64 {
+        let id = self.next_task_id.fetch_add(1, Ordering::Relaxed);
+        self.running
+            .lock()
+            .unwrap()
+            .insert(id, (name.to_string(), abort));
+        id
+    }
+
+    /// Forget a task once it has finished
+    pub fn unregister_task(&self, id: u64) {
+        self.running.lock().unwrap().remove(&id);
+    }
+
+    /// Names of the registered tasks that are still running, sorted
+    pub fn running_tasks(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .running
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Abort all registered tasks
+    fn abort_running(&self) {
+        for (_, abort) in self.running.lock().unwrap().values() {
+            abort.abort();
+        }
+    }
+}
+
+/// Wait for the tracker's tasks to finish, for at most `timeout`.
+///
+/// Tasks registered with `token` that are still running when the timeout
+/// expires are logged and aborted, so a task stuck on e.g. an unresponsive
+/// object store cannot wedge shutdown. Returns the names of the aborted tasks.
+pub async fn drain_tasks(
+    tracker: &TaskTracker,
+    token: &ShutdownToken,
+    timeout: Duration,
+) -> Vec<String> {
+    if tokio::time::timeout(timeout, tracker.wait()).await.is_ok() {
+        return Vec::new();
+    }
+
+    let stalled = token.running_tasks();
+    log::error!(
+        "Tasks still running {:?} after shutdown, cancelling: {}",
+        timeout,
+        stalled.join(", ")
+    );
+    token.abort_running();
+    tracker.wait().await;
+    stalled
+}
+
+/// Duration timeout handler - exits when duration completes or cancellation token is triggered
+pub async fn duration_timeout_handler(
+    duration: Duration,
+    cancellation_token: ShutdownToken,
+) -> anyhow::Result<()> {
+    // Wait for either duration timeout or cancellation
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {
+            log::debug!("Duration timeout reached");
+            cancellation_token.cancel(ShutdownReason::Duration);
+        }
+        _ = cancellation_token.cancelled() => {
+            log::debug!("Duration timeout handler cancelled");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task_completion_handler::task_completion_handler;
+
+    #[test]
+    fn test_first_reason_wins() {
+        let token = ShutdownToken::new();
+        let clone = token.clone();
+        assert_eq!(token.reason(), None);
+
+        clone.cancel(ShutdownReason::Duration);
+        token.cancel(ShutdownReason::TaskCompleted("writer".to_string()));
+
+        assert!(token.is_cancelled());
+        assert_eq!(token.reason(), Some(ShutdownReason::Duration));
+    }
+
+    #[tokio::test]
+    async fn test_drain_completes() {
+        let token = ShutdownToken::new();
+        let tracker = TaskTracker::new();
+        tracker.spawn(task_completion_handler(
+            async { Ok::<(), ()>(()) },
+            token.clone(),
+            "Quick",
+        ));
+        tracker.close();
+
+        let stalled = drain_tasks(&tracker, &token, Duration::from_secs(5)).await;
+        assert!(stalled.is_empty());
+        assert!(token.running_tasks().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drain_cancels_stalled_tasks() {
+        let token = ShutdownToken::new();
+        let tracker = TaskTracker::new();
+        tracker.spawn(task_completion_handler(
+            async { Ok::<(), ()>(()) },
+            token.clone(),
+            "Quick",
+        ));
+        tracker.spawn(task_completion_handler(
+            async {
+                std::future::pending::<()>().await;
+                Ok::<(), ()>(())
+            },
+            token.clone(),
+            "Stalled",
+        ));
+        tracker.close();
+
+        let start = std::time::Instant::now();
+        let stalled = drain_tasks(&tracker, &token, Duration::from_millis(100)).await;
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(stalled, vec!["Stalled".to_string()]);
+        assert!(token.running_tasks().is_empty());
+        assert!(tracker.is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use timeslot::TrackerState;
+
+use crate::storage_quota::QuotaState;
+
+/// Collector state kept in a local file across restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CollectorState {
+    /// Timeslot tracker progress at the last clean shutdown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_tracker: Option<TrackerState>,
+    /// Bytes written by each output, counted against the storage quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_quota: Option<QuotaState>,
+    /// Other entries in the file, preserved as-is
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl CollectorState {
+    /// Load the state file, or return an empty state if it does not exist
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Failed to parse state file {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to read state file {}", path.display()))
+            }
+        }
+    }
+
+    /// Write the state file, replacing it atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Failed to write state file {}", path.display()))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to replace state file {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Current CLOCK_MONOTONIC time in nanoseconds, the clock of BPF event timestamps
+pub fn monotonic_now_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip_preserves_other_entries() {
+        let path = std::env::temp_dir().join(format!("collector_state_{}", std::process::id()));
+
+        // A missing file is an empty state
+        let state = CollectorState::load(&path).unwrap();
+        assert!(state.min_tracker.is_none());
+
+        // Entries written by others survive a save
+        fs::write(&path, r#"{"quota": {"bytes_written": 1234}}"#).unwrap();
+        let mut state = CollectorState::load(&path).unwrap();
+        state.min_tracker = Some(TrackerState {
+            time_slot_size: 1_000_000,
+            cpu_timestamps: vec![Some(5_000_000), None],
+        });
+        state.save(&path).unwrap();
+
+        let loaded = CollectorState::load(&path).unwrap();
+        assert_eq!(loaded.min_tracker, state.min_tracker);
+        assert_eq!(loaded.other["quota"]["bytes_written"], 1234);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
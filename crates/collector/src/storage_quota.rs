@@ -0,0 +1,591 @@
+//! Arbitration of the storage quota between the collector's output streams.
+//!
+//! Every parquet writer counts the bytes it produces against a shared
+//! [`QuotaManager`] instead of keeping its own total. The manager holds the
+//! global quota of `--storage-quota` and, for each stream, an optional
+//! reservation, a weight and an optional limit of its own.
+//!
+//! A stream can always use its reservation. Beyond it, streams borrow from
+//! the shared budget, the global quota less every reservation. Streams are
+//! ranked by [`QuotaStream`], trace lowest and timeslots highest, and a
+//! stream stops borrowing once what is left of the shared budget is only the
+//! share kept for the streams that outrank it, their weights relative to all
+//! weights. So as the global quota is approached the trace stops first and
+//! the timeslots last, while each stream keeps its reservation. A stopped
+//! stream behaves as if it reached its own quota.
+//!
+//! Usage is kept in the state file under a quota epoch, and only carried into
+//! a run of the same epoch. Files deleted to reclaim disk space give their
+//! bytes back to their stream.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Output streams sharing the storage quota, lowest priority first
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuotaStream {
+    /// The main output with --trace
+    Trace,
+    /// Container lifecycle events, with --cgroup-filter
+    ContainerEvents,
+    /// Window rollup files, with --window-rollup
+    WindowRollup,
+    /// The main output without --trace, timeslots or their cgroup rollup
+    Timeslots,
+}
+
+impl QuotaStream {
+    /// The stream's name on the command line and in the state file
+    pub fn name(self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+}
+
+/// How a stream shares the global quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamQuota {
+    /// Bytes kept for the stream however much the others write
+    pub reserved: usize,
+    /// Share of the shared budget kept for the stream against lower
+    /// priority streams
+    pub weight: u32,
+    /// Bytes the stream may write at most, whatever the global quota
+    pub limit: Option<usize>,
+}
+
+impl Default for StreamQuota {
+    fn default() -> Self {
+        Self {
+            reserved: 0,
+            weight: 1,
+            limit: None,
+        }
+    }
+}
+
+/// Bytes written by each stream, kept in the state file across restarts
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaState {
+    /// The --storage-quota-epoch of the run that wrote the state; usage is
+    /// restored only into a run of the same epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<String>,
+    /// Bytes of closed files, by stream
+    pub closed_bytes: BTreeMap<QuotaStream, usize>,
+}
+
+struct StreamUsage {
+    quota: StreamQuota,
+    /// Bytes in closed files
+    closed: usize,
+    /// Bytes the writer has in open, buffered or closing files, as of its
+    /// last request
+    pending: usize,
+    /// Once the stream stopped writing for good, the bytes counted for it
+    /// for the rest of the run
+    exhausted: Option<usize>,
+}
+
+impl StreamUsage {
+    /// Bytes counted as closed during this run
+    fn counted(&self) -> usize {
+        self.exhausted.unwrap_or(self.closed)
+    }
+
+    fn used(&self) -> usize {
+        self.counted() + self.pending
+    }
+
+    /// Bytes used beyond the reservation, taken from the shared budget
+    fn borrowed(&self) -> usize {
+        self.used().saturating_sub(self.quota.reserved)
+    }
+}
+
+/// Global and per-stream storage usage, shared by the writers of every
+/// output
+pub struct QuotaManager {
+    global: Option<usize>,
+    streams: Mutex<BTreeMap<QuotaStream, StreamUsage>>,
+}
+
+impl QuotaManager {
+    /// Create a manager for a global quota of `global` bytes, or None for
+    /// streams limited only by their own limits
+    pub fn new(global: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            global,
+            streams: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// The global quota
+    pub fn global(&self) -> Option<usize> {
+        self.global
+    }
+
+    /// Add `stream` with `quota`, returning the handle its writer counts its
+    /// bytes with. Fails if the stream was added before, or if the
+    /// reservations no longer fit in the global quota or the stream's limit.
+    pub fn register(
+        self: &Arc<Self>,
+        stream: QuotaStream,
+        quota: StreamQuota,
+    ) -> Result<QuotaHandle> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.contains_key(&stream) {
+            bail!("Storage quota stream {} added twice", stream.name());
+        }
+        if let Some(limit) = quota.limit {
+            if quota.reserved > limit {
+                bail!(
+                    "Storage quota reservation of {} ({} bytes) exceeds its limit ({} bytes)",
+                    stream.name(),
+                    quota.reserved,
+                    limit
+                );
+            }
+        }
+        let reserved = streams
+            .values()
+            .map(|usage| usage.quota.reserved)
+            .sum::<usize>()
+            + quota.reserved;
+        match self.global {
+            Some(global) if reserved > global => bail!(
+                "Storage quota reservations ({} bytes) exceed --storage-quota ({} bytes)",
+                reserved,
+                global
+            ),
+            None if quota.reserved > 0 => bail!(
+                "Storage quota reservation of {} requires --storage-quota",
+                stream.name()
+            ),
+            _ => {}
+        }
+
+        streams.insert(
+            stream,
+            StreamUsage {
+                quota,
+                closed: 0,
+                pending: 0,
+                exhausted: None,
+            },
+        );
+        Ok(QuotaHandle {
+            manager: self.clone(),
+            stream,
+            name: stream.name(),
+        })
+    }
+
+    /// Count the bytes written by a previous run of the same `epoch`.
+    /// Returns whether the state was of that epoch.
+    pub fn restore(&self, state: &QuotaState, epoch: Option<&str>) -> bool {
+        if epoch.is_none() || state.epoch.as_deref() != epoch {
+            return false;
+        }
+        let mut streams = self.streams.lock().unwrap();
+        for (stream, bytes) in &state.closed_bytes {
+            if let Some(usage) = streams.get_mut(stream) {
+                usage.closed += bytes;
+            }
+        }
+        true
+    }
+
+    /// The bytes of the files closed so far, for the state file. A stream
+    /// that stopped is saved with the bytes it wrote, not with its quota.
+    pub fn state(&self, epoch: Option<&str>) -> QuotaState {
+        let streams = self.streams.lock().unwrap();
+        QuotaState {
+            epoch: epoch.map(str::to_string),
+            closed_bytes: streams
+                .iter()
+                .map(|(stream, usage)| (*stream, usage.closed))
+                .collect(),
+        }
+    }
+
+    /// Bytes `stream` may still write, with `pending` bytes not yet in
+    /// closed files
+    fn allowance(&self, stream: QuotaStream, pending: usize) -> usize {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(usage) = streams.get_mut(&stream) else {
+            return 0;
+        };
+        usage.pending = pending;
+        if usage.exhausted.is_some() {
+            return 0;
+        }
+        let usage = &streams[&stream];
+        let limit_room = usage
+            .quota
+            .limit
+            .map_or(usize::MAX, |limit| limit.saturating_sub(usage.used()));
+
+        let Some(global) = self.global else {
+            return limit_room;
+        };
+        let reserved: usize = streams.values().map(|other| other.quota.reserved).sum();
+        let shared = global.saturating_sub(reserved);
+        let borrowed: usize = streams.values().map(StreamUsage::borrowed).sum();
+
+        // Keep the weighted share of the streams above this one
+        let total_weight: u128 = streams
+            .values()
+            .map(|other| other.quota.weight as u128)
+            .sum();
+        let weight_above: u128 = streams
+            .range((
+                std::ops::Bound::Excluded(stream),
+                std::ops::Bound::Unbounded,
+            ))
+            .map(|(_, other)| other.quota.weight as u128)
+            .sum();
+        let cutoff = (shared as u128 * (total_weight - weight_above))
+            .checked_div(total_weight)
+            .map_or(shared, |cutoff| cutoff as usize);
+
+        let reserved_room = usage.quota.reserved.saturating_sub(usage.used());
+        let shared_room = cutoff.saturating_sub(borrowed);
+        limit_room.min(reserved_room + shared_room)
+    }
+
+    fn record_closed(&self, stream: QuotaStream, bytes: usize) {
+        if let Some(usage) = self.streams.lock().unwrap().get_mut(&stream) {
+            usage.closed += bytes;
+        }
+    }
+
+    fn record_deleted(&self, stream: QuotaStream, bytes: usize) {
+        if let Some(usage) = self.streams.lock().unwrap().get_mut(&stream) {
+            usage.closed = usage.closed.saturating_sub(bytes);
+            if let Some(counted) = usage.exhausted.as_mut() {
+                *counted = counted.saturating_sub(bytes);
+            }
+        }
+    }
+
+    fn closed_size(&self, stream: QuotaStream) -> usize {
+        self.streams
+            .lock()
+            .unwrap()
+            .get(&stream)
+            .map_or(0, StreamUsage::counted)
+    }
+
+    fn exhaust(&self, stream: QuotaStream, used: usize) -> usize {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(usage) = streams.get_mut(&stream) else {
+            return 0;
+        };
+        let cap = match (usage.quota.limit, self.global) {
+            (Some(limit), Some(global)) => limit.min(global),
+            (limit, global) => limit.or(global).unwrap_or(usize::MAX),
+        };
+        let counted = usage.closed.max(used).min(cap);
+        usage.exhausted = Some(counted);
+        counted
+    }
+}
+
+/// A writer's view of the [`QuotaManager`], counting against one stream
+#[derive(Clone)]
+pub struct QuotaHandle {
+    manager: Arc<QuotaManager>,
+    stream: QuotaStream,
+    name: String,
+}
+
+impl QuotaHandle {
+    /// A handle to a manager of its own, for a writer named `name` limited
+    /// to `limit` bytes, or not limited with None
+    pub fn standalone(name: impl Into<String>, limit: Option<usize>) -> Self {
+        let handle = QuotaManager::new(None)
+            .register(
+                QuotaStream::Timeslots,
+                StreamQuota {
+                    limit,
+                    ..Default::default()
+                },
+            )
+            .expect("a single stream without reservation fits");
+        Self {
+            name: name.into(),
+            ..handle
+        }
+    }
+
+    /// The stream the handle counts against
+    pub fn stream(&self) -> QuotaStream {
+        self.stream
+    }
+
+    /// Name of the stream in logs, the writer's own name for a standalone
+    /// handle
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How the stream shares the global quota
+    pub fn settings(&self) -> StreamQuota {
+        self.manager.streams.lock().unwrap()[&self.stream].quota
+    }
+
+    /// Bytes the stream may still write, given the `pending` bytes it has
+    /// in files not closed yet
+    pub fn allowance(&self, pending: usize) -> usize {
+        self.manager.allowance(self.stream, pending)
+    }
+
+    /// Count a closed file of `bytes` bytes
+    pub fn record_closed(&self, bytes: usize) {
+        self.manager.record_closed(self.stream, bytes);
+    }
+
+    /// Give back the bytes of a closed file that was deleted
+    pub fn record_deleted(&self, bytes: usize) {
+        self.manager.record_deleted(self.stream, bytes);
+    }
+
+    /// Bytes in the stream's closed files
+    pub fn closed_size(&self) -> usize {
+        self.manager.closed_size(self.stream)
+    }
+
+    /// Stop the stream for good after it was denied writing with `used`
+    /// bytes, counting those bytes up to its quota. Returns the bytes now
+    /// counted as closed.
+    pub fn exhaust(&self, used: usize) -> usize {
+        self.manager.exhaust(self.stream, used)
+    }
+}
+
+/// Parse a `<stream>=<value>` pair of --storage-quota-reserve or
+/// --storage-quota-weight
+pub fn parse_stream_setting<T: FromStr>(setting: &str) -> Result<(QuotaStream, T), String> {
+    let (stream, value) = setting
+        .split_once('=')
+        .ok_or_else(|| format!("expected <stream>=<value>, got '{}'", setting))?;
+    let stream = QuotaStream::from_str(stream, false)?;
+    let value = value
+        .parse()
+        .map_err(|_| format!("invalid value '{}' for {}", value, stream.name()))?;
+    Ok((stream, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `chunk` bytes at a time to each stream that may, in turn, until
+    /// all stop. Returns the bytes each stream wrote and the order in which
+    /// they stopped.
+    fn write_until_stopped(
+        handles: &[QuotaHandle],
+        chunk: usize,
+    ) -> (Vec<usize>, Vec<QuotaStream>) {
+        let mut written = vec![0; handles.len()];
+        let mut stopped = Vec::new();
+        while stopped.len() < handles.len() {
+            for (handle, written) in handles.iter().zip(&mut written) {
+                if stopped.contains(&handle.stream()) {
+                    continue;
+                }
+                if handle.allowance(0) < chunk {
+                    stopped.push(handle.stream());
+                    continue;
+                }
+                handle.record_closed(chunk);
+                *written += chunk;
+            }
+        }
+        (written, stopped)
+    }
+
+    #[test]
+    fn test_starvation_order() {
+        let manager = QuotaManager::new(Some(10_000));
+        let register = |stream, reserved| {
+            manager
+                .register(
+                    stream,
+                    StreamQuota {
+                        reserved,
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+        };
+        let handles = [
+            register(QuotaStream::Timeslots, 2_000),
+            register(QuotaStream::Trace, 0),
+            register(QuotaStream::WindowRollup, 1_000),
+        ];
+
+        // The trace stops borrowing once only the two thirds of the shared
+        // 7000 bytes kept for the streams above it are left, the rollup once
+        // only the third of the timeslots is, and the timeslots last
+        let (written, stopped) = write_until_stopped(&handles, 10);
+        assert_eq!(
+            stopped,
+            vec![
+                QuotaStream::Trace,
+                QuotaStream::WindowRollup,
+                QuotaStream::Timeslots
+            ]
+        );
+        assert!(written[1] <= 2_333, "{:?}", written);
+        assert!(written[2] > 1_000, "{:?}", written);
+        assert!(written[0] > 2_000 + 2_333, "{:?}", written);
+        assert_eq!(written.iter().sum::<usize>(), 10_000);
+
+        // Every stream is stopped once the global quota is used up
+        assert!(handles.iter().all(|handle| handle.allowance(0) < 10));
+    }
+
+    #[test]
+    fn test_reservations_honored_near_global_quota() {
+        let manager = QuotaManager::new(Some(10_000));
+        let trace = manager
+            .register(QuotaStream::Trace, StreamQuota::default())
+            .unwrap();
+        let events = manager
+            .register(
+                QuotaStream::ContainerEvents,
+                StreamQuota {
+                    reserved: 500,
+                    weight: 0,
+                    limit: Some(800),
+                },
+            )
+            .unwrap();
+        let timeslots = manager
+            .register(
+                QuotaStream::Timeslots,
+                StreamQuota {
+                    reserved: 3_000,
+                    weight: 3,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        // The trace alone takes its quarter of the shared 6500 bytes, and
+        // the timeslots everything else left to borrow
+        assert_eq!(trace.allowance(0), 1_625);
+        trace.record_closed(1_625);
+        assert_eq!(trace.allowance(0), 0);
+        assert_eq!(timeslots.allowance(0), 3_000 + 4_875);
+        timeslots.record_closed(3_000 + 4_875);
+        assert_eq!(timeslots.allowance(0), 0);
+
+        // With the global quota all but used, the events still get their
+        // reservation, and nothing beyond it
+        assert_eq!(events.allowance(0), 500);
+        assert_eq!(events.allowance(400), 100);
+        events.record_closed(500);
+        assert_eq!(events.allowance(0), 0);
+        assert_eq!(
+            manager.state(None).closed_bytes.values().sum::<usize>(),
+            10_000
+        );
+
+        // Reservations must fit in the global quota and the stream's limit
+        assert!(manager
+            .register(
+                QuotaStream::WindowRollup,
+                StreamQuota {
+                    reserved: 6_501,
+                    ..Default::default()
+                },
+            )
+            .is_err());
+        assert!(QuotaManager::new(Some(10_000))
+            .register(
+                QuotaStream::WindowRollup,
+                StreamQuota {
+                    reserved: 200,
+                    weight: 1,
+                    limit: Some(100),
+                },
+            )
+            .is_err());
+        assert!(manager
+            .register(QuotaStream::Trace, StreamQuota::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_exhaust_and_restore() {
+        let handle = QuotaHandle::standalone("rollup", Some(1_000));
+        assert_eq!(handle.name(), "rollup");
+        assert_eq!(handle.allowance(300), 700);
+        // Overshooting the limit counts the limit
+        handle.record_closed(1_200);
+        assert_eq!(handle.exhaust(1_200), 1_000);
+        assert_eq!(handle.allowance(0), 0);
+        assert_eq!(handle.closed_size(), 1_000);
+        // The state holds the bytes written, not the stopped stream's cap
+        assert_eq!(
+            handle.manager.state(None).closed_bytes,
+            BTreeMap::from([(QuotaStream::Timeslots, 1_200)])
+        );
+
+        // Usage carries over to the next run's streams of the same epoch
+        let manager = QuotaManager::new(Some(5_000));
+        let trace = manager
+            .register(QuotaStream::Trace, StreamQuota::default())
+            .unwrap();
+        assert_eq!(trace.name(), "trace");
+        let state = QuotaState {
+            epoch: Some("2024-06".to_string()),
+            closed_bytes: BTreeMap::from([
+                (QuotaStream::Trace, 4_000),
+                (QuotaStream::Timeslots, 1),
+            ]),
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(
+            json,
+            r#"{"epoch":"2024-06","closed_bytes":{"trace":4000,"timeslots":1}}"#
+        );
+        let state: QuotaState = serde_json::from_str(&json).unwrap();
+        assert!(!manager.restore(&state, None));
+        assert!(!manager.restore(&state, Some("2024-07")));
+        assert_eq!(trace.closed_size(), 0);
+        assert!(manager.restore(&state, Some("2024-06")));
+        assert_eq!(trace.closed_size(), 4_000);
+        assert_eq!(trace.allowance(0), 1_000);
+        assert_eq!(
+            manager.state(Some("2024-06")),
+            QuotaState {
+                epoch: Some("2024-06".to_string()),
+                closed_bytes: BTreeMap::from([(QuotaStream::Trace, 4_000)]),
+            }
+        );
+
+        // Deleted files give their bytes back
+        trace.record_deleted(3_000);
+        assert_eq!(trace.closed_size(), 1_000);
+        assert_eq!(trace.allowance(0), 4_000);
+
+        assert_eq!(
+            parse_stream_setting::<usize>("container-events=100"),
+            Ok((QuotaStream::ContainerEvents, 100))
+        );
+        assert!(parse_stream_setting::<u32>("trace").is_err());
+        assert!(parse_stream_setting::<u32>("events=1").is_err());
+        assert!(parse_stream_setting::<u32>("trace=x").is_err());
+    }
+}
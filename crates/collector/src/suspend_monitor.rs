@@ -0,0 +1,174 @@
+//! Detecting that the sync timer stopped because the system was suspended.
+//!
+//! BPF timers armed before a suspend (or some live migrations) can expire far
+//! in the past or never fire again after resuming, and the collector then
+//! silently stops producing timeslots. The [`SuspendMonitor`] is fed the count
+//! of timer messages from the polling loop. When none arrive for several
+//! timer intervals, and CLOCK_BOOTTIME has moved ahead of CLOCK_MONOTONIC
+//! since the last one, the system was suspended in between: CLOCK_MONOTONIC
+//! stops during suspend while CLOCK_BOOTTIME keeps counting.
+
+use std::time::Duration;
+
+use crate::bpf_timeslot_tracker::TIMESLOT_SIZE_NS;
+
+/// Readings of the two clocks whose difference grows while suspended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockReading {
+    pub boottime_ns: u64,
+    pub monotonic_ns: u64,
+}
+
+impl ClockReading {
+    /// Read both clocks, boot time last so it never trails monotonic time
+    pub fn now() -> Self {
+        let monotonic_ns = clock_ns(libc::CLOCK_MONOTONIC);
+        Self {
+            boottime_ns: clock_ns(libc::CLOCK_BOOTTIME),
+            monotonic_ns,
+        }
+    }
+
+    /// Total time spent suspended since boot, in nanoseconds
+    fn suspended_ns(&self) -> u64 {
+        self.boottime_ns.saturating_sub(self.monotonic_ns)
+    }
+}
+
+fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// When a stall of timer messages counts as a suspend
+#[derive(Debug, Clone)]
+pub struct SuspendMonitorConfig {
+    /// Timer intervals without any timer message before the timer is stalled
+    pub stall_intervals: u64,
+    /// Interval of the sync timer
+    pub interval: Duration,
+    /// Smallest jump of CLOCK_BOOTTIME over CLOCK_MONOTONIC that is a suspend
+    pub min_suspend: Duration,
+}
+
+impl Default for SuspendMonitorConfig {
+    fn default() -> Self {
+        Self {
+            stall_intervals: 1000,
+            interval: Duration::from_nanos(TIMESLOT_SIZE_NS),
+            min_suspend: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Watches timer messages for a stall following a suspend
+pub struct SuspendMonitor {
+    config: SuspendMonitorConfig,
+    /// Timer messages seen at the last progress
+    timer_messages: u64,
+    /// Clocks when timer messages last progressed, None before the first check
+    last_progress: Option<ClockReading>,
+}
+
+impl SuspendMonitor {
+    pub fn new(config: SuspendMonitorConfig) -> Self {
+        Self {
+            config,
+            timer_messages: 0,
+            last_progress: None,
+        }
+    }
+
+    /// Check the timer message count at `now`. Returns how long the system
+    /// was suspended when the timer stalled across a suspend, after which the
+    /// timer should be restarted. Reports each suspend once.
+    pub fn check(&mut self, now: ClockReading, timer_messages: u64) -> Option<Duration> {
+        let last_progress = match self.last_progress {
+            Some(last_progress) if timer_messages == self.timer_messages => last_progress,
+            _ => {
+                self.timer_messages = timer_messages;
+                self.last_progress = Some(now);
+                return None;
+            }
+        };
+
+        let stalled_ns = now.monotonic_ns.saturating_sub(last_progress.monotonic_ns);
+        let stall_ns = self.config.interval.as_nanos() as u64 * self.config.stall_intervals;
+        if stalled_ns < stall_ns {
+            return None;
+        }
+
+        let suspended = Duration::from_nanos(
+            now.suspended_ns()
+                .saturating_sub(last_progress.suspended_ns()),
+        );
+        if suspended < self.config.min_suspend {
+            return None;
+        }
+
+        // Wait for another full stall before reporting again
+        self.last_progress = Some(now);
+        Some(suspended)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+    const SEC: u64 = 1_000 * MS;
+
+    fn reading(monotonic_ns: u64, suspended_ns: u64) -> ClockReading {
+        ClockReading {
+            boottime_ns: monotonic_ns + suspended_ns,
+            monotonic_ns,
+        }
+    }
+
+    #[test]
+    fn test_suspend_detection() {
+        let mut monitor = SuspendMonitor::new(SuspendMonitorConfig::default());
+
+        // Ticks flowing, across a suspend the timers survived
+        assert_eq!(monitor.check(reading(10 * SEC, 0), 100), None);
+        assert_eq!(monitor.check(reading(11 * SEC, 0), 1100), None);
+        assert_eq!(monitor.check(reading(11 * SEC + MS, 30 * SEC), 1101), None);
+
+        // A stall without a suspend is not a suspend
+        assert_eq!(monitor.check(reading(13 * SEC, 30 * SEC), 1101), None);
+
+        // Ticks stop after a 60s suspend: reported once stalled for 1000 intervals
+        assert_eq!(monitor.check(reading(14 * SEC, 30 * SEC), 3000), None);
+        assert_eq!(monitor.check(reading(14 * SEC + MS, 90 * SEC), 3000), None);
+        assert_eq!(
+            monitor.check(reading(15 * SEC, 90 * SEC), 3000),
+            Some(Duration::from_secs(60))
+        );
+
+        // Only once, until another suspend
+        assert_eq!(monitor.check(reading(16 * SEC, 90 * SEC), 3000), None);
+        assert_eq!(monitor.check(reading(17 * SEC, 90 * SEC), 3000), None);
+
+        // Ticks resume after the restart
+        assert_eq!(monitor.check(reading(17 * SEC + MS, 90 * SEC), 3001), None);
+        assert_eq!(monitor.check(reading(19 * SEC, 90 * SEC), 3001), None);
+    }
+
+    #[test]
+    fn test_short_suspend_ignored() {
+        let mut monitor = SuspendMonitor::new(SuspendMonitorConfig::default());
+        assert_eq!(monitor.check(reading(SEC, 0), 10), None);
+
+        // A clock adjustment below the minimum suspend
+        assert_eq!(monitor.check(reading(3 * SEC, 500 * MS), 10), None);
+
+        // Boot time includes the time suspended, so never trails monotonic time
+        let now = ClockReading::now();
+        assert!(now.boottime_ns >= now.monotonic_ns);
+    }
+}
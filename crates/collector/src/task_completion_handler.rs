@@ -1,37 +1,62 @@
+use std::any::Any;
 use std::future::Future;
-use tokio_util::sync::CancellationToken;
+
+use collector_errors::ErrorCode;
+
+use crate::error_code::error_code;
+use crate::shutdown::{ShutdownReason, ShutdownToken};
 
 /// Task completion handler that manages task lifecycle and cancellation
 ///
 /// This handler wraps any future that returns a Result and ensures:
 /// 1. Proper logging of success, errors, and panics
-/// 2. Cancellation token is triggered when task completes for any reason
+/// 2. Cancellation token is triggered when task completes for any reason, recording
+///    why the task ended as the shutdown reason, with the error code of an
+///    `anyhow::Error` the task failed with
 /// 3. Graceful handling of all task completion scenarios
-pub async fn task_completion_handler<F, T, E>(future: F, token: CancellationToken, task_name: &str)
+/// 4. The task is registered with the token while running, so a stalled
+///    shutdown can name and abort it
+pub async fn task_completion_handler<F, T, E>(future: F, token: ShutdownToken, task_name: &str)
 where
     F: Future<Output = Result<T, E>> + Send + 'static,
     T: Send + 'static,
     E: Send + 'static + std::fmt::Debug,
 {
     let handle = tokio::spawn(future);
+    let task_id = token.register_task(task_name, handle.abort_handle());
 
-    match handle.await {
+    let reason = match handle.await {
         Ok(Ok(_)) => {
             // Task completed successfully
             log::debug!("{} completed successfully", task_name);
+            ShutdownReason::TaskCompleted(task_name.to_string())
         }
         Ok(Err(error)) => {
             // Task completed but returned an error
             log::error!("{} failed with error: {:?}", task_name, error);
+            let code = (&error as &dyn Any)
+                .downcast_ref::<anyhow::Error>()
+                .and_then(error_code);
+            ShutdownReason::Error {
+                message: format!("{} failed with error: {:?}", task_name, error),
+                code,
+            }
         }
         Err(join_error) => {
             // Task panicked or was cancelled
             log::error!("{} panicked or was cancelled: {:?}", task_name, join_error);
+            ShutdownReason::Error {
+                message: format!("{} panicked or was cancelled", task_name),
+                code: join_error.is_panic().then_some(ErrorCode::Internal),
+            }
         }
-    }
+    };
 
-    // Always cancel the token when task completes for any reason
-    token.cancel();
+    token.unregister_task(task_id);
+
+    // Always cancel the token when task completes for any reason. If another
+    // task already cancelled it, the earlier reason is kept.
+    token.cancel(reason);
 }
 
 #[cfg(test)]
@@ -46,7 +71,7 @@ mod tests {
     async fn test_successful_completion() {
         testing_logger::setup();
 
-        let token = CancellationToken::new();
+        let token = ShutdownToken::new();
         let token_clone = token.clone();
 
         // Create a future that succeeds
@@ -57,6 +82,10 @@ mod tests {
 
         // Verify token was cancelled
         assert!(token_clone.is_cancelled());
+        assert_eq!(
+            token_clone.reason(),
+            Some(ShutdownReason::TaskCompleted("test_task".to_string()))
+        );
 
         // Verify log output
         testing_logger::validate(|captured_logs| {
@@ -70,7 +99,7 @@ mod tests {
     async fn test_error_completion() {
         testing_logger::setup();
 
-        let token = CancellationToken::new();
+        let token = ShutdownToken::new();
         let token_clone = token.clone();
 
         // Create a future that returns an error
@@ -81,6 +110,13 @@ mod tests {
 
         // Verify token was cancelled
         assert!(token_clone.is_cancelled());
+        assert_eq!(
+            token_clone.reason(),
+            Some(ShutdownReason::Error {
+                message: "error_task failed with error: TestError(\"test error\")".to_string(),
+                code: None,
+            })
+        );
 
         // Verify log output
         testing_logger::validate(|captured_logs| {
@@ -97,7 +133,7 @@ mod tests {
     async fn test_panic_completion() {
         testing_logger::setup();
 
-        let token = CancellationToken::new();
+        let token = ShutdownToken::new();
         let token_clone = token.clone();
 
         // Create a future that panics
@@ -110,8 +146,15 @@ mod tests {
         // Run the completion handler
         task_completion_handler(future, token, "panic_task").await;
 
-        // Verify token was cancelled
+        // Verify token was cancelled, with panics classified as internal errors
         assert!(token_clone.is_cancelled());
+        assert!(matches!(
+            token_clone.reason(),
+            Some(ShutdownReason::Error {
+                code: Some(ErrorCode::Internal),
+                ..
+            })
+        ));
 
         // Verify log output
         testing_logger::validate(|captured_logs| {
@@ -128,8 +171,8 @@ mod tests {
     async fn test_multiple_handlers_independent_cancellation() {
         testing_logger::setup();
 
-        let token1 = CancellationToken::new();
-        let token2 = CancellationToken::new();
+        let token1 = ShutdownToken::new();
+        let token2 = ShutdownToken::new();
         let token1_clone = token1.clone();
         let token2_clone = token2.clone();
 
@@ -186,7 +229,7 @@ mod tests {
         ];
 
         for (task_name, _expected_message) in test_cases.iter() {
-            let token = CancellationToken::new();
+            let token = ShutdownToken::new();
             let future = async { Ok::<(), TestError>(()) };
 
             task_completion_handler(future, token.clone(), task_name).await;
@@ -217,7 +260,7 @@ mod tests {
             message: String,
         }
 
-        let token = CancellationToken::new();
+        let token = ShutdownToken::new();
         let token_clone = token.clone();
 
         let future = async {
@@ -243,4 +286,24 @@ mod tests {
             assert!(captured_logs[0].body.contains("Resource not found"));
         });
     }
+
+    #[tokio::test]
+    async fn test_error_code_recorded() {
+        let token = ShutdownToken::new();
+
+        // A perf_events error keeps its code through anyhow
+        let future = async {
+            perf_events::Reader::new().start()?;
+            Ok::<(), anyhow::Error>(())
+        };
+        task_completion_handler(future, token.clone(), "reader_task").await;
+
+        match token.reason() {
+            Some(ShutdownReason::Error { message, code }) => {
+                assert_eq!(code, Some(ErrorCode::Internal));
+                assert!(message.starts_with("reader_task failed with error:"));
+            }
+            other => panic!("expected an error reason, got {:?}", other),
+        }
+    }
 }
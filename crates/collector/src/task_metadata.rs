@@ -42,6 +42,11 @@ impl TaskCollection {
         self.tasks.get(&pid)
     }
 
+    /// Number of tasks in the collection, including those queued for removal
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
     /// Queue a task for removal without immediately removing it
     pub fn queue_removal(&mut self, pid: u32) {
         if self.tasks.contains_key(&pid) {
@@ -1,13 +1,29 @@
+use crate::cgroup_filter::ContainerInfo;
+use crate::cpu_throttle::CpuStat;
 use crate::metrics::Metric;
 use crate::task_metadata::TaskMetadata;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 /// Represents data collected for a specific timeslot
 pub struct TimeslotData {
     /// Timestamp at the end of this timeslot
     pub start_timestamp: u64,
+    /// Number of consecutive timeslots folded into this one, 1 unless merging
+    pub slots_merged: u32,
     /// Map from PID to task data (metadata + metrics)
     pub tasks: HashMap<u32, TaskData>,
+    /// Throttling growth of container cgroups sampled this timeslot, by cgroup id
+    pub throttling: HashMap<u64, CpuStat>,
+    /// Samples the kernel reported lost on full rings during this timeslot
+    pub lost_count: u64,
+    /// Identity and resource limits of the containers' cgroups, by cgroup id
+    pub containers: HashMap<u64, Arc<ContainerInfo>>,
+    /// Whether collection started partway through this timeslot
+    pub partial: bool,
+    /// Whether container metadata had stopped updating when this timeslot
+    /// completed
+    pub attribution_stale: bool,
 }
 
 /// Combines task metadata with metrics
@@ -16,6 +32,8 @@ pub struct TaskData {
     pub metadata: Option<TaskMetadata>,
     /// Performance metrics for this task
     pub metrics: Metric,
+    /// Pid inside the task's container, if translated
+    pub container_pid: Option<u32>,
 }
 
 impl TimeslotData {
@@ -23,7 +41,13 @@ impl TimeslotData {
     pub fn new(start_timestamp: u64) -> Self {
         Self {
             start_timestamp,
+            slots_merged: 1,
             tasks: HashMap::new(),
+            throttling: HashMap::new(),
+            lost_count: 0,
+            containers: HashMap::new(),
+            partial: false,
+            attribution_stale: false,
         }
     }
 
@@ -38,11 +62,27 @@ impl TimeslotData {
         }
     }
 
+    /// Records the pid of a task inside its container
+    pub fn set_container_pid(&mut self, pid: u32, container_pid: u32) {
+        if let Some(task_data) = self.tasks.get_mut(&pid) {
+            task_data.container_pid = Some(container_pid);
+        }
+    }
+
     /// Returns an iterator over all task data
     pub fn iter_tasks(&self) -> impl Iterator<Item = (&u32, &TaskData)> {
         self.tasks.iter()
     }
 
+    /// Returns the cgroup ids of all tasks with metadata
+    pub fn cgroup_ids(&self) -> HashSet<u64> {
+        self.tasks
+            .values()
+            .filter_map(|task| task.metadata.as_ref())
+            .map(|metadata| metadata.cgroup_id)
+            .collect()
+    }
+
     /// Returns the number of tracked tasks
     pub fn task_count(&self) -> usize {
         self.tasks.len()
@@ -52,6 +92,10 @@ impl TimeslotData {
 impl TaskData {
     /// Creates a new task data entry
     pub fn new(metadata: Option<TaskMetadata>, metrics: Metric) -> Self {
-        Self { metadata, metrics }
+        Self {
+            metadata,
+            metrics,
+            container_pid: None,
+        }
     }
 }
@@ -1,32 +1,215 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use arrow_array::builder::{Int32Builder, Int64Builder, StringBuilder};
+use arrow_array::builder::{BooleanBuilder, Int32Builder, Int64Builder, StringBuilder};
 use arrow_array::{ArrayRef, RecordBatch};
 use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use arrow_select::concat::concat_batches;
+use nri::metadata::ContainerLimits;
 use tokio::sync::mpsc;
 
+use crate::attribution::{AttributionBuilder, AttributionLayout};
+use crate::cgroup_filter::ContainerTable;
+use crate::cpu_throttle::ThrottleTable;
+use crate::noisy_neighbor::NoisyNeighborScorer;
 use crate::timeslot_data::TimeslotData;
 
-/// Create the schema for timeslot record batches
-pub fn create_timeslot_schema() -> SchemaRef {
-    Arc::new(Schema::new(vec![
+/// Version of the timeslot schema, bumped whenever columns change. Version 7
+/// nested process_name and container_pid in a struct. Version 8 added the
+/// partial column, and version 9 the attribution_stale column. Version 10
+/// attributes rows to their pod and container in the last columns, with
+/// process_name and container_pid back at the top level. Version 11 made pid
+/// and cgroup_id nullable, null on the lost samples row.
+pub const TIMESLOT_SCHEMA_VERSION: u32 = 11;
+
+/// Create the schema for timeslot record batches, with the container
+/// identity in `layout`
+pub fn create_timeslot_schema(layout: AttributionLayout) -> SchemaRef {
+    let mut fields = vec![
         Field::new("start_time", DataType::Int64, false),
-        Field::new("pid", DataType::Int32, false),
+        // Null on the row of lost samples, which no task is known for
+        Field::new("pid", DataType::Int32, true),
         Field::new("process_name", DataType::Utf8, true),
-        Field::new("cgroup_id", DataType::Int64, false),
+        Field::new("cgroup_id", DataType::Int64, true),
         Field::new("cycles", DataType::Int64, false),
         Field::new("instructions", DataType::Int64, false),
         Field::new("llc_misses", DataType::Int64, false),
         Field::new("cache_references", DataType::Int64, false),
         Field::new("duration", DataType::Int64, false),
-    ]))
+        Field::new("slots_merged", DataType::Int32, false),
+        Field::new("container_pid", DataType::Int32, true),
+        Field::new("nr_throttled_delta", DataType::Int64, true),
+        Field::new("throttled_usec_delta", DataType::Int64, true),
+        // Set only on the row recording samples the kernel lost on full rings
+        Field::new("lost_count", DataType::Int64, true),
+        // Container limits, set on one row of each container per timeslot.
+        // The quota and memory limit are -1 when unlimited.
+        Field::new("cpu_quota_usec", DataType::Int64, true),
+        Field::new("cpu_period_usec", DataType::Int64, true),
+        Field::new("cpu_shares", DataType::Int64, true),
+        Field::new("cpuset_cpus", DataType::Utf8, true),
+        Field::new("cpuset_mems", DataType::Utf8, true),
+        Field::new("memory_limit_bytes", DataType::Int64, true),
+        // Set on the rows of the first timeslot, which collection started partway through
+        Field::new("partial", DataType::Boolean, false),
+        // Set once container metadata stopped updating, see --on-nri-shutdown
+        Field::new("attribution_stale", DataType::Boolean, false),
+    ];
+    // Pod and container of the row's cgroup, null unless it is collected
+    fields.extend(layout.fields());
+    Arc::new(Schema::new(fields))
+}
+
+/// Builders for the container limit columns
+pub(crate) struct LimitColumnsBuilder {
+    cpu_quota: Int64Builder,
+    cpu_period: Int64Builder,
+    cpu_shares: Int64Builder,
+    cpuset_cpus: StringBuilder,
+    cpuset_mems: StringBuilder,
+    memory_limit: Int64Builder,
+}
+
+impl LimitColumnsBuilder {
+    pub(crate) fn with_capacity(rows: usize) -> Self {
+        Self {
+            cpu_quota: Int64Builder::with_capacity(rows),
+            cpu_period: Int64Builder::with_capacity(rows),
+            cpu_shares: Int64Builder::with_capacity(rows),
+            cpuset_cpus: StringBuilder::new(),
+            cpuset_mems: StringBuilder::new(),
+            memory_limit: Int64Builder::with_capacity(rows),
+        }
+    }
+
+    /// Append a row with `limits`, or with nulls if None
+    pub(crate) fn append(&mut self, limits: Option<&ContainerLimits>) {
+        self.cpu_quota
+            .append_option(limits.and_then(|limits| limits.cpu_quota));
+        self.cpu_period
+            .append_option(limits.and_then(|limits| limits.cpu_period.map(|v| v as i64)));
+        self.cpu_shares
+            .append_option(limits.and_then(|limits| limits.cpu_shares.map(|v| v as i64)));
+        self.cpuset_cpus
+            .append_option(limits.and_then(|limits| limits.cpuset_cpus.as_deref()));
+        self.cpuset_mems
+            .append_option(limits.and_then(|limits| limits.cpuset_mems.as_deref()));
+        self.memory_limit
+            .append_option(limits.and_then(|limits| limits.memory_limit));
+    }
+
+    pub(crate) fn finish(mut self, arrays: &mut Vec<ArrayRef>) {
+        arrays.push(Arc::new(self.cpu_quota.finish()));
+        arrays.push(Arc::new(self.cpu_period.finish()));
+        arrays.push(Arc::new(self.cpu_shares.finish()));
+        arrays.push(Arc::new(self.cpuset_cpus.finish()));
+        arrays.push(Arc::new(self.cpuset_mems.finish()));
+        arrays.push(Arc::new(self.memory_limit.finish()));
+    }
+}
+
+/// Timeslots with fewer tasks per shard than this are not split further
+const MIN_SHARD_ROWS: usize = 4096;
+
+/// Default number of shards converting a timeslot: min(4, cores / 8), at least 1
+pub fn default_conversion_parallelism(num_cpus: usize) -> usize {
+    (num_cpus / 8).clamp(1, 4)
+}
+
+/// The rows of a timeslot's tasks, in pid order so conversion is
+/// deterministic however the key space is split
+struct RowOrder {
+    pids: Vec<u32>,
+    /// Whether each row carries the limits of its container, set on the
+    /// container's first row
+    with_limits: Vec<bool>,
 }
 
-/// Convert a TimeslotData to an Arrow RecordBatch
+impl RowOrder {
+    fn new(timeslot: &TimeslotData) -> Self {
+        let mut pids: Vec<u32> = timeslot.tasks.keys().copied().collect();
+        pids.sort_unstable();
+
+        // Containers whose limits were added to a row already
+        let mut limits_emitted = HashSet::new();
+        let with_limits = pids
+            .iter()
+            .map(|pid| {
+                timeslot.tasks[pid]
+                    .metadata
+                    .as_ref()
+                    .is_some_and(|metadata| limits_emitted.insert(metadata.cgroup_id))
+            })
+            .collect();
+        Self { pids, with_limits }
+    }
+}
+
+/// Convert a TimeslotData to an Arrow RecordBatch, with a row per task in pid order
 pub fn timeslot_to_batch(timeslot: TimeslotData, schema: SchemaRef) -> Result<RecordBatch> {
-    // Get the task count to preallocate builders
-    let task_count = timeslot.task_count();
+    let order = RowOrder::new(&timeslot);
+    convert_rows(&timeslot, &order.pids, &order.with_limits, true, schema)
+}
+
+/// Convert a TimeslotData to an Arrow RecordBatch, splitting its tasks into
+/// up to `shards` ranges of pids converted on the blocking pool.
+///
+/// The shards read the timeslot as an immutable snapshot, and their batches
+/// are concatenated in pid order, so the result is the same as that of
+/// [`timeslot_to_batch`].
+pub async fn timeslot_to_batch_sharded(
+    timeslot: TimeslotData,
+    schema: SchemaRef,
+    shards: usize,
+) -> Result<RecordBatch> {
+    let shards = shards.min(timeslot.task_count() / MIN_SHARD_ROWS).max(1);
+    if shards == 1 {
+        return timeslot_to_batch(timeslot, schema);
+    }
+
+    let order = Arc::new(RowOrder::new(&timeslot));
+    let timeslot = Arc::new(timeslot);
+    let shard_rows = order.pids.len().div_ceil(shards);
+    let handles: Vec<_> = (0..shards)
+        .map(|shard| {
+            let start = shard * shard_rows;
+            let end = (start + shard_rows).min(order.pids.len());
+            let (timeslot, order, schema) = (timeslot.clone(), order.clone(), schema.clone());
+            tokio::task::spawn_blocking(move || {
+                convert_rows(
+                    &timeslot,
+                    &order.pids[start..end],
+                    &order.with_limits[start..end],
+                    // The lost samples row follows all tasks
+                    shard == shards - 1,
+                    schema,
+                )
+            })
+        })
+        .collect();
+
+    // Shards may finish in any order, collect them in the order they were split
+    let mut batches = Vec::with_capacity(shards);
+    for handle in handles {
+        batches.push(handle.await??);
+    }
+    concat_batches(&schema, &batches).map_err(|e| anyhow!("Failed to concatenate shards: {}", e))
+}
+
+/// Convert the rows of the tasks `pids` of a timeslot, and the row of its lost
+/// samples if `lost_row` is set
+fn convert_rows(
+    timeslot: &TimeslotData,
+    pids: &[u32],
+    with_limits: &[bool],
+    lost_row: bool,
+    schema: SchemaRef,
+) -> Result<RecordBatch> {
+    let lost_row = lost_row && timeslot.lost_count > 0;
+    // Get the row count to preallocate builders, with a row for lost samples
+    let task_count = pids.len() + usize::from(lost_row);
 
     // Create array builders for each column
     let mut start_time_builder = Int64Builder::with_capacity(task_count);
@@ -35,14 +218,26 @@ pub fn timeslot_to_batch(timeslot: TimeslotData, schema: SchemaRef) -> Result<Re
     // Estimate 16 bytes per string for process names
     let mut process_name_builder = StringBuilder::with_capacity(task_count, task_count * 16);
     let mut cgroup_id_builder = Int64Builder::with_capacity(task_count);
+    let mut attribution_builder =
+        AttributionBuilder::new(AttributionLayout::of(&schema), task_count);
     let mut cycles_builder = Int64Builder::with_capacity(task_count);
     let mut instructions_builder = Int64Builder::with_capacity(task_count);
     let mut llc_misses_builder = Int64Builder::with_capacity(task_count);
     let mut cache_references_builder = Int64Builder::with_capacity(task_count);
     let mut duration_builder = Int64Builder::with_capacity(task_count);
+    let mut slots_merged_builder = Int32Builder::with_capacity(task_count);
+    let mut container_pid_builder = Int32Builder::with_capacity(task_count);
+    let mut nr_throttled_builder = Int64Builder::with_capacity(task_count);
+    let mut throttled_usec_builder = Int64Builder::with_capacity(task_count);
+    let mut lost_count_builder = Int64Builder::with_capacity(task_count);
+    let mut limits_builder = LimitColumnsBuilder::with_capacity(task_count);
+    let mut partial_builder = BooleanBuilder::with_capacity(task_count);
+    let mut stale_builder = BooleanBuilder::with_capacity(task_count);
 
     // Convert timeslot data to arrays
-    for (pid, task_data) in timeslot.iter_tasks() {
+    for (pid, &with_limits) in pids.iter().zip(with_limits) {
+        let task_data = &timeslot.tasks[pid];
+
         // Add start timestamp (common for all tasks in this timeslot)
         start_time_builder.append_value(timeslot.start_timestamp as i64);
 
@@ -63,16 +258,62 @@ pub fn timeslot_to_batch(timeslot: TimeslotData, schema: SchemaRef) -> Result<Re
             cgroup_id_builder.append_value(0); // Default value when no metadata available
         }
 
+        // Every row of a collected container is attributed to it
+        let container = task_data
+            .metadata
+            .as_ref()
+            .and_then(|metadata| timeslot.containers.get(&metadata.cgroup_id));
+        attribution_builder.append(container.map(|info| &info.identity));
+
         // Add metrics
         cycles_builder.append_value(task_data.metrics.cycles as i64);
         instructions_builder.append_value(task_data.metrics.instructions as i64);
         llc_misses_builder.append_value(task_data.metrics.llc_misses as i64);
         cache_references_builder.append_value(task_data.metrics.cache_references as i64);
         duration_builder.append_value(task_data.metrics.time_ns as i64);
+        slots_merged_builder.append_value(timeslot.slots_merged as i32);
+        container_pid_builder.append_option(task_data.container_pid.map(|pid| pid as i32));
+
+        // Throttling is only known for rows of sampled container cgroups
+        let throttling = task_data
+            .metadata
+            .as_ref()
+            .and_then(|metadata| timeslot.throttling.get(&metadata.cgroup_id));
+        nr_throttled_builder.append_option(throttling.map(|stat| stat.nr_throttled as i64));
+        throttled_usec_builder.append_option(throttling.map(|stat| stat.throttled_usec as i64));
+        lost_count_builder.append_null();
+
+        // Limits go on the first row of each container
+        let limits = container.filter(|_| with_limits).map(|info| &info.limits);
+        limits_builder.append(limits);
+        partial_builder.append_value(timeslot.partial);
+        stale_builder.append_value(timeslot.attribution_stale);
+    }
+
+    // Samples lost on full rings are a row of their own, not attributed to a task
+    if lost_row {
+        start_time_builder.append_value(timeslot.start_timestamp as i64);
+        pid_builder.append_null();
+        process_name_builder.append_null();
+        cgroup_id_builder.append_null();
+        attribution_builder.append(None);
+        cycles_builder.append_value(0);
+        instructions_builder.append_value(0);
+        llc_misses_builder.append_value(0);
+        cache_references_builder.append_value(0);
+        duration_builder.append_value(0);
+        slots_merged_builder.append_value(timeslot.slots_merged as i32);
+        container_pid_builder.append_null();
+        nr_throttled_builder.append_null();
+        throttled_usec_builder.append_null();
+        lost_count_builder.append_value(timeslot.lost_count as i64);
+        limits_builder.append(None);
+        partial_builder.append_value(timeslot.partial);
+        stale_builder.append_value(timeslot.attribution_stale);
     }
 
     // Finish building arrays
-    let arrays: Vec<ArrayRef> = vec![
+    let mut arrays: Vec<ArrayRef> = vec![
         Arc::new(start_time_builder.finish()),
         Arc::new(pid_builder.finish()),
         Arc::new(process_name_builder.finish()),
@@ -82,17 +323,58 @@ pub fn timeslot_to_batch(timeslot: TimeslotData, schema: SchemaRef) -> Result<Re
         Arc::new(llc_misses_builder.finish()),
         Arc::new(cache_references_builder.finish()),
         Arc::new(duration_builder.finish()),
+        Arc::new(slots_merged_builder.finish()),
+        Arc::new(container_pid_builder.finish()),
+        Arc::new(nr_throttled_builder.finish()),
+        Arc::new(throttled_usec_builder.finish()),
+        Arc::new(lost_count_builder.finish()),
     ];
+    limits_builder.finish(&mut arrays);
+    arrays.push(Arc::new(partial_builder.finish()));
+    arrays.push(Arc::new(stale_builder.finish()));
+    attribution_builder.finish(&mut arrays);
 
     // Create and return the RecordBatch
     RecordBatch::try_new(schema, arrays).map_err(|e| anyhow!("Failed to create RecordBatch: {}", e))
 }
 
+/// Stop taking timeslots once the writer stopped. Returns the number of
+/// batches lost: the one that could not be sent, and one per queued timeslot.
+pub fn drop_pending_timeslots(receiver: &mut mpsc::Receiver<TimeslotData>) -> usize {
+    receiver.close();
+    let mut dropped = 1;
+    while receiver.try_recv().is_ok() {
+        dropped += 1;
+    }
+    dropped
+}
+
+/// What to do with the first timeslot, which collection started partway
+/// through. The tracker emits a timeslot once every CPU has moved past it, so
+/// the first one holds the incomplete first slot of each CPU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PartialTimeslotPolicy {
+    /// Write it with the partial column set, so consumers can exclude it
+    #[default]
+    Mark,
+    /// Leave it out of the output
+    Drop,
+}
+
 /// Worker task for converting timeslots to record batches
 pub struct TimeslotToRecordBatchTask {
     timeslot_receiver: mpsc::Receiver<TimeslotData>,
     batch_sender: mpsc::Sender<RecordBatch>,
     schema: SchemaRef,
+    timeslot_count: Arc<AtomicUsize>,
+    dropped_batches: Arc<AtomicUsize>,
+    parallelism: usize,
+    throttling: Option<ThrottleTable>,
+    scorer: Option<NoisyNeighborScorer>,
+    containers: Option<ContainerTable>,
+    partial_policy: PartialTimeslotPolicy,
+    /// Whether the next timeslot received is the first
+    awaiting_first: bool,
 }
 
 impl TimeslotToRecordBatchTask {
@@ -101,31 +383,110 @@ impl TimeslotToRecordBatchTask {
         timeslot_receiver: mpsc::Receiver<TimeslotData>,
         batch_sender: mpsc::Sender<RecordBatch>,
     ) -> Self {
-        let schema = create_timeslot_schema();
+        let schema = create_timeslot_schema(AttributionLayout::default());
         Self {
             timeslot_receiver,
             batch_sender,
             schema,
+            timeslot_count: Arc::new(AtomicUsize::new(0)),
+            dropped_batches: Arc::new(AtomicUsize::new(0)),
+            parallelism: 1,
+            throttling: None,
+            scorer: None,
+            containers: None,
+            partial_policy: PartialTimeslotPolicy::default(),
+            awaiting_first: true,
         }
     }
 
+    /// Convert each timeslot in up to `parallelism` shards, see
+    /// [`timeslot_to_batch_sharded`]
+    pub fn set_parallelism(&mut self, parallelism: usize) {
+        self.parallelism = parallelism.max(1);
+    }
+
+    /// Add the container CPU throttling sampled by a worker to the rows
+    pub fn set_throttling(&mut self, throttling: ThrottleTable) {
+        self.throttling = Some(throttling);
+    }
+
+    /// Attribute the rows of each timeslot to the containers in `containers`,
+    /// adding their limits
+    pub fn set_containers(&mut self, containers: ContainerTable) {
+        self.containers = Some(containers);
+    }
+
+    /// Lay the container identity out as `layout` in the output
+    pub fn set_attribution_layout(&mut self, layout: AttributionLayout) {
+        self.schema = create_timeslot_schema(layout);
+    }
+
+    /// Update noisy neighbor scores with each timeslot
+    pub fn set_scorer(&mut self, scorer: NoisyNeighborScorer) {
+        self.scorer = Some(scorer);
+    }
+
+    /// Choose whether the first, partial timeslot is marked or dropped
+    pub fn set_partial_policy(&mut self, policy: PartialTimeslotPolicy) {
+        self.partial_policy = policy;
+    }
+
     /// Get the schema for the record batches this task produces
     pub fn schema(&self) -> SchemaRef {
         self.schema.clone()
     }
 
+    /// Get a handle to the number of timeslots converted so far
+    pub fn timeslot_counter(&self) -> Arc<AtomicUsize> {
+        self.timeslot_count.clone()
+    }
+
+    /// Get a handle to the number of batches lost because the writer stopped
+    pub fn dropped_batch_counter(&self) -> Arc<AtomicUsize> {
+        self.dropped_batches.clone()
+    }
+
     /// Run the task, processing timeslots until the input channel is closed
     pub async fn run(mut self) -> Result<()> {
         loop {
             match self.timeslot_receiver.recv().await {
-                Some(timeslot) => {
+                Some(mut timeslot) => {
+                    if std::mem::take(&mut self.awaiting_first) {
+                        if self.partial_policy == PartialTimeslotPolicy::Drop {
+                            log::debug!(
+                                "Dropping the partial first timeslot at {}",
+                                timeslot.start_timestamp
+                            );
+                            continue;
+                        }
+                        timeslot.partial = true;
+                    }
+                    if let Some(ref throttling) = self.throttling {
+                        timeslot.throttling = throttling.take(&timeslot.cgroup_ids());
+                    }
+                    if let Some(ref containers) = self.containers {
+                        timeslot.containers = containers.get(&timeslot.cgroup_ids());
+                    }
+                    if let Some(ref mut scorer) = self.scorer {
+                        scorer.observe(&timeslot);
+                    }
+
                     // Convert timeslot to a batch
-                    let batch = timeslot_to_batch(timeslot, self.schema.clone())?;
+                    let batch =
+                        timeslot_to_batch_sharded(timeslot, self.schema.clone(), self.parallelism)
+                            .await?;
+                    self.timeslot_count.fetch_add(1, Ordering::Relaxed);
 
                     // Send the batch to the output channel
                     if let Err(_) = self.batch_sender.send(batch).await {
-                        // Receiver dropped, pipeline shutting down
-                        log::debug!("Batch receiver dropped, shutting down conversion task");
+                        // Receiver dropped: the writer finished, so stop
+                        // converting timeslots it would not take
+                        let dropped = drop_pending_timeslots(&mut self.timeslot_receiver);
+                        self.dropped_batches.fetch_add(dropped, Ordering::Relaxed);
+                        log::error!(
+                            "Batch receiver dropped, {} timeslot batches could not be enqueued",
+                            dropped
+                        );
                         break;
                     }
                 }
@@ -144,10 +505,26 @@ impl TimeslotToRecordBatchTask {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::attribution::ContainerIdentity;
+    use crate::cgroup_filter::ContainerInfo;
+    use crate::cpu_throttle::CpuStat;
     use crate::metrics::Metric;
     use crate::task_metadata::TaskMetadata;
     use crate::timeslot_data::TimeslotData;
 
+    /// A container of pod prod/web-0 with `limits`
+    fn container_info(limits: ContainerLimits) -> Arc<ContainerInfo> {
+        Arc::new(ContainerInfo {
+            identity: ContainerIdentity {
+                pod_namespace: "prod".to_string(),
+                pod_name: "web-0".to_string(),
+                container_name: "web".to_string(),
+                container_id: "c1".to_string(),
+            },
+            limits,
+        })
+    }
+
     #[test]
     fn test_timeslot_to_batch_conversion() {
         // Create a test timeslot
@@ -168,17 +545,26 @@ mod tests {
         let metadata2 = Some(TaskMetadata::new(202, comm2, 22222));
         let metrics2 = Metric::from_deltas(3000, 4000, 60, 800, 200000);
         timeslot.update(202, metadata2, metrics2);
+        timeslot.set_container_pid(202, 7);
+        timeslot.slots_merged = 2;
+        timeslot.throttling.insert(
+            22222,
+            CpuStat {
+                nr_throttled: 4,
+                throttled_usec: 2500,
+            },
+        );
 
         // Convert to batch
-        let schema = create_timeslot_schema();
+        let schema = create_timeslot_schema(AttributionLayout::default());
         let batch = timeslot_to_batch(timeslot, schema).unwrap();
 
         // Verify batch structure
         assert_eq!(batch.num_rows(), 2);
-        assert_eq!(batch.num_columns(), 9);
+        assert_eq!(batch.num_columns(), 23);
 
         // Verify content - extract arrays and check values (accounting for unordered timeslot iteration)
-        use arrow_array::{Int32Array, Int64Array, StringArray};
+        use arrow_array::{Array, Int32Array, Int64Array, StringArray};
 
         let start_time_array = batch
             .column(0)
@@ -261,6 +647,215 @@ mod tests {
         assert_eq!(llc_misses_array.value(proc_two_idx), 60);
         assert_eq!(cache_references_array.value(proc_two_idx), 800);
         assert_eq!(duration_array.value(proc_two_idx), 200000);
+
+        // Every row carries the timeslot's merge count
+        let slots_merged_array = batch
+            .column(9)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        assert!(slots_merged_array.iter().all(|v| v == Some(2)));
+
+        // Only the translated task has a container pid
+        let container_pid_array = batch
+            .column(10)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        assert!(container_pid_array.is_null(proc_one_idx));
+        assert_eq!(container_pid_array.value(proc_two_idx), 7);
+
+        // Throttling joins onto the rows of the sampled container's cgroup
+        let nr_throttled_array = batch
+            .column(11)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        let throttled_usec_array = batch
+            .column(12)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert!(nr_throttled_array.is_null(proc_one_idx));
+        assert!(throttled_usec_array.is_null(proc_one_idx));
+        assert_eq!(nr_throttled_array.value(proc_two_idx), 4);
+        assert_eq!(throttled_usec_array.value(proc_two_idx), 2500);
+    }
+
+    #[test]
+    fn test_lost_samples_row() {
+        let timeslot_with_lost = |lost_count| {
+            let mut timeslot = TimeslotData::new(1500000);
+            let metadata = Some(TaskMetadata::new(101, [0u8; 16], 11111));
+            timeslot.update(
+                101,
+                metadata,
+                Metric::from_deltas(1000, 2000, 30, 500, 100000),
+            );
+            timeslot.lost_count = lost_count;
+            timeslot
+        };
+
+        // Without lost samples there is no extra row
+        let batch = timeslot_to_batch(
+            timeslot_with_lost(0),
+            create_timeslot_schema(AttributionLayout::default()),
+        )
+        .unwrap();
+        assert_eq!(batch.num_rows(), 1);
+
+        let batch = timeslot_to_batch(
+            timeslot_with_lost(9),
+            create_timeslot_schema(AttributionLayout::default()),
+        )
+        .unwrap();
+        assert_eq!(batch.num_rows(), 2);
+
+        use arrow_array::{Array, Int32Array, Int64Array};
+        let pid_array = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        let cycles_array = batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        let lost_count_array = batch
+            .column(13)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert!(lost_count_array.is_null(0));
+        assert!(pid_array.is_null(1));
+        assert!(batch.column(3).is_null(1));
+        assert_eq!(cycles_array.value(1), 0);
+        assert_eq!(lost_count_array.value(1), 9);
+    }
+
+    #[test]
+    fn test_attribution_columns() {
+        let timeslot = || {
+            let mut timeslot = TimeslotData::new(1500000);
+            for (pid, cgroup_id) in [(1, 100), (2, 100), (3, 200)] {
+                let metadata = Some(TaskMetadata::new(pid, [0u8; 16], cgroup_id));
+                timeslot.update(
+                    pid,
+                    metadata,
+                    Metric::from_deltas(1000, 2000, 30, 500, 100000),
+                );
+            }
+            timeslot.update(4, None, Metric::from_deltas(1, 1, 0, 0, 10));
+            timeslot
+                .containers
+                .insert(100, container_info(Default::default()));
+            timeslot.lost_count = 2;
+            timeslot
+        };
+
+        use arrow_array::cast::AsArray;
+        use arrow_array::Array;
+        for layout in [AttributionLayout::Nested, AttributionLayout::Flat] {
+            let batch = timeslot_to_batch(timeslot(), create_timeslot_schema(layout)).unwrap();
+            assert_eq!(batch.num_rows(), 5);
+            let pod_name = match layout {
+                AttributionLayout::Nested => {
+                    let attribution = batch.column_by_name("attribution").unwrap().as_struct();
+                    let valid: Vec<bool> = (0..5).map(|row| attribution.is_valid(row)).collect();
+                    // Both rows of the collected container, not the others
+                    // nor the lost samples row
+                    assert_eq!(valid, vec![true, true, false, false, false]);
+                    attribution.column(1).clone()
+                }
+                AttributionLayout::Flat => batch.column_by_name("pod_name").unwrap().clone(),
+            };
+            let pod_name = pod_name.as_string::<i32>();
+            assert_eq!(pod_name.value(0), "web-0");
+            assert_eq!(pod_name.value(1), "web-0");
+            assert!((2..5).all(|row| pod_name.is_null(row)));
+        }
+    }
+
+    #[test]
+    fn test_container_limit_columns() {
+        let mut timeslot = TimeslotData::new(1500000);
+        for (pid, cgroup_id) in [(1, 100), (2, 100), (3, 200)] {
+            let metadata = Some(TaskMetadata::new(pid, [0u8; 16], cgroup_id));
+            timeslot.update(
+                pid,
+                metadata,
+                Metric::from_deltas(1000, 2000, 30, 500, 100000),
+            );
+        }
+        // Unlimited quota, an unset period and a zero memory limit
+        timeslot.containers.insert(
+            100,
+            container_info(ContainerLimits {
+                cpu_quota: Some(ContainerLimits::UNLIMITED),
+                cpu_shares: Some(1024),
+                cpuset_cpus: Some("0-3".to_string()),
+                memory_limit: Some(0),
+                ..Default::default()
+            }),
+        );
+
+        let batch = timeslot_to_batch(
+            timeslot,
+            create_timeslot_schema(AttributionLayout::default()),
+        )
+        .unwrap();
+        assert_eq!(batch.num_rows(), 3);
+
+        use arrow_array::{Array, Int64Array, StringArray};
+        let int64_column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap()
+                .clone()
+        };
+        let cgroup_ids = int64_column("cgroup_id");
+        let cpu_quota = int64_column("cpu_quota_usec");
+        let cpu_period = int64_column("cpu_period_usec");
+        let cpu_shares = int64_column("cpu_shares");
+        let memory_limit = int64_column("memory_limit_bytes");
+        let cpuset_cpus = batch
+            .column_by_name("cpuset_cpus")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let cpuset_mems = batch.column_by_name("cpuset_mems").unwrap();
+
+        // One row of the limited container carries its limits
+        let limited_rows: Vec<usize> = (0..batch.num_rows())
+            .filter(|&row| cgroup_ids.value(row) == 100)
+            .collect();
+        assert_eq!(limited_rows.len(), 2);
+        let with_limits: Vec<usize> = limited_rows
+            .iter()
+            .copied()
+            .filter(|&row| !cpu_quota.is_null(row))
+            .collect();
+        assert_eq!(with_limits.len(), 1);
+        let row = with_limits[0];
+        assert_eq!(cpu_quota.value(row), -1);
+        assert!(cpu_period.is_null(row));
+        assert_eq!(cpu_shares.value(row), 1024);
+        assert_eq!(cpuset_cpus.value(row), "0-3");
+        assert!(cpuset_mems.is_null(row));
+        assert_eq!(memory_limit.value(row), 0);
+
+        // The other rows have no limits
+        for row in (0..batch.num_rows()).filter(|&row| row != with_limits[0]) {
+            assert!(cpu_quota.is_null(row));
+            assert!(cpu_shares.is_null(row));
+            assert!(cpuset_cpus.is_null(row));
+            assert!(memory_limit.is_null(row));
+        }
     }
 
     #[tokio::test]
@@ -272,6 +867,7 @@ mod tests {
         // Create task
         let task = TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
         let schema = task.schema();
+        let timeslot_counter = task.timeslot_counter();
 
         // Start the task
         let task_handle = tokio::spawn(task.run());
@@ -378,5 +974,234 @@ mod tests {
 
         // Wait for task to complete
         task_handle.await.unwrap().unwrap();
+        assert_eq!(timeslot_counter.load(Ordering::Relaxed), 1);
+    }
+
+    /// Run a conversion task with `policy` over three timeslots, returning
+    /// the start time and partial flags of each batch
+    async fn convert_with_partial_policy(policy: PartialTimeslotPolicy) -> Vec<(i64, Vec<bool>)> {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::Int64Type;
+
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel::<TimeslotData>(10);
+        let (batch_sender, mut batch_receiver) = mpsc::channel::<RecordBatch>(10);
+        let mut task = TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
+        task.set_partial_policy(policy);
+        let task_handle = tokio::spawn(task.run());
+
+        // The first timeslot started before collection did
+        for slot in 1..4 {
+            timeslot_sender
+                .send(synthetic_timeslot(slot * 1_000_000, 3))
+                .await
+                .unwrap();
+        }
+        drop(timeslot_sender);
+        task_handle.await.unwrap().unwrap();
+
+        let mut batches = Vec::new();
+        while let Some(batch) = batch_receiver.recv().await {
+            let start_time = batch.column(0).as_primitive::<Int64Type>().value(0);
+            let partial = batch
+                .column_by_name("partial")
+                .unwrap()
+                .as_boolean()
+                .iter()
+                .map(Option::unwrap)
+                .collect();
+            batches.push((start_time, partial));
+        }
+        batches
+    }
+
+    #[tokio::test]
+    async fn test_partial_first_timeslot() {
+        // Marked by default: every row of the first timeslot, the lost
+        // samples row included, and no row of later ones
+        let batches = convert_with_partial_policy(PartialTimeslotPolicy::default()).await;
+        assert_eq!(
+            batches,
+            vec![
+                (1_000_000, vec![true; 4]),
+                (2_000_000, vec![false; 4]),
+                (3_000_000, vec![false; 4]),
+            ]
+        );
+
+        // Dropped on request
+        let batches = convert_with_partial_policy(PartialTimeslotPolicy::Drop).await;
+        assert_eq!(
+            batches,
+            vec![(2_000_000, vec![false; 4]), (3_000_000, vec![false; 4])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attribution_stale() {
+        use arrow_array::cast::AsArray;
+
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel::<TimeslotData>(10);
+        let (batch_sender, mut batch_receiver) = mpsc::channel::<RecordBatch>(10);
+        let task = TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
+        let task_handle = tokio::spawn(task.run());
+
+        // Rows carry the flag of their timeslot
+        let mut columns = Vec::new();
+        for (slot, frozen) in [(1, false), (2, true), (3, true)] {
+            let mut timeslot = synthetic_timeslot(slot * 1_000_000, 3);
+            timeslot.attribution_stale = frozen;
+            timeslot_sender.send(timeslot).await.unwrap();
+            let batch = batch_receiver.recv().await.unwrap();
+            let column: Vec<bool> = batch
+                .column_by_name("attribution_stale")
+                .unwrap()
+                .as_boolean()
+                .iter()
+                .map(Option::unwrap)
+                .collect();
+            columns.push(column);
+        }
+        drop(timeslot_sender);
+        task_handle.await.unwrap().unwrap();
+        assert_eq!(columns, vec![vec![false; 4], vec![true; 4], vec![true; 4]]);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_batches_counted_when_writer_stopped() {
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel::<TimeslotData>(10);
+        let (batch_sender, batch_receiver) = mpsc::channel::<RecordBatch>(1);
+        let task = TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
+        let timeslot_counter = task.timeslot_counter();
+        let dropped_batches = task.dropped_batch_counter();
+
+        // Three timeslots are queued when the writer has already stopped
+        for slot in 0..3u64 {
+            let mut timeslot = TimeslotData::new(slot * 1_000_000);
+            timeslot.update(100, None, Metric::from_deltas(10, 20, 1, 2, 1000));
+            timeslot_sender.send(timeslot).await.unwrap();
+        }
+        drop(batch_receiver);
+        task.run().await.unwrap();
+
+        // The first is converted but cannot be sent, the others are not converted
+        assert_eq!(timeslot_counter.load(Ordering::Relaxed), 1);
+        assert_eq!(dropped_batches.load(Ordering::Relaxed), 3);
+        assert!(timeslot_sender.is_closed());
+    }
+
+    /// A timeslot of `tasks` tasks in 500 cgroups, some with limits and throttling
+    fn synthetic_timeslot(start_timestamp: u64, tasks: u32) -> TimeslotData {
+        let mut timeslot = TimeslotData::new(start_timestamp);
+        for pid in 1..=tasks {
+            let cgroup_id = u64::from(pid % 500);
+            let mut comm = [0u8; 16];
+            let name = format!("task{}", pid);
+            comm[..name.len()].copy_from_slice(name.as_bytes());
+            // Kernel threads have no metadata
+            let metadata = (pid % 10 != 0).then(|| TaskMetadata::new(pid, comm, cgroup_id));
+            let pid64 = u64::from(pid);
+            let metrics = Metric::from_deltas(pid64 * 3, pid64 * 5, pid64 % 17, pid64 % 31, 1000);
+            timeslot.update(pid, metadata, metrics);
+            if pid % 3 == 0 {
+                timeslot.set_container_pid(pid, pid % 100);
+            }
+        }
+        for cgroup_id in (0..500).step_by(7) {
+            timeslot.containers.insert(
+                cgroup_id,
+                container_info(ContainerLimits {
+                    cpu_quota: Some(50000),
+                    cpu_period: Some(100000),
+                    cpuset_cpus: Some("0-3".to_string()),
+                    ..Default::default()
+                }),
+            );
+            timeslot.throttling.insert(
+                cgroup_id,
+                CpuStat {
+                    nr_throttled: cgroup_id,
+                    throttled_usec: cgroup_id * 10,
+                },
+            );
+        }
+        timeslot.lost_count = 3;
+        timeslot
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sharded_conversion_matches_single() {
+        let schema = create_timeslot_schema(AttributionLayout::default());
+        let single = timeslot_to_batch(synthetic_timeslot(1500000, 20000), schema.clone()).unwrap();
+
+        // Rows are in pid order, with the lost samples row last
+        use arrow_array::{Array, Int32Array, Int64Array};
+        let pids = single
+            .column_by_name("pid")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        assert_eq!(single.num_rows(), 20001);
+        assert!(pids.values()[..20000].windows(2).all(|w| w[0] < w[1]));
+        let lost_count = single
+            .column_by_name("lost_count")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(lost_count.null_count(), 20000);
+        assert_eq!(lost_count.value(20000), 3);
+
+        for shards in [2, 4, 16] {
+            let sharded = timeslot_to_batch_sharded(
+                synthetic_timeslot(1500000, 20000),
+                schema.clone(),
+                shards,
+            )
+            .await
+            .unwrap();
+            assert_eq!(sharded, single, "{} shards", shards);
+        }
+
+        // Small timeslots are not split
+        let small = timeslot_to_batch_sharded(synthetic_timeslot(1500000, 10), schema.clone(), 4)
+            .await
+            .unwrap();
+        assert_eq!(
+            small,
+            timeslot_to_batch(synthetic_timeslot(1500000, 10), schema).unwrap()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_parallel_conversion_preserves_slot_order() {
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel::<TimeslotData>(10);
+        let (batch_sender, mut batch_receiver) = mpsc::channel::<RecordBatch>(10);
+        let mut task = TimeslotToRecordBatchTask::new(timeslot_receiver, batch_sender);
+        task.set_parallelism(4);
+        let task_handle = tokio::spawn(task.run());
+
+        // Alternate large and small timeslots, so later ones convert faster
+        let sizes = [20000, 5, 12000, 1, 20000, 3];
+        for (slot, &tasks) in sizes.iter().enumerate() {
+            timeslot_sender
+                .send(synthetic_timeslot(slot as u64 * 1_000_000, tasks))
+                .await
+                .unwrap();
+        }
+        drop(timeslot_sender);
+
+        use arrow_array::Int64Array;
+        for (slot, &tasks) in sizes.iter().enumerate() {
+            let batch = batch_receiver.recv().await.unwrap();
+            assert_eq!(batch.num_rows(), tasks as usize + 1);
+            let start_time = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap();
+            assert_eq!(start_time.value(0), slot as i64 * 1_000_000);
+        }
+        task_handle.await.unwrap().unwrap();
     }
 }
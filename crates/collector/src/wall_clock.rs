@@ -0,0 +1,198 @@
+//! Wall-clock time derived from CLOCK_MONOTONIC, robust to clock steps.
+//!
+//! The realtime clock can jump when NTP steps it, most often backwards
+//! shortly after boot. A [`WallClock`] samples the offset between the
+//! realtime clock and CLOCK_MONOTONIC, and reports a [`ClockStep`] when the
+//! offset moves by more than a threshold between samples. The offset in use
+//! only changes when [`WallClock::rotate`] is called, so the parquet writer
+//! keeps deriving wall-clock time from the same offset until the end of the
+//! file, and times within a file never go backwards.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::state_file::monotonic_now_ns;
+
+/// Source of the two clocks whose offset is tracked
+pub trait Clocks: Send {
+    /// CLOCK_MONOTONIC, in nanoseconds
+    fn monotonic_ns(&self) -> u64;
+    /// Realtime clock, in nanoseconds since the Unix epoch
+    fn realtime_ns(&self) -> i64;
+}
+
+/// The system's clocks
+pub struct SystemClocks;
+
+impl Clocks for SystemClocks {
+    fn monotonic_ns(&self) -> u64 {
+        monotonic_now_ns()
+    }
+
+    fn realtime_ns(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as i64)
+    }
+}
+
+/// A jump of the realtime clock against CLOCK_MONOTONIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockStep {
+    /// CLOCK_MONOTONIC when the step was detected, in nanoseconds
+    pub monotonic_ns: u64,
+    /// How far the realtime clock moved, negative when stepped backwards
+    pub delta_ns: i64,
+}
+
+/// Wall-clock time as CLOCK_MONOTONIC plus an offset, switched explicitly
+pub struct WallClock {
+    clocks: Box<dyn Clocks>,
+    /// Smallest change of offset between samples reported as a step
+    step_threshold_ns: i64,
+    /// Offset used to derive wall-clock time
+    offset_ns: i64,
+    /// Offset seen in the latest sample
+    latest_offset_ns: i64,
+}
+
+impl WallClock {
+    /// Default smallest change of offset counted as a step. NTP slews the
+    /// clock by far less than this between samples.
+    pub const DEFAULT_STEP_THRESHOLD: Duration = Duration::from_secs(1);
+
+    pub fn new(clocks: Box<dyn Clocks>, step_threshold: Duration) -> Self {
+        let offset_ns = Self::read_offset(clocks.as_ref()).1;
+        Self {
+            clocks,
+            step_threshold_ns: step_threshold.as_nanos() as i64,
+            offset_ns,
+            latest_offset_ns: offset_ns,
+        }
+    }
+
+    /// A wall clock following the system's clocks
+    pub fn system() -> Self {
+        Self::new(Box::new(SystemClocks), Self::DEFAULT_STEP_THRESHOLD)
+    }
+
+    /// Read CLOCK_MONOTONIC and the offset of the realtime clock from it
+    fn read_offset(clocks: &dyn Clocks) -> (u64, i64) {
+        let monotonic_ns = clocks.monotonic_ns();
+        (monotonic_ns, clocks.realtime_ns() - monotonic_ns as i64)
+    }
+
+    /// Offset from CLOCK_MONOTONIC to wall-clock time in use, in nanoseconds
+    pub fn offset_ns(&self) -> i64 {
+        self.offset_ns
+    }
+
+    /// Current wall-clock time from the offset in use, in nanoseconds since
+    /// the Unix epoch
+    pub fn now_ns(&self) -> i64 {
+        self.clocks.monotonic_ns() as i64 + self.offset_ns
+    }
+
+    /// Sample the offset, returning the step if it moved by more than the
+    /// threshold since the previous sample. The offset in use is unchanged.
+    pub fn sample(&mut self) -> Option<ClockStep> {
+        let (monotonic_ns, offset_ns) = Self::read_offset(self.clocks.as_ref());
+        let delta_ns = offset_ns - self.latest_offset_ns;
+        self.latest_offset_ns = offset_ns;
+        (delta_ns.abs() > self.step_threshold_ns).then_some(ClockStep {
+            monotonic_ns,
+            delta_ns,
+        })
+    }
+
+    /// Switch to the latest offset, as when starting a new file. Returns
+    /// a step not yet reported by [`WallClock::sample`], if any.
+    pub fn rotate(&mut self) -> Option<ClockStep> {
+        let step = self.sample();
+        self.offset_ns = self.latest_offset_ns;
+        step
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// Clocks set by the test
+    #[derive(Clone, Default)]
+    pub(crate) struct FakeClocks {
+        monotonic_ns: Arc<AtomicU64>,
+        realtime_ns: Arc<AtomicI64>,
+    }
+
+    impl FakeClocks {
+        /// Clocks reading `monotonic_ns` and `realtime_ns`
+        pub(crate) fn new(monotonic_ns: u64, realtime_ns: i64) -> Self {
+            let clocks = Self::default();
+            clocks.monotonic_ns.store(monotonic_ns, Ordering::Relaxed);
+            clocks.realtime_ns.store(realtime_ns, Ordering::Relaxed);
+            clocks
+        }
+
+        /// Let `ns` pass on both clocks
+        pub(crate) fn advance(&self, ns: u64) {
+            self.monotonic_ns.fetch_add(ns, Ordering::Relaxed);
+            self.realtime_ns.fetch_add(ns as i64, Ordering::Relaxed);
+        }
+
+        /// Step the realtime clock by `delta_ns`
+        pub(crate) fn step(&self, delta_ns: i64) {
+            self.realtime_ns.fetch_add(delta_ns, Ordering::Relaxed);
+        }
+    }
+
+    impl Clocks for FakeClocks {
+        fn monotonic_ns(&self) -> u64 {
+            self.monotonic_ns.load(Ordering::Relaxed)
+        }
+
+        fn realtime_ns(&self) -> i64 {
+            self.realtime_ns.load(Ordering::Relaxed)
+        }
+    }
+
+    const SEC: i64 = 1_000_000_000;
+
+    #[test]
+    fn test_clock_steps() {
+        let clocks = FakeClocks::new(10 * SEC as u64, 1_000 * SEC);
+        let mut wall_clock = WallClock::new(Box::new(clocks.clone()), Duration::from_secs(1));
+        assert_eq!(wall_clock.offset_ns(), 990 * SEC);
+        assert_eq!(wall_clock.now_ns(), 1_000 * SEC);
+
+        // Slewing by less than the threshold is not a step
+        clocks.advance(SEC as u64);
+        clocks.step(SEC / 2);
+        assert_eq!(wall_clock.sample(), None);
+
+        // Stepped backwards: reported, but time keeps following the old offset
+        clocks.step(-30 * SEC);
+        assert_eq!(
+            wall_clock.sample(),
+            Some(ClockStep {
+                monotonic_ns: 11 * SEC as u64,
+                delta_ns: -30 * SEC,
+            })
+        );
+        assert_eq!(wall_clock.sample(), None);
+        assert_eq!(wall_clock.now_ns(), 1_001 * SEC);
+
+        // Rotating switches to the stepped offset, without reporting it again
+        assert_eq!(wall_clock.rotate(), None);
+        assert_eq!(wall_clock.now_ns(), 971 * SEC + SEC / 2);
+
+        // A forward step first seen at rotation is reported by it
+        clocks.step(60 * SEC);
+        assert_eq!(
+            wall_clock.rotate().map(|step| step.delta_ns),
+            Some(60 * SEC)
+        );
+        assert_eq!(wall_clock.now_ns(), 1_031 * SEC + SEC / 2);
+    }
+}
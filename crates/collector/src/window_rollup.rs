@@ -0,0 +1,594 @@
+//! Downsampled long-retention output.
+//!
+//! With `--window-rollup`, a [`TimeslotTee`] passes every timeslot on to the
+//! main output unchanged, and sends its per-cgroup sums to a
+//! [`WindowRollupTask`]. The task adds them up over windows aligned to
+//! wall-clock boundaries (every minute by default) and writes a row per
+//! cgroup and window to files of their own, which can be kept far longer
+//! than the full-resolution data.
+//!
+//! BPF timestamps use CLOCK_MONOTONIC, so windows are aligned after adding
+//! the offset from that clock to wall-clock time, when one is configured.
+//! A timeslot is counted whole in the window its start falls in, even if it
+//! ends past the window's edge: timeslots are a millisecond, or a few when
+//! merged, so splitting them proportionally would change the sums very
+//! little while making them fractional.
+//!
+//! Windows the collector did not see all of are flagged as partial: the
+//! first one when collection began after its start, the last one, written
+//! at shutdown, and any window with timeslots the tee left out because the
+//! rollup fell behind. The main output is never held up by the rollup.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use arrow_array::builder::{BooleanBuilder, Int64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use tokio::sync::mpsc;
+
+use crate::cgroup_rollup_task::{rollup, CgroupTotals};
+use crate::metrics::Metric;
+use crate::state_file::monotonic_now_ns;
+use crate::timeslot_data::TimeslotData;
+
+/// Version of the window rollup schema, bumped whenever columns change
+pub const WINDOW_ROLLUP_SCHEMA_VERSION: u32 = 1;
+
+/// Create the schema for window rollup record batches
+pub fn create_window_rollup_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        // Wall-clock nanoseconds since the Unix epoch, if a clock offset is set
+        Field::new("window_start", DataType::Int64, false),
+        Field::new("window_end", DataType::Int64, false),
+        // Tasks without metadata are rolled up into cgroup 0
+        Field::new("cgroup_id", DataType::Int64, false),
+        // Timeslots in which the cgroup had tasks, counting merged ones
+        Field::new("slots", DataType::Int64, false),
+        Field::new("cycles", DataType::Int64, false),
+        Field::new("instructions", DataType::Int64, false),
+        Field::new("llc_misses", DataType::Int64, false),
+        Field::new("cache_references", DataType::Int64, false),
+        Field::new("duration", DataType::Int64, false),
+        Field::new("partial", DataType::Boolean, false),
+    ]))
+}
+
+/// Offset from CLOCK_MONOTONIC, the clock of timeslot timestamps, to
+/// wall-clock time, in nanoseconds
+pub fn wall_clock_offset_ns() -> i64 {
+    let wall_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as i64);
+    wall_ns - monotonic_now_ns() as i64
+}
+
+/// Configuration of the window rollup
+#[derive(Debug, Clone)]
+pub struct WindowRollupConfig {
+    /// Length of each window
+    pub window: Duration,
+    /// Added to timeslot timestamps to align windows to wall-clock time,
+    /// see [`wall_clock_offset_ns`]. Windows align to the raw timestamps
+    /// without it.
+    pub clock_offset_ns: Option<i64>,
+}
+
+impl Default for WindowRollupConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            clock_offset_ns: None,
+        }
+    }
+}
+
+/// The per-cgroup sums of a timeslot, sent from the tee to the rollup task
+#[derive(Debug)]
+pub struct SlotTotals {
+    pub start_timestamp: u64,
+    pub slots_merged: u32,
+    cgroups: BTreeMap<u64, CgroupTotals>,
+    /// Start timestamps of the first and last timeslots the tee left out
+    /// right before this one
+    dropped_before: Option<(u64, u64)>,
+}
+
+impl SlotTotals {
+    /// Sum a timeslot's tasks by cgroup
+    pub fn from_timeslot(timeslot: &TimeslotData) -> Self {
+        Self {
+            start_timestamp: timeslot.start_timestamp,
+            slots_merged: timeslot.slots_merged,
+            cgroups: rollup(timeslot),
+            dropped_before: None,
+        }
+    }
+}
+
+/// Sums of a cgroup over a window
+#[derive(Debug, Default)]
+struct WindowTotals {
+    slots: u64,
+    metrics: Metric,
+}
+
+/// The window timeslots are currently added to
+struct OpenWindow {
+    start: i64,
+    partial: bool,
+    cgroups: BTreeMap<u64, WindowTotals>,
+}
+
+/// Adds timeslot sums up over wall-clock aligned windows
+pub struct WindowRollup {
+    schema: SchemaRef,
+    window_ns: i64,
+    clock_offset_ns: i64,
+    current: Option<OpenWindow>,
+}
+
+impl WindowRollup {
+    /// Create a rollup with the given configuration. The window must not be
+    /// empty.
+    pub fn new(config: WindowRollupConfig) -> Result<Self> {
+        let window_ns = config.window.as_nanos() as i64;
+        if window_ns <= 0 {
+            return Err(anyhow!("The rollup window must be longer than zero"));
+        }
+        Ok(Self {
+            schema: create_window_rollup_schema(),
+            window_ns,
+            clock_offset_ns: config.clock_offset_ns.unwrap_or(0),
+            current: None,
+        })
+    }
+
+    /// Get the schema for the record batches of the rollup
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Add a timeslot to its window, returning the previous window's rows
+    /// when this timeslot is the first of a later window.
+    ///
+    /// Timeslots arriving after their window was written are added to the
+    /// current window instead.
+    ///
+    /// Windows of timeslots the tee left out before this one are flagged
+    /// as partial.
+    pub fn add(&mut self, slot: &SlotTotals) -> Result<Option<RecordBatch>> {
+        let time = self.wall_time(slot.start_timestamp);
+        let window_start = self.window_of(slot.start_timestamp);
+        let dropped = slot
+            .dropped_before
+            .map(|(first, last)| (self.window_of(first), self.window_of(last)));
+        let lost_part =
+            |start: i64| dropped.is_some_and(|(first, last)| first <= start && start <= last);
+
+        let mut batch = None;
+        match &mut self.current {
+            Some(current) if window_start > current.start => {
+                current.partial |= lost_part(current.start);
+                batch = self.take_window(false)?;
+                self.open(window_start, lost_part(window_start));
+            }
+            Some(current) => current.partial |= lost_part(current.start),
+            None => self.open(window_start, time > window_start || lost_part(window_start)),
+        }
+
+        if let Some(current) = &mut self.current {
+            for (&cgroup_id, totals) in &slot.cgroups {
+                let cgroup = current.cgroups.entry(cgroup_id).or_default();
+                cgroup.slots += u64::from(slot.slots_merged);
+                cgroup.metrics.add(&totals.metrics);
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Write the rows of the window in progress, flagged as partial, e.g.
+    /// at shutdown
+    pub fn finish(&mut self) -> Result<Option<RecordBatch>> {
+        self.take_window(true)
+    }
+
+    fn wall_time(&self, timestamp: u64) -> i64 {
+        timestamp as i64 + self.clock_offset_ns
+    }
+
+    /// Start of the window a timeslot starting at `timestamp` is counted in
+    fn window_of(&self, timestamp: u64) -> i64 {
+        self.wall_time(timestamp).div_euclid(self.window_ns) * self.window_ns
+    }
+
+    fn open(&mut self, start: i64, partial: bool) {
+        self.current = Some(OpenWindow {
+            start,
+            partial,
+            cgroups: BTreeMap::new(),
+        });
+    }
+
+    /// Convert the current window to a record batch, or None if it has no rows
+    fn take_window(&mut self, partial: bool) -> Result<Option<RecordBatch>> {
+        let Some(window) = self.current.take() else {
+            return Ok(None);
+        };
+        if window.cgroups.is_empty() {
+            return Ok(None);
+        }
+
+        let rows = window.cgroups.len();
+        let partial = partial || window.partial;
+        let mut window_start_builder = Int64Builder::with_capacity(rows);
+        let mut window_end_builder = Int64Builder::with_capacity(rows);
+        let mut cgroup_id_builder = Int64Builder::with_capacity(rows);
+        let mut slots_builder = Int64Builder::with_capacity(rows);
+        let mut cycles_builder = Int64Builder::with_capacity(rows);
+        let mut instructions_builder = Int64Builder::with_capacity(rows);
+        let mut llc_misses_builder = Int64Builder::with_capacity(rows);
+        let mut cache_references_builder = Int64Builder::with_capacity(rows);
+        let mut duration_builder = Int64Builder::with_capacity(rows);
+        let mut partial_builder = BooleanBuilder::with_capacity(rows);
+
+        for (cgroup_id, cgroup) in window.cgroups {
+            window_start_builder.append_value(window.start);
+            window_end_builder.append_value(window.start + self.window_ns);
+            cgroup_id_builder.append_value(cgroup_id as i64);
+            slots_builder.append_value(cgroup.slots as i64);
+            cycles_builder.append_value(cgroup.metrics.cycles as i64);
+            instructions_builder.append_value(cgroup.metrics.instructions as i64);
+            llc_misses_builder.append_value(cgroup.metrics.llc_misses as i64);
+            cache_references_builder.append_value(cgroup.metrics.cache_references as i64);
+            duration_builder.append_value(cgroup.metrics.time_ns as i64);
+            partial_builder.append_value(partial);
+        }
+
+        let arrays: Vec<ArrayRef> = vec![
+            Arc::new(window_start_builder.finish()),
+            Arc::new(window_end_builder.finish()),
+            Arc::new(cgroup_id_builder.finish()),
+            Arc::new(slots_builder.finish()),
+            Arc::new(cycles_builder.finish()),
+            Arc::new(instructions_builder.finish()),
+            Arc::new(llc_misses_builder.finish()),
+            Arc::new(cache_references_builder.finish()),
+            Arc::new(duration_builder.finish()),
+            Arc::new(partial_builder.finish()),
+        ];
+
+        RecordBatch::try_new(self.schema.clone(), arrays)
+            .map(Some)
+            .map_err(|e| anyhow!("Failed to create RecordBatch: {}", e))
+    }
+}
+
+/// Worker task passing timeslots on to the main output, and their
+/// per-cgroup sums to the window rollup
+pub struct TimeslotTee {
+    timeslot_receiver: mpsc::Receiver<TimeslotData>,
+    timeslot_sender: mpsc::Sender<TimeslotData>,
+    totals_sender: mpsc::Sender<SlotTotals>,
+    /// Timeslots left out of the rollup because it fell behind
+    dropped_totals: usize,
+    /// Start timestamps of the first and last timeslots left out since
+    /// totals were last sent
+    pending_drop: Option<(u64, u64)>,
+}
+
+impl TimeslotTee {
+    /// Create a new TimeslotTee with pre-configured channels
+    pub fn new(
+        timeslot_receiver: mpsc::Receiver<TimeslotData>,
+        timeslot_sender: mpsc::Sender<TimeslotData>,
+        totals_sender: mpsc::Sender<SlotTotals>,
+    ) -> Self {
+        Self {
+            timeslot_receiver,
+            timeslot_sender,
+            totals_sender,
+            dropped_totals: 0,
+            pending_drop: None,
+        }
+    }
+
+    /// Run the task until the input channel is closed. The main output is
+    /// never held up by the rollup: timeslots are left out of it while it is
+    /// behind, and the next totals sent carry the range that was left out.
+    pub async fn run(mut self) -> Result<()> {
+        while let Some(timeslot) = self.timeslot_receiver.recv().await {
+            let mut totals = SlotTotals::from_timeslot(&timeslot);
+            totals.dropped_before = self.pending_drop;
+            match self.totals_sender.try_send(totals) {
+                Ok(()) => self.pending_drop = None,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.dropped_totals += 1;
+                    let first = self
+                        .pending_drop
+                        .map_or(timeslot.start_timestamp, |(first, _)| first);
+                    self.pending_drop = Some((first, timeslot.start_timestamp));
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+
+            if self.timeslot_sender.send(timeslot).await.is_err() {
+                log::error!("Timeslot receiver dropped, stopping the rollup tee");
+                return Ok(());
+            }
+        }
+
+        if self.dropped_totals > 0 {
+            log::warn!(
+                "{} timeslots were left out of the window rollup",
+                self.dropped_totals
+            );
+        }
+        log::debug!("Timeslot channel closed, shutting down rollup tee");
+        Ok(())
+    }
+}
+
+/// Worker task writing the window rollup's rows as record batches
+pub struct WindowRollupTask {
+    totals_receiver: mpsc::Receiver<SlotTotals>,
+    batch_sender: mpsc::Sender<RecordBatch>,
+    rollup: WindowRollup,
+}
+
+impl WindowRollupTask {
+    /// Create a new WindowRollupTask with pre-configured channels
+    pub fn new(
+        config: WindowRollupConfig,
+        totals_receiver: mpsc::Receiver<SlotTotals>,
+        batch_sender: mpsc::Sender<RecordBatch>,
+    ) -> Result<Self> {
+        Ok(Self {
+            totals_receiver,
+            batch_sender,
+            rollup: WindowRollup::new(config)?,
+        })
+    }
+
+    /// Get the schema for the record batches this task produces
+    pub fn schema(&self) -> SchemaRef {
+        self.rollup.schema()
+    }
+
+    /// Run the task until the tee stops, then write the partial last window
+    pub async fn run(mut self) -> Result<()> {
+        while let Some(slot) = self.totals_receiver.recv().await {
+            if let Some(batch) = self.rollup.add(&slot)? {
+                if self.batch_sender.send(batch).await.is_err() {
+                    log::error!("Batch receiver dropped, stopping the window rollup");
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(batch) = self.rollup.finish()? {
+            if self.batch_sender.send(batch).await.is_err() {
+                log::error!("Batch receiver dropped, last rollup window not written");
+            }
+        }
+        log::debug!("Timeslot totals channel closed, shutting down window rollup");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{BooleanArray, Int64Array};
+
+    use collector::task_metadata::TaskMetadata;
+
+    const MS: u64 = 1_000_000;
+
+    fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> &'a T {
+        batch
+            .column_by_name(name)
+            .unwrap()
+            .as_any()
+            .downcast_ref::<T>()
+            .unwrap()
+    }
+
+    /// A timeslot with a task per (cgroup, cycles) entry
+    fn timeslot(start_timestamp: u64, tasks: &[(u64, u64)]) -> TimeslotData {
+        let mut timeslot = TimeslotData::new(start_timestamp);
+        for (pid, &(cgroup_id, cycles)) in tasks.iter().enumerate() {
+            let metadata = Some(TaskMetadata::new(pid as u32, [0u8; 16], cgroup_id));
+            let metrics = Metric::from_deltas(cycles, 2 * cycles, 1, 0, 1000);
+            timeslot.update(pid as u32, metadata, metrics);
+        }
+        timeslot
+    }
+
+    #[tokio::test]
+    async fn test_window_rollup() {
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel(10);
+        let (output_sender, mut output_receiver) = mpsc::channel(10);
+        let (totals_sender, totals_receiver) = mpsc::channel(10);
+        let (batch_sender, mut batch_receiver) = mpsc::channel(10);
+
+        // 10ms windows, with wall-clock time 3ms ahead of the timestamps
+        let config = WindowRollupConfig {
+            window: Duration::from_millis(10),
+            clock_offset_ns: Some(3 * MS as i64),
+        };
+        let task = WindowRollupTask::new(config, totals_receiver, batch_sender).unwrap();
+        let schema = task.schema();
+        let tee = TimeslotTee::new(timeslot_receiver, output_sender, totals_sender);
+        let tee_handle = tokio::spawn(tee.run());
+        let task_handle = tokio::spawn(task.run());
+
+        // The first window starts at wall-clock 10ms, timestamp 7ms
+        let mut spanning = timeslot(15 * MS, &[(100, 4)]);
+        spanning.slots_merged = 4;
+        for slot in [
+            timeslot(7 * MS, &[(100, 10), (200, 1)]),
+            timeslot(8 * MS, &[(100, 20), (100, 5)]),
+            // Starts in the first window and ends in the second
+            spanning,
+            timeslot(17 * MS, &[(200, 7)]),
+            timeslot(20 * MS, &[(100, 1)]),
+        ] {
+            timeslot_sender.send(slot).await.unwrap();
+        }
+
+        // The main output gets every timeslot unchanged
+        for start_timestamp in [7, 8, 15, 17, 20] {
+            let slot = output_receiver.recv().await.unwrap();
+            assert_eq!(slot.start_timestamp, start_timestamp * MS);
+        }
+
+        let first = batch_receiver.recv().await.unwrap();
+        assert_eq!(first.schema(), schema);
+        assert_eq!(
+            column::<Int64Array>(&first, "cgroup_id").values(),
+            &[100, 200]
+        );
+        assert!(column::<Int64Array>(&first, "window_start")
+            .values()
+            .iter()
+            .all(|&start| start == 10 * MS as i64));
+        assert!(column::<Int64Array>(&first, "window_end")
+            .values()
+            .iter()
+            .all(|&end| end == 20 * MS as i64));
+        assert_eq!(
+            column::<Int64Array>(&first, "cycles").values(),
+            &[10 + 20 + 5 + 4, 1]
+        );
+        assert_eq!(
+            column::<Int64Array>(&first, "instructions").values(),
+            &[2 * 39, 2]
+        );
+        assert_eq!(column::<Int64Array>(&first, "slots").values(), &[6, 1]);
+        assert_eq!(
+            column::<Int64Array>(&first, "duration").values(),
+            &[4000, 1000]
+        );
+        assert!(column::<BooleanArray>(&first, "partial")
+            .iter()
+            .all(|partial| partial == Some(false)));
+
+        // The last window is written when the input closes, as partial
+        drop(timeslot_sender);
+        tee_handle.await.unwrap().unwrap();
+        task_handle.await.unwrap().unwrap();
+        let last = batch_receiver.recv().await.unwrap();
+        assert_eq!(
+            column::<Int64Array>(&last, "cgroup_id").values(),
+            &[100, 200]
+        );
+        assert!(column::<Int64Array>(&last, "window_start")
+            .values()
+            .iter()
+            .all(|&start| start == 20 * MS as i64));
+        assert_eq!(column::<Int64Array>(&last, "cycles").values(), &[1, 7]);
+        assert!(column::<BooleanArray>(&last, "partial")
+            .iter()
+            .all(|partial| partial == Some(true)));
+        assert!(batch_receiver.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_dropped_timeslots_mark_windows_partial() {
+        let mut rollup = WindowRollup::new(WindowRollupConfig {
+            window: Duration::from_millis(10),
+            clock_offset_ns: None,
+        })
+        .unwrap();
+        let slot = |start: u64, dropped_before| SlotTotals {
+            dropped_before,
+            ..SlotTotals::from_timeslot(&timeslot(start, &[(1, 1)]))
+        };
+        let partial = |batch: RecordBatch| column::<BooleanArray>(&batch, "partial").value(0);
+
+        // Timeslots 3ms to 11ms were left out: both windows they fall in
+        // are partial, the one after is not
+        assert!(rollup.add(&slot(0, None)).unwrap().is_none());
+        assert!(partial(
+            rollup
+                .add(&slot(12 * MS, Some((3 * MS, 11 * MS))))
+                .unwrap()
+                .unwrap()
+        ));
+        assert!(partial(rollup.add(&slot(20 * MS, None)).unwrap().unwrap()));
+        assert!(!partial(rollup.add(&slot(30 * MS, None)).unwrap().unwrap()));
+
+        // Left out within the current window
+        assert!(rollup
+            .add(&slot(35 * MS, Some((32 * MS, 33 * MS))))
+            .unwrap()
+            .is_none());
+        assert!(partial(rollup.add(&slot(40 * MS, None)).unwrap().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_tee_reports_dropped_timeslots() {
+        let (timeslot_sender, timeslot_receiver) = mpsc::channel(10);
+        let (output_sender, mut output_receiver) = mpsc::channel(10);
+        let (totals_sender, mut totals_receiver) = mpsc::channel(1);
+        let tee_handle =
+            tokio::spawn(TimeslotTee::new(timeslot_receiver, output_sender, totals_sender).run());
+
+        // The rollup does not keep up: only the first totals fit
+        for start in [1, 2, 3] {
+            timeslot_sender
+                .send(timeslot(start * MS, &[]))
+                .await
+                .unwrap();
+            output_receiver.recv().await.unwrap();
+        }
+        let first = totals_receiver.recv().await.unwrap();
+        assert_eq!((first.start_timestamp, first.dropped_before), (MS, None));
+
+        timeslot_sender.send(timeslot(4 * MS, &[])).await.unwrap();
+        output_receiver.recv().await.unwrap();
+        let next = totals_receiver.recv().await.unwrap();
+        assert_eq!(next.start_timestamp, 4 * MS);
+        assert_eq!(next.dropped_before, Some((2 * MS, 3 * MS)));
+
+        drop(timeslot_sender);
+        tee_handle.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_first_window_partial() {
+        let mut rollup = WindowRollup::new(WindowRollupConfig {
+            window: Duration::from_millis(10),
+            clock_offset_ns: None,
+        })
+        .unwrap();
+
+        // Collection starts part way through the first window
+        let batch = rollup
+            .add(&SlotTotals::from_timeslot(&timeslot(4 * MS, &[(1, 1)])))
+            .unwrap();
+        assert!(batch.is_none());
+        let first = rollup
+            .add(&SlotTotals::from_timeslot(&timeslot(10 * MS, &[(1, 1)])))
+            .unwrap()
+            .unwrap();
+        assert_eq!(column::<Int64Array>(&first, "window_start").value(0), 0);
+        assert!(column::<BooleanArray>(&first, "partial").value(0));
+
+        // Empty windows have no rows
+        rollup
+            .add(&SlotTotals::from_timeslot(&timeslot(25 * MS, &[])))
+            .unwrap()
+            .unwrap();
+        assert!(rollup.finish().unwrap().is_none());
+
+        assert!(WindowRollup::new(WindowRollupConfig {
+            window: Duration::ZERO,
+            clock_offset_ns: None,
+        })
+        .is_err());
+    }
+}
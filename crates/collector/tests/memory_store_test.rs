@@ -0,0 +1,50 @@
+//! Running the collector binary against the in-memory store and reading its
+//! output back.
+#![cfg(target_os = "linux")]
+
+use std::fs::File;
+use std::process::Command;
+
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+#[test]
+#[ignore] // This test requires root, run with cargo test -- --ignored
+fn test_memory_store_output() {
+    let dir = std::env::temp_dir().join(format!("collector_memory_store_{}", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_collector"))
+        .args(["--storage-type", "memory", "--duration", "2"])
+        .args(["--prefix", "memory-test-"])
+        .arg("--memory-dump-dir")
+        .arg(&dir)
+        .status()
+        .expect("Failed to run the collector");
+    assert!(status.success(), "collector exited with {}", status);
+
+    // Everything written went to the store, and from there to the dump
+    let names: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(names.iter().all(|name| name.starts_with("memory-test-")));
+    assert!(names.iter().any(|name| name.ends_with(".summary.json")));
+
+    let parquet_files: Vec<&String> = names
+        .iter()
+        .filter(|name| name.ends_with(".parquet"))
+        .collect();
+    assert_eq!(parquet_files.len(), 1, "{:?}", names);
+    let reader =
+        ParquetRecordBatchReaderBuilder::try_new(File::open(dir.join(parquet_files[0])).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+    let mut rows = 0;
+    for batch in reader {
+        let batch = batch.unwrap();
+        assert!(batch.schema().index_of("start_time").is_ok());
+        rows += batch.num_rows();
+    }
+    assert!(rows > 0, "no timeslot rows were written");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,141 @@
+//! # Collector errors
+//!
+//! Error codes shared by the collector crates. Each crate keeps its own error
+//! types, and implements [`Classified`] for them so the collector can report
+//! a stable [`ErrorCode`] for a failure rather than only its message.
+//!
+//! Errors without a classified type, such as those from libbpf or the
+//! parquet writer, are tagged with a [`Coded`] error or context.
+
+use std::fmt;
+
+use serde::Serialize;
+use thiserror::Error;
+
+/// Stable code for a class of failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Opening or loading the BPF programs failed
+    BpfLoad,
+    /// Attaching a BPF program failed
+    BpfAttach,
+    /// Starting the per-CPU sync timers failed
+    SyncTimer,
+    /// Opening a perf event or mapping its ring failed
+    PerfOpen,
+    /// A perf ring could not be set up, or was used incorrectly
+    RingSetup,
+    /// Records were lost or overwritten before they were read
+    RingLost,
+    /// A record in a perf ring could not be decoded
+    RingCorrupt,
+    /// Writing output to the object store failed
+    StorageWrite,
+    /// The storage quota could not be tracked or enforced
+    StorageQuota,
+    /// Reading local files or filesystem state failed, such as the free
+    /// space of the output directory
+    Io,
+    /// Connecting to the container runtime over NRI failed
+    NriConnect,
+    /// The container runtime sent malformed NRI traffic
+    NriProtocol,
+    /// A bug or misuse within the collector
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code's name, as written to the run summary
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BpfLoad => "bpf_load",
+            ErrorCode::BpfAttach => "bpf_attach",
+            ErrorCode::SyncTimer => "sync_timer",
+            ErrorCode::PerfOpen => "perf_open",
+            ErrorCode::RingSetup => "ring_setup",
+            ErrorCode::RingLost => "ring_lost",
+            ErrorCode::RingCorrupt => "ring_corrupt",
+            ErrorCode::StorageWrite => "storage_write",
+            ErrorCode::StorageQuota => "storage_quota",
+            ErrorCode::Io => "io",
+            ErrorCode::NriConnect => "nri_connect",
+            ErrorCode::NriProtocol => "nri_protocol",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    /// Whether failures of this class are usually transient, so retrying
+    /// the operation may succeed
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::RingLost | ErrorCode::StorageWrite | ErrorCode::NriConnect
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error that can be classified by an [`ErrorCode`]
+pub trait Classified {
+    /// The class of this error
+    fn code(&self) -> ErrorCode;
+
+    /// Whether retrying the failed operation may succeed. Defaults to
+    /// whether the code's failures are usually transient.
+    fn retryable(&self) -> bool {
+        self.code().retryable()
+    }
+}
+
+/// An error message tagged with a code, for failures whose error type is
+/// not [`Classified`]. Usable as an error or as context on another error.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{message}")]
+pub struct Coded {
+    code: ErrorCode,
+    message: String,
+}
+
+impl Coded {
+    /// Tag `message` with `code`
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Classified for Coded {
+    fn code(&self) -> ErrorCode {
+        self.code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(ErrorCode::StorageQuota.to_string(), "storage_quota");
+        assert_eq!(ErrorCode::Io.to_string(), "io");
+        assert_eq!(
+            serde_json::to_value(ErrorCode::BpfAttach).unwrap(),
+            "bpf_attach"
+        );
+        assert!(ErrorCode::NriConnect.retryable());
+        assert!(!ErrorCode::BpfLoad.retryable());
+
+        let coded = Coded::new(ErrorCode::StorageWrite, "upload failed");
+        assert_eq!(coded.to_string(), "upload failed");
+        assert_eq!(coded.code(), ErrorCode::StorageWrite);
+        assert!(coded.retryable());
+    }
+}
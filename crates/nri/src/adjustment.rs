@@ -0,0 +1,725 @@
+//! Builders for container adjustment and update responses.
+//!
+//! Plugins adjust a container while it is being created by returning a
+//! [`CreateContainerResponse`], and update the resources of existing
+//! containers by returning an [`UpdateContainerResponse`]. The builders here
+//! produce those messages without hand-assembling the nested protobuf types,
+//! and reject requests the runtime would refuse or silently misapply.
+//!
+//! # Runtime semantics
+//!
+//! containerd merges the responses of all plugins before applying them:
+//!
+//! - Only fields that a response sets are applied; everything else keeps the
+//!   value from the container's spec, or from plugins earlier in the order.
+//! - Each field may be claimed by a single plugin. If two plugins set the same
+//!   environment variable, annotation, mount destination or resource, the
+//!   runtime fails the request rather than picking one.
+//! - Environment variables and annotations replace existing entries with the
+//!   same key. Mounts replace an existing mount at the same destination.
+//! - Adjustments are only applied at creation time. Returning an adjustment
+//!   from any other event has no effect.
+//! - An update that fails to apply fails the request, unless the update is
+//!   marked to ignore failures.
+
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+use protobuf::MessageField;
+use thiserror::Error;
+
+use crate::api::{
+    ContainerAdjustment, ContainerEviction, ContainerUpdate, CreateContainerResponse, KeyValue,
+    LinuxCPU, LinuxContainerAdjustment, LinuxContainerUpdate, LinuxMemory, LinuxResources, Mount,
+    OptionalInt64, OptionalUInt64, UpdateContainerResponse,
+};
+
+/// Errors detected while building a response
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AdjustmentError {
+    #[error("environment variable {0} is set more than once")]
+    DuplicateEnv(String),
+
+    #[error("annotation {0} is set more than once")]
+    DuplicateAnnotation(String),
+
+    #[error("more than one mount at {0}")]
+    DuplicateMount(String),
+
+    #[error("{setting} is set to both {first} and {second}")]
+    ConflictingSetting {
+        setting: &'static str,
+        first: String,
+        second: String,
+    },
+
+    #[error("memory reservation {reservation} exceeds memory limit {limit}")]
+    ReservationAboveLimit { reservation: i64, limit: i64 },
+
+    #[error("CPU period {0}us is outside the range the kernel accepts (1000us to 1s)")]
+    CpuPeriodOutOfRange(u64),
+
+    #[error("container {0} is updated more than once")]
+    DuplicateUpdate(String),
+
+    #[error("container {0} is both updated and evicted")]
+    UpdateAndEvict(String),
+}
+
+/// Resource settings shared by adjustments and updates
+#[derive(Debug, Clone, Default)]
+struct ResourceSettings {
+    cpu_shares: Option<u64>,
+    cpu_quota: Option<i64>,
+    cpu_period: Option<u64>,
+    cpuset_cpus: Option<String>,
+    memory_limit: Option<i64>,
+    memory_reservation: Option<i64>,
+    // First conflicting setting, reported by build()
+    conflict: Option<AdjustmentError>,
+}
+
+impl ResourceSettings {
+    /// Set a value, recording a conflict if it was already set to something else
+    fn set<T: PartialEq + Debug>(&mut self, setting: &'static str, value: T, slot: SlotFn<T>) {
+        let current = slot(self);
+        let error = match current {
+            Some(first) if *first != value => AdjustmentError::ConflictingSetting {
+                setting,
+                first: format!("{:?}", first),
+                second: format!("{:?}", value),
+            },
+            _ => {
+                *current = Some(value);
+                return;
+            }
+        };
+        self.conflict.get_or_insert(error);
+    }
+
+    fn validate(&self) -> Result<(), AdjustmentError> {
+        if let Some(conflict) = &self.conflict {
+            return Err(conflict.clone());
+        }
+
+        if let (Some(reservation), Some(limit)) = (self.memory_reservation, self.memory_limit) {
+            if reservation > limit {
+                return Err(AdjustmentError::ReservationAboveLimit { reservation, limit });
+            }
+        }
+
+        if let Some(period) = self.cpu_period {
+            if !(1_000..=1_000_000).contains(&period) {
+                return Err(AdjustmentError::CpuPeriodOutOfRange(period));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Convert to the protobuf resources, or None if nothing is set
+    fn to_proto(&self) -> Option<LinuxResources> {
+        let mut cpu = LinuxCPU::new();
+        let mut cpu_set = false;
+        if let Some(shares) = self.cpu_shares {
+            cpu.shares = uint64(shares);
+            cpu_set = true;
+        }
+        if let Some(quota) = self.cpu_quota {
+            cpu.quota = int64(quota);
+            cpu_set = true;
+        }
+        if let Some(period) = self.cpu_period {
+            cpu.period = uint64(period);
+            cpu_set = true;
+        }
+        if let Some(cpus) = &self.cpuset_cpus {
+            cpu.cpus = cpus.clone();
+            cpu_set = true;
+        }
+
+        let mut memory = LinuxMemory::new();
+        let mut memory_set = false;
+        if let Some(limit) = self.memory_limit {
+            memory.limit = int64(limit);
+            memory_set = true;
+        }
+        if let Some(reservation) = self.memory_reservation {
+            memory.reservation = int64(reservation);
+            memory_set = true;
+        }
+
+        if !cpu_set && !memory_set {
+            return None;
+        }
+
+        let mut resources = LinuxResources::new();
+        if cpu_set {
+            resources.cpu = MessageField::some(cpu);
+        }
+        if memory_set {
+            resources.memory = MessageField::some(memory);
+        }
+        Some(resources)
+    }
+}
+
+/// Accessor for one of the optional settings in [`ResourceSettings`]
+type SlotFn<T> = fn(&mut ResourceSettings) -> &mut Option<T>;
+
+fn int64(value: i64) -> MessageField<OptionalInt64> {
+    MessageField::some(OptionalInt64 {
+        value,
+        ..Default::default()
+    })
+}
+
+fn uint64(value: u64) -> MessageField<OptionalUInt64> {
+    MessageField::some(OptionalUInt64 {
+        value,
+        ..Default::default()
+    })
+}
+
+/// Returns the first key that appears more than once
+fn first_duplicate<'a>(keys: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let mut seen = HashSet::new();
+    keys.into_iter().find(|key| !seen.insert(*key))
+}
+
+/// Builds the [`CreateContainerResponse`] adjusting a container being created
+///
+/// # Example
+///
+/// ```
+/// use nri::adjustment::AdjustmentBuilder;
+///
+/// let response = AdjustmentBuilder::new()
+///     .add_env("COLLECTOR_ENABLED", "1")
+///     .add_bind_mount("/var/run/collector", "/run/collector", true)
+///     .set_cpu_quota(50_000)
+///     .set_cpu_period(100_000)
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(response.adjust.env[0].key, "COLLECTOR_ENABLED");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AdjustmentBuilder {
+    env: Vec<(String, String)>,
+    annotations: Vec<(String, String)>,
+    mounts: Vec<Mount>,
+    resources: ResourceSettings,
+}
+
+impl AdjustmentBuilder {
+    /// Start an empty adjustment
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set an environment variable
+    pub fn add_env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set an annotation
+    pub fn add_annotation(mut self, key: &str, value: &str) -> Self {
+        self.annotations.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Add a mount
+    pub fn add_mount(mut self, mount: Mount) -> Self {
+        self.mounts.push(mount);
+        self
+    }
+
+    /// Bind mount a host path into the container
+    pub fn add_bind_mount(self, source: &str, destination: &str, read_only: bool) -> Self {
+        let mut options = vec!["rbind".to_string()];
+        options.push(if read_only { "ro" } else { "rw" }.to_string());
+        self.add_mount(Mount {
+            destination: destination.to_string(),
+            type_: "bind".to_string(),
+            source: source.to_string(),
+            options,
+            ..Default::default()
+        })
+    }
+
+    /// Set the relative CPU weight
+    pub fn set_cpu_shares(mut self, shares: u64) -> Self {
+        self.resources
+            .set("cpu shares", shares, |r| &mut r.cpu_shares);
+        self
+    }
+
+    /// Set the CPU time, in microseconds, the container may use each period
+    pub fn set_cpu_quota(mut self, quota: i64) -> Self {
+        self.resources.set("cpu quota", quota, |r| &mut r.cpu_quota);
+        self
+    }
+
+    /// Set the CPU quota period, in microseconds
+    pub fn set_cpu_period(mut self, period: u64) -> Self {
+        self.resources
+            .set("cpu period", period, |r| &mut r.cpu_period);
+        self
+    }
+
+    /// Restrict the container to a set of CPUs, e.g. "0-3,8"
+    pub fn set_cpuset_cpus(mut self, cpus: &str) -> Self {
+        self.resources
+            .set("cpuset cpus", cpus.to_string(), |r| &mut r.cpuset_cpus);
+        self
+    }
+
+    /// Set the memory limit in bytes
+    pub fn set_memory_limit(mut self, limit: i64) -> Self {
+        self.resources
+            .set("memory limit", limit, |r| &mut r.memory_limit);
+        self
+    }
+
+    /// Set the memory soft limit in bytes
+    pub fn set_memory_reservation(mut self, reservation: i64) -> Self {
+        self.resources.set("memory reservation", reservation, |r| {
+            &mut r.memory_reservation
+        });
+        self
+    }
+
+    /// Validate the adjustment and build the response
+    pub fn build(self) -> Result<CreateContainerResponse, AdjustmentError> {
+        if let Some(key) = first_duplicate(self.env.iter().map(|(k, _)| k.as_str())) {
+            return Err(AdjustmentError::DuplicateEnv(key.to_string()));
+        }
+        if let Some(key) = first_duplicate(self.annotations.iter().map(|(k, _)| k.as_str())) {
+            return Err(AdjustmentError::DuplicateAnnotation(key.to_string()));
+        }
+        if let Some(destination) =
+            first_duplicate(self.mounts.iter().map(|m| m.destination.as_str()))
+        {
+            return Err(AdjustmentError::DuplicateMount(destination.to_string()));
+        }
+        self.resources.validate()?;
+
+        let mut adjust = ContainerAdjustment::new();
+        adjust.env = self
+            .env
+            .into_iter()
+            .map(|(key, value)| KeyValue {
+                key,
+                value,
+                ..Default::default()
+            })
+            .collect();
+        adjust.annotations = self.annotations.into_iter().collect();
+        adjust.mounts = self.mounts;
+        if let Some(resources) = self.resources.to_proto() {
+            let mut linux = LinuxContainerAdjustment::new();
+            linux.resources = MessageField::some(resources);
+            adjust.linux = MessageField::some(linux);
+        }
+
+        let mut response = CreateContainerResponse::new();
+        response.adjust = MessageField::some(adjust);
+        Ok(response)
+    }
+}
+
+/// Builds the resource update of a single existing container, for [`UpdateBuilder`]
+#[derive(Debug, Clone)]
+pub struct ContainerUpdateBuilder {
+    container_id: String,
+    ignore_failure: bool,
+    resources: ResourceSettings,
+}
+
+impl ContainerUpdateBuilder {
+    /// Start an update of the given container
+    pub fn new(container_id: &str) -> Self {
+        Self {
+            container_id: container_id.to_string(),
+            ignore_failure: false,
+            resources: ResourceSettings::default(),
+        }
+    }
+
+    /// Let the request succeed even if this update cannot be applied
+    pub fn ignore_failure(mut self, ignore: bool) -> Self {
+        self.ignore_failure = ignore;
+        self
+    }
+
+    /// Set the relative CPU weight
+    pub fn set_cpu_shares(mut self, shares: u64) -> Self {
+        self.resources
+            .set("cpu shares", shares, |r| &mut r.cpu_shares);
+        self
+    }
+
+    /// Set the CPU time, in microseconds, the container may use each period
+    pub fn set_cpu_quota(mut self, quota: i64) -> Self {
+        self.resources.set("cpu quota", quota, |r| &mut r.cpu_quota);
+        self
+    }
+
+    /// Set the CPU quota period, in microseconds
+    pub fn set_cpu_period(mut self, period: u64) -> Self {
+        self.resources
+            .set("cpu period", period, |r| &mut r.cpu_period);
+        self
+    }
+
+    /// Restrict the container to a set of CPUs, e.g. "0-3,8"
+    pub fn set_cpuset_cpus(mut self, cpus: &str) -> Self {
+        self.resources
+            .set("cpuset cpus", cpus.to_string(), |r| &mut r.cpuset_cpus);
+        self
+    }
+
+    /// Set the memory limit in bytes
+    pub fn set_memory_limit(mut self, limit: i64) -> Self {
+        self.resources
+            .set("memory limit", limit, |r| &mut r.memory_limit);
+        self
+    }
+
+    /// Set the memory soft limit in bytes
+    pub fn set_memory_reservation(mut self, reservation: i64) -> Self {
+        self.resources.set("memory reservation", reservation, |r| {
+            &mut r.memory_reservation
+        });
+        self
+    }
+
+    fn build(self) -> Result<ContainerUpdate, AdjustmentError> {
+        self.resources.validate()?;
+
+        let mut linux = LinuxContainerUpdate::new();
+        if let Some(resources) = self.resources.to_proto() {
+            linux.resources = MessageField::some(resources);
+        }
+
+        let mut update = ContainerUpdate::new();
+        update.container_id = self.container_id;
+        update.linux = MessageField::some(linux);
+        update.ignore_failure = self.ignore_failure;
+        Ok(update)
+    }
+}
+
+/// Builds the [`UpdateContainerResponse`] updating or evicting existing containers
+///
+/// # Example
+///
+/// ```
+/// use nri::adjustment::{ContainerUpdateBuilder, UpdateBuilder};
+///
+/// let response = UpdateBuilder::new()
+///     .update(ContainerUpdateBuilder::new("ctr1").set_memory_limit(1 << 30))
+///     .evict("ctr2", "memory pressure")
+///     .build()
+///     .unwrap();
+///
+/// assert_eq!(response.update[0].container_id, "ctr1");
+/// assert_eq!(response.evict[0].container_id, "ctr2");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct UpdateBuilder {
+    updates: Vec<ContainerUpdateBuilder>,
+    evictions: Vec<(String, String)>,
+}
+
+impl UpdateBuilder {
+    /// Start an empty response
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the resources of an existing container
+    pub fn update(mut self, update: ContainerUpdateBuilder) -> Self {
+        self.updates.push(update);
+        self
+    }
+
+    /// Ask the runtime to stop a container, with a human-readable reason
+    pub fn evict(mut self, container_id: &str, reason: &str) -> Self {
+        self.evictions
+            .push((container_id.to_string(), reason.to_string()));
+        self
+    }
+
+    /// Validate the updates and build the response
+    pub fn build(self) -> Result<UpdateContainerResponse, AdjustmentError> {
+        if let Some(id) = first_duplicate(self.updates.iter().map(|u| u.container_id.as_str())) {
+            return Err(AdjustmentError::DuplicateUpdate(id.to_string()));
+        }
+        let updated: HashSet<&str> = self
+            .updates
+            .iter()
+            .map(|u| u.container_id.as_str())
+            .collect();
+        if let Some((id, _)) = self
+            .evictions
+            .iter()
+            .find(|(id, _)| updated.contains(id.as_str()))
+        {
+            return Err(AdjustmentError::UpdateAndEvict(id.clone()));
+        }
+
+        let mut response = UpdateContainerResponse::new();
+        response.update = self
+            .updates
+            .into_iter()
+            .map(ContainerUpdateBuilder::build)
+            .collect::<Result<_, _>>()?;
+        response.evict = self
+            .evictions
+            .into_iter()
+            .map(|(container_id, reason)| ContainerEviction {
+                container_id,
+                reason,
+                ..Default::default()
+            })
+            .collect();
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_adjustment() {
+        let response = AdjustmentBuilder::new().build().unwrap();
+        let adjust = response.adjust.as_ref().unwrap();
+        assert!(adjust.env.is_empty());
+        assert!(adjust.mounts.is_empty());
+        assert!(adjust.annotations.is_empty());
+        assert!(adjust.linux.is_none());
+        assert!(response.update.is_empty());
+        assert!(response.evict.is_empty());
+    }
+
+    #[test]
+    fn test_add_env() {
+        let response = AdjustmentBuilder::new()
+            .add_env("A", "1")
+            .add_env("B", "2")
+            .build()
+            .unwrap();
+        let env: Vec<(&str, &str)> = response
+            .adjust
+            .env
+            .iter()
+            .map(|kv| (kv.key.as_str(), kv.value.as_str()))
+            .collect();
+        assert_eq!(env, vec![("A", "1"), ("B", "2")]);
+
+        let err = AdjustmentBuilder::new()
+            .add_env("A", "1")
+            .add_env("A", "2")
+            .build()
+            .unwrap_err();
+        assert_eq!(err, AdjustmentError::DuplicateEnv("A".to_string()));
+    }
+
+    #[test]
+    fn test_add_annotation() {
+        let response = AdjustmentBuilder::new()
+            .add_annotation("example.com/owner", "collector")
+            .build()
+            .unwrap();
+        assert_eq!(
+            response.adjust.annotations.get("example.com/owner"),
+            Some(&"collector".to_string())
+        );
+
+        let err = AdjustmentBuilder::new()
+            .add_annotation("a", "1")
+            .add_annotation("a", "1")
+            .build()
+            .unwrap_err();
+        assert_eq!(err, AdjustmentError::DuplicateAnnotation("a".to_string()));
+    }
+
+    #[test]
+    fn test_add_mount() {
+        let response = AdjustmentBuilder::new()
+            .add_bind_mount("/host/data", "/data", true)
+            .add_mount(Mount {
+                destination: "/scratch".to_string(),
+                type_: "tmpfs".to_string(),
+                source: "tmpfs".to_string(),
+                options: vec!["size=64m".to_string()],
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        let mounts = &response.adjust.mounts;
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].destination, "/data");
+        assert_eq!(mounts[0].source, "/host/data");
+        assert_eq!(mounts[0].type_, "bind");
+        assert_eq!(mounts[0].options, vec!["rbind", "ro"]);
+        assert_eq!(mounts[1].type_, "tmpfs");
+
+        let err = AdjustmentBuilder::new()
+            .add_bind_mount("/a", "/data", false)
+            .add_bind_mount("/b", "/data", false)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, AdjustmentError::DuplicateMount("/data".to_string()));
+    }
+
+    #[test]
+    fn test_cpu_resources() {
+        let response = AdjustmentBuilder::new()
+            .set_cpu_shares(512)
+            .set_cpu_quota(50_000)
+            .set_cpu_period(100_000)
+            .set_cpuset_cpus("0-3")
+            .build()
+            .unwrap();
+
+        let resources = response.adjust.linux.resources.as_ref().unwrap();
+        let cpu = resources.cpu.as_ref().unwrap();
+        assert_eq!(cpu.shares.value, 512);
+        assert_eq!(cpu.quota.value, 50_000);
+        assert_eq!(cpu.period.value, 100_000);
+        assert_eq!(cpu.cpus, "0-3");
+        assert!(cpu.realtime_runtime.is_none());
+        assert!(resources.memory.is_none());
+    }
+
+    #[test]
+    fn test_memory_resources() {
+        let response = AdjustmentBuilder::new()
+            .set_memory_limit(1 << 30)
+            .set_memory_reservation(1 << 29)
+            .build()
+            .unwrap();
+
+        let resources = response.adjust.linux.resources.as_ref().unwrap();
+        let memory = resources.memory.as_ref().unwrap();
+        assert_eq!(memory.limit.value, 1 << 30);
+        assert_eq!(memory.reservation.value, 1 << 29);
+        assert!(memory.swap.is_none());
+        assert!(resources.cpu.is_none());
+
+        let err = AdjustmentBuilder::new()
+            .set_memory_limit(1 << 20)
+            .set_memory_reservation(1 << 21)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            AdjustmentError::ReservationAboveLimit {
+                reservation: 1 << 21,
+                limit: 1 << 20
+            }
+        );
+    }
+
+    #[test]
+    fn test_conflicting_resources() {
+        // Setting the same value twice is fine
+        assert!(AdjustmentBuilder::new()
+            .set_cpu_quota(1000)
+            .set_cpu_quota(1000)
+            .build()
+            .is_ok());
+
+        let err = AdjustmentBuilder::new()
+            .set_cpu_quota(1000)
+            .set_cpu_quota(2000)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            AdjustmentError::ConflictingSetting {
+                setting: "cpu quota",
+                first: "1000".to_string(),
+                second: "2000".to_string(),
+            }
+        );
+        assert_eq!(err.to_string(), "cpu quota is set to both 1000 and 2000");
+
+        let err = AdjustmentBuilder::new()
+            .set_cpu_period(10)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, AdjustmentError::CpuPeriodOutOfRange(10));
+    }
+
+    #[test]
+    fn test_update_builder() {
+        let response = UpdateBuilder::new()
+            .update(
+                ContainerUpdateBuilder::new("ctr1")
+                    .set_cpu_shares(256)
+                    .set_memory_limit(1 << 30)
+                    .ignore_failure(true),
+            )
+            .update(ContainerUpdateBuilder::new("ctr2").set_cpuset_cpus("4-7"))
+            .evict("ctr3", "out of memory")
+            .build()
+            .unwrap();
+
+        assert_eq!(response.update.len(), 2);
+        let first = &response.update[0];
+        assert_eq!(first.container_id, "ctr1");
+        assert!(first.ignore_failure);
+        let resources = first.linux.resources.as_ref().unwrap();
+        assert_eq!(resources.cpu.shares.value, 256);
+        assert_eq!(resources.memory.limit.value, 1 << 30);
+
+        let second = &response.update[1];
+        assert!(!second.ignore_failure);
+        assert_eq!(second.linux.resources.cpu.cpus, "4-7");
+        assert!(second.linux.resources.memory.is_none());
+
+        assert_eq!(response.evict.len(), 1);
+        assert_eq!(response.evict[0].container_id, "ctr3");
+        assert_eq!(response.evict[0].reason, "out of memory");
+    }
+
+    #[test]
+    fn test_update_builder_validation() {
+        let err = UpdateBuilder::new()
+            .update(ContainerUpdateBuilder::new("ctr1").set_cpu_shares(1))
+            .update(ContainerUpdateBuilder::new("ctr1").set_cpu_shares(2))
+            .build()
+            .unwrap_err();
+        assert_eq!(err, AdjustmentError::DuplicateUpdate("ctr1".to_string()));
+
+        let err = UpdateBuilder::new()
+            .update(ContainerUpdateBuilder::new("ctr1").set_cpu_shares(1))
+            .evict("ctr1", "reason")
+            .build()
+            .unwrap_err();
+        assert_eq!(err, AdjustmentError::UpdateAndEvict("ctr1".to_string()));
+
+        let err = UpdateBuilder::new()
+            .update(
+                ContainerUpdateBuilder::new("ctr1")
+                    .set_memory_limit(1)
+                    .set_memory_limit(2),
+            )
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AdjustmentError::ConflictingSetting {
+                setting: "memory limit",
+                ..
+            }
+        ));
+    }
+}
@@ -13,6 +13,22 @@ pub fn valid_events() -> EventMask {
     EventMask((1 << (Event::LAST.value() - 1)) - 1)
 }
 
+/// Events that differ between two EventMasks, see [`EventMask::diff`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MaskDiff {
+    /// Events set in the new mask but not in the old one
+    pub added: EventMask,
+    /// Events set in the old mask but not in the new one
+    pub removed: EventMask,
+}
+
+impl MaskDiff {
+    /// Check if the masks are the same.
+    pub fn is_empty(&self) -> bool {
+        self.added.raw_value() == 0 && self.removed.raw_value() == 0
+    }
+}
+
 impl EventMask {
     /// Create a new empty EventMask.
     pub fn new() -> Self {
@@ -61,6 +77,14 @@ impl EventMask {
         (self.0 & (1 << (event.value() - 1))) != 0
     }
 
+    /// Describe the events added and removed when changing from this mask to `other`.
+    pub fn diff(&self, other: &EventMask) -> MaskDiff {
+        MaskDiff {
+            added: Self(other.0 & !self.0),
+            removed: Self(self.0 & !other.0),
+        }
+    }
+
     /// Return a human-readable string representation of the EventMask.
     pub fn pretty_string(&self) -> String {
         let mut events = Vec::new();
@@ -200,6 +224,36 @@ mod tests {
         assert!(!mask4.is_set(Event::STOP_CONTAINER));
     }
 
+    #[test]
+    fn test_diff() {
+        let mut old = EventMask::new();
+        old.set(&[Event::CREATE_CONTAINER, Event::STOP_CONTAINER]);
+        let mut new = EventMask::new();
+        new.set(&[Event::STOP_CONTAINER, Event::UPDATE_CONTAINER]);
+
+        let diff = old.diff(&new);
+        assert!(!diff.is_empty());
+        assert!(diff.added.is_set(Event::UPDATE_CONTAINER));
+        assert!(!diff.added.is_set(Event::STOP_CONTAINER));
+        assert!(!diff.added.is_set(Event::CREATE_CONTAINER));
+        assert!(diff.removed.is_set(Event::CREATE_CONTAINER));
+        assert!(!diff.removed.is_set(Event::STOP_CONTAINER));
+        assert!(!diff.removed.is_set(Event::UPDATE_CONTAINER));
+
+        // The reverse diff swaps added and removed
+        let reverse = new.diff(&old);
+        assert_eq!(reverse.added, diff.removed);
+        assert_eq!(reverse.removed, diff.added);
+
+        // Identical masks have an empty diff
+        assert!(old.diff(&old).is_empty());
+        assert_eq!(old.diff(&old), MaskDiff::default());
+
+        // Widening from nothing adds everything, narrowing to nothing removes everything
+        assert_eq!(EventMask::new().diff(&old).added, old);
+        assert_eq!(old.diff(&EventMask::new()).removed, old);
+    }
+
     #[test]
     fn test_pretty_string() {
         let mut mask = EventMask::new();
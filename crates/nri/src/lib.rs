@@ -6,18 +6,52 @@ pub mod api_ttrpc {
     include!(concat!(env!("OUT_DIR"), "/api_ttrpc.rs"));
 }
 
+pub mod adjustment;
 pub mod events_mask;
 pub mod metadata;
 pub mod multiplex;
+pub mod reconnect;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
-use log::info;
-use tokio::sync::mpsc;
+use log::{info, warn};
+use tokio::sync::{mpsc, Mutex, Notify};
 use tokio::task::JoinHandle;
 use ttrpc::context::Context;
 
 use api::RegisterPluginRequest;
 use api_ttrpc::{Plugin, RuntimeClient};
+use events_mask::{EventMask, MaskDiff};
+use metadata::{MetadataMessage, MetadataPlugin, OverflowPolicy, ShutdownNotice};
+use reconnect::{ReconnectError, ReconnectPolicy};
+
+/// Default capacity of the metadata channel created by [`NRIBuilder`]
+pub const DEFAULT_METADATA_CHANNEL_CAPACITY: usize = 1000;
+
+/// How long [`NRI::try_register`] waits for the runtime to answer
+pub const TRY_REGISTER_DEADLINE: Duration = Duration::from_millis(10);
+
+/// Outcome of [`NRI::try_register`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistrationState {
+    /// The runtime accepted the registration
+    Registered,
+    /// The request was sent but the runtime has not answered yet; calling
+    /// `try_register` again checks on the same request
+    Pending,
+    /// The runtime rejected the registration or the connection failed; the
+    /// next `try_register` sends a new request
+    Failed(String),
+}
+
+/// Progress of the plugin's registration with the runtime
+enum Registration {
+    NotStarted,
+    InFlight(JoinHandle<Result<()>>),
+    Registered,
+}
 
 /// NRI struct provides a focused interface for NRI plugins
 pub struct NRI {
@@ -29,9 +63,30 @@ pub struct NRI {
     runtime_client: RuntimeClient,
     /// Shutdown channel sender
     shutdown_tx: mpsc::Sender<()>,
+    /// Registration state, for try_register
+    registration: Mutex<Registration>,
 }
 
 impl NRI {
+    /// Start building an NRI instance running the metadata plugin
+    ///
+    /// # Arguments
+    ///
+    /// * `plugin_name` - Name of the plugin
+    /// * `plugin_idx` - Index of the plugin (for ordering)
+    pub fn builder(plugin_name: &str, plugin_idx: &str) -> NRIBuilder {
+        NRIBuilder {
+            plugin_name: plugin_name.to_string(),
+            plugin_idx: plugin_idx.to_string(),
+            channel_capacity: DEFAULT_METADATA_CHANNEL_CAPACITY,
+            overflow_policy: OverflowPolicy::default(),
+            reconnect_policy: ReconnectPolicy::default(),
+            event_mask: metadata::default_event_mask(),
+            shutdown_notifier: None,
+            lifecycle_hints: false,
+        }
+    }
+
     /// Create a new NRI instance and start the plugin server
     ///
     /// # Arguments
@@ -86,8 +141,39 @@ impl NRI {
         plugin_idx: &str,
     ) -> Result<(Self, JoinHandle<Result<()>>)> {
         // Create the multiplexer using the socket
-        let mut mux = multiplex::Mux::new(socket);
+        let mux = multiplex::Mux::with_config(socket, multiplex::MuxConfig::nri());
+        Self::start(mux, plugin, plugin_name, plugin_idx).await
+    }
+
+    /// Connect to the runtime's socket and start the plugin server
+    ///
+    /// Like [`NRI::new`], but connects with [`multiplex::Mux::connect_unix`],
+    /// so a missing socket or missing permissions produce a targeted error.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_path` - Path of the runtime's NRI socket, usually /var/run/nri/nri.sock
+    /// * `plugin` - Plugin implementation
+    /// * `plugin_name` - Name of the plugin
+    /// * `plugin_idx` - Index of the plugin (for ordering)
+    pub async fn connect<P: Plugin + Send + Sync + 'static>(
+        socket_path: impl AsRef<std::path::Path>,
+        plugin: P,
+        plugin_name: &str,
+        plugin_idx: &str,
+    ) -> Result<(Self, JoinHandle<Result<()>>)> {
+        let mux =
+            multiplex::Mux::connect_unix(socket_path.as_ref(), multiplex::MuxConfig::nri()).await?;
+        Self::start(mux, plugin, plugin_name, plugin_idx).await
+    }
 
+    /// Open the plugin and runtime connections on `mux` and start the plugin server
+    async fn start<P: Plugin + Send + Sync + 'static>(
+        mut mux: multiplex::Mux,
+        plugin: P,
+        plugin_name: &str,
+        plugin_idx: &str,
+    ) -> Result<(Self, JoinHandle<Result<()>>)> {
         // Open the runtime connection (client side)
         let rt_socket = mux.open(multiplex::RUNTIME_SERVICE_CONN).await?;
         let runtime_socket = ttrpc::r#async::transport::Socket::new(rt_socket);
@@ -149,6 +235,7 @@ impl NRI {
             plugin_idx: plugin_idx.to_string(),
             runtime_client,
             shutdown_tx,
+            registration: Mutex::new(Registration::NotStarted),
         };
 
         Ok((nri, join_handle))
@@ -164,23 +251,92 @@ impl NRI {
     pub async fn register(&self) -> Result<()> {
         info!("Registering plugin '{}' with runtime", self.plugin_name);
 
-        // Create the register request
-        let req = RegisterPluginRequest {
+        Self::send_register(&self.runtime_client, &self.register_request()).await?;
+        *self.registration.lock().await = Registration::Registered;
+
+        info!("Plugin '{}' registered successfully", self.plugin_name);
+        Ok(())
+    }
+
+    /// Attempt to register the plugin without waiting for the runtime
+    ///
+    /// Sends the RegisterPlugin RPC on a background task and waits at most
+    /// [`TRY_REGISTER_DEADLINE`] for the answer. If the runtime is slower, the
+    /// request stays in flight and the next call checks on it instead of
+    /// sending another one.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<RegistrationState>` - Whether the plugin is registered, the
+    ///   request is pending, or it failed; an error if the registration task panicked
+    pub async fn try_register(&self) -> Result<RegistrationState> {
+        let mut registration = self.registration.lock().await;
+        if let Registration::NotStarted = *registration {
+            info!("Registering plugin '{}' with runtime", self.plugin_name);
+            let client = self.runtime_client.clone();
+            let req = self.register_request();
+            *registration = Registration::InFlight(tokio::spawn(async move {
+                Self::send_register(&client, &req).await
+            }));
+        }
+        let Registration::InFlight(handle) = &mut *registration else {
+            return Ok(RegistrationState::Registered);
+        };
+
+        let joined = match tokio::time::timeout(TRY_REGISTER_DEADLINE, handle).await {
+            Ok(joined) => joined,
+            Err(_) => return Ok(RegistrationState::Pending),
+        };
+        *registration = Registration::NotStarted;
+        match joined.map_err(|e| anyhow!("Registration task failed: {}", e))? {
+            Ok(()) => {
+                *registration = Registration::Registered;
+                info!("Plugin '{}' registered successfully", self.plugin_name);
+                Ok(RegistrationState::Registered)
+            }
+            Err(e) => {
+                warn!("Plugin '{}' failed to register: {}", self.plugin_name, e);
+                Ok(RegistrationState::Failed(e.to_string()))
+            }
+        }
+    }
+
+    fn register_request(&self) -> RegisterPluginRequest {
+        RegisterPluginRequest {
             plugin_name: self.plugin_name.clone(),
             plugin_idx: self.plugin_idx.clone(),
             special_fields: protobuf::SpecialFields::default(),
-        };
+        }
+    }
 
-        // Make the RPC call
-        self.runtime_client
-            .register_plugin(Context::default(), &req)
+    /// Make the RegisterPlugin RPC call
+    async fn send_register(client: &RuntimeClient, req: &RegisterPluginRequest) -> Result<()> {
+        client
+            .register_plugin(Context::default(), req)
             .await
             .map_err(|e| anyhow!("Registration error: {}", e))?;
-
-        info!("Plugin '{}' registered successfully", self.plugin_name);
         Ok(())
     }
 
+    /// Have the runtime configure the plugin again, applying its current event mask
+    ///
+    /// NRI has no RPC for changing a plugin's subscription: the runtime reads
+    /// the event mask from the plugin's Configure response, and only calls
+    /// Configure after the plugin registers. Re-subscribing therefore closes
+    /// this connection; connect and register again (as [`NRIBuilder::run`]
+    /// does) for the runtime to configure the plugin with its new mask.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Success or error
+    pub async fn resubscribe(&self) -> Result<()> {
+        info!(
+            "Closing NRI connection for plugin '{}' to re-subscribe",
+            self.plugin_name
+        );
+        self.close().await
+    }
+
     /// Close the NRI connection and release resources
     ///
     /// This will signal the plugin server to shutdown and close the connection.
@@ -198,6 +354,251 @@ impl NRI {
     }
 }
 
+/// Builder for an NRI instance running the [`MetadataPlugin`]
+///
+/// Creates the metadata channel together with the plugin, so the channel
+/// capacity and the policy for a full channel are decided in one place.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use nri::{metadata::OverflowPolicy, NRI};
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let socket = tokio::net::UnixStream::connect("/var/run/nri/nri.sock").await?;
+///
+///     let (nri, join_handle, mut metadata_rx) = NRI::builder("metadata-plugin", "10")
+///         .channel_capacity(500)
+///         .overflow_policy(OverflowPolicy::BlockWithTimeout(Duration::from_millis(100)))
+///         .build(socket)
+///         .await?;
+///
+///     nri.register().await?;
+///     while let Some(message) = metadata_rx.recv().await {
+///         println!("{:?}", message);
+///     }
+///
+///     join_handle.await??;
+///     Ok(())
+/// }
+/// ```
+pub struct NRIBuilder {
+    plugin_name: String,
+    plugin_idx: String,
+    channel_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    reconnect_policy: ReconnectPolicy,
+    event_mask: EventMask,
+    shutdown_notifier: Option<mpsc::UnboundedSender<ShutdownNotice>>,
+    lifecycle_hints: bool,
+}
+
+impl NRIBuilder {
+    /// Set the capacity of the metadata channel (at least 1)
+    pub fn channel_capacity(mut self, channel_capacity: usize) -> Self {
+        self.channel_capacity = channel_capacity.max(1);
+        self
+    }
+
+    /// Set what the plugin does when the metadata channel is full
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Set how [`NRIBuilder::run`] retries connecting to the runtime
+    pub fn reconnect_policy(mut self, reconnect_policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = reconnect_policy;
+        self
+    }
+
+    /// Set the events the metadata plugin initially subscribes to
+    pub fn event_mask(mut self, event_mask: EventMask) -> Self {
+        self.event_mask = event_mask;
+        self
+    }
+
+    /// Notify `notifier` when the runtime shuts the metadata plugin down, see
+    /// [`MetadataPlugin::with_shutdown_notifier`]
+    pub fn shutdown_notifier(mut self, notifier: mpsc::UnboundedSender<ShutdownNotice>) -> Self {
+        self.shutdown_notifier = Some(notifier);
+        self
+    }
+
+    /// Set whether the metadata plugin sends lifecycle hints, see
+    /// [`MetadataPlugin::with_lifecycle_hints`]
+    pub fn lifecycle_hints(mut self, lifecycle_hints: bool) -> Self {
+        self.lifecycle_hints = lifecycle_hints;
+        self
+    }
+
+    /// Create the metadata plugin and the receiving end of its channel
+    pub fn metadata_plugin(&self) -> (MetadataPlugin, mpsc::Receiver<MetadataMessage>) {
+        let (tx, rx) = mpsc::channel(self.channel_capacity);
+        let mut plugin = MetadataPlugin::with_overflow_policy(tx, self.overflow_policy);
+        if let Some(notifier) = &self.shutdown_notifier {
+            plugin = plugin.with_shutdown_notifier(notifier.clone());
+        }
+        if self.lifecycle_hints {
+            plugin = plugin.with_lifecycle_hints();
+        }
+        plugin.set_event_mask(self.event_mask);
+        (plugin, rx)
+    }
+
+    /// Create the metadata plugin and channel, then connect and start the
+    /// plugin server as [`NRI::new`] does
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(NRI, JoinHandle<Result<()>>, mpsc::Receiver<MetadataMessage>)>` - NRI
+    ///   instance, server task handle and metadata receiver, or error
+    pub async fn build(
+        self,
+        socket: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Sync + 'static,
+    ) -> Result<(NRI, JoinHandle<Result<()>>, mpsc::Receiver<MetadataMessage>)> {
+        let (plugin, rx) = self.metadata_plugin();
+        let (nri, join_handle) =
+            NRI::new(socket, plugin, &self.plugin_name, &self.plugin_idx).await?;
+        Ok((nri, join_handle, rx))
+    }
+
+    /// Create the metadata plugin and channel, then keep the plugin connected
+    /// and registered with the runtime listening on `socket_path`
+    ///
+    /// Whenever the connection ends, the plugin connects and registers again,
+    /// retrying according to the reconnect policy. Delays only start over
+    /// once a connection lasted the policy's `min_uptime`. The metadata channel
+    /// survives reconnections. The returned task only finishes when the
+    /// policy gives up; abort it to stop the plugin.
+    ///
+    /// The returned [`Subscription`] changes the events the plugin subscribes
+    /// to, reconnecting to apply them (see [`NRI::resubscribe`]), and can
+    /// force a reconnection.
+    ///
+    /// # Returns
+    ///
+    /// * `(JoinHandle<Result<(), ReconnectError>>, mpsc::Receiver<MetadataMessage>, Subscription)` -
+    ///   reconnecting task handle, metadata receiver and subscription handle
+    pub fn run(
+        self,
+        socket_path: impl Into<std::path::PathBuf>,
+    ) -> (
+        JoinHandle<Result<(), ReconnectError>>,
+        mpsc::Receiver<MetadataMessage>,
+        Subscription,
+    ) {
+        let socket_path = socket_path.into();
+        let (plugin, rx) = self.metadata_plugin();
+        let subscription = Subscription {
+            plugin: plugin.clone(),
+            resubscribe: Arc::new(Notify::new()),
+            reconnect: Arc::new(Notify::new()),
+        };
+        let resubscribe = subscription.resubscribe.clone();
+        let reconnect = subscription.reconnect.clone();
+
+        let join_handle = tokio::spawn(async move {
+            let (socket_path, plugin, builder) = (&socket_path, &plugin, &self);
+            let mut backoff = builder.reconnect_policy.backoff();
+            loop {
+                let (nri, mut join_handle) = builder
+                    .reconnect_policy
+                    .retry_with(&mut backoff, |_| async move {
+                        let (nri, join_handle) = NRI::connect(
+                            socket_path,
+                            plugin.clone(),
+                            &builder.plugin_name,
+                            &builder.plugin_idx,
+                        )
+                        .await?;
+                        nri.register().await?;
+                        Ok((nri, join_handle))
+                    })
+                    .await?;
+                let connected_at = Instant::now();
+
+                // Registered; wait for the connection to end, the event mask
+                // to change or a reconnection request, then start over
+                let result = tokio::select! {
+                    result = &mut join_handle => result,
+                    _ = resubscribe.notified() => {
+                        let _ = nri.resubscribe().await;
+                        join_handle.await.map(|_| Ok(()))
+                    }
+                    _ = reconnect.notified() => {
+                        let _ = nri.close().await;
+                        join_handle.await.map(|_| Ok(()))
+                    }
+                };
+                match result {
+                    Ok(Ok(())) => warn!("NRI connection closed, reconnecting"),
+                    Ok(Err(e)) => warn!("NRI connection failed: {}, reconnecting", e),
+                    Err(e) => warn!("NRI plugin server task failed: {}, reconnecting", e),
+                }
+                let uptime = connected_at.elapsed();
+                if let Some(delay) = backoff.after_connection(uptime) {
+                    warn!(
+                        "NRI connection lasted only {:?}, reconnecting in {:?}",
+                        uptime, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        });
+
+        (join_handle, rx, subscription)
+    }
+}
+
+/// Handle for changing the events of a plugin started with [`NRIBuilder::run`]
+#[derive(Clone)]
+pub struct Subscription {
+    plugin: MetadataPlugin,
+    resubscribe: Arc<Notify>,
+    reconnect: Arc<Notify>,
+}
+
+impl Subscription {
+    /// Get the events the plugin subscribes to
+    pub fn event_mask(&self) -> EventMask {
+        self.plugin.event_mask()
+    }
+
+    /// Change the events the plugin subscribes to, returning what changed
+    ///
+    /// The plugin ignores removed events right away. If the mask changed, the
+    /// plugin then reconnects so the runtime configures it with the new mask.
+    /// The new connection synchronizes again, sending `Add` messages for all
+    /// existing containers.
+    pub fn set_event_mask(&self, event_mask: EventMask) -> MaskDiff {
+        let diff = self.plugin.set_event_mask(event_mask);
+        if !diff.is_empty() {
+            info!(
+                "NRI event mask changed (added: {}, removed: {}), re-subscribing",
+                diff.added.pretty_string(),
+                diff.removed.pretty_string()
+            );
+            self.resubscribe.notify_one();
+        }
+        diff
+    }
+
+    /// Close the current connection and connect again, such as after the
+    /// runtime shut the plugin down without closing the connection
+    ///
+    /// A request made while the plugin is connecting is kept, and closes the
+    /// connection as soon as it is established. Requests made before then
+    /// count as one. The new connection synchronizes again.
+    pub fn reconnect(&self) {
+        info!("Reconnecting the NRI plugin");
+        self.reconnect.notify_one();
+    }
+}
+
 // Export types for convenience
 pub mod types {
     // NRI doesn't have all the types we were originally expecting
@@ -206,7 +607,7 @@ pub mod types {
     pub use crate::api::Event;
     pub use crate::api::LinuxNamespace;
     pub use crate::api::Mount;
-    pub use crate::events_mask::{valid_events, EventMask};
+    pub use crate::events_mask::{valid_events, EventMask, MaskDiff};
 }
 
 // Include examples
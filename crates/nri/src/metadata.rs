@@ -1,8 +1,10 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
+    atomic::{AtomicI32, AtomicUsize, Ordering},
+    Arc, Mutex,
 };
+use std::time::{Duration, SystemTime};
 
 use log::{debug, info, warn};
 use tokio::sync::mpsc;
@@ -10,12 +12,99 @@ use ttrpc::r#async::TtrpcContext;
 
 use crate::api::{
     self, ConfigureRequest, ConfigureResponse, CreateContainerRequest, CreateContainerResponse,
-    Empty, Event, StopContainerRequest, StopContainerResponse, SynchronizeRequest,
-    SynchronizeResponse, UpdateContainerRequest, UpdateContainerResponse, UpdatePodSandboxRequest,
-    UpdatePodSandboxResponse,
+    Empty, Event, StateChangeEvent, StopContainerRequest, StopContainerResponse,
+    SynchronizeRequest, SynchronizeResponse, UpdateContainerRequest, UpdateContainerResponse,
+    UpdatePodSandboxRequest, UpdatePodSandboxResponse,
 };
 use crate::api_ttrpc::Plugin;
-use crate::events_mask::EventMask;
+use crate::events_mask::{EventMask, MaskDiff};
+
+/// Events the metadata plugin subscribes to unless configured otherwise
+pub fn default_event_mask() -> EventMask {
+    let mut events = EventMask::new();
+    events.set(&[Event::CREATE_CONTAINER, Event::STOP_CONTAINER]);
+    events
+}
+
+/// Mount point of the cgroup v2 hierarchy, under which relative cgroup paths
+/// are resolved
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Directory of the cgroup a runtime reports as `cgroup_path`, under the
+/// cgroup mount `root`.
+///
+/// Runtimes using the systemd cgroup driver report `slice:prefix:name`,
+/// which systemd places in the unit `<prefix>-<name>.scope` under the slice.
+/// Other runtimes report a path, absolute or relative to the mount. Returns
+/// None for an empty path.
+pub fn cgroup_dir(cgroup_path: &str, root: &Path) -> Option<PathBuf> {
+    if cgroup_path.is_empty() {
+        return None;
+    }
+    if let [slice, prefix, name] = cgroup_path.split(':').collect::<Vec<_>>()[..] {
+        if !cgroup_path.contains('/') {
+            let unit = if name.ends_with(".slice") {
+                name.to_string()
+            } else if prefix.is_empty() {
+                format!("{}.scope", name)
+            } else {
+                format!("{}-{}.scope", prefix, name)
+            };
+            return Some(root.join(systemd_slice_dir(slice)).join(unit));
+        }
+    }
+
+    let path = Path::new(cgroup_path);
+    Some(if path.starts_with(root) {
+        path.to_path_buf()
+    } else {
+        root.join(cgroup_path.trim_start_matches('/'))
+    })
+}
+
+/// Directory of a systemd slice relative to the cgroup mount. Each dash in
+/// the name nests a slice in its parent, so `kubepods-besteffort.slice` is
+/// `kubepods.slice/kubepods-besteffort.slice`, and `-.slice` is the root.
+fn systemd_slice_dir(slice: &str) -> PathBuf {
+    let mut dir = PathBuf::new();
+    let name = slice.strip_suffix(".slice").unwrap_or(slice);
+    if name.is_empty() || name == "-" {
+        return dir;
+    }
+    let mut end = 0;
+    for part in name.split('-') {
+        end += part.len();
+        dir.push(format!("{}.slice", &name[..end]));
+        end += 1;
+    }
+    dir
+}
+
+/// Resolve the cgroup id of the cgroup a runtime reports as `cgroup_path`
+/// (see [`cgroup_dir`]): the inode number of its directory, which is the id
+/// BPF programs report. The directory is read on the blocking pool.
+///
+/// Returns None if the path is empty or cannot be read, e.g. because the
+/// runtime has not created the cgroup yet.
+#[cfg(target_os = "linux")]
+pub async fn resolve_cgroup_id(cgroup_path: &str, root: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = cgroup_dir(cgroup_path, root)?;
+    match tokio::fs::metadata(&path).await {
+        Ok(metadata) => Some(metadata.ino()),
+        Err(e) => {
+            debug!("Cannot resolve cgroup id of {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Cgroup ids are only resolved on Linux
+#[cfg(not(target_os = "linux"))]
+pub async fn resolve_cgroup_id(_cgroup_path: &str, _root: &Path) -> Option<u64> {
+    None
+}
 
 /// Container metadata collected from NRI.
 #[derive(Debug, Clone)]
@@ -32,59 +121,248 @@ pub struct ContainerMetadata {
     pub container_name: String,
     /// Cgroup path
     pub cgroup_path: String,
+    /// Cgroup id (inode of the cgroup directory), the key of BPF events.
+    /// None if the cgroup could not be read when the metadata was extracted.
+    pub cgroup_id: Option<u64>,
     /// Container process PID
     pub pid: Option<u32>,
     /// Container labels
     pub labels: HashMap<String, String>,
     /// Container annotations
     pub annotations: HashMap<String, String>,
+    /// Resource limits from the container's Linux resources
+    pub limits: ContainerLimits,
+}
+
+/// Resource limits of a container, as configured in its Linux resources.
+///
+/// A field is `None` when the runtime does not set it. The CPU quota and
+/// memory limit are `-1` when explicitly unlimited, so unset, unlimited and
+/// zero stay distinct.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContainerLimits {
+    /// CFS quota in microseconds per period
+    pub cpu_quota: Option<i64>,
+    /// CFS period in microseconds
+    pub cpu_period: Option<u64>,
+    /// Relative CPU weight in cgroup v1 shares
+    pub cpu_shares: Option<u64>,
+    /// CPUs the container may run on, in cpuset list format, e.g. "0-3,8"
+    pub cpuset_cpus: Option<String>,
+    /// Memory nodes the container may allocate from, in cpuset list format
+    pub cpuset_mems: Option<String>,
+    /// Memory limit in bytes
+    pub memory_limit: Option<i64>,
+}
+
+impl ContainerLimits {
+    /// Value of the CPU quota and memory limit when explicitly unlimited
+    pub const UNLIMITED: i64 = -1;
+
+    /// Read the limits from a container's Linux resources
+    pub fn from_resources(resources: &api::LinuxResources) -> Self {
+        // Runtimes write any negative quota or limit for "max"
+        fn limit(value: i64) -> i64 {
+            if value < 0 {
+                ContainerLimits::UNLIMITED
+            } else {
+                value
+            }
+        }
+        fn cpuset(list: &str) -> Option<String> {
+            Some(list.to_string()).filter(|list| !list.is_empty())
+        }
+
+        let cpu = resources.cpu.as_ref();
+        let memory = resources.memory.as_ref();
+        Self {
+            cpu_quota: cpu
+                .and_then(|cpu| cpu.quota.as_ref())
+                .map(|quota| limit(quota.value)),
+            cpu_period: cpu
+                .and_then(|cpu| cpu.period.as_ref())
+                .map(|period| period.value),
+            cpu_shares: cpu
+                .and_then(|cpu| cpu.shares.as_ref())
+                .map(|shares| shares.value),
+            cpuset_cpus: cpu.and_then(|cpu| cpuset(&cpu.cpus)),
+            cpuset_mems: cpu.and_then(|cpu| cpuset(&cpu.mems)),
+            memory_limit: memory
+                .and_then(|memory| memory.limit.as_ref())
+                .map(|memory_limit| limit(memory_limit.value)),
+        }
+    }
 }
 
 /// Message types sent through the metadata channel.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MetadataMessage {
     /// Add or update metadata for a container
     Add(String, ContainerMetadata),
     /// Remove metadata for a container
     Remove(String),
+    /// The runtime finished synchronizing a new connection, listing the ids
+    /// of all its containers. Containers not listed went away while the
+    /// plugin was disconnected.
+    Synchronized(Vec<String>),
+    /// The runtime shut the plugin down and no more messages will follow
+    /// from this connection. If the plugin reconnects, the new connection
+    /// synchronizes again with `Add` messages and `Synchronized`.
+    Shutdown,
+    /// The runtime created, started or stopped a container. Only sent by a
+    /// plugin built [`MetadataPlugin::with_lifecycle_hints`]: `Created`
+    /// follows the container's `Add`, and `Stopped` precedes its `Remove`.
+    Lifecycle(String, LifecycleHint),
+}
+
+/// A step of a container's lifecycle, named after the NRI request that
+/// reported it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleHint {
+    Created,
+    Started,
+    Stopped,
+}
+
+/// Sent to the plugin's shutdown notifier when the runtime shuts the plugin
+/// down, e.g. because it is stopping or NRI was disabled.
+///
+/// Unlike [`MetadataMessage::Shutdown`], which waits in line behind the
+/// metadata and can be dropped on a full channel, the notice is delivered
+/// right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownNotice {
+    /// When the runtime's shutdown request arrived
+    pub received_at: SystemTime,
+}
+
+/// What the plugin does with a message when the metadata channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the message immediately
+    #[default]
+    Drop,
+    /// Wait up to the given duration for room in the channel, then drop the message
+    BlockWithTimeout(Duration),
 }
 
 /// Metadata plugin for NRI.
 ///
 /// This plugin collects container metadata from the NRI runtime and sends it through
 /// a channel. It handles container lifecycle events and synchronization events.
+///
+/// The plugin subscribes to the events in its event mask, which clones share.
+/// Events outside the mask are ignored even if the runtime sends them, so
+/// narrowing the mask takes effect before the runtime is told about it.
 #[derive(Clone)]
 pub struct MetadataPlugin {
     /// Channel for sending metadata messages
     tx: mpsc::Sender<MetadataMessage>,
     /// Counter for dropped messages
     dropped_messages: Arc<AtomicUsize>,
+    /// What to do when the channel is full
+    overflow_policy: OverflowPolicy,
+    /// Raw value of the event mask returned from Configure
+    events: Arc<AtomicI32>,
+    /// Notified when the runtime shuts the plugin down
+    shutdown_notifier: Option<mpsc::UnboundedSender<ShutdownNotice>>,
+    /// Ids of the containers listed so far by a synchronization sent in parts
+    synchronizing: Arc<Mutex<Vec<String>>>,
+    /// Whether to send [`MetadataMessage::Lifecycle`] hints
+    lifecycle_hints: bool,
 }
 
 impl MetadataPlugin {
-    /// Create a new metadata plugin with the given sender.
+    /// Create a new metadata plugin with the given sender, dropping messages
+    /// when the channel is full.
     pub fn new(tx: mpsc::Sender<MetadataMessage>) -> Self {
+        Self::with_overflow_policy(tx, OverflowPolicy::Drop)
+    }
+
+    /// Create a new metadata plugin with the given sender and overflow policy.
+    pub fn with_overflow_policy(
+        tx: mpsc::Sender<MetadataMessage>,
+        overflow_policy: OverflowPolicy,
+    ) -> Self {
         Self {
             tx,
             dropped_messages: Arc::new(AtomicUsize::new(0)),
+            overflow_policy,
+            events: Arc::new(AtomicI32::new(default_event_mask().raw_value())),
+            shutdown_notifier: None,
+            synchronizing: Arc::new(Mutex::new(Vec::new())),
+            lifecycle_hints: false,
         }
     }
 
+    /// Send a [`ShutdownNotice`] to `notifier` when the runtime shuts the
+    /// plugin down, so the owner can react to it as it happens.
+    pub fn with_shutdown_notifier(
+        mut self,
+        notifier: mpsc::UnboundedSender<ShutdownNotice>,
+    ) -> Self {
+        self.shutdown_notifier = Some(notifier);
+        self
+    }
+
+    /// Also send a [`MetadataMessage::Lifecycle`] hint when a container is
+    /// created, started or stopped. Start hints need `START_CONTAINER` in the
+    /// event mask.
+    pub fn with_lifecycle_hints(mut self) -> Self {
+        self.lifecycle_hints = true;
+        self
+    }
+
+    /// Get the events the plugin subscribes to.
+    pub fn event_mask(&self) -> EventMask {
+        EventMask::from_raw(self.events.load(Ordering::Relaxed))
+    }
+
+    /// Change the events the plugin subscribes to, returning what changed.
+    ///
+    /// Removed events are ignored from now on. The runtime only reads the mask
+    /// when it configures the plugin, so added events arrive after the plugin
+    /// re-registers (see [`crate::NRI::resubscribe`]).
+    pub fn set_event_mask(&self, events: EventMask) -> MaskDiff {
+        let previous = EventMask::from_raw(self.events.swap(events.raw_value(), Ordering::Relaxed));
+        previous.diff(&events)
+    }
+
+    /// Check if the plugin subscribes to `event`, logging events it ignores.
+    fn is_subscribed(&self, event: Event) -> bool {
+        let subscribed = self.event_mask().is_set(event);
+        if !subscribed {
+            debug!("Ignoring {:?} event outside the event mask", event);
+        }
+        subscribed
+    }
+
+    /// Get the policy applied when the channel is full.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
     /// Get the number of dropped messages.
     pub fn dropped_messages(&self) -> usize {
         self.dropped_messages.load(Ordering::Relaxed)
     }
 
-    /// Extract container metadata from a container and pod.
+    /// Extract container metadata from a container and pod, leaving the
+    /// cgroup id to [`MetadataPlugin::container_metadata`].
     fn extract_metadata(
         &self,
         container: &api::Container,
         pod: Option<&api::PodSandbox>,
     ) -> ContainerMetadata {
-        let cgroup_path = if let Some(linux_container) = container.linux.as_ref() {
-            linux_container.cgroups_path.clone()
+        let (cgroup_path, limits) = if let Some(linux_container) = container.linux.as_ref() {
+            let limits = linux_container
+                .resources
+                .as_ref()
+                .map(ContainerLimits::from_resources)
+                .unwrap_or_default();
+            (linux_container.cgroups_path.clone(), limits)
         } else {
-            String::new()
+            (String::new(), ContainerLimits::default())
         };
 
         let (pod_name, pod_namespace, pod_uid) = if let Some(pod) = pod {
@@ -99,6 +377,7 @@ impl MetadataPlugin {
             pod_namespace,
             pod_uid,
             container_name: container.name.clone(),
+            cgroup_id: None,
             cgroup_path,
             pid: if container.pid > 0 {
                 Some(container.pid)
@@ -107,29 +386,60 @@ impl MetadataPlugin {
             },
             labels: container.labels.clone(),
             annotations: container.annotations.clone(),
+            limits,
         }
     }
 
-    /// Send a metadata message through the channel.
-    fn send_message(&self, message: MetadataMessage) {
-        // Use try_send to avoid blocking the runtime
-        if let Err(e) = self.tx.try_send(message) {
+    /// Extract container metadata and resolve the container's cgroup id
+    async fn container_metadata(
+        &self,
+        container: &api::Container,
+        pod: Option<&api::PodSandbox>,
+    ) -> ContainerMetadata {
+        let mut metadata = self.extract_metadata(container, pod);
+        metadata.cgroup_id = resolve_cgroup_id(&metadata.cgroup_path, Path::new(CGROUP_ROOT)).await;
+        metadata
+    }
+
+    /// Send a metadata message through the channel, applying the overflow policy.
+    async fn send_message(&self, message: MetadataMessage) {
+        let result = match self.overflow_policy {
+            // Use try_send to avoid blocking the runtime
+            OverflowPolicy::Drop => self.tx.try_send(message).map_err(|e| e.to_string()),
+            OverflowPolicy::BlockWithTimeout(timeout) => {
+                match tokio::time::timeout(timeout, self.tx.send(message)).await {
+                    Ok(result) => result.map_err(|e| e.to_string()),
+                    Err(_) => Err(format!("channel still full after {:?}", timeout)),
+                }
+            }
+        };
+
+        if let Err(e) = result {
             self.dropped_messages.fetch_add(1, Ordering::Relaxed);
             warn!("Failed to send metadata message: {}", e);
         }
     }
 
+    /// Send a lifecycle hint for a container, if the plugin sends them
+    async fn send_hint(&self, container_id: &str, hint: LifecycleHint) {
+        if self.lifecycle_hints {
+            self.send_message(MetadataMessage::Lifecycle(container_id.to_string(), hint))
+                .await;
+        }
+    }
+
     /// Initial synchronization handler for containers: send metadata messages.
-    fn process_containers(&self, containers: &[api::Container], pods: &[api::PodSandbox]) {
+    async fn process_containers(&self, containers: &[api::Container], pods: &[api::PodSandbox]) {
         let pods_map: HashMap<String, &api::PodSandbox> =
             pods.iter().map(|pod| (pod.id.clone(), pod)).collect();
 
         for container in containers {
             let pod = pods_map.get(&container.pod_sandbox_id).copied();
-            let metadata = self.extract_metadata(container, pod);
+            let metadata = self.container_metadata(container, pod).await;
 
             debug!("Adding container metadata: {:?}", metadata);
-            self.send_message(MetadataMessage::Add(container.id.clone(), metadata));
+            self.send_message(MetadataMessage::Add(container.id.clone(), metadata))
+                .await;
         }
     }
 }
@@ -146,9 +456,11 @@ impl Plugin for MetadataPlugin {
             req.runtime_name, req.runtime_version
         );
 
-        // Subscribe to container lifecycle events
-        let mut events = EventMask::new();
-        events.set(&[Event::CREATE_CONTAINER, Event::STOP_CONTAINER]);
+        // A new connection, which synchronizes from scratch
+        self.synchronizing.lock().unwrap().clear();
+
+        let events = self.event_mask();
+        info!("Subscribing to events: {}", events.pretty_string());
 
         Ok(ConfigureResponse {
             events: events.raw_value(),
@@ -168,7 +480,22 @@ impl Plugin for MetadataPlugin {
         );
 
         // Process existing containers
-        self.process_containers(&req.containers, &req.pods);
+        self.process_containers(&req.containers, &req.pods).await;
+
+        // The runtime may list its containers over several requests
+        let listed = {
+            let mut synchronizing = self.synchronizing.lock().unwrap();
+            synchronizing.extend(req.containers.iter().map(|container| container.id.clone()));
+            if req.more {
+                None
+            } else {
+                Some(std::mem::take(&mut *synchronizing))
+            }
+        };
+        if let Some(listed) = listed {
+            self.send_message(MetadataMessage::Synchronized(listed))
+                .await;
+        }
 
         // We don't request any container updates
         Ok(SynchronizeResponse {
@@ -183,14 +510,19 @@ impl Plugin for MetadataPlugin {
         _ctx: &TtrpcContext,
         req: CreateContainerRequest,
     ) -> ttrpc::Result<CreateContainerResponse> {
+        if !self.is_subscribed(Event::CREATE_CONTAINER) {
+            return Ok(CreateContainerResponse::default());
+        }
         let container = &req.container;
 
-        // Convert MessageField<PodSandbox> to &PodSandbox for extract_metadata
+        // Convert MessageField<PodSandbox> to &PodSandbox for container_metadata
         let pod = req.pod.as_ref();
 
         debug!("Container created: {}", container.id);
-        let metadata = self.extract_metadata(container, pod);
-        self.send_message(MetadataMessage::Add(container.id.clone(), metadata));
+        let metadata = self.container_metadata(container, pod).await;
+        self.send_message(MetadataMessage::Add(container.id.clone(), metadata))
+            .await;
+        self.send_hint(&container.id, LifecycleHint::Created).await;
 
         // We don't request any container adjustments
         Ok(CreateContainerResponse::default())
@@ -201,14 +533,23 @@ impl Plugin for MetadataPlugin {
         _ctx: &TtrpcContext,
         req: UpdateContainerRequest,
     ) -> ttrpc::Result<UpdateContainerResponse> {
+        if !self.is_subscribed(Event::UPDATE_CONTAINER) {
+            return Ok(UpdateContainerResponse::default());
+        }
         let container = &req.container;
 
-        // Convert MessageField<PodSandbox> to &PodSandbox for extract_metadata
+        // Convert MessageField<PodSandbox> to &PodSandbox for container_metadata
         let pod = req.pod.as_ref();
 
         debug!("Container updated: {}", container.id);
-        let metadata = self.extract_metadata(container, pod);
-        self.send_message(MetadataMessage::Add(container.id.clone(), metadata));
+        let mut metadata = self.container_metadata(container, pod).await;
+
+        // The container still has its old resources; the request carries the new ones
+        if let Some(resources) = req.linux_resources.as_ref() {
+            metadata.limits = ContainerLimits::from_resources(resources);
+        }
+        self.send_message(MetadataMessage::Add(container.id.clone(), metadata))
+            .await;
 
         // We don't request any container updates
         Ok(UpdateContainerResponse::default())
@@ -219,10 +560,15 @@ impl Plugin for MetadataPlugin {
         _ctx: &TtrpcContext,
         req: StopContainerRequest,
     ) -> ttrpc::Result<StopContainerResponse> {
+        if !self.is_subscribed(Event::STOP_CONTAINER) {
+            return Ok(StopContainerResponse::default());
+        }
         let container_id = &req.container.id;
 
         debug!("Container stopped/removed: {}", container_id);
-        self.send_message(MetadataMessage::Remove(container_id.clone()));
+        self.send_hint(container_id, LifecycleHint::Stopped).await;
+        self.send_message(MetadataMessage::Remove(container_id.clone()))
+            .await;
 
         // We don't request any container updates
         Ok(StopContainerResponse::default())
@@ -238,8 +584,39 @@ impl Plugin for MetadataPlugin {
         Ok(UpdatePodSandboxResponse::default())
     }
 
+    async fn state_change(
+        &self,
+        _ctx: &TtrpcContext,
+        req: StateChangeEvent,
+    ) -> ttrpc::Result<Empty> {
+        // Only container starts carry news; other changes arrive as requests
+        if req.event.enum_value() != Ok(Event::START_CONTAINER)
+            || !self.is_subscribed(Event::START_CONTAINER)
+        {
+            return Ok(Empty::default());
+        }
+
+        debug!("Container started: {}", req.container.id);
+        self.send_hint(&req.container.id, LifecycleHint::Started)
+            .await;
+        Ok(Empty::default())
+    }
+
     async fn shutdown(&self, _ctx: &TtrpcContext, _req: Empty) -> ttrpc::Result<Empty> {
         info!("Shutting down metadata plugin");
+
+        if let Some(notifier) = &self.shutdown_notifier {
+            // Nobody listening is fine: the owner chose to ignore shutdowns
+            let _ = notifier.send(ShutdownNotice {
+                received_at: SystemTime::now(),
+            });
+        }
+
+        // Best effort: don't hold up the runtime's shutdown on a full channel
+        if let Err(e) = self.tx.try_send(MetadataMessage::Shutdown) {
+            self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+            warn!("Failed to send shutdown message: {}", e);
+        }
         Ok(Empty::default())
     }
 }
@@ -250,6 +627,32 @@ mod tests {
     use protobuf::{EnumOrUnknown, MessageField, SpecialFields};
     use tokio::sync::mpsc;
 
+    /// Linux resources with a CPU quota and period, a cpuset and a memory limit
+    fn resources(quota: i64, cpus: &str, memory_limit: i64) -> api::LinuxResources {
+        api::LinuxResources {
+            cpu: MessageField::some(api::LinuxCPU {
+                quota: MessageField::some(api::OptionalInt64 {
+                    value: quota,
+                    ..Default::default()
+                }),
+                period: MessageField::some(api::OptionalUInt64 {
+                    value: 100_000,
+                    ..Default::default()
+                }),
+                cpus: cpus.to_string(),
+                ..Default::default()
+            }),
+            memory: MessageField::some(api::LinuxMemory {
+                limit: MessageField::some(api::OptionalInt64 {
+                    value: memory_limit,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
     #[tokio::test]
     async fn test_metadata_extraction() {
         // Create a channel for testing
@@ -266,7 +669,7 @@ mod tests {
                 cgroups_path: "/sys/fs/cgroup/test".to_string(),
                 namespaces: vec![],
                 devices: vec![],
-                resources: MessageField::none(),
+                resources: MessageField::some(resources(50_000, "0-3", 1 << 30)),
                 oom_score_adj: MessageField::none(),
                 special_fields: SpecialFields::default(),
             }),
@@ -299,9 +702,22 @@ mod tests {
         assert_eq!(metadata.container_name, "test-container");
         assert_eq!(metadata.cgroup_path, "/sys/fs/cgroup/test");
         assert_eq!(metadata.pid, Some(1234));
+        assert_eq!(
+            metadata.limits,
+            ContainerLimits {
+                cpu_quota: Some(50_000),
+                cpu_period: Some(100_000),
+                cpu_shares: None,
+                cpuset_cpus: Some("0-3".to_string()),
+                cpuset_mems: None,
+                memory_limit: Some(1 << 30),
+            }
+        );
 
         // Test sending a message
-        plugin.send_message(MetadataMessage::Add(container.id.clone(), metadata));
+        plugin
+            .send_message(MetadataMessage::Add(container.id.clone(), metadata))
+            .await;
 
         // Verify message was received
         let message = rx.recv().await.unwrap();
@@ -453,6 +869,10 @@ mod tests {
             }
             _ => panic!("Expected Add message for container1"),
         }
+        match rx.recv().await.unwrap() {
+            MetadataMessage::Synchronized(listed) => assert_eq!(listed, vec!["container1"]),
+            message => panic!("Expected Synchronized, got {:?}", message),
+        }
 
         // Test 3: Create a new container
         let new_pod = create_test_pod("pod2", "new-pod", "test-namespace");
@@ -483,7 +903,12 @@ mod tests {
             _ => panic!("Expected Add message for container2"),
         }
 
-        // Test 4: Update a container
+        // Test 4: Update a container, which needs a subscription to updates
+        let mut events = plugin.event_mask();
+        events.set(&[Event::UPDATE_CONTAINER]);
+        let diff = plugin.set_event_mask(events);
+        assert!(diff.added.is_set(Event::UPDATE_CONTAINER));
+        assert_eq!(diff.removed, EventMask::new());
         let updated_pod = create_test_pod("pod2", "new-pod", "test-namespace");
         let mut updated_container = create_test_container(
             "container2",
@@ -496,7 +921,7 @@ mod tests {
         let update_req = UpdateContainerRequest {
             pod: MessageField::some(updated_pod),
             container: MessageField::some(updated_container),
-            linux_resources: MessageField::none(),
+            linux_resources: MessageField::some(resources(-1, "2", 1 << 20)),
             special_fields: SpecialFields::default(),
         };
 
@@ -515,6 +940,11 @@ mod tests {
                     "/sys/fs/cgroup/updated",
                 );
                 assert_eq!(metadata.pid, Some(2000));
+
+                // Limits come from the requested resources
+                assert_eq!(metadata.limits.cpu_quota, Some(ContainerLimits::UNLIMITED));
+                assert_eq!(metadata.limits.cpuset_cpus.as_deref(), Some("2"));
+                assert_eq!(metadata.limits.memory_limit, Some(1 << 20));
             }
             _ => panic!("Expected Add message for updated container2"),
         }
@@ -545,4 +975,311 @@ mod tests {
             _ => panic!("Expected Remove message for container1"),
         }
     }
+
+    #[tokio::test]
+    async fn test_lifecycle_hints() {
+        let context = TtrpcContext {
+            mh: ttrpc::MessageHeader::default(),
+            metadata: HashMap::<String, Vec<String>>::default(),
+            timeout_nano: 5000,
+        };
+        let container = api::Container {
+            id: "c0".to_string(),
+            ..Default::default()
+        };
+        let started = StateChangeEvent {
+            event: EnumOrUnknown::from(Event::START_CONTAINER),
+            container: MessageField::some(container.clone()),
+            ..Default::default()
+        };
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let plugin = MetadataPlugin::new(tx).with_lifecycle_hints();
+        let mut events = default_event_mask();
+        events.set(&[Event::START_CONTAINER]);
+        plugin.set_event_mask(events);
+
+        plugin
+            .create_container(
+                &context,
+                CreateContainerRequest {
+                    container: MessageField::some(container.clone()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        plugin
+            .state_change(&context, started.clone())
+            .await
+            .unwrap();
+        plugin
+            .stop_container(
+                &context,
+                StopContainerRequest {
+                    container: MessageField::some(container.clone()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        // Hints follow the addition and precede the removal
+        let mut messages = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            messages.push(match message {
+                MetadataMessage::Add(id, _) => format!("add {}", id),
+                MetadataMessage::Remove(id) => format!("remove {}", id),
+                MetadataMessage::Lifecycle(id, hint) => format!("{:?} {}", hint, id),
+                message => panic!("Unexpected message {:?}", message),
+            });
+        }
+        assert_eq!(
+            messages,
+            [
+                "add c0",
+                "Created c0",
+                "Started c0",
+                "Stopped c0",
+                "remove c0"
+            ]
+        );
+
+        // Without hints, starts are not reported
+        let (tx, mut rx) = mpsc::channel(10);
+        let plugin = MetadataPlugin::new(tx);
+        plugin.set_event_mask(events);
+        plugin.state_change(&context, started).await.unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_limits_from_resources() {
+        // Nothing set
+        assert_eq!(
+            ContainerLimits::from_resources(&api::LinuxResources::default()),
+            ContainerLimits::default()
+        );
+
+        // Negative values are unlimited, zero is kept, empty cpusets are unset
+        let limits = ContainerLimits::from_resources(&resources(-100, "", 0));
+        assert_eq!(limits.cpu_quota, Some(ContainerLimits::UNLIMITED));
+        assert_eq!(limits.memory_limit, Some(0));
+        assert_eq!(limits.cpuset_cpus, None);
+        let limits = ContainerLimits::from_resources(&resources(0, "1", -1));
+        assert_eq!(limits.cpu_quota, Some(0));
+        assert_eq!(limits.memory_limit, Some(ContainerLimits::UNLIMITED));
+
+        // A present wrapper holding zero is set, unlike a missing one
+        let mut with_shares = resources(0, "", 0);
+        with_shares.cpu.as_mut().unwrap().shares = MessageField::some(api::OptionalUInt64 {
+            value: 0,
+            ..Default::default()
+        });
+        assert_eq!(
+            ContainerLimits::from_resources(&with_shares).cpu_shares,
+            Some(0)
+        );
+        assert_eq!(
+            ContainerLimits::from_resources(&resources(0, "", 0)).cpu_shares,
+            None
+        );
+    }
+
+    #[test]
+    fn test_cgroup_dir() {
+        let root = Path::new("/sys/fs/cgroup");
+        assert_eq!(
+            cgroup_dir("/kubepods/pod-uid/c1", root),
+            Some(root.join("kubepods/pod-uid/c1"))
+        );
+        assert_eq!(
+            cgroup_dir("/sys/fs/cgroup/kubepods/pod-uid/c1", root),
+            Some(root.join("kubepods/pod-uid/c1"))
+        );
+
+        // Systemd cgroup driver
+        assert_eq!(
+            cgroup_dir(
+                "kubepods-besteffort-pod1234.slice:cri-containerd:c1",
+                root
+            ),
+            Some(root.join(
+                "kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1234.slice/cri-containerd-c1.scope"
+            ))
+        );
+        assert_eq!(
+            cgroup_dir("system.slice:docker:c1", root),
+            Some(root.join("system.slice/docker-c1.scope"))
+        );
+        assert_eq!(cgroup_dir("-.slice::c1", root), Some(root.join("c1.scope")));
+        assert_eq!(
+            cgroup_dir("kubepods.slice:cri-containerd:nested.slice", root),
+            Some(root.join("kubepods.slice/nested.slice"))
+        );
+
+        assert_eq!(cgroup_dir("", root), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_resolve_cgroup_id() {
+        use std::os::unix::fs::MetadataExt;
+
+        // Directories standing in for cgroups under a cgroup mount
+        let root = std::env::temp_dir().join(format!("nri_cgroup_root_{}", std::process::id()));
+        let cgroup = root.join("kubepods/pod-uid/container1");
+        std::fs::create_dir_all(&cgroup).unwrap();
+        let inode = std::fs::metadata(&cgroup).unwrap().ino();
+        let scope = root.join("kubepods.slice/kubepods-pod1.slice/cri-containerd-container1.scope");
+        std::fs::create_dir_all(&scope).unwrap();
+        let scope_inode = std::fs::metadata(&scope).unwrap().ino();
+
+        // Absolute under the root, and relative to it with or without a slash
+        assert_eq!(
+            resolve_cgroup_id(cgroup.to_str().unwrap(), &root).await,
+            Some(inode)
+        );
+        assert_eq!(
+            resolve_cgroup_id("kubepods/pod-uid/container1", &root).await,
+            Some(inode)
+        );
+        assert_eq!(
+            resolve_cgroup_id("/kubepods/pod-uid/container1", &root).await,
+            Some(inode)
+        );
+        assert_eq!(
+            resolve_cgroup_id("kubepods-pod1.slice:cri-containerd:container1", &root).await,
+            Some(scope_inode)
+        );
+
+        // Missing cgroups and empty paths are not resolved
+        assert_eq!(
+            resolve_cgroup_id("kubepods/pod-uid/missing", &root).await,
+            None
+        );
+        assert_eq!(
+            resolve_cgroup_id("kubepods-pod1.slice:cri-containerd:missing", &root).await,
+            None
+        );
+        assert_eq!(resolve_cgroup_id("", &root).await, None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sends_terminal_message() {
+        let context = TtrpcContext {
+            mh: ttrpc::MessageHeader::default(),
+            metadata: HashMap::<String, Vec<String>>::default(),
+            timeout_nano: 5000,
+        };
+
+        let (tx, mut rx) = mpsc::channel(2);
+        let plugin = MetadataPlugin::new(tx);
+        plugin
+            .send_message(MetadataMessage::Remove("c0".to_string()))
+            .await;
+        plugin.shutdown(&context, Empty::default()).await.unwrap();
+
+        // The terminal message follows everything sent before it
+        assert!(matches!(rx.recv().await, Some(MetadataMessage::Remove(_))));
+        assert!(matches!(rx.recv().await, Some(MetadataMessage::Shutdown)));
+        assert_eq!(plugin.dropped_messages(), 0);
+
+        // With the channel full, shutdown still returns and counts the drop
+        let (tx, _rx) = mpsc::channel(1);
+        let plugin = MetadataPlugin::new(tx);
+        plugin
+            .send_message(MetadataMessage::Remove("c0".to_string()))
+            .await;
+        plugin.shutdown(&context, Empty::default()).await.unwrap();
+        assert_eq!(plugin.dropped_messages(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notifier() {
+        let context = TtrpcContext {
+            mh: ttrpc::MessageHeader::default(),
+            metadata: HashMap::<String, Vec<String>>::default(),
+            timeout_nano: 5000,
+        };
+
+        // The notice arrives even when the terminal message is dropped
+        let (tx, _rx) = mpsc::channel(1);
+        let (notice_tx, mut notice_rx) = mpsc::unbounded_channel();
+        let plugin = MetadataPlugin::new(tx).with_shutdown_notifier(notice_tx);
+        plugin
+            .send_message(MetadataMessage::Remove("c0".to_string()))
+            .await;
+        let before = SystemTime::now();
+        plugin.shutdown(&context, Empty::default()).await.unwrap();
+        assert_eq!(plugin.dropped_messages(), 1);
+        let notice = notice_rx.try_recv().unwrap();
+        assert!(notice.received_at >= before);
+        assert!(notice_rx.try_recv().is_err());
+
+        // Clones share the notifier, and a closed one does not fail shutdown
+        plugin
+            .clone()
+            .shutdown(&context, Empty::default())
+            .await
+            .unwrap();
+        assert!(notice_rx.try_recv().is_ok());
+        drop(notice_rx);
+        plugin.shutdown(&context, Empty::default()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_builder_applies_overflow_policy() {
+        // Dropping: messages beyond the channel capacity are dropped
+        let (plugin, _rx) = crate::NRI::builder("test", "10")
+            .channel_capacity(2)
+            .metadata_plugin();
+        assert_eq!(plugin.overflow_policy(), OverflowPolicy::Drop);
+        for i in 0..3 {
+            plugin
+                .send_message(MetadataMessage::Remove(format!("c{}", i)))
+                .await;
+        }
+        assert_eq!(plugin.dropped_messages(), 1);
+
+        // Blocking: the sender waits for the receiver to make room
+        let policy = OverflowPolicy::BlockWithTimeout(Duration::from_secs(5));
+        let (plugin, mut rx) = crate::NRI::builder("test", "10")
+            .channel_capacity(1)
+            .overflow_policy(policy)
+            .metadata_plugin();
+        assert_eq!(plugin.overflow_policy(), policy);
+        plugin
+            .send_message(MetadataMessage::Remove("c0".to_string()))
+            .await;
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                if let Some(MetadataMessage::Remove(id)) = rx.recv().await {
+                    ids.push(id);
+                }
+            }
+            ids
+        });
+        plugin
+            .send_message(MetadataMessage::Remove("c1".to_string()))
+            .await;
+        assert_eq!(reader.await.unwrap(), vec!["c0", "c1"]);
+        assert_eq!(plugin.dropped_messages(), 0);
+
+        // Blocking gives up after the timeout
+        let (plugin, _rx) = crate::NRI::builder("test", "10")
+            .channel_capacity(1)
+            .overflow_policy(OverflowPolicy::BlockWithTimeout(Duration::from_millis(10)))
+            .metadata_plugin();
+        for i in 0..2 {
+            plugin
+                .send_message(MetadataMessage::Remove(format!("c{}", i)))
+                .await;
+        }
+        assert_eq!(plugin.dropped_messages(), 1);
+    }
 }
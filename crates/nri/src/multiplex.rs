@@ -1,14 +1,20 @@
 use std::collections::HashMap;
+use std::fs::Metadata;
 use std::io::{self, ErrorKind};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
+use collector_errors::{Classified, ErrorCode};
 use futures::{ready, Future, FutureExt};
 use log::{debug, error};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::UnixStream;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::task::JoinHandle;
 
@@ -27,6 +33,58 @@ const TTRPC_MESSAGE_HEADER_LENGTH: usize = 10;
 const TTRPC_MESSAGE_LENGTH_MAX: usize = 4 << 20;
 // Maximum allowed payload size (same as in Go implementation)
 const MAX_PAYLOAD_SIZE: usize = TTRPC_MESSAGE_HEADER_LENGTH + TTRPC_MESSAGE_LENGTH_MAX;
+// Default time allowed for connecting to a socket in Mux::connect_unix
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configuration for the multiplexer.
+#[derive(Debug, Clone)]
+pub struct MuxConfig {
+    /// Maximum payload size of a single frame, in bytes. Incoming frames that
+    /// announce a larger payload are treated as a protocol error.
+    pub max_payload_size: usize,
+    /// Connection IDs allowed in incoming frame headers. `None` allows any
+    /// non-zero ID. A header with a disallowed ID is treated as a protocol error.
+    pub allowed_conn_ids: Option<Vec<ConnID>>,
+    /// Time allowed for connecting in [`Mux::connect_unix`].
+    pub connect_timeout: Duration,
+    /// Whether sockets opened on this multiplexer fill each read with as
+    /// many queued frames as fit, rather than one frame per read. Can be
+    /// changed per socket with [`MuxSocket::set_coalesce_reads`].
+    pub coalesce_reads: bool,
+}
+
+impl Default for MuxConfig {
+    fn default() -> Self {
+        Self {
+            max_payload_size: MAX_PAYLOAD_SIZE,
+            allowed_conn_ids: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            coalesce_reads: false,
+        }
+    }
+}
+
+impl MuxConfig {
+    /// Configuration for an NRI connection, which only uses the plugin and
+    /// runtime service connections.
+    pub fn nri() -> Self {
+        Self {
+            allowed_conn_ids: Some(vec![PLUGIN_SERVICE_CONN, RUNTIME_SERVICE_CONN]),
+            ..Self::default()
+        }
+    }
+
+    /// Returns whether a connection ID may appear on this multiplexer.
+    fn is_allowed(&self, conn_id: ConnID) -> bool {
+        if conn_id == 0 {
+            return false;
+        }
+        match &self.allowed_conn_ids {
+            Some(allowed) => allowed.contains(&conn_id),
+            None => true,
+        }
+    }
+}
 
 /// # NRI Socket Multiplexer
 ///
@@ -99,8 +157,21 @@ pub enum MuxError {
     #[error("I/O error during write: {0}")]
     Write(#[source] io::Error),
 
-    #[error("Payload too large: {0} bytes (max: {MAX_PAYLOAD_SIZE})")]
-    PayloadTooLarge(usize),
+    #[error("Payload too large: {0} bytes (max: {1})")]
+    PayloadTooLarge(usize, usize),
+
+    #[error(
+        "Protocol error: {violation} after {frames} frames ({bytes} bytes), header: {header:02x?}"
+    )]
+    Protocol {
+        violation: FrameViolation,
+        /// Number of valid frames read before the offending header
+        frames: u64,
+        /// Number of bytes read before the offending header
+        bytes: u64,
+        /// The raw bytes of the offending header
+        header: [u8; HEADER_SIZE],
+    },
 
     #[error("Connection with ID {0} already exists")]
     ConnectionAlreadyExists(ConnID),
@@ -116,6 +187,116 @@ pub enum MuxError {
 
     #[error("Failed to send payload to connection {0}: {1}")]
     SendError(ConnID, String),
+
+    #[error(
+        "Socket {} does not exist; is NRI enabled in the containerd config \
+         (disable = false under [plugins.\"io.containerd.nri.v1.nri\"])?",
+        path.display()
+    )]
+    SocketNotFound { path: PathBuf },
+
+    #[error("{} is not a socket", path.display())]
+    NotASocket { path: PathBuf },
+
+    #[error(
+        "Permission denied connecting to {} (mode {mode:o}, owner uid {uid}) as euid {euid}; \
+         NRI plugins usually need to run as root",
+        path.display()
+    )]
+    PermissionDenied {
+        path: PathBuf,
+        /// Permission bits of the socket
+        mode: u32,
+        /// Owner of the socket
+        uid: u32,
+        /// Effective uid of this process
+        euid: u32,
+    },
+
+    #[error("Timed out connecting to {} after {timeout:?}", path.display())]
+    ConnectTimeout { path: PathBuf, timeout: Duration },
+
+    #[error("Failed to connect to {}: {source}", path.display())]
+    Connect {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl Classified for MuxError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            MuxError::Read(_)
+            | MuxError::Write(_)
+            | MuxError::SocketNotFound { .. }
+            | MuxError::NotASocket { .. }
+            | MuxError::PermissionDenied { .. }
+            | MuxError::ConnectTimeout { .. }
+            | MuxError::Connect { .. } => ErrorCode::NriConnect,
+            MuxError::PayloadTooLarge(..) | MuxError::Protocol { .. } => ErrorCode::NriProtocol,
+            MuxError::ConnectionAlreadyExists(_)
+            | MuxError::InvalidConnectionId(_)
+            | MuxError::TaskPanic(..)
+            | MuxError::LockError
+            | MuxError::SendError(..) => ErrorCode::Internal,
+        }
+    }
+
+    fn retryable(&self) -> bool {
+        // Fixing the socket's type or permissions needs an operator
+        !matches!(
+            self,
+            MuxError::NotASocket { .. } | MuxError::PermissionDenied { .. }
+        ) && self.code().retryable()
+    }
+}
+
+/// Build the permission diagnostic for a socket from its metadata
+fn permission_denied(path: &Path, metadata: &Metadata, euid: u32) -> MuxError {
+    MuxError::PermissionDenied {
+        path: path.to_path_buf(),
+        mode: metadata.mode() & 0o7777,
+        uid: metadata.uid(),
+        euid,
+    }
+}
+
+/// Run a connection attempt for at most `timeout`.
+async fn connect_with_timeout<F>(path: &Path, timeout: Duration, connect: F) -> Result<UnixStream>
+where
+    F: Future<Output = io::Result<UnixStream>>,
+{
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(source)) => Err(MuxError::Connect {
+            path: path.to_path_buf(),
+            source,
+        }),
+        Err(_) => Err(MuxError::ConnectTimeout {
+            path: path.to_path_buf(),
+            timeout,
+        }),
+    }
+}
+
+/// A frame header that cannot belong to a well-formed stream, usually because
+/// the stream lost synchronization with the frame boundaries.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum FrameViolation {
+    #[error("unexpected connection ID {0}")]
+    UnexpectedConnId(ConnID),
+
+    #[error("payload length {len} exceeds maximum {max}")]
+    PayloadTooLarge { len: usize, max: usize },
+}
+
+/// Counters of the frames read from the socket, used to locate where a
+/// stream lost synchronization.
+#[derive(Debug, Default)]
+struct ReadCounters {
+    frames: u64,
+    bytes: u64,
 }
 
 /// Result type for multiplexer operations.
@@ -131,6 +312,8 @@ pub struct Mux {
     shutdown_tx: Sender<()>,
     // Monitor handle
     monitor_handle: JoinHandle<Result<()>>,
+    // Frame limits and allowed connection IDs
+    config: MuxConfig,
 }
 
 /// MuxSocket represents a logical connection within the multiplexer.
@@ -139,10 +322,14 @@ pub struct MuxSocket {
     conn_id: ConnID,
     /// Channel to send data to the multiplexer.
     write_tx: Sender<WriteRequest>,
+    /// Maximum payload size of a single write.
+    max_payload_size: usize,
     /// Channel to receive data from the multiplexer.
     read_rx: Receiver<Bytes>,
     /// Buffer for partial reads.
     read_buffer: BytesMut,
+    /// Whether a read takes further queued frames after the first.
+    coalesce_reads: bool,
     /// Reference to the connection map for cleanup.
     connections: Arc<Mutex<HashMap<ConnID, Sender<Bytes>>>>,
     /// Whether this socket has been closed.
@@ -160,8 +347,63 @@ pub struct MuxSocket {
 }
 
 impl Mux {
-    /// Creates a new multiplexer using the provided socket.
+    /// Connects to the Unix socket at `path` and creates a multiplexer over it.
+    ///
+    /// The socket is checked before connecting, so the common failures get a
+    /// targeted error instead of a bare OS error: a missing socket (NRI is
+    /// likely disabled in the runtime) and a path that is not a socket. When
+    /// the kernel denies the connection, the error reports the socket's mode
+    /// and owner, and this process' effective uid. Connecting fails with
+    /// [`MuxError::ConnectTimeout`] after `config.connect_timeout`.
+    pub async fn connect_unix(path: &Path, config: MuxConfig) -> Result<Self> {
+        let metadata = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(MuxError::SocketNotFound {
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(source) => {
+                return Err(MuxError::Connect {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(MuxError::NotASocket {
+                path: path.to_path_buf(),
+            });
+        }
+
+        // Leave the access decision to the kernel, which also accounts for
+        // supplementary groups, ACLs and capabilities
+        let stream =
+            match connect_with_timeout(path, config.connect_timeout, UnixStream::connect(path))
+                .await
+            {
+                Err(MuxError::Connect { source, .. })
+                    if source.kind() == ErrorKind::PermissionDenied =>
+                {
+                    // Safety: geteuid has no preconditions and cannot fail
+                    let euid = unsafe { libc::geteuid() };
+                    return Err(permission_denied(path, &metadata, euid));
+                }
+                result => result?,
+            };
+        Ok(Self::with_config(stream, config))
+    }
+
+    /// Creates a new multiplexer using the provided socket and the default configuration.
     pub fn new(socket: impl AsyncRead + AsyncWrite + Send + Sync + 'static) -> Self {
+        Self::with_config(socket, MuxConfig::default())
+    }
+
+    /// Creates a new multiplexer using the provided socket and configuration.
+    pub fn with_config(
+        socket: impl AsyncRead + AsyncWrite + Send + Sync + 'static,
+        config: MuxConfig,
+    ) -> Self {
         let (write_tx, write_rx) = mpsc::channel(100);
         let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
         let connections = Arc::new(Mutex::new(HashMap::new()));
@@ -175,13 +417,27 @@ impl Mux {
 
         // Create the reader task
         let reader_connections = connections.clone();
+        let reader_config = config.clone();
         let reader_handle = tokio::spawn(async move {
-            Self::run_reader(socket_reader, reader_connections, reader_shutdown_rx).await
+            Self::run_reader(
+                socket_reader,
+                reader_connections,
+                reader_config,
+                reader_shutdown_rx,
+            )
+            .await
         });
 
         // Create the writer task
+        let max_payload_size = config.max_payload_size;
         let writer_handle = tokio::spawn(async move {
-            Self::run_writer(socket_writer, write_rx, writer_shutdown_rx).await
+            Self::run_writer(
+                socket_writer,
+                write_rx,
+                max_payload_size,
+                writer_shutdown_rx,
+            )
+            .await
         });
 
         // Create the monitor task
@@ -204,6 +460,11 @@ impl Mux {
                             debug!("Reader task completed successfully");
                             Ok(())
                         }
+                        Ok(Err(e @ MuxError::Protocol { .. })) => {
+                            // Keep the diagnostic details for the caller
+                            error!("Reader error: {}", e);
+                            Err(e)
+                        }
                         Ok(Err(e)) => {
                             error!("Reader error: {}", e);
                             // Create a new error rather than moving the original
@@ -249,6 +510,7 @@ impl Mux {
             write_tx,
             shutdown_tx,
             monitor_handle,
+            config,
         }
     }
 
@@ -256,14 +518,16 @@ impl Mux {
     async fn run_reader(
         mut reader: impl AsyncRead + Unpin,
         connections: Arc<Mutex<HashMap<ConnID, Sender<Bytes>>>>,
+        config: MuxConfig,
         mut shutdown_rx: Receiver<()>,
     ) -> Result<()> {
+        let mut counters = ReadCounters::default();
         loop {
             tokio::select! {
                 _ = shutdown_rx.recv() => {
                     return Ok(());
                 }
-                result = Self::read_and_route(&mut reader, &connections) => {
+                result = Self::read_and_route(&mut reader, &connections, &config, &mut counters) => {
                     match result {
                         Ok(_) => continue,
                         Err(e) => return Err(e),
@@ -277,6 +541,7 @@ impl Mux {
     async fn run_writer(
         mut writer: impl AsyncWrite + Unpin,
         mut write_rx: Receiver<WriteRequest>,
+        max_payload_size: usize,
         mut shutdown_rx: Receiver<()>,
     ) -> Result<()> {
         loop {
@@ -287,7 +552,7 @@ impl Mux {
                 maybe_request = write_rx.recv() => {
                     match maybe_request {
                         Some(request) => {
-                            if let Err(e) = Self::write_frame(&mut writer, request).await {
+                            if let Err(e) = Self::write_frame(&mut writer, request, max_payload_size).await {
                                 return Err(e);
                             }
                         }
@@ -302,9 +567,15 @@ impl Mux {
     }
 
     /// Reads a single frame from the socket and routes it to the appropriate connection.
+    ///
+    /// Headers with a disallowed connection ID or an oversized payload length are
+    /// rejected with `MuxError::Protocol` before any payload is read, since they
+    /// indicate the stream is no longer aligned on frame boundaries.
     async fn read_and_route(
         reader: &mut (impl AsyncRead + Unpin),
         connections: &Arc<Mutex<HashMap<ConnID, Sender<Bytes>>>>,
+        config: &MuxConfig,
+        counters: &mut ReadCounters,
     ) -> Result<()> {
         // Read header
         let mut header_buf = [0u8; HEADER_SIZE];
@@ -323,8 +594,26 @@ impl Mux {
         let conn_id = u32::from_be_bytes(header_buf[0..4].try_into().unwrap());
         let payload_len = u32::from_be_bytes(header_buf[4..8].try_into().unwrap()) as usize;
 
-        if payload_len > MAX_PAYLOAD_SIZE {
-            return Err(MuxError::PayloadTooLarge(payload_len));
+        let violation = if !config.is_allowed(conn_id) {
+            Some(FrameViolation::UnexpectedConnId(conn_id))
+        } else if payload_len > config.max_payload_size {
+            Some(FrameViolation::PayloadTooLarge {
+                len: payload_len,
+                max: config.max_payload_size,
+            })
+        } else {
+            None
+        };
+
+        if let Some(violation) = violation {
+            let err = MuxError::Protocol {
+                violation,
+                frames: counters.frames,
+                bytes: counters.bytes,
+                header: header_buf,
+            };
+            error!("Stream desynchronized, shutting down multiplexer: {}", err);
+            return Err(err);
         }
 
         // Read payload
@@ -334,6 +623,9 @@ impl Mux {
             Err(e) => return Err(MuxError::Read(e)),
         }
 
+        counters.frames += 1;
+        counters.bytes += (HEADER_SIZE + payload_len) as u64;
+
         // Convert to Bytes for efficient sharing
         let payload = Bytes::from(payload);
 
@@ -366,14 +658,15 @@ impl Mux {
     async fn write_frame(
         writer: &mut (impl AsyncWrite + Unpin),
         request: WriteRequest,
+        max_payload_size: usize,
     ) -> Result<()> {
         let conn_id = request.conn_id;
         let data = request.data;
         let data_len = data.len();
 
         // Check payload size
-        if data_len > MAX_PAYLOAD_SIZE {
-            return Err(MuxError::PayloadTooLarge(data_len));
+        if data_len > max_payload_size {
+            return Err(MuxError::PayloadTooLarge(data_len, max_payload_size));
         }
 
         // Prepare header (big-endian)
@@ -403,7 +696,7 @@ impl Mux {
 
     /// Opens a connection with the specified ID.
     pub async fn open(&self, conn_id: ConnID) -> Result<MuxSocket> {
-        if conn_id == 0 {
+        if !self.config.is_allowed(conn_id) {
             return Err(MuxError::InvalidConnectionId(conn_id));
         }
 
@@ -419,8 +712,10 @@ impl Mux {
         Ok(MuxSocket {
             conn_id,
             write_tx: self.write_tx.clone(),
+            max_payload_size: self.config.max_payload_size,
             read_rx,
             read_buffer: BytesMut::new(),
+            coalesce_reads: self.config.coalesce_reads,
             connections: self.connections.clone(),
             closed: false,
             pending_permit: None,
@@ -484,6 +779,22 @@ impl Mux {
 }
 
 impl MuxSocket {
+    /// Sets whether reads are filled with as many queued frames as fit.
+    ///
+    /// Without coalescing, each read returns data from at most one frame,
+    /// which costs a read per frame when many small frames arrive together.
+    pub fn set_coalesce_reads(&mut self, coalesce_reads: bool) {
+        self.coalesce_reads = coalesce_reads;
+    }
+
+    /// Copies as much of `data` into `buf` as fits, buffering the rest for
+    /// the next read.
+    fn fill(&mut self, buf: &mut ReadBuf<'_>, data: &[u8]) {
+        let to_copy = std::cmp::min(buf.remaining(), data.len());
+        buf.put_slice(&data[..to_copy]);
+        self.read_buffer.extend_from_slice(&data[to_copy..]);
+    }
+
     /// Closes the connection.
     async fn close(&mut self) -> Result<()> {
         if self.closed {
@@ -516,26 +827,30 @@ impl AsyncRead for MuxSocket {
             let to_copy = std::cmp::min(buf.remaining(), self.read_buffer.len());
             let data = self.read_buffer.split_to(to_copy);
             buf.put_slice(&data);
-            return Poll::Ready(Ok(()));
+        } else {
+            // Otherwise poll for more data
+            match ready!(self.read_rx.poll_recv(cx)) {
+                Some(data) => self.fill(buf, &data),
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::BrokenPipe,
+                        "Connection closed",
+                    )))
+                }
+            }
         }
 
-        // Otherwise poll for more data
-        match ready!(self.read_rx.poll_recv(cx)) {
-            Some(data) => {
-                if data.len() <= buf.remaining() {
-                    buf.put_slice(&data);
-                } else {
-                    let to_copy = buf.remaining();
-                    buf.put_slice(&data[..to_copy]);
-                    self.read_buffer.extend_from_slice(&data[to_copy..]);
+        // Add frames already queued while there is room. A closed channel is
+        // reported by the next read, after the data read so far.
+        if self.coalesce_reads {
+            while buf.remaining() > 0 && self.read_buffer.is_empty() {
+                match self.read_rx.try_recv() {
+                    Ok(data) => self.fill(buf, &data),
+                    Err(_) => break,
                 }
-                Poll::Ready(Ok(()))
             }
-            None => Poll::Ready(Err(io::Error::new(
-                ErrorKind::BrokenPipe,
-                "Connection closed",
-            ))),
         }
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -553,13 +868,13 @@ impl AsyncWrite for MuxSocket {
         }
 
         // Check payload size
-        if buf.len() > MAX_PAYLOAD_SIZE {
+        if buf.len() > self.max_payload_size {
             return Poll::Ready(Err(io::Error::new(
                 ErrorKind::InvalidData,
                 format!(
                     "Payload too large: {} bytes (max: {})",
                     buf.len(),
-                    MAX_PAYLOAD_SIZE
+                    self.max_payload_size
                 ),
             )));
         }
@@ -668,8 +983,76 @@ impl Drop for MuxSocket {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
     use tokio::io::duplex;
-    use tokio::time::{timeout, Duration};
+    use tokio::time::timeout;
+
+    /// Create an empty directory for sockets of the named test
+    fn socket_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nri_mux_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_connect_unix_diagnostics() {
+        let dir = socket_dir("diagnostics");
+
+        // Missing socket, with a hint about the runtime config
+        let missing = dir.join("missing.sock");
+        let err = Mux::connect_unix(&missing, MuxConfig::nri())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(&err, MuxError::SocketNotFound { path } if path == &missing));
+        assert!(err.to_string().contains("io.containerd.nri.v1.nri"));
+        assert_eq!(err.code(), ErrorCode::NriConnect);
+        assert!(err.retryable());
+
+        // Regular file
+        let file = dir.join("file.sock");
+        fs::write(&file, b"").unwrap();
+        let err = Mux::connect_unix(&file, MuxConfig::nri())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(&err, MuxError::NotASocket { path } if path == &file));
+        assert_eq!(err.code(), ErrorCode::NriConnect);
+        assert!(!err.retryable());
+
+        // Socket nobody listens on anymore
+        let stale = dir.join("stale.sock");
+        drop(UnixListener::bind(&stale).unwrap());
+        let err = Mux::connect_unix(&stale, MuxConfig::nri())
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&err, MuxError::Connect { source, .. } if source.kind() == ErrorKind::ConnectionRefused)
+        );
+
+        // Listening socket
+        let socket = dir.join("nri.sock");
+        let _listener = UnixListener::bind(&socket).unwrap();
+        let mux = Mux::connect_unix(&socket, MuxConfig::nri()).await.unwrap();
+        mux.shutdown().await.unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let path = Path::new("/run/nri/nri.sock");
+        let err = connect_with_timeout(path, Duration::from_millis(10), std::future::pending())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            MuxError::ConnectTimeout { timeout, .. } if timeout == Duration::from_millis(10)
+        ));
+    }
 
     #[tokio::test]
     async fn test_open_connection() {
@@ -1249,4 +1632,181 @@ mod tests {
 
         Ok(())
     }
+
+    /// Builds a raw frame as it appears on the wire
+    fn raw_frame(conn_id: ConnID, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&conn_id.to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Writes raw bytes into a multiplexer and returns the result it shuts down with
+    async fn feed_raw_stream(config: MuxConfig, raw: &[u8]) -> Result<()> {
+        let (client, mut server) = duplex(4096);
+        let mut mux = Mux::with_config(client, config);
+        let _conn = mux.open(PLUGIN_SERVICE_CONN).await?;
+
+        server.write_all(raw).await.unwrap();
+
+        // The mux must shut down on its own rather than hang waiting for payload
+        timeout(Duration::from_secs(1), mux.monitor_handle())
+            .await
+            .expect("multiplexer did not shut down on a corrupted stream")
+            .expect("monitor task panicked")
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_stream_bad_conn_id() {
+        let mut raw = raw_frame(PLUGIN_SERVICE_CONN, b"hello");
+        raw.extend_from_slice(&raw_frame(0xdeadbeef, b"garbage"));
+
+        let result = feed_raw_stream(MuxConfig::nri(), &raw).await;
+        assert_eq!(
+            result.as_ref().map_err(MuxError::code).err(),
+            Some(ErrorCode::NriProtocol)
+        );
+        match result {
+            Err(MuxError::Protocol {
+                violation,
+                frames,
+                bytes,
+                header,
+            }) => {
+                assert_eq!(violation, FrameViolation::UnexpectedConnId(0xdeadbeef));
+                assert_eq!(frames, 1);
+                assert_eq!(bytes, (HEADER_SIZE + 5) as u64);
+                assert_eq!(header, [0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 7]);
+            }
+            other => panic!("expected protocol error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_stream_absurd_length() {
+        // A 4GB length must be rejected from the header alone, without allocating
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&PLUGIN_SERVICE_CONN.to_be_bytes());
+        raw.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        match feed_raw_stream(MuxConfig::default(), &raw).await {
+            Err(MuxError::Protocol {
+                violation, frames, ..
+            }) => {
+                assert_eq!(
+                    violation,
+                    FrameViolation::PayloadTooLarge {
+                        len: u32::MAX as usize,
+                        max: MAX_PAYLOAD_SIZE,
+                    }
+                );
+                assert_eq!(frames, 0);
+            }
+            other => panic!("expected protocol error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_configured_max_payload() -> Result<()> {
+        let config = MuxConfig {
+            max_payload_size: 16,
+            ..MuxConfig::default()
+        };
+
+        // Incoming frames over the limit are a protocol error
+        let mut raw = raw_frame(PLUGIN_SERVICE_CONN, &[1u8; 16]);
+        raw.extend_from_slice(&raw_frame(PLUGIN_SERVICE_CONN, &[2u8; 17]));
+        match feed_raw_stream(config.clone(), &raw).await {
+            Err(MuxError::Protocol {
+                violation,
+                frames,
+                bytes,
+                ..
+            }) => {
+                assert_eq!(
+                    violation,
+                    FrameViolation::PayloadTooLarge { len: 17, max: 16 }
+                );
+                assert_eq!(frames, 1);
+                assert_eq!(bytes, (HEADER_SIZE + 16) as u64);
+            }
+            other => panic!("expected protocol error, got {:?}", other),
+        }
+
+        // Outgoing writes over the limit are rejected
+        let (client, _server) = duplex(4096);
+        let mux = Mux::with_config(client, config);
+        let mut conn = mux.open(PLUGIN_SERVICE_CONN).await?;
+        assert!(conn.write_all(&[0u8; 17]).await.is_err());
+        conn.write_all(&[0u8; 16]).await.map_err(MuxError::Write)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_reads() -> Result<()> {
+        let config = MuxConfig {
+            coalesce_reads: true,
+            ..MuxConfig::default()
+        };
+        let (client, mut server) = duplex(4096);
+        let mux = Mux::with_config(client, config);
+        let mut coalesced = mux.open(PLUGIN_SERVICE_CONN).await?;
+        let mut single = mux.open(RUNTIME_SERVICE_CONN).await?;
+        single.set_coalesce_reads(false);
+
+        let frames: [&[u8]; 4] = [b"abc", b"de", b"fghij", b"k"];
+        for conn_id in [PLUGIN_SERVICE_CONN, RUNTIME_SERVICE_CONN] {
+            for frame in frames {
+                server.write_all(&raw_frame(conn_id, frame)).await.unwrap();
+            }
+        }
+        // Let the reader task queue every frame
+        while coalesced.read_rx.len() < frames.len() || single.read_rx.len() < frames.len() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // A single large read returns the frames concatenated
+        let mut buf = [0u8; 64];
+        let n = coalesced.read(&mut buf).await.map_err(MuxError::Read)?;
+        assert_eq!(&buf[..n], b"abcdefghijk");
+
+        // Without coalescing, each read stops at the end of a frame
+        let n = single.read(&mut buf).await.map_err(MuxError::Read)?;
+        assert_eq!(&buf[..n], b"abc");
+
+        // Reads smaller than the queued frames split them and keep the rest
+        for frame in frames {
+            server
+                .write_all(&raw_frame(PLUGIN_SERVICE_CONN, frame))
+                .await
+                .unwrap();
+        }
+        while coalesced.read_rx.len() < frames.len() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let mut data = Vec::new();
+        let mut small = [0u8; 4];
+        for expected in [&b"abcd"[..], b"efgh", b"ijk"] {
+            let n = coalesced.read(&mut small).await.map_err(MuxError::Read)?;
+            assert_eq!(&small[..n], expected);
+            data.extend_from_slice(&small[..n]);
+        }
+        assert_eq!(data, b"abcdefghijk");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_disallowed_connection() {
+        let (client, _server) = duplex(1024);
+        let mux = Mux::with_config(client, MuxConfig::nri());
+
+        assert!(mux.open(RUNTIME_SERVICE_CONN).await.is_ok());
+        assert!(matches!(
+            mux.open(3).await,
+            Err(MuxError::InvalidConnectionId(3))
+        ));
+    }
 }
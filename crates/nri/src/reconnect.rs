@@ -0,0 +1,335 @@
+//! Reconnect policy for the NRI connection.
+//!
+//! When the runtime goes away (e.g. containerd restarts), the plugin has to
+//! connect and register again. [`ReconnectPolicy`] bounds how hard it tries:
+//! delays between attempts grow from `base_backoff` up to `max_backoff`, and
+//! after `max_attempts` consecutive failures it gives up with
+//! [`ReconnectError::AttemptsExhausted`]. A connection that ends before
+//! `min_uptime` continues the delays instead of starting them over, so a
+//! runtime that drops the plugin right after registration is not hammered.
+//!
+//! With jitter enabled the delays use "decorrelated jitter": each delay is
+//! drawn uniformly between `base_backoff` and three times the previous delay,
+//! capped at `max_backoff`. This keeps collectors on many nodes from retrying
+//! in lockstep against a runtime that restarted everywhere at once.
+
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use collector_errors::{Classified, ErrorCode};
+use log::warn;
+use thiserror::Error;
+
+/// Errors that end reconnection
+#[derive(Error, Debug)]
+pub enum ReconnectError {
+    #[error("giving up after {attempts} failed connection attempts: {last_error}")]
+    AttemptsExhausted {
+        attempts: u32,
+        last_error: anyhow::Error,
+    },
+}
+
+impl Classified for ReconnectError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::NriConnect
+    }
+
+    fn retryable(&self) -> bool {
+        // Retrying already gave up
+        false
+    }
+}
+
+/// How to retry connecting to the runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Consecutive failed attempts before giving up, None to retry forever
+    pub max_attempts: Option<u32>,
+    /// Delay before the first retry, and the lower bound of jittered delays
+    pub base_backoff: Duration,
+    /// Upper bound on the delay between attempts
+    pub max_backoff: Duration,
+    /// Randomize delays with decorrelated jitter instead of plain doubling
+    pub jitter: bool,
+    /// How long a connection must last for the delays to start over
+    pub min_uptime: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(10),
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+            min_uptime: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Start a fresh sequence of delays
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(*self, seed())
+    }
+
+    /// Run `connect` until it succeeds, sleeping between failed attempts.
+    ///
+    /// `connect` receives the 1-based attempt number. After `max_attempts`
+    /// failures, returns [`ReconnectError::AttemptsExhausted`] with the last
+    /// error.
+    pub async fn retry<T, F, Fut>(&self, connect: F) -> Result<T, ReconnectError>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        self.retry_with(&mut self.backoff(), connect).await
+    }
+
+    /// Like [`ReconnectPolicy::retry`], continuing the delays of `backoff`
+    pub async fn retry_with<T, F, Fut>(
+        &self,
+        backoff: &mut Backoff,
+        mut connect: F,
+    ) -> Result<T, ReconnectError>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let error = match connect(attempt).await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+
+            if self.max_attempts.is_some_and(|max| attempt >= max) {
+                return Err(ReconnectError::AttemptsExhausted {
+                    attempts: attempt,
+                    last_error: error,
+                });
+            }
+
+            let delay = backoff.next_delay();
+            warn!(
+                "NRI connection attempt {} failed: {}, retrying in {:?}",
+                attempt, error, delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Sequence of delays between reconnection attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    previous: Option<Duration>,
+    rng: SplitMix64,
+}
+
+impl Backoff {
+    fn new(policy: ReconnectPolicy, seed: u64) -> Self {
+        Self {
+            policy,
+            previous: None,
+            rng: SplitMix64(seed),
+        }
+    }
+
+    /// Delay to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.policy.base_backoff;
+        let cap = self.policy.max_backoff.max(base);
+
+        let delay = match self.previous {
+            None => base,
+            Some(previous) if self.policy.jitter => {
+                let upper = previous.saturating_mul(3).min(cap).max(base);
+                self.rng.between(base, upper)
+            }
+            Some(previous) => previous.saturating_mul(2),
+        }
+        .min(cap);
+
+        self.previous = Some(delay);
+        delay
+    }
+
+    /// Delay before reconnecting after a connection that lasted `uptime`.
+    /// A connection of at least `min_uptime` starts the delays over and
+    /// reconnects right away; a shorter one waits the next delay.
+    pub fn after_connection(&mut self, uptime: Duration) -> Option<Duration> {
+        if uptime >= self.policy.min_uptime {
+            self.previous = None;
+            return None;
+        }
+        Some(self.next_delay())
+    }
+}
+
+/// Small PRNG for jitter, which needs spread but not quality
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform duration in `[low, high]`, at nanosecond granularity
+    fn between(&mut self, low: Duration, high: Duration) -> Duration {
+        let span = (high - low).as_nanos() as u64;
+        let offset = match span.checked_add(1) {
+            Some(range) => self.next_u64() % range,
+            None => self.next_u64(),
+        };
+        low + Duration::from_nanos(offset)
+    }
+}
+
+/// Seed that differs between processes and runs, so nodes do not share delays
+fn seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default();
+    nanos ^ (u64::from(std::process::id()) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: bool) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: Some(5),
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            jitter,
+            min_uptime: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn test_exponential_backoff_capped() {
+        let mut backoff = Backoff::new(policy(false), 0);
+        let delays: Vec<u64> = (0..8)
+            .map(|_| backoff.next_delay().as_millis() as u64)
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1600, 2000, 2000, 2000]);
+    }
+
+    #[test]
+    fn test_short_connections_continue_backoff() {
+        let mut backoff = Backoff::new(policy(false), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+
+        // Connections dropped right after registering keep growing the delay
+        let short = Duration::from_millis(10);
+        assert_eq!(
+            backoff.after_connection(short),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(
+            backoff.after_connection(short),
+            Some(Duration::from_millis(400))
+        );
+        assert_eq!(backoff.next_delay(), Duration::from_millis(800));
+
+        // A connection that lasted starts over
+        assert_eq!(backoff.after_connection(Duration::from_secs(1)), None);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_decorrelated_jitter_bounds() {
+        let policy = policy(true);
+        for seed in 0..100 {
+            let mut backoff = Backoff::new(policy, seed);
+            let mut previous = backoff.next_delay();
+            assert_eq!(previous, policy.base_backoff);
+
+            for _ in 0..50 {
+                let delay = backoff.next_delay();
+                assert!(delay >= policy.base_backoff, "{:?} below base", delay);
+                assert!(delay <= policy.max_backoff, "{:?} above cap", delay);
+                assert!(delay <= previous * 3, "{:?} after {:?}", delay, previous);
+                previous = delay;
+            }
+        }
+    }
+
+    #[test]
+    fn test_jitter_spreads_delays() {
+        // Different seeds, as on different nodes, produce different delays
+        let delays: std::collections::HashSet<Duration> = (0..20)
+            .map(|seed| {
+                let mut backoff = Backoff::new(policy(true), seed);
+                backoff.next_delay();
+                backoff.next_delay()
+            })
+            .collect();
+        assert!(delays.len() > 10);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up_after_max_attempts() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(3),
+            base_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            jitter: true,
+            min_uptime: Duration::from_secs(1),
+        };
+
+        let mut attempts = Vec::new();
+        let result: Result<(), _> = policy
+            .retry(|attempt| {
+                attempts.push(attempt);
+                async move { Err(anyhow::anyhow!("runtime gone ({})", attempt)) }
+            })
+            .await;
+
+        assert_eq!(attempts, vec![1, 2, 3]);
+        let err = result.as_ref().unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NriConnect);
+        assert!(!err.retryable());
+        match result {
+            Err(ReconnectError::AttemptsExhausted {
+                attempts,
+                last_error,
+            }) => {
+                assert_eq!(attempts, 3);
+                assert_eq!(last_error.to_string(), "runtime gone (3)");
+            }
+            other => panic!("expected AttemptsExhausted, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_returns_first_success() {
+        let policy = ReconnectPolicy {
+            base_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+            ..Default::default()
+        };
+
+        let result = policy
+            .retry(|attempt| async move {
+                if attempt < 3 {
+                    Err(anyhow::anyhow!("not yet"))
+                } else {
+                    Ok(attempt)
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+}
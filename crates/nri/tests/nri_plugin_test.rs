@@ -1,4 +1,5 @@
 use anyhow::Result;
+use nri::adjustment::{AdjustmentBuilder, ContainerUpdateBuilder, UpdateBuilder};
 use nri::api::{
     ConfigureRequest, ConfigureResponse, CreateContainerRequest, CreateContainerResponse, Empty,
     Event, StateChangeEvent, StopContainerRequest, StopContainerResponse, SynchronizeRequest,
@@ -7,9 +8,10 @@ use nri::api::{
 };
 use nri::api_ttrpc::{Plugin, Runtime};
 use nri::events_mask::EventMask;
+use nri::metadata::{MetadataMessage, OverflowPolicy};
 use nri::multiplex::{Mux, RUNTIME_SERVICE_CONN};
-use nri::NRI;
-use protobuf::SpecialFields;
+use nri::{RegistrationState, NRI};
+use protobuf::{Message, SpecialFields};
 use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
 use tokio::sync::Mutex;
@@ -18,6 +20,31 @@ use ttrpc::context::Context;
 use ttrpc::r#async::transport::Socket;
 use ttrpc::r#async::TtrpcContext;
 
+// Adjustment returned by CounterPlugin for every created container
+fn expected_create_response() -> CreateContainerResponse {
+    AdjustmentBuilder::new()
+        .add_env("COUNTER_PLUGIN", "1")
+        .add_annotation("counter-plugin/adjusted", "true")
+        .add_bind_mount("/var/lib/counter", "/counter", true)
+        .set_cpu_shares(512)
+        .set_memory_limit(256 << 20)
+        .build()
+        .expect("valid adjustment")
+}
+
+// Update returned by CounterPlugin for every updated container
+fn expected_update_response() -> UpdateContainerResponse {
+    UpdateBuilder::new()
+        .update(
+            ContainerUpdateBuilder::new("counter-container")
+                .set_cpuset_cpus("0-1")
+                .ignore_failure(true),
+        )
+        .evict("noisy-container", "counted too much")
+        .build()
+        .expect("valid update")
+}
+
 // A simple example plugin that counts method calls
 struct CounterPlugin {
     configure_count: Arc<StdMutex<i32>>,
@@ -123,7 +150,7 @@ impl Plugin for CounterPlugin {
             *count += 1;
         }
 
-        Ok(CreateContainerResponse::default())
+        Ok(expected_create_response())
     }
 
     async fn update_container(
@@ -137,7 +164,7 @@ impl Plugin for CounterPlugin {
             *count += 1;
         }
 
-        Ok(UpdateContainerResponse::default())
+        Ok(expected_update_response())
     }
 
     async fn stop_container(
@@ -188,6 +215,9 @@ impl Plugin for CounterPlugin {
 struct MockRuntimeService {
     // For tracking registration
     register_called: Arc<Mutex<bool>>,
+    register_count: Arc<StdMutex<usize>>,
+    // How long register_plugin takes to answer
+    register_delay: Duration,
     plugin_name: Arc<Mutex<String>>,
     plugin_idx: Arc<Mutex<String>>,
     plugin_client: Option<nri::api_ttrpc::PluginClient>,
@@ -197,6 +227,8 @@ impl MockRuntimeService {
     fn new() -> Self {
         Self {
             register_called: Arc::new(Mutex::new(false)),
+            register_count: Arc::new(StdMutex::new(0)),
+            register_delay: Duration::ZERO,
             plugin_name: Arc::new(Mutex::new(String::new())),
             plugin_idx: Arc::new(Mutex::new(String::new())),
             plugin_client: None,
@@ -267,6 +299,17 @@ impl MockRuntimeService {
         }
     }
 
+    async fn call_shutdown(&self) -> Result<Empty> {
+        if let Some(client) = &self.plugin_client {
+            let resp = client
+                .shutdown(Context::default(), &Empty::default())
+                .await?;
+            Ok(resp)
+        } else {
+            Err(anyhow::anyhow!("Plugin client not set"))
+        }
+    }
+
     async fn call_state_change(&self) -> Result<Empty> {
         if let Some(client) = &self.plugin_client {
             let req = StateChangeEvent::default();
@@ -285,6 +328,9 @@ impl Runtime for MockRuntimeService {
         _ctx: &TtrpcContext,
         req: nri::api::RegisterPluginRequest,
     ) -> ttrpc::Result<Empty> {
+        *self.register_count.lock().unwrap() += 1;
+        tokio::time::sleep(self.register_delay).await;
+
         // Record that the register function was called
         let mut register_called = self.register_called.lock().await;
         *register_called = true;
@@ -323,6 +369,22 @@ async fn test_nri_creation() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_nri_builder_creation() -> Result<()> {
+    // Create a duplex pipe for communication
+    let (_runtime_stream, plugin_stream) = tokio::io::duplex(1024);
+
+    // Create an NRI instance with the metadata plugin and its channel
+    let (_nri, _join_handle, metadata_rx) = NRI::builder("metadata-plugin", "5")
+        .channel_capacity(16)
+        .overflow_policy(OverflowPolicy::BlockWithTimeout(Duration::from_millis(100)))
+        .build(plugin_stream)
+        .await?;
+    assert_eq!(metadata_rx.max_capacity(), 16);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_counter_plugin_with_nri() -> Result<()> {
     // Create a duplex pipe for communication
@@ -448,21 +510,44 @@ async fn test_counter_plugin_with_nri() -> Result<()> {
     );
 
     // Call create_container and verify the count increased
-    runtime_service.call_create_container().await?;
+    let create_response = runtime_service.call_create_container().await?;
     assert_eq!(
         *create_container_count.lock().unwrap(),
         1,
         "create_container should have been called once"
     );
 
+    // Verify the adjustment arrived intact
+    let expected = expected_create_response();
+    assert_eq!(create_response, expected);
+    assert_eq!(
+        create_response.write_to_bytes()?,
+        expected.write_to_bytes()?
+    );
+    assert_eq!(create_response.adjust.env[0].key, "COUNTER_PLUGIN");
+    assert_eq!(
+        create_response.adjust.linux.resources.memory.limit.value,
+        256 << 20
+    );
+
     // Call update_container and verify the count increased
-    runtime_service.call_update_container().await?;
+    let update_response = runtime_service.call_update_container().await?;
     assert_eq!(
         *update_container_count.lock().unwrap(),
         1,
         "update_container should have been called once"
     );
 
+    // Verify the update arrived intact
+    let expected = expected_update_response();
+    assert_eq!(update_response, expected);
+    assert_eq!(
+        update_response.write_to_bytes()?,
+        expected.write_to_bytes()?
+    );
+    assert_eq!(update_response.update[0].container_id, "counter-container");
+    assert_eq!(update_response.evict[0].reason, "counted too much");
+
     // Call stop_container and verify the count increased
     runtime_service.call_stop_container().await?;
     assert_eq!(
@@ -499,6 +584,184 @@ async fn test_counter_plugin_with_nri() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_metadata_plugin_narrowed_mask() -> Result<()> {
+    let (runtime_stream, plugin_stream) = tokio::io::duplex(1024);
+
+    // Widen the default subscription to container updates
+    let mut events = EventMask::new();
+    events.set(&[
+        Event::CREATE_CONTAINER,
+        Event::UPDATE_CONTAINER,
+        Event::STOP_CONTAINER,
+    ]);
+    let (plugin, mut metadata_rx) = NRI::builder("metadata-plugin", "10")
+        .event_mask(events)
+        .metadata_plugin();
+    let (nri, mut join_handle) =
+        NRI::new(plugin_stream, plugin.clone(), "metadata-plugin", "10").await?;
+
+    // Connect the mock runtime to the plugin service
+    let runtime_mux = Mux::new(runtime_stream);
+    let mut runtime_service = MockRuntimeService::new();
+    let plugin_client = {
+        let plugin_socket = runtime_mux
+            .open(nri::multiplex::PLUGIN_SERVICE_CONN)
+            .await?;
+        let client = ttrpc::r#async::Client::new(Socket::new(plugin_socket));
+        nri::api_ttrpc::PluginClient::new(client)
+    };
+    runtime_service.set_plugin_client(plugin_client).await;
+
+    let configured = EventMask::from_raw(runtime_service.call_configure().await?.events);
+    assert_eq!(configured, events);
+
+    // Subscribed events produce metadata messages
+    runtime_service.call_create_container().await?;
+    assert!(matches!(
+        metadata_rx.try_recv(),
+        Ok(MetadataMessage::Add(_, _))
+    ));
+    runtime_service.call_update_container().await?;
+    assert!(matches!(
+        metadata_rx.try_recv(),
+        Ok(MetadataMessage::Add(_, _))
+    ));
+
+    // Narrow the subscription to stop events
+    let mut narrowed = events;
+    narrowed.clear(&[Event::CREATE_CONTAINER, Event::UPDATE_CONTAINER]);
+    let diff = plugin.set_event_mask(narrowed);
+    assert!(diff.removed.is_set(Event::CREATE_CONTAINER));
+    assert!(diff.removed.is_set(Event::UPDATE_CONTAINER));
+    assert_eq!(diff.added, EventMask::new());
+
+    // Events the runtime still sends are ignored
+    runtime_service.call_create_container().await?;
+    runtime_service.call_update_container().await?;
+    assert!(metadata_rx.try_recv().is_err());
+
+    // Events in the narrowed mask still produce messages
+    runtime_service.call_stop_container().await?;
+    assert!(matches!(
+        metadata_rx.try_recv(),
+        Ok(MetadataMessage::Remove(_))
+    ));
+
+    // Configuring again reports the narrowed mask
+    let configured = EventMask::from_raw(runtime_service.call_configure().await?.events);
+    assert_eq!(configured, narrowed);
+    assert!(metadata_rx.try_recv().is_err());
+
+    nri.close().await?;
+    let _ = timeout(Duration::from_secs(1), &mut join_handle).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metadata_plugin_shutdown_notice() -> Result<()> {
+    let (runtime_stream, plugin_stream) = tokio::io::duplex(1024);
+
+    // A channel of one message, which the create event fills
+    let (notice_tx, mut notice_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (plugin, mut metadata_rx) = NRI::builder("metadata-plugin", "10")
+        .channel_capacity(1)
+        .shutdown_notifier(notice_tx)
+        .metadata_plugin();
+    let (nri, mut join_handle) =
+        NRI::new(plugin_stream, plugin.clone(), "metadata-plugin", "10").await?;
+
+    let runtime_mux = Mux::new(runtime_stream);
+    let mut runtime_service = MockRuntimeService::new();
+    let plugin_client = {
+        let plugin_socket = runtime_mux
+            .open(nri::multiplex::PLUGIN_SERVICE_CONN)
+            .await?;
+        let client = ttrpc::r#async::Client::new(Socket::new(plugin_socket));
+        nri::api_ttrpc::PluginClient::new(client)
+    };
+    runtime_service.set_plugin_client(plugin_client).await;
+    runtime_service.call_configure().await?;
+    runtime_service.call_create_container().await?;
+    assert!(notice_rx.try_recv().is_err());
+
+    // The runtime shuts the plugin down: the notice arrives right away,
+    // while the terminal message is dropped on the full channel
+    runtime_service.call_shutdown().await?;
+    let notice = timeout(Duration::from_secs(1), notice_rx.recv())
+        .await?
+        .expect("shutdown notice");
+    assert!(notice.received_at <= std::time::SystemTime::now());
+    assert_eq!(plugin.dropped_messages(), 1);
+    assert!(matches!(
+        metadata_rx.try_recv(),
+        Ok(MetadataMessage::Add(_, _))
+    ));
+    assert!(metadata_rx.try_recv().is_err());
+
+    // With room in the channel, both arrive
+    runtime_service.call_shutdown().await?;
+    assert!(notice_rx.try_recv().is_ok());
+    assert!(matches!(
+        metadata_rx.try_recv(),
+        Ok(MetadataMessage::Shutdown)
+    ));
+
+    nri.close().await?;
+    let _ = timeout(Duration::from_secs(1), &mut join_handle).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_try_register_slow_runtime() -> Result<()> {
+    let (runtime_stream, plugin_stream) = tokio::io::duplex(1024);
+
+    // A runtime that takes 300ms to answer registration
+    let runtime_mux = Mux::new(runtime_stream);
+    let runtime_socket = Socket::new(runtime_mux.open(RUNTIME_SERVICE_CONN).await?);
+    let mut runtime_service = MockRuntimeService::new();
+    runtime_service.register_delay = Duration::from_millis(300);
+    let service_map = nri::api_ttrpc::create_runtime(Arc::new(runtime_service.clone()));
+    let mut runtime_server = ttrpc::r#async::Server::new().register_service(service_map);
+    let server_handle =
+        tokio::spawn(async move { runtime_server.start_connected(runtime_socket).await });
+
+    let (nri, mut join_handle) =
+        NRI::new(plugin_stream, CounterPlugin::new(), "counter-plugin", "10").await?;
+
+    // The attempt returns pending instead of waiting for the answer
+    let started = std::time::Instant::now();
+    assert_eq!(nri.try_register().await?, RegistrationState::Pending);
+    assert!(started.elapsed() < Duration::from_millis(200));
+
+    // Later attempts check on the same request until the runtime answers
+    let registered = timeout(Duration::from_secs(2), async {
+        loop {
+            match nri.try_register().await? {
+                RegistrationState::Pending => tokio::time::sleep(Duration::from_millis(20)).await,
+                state => return Ok::<_, anyhow::Error>(state),
+            }
+        }
+    })
+    .await??;
+    assert_eq!(registered, RegistrationState::Registered);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(*runtime_service.register_count.lock().unwrap(), 1);
+    assert_eq!(*runtime_service.plugin_name.lock().await, "counter-plugin");
+
+    // Once registered, attempts return immediately without another request
+    assert_eq!(nri.try_register().await?, RegistrationState::Registered);
+    assert_eq!(*runtime_service.register_count.lock().unwrap(), 1);
+
+    nri.close().await?;
+    let _ = timeout(Duration::from_secs(1), &mut join_handle).await??;
+    server_handle.abort();
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_nri_connection_error_handling() -> Result<()> {
     // Create a duplex pipe for communication
@@ -538,3 +801,134 @@ async fn test_nri_connection_error_handling() -> Result<()> {
 
     Ok(())
 }
+
+// Runtime listening on a unix socket that drops every plugin right after it
+// registers, recording when each connection was accepted
+async fn flapping_runtime(
+    listener: tokio::net::UnixListener,
+    accepted: Arc<StdMutex<Vec<std::time::Instant>>>,
+) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        accepted.lock().unwrap().push(std::time::Instant::now());
+
+        let runtime_mux = Mux::with_config(stream, nri::multiplex::MuxConfig::nri());
+        let runtime_socket = Socket::new(runtime_mux.open(RUNTIME_SERVICE_CONN).await?);
+        let runtime_service = MockRuntimeService::new();
+        let service_map = nri::api_ttrpc::create_runtime(Arc::new(runtime_service.clone()));
+        let mut runtime_server = ttrpc::r#async::Server::new().register_service(service_map);
+        let server_handle =
+            tokio::spawn(async move { runtime_server.start_connected(runtime_socket).await });
+
+        while *runtime_service.register_count.lock().unwrap() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        server_handle.abort();
+        runtime_mux.shutdown().await?;
+    }
+}
+
+#[tokio::test]
+async fn test_run_backs_off_from_flapping_runtime() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("nri_flapping_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let socket_path = dir.join("nri.sock");
+    let listener = tokio::net::UnixListener::bind(&socket_path)?;
+    let accepted = Arc::new(StdMutex::new(Vec::new()));
+    let runtime_handle = tokio::spawn(flapping_runtime(listener, accepted.clone()));
+
+    let (join_handle, _metadata_rx, _subscription) = NRI::builder("metadata-plugin", "10")
+        .reconnect_policy(nri::reconnect::ReconnectPolicy {
+            max_attempts: Some(3),
+            base_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_secs(1),
+            jitter: false,
+            min_uptime: Duration::from_secs(10),
+        })
+        .run(&socket_path);
+
+    timeout(Duration::from_secs(5), async {
+        while accepted.lock().unwrap().len() < 4 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await?;
+    join_handle.abort();
+    runtime_handle.abort();
+
+    // Every connection registered, yet the delays between them kept
+    // doubling instead of starting over
+    let accepted = accepted.lock().unwrap().clone();
+    for (index, pair) in accepted.windows(2).take(3).enumerate() {
+        let delay = Duration::from_millis(20 << index);
+        assert!(
+            pair[1] - pair[0] >= delay,
+            "connection {} after {:?}, expected at least {:?}",
+            index + 1,
+            pair[1] - pair[0],
+            delay
+        );
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+    Ok(())
+}
+
+// Runtime listening on a unix socket that keeps every plugin connected,
+// counting the connections accepted
+async fn steady_runtime(
+    listener: tokio::net::UnixListener,
+    accepted: Arc<StdMutex<usize>>,
+) -> Result<()> {
+    let mut connections = Vec::new();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        *accepted.lock().unwrap() += 1;
+
+        let runtime_mux = Mux::with_config(stream, nri::multiplex::MuxConfig::nri());
+        let runtime_socket = Socket::new(runtime_mux.open(RUNTIME_SERVICE_CONN).await?);
+        let service_map = nri::api_ttrpc::create_runtime(Arc::new(MockRuntimeService::new()));
+        let mut runtime_server = ttrpc::r#async::Server::new().register_service(service_map);
+        let server_handle =
+            tokio::spawn(async move { runtime_server.start_connected(runtime_socket).await });
+        connections.push((runtime_mux, server_handle));
+    }
+}
+
+#[tokio::test]
+async fn test_reconnect_requested_while_connecting() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("nri_reconnect_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let socket_path = dir.join("nri.sock");
+    let listener = tokio::net::UnixListener::bind(&socket_path)?;
+    let accepted = Arc::new(StdMutex::new(0));
+    let runtime_handle = tokio::spawn(steady_runtime(listener, accepted.clone()));
+
+    let (join_handle, _metadata_rx, subscription) = NRI::builder("metadata-plugin", "10")
+        .reconnect_policy(nri::reconnect::ReconnectPolicy {
+            min_uptime: Duration::ZERO,
+            ..Default::default()
+        })
+        .run(&socket_path);
+
+    // Requested before the plugin connected, while nothing waits for it
+    subscription.reconnect();
+
+    timeout(Duration::from_secs(5), async {
+        while *accepted.lock().unwrap() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await?;
+
+    // The request caused a single reconnection
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(*accepted.lock().unwrap(), 2);
+
+    join_handle.abort();
+    runtime_handle.abort();
+    let _ = std::fs::remove_dir_all(&dir);
+    Ok(())
+}
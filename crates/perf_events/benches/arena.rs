@@ -0,0 +1,81 @@
+//! Compares per-event heap allocations in subscribers with allocating from
+//! the dispatcher's arena.
+//!
+//! Run with `cargo bench -p perf_events`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use perf_events::{sample_payload, Dispatcher, PerfRing, Reader, PERF_RECORD_SAMPLE};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const PAGE_SIZE: u64 = 4096;
+const N_PAGES: u32 = 64;
+const EVENTS_PER_BATCH: usize = 4096;
+const MSG_TYPE: u32 = 1;
+
+/// A message type and timestamp followed by a NUL-padded comm
+fn message(i: u64) -> Vec<u8> {
+    let mut body = [0u8; 16];
+    let comm = format!("worker/{}", i % 64);
+    body[..comm.len()].copy_from_slice(comm.as_bytes());
+    sample_payload(MSG_TYPE, i, &body)
+}
+
+/// The comm of a message, as it would be before appending it to a column
+fn comm(data: &[u8]) -> &str {
+    std::str::from_utf8(&data[16..32])
+        .unwrap_or("<invalid utf8>")
+        .trim_end_matches(char::from(0))
+}
+
+fn run(c: &mut Criterion, name: &str, subscribe: fn(&mut Dispatcher, Rc<Cell<usize>>)) {
+    let mut buffer = vec![0u8; (PAGE_SIZE * (1 + u64::from(N_PAGES))) as usize];
+    let mut writer = unsafe { PerfRing::init_contiguous(&mut buffer, N_PAGES, PAGE_SIZE).unwrap() };
+    let mut reader = Reader::new();
+    reader
+        .add_ring(unsafe { PerfRing::init_contiguous(&mut buffer, N_PAGES, PAGE_SIZE).unwrap() })
+        .unwrap();
+
+    let total = Rc::new(Cell::new(0));
+    let mut dispatcher = Dispatcher::new();
+    subscribe(&mut dispatcher, total.clone());
+
+    let events: Vec<Vec<u8>> = (0..EVENTS_PER_BATCH as u64).map(message).collect();
+    c.bench_function(name, |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                writer.start_write_batch();
+                for event in &events {
+                    writer.write(event, PERF_RECORD_SAMPLE).unwrap();
+                }
+                writer.finish_write_batch();
+
+                let start = Instant::now();
+                dispatcher.poll_once(&mut reader).unwrap();
+                elapsed += start.elapsed();
+            }
+            std::hint::black_box(total.get());
+            elapsed
+        });
+    });
+}
+
+fn bench_arena(c: &mut Criterion) {
+    run(c, "dispatch_comm/string", |dispatcher, total| {
+        dispatcher.subscribe(MSG_TYPE, move |_, data| {
+            let name = comm(data).to_string();
+            total.set(total.get() + name.len());
+        });
+    });
+    run(c, "dispatch_comm/arena", |dispatcher, total| {
+        dispatcher.subscribe_with_arena(MSG_TYPE, move |_, data, arena| {
+            let name = arena.alloc_str(comm(data));
+            total.set(total.get() + name.len());
+        });
+    });
+}
+
+criterion_group!(benches, bench_arena);
+criterion_main!(benches);
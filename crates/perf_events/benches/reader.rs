@@ -0,0 +1,103 @@
+//! Compares the reader's ordering strategies.
+//!
+//! Run with `cargo bench -p perf_events`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use perf_events::{write_lost, write_sample, PerfRing, Reader, ReaderOrdering};
+use std::time::{Duration, Instant};
+
+const PAGE_SIZE: u64 = 4096;
+const N_PAGES: u32 = 16;
+const EVENTS_PER_RING: usize = 256;
+
+/// Rings with their writer views, backed by heap buffers
+struct Rings {
+    _buffers: Vec<Vec<u8>>,
+    writers: Vec<PerfRing>,
+    reader: Reader,
+}
+
+impl Rings {
+    fn new(n_rings: usize, ordering: ReaderOrdering) -> Self {
+        let mut buffers: Vec<Vec<u8>> = (0..n_rings)
+            .map(|_| vec![0u8; (PAGE_SIZE * (1 + u64::from(N_PAGES))) as usize])
+            .collect();
+        let mut reader = Reader::with_ordering(ordering);
+        let mut writers = Vec::with_capacity(n_rings);
+        for buffer in buffers.iter_mut() {
+            reader
+                .add_ring(unsafe { PerfRing::init_contiguous(buffer, N_PAGES, PAGE_SIZE).unwrap() })
+                .unwrap();
+            writers.push(unsafe { PerfRing::init_contiguous(buffer, N_PAGES, PAGE_SIZE).unwrap() });
+        }
+        Rings {
+            _buffers: buffers,
+            writers,
+            reader,
+        }
+    }
+
+    /// Fills every ring with interleaved timestamps, like per-CPU samples
+    /// taken at slightly different rates, with an occasional lost record
+    fn fill(&mut self, base: u64) {
+        let n_rings = self.writers.len() as u64;
+        for (cpu, writer) in self.writers.iter_mut().enumerate() {
+            let cpu = cpu as u64;
+            writer.start_write_batch();
+            for i in 0..EVENTS_PER_RING as u64 {
+                let timestamp = base + i * (1000 + cpu % 7) + cpu * 1000 / n_rings;
+                let phase = (i + cpu) % 97;
+                if phase == 0 {
+                    write_lost(writer, 0, 1).unwrap();
+                } else {
+                    write_sample(writer, 0, timestamp, &[0u8; 16]).unwrap();
+                }
+            }
+            writer.finish_write_batch();
+        }
+    }
+
+    /// Reads all events, returning the number read
+    fn drain(&mut self) -> usize {
+        let mut count = 0;
+        self.reader.start().unwrap();
+        while !self.reader.is_empty() {
+            let (ring, _) = self.reader.current_ring().unwrap();
+            std::hint::black_box(ring.peek_type());
+            self.reader.pop().unwrap();
+            count += 1;
+        }
+        self.reader.finish().unwrap();
+        count
+    }
+}
+
+fn bench_reader(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reader_drain");
+    for n_rings in [4, 64, 256] {
+        for (name, ordering) in [
+            ("heap", ReaderOrdering::Heap),
+            ("tournament", ReaderOrdering::Tournament),
+        ] {
+            group.bench_with_input(BenchmarkId::new(name, n_rings), &n_rings, |b, &n| {
+                let mut rings = Rings::new(n, ordering);
+                let mut base = 0;
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        rings.fill(base);
+                        base += 1_000_000_000;
+                        let start = Instant::now();
+                        std::hint::black_box(rings.drain());
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_reader);
+criterion_main!(benches);
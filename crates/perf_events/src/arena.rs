@@ -0,0 +1,259 @@
+//! Bump arena for temporary allocations made while dispatching a batch.
+//!
+//! Subscribers often need short-lived buffers per event, e.g. to convert a
+//! task's `comm` into a string before appending it to a column. Allocating
+//! these from an [`Arena`] costs a pointer bump, and all of them are released
+//! at once when the dispatcher resets the arena at the start of the next batch.
+//!
+//! Allocations borrow the arena, and resetting it requires `&mut Arena`, so
+//! nothing allocated from the arena can be used after a reset:
+//!
+//! ```compile_fail
+//! use perf_events::Arena;
+//!
+//! let mut arena = Arena::new();
+//! let name = arena.alloc_str("bash");
+//! arena.reset();
+//! println!("{}", name);
+//! ```
+//!
+//! Only `Copy` values can be allocated, since the arena never runs destructors.
+
+use std::alloc::{self, Layout};
+use std::cell::UnsafeCell;
+use std::ptr::{self, NonNull};
+
+/// Default size of each chunk of arena memory
+pub const DEFAULT_ARENA_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Alignment of every chunk; larger alignments are padded within the chunk
+const CHUNK_ALIGN: usize = 16;
+
+/// A block of memory allocations are bumped out of
+struct Chunk {
+    ptr: NonNull<u8>,
+    size: usize,
+}
+
+/// Bookkeeping for the chunks, mutated through `&Arena`
+struct Chunks {
+    /// Chunks kept across resets, in fill order
+    chunks: Vec<Chunk>,
+    /// Index of the chunk currently being filled
+    current: usize,
+    /// Bytes used in the current chunk
+    offset: usize,
+    /// Bytes handed out since the last reset, including alignment padding
+    allocated: usize,
+}
+
+/// A bump allocator for values that live until the end of a dispatch batch
+pub struct Arena {
+    chunk_size: usize,
+    inner: UnsafeCell<Chunks>,
+}
+
+impl Arena {
+    /// Creates an empty arena with [`DEFAULT_ARENA_CHUNK_SIZE`] chunks
+    pub fn new() -> Self {
+        Self::with_chunk_size(DEFAULT_ARENA_CHUNK_SIZE)
+    }
+
+    /// Creates an empty arena allocating memory in chunks of `chunk_size`
+    /// bytes. Allocations larger than a chunk get a chunk of their own.
+    pub fn with_chunk_size(chunk_size: usize) -> Self {
+        Arena {
+            chunk_size: chunk_size.max(CHUNK_ALIGN),
+            inner: UnsafeCell::new(Chunks {
+                chunks: Vec::new(),
+                current: 0,
+                offset: 0,
+                allocated: 0,
+            }),
+        }
+    }
+
+    /// Bytes allocated since the last reset
+    pub fn allocated_bytes(&self) -> usize {
+        unsafe { (*self.inner.get()).allocated }
+    }
+
+    /// Total bytes of memory held by the arena
+    pub fn capacity(&self) -> usize {
+        unsafe { (*self.inner.get()).chunks.iter().map(|c| c.size).sum() }
+    }
+
+    /// Releases all allocations, keeping the memory for reuse
+    pub fn reset(&mut self) {
+        let inner = self.inner.get_mut();
+        inner.current = 0;
+        inner.offset = 0;
+        inner.allocated = 0;
+    }
+
+    /// Moves `value` into the arena
+    pub fn alloc<T: Copy>(&self, value: T) -> &T {
+        let ptr = self.alloc_layout(Layout::new::<T>()).cast::<T>();
+        unsafe {
+            ptr::write(ptr.as_ptr(), value);
+            &*ptr.as_ptr()
+        }
+    }
+
+    /// Copies `src` into the arena
+    pub fn alloc_slice_copy<T: Copy>(&self, src: &[T]) -> &[T] {
+        let layout = Layout::array::<T>(src.len()).expect("arena allocation too large");
+        let ptr = self.alloc_layout(layout).cast::<T>();
+        unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr(), ptr.as_ptr(), src.len());
+            std::slice::from_raw_parts(ptr.as_ptr(), src.len())
+        }
+    }
+
+    /// Allocates a slice of `len` values, the i-th set to `f(i)`
+    pub fn alloc_slice_fill_with<T: Copy, F>(&self, len: usize, mut f: F) -> &[T]
+    where
+        F: FnMut(usize) -> T,
+    {
+        let layout = Layout::array::<T>(len).expect("arena allocation too large");
+        let ptr = self.alloc_layout(layout).cast::<T>();
+        unsafe {
+            for i in 0..len {
+                ptr::write(ptr.as_ptr().add(i), f(i));
+            }
+            std::slice::from_raw_parts(ptr.as_ptr(), len)
+        }
+    }
+
+    /// Copies `src` into the arena
+    pub fn alloc_str(&self, src: &str) -> &str {
+        let bytes = self.alloc_slice_copy(src.as_bytes());
+        unsafe { std::str::from_utf8_unchecked(bytes) }
+    }
+
+    /// Returns memory for `layout` that stays valid until the next reset
+    fn alloc_layout(&self, layout: Layout) -> NonNull<u8> {
+        // Safety: the bookkeeping is only touched here and in `&mut self`
+        // methods, and the returned regions never overlap
+        let inner = unsafe { &mut *self.inner.get() };
+
+        if layout.size() == 0 {
+            return unsafe { NonNull::new_unchecked(layout.align() as *mut u8) };
+        }
+
+        loop {
+            if let Some(chunk) = inner.chunks.get(inner.current) {
+                let base = chunk.ptr.as_ptr() as usize;
+                let start = (base + inner.offset).next_multiple_of(layout.align()) - base;
+                if start + layout.size() <= chunk.size {
+                    inner.allocated += start + layout.size() - inner.offset;
+                    inner.offset = start + layout.size();
+                    return unsafe { NonNull::new_unchecked(chunk.ptr.as_ptr().add(start)) };
+                }
+            }
+
+            // Move on to the next chunk, allocating one if the next chunk kept
+            // from earlier batches is missing or too small
+            let needed = layout.size() + layout.align().saturating_sub(CHUNK_ALIGN);
+            let next = if inner.chunks.is_empty() {
+                0
+            } else {
+                inner.current + 1
+            };
+            if inner
+                .chunks
+                .get(next)
+                .is_none_or(|chunk| chunk.size < needed)
+            {
+                let size = needed.next_multiple_of(CHUNK_ALIGN).max(self.chunk_size);
+                let chunk_layout =
+                    Layout::from_size_align(size, CHUNK_ALIGN).expect("arena allocation too large");
+                let ptr = unsafe { alloc::alloc(chunk_layout) };
+                let ptr =
+                    NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(chunk_layout));
+                inner.chunks.insert(next, Chunk { ptr, size });
+            }
+            inner.current = next;
+            inner.offset = 0;
+        }
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for chunk in self.inner.get_mut().chunks.drain(..) {
+            unsafe {
+                alloc::dealloc(
+                    chunk.ptr.as_ptr(),
+                    Layout::from_size_align_unchecked(chunk.size, CHUNK_ALIGN),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values() {
+        let arena = Arena::with_chunk_size(64);
+
+        let name = arena.alloc_str("kworker/0:1");
+        let counts = arena.alloc_slice_copy(&[1u64, 2, 3]);
+        let flag = arena.alloc(true);
+        let squares = arena.alloc_slice_fill_with(5, |i| (i * i) as u32);
+
+        assert_eq!(name, "kworker/0:1");
+        assert_eq!(counts, &[1, 2, 3]);
+        assert!(*flag);
+        assert_eq!(squares, &[0, 1, 4, 9, 16]);
+        assert_eq!(counts.as_ptr() as usize % align_of::<u64>(), 0);
+        assert_eq!(arena.alloc_str(""), "");
+    }
+
+    #[test]
+    fn test_spans_chunks() {
+        let arena = Arena::with_chunk_size(64);
+
+        // Many small allocations and one larger than a chunk
+        let small: Vec<&[u8]> = (0..40u8)
+            .map(|i| arena.alloc_slice_fill_with(7, |_| i))
+            .collect();
+        let large = arena.alloc_slice_fill_with(1000, |_| 0xabu8);
+
+        for (i, slice) in small.iter().enumerate() {
+            assert!(slice.iter().all(|&b| b == i as u8));
+        }
+        assert!(large.iter().all(|&b| b == 0xab));
+        assert!(arena.capacity() >= 40 * 7 + 1000);
+    }
+
+    #[test]
+    fn test_reset_reuses_memory() {
+        let mut arena = Arena::with_chunk_size(128);
+
+        for batch in 0..10u32 {
+            for i in 0..50u32 {
+                let value = arena.alloc(batch * 100 + i);
+                assert_eq!(*value, batch * 100 + i);
+            }
+            assert_eq!(arena.allocated_bytes(), 50 * 4);
+            let capacity = arena.capacity();
+
+            arena.reset();
+            assert_eq!(arena.allocated_bytes(), 0);
+            assert_eq!(arena.capacity(), capacity);
+        }
+
+        // Later batches fit in the chunks allocated by the first
+        assert_eq!(arena.capacity(), 256);
+    }
+}
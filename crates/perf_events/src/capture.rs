@@ -0,0 +1,247 @@
+use collector_errors::{Classified, ErrorCode};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Magic bytes at the start of every capture file
+pub const CAPTURE_MAGIC: &[u8; 8] = b"PECAPT01";
+
+/// Size of the header before each record: ring index and data length
+pub const CAPTURE_RECORD_HEADER_SIZE: usize = 8;
+
+/// Errors that can occur when parsing a capture file
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CaptureError {
+    #[error("not a capture file")]
+    BadMagic,
+
+    #[error("capture file truncated at byte {0}")]
+    Truncated(usize),
+}
+
+impl Classified for CaptureError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Internal
+    }
+}
+
+/// Predicate selecting the messages a capture keeps
+pub type CaptureFilter = Box<dyn Fn(&[u8]) -> bool>;
+
+/// Rewrites a message in place before a capture writes it
+pub type CaptureRewrite = Box<dyn Fn(&mut [u8])>;
+
+/// What [`crate::Dispatcher::enable_capture`] writes to a file, and when it
+/// stops
+pub struct CaptureSpec {
+    /// Message types to capture, or every type if empty
+    pub msg_types: Vec<u32>,
+
+    /// Stop after this many messages
+    pub max_events: u64,
+
+    /// Stop before the records written would exceed this many bytes,
+    /// counting their headers but not the file's magic
+    pub max_bytes: u64,
+
+    /// Captures only messages for which this returns true, such as those
+    /// of a single pid. Receives the message as subscribers do.
+    pub pid_filter: Option<CaptureFilter>,
+
+    /// Applied to a copy of each selected message before it is written,
+    /// such as to redact fields. Subscribers still receive the original.
+    pub rewrite: Option<CaptureRewrite>,
+
+    /// File to write, truncated if it exists
+    pub path: PathBuf,
+}
+
+impl CaptureSpec {
+    /// Creates a spec capturing every message type to `path`, up to 10000
+    /// messages or 64 MiB
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            msg_types: Vec::new(),
+            max_events: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+            pid_filter: None,
+            rewrite: None,
+            path: path.into(),
+        }
+    }
+}
+
+/// Whether a capture is still writing, and why it stopped otherwise
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureState {
+    Active,
+    /// Stopped after `max_events` messages
+    EventLimit,
+    /// Stopped because the next message would exceed `max_bytes`
+    ByteLimit,
+    /// Stopped by [`crate::Dispatcher::disable_capture`]
+    Disabled,
+    /// Stopped after failing to write the file
+    Failed(String),
+}
+
+/// Progress of a capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureStatus {
+    pub path: PathBuf,
+    pub state: CaptureState,
+    /// Messages written
+    pub events: u64,
+    /// Bytes written, excluding the file's magic
+    pub bytes: u64,
+}
+
+/// A message read back from a capture file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedRecord {
+    pub ring_index: usize,
+    /// The message as subscribers received it, starting with its
+    /// `SampleHeader`
+    pub data: Vec<u8>,
+}
+
+/// Writes the messages selected by a [`CaptureSpec`] to its file.
+///
+/// Each record is the ring index and data length as little-endian u32s,
+/// followed by the data.
+pub(crate) struct Capture {
+    spec: CaptureSpec,
+    // None once the capture stopped
+    writer: Option<BufWriter<File>>,
+    status: CaptureStatus,
+    // Copy of the message being rewritten, reused across messages
+    rewritten: Vec<u8>,
+}
+
+impl Capture {
+    /// Creates the capture file and writes its magic
+    pub(crate) fn create(spec: CaptureSpec) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(&spec.path)?);
+        writer.write_all(CAPTURE_MAGIC)?;
+
+        let status = CaptureStatus {
+            path: spec.path.clone(),
+            state: CaptureState::Active,
+            events: 0,
+            bytes: 0,
+        };
+        Ok(Self {
+            spec,
+            writer: Some(writer),
+            status,
+            rewritten: Vec::new(),
+        })
+    }
+
+    /// Returns the capture's progress
+    pub(crate) fn status(&self) -> &CaptureStatus {
+        &self.status
+    }
+
+    /// Writes a message if it matches the spec, stopping once a limit is hit
+    pub(crate) fn offer(&mut self, ring_index: usize, message_type: u32, data: &[u8]) {
+        if self.writer.is_none() {
+            return;
+        }
+        if !self.spec.msg_types.is_empty() && !self.spec.msg_types.contains(&message_type) {
+            return;
+        }
+        if let Some(filter) = &self.spec.pid_filter {
+            if !filter(data) {
+                return;
+            }
+        }
+
+        let record_len = (CAPTURE_RECORD_HEADER_SIZE + data.len()) as u64;
+        if self.status.bytes + record_len > self.spec.max_bytes {
+            self.stop(CaptureState::ByteLimit);
+            return;
+        }
+
+        let data = match &self.spec.rewrite {
+            Some(rewrite) => {
+                self.rewritten.clear();
+                self.rewritten.extend_from_slice(data);
+                rewrite(&mut self.rewritten);
+                &self.rewritten[..]
+            }
+            None => data,
+        };
+        if let Err(e) = write_record(&mut self.writer, ring_index, data) {
+            self.stop(CaptureState::Failed(e.to_string()));
+            return;
+        }
+        self.status.events += 1;
+        self.status.bytes += record_len;
+
+        if self.status.events >= self.spec.max_events {
+            self.stop(CaptureState::EventLimit);
+        }
+    }
+
+    /// Stops the capture if it is still active, flushing the file
+    pub(crate) fn finish(mut self) -> CaptureStatus {
+        self.stop(CaptureState::Disabled);
+        self.status
+    }
+
+    fn stop(&mut self, state: CaptureState) {
+        let Some(mut writer) = self.writer.take() else {
+            return;
+        };
+        self.status.state = match writer.flush() {
+            Ok(()) => state,
+            Err(e) => CaptureState::Failed(e.to_string()),
+        };
+    }
+}
+
+/// Writes a record of `data` to `writer`, unless the capture stopped
+fn write_record(
+    writer: &mut Option<BufWriter<File>>,
+    ring_index: usize,
+    data: &[u8],
+) -> io::Result<()> {
+    if let Some(writer) = writer.as_mut() {
+        writer.write_all(&(ring_index as u32).to_le_bytes())?;
+        writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        writer.write_all(data)?;
+    }
+    Ok(())
+}
+
+/// Parses a file written by a capture, for feeding its messages back
+/// through [`crate::Dispatcher::dispatch_record`] as samples
+pub fn read_capture(data: &[u8]) -> Result<Vec<CapturedRecord>, CaptureError> {
+    if data.get(..CAPTURE_MAGIC.len()) != Some(CAPTURE_MAGIC.as_slice()) {
+        return Err(CaptureError::BadMagic);
+    }
+
+    let mut records = Vec::new();
+    let mut offset = CAPTURE_MAGIC.len();
+    while offset < data.len() {
+        let header = data
+            .get(offset..offset + CAPTURE_RECORD_HEADER_SIZE)
+            .ok_or(CaptureError::Truncated(offset))?;
+        let ring_index = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+
+        let start = offset + CAPTURE_RECORD_HEADER_SIZE;
+        let record = data
+            .get(start..start + len)
+            .ok_or(CaptureError::Truncated(offset))?;
+        records.push(CapturedRecord {
+            ring_index,
+            data: record.to_vec(),
+        });
+        offset = start + len;
+    }
+
+    Ok(records)
+}
@@ -0,0 +1,81 @@
+//! Per-ring window of recently delivered records, used to drop duplicates.
+//!
+//! Records are identified by their timestamp and a hash of their bytes. The
+//! window holds a bounded number of keys and forgets the oldest first, so a
+//! duplicate is only detected if it arrives within `capacity` records of the
+//! original on the same ring.
+
+use std::collections::{HashSet, VecDeque};
+
+/// Default number of records remembered per ring
+pub const DEFAULT_DEDUP_WINDOW: usize = 1024;
+
+/// Bounded set of recently seen (timestamp, hash) keys
+pub(crate) struct DedupWindow {
+    capacity: usize,
+    order: VecDeque<(u64, u64)>,
+    seen: HashSet<(u64, u64)>,
+}
+
+impl DedupWindow {
+    /// Creates an empty window remembering up to `capacity` keys
+    pub(crate) fn new(capacity: usize) -> Self {
+        DedupWindow {
+            capacity: capacity.max(1),
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Records a message, returning false if it is a duplicate of one in the window
+    pub(crate) fn insert(&mut self, timestamp: u64, data: &[u8]) -> bool {
+        let key = (timestamp, fnv1a(data));
+        if self.seen.contains(&key) {
+            return false;
+        }
+
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        self.seen.insert(key);
+        true
+    }
+}
+
+/// 64-bit FNV-1a hash, used to fingerprint message payloads
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_drops_duplicates() {
+        let mut window = DedupWindow::new(4);
+        assert!(window.insert(100, b"a"));
+        assert!(!window.insert(100, b"a"));
+
+        // Same timestamp or same payload alone is not a duplicate
+        assert!(window.insert(100, b"b"));
+        assert!(window.insert(200, b"a"));
+    }
+
+    #[test]
+    fn test_window_forgets_oldest() {
+        let mut window = DedupWindow::new(2);
+        assert!(window.insert(1, b"x"));
+        assert!(window.insert(2, b"x"));
+        assert!(window.insert(3, b"x"));
+
+        // The first key was evicted, the others are still remembered
+        assert!(window.insert(1, b"x"));
+        assert!(!window.insert(3, b"x"));
+    }
+}
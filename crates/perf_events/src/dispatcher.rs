@@ -1,8 +1,18 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use collector_errors::{Classified, ErrorCode};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    time::Duration,
+};
 use thiserror::Error;
 
+use crate::capture::Capture;
+use crate::dedup::DedupWindow;
 use crate::{
-    PerfRingError, Reader, ReaderError, SampleHeader, PERF_RECORD_LOST, PERF_RECORD_SAMPLE,
+    Arena, BatchSummary, CaptureSpec, CaptureStatus, ChunkAssembler, LayoutError, PerfRecordType,
+    PerfRingError, Reader, ReaderError, SampleHeader, SampleLayout, DEFAULT_DEDUP_WINDOW,
+    PERF_MSG_CHUNK,
 };
 
 /// Errors that can occur during dispatch operations
@@ -18,6 +28,16 @@ pub enum DispatchError {
     InvalidFormat(String),
 }
 
+impl Classified for DispatchError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            DispatchError::ReaderError(e) => e.code(),
+            DispatchError::RingError(e) => e.code(),
+            DispatchError::InvalidFormat(_) => ErrorCode::RingCorrupt,
+        }
+    }
+}
+
 /// Tracks statistics for the dispatcher
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Stats {
@@ -32,18 +52,102 @@ pub struct Stats {
 
     /// Number of messages with no registered callbacks
     pub dropped_messages: usize,
+
+    /// Number of chunked messages that could not be reassembled
+    pub chunk_errors: usize,
+
+    /// Number of duplicate messages dropped by deduplication
+    pub duplicates_dropped: usize,
+
+    /// Number of records other than samples and lost records, including
+    /// types this crate does not know
+    pub unknown_records: usize,
+
+    /// Number of records consumed while the dispatcher was paused, and not
+    /// delivered
+    pub paused_dropped: usize,
+}
+
+impl Stats {
+    /// Counts accumulated since an `earlier` snapshot of the same dispatcher
+    pub fn since(&self, earlier: &Stats) -> Stats {
+        Stats {
+            samples_processed: self.samples_processed - earlier.samples_processed,
+            lost_events_processed: self.lost_events_processed - earlier.lost_events_processed,
+            callback_errors: self.callback_errors - earlier.callback_errors,
+            dropped_messages: self.dropped_messages - earlier.dropped_messages,
+            chunk_errors: self.chunk_errors - earlier.chunk_errors,
+            duplicates_dropped: self.duplicates_dropped - earlier.duplicates_dropped,
+            unknown_records: self.unknown_records - earlier.unknown_records,
+            paused_dropped: self.paused_dropped - earlier.paused_dropped,
+        }
+    }
+}
+
+/// Summary of the callbacks registered on a dispatcher
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DispatcherSummary {
+    /// Number of message types with at least one sample subscriber
+    pub sample_message_types: usize,
+
+    /// Total number of registered callbacks, sample, lost and unknown
+    /// record
+    pub total_callbacks: usize,
+
+    /// Whether any lost sample subscriber is registered
+    pub has_lost_subscribers: bool,
+}
+
+/// Callbacks subscribed to a message type
+#[derive(Default)]
+struct TypeSubscribers {
+    callbacks: Vec<Box<dyn FnMut(usize, u16, &[u8], &Arena)>>,
+    /// Messages of the type delivered to the callbacks
+    delivered: u64,
 }
 
+/// Callback for records that are neither samples nor lost records
+type UnknownRecordCallback = Box<dyn FnMut(usize, PerfRecordType, &[u8])>;
+
 /// Dispatcher handles message distribution to subscribers based on message type
 pub struct Dispatcher {
-    /// Callbacks for specific message types (message_type => vec of callbacks)
-    sample_subscribers: HashMap<u32, Vec<Box<dyn FnMut(usize, &[u8])>>>,
+    /// Callbacks for specific message types (message_type => callbacks)
+    sample_subscribers: HashMap<u32, TypeSubscribers>,
 
     /// Callbacks for lost sample events
     lost_subscribers: Vec<Box<dyn FnMut(usize, &[u8])>>,
 
+    /// Callbacks for records that are neither samples nor lost records
+    unknown_subscribers: Vec<UnknownRecordCallback>,
+
+    /// Reassembly state for chunked messages
+    chunks: ChunkAssembler,
+
+    /// Message types deduplicated before delivery
+    dedup_types: HashSet<u32>,
+
+    /// Recently delivered deduplicated messages, per ring
+    dedup_windows: Vec<DedupWindow>,
+
+    /// Scratch memory for subscribers, reset at the start of each batch
+    arena: Arena,
+
+    /// Buffer records are copied into from the ring, kept across events so
+    /// it only grows when a larger record than any before arrives
+    record_buf: Vec<u8>,
+
+    /// Debug capture of raw messages to a file, if enabled
+    capture: Option<Capture>,
+
+    /// Whether records are consumed without being delivered
+    paused: bool,
+
     /// Statistics counters
     stats: Stats,
+
+    /// Bytes and messages per type in the current `dispatch_all`
+    #[cfg(feature = "tracing")]
+    counts: crate::instrument::DispatchCounts,
 }
 
 impl Dispatcher {
@@ -52,23 +156,178 @@ impl Dispatcher {
         Dispatcher {
             sample_subscribers: HashMap::new(),
             lost_subscribers: Vec::new(),
+            unknown_subscribers: Vec::new(),
+            chunks: ChunkAssembler::new(),
+            dedup_types: HashSet::new(),
+            dedup_windows: Vec::new(),
+            arena: Arena::new(),
+            record_buf: Vec::new(),
+            capture: None,
+            paused: false,
             stats: Stats::default(),
+            #[cfg(feature = "tracing")]
+            counts: Default::default(),
         }
     }
 
+    /// Creates a new dispatcher after checking that samples and the message
+    /// types read from them have the expected layout
+    pub fn with_layout(layout: &SampleLayout) -> Result<Self, LayoutError> {
+        layout.validate()?;
+        Ok(Self::new())
+    }
+
+    /// Stops delivering records to subscribers, e.g. while they are being
+    /// reconfigured
+    ///
+    /// Dispatching still consumes records from the reader, so the rings do
+    /// not overflow, but counts them in `Stats::paused_dropped` instead of
+    /// delivering them. A chunked message with chunks on both sides of a
+    /// pause or resume is not delivered either.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resumes delivering records to subscribers after [`Dispatcher::pause`]
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Returns whether the dispatcher is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
     /// Returns the current statistics
     pub fn stats(&self) -> Stats {
         self.stats
     }
 
+    /// Returns a summary of the registered callbacks
+    pub fn summary(&self) -> DispatcherSummary {
+        let sample_callbacks: usize = self
+            .sample_subscribers
+            .values()
+            .map(|subscribers| subscribers.callbacks.len())
+            .sum();
+        DispatcherSummary {
+            sample_message_types: self.sample_subscribers.len(),
+            total_callbacks: sample_callbacks
+                + self.lost_subscribers.len()
+                + self.unknown_subscribers.len(),
+            has_lost_subscribers: !self.lost_subscribers.is_empty(),
+        }
+    }
+
+    /// Returns the number of messages delivered to subscribers, by message
+    /// type in ascending order
+    pub fn delivered_by_type(&self) -> Vec<(u32, u64)> {
+        let mut counts: Vec<(u32, u64)> = self
+            .sample_subscribers
+            .iter()
+            .map(|(&message_type, subscribers)| (message_type, subscribers.delivered))
+            .collect();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// Whether any sample subscriber is registered for `message_type`
+    pub fn has_subscribers(&self, message_type: u32) -> bool {
+        self.sample_subscribers.contains_key(&message_type)
+    }
+
+    /// Drop duplicate messages of a type before they reach subscribers.
+    ///
+    /// A message is a duplicate if one with the same timestamp and bytes was
+    /// delivered within the last [`DEFAULT_DEDUP_WINDOW`] deduplicated messages
+    /// on the same ring. Dropped duplicates are counted in
+    /// `Stats::duplicates_dropped`. Off by default, as it hashes every message
+    /// of the type.
+    pub fn enable_dedup(&mut self, message_type: u32) {
+        self.dedup_types.insert(message_type);
+    }
+
+    /// Start writing the raw messages selected by `spec` to its file, for
+    /// debugging. Replaces any capture already in progress.
+    ///
+    /// The capture sees every complete sample message after deduplication,
+    /// including those of types without subscribers, and stops on its own
+    /// once it reaches `max_events` or `max_bytes`. The file can be read back
+    /// with [`crate::read_capture`].
+    pub fn enable_capture(&mut self, spec: CaptureSpec) -> std::io::Result<()> {
+        self.disable_capture();
+        self.capture = Some(Capture::create(spec)?);
+        Ok(())
+    }
+
+    /// Stop the capture, flushing its file, and return its final status.
+    /// Returns None if no capture was enabled.
+    pub fn disable_capture(&mut self) -> Option<CaptureStatus> {
+        self.capture.take().map(Capture::finish)
+    }
+
+    /// Returns the progress of the capture, including one that stopped at a
+    /// limit, or None if no capture is enabled
+    pub fn capture_status(&self) -> Option<CaptureStatus> {
+        self.capture
+            .as_ref()
+            .map(|capture| capture.status().clone())
+    }
+
+    /// Returns the arena passed to subscribers
+    pub fn arena(&self) -> &Arena {
+        &self.arena
+    }
+
     /// Subscribe to events of a specific message type
-    pub fn subscribe<F>(&mut self, message_type: u32, callback: F)
+    pub fn subscribe<F>(&mut self, message_type: u32, mut callback: F)
     where
         F: FnMut(usize, &[u8]) + 'static,
+    {
+        self.subscribe_with_arena(message_type, move |ring_index, data, _arena| {
+            callback(ring_index, data)
+        });
+    }
+
+    /// Subscribe to events of a specific message type, also receiving the
+    /// dispatcher's [`Arena`] for temporary allocations.
+    ///
+    /// Allocations are released when the next batch starts in
+    /// [`Dispatcher::dispatch_all`]. They borrow the arena for the duration of
+    /// the callback only, so they cannot be kept across events; copy anything
+    /// that must outlive the callback into the subscriber's own storage.
+    pub fn subscribe_with_arena<F>(&mut self, message_type: u32, mut callback: F)
+    where
+        F: FnMut(usize, &[u8], &Arena) + 'static,
+    {
+        self.push_subscriber(message_type, move |ring_index, _misc, data, arena| {
+            callback(ring_index, data, arena)
+        });
+    }
+
+    /// Subscribe to events of a specific message type, also receiving the
+    /// `misc` flags of the perf record header.
+    ///
+    /// The flags tell the CPU mode the sample was taken in, see
+    /// [`crate::is_kernel`] and the other helpers. Records dispatched
+    /// without a ring through [`Dispatcher::dispatch_record`] have no flags.
+    pub fn subscribe_with_misc<F>(&mut self, message_type: u32, mut callback: F)
+    where
+        F: FnMut(usize, u16, &[u8]) + 'static,
+    {
+        self.push_subscriber(message_type, move |ring_index, misc, data, _arena| {
+            callback(ring_index, misc, data)
+        });
+    }
+
+    fn push_subscriber<F>(&mut self, message_type: u32, callback: F)
+    where
+        F: FnMut(usize, u16, &[u8], &Arena) + 'static,
     {
         self.sample_subscribers
             .entry(message_type)
             .or_default()
+            .callbacks
             .push(Box::new(callback));
     }
 
@@ -80,6 +339,17 @@ impl Dispatcher {
         self.lost_subscribers.push(Box::new(callback));
     }
 
+    /// Subscribe to records that are neither samples nor lost records, such
+    /// as throttling or records of types this crate does not know. The
+    /// callback receives the record's type and its data after the perf
+    /// header.
+    pub fn subscribe_unknown_records<F>(&mut self, callback: F)
+    where
+        F: FnMut(usize, PerfRecordType, &[u8]) + 'static,
+    {
+        self.unknown_subscribers.push(Box::new(callback));
+    }
+
     /// Subscribe to events of a specific message type with a method from a struct
     pub fn subscribe_method<T: 'static>(
         &mut self,
@@ -93,6 +363,20 @@ impl Dispatcher {
         self.subscribe(message_type, callback);
     }
 
+    /// Subscribe to events of a specific message type with a method from a
+    /// struct that also receives the dispatcher's [`Arena`]
+    pub fn subscribe_method_with_arena<T: 'static>(
+        &mut self,
+        message_type: u32,
+        instance: Rc<RefCell<T>>,
+        method: fn(&mut T, usize, &[u8], &Arena),
+    ) {
+        let callback = move |ring_index, data: &[u8], arena: &Arena| {
+            method(&mut instance.borrow_mut(), ring_index, data, arena);
+        };
+        self.subscribe_with_arena(message_type, callback);
+    }
+
     /// Dispatch events from the reader to registered subscribers
     pub fn dispatch(&mut self, reader: &mut Reader) -> Result<(), DispatchError> {
         if reader.is_empty() {
@@ -102,60 +386,248 @@ impl Dispatcher {
         // Get the current ring and its index
         let (ring, ring_index) = reader.current_ring()?;
 
-        let size = ring.peek_size()?;
-        let mut event_data = vec![0u8; size];
-        ring.peek_copy(&mut event_data, 0)?;
+        let record_type = ring.peek_record_type();
+        let misc = ring.peek_misc();
+
+        // The buffer is taken out of the dispatcher while subscribers borrow
+        // the record, and put back before returning, including on errors
+        let mut event_data = std::mem::take(&mut self.record_buf);
+        let copied = ring.peek_size().and_then(|size| {
+            event_data.clear();
+            event_data.resize(size, 0);
+            ring.peek_copy(&mut event_data, 0)
+        });
+        let result = match copied {
+            Ok(()) => self.dispatch_record_with_misc(ring_index, record_type, misc, &event_data),
+            Err(PerfRingError::Overwritten) => {
+                // The writer lapped us and the record may be torn; like lost
+                // records, skip ahead and count the loss in the ring stats
+                self.record_buf = event_data;
+                self.stats.chunk_errors += self.chunks.discard_ring(ring_index);
+                reader.resync_current()?;
+                return Ok(());
+            }
+            Err(e) => Err(e.into()),
+        };
+        self.record_buf = event_data;
+        result?;
+
+        // Pop the event from the reader
+        reader.pop()?;
+
+        Ok(())
+    }
+
+    /// Dispatch a single perf record that has already been read from a ring.
+    ///
+    /// `event_data` has the same layout subscribers receive: for samples it
+    /// starts with the `SampleHeader`. This allows feeding recorded events back
+    /// through the dispatcher without a ring, and lets subscriber tests
+    /// dispatch samples and lost records directly. Routing, statistics and
+    /// rejection of malformed samples are the same as for ring events.
+    /// `record_type` is a [`PerfRecordType`] or the raw `PERF_RECORD_*` value.
+    pub fn dispatch_record(
+        &mut self,
+        ring_index: usize,
+        record_type: impl Into<PerfRecordType>,
+        event_data: &[u8],
+    ) -> Result<(), DispatchError> {
+        self.dispatch_record_with_misc(ring_index, record_type, 0, event_data)
+    }
+
+    /// Dispatch a single perf record along with the `misc` flags of its
+    /// header, see [`Dispatcher::dispatch_record`]. A chunked message is
+    /// delivered with the flags of its last chunk.
+    pub fn dispatch_record_with_misc(
+        &mut self,
+        ring_index: usize,
+        record_type: impl Into<PerfRecordType>,
+        misc: u16,
+        event_data: &[u8],
+    ) -> Result<(), DispatchError> {
+        if self.paused {
+            self.stats.paused_dropped += 1;
+            return Ok(());
+        }
+
+        #[cfg(feature = "tracing")]
+        self.counts.record(event_data.len());
 
-        // Check the event type
-        match ring.peek_type() {
-            PERF_RECORD_SAMPLE => {
+        let record_type = record_type.into();
+        match record_type {
+            PerfRecordType::Sample => {
                 // The message format after the perf header is defined by the SampleHeader struct
 
-                let header: &SampleHeader = plain::from_bytes(&event_data).map_err(|_e| {
+                let header: &SampleHeader = plain::from_bytes(event_data).map_err(|_e| {
                     DispatchError::InvalidFormat(
                         "Sample event too small to contain message type and timestamp".to_string(),
                     )
                 })?;
 
-                // Check if we have subscribers for this message type
-                if let Some(subscribers) = self.sample_subscribers.get_mut(&header.type_) {
-                    // Call each subscriber with the ring index and message data
-                    for subscriber in subscribers {
-                        subscriber(ring_index, &event_data);
+                if header.type_ == PERF_MSG_CHUNK {
+                    // Part of a larger message; deliver once all chunks have arrived
+                    match self.chunks.push(ring_index, event_data) {
+                        Ok(Some(message)) => self.deliver_sample(ring_index, misc, &message),
+                        Ok(None) => {}
+                        Err(_) => self.stats.chunk_errors += 1,
                     }
-                    self.stats.samples_processed += 1;
                 } else {
-                    // No subscribers for this message type
-                    self.stats.dropped_messages += 1;
+                    self.deliver_sample(ring_index, misc, event_data);
                 }
             }
-            PERF_RECORD_LOST => {
+            PerfRecordType::Lost => {
                 // For lost events, we just pass the raw event data
 
+                // The lost records may include the remaining chunks of the
+                // messages being reassembled on this ring
+                self.stats.chunk_errors += self.chunks.discard_ring(ring_index);
+
                 // Call lost sample subscribers
                 for subscriber in &mut self.lost_subscribers {
-                    subscriber(ring_index, &event_data);
+                    subscriber(ring_index, event_data);
                 }
                 self.stats.lost_events_processed += 1;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(ring_index, "lost record");
             }
-            _ => {
-                // Unhandled event type, just track as dropped
-                self.stats.dropped_messages += 1;
+            PerfRecordType::Mmap
+            | PerfRecordType::Comm
+            | PerfRecordType::Exit
+            | PerfRecordType::Throttle
+            | PerfRecordType::Unthrottle
+            | PerfRecordType::Fork
+            | PerfRecordType::Read
+            | PerfRecordType::Mmap2
+            | PerfRecordType::Aux
+            | PerfRecordType::ItraceStart
+            | PerfRecordType::LostSamples
+            | PerfRecordType::Switch
+            | PerfRecordType::SwitchCpuWide
+            | PerfRecordType::Namespaces
+            | PerfRecordType::Ksymbol
+            | PerfRecordType::BpfEvent
+            | PerfRecordType::Cgroup
+            | PerfRecordType::TextPoke
+            | PerfRecordType::AuxOutputHwId
+            | PerfRecordType::Unknown(_) => {
+                // Not handled here; left to the unknown record subscribers
+                for subscriber in &mut self.unknown_subscribers {
+                    subscriber(ring_index, record_type, event_data);
+                }
+                self.stats.unknown_records += 1;
+                if self.unknown_subscribers.is_empty() {
+                    self.stats.dropped_messages += 1;
+                }
+                #[cfg(feature = "tracing")]
+                tracing::debug!(ring_index, ?record_type, "unknown record");
             }
         }
 
-        // Pop the event from the reader
-        reader.pop()?;
-
         Ok(())
     }
 
-    /// Dispatches all available events until the reader is empty
-    pub fn dispatch_all(&mut self, reader: &mut Reader) -> Result<(), DispatchError> {
+    /// Deliver a complete sample message to the subscribers of its message type
+    fn deliver_sample(&mut self, ring_index: usize, misc: u16, data: &[u8]) {
+        // Callers have verified the data holds a SampleHeader
+        let (message_type, timestamp) = match plain::from_bytes::<SampleHeader>(data) {
+            Ok(header) => (header.type_, header.timestamp),
+            Err(_) => return,
+        };
+
+        // Drop messages already delivered, if deduplication is enabled for the type
+        if !self.dedup_types.is_empty() && self.dedup_types.contains(&message_type) {
+            if self.dedup_windows.len() <= ring_index {
+                self.dedup_windows
+                    .resize_with(ring_index + 1, || DedupWindow::new(DEFAULT_DEDUP_WINDOW));
+            }
+            if !self.dedup_windows[ring_index].insert(timestamp, data) {
+                self.stats.duplicates_dropped += 1;
+                return;
+            }
+        }
+
+        if let Some(capture) = &mut self.capture {
+            capture.offer(ring_index, message_type, data);
+        }
+
+        // Check if we have subscribers for this message type
+        if let Some(subscribers) = self.sample_subscribers.get_mut(&message_type) {
+            // Call each subscriber with the ring index and message data
+            for subscriber in &mut subscribers.callbacks {
+                subscriber(ring_index, misc, data, &self.arena);
+            }
+            subscribers.delivered += 1;
+            self.stats.samples_processed += 1;
+            #[cfg(feature = "tracing")]
+            self.counts.deliver(message_type);
+        } else {
+            // No subscribers for this message type
+            self.stats.dropped_messages += 1;
+        }
+    }
+
+    /// Dispatches all available events until the reader is empty.
+    /// Returns the number of events dispatched.
+    ///
+    /// Each call starts a new batch, releasing what subscribers allocated
+    /// from the arena during the previous one.
+    pub fn dispatch_all(&mut self, reader: &mut Reader) -> Result<usize, DispatchError> {
+        self.arena.reset();
+
+        #[cfg(feature = "tracing")]
+        let span = {
+            self.counts = Default::default();
+            crate::instrument::DispatchSpan::start(self.stats)
+        };
+        #[cfg(feature = "tracing")]
+        let _entered = span.span().clone().entered();
+
+        let mut dispatched = 0;
         while !reader.is_empty() {
             self.dispatch(reader)?;
+            dispatched += 1;
         }
-        Ok(())
+
+        #[cfg(feature = "tracing")]
+        span.finish(dispatched, self.stats, &self.counts);
+
+        Ok(dispatched)
+    }
+
+    /// Runs one read batch on the reader, dispatching all available events.
+    ///
+    /// If the batch was empty, sleeps for `max_idle_sleep` before returning so
+    /// an idle poll loop does not spin. When events were processed it returns
+    /// immediately, so a busy loop does not add latency. Returns the summary of
+    /// the batch, for callers that adapt how often they poll.
+    pub fn poll(
+        &mut self,
+        reader: &mut Reader,
+        max_idle_sleep: Duration,
+    ) -> Result<BatchSummary, DispatchError> {
+        reader.start()?;
+        let dispatched = self.dispatch_all(reader)?;
+        let summary = reader.finish_with_summary()?;
+
+        if dispatched == 0 && !max_idle_sleep.is_zero() {
+            std::thread::sleep(max_idle_sleep);
+        }
+
+        Ok(summary)
+    }
+
+    /// Runs one read batch on the reader, dispatching all available events
+    /// without sleeping.
+    ///
+    /// Returns the statistics accumulated during this call.
+    pub fn poll_once(&mut self, reader: &mut Reader) -> Result<Stats, DispatchError> {
+        let before = self.stats;
+
+        reader.start()?;
+        self.dispatch_all(reader)?;
+        reader.finish()?;
+
+        Ok(self.stats.since(&before))
     }
 }
 
@@ -170,7 +642,13 @@ mod tests {
     use plain::Plain;
 
     use super::*;
-    use crate::PerfRing;
+    use crate::{
+        is_exact_ip, is_hypervisor, is_kernel, is_user, lost_record, read_capture, sample_payload,
+        sample_record, write_lost, write_sample, CaptureError, CaptureState, CapturedRecord,
+        PerfRing, CAPTURE_MAGIC, CAPTURE_RECORD_HEADER_SIZE, PERF_RECORD_MISC_EXACT_IP,
+        PERF_RECORD_MISC_HYPERVISOR, PERF_RECORD_MISC_KERNEL, PERF_RECORD_MISC_USER,
+        PERF_RECORD_SAMPLE,
+    };
     use std::cell::RefCell;
     use std::rc::Rc;
 
@@ -185,21 +663,6 @@ mod tests {
     }
     unsafe impl Plain for TestMessage {}
 
-    // Create a test message
-    fn create_test_message(msg_type: u32, timestamp: u64, data: &[u8]) -> Vec<u8> {
-        let mut message = Vec::with_capacity(size_of::<TestMessage>());
-        let msg = TestMessage {
-            header: SampleHeader {
-                size: 8,
-                type_: msg_type,
-                timestamp,
-            },
-            data: data.try_into().unwrap(),
-        };
-        message.extend_from_slice(unsafe { plain::as_bytes(&msg)[4..].as_ref() });
-        message
-    }
-
     #[test]
     fn test_dispatcher_basic() {
         // Setup test rings and reader
@@ -262,23 +725,19 @@ mod tests {
         ring1.start_write_batch();
 
         // FOO message
-        let foo_msg = create_test_message(MSG_TYPE_FOO, 100, b"FOO DATA");
-        ring1.write(&foo_msg, PERF_RECORD_SAMPLE).unwrap();
+        write_sample(&mut ring1, MSG_TYPE_FOO, 100, b"FOO DATA").unwrap();
 
         // BAR message
-        let bar_msg = create_test_message(MSG_TYPE_BAR, 200, b"BAR DATA");
-        ring1.write(&bar_msg, PERF_RECORD_SAMPLE).unwrap();
+        write_sample(&mut ring1, MSG_TYPE_BAR, 200, b"BAR DATA").unwrap();
 
         // Lost event
-        let lost_data = [0u8; 8];
-        ring1.write(&lost_data, PERF_RECORD_LOST).unwrap();
+        write_lost(&mut ring1, 0, 1).unwrap();
 
         ring1.finish_write_batch();
 
         // Write another message to ring2
         ring2.start_write_batch();
-        let foo_msg2 = create_test_message(MSG_TYPE_FOO, 150, b"FOO DATA");
-        ring2.write(&foo_msg2, PERF_RECORD_SAMPLE).unwrap();
+        write_sample(&mut ring2, MSG_TYPE_FOO, 150, b"FOO DATA").unwrap();
         ring2.finish_write_batch();
 
         // Start reading
@@ -304,167 +763,1137 @@ mod tests {
     }
 
     #[test]
-    fn test_dispatcher_no_subscribers() {
-        // Setup test rings and reader
+    fn test_misc_flags() {
         let page_size = 4096u64;
         let n_pages = 2u32;
         let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
 
         let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
-
-        // Create the reader
         let mut reader = Reader::new();
         reader
             .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
             .unwrap();
 
-        // Create the dispatcher
         let mut dispatcher = Dispatcher::new();
+        let flags = Rc::new(RefCell::new(Vec::new()));
+        {
+            let flags = flags.clone();
+            dispatcher.subscribe_with_misc(MSG_TYPE_FOO, move |_, misc, data| {
+                let msg: &TestMessage = plain::from_bytes(data).unwrap();
+                flags.borrow_mut().push((msg.header.timestamp, misc));
+            });
+        }
+        // Subscribers without flags still receive the messages
+        let plain_count = Rc::new(RefCell::new(0));
+        {
+            let plain_count = plain_count.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |_, _| *plain_count.borrow_mut() += 1);
+        }
 
-        // Write a message with no subscribers
+        let written = [
+            (100, PERF_RECORD_MISC_KERNEL),
+            (200, PERF_RECORD_MISC_USER | PERF_RECORD_MISC_EXACT_IP),
+            (300, PERF_RECORD_MISC_HYPERVISOR),
+            (400, 0),
+        ];
         ring.start_write_batch();
-        let unknown_msg = create_test_message(999, 100, b"UNKNOWN ");
-        ring.write(&unknown_msg, PERF_RECORD_SAMPLE).unwrap();
+        for (timestamp, misc) in written {
+            let payload = sample_payload(MSG_TYPE_FOO, timestamp, b"FOO DATA");
+            ring.write_with_misc(&payload, PERF_RECORD_SAMPLE, misc)
+                .unwrap();
+        }
         ring.finish_write_batch();
 
-        // Start reading
-        reader.start().unwrap();
-
-        // Dispatch
-        dispatcher.dispatch_all(&mut reader).unwrap();
-
-        // Check statistics
-        let stats = dispatcher.stats();
-        assert_eq!(stats.dropped_messages, 1);
-        assert_eq!(stats.samples_processed, 0);
-
-        // Finish reading
-        reader.finish().unwrap();
+        dispatcher.poll_once(&mut reader).unwrap();
+        assert_eq!(*flags.borrow(), written);
+        assert_eq!(*plain_count.borrow(), 4);
+
+        let seen = flags.borrow().clone();
+        let kernel: Vec<bool> = seen.iter().map(|&(_, misc)| is_kernel(misc)).collect();
+        assert_eq!(kernel, vec![true, false, false, false]);
+        assert!(is_user(seen[1].1) && is_exact_ip(seen[1].1));
+        assert!(!is_exact_ip(seen[0].1));
+        assert!(is_hypervisor(seen[2].1));
+        assert!(!is_user(seen[3].1) && !is_kernel(seen[3].1));
+
+        // Records dispatched without a ring have no flags
+        let record = sample_record(&sample_payload(MSG_TYPE_FOO, 500, b"FOO DATA"));
+        dispatcher
+            .dispatch_record(0, PerfRecordType::Sample, &record)
+            .unwrap();
+        assert_eq!(flags.borrow().last(), Some(&(500, 0)));
     }
 
     #[test]
-    fn test_dispatcher_using_instance_methods() {
-        // Setup test rings and reader
+    fn test_record_buffer_reused() {
         let page_size = 4096u64;
         let n_pages = 2u32;
         let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
 
         let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
-
-        // Create the reader
         let mut reader = Reader::new();
         reader
             .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
             .unwrap();
 
-        // Handler with instance methods
-        struct MyHandler {
-            foo_counter: usize,
-            bar_counter: usize,
+        // Subscribers see the record in the dispatcher's buffer, so its
+        // address shows whether the buffer was reallocated
+        let mut dispatcher = Dispatcher::new();
+        let addresses = Rc::new(RefCell::new(Vec::new()));
+        {
+            let addresses = addresses.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |_, data| {
+                addresses
+                    .borrow_mut()
+                    .push((data.as_ptr() as usize, data.len()));
+            });
         }
 
-        impl MyHandler {
-            fn handle_foo(&mut self, _ring_index: usize, data: &[u8]) {
-                self.foo_counter += 1;
-                let msg: &TestMessage = plain::from_bytes(data).unwrap();
-                assert_eq!(&msg.data, b"FOO DATA");
-            }
-
-            fn handle_bar(&mut self, _ring_index: usize, data: &[u8]) {
-                self.bar_counter += 1;
-                let msg: &TestMessage = plain::from_bytes(data).unwrap();
-                assert_eq!(&msg.data, b"BAR DATA");
+        // A large record first warms the buffer up, then many smaller ones
+        // and one of the same size fit in it
+        let mut timestamp = 0;
+        for _ in 0..20 {
+            ring.start_write_batch();
+            for body_len in [64usize, 8, 16, 8, 64] {
+                timestamp += 1;
+                write_sample(&mut ring, MSG_TYPE_FOO, timestamp, &vec![0u8; body_len]).unwrap();
             }
+            ring.finish_write_batch();
+            dispatcher.poll_once(&mut reader).unwrap();
         }
 
-        // Create handler
-        let handler = Rc::new(RefCell::new(MyHandler {
-            foo_counter: 0,
-            bar_counter: 0,
-        }));
+        let addresses = addresses.borrow();
+        assert_eq!(addresses.len(), 100);
+        assert!(addresses
+            .iter()
+            .all(|&(address, _)| address == addresses[0].0));
+        let lengths: Vec<usize> = addresses.iter().take(5).map(|&(_, len)| len).collect();
+        assert_eq!(lengths[1], lengths[3]);
+        assert!(lengths[0] > lengths[2] && lengths[2] > lengths[1]);
+        assert_eq!(dispatcher.stats().samples_processed, 100);
+    }
 
-        // Create the dispatcher
+    #[test]
+    fn test_capture_stops_at_limits() {
+        let path = std::env::temp_dir().join(format!("dispatcher_capture_{}", std::process::id()));
         let mut dispatcher = Dispatcher::new();
-
-        // Register instance methods as callbacks
+        let delivered = Rc::new(RefCell::new(0));
         {
-            let handler_clone = handler.clone();
-            dispatcher.subscribe(MSG_TYPE_FOO, move |idx, data| {
-                handler_clone.borrow_mut().handle_foo(idx, data);
-            });
+            let delivered = delivered.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |_, _| *delivered.borrow_mut() += 1);
+        }
+        assert_eq!(dispatcher.capture_status(), None);
+
+        // FOO messages whose body starts with 'K'
+        let spec = CaptureSpec {
+            msg_types: vec![MSG_TYPE_FOO],
+            max_events: 3,
+            pid_filter: Some(Box::new(|data: &[u8]| {
+                data.get(size_of::<SampleHeader>()) == Some(&b'K')
+            })),
+            ..CaptureSpec::new(&path)
+        };
+        dispatcher.enable_capture(spec).unwrap();
+
+        let mut expected = Vec::new();
+        for timestamp in 0..10u64 {
+            let (message_type, body) = match timestamp % 3 {
+                0 => (MSG_TYPE_FOO, b"KEEP ME!"),
+                1 => (MSG_TYPE_FOO, b"SKIP ME!"),
+                _ => (MSG_TYPE_BAR, b"KEEP BAR"),
+            };
+            let record = sample_record(&sample_payload(message_type, timestamp, body));
+            dispatcher
+                .dispatch_record(timestamp as usize % 2, PerfRecordType::Sample, &record)
+                .unwrap();
+            if message_type == MSG_TYPE_FOO && body[0] == b'K' && expected.len() < 3 {
+                expected.push(CapturedRecord {
+                    ring_index: timestamp as usize % 2,
+                    data: record,
+                });
+            }
+        }
+
+        // The capture stopped by itself after the third match, while
+        // subscribers kept receiving messages
+        let status = dispatcher.capture_status().unwrap();
+        assert_eq!(status.state, CaptureState::EventLimit);
+        assert_eq!(status.events, 3);
+        assert_eq!(*delivered.borrow(), 7);
+        let records = read_capture(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(records, expected);
+        assert_eq!(
+            status.bytes,
+            records
+                .iter()
+                .map(|r| (CAPTURE_RECORD_HEADER_SIZE + r.data.len()) as u64)
+                .sum::<u64>()
+        );
+
+        // A byte limit stops before the record that would exceed it
+        let record_size = (CAPTURE_RECORD_HEADER_SIZE + expected[0].data.len()) as u64;
+        let spec = CaptureSpec {
+            max_bytes: record_size * 2 + 1,
+            ..CaptureSpec::new(&path)
+        };
+        dispatcher.enable_capture(spec).unwrap();
+        for timestamp in 0..5u64 {
+            let payload = sample_payload(MSG_TYPE_BAR, timestamp, b"BAR DATA");
+            dispatcher
+                .dispatch_record(0, PerfRecordType::Sample, &sample_record(&payload))
+                .unwrap();
         }
+        let status = dispatcher.disable_capture().unwrap();
+        assert_eq!(status.state, CaptureState::ByteLimit);
+        assert_eq!(status.events, 2);
+        assert_eq!(
+            read_capture(&std::fs::read(&path).unwrap()).unwrap().len(),
+            2
+        );
+        assert_eq!(dispatcher.capture_status(), None);
+
+        // Disabling an active capture flushes what it wrote
+        dispatcher.enable_capture(CaptureSpec::new(&path)).unwrap();
+        let record = sample_record(&sample_payload(MSG_TYPE_FOO, 1, b"FOO DATA"));
+        dispatcher
+            .dispatch_record(0, PerfRecordType::Sample, &record)
+            .unwrap();
+        let status = dispatcher.disable_capture().unwrap();
+        assert_eq!(status.state, CaptureState::Disabled);
+        let records = read_capture(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data, record);
+
+        let mut truncated = std::fs::read(&path).unwrap();
+        truncated.pop();
+        assert_eq!(
+            read_capture(&truncated),
+            Err(CaptureError::Truncated(CAPTURE_MAGIC.len()))
+        );
+        assert_eq!(read_capture(b"PEPLOG01"), Err(CaptureError::BadMagic));
+
+        std::fs::remove_file(&path).unwrap();
+    }
 
+    #[test]
+    fn test_capture_rewrite() {
+        let path = std::env::temp_dir().join(format!("dispatcher_rewrite_{}", std::process::id()));
+        let mut dispatcher = Dispatcher::new();
+        let delivered = Rc::new(RefCell::new(Vec::new()));
         {
-            let handler_clone = handler.clone();
-            dispatcher.subscribe(MSG_TYPE_BAR, move |idx, data| {
-                handler_clone.borrow_mut().handle_bar(idx, data);
+            let delivered = delivered.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |_, data| {
+                delivered.borrow_mut().push(data.to_vec())
             });
         }
 
-        // Write test messages
-        ring.start_write_batch();
-        let foo_msg = create_test_message(MSG_TYPE_FOO, 100, b"FOO DATA");
-        ring.write(&foo_msg, PERF_RECORD_SAMPLE).unwrap();
-
-        let bar_msg = create_test_message(MSG_TYPE_BAR, 200, b"BAR DATA");
-        ring.write(&bar_msg, PERF_RECORD_SAMPLE).unwrap();
-        ring.finish_write_batch();
-
-        // Start reading
-        reader.start().unwrap();
-
-        // Dispatch all events
-        dispatcher.dispatch_all(&mut reader).unwrap();
+        // The capture masks the body, subscribers still see it
+        let spec = CaptureSpec {
+            rewrite: Some(Box::new(|data: &mut [u8]| {
+                data[size_of::<SampleHeader>()..].fill(b'*')
+            })),
+            ..CaptureSpec::new(&path)
+        };
+        dispatcher.enable_capture(spec).unwrap();
+        let record = sample_record(&sample_payload(MSG_TYPE_FOO, 1, b"SECRET!!"));
+        dispatcher
+            .dispatch_record(0, PerfRecordType::Sample, &record)
+            .unwrap();
+        let status = dispatcher.disable_capture().unwrap();
+        assert_eq!(status.events, 1);
 
-        // Check handler counters
-        let handler_ref = handler.borrow();
-        assert_eq!(handler_ref.foo_counter, 1);
-        assert_eq!(handler_ref.bar_counter, 1);
+        assert_eq!(*delivered.borrow(), vec![record]);
+        let records = read_capture(&std::fs::read(&path).unwrap()).unwrap();
+        let masked = sample_record(&sample_payload(MSG_TYPE_FOO, 1, b"********"));
+        assert_eq!(records[0].data, masked);
 
-        // Finish reading
-        reader.finish().unwrap();
+        std::fs::remove_file(&path).unwrap();
     }
 
     #[test]
-    fn test_invalid_message_format() {
-        // Setup test rings and reader
+    fn test_poll_sleeps_only_when_idle() {
         let page_size = 4096u64;
         let n_pages = 2u32;
         let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
 
         let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
-
-        // Create the reader
         let mut reader = Reader::new();
         reader
             .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
             .unwrap();
 
-        // Create the dispatcher
         let mut dispatcher = Dispatcher::new();
-
-        // Subscribe to ensure we're testing the message format check
         dispatcher.subscribe(MSG_TYPE_FOO, |_, _| {});
 
-        // Write an incomplete message (missing timestamp)
         ring.start_write_batch();
-        // Only write the message type, not the timestamp
-        let incomplete_msg = vec![1, 0, 0, 0]; // message type 1 in little-endian
-        ring.write(&incomplete_msg, PERF_RECORD_SAMPLE).unwrap();
+        for timestamp in [100, 200] {
+            write_sample(&mut ring, MSG_TYPE_FOO, timestamp, b"FOO DATA").unwrap();
+        }
         ring.finish_write_batch();
 
-        // Start reading
-        reader.start().unwrap();
+        // With events available, poll returns without sleeping
+        let idle_sleep = Duration::from_secs(5);
+        let start = std::time::Instant::now();
+        assert_eq!(
+            dispatcher
+                .poll(&mut reader, idle_sleep)
+                .unwrap()
+                .events_popped,
+            2
+        );
+        assert!(start.elapsed() < idle_sleep);
+
+        // With no events, poll sleeps for the idle duration
+        let idle_sleep = Duration::from_millis(20);
+        let start = std::time::Instant::now();
+        assert_eq!(
+            dispatcher
+                .poll(&mut reader, idle_sleep)
+                .unwrap()
+                .events_popped,
+            0
+        );
+        assert!(start.elapsed() >= idle_sleep);
+    }
 
-        // Dispatch should fail with InvalidFormat
-        let result = dispatcher.dispatch(&mut reader);
-        assert!(result.is_err());
-        match result {
-            Err(DispatchError::InvalidFormat(_)) => {}
-            _ => panic!("Expected InvalidFormat error"),
-        }
+    #[test]
+    fn test_poll_once_returns_delta() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
 
-        // Finish reading
-        reader.finish().unwrap();
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.subscribe(MSG_TYPE_FOO, |_, _| {});
+
+        let mut write = |samples: &[(u32, u64)], lost: usize| {
+            ring.start_write_batch();
+            for &(msg_type, timestamp) in samples {
+                write_sample(&mut ring, msg_type, timestamp, b"testdata").unwrap();
+            }
+            for _ in 0..lost {
+                write_lost(&mut ring, 0, 1).unwrap();
+            }
+            ring.finish_write_batch();
+        };
+
+        // Each call reports only the events written since the previous one
+        write(
+            &[(MSG_TYPE_FOO, 1), (MSG_TYPE_FOO, 2), (MSG_TYPE_BAR, 3)],
+            1,
+        );
+        let delta = dispatcher.poll_once(&mut reader).unwrap();
+        assert_eq!(delta.samples_processed, 2);
+        assert_eq!(delta.dropped_messages, 1);
+        assert_eq!(delta.lost_events_processed, 1);
+
+        write(&[(MSG_TYPE_FOO, 4)], 0);
+        let delta = dispatcher.poll_once(&mut reader).unwrap();
+        assert_eq!(delta.samples_processed, 1);
+        assert_eq!(delta.dropped_messages, 0);
+        assert_eq!(delta.lost_events_processed, 0);
+
+        let delta = dispatcher.poll_once(&mut reader).unwrap();
+        assert_eq!(delta.samples_processed, 0);
+
+        // The running totals are unaffected
+        assert_eq!(dispatcher.stats().samples_processed, 3);
+        assert_eq!(dispatcher.stats().lost_events_processed, 1);
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        let mut dispatcher = Dispatcher::new();
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        {
+            let delivered = delivered.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |_, data| {
+                let msg: &TestMessage = plain::from_bytes(data).unwrap();
+                delivered.borrow_mut().push(msg.header.timestamp);
+            });
+        }
+        let lost = Rc::new(RefCell::new(0));
+        {
+            let lost = lost.clone();
+            dispatcher.subscribe_lost_samples(move |_, _| *lost.borrow_mut() += 1);
+        }
+
+        let mut write = |timestamps: &[u64], lost: usize| {
+            ring.start_write_batch();
+            for &timestamp in timestamps {
+                write_sample(&mut ring, MSG_TYPE_FOO, timestamp, b"FOO DATA").unwrap();
+            }
+            for _ in 0..lost {
+                write_lost(&mut ring, 0, 1).unwrap();
+            }
+            ring.finish_write_batch();
+        };
+
+        write(&[1], 0);
+        dispatcher.poll_once(&mut reader).unwrap();
+        assert!(!dispatcher.is_paused());
+
+        // While paused, records are consumed and counted but not delivered
+        dispatcher.pause();
+        assert!(dispatcher.is_paused());
+        write(&[2, 3], 1);
+        let delta = dispatcher.poll_once(&mut reader).unwrap();
+        assert_eq!(delta.paused_dropped, 3);
+        assert_eq!(delta.samples_processed, 0);
+        assert_eq!(delta.lost_events_processed, 0);
+        assert_eq!(delta.dropped_messages, 0);
+        assert_eq!(*delivered.borrow(), vec![1]);
+        assert_eq!(*lost.borrow(), 0);
+        assert_eq!(
+            reader.rings()[0].kernel_head(),
+            reader.rings()[0].consumer_tail()
+        );
+
+        // Delivery picks up with the records written after resuming
+        dispatcher.resume();
+        write(&[4], 1);
+        let delta = dispatcher.poll_once(&mut reader).unwrap();
+        assert_eq!(delta.paused_dropped, 0);
+        assert_eq!(delta.samples_processed, 1);
+        assert_eq!(*delivered.borrow(), vec![1, 4]);
+        assert_eq!(*lost.borrow(), 1);
+        assert_eq!(dispatcher.stats().paused_dropped, 3);
+    }
+
+    #[test]
+    fn test_arena_resets_between_batches() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        // Each event records the arena usage it saw and the string it built there
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut dispatcher = Dispatcher::new();
+        {
+            let seen = seen.clone();
+            dispatcher.subscribe_with_arena(MSG_TYPE_FOO, move |_, data, arena| {
+                let used = arena.allocated_bytes();
+                let msg: &TestMessage = plain::from_bytes(data).unwrap();
+                let text = arena.alloc_str(std::str::from_utf8(&msg.data).unwrap());
+                seen.borrow_mut().push((used, text.to_uppercase()));
+            });
+        }
+
+        let mut write = |payloads: &[&[u8]]| {
+            ring.start_write_batch();
+            for (i, payload) in payloads.iter().enumerate() {
+                write_sample(&mut ring, MSG_TYPE_FOO, i as u64, payload).unwrap();
+            }
+            ring.finish_write_batch();
+        };
+
+        write(&[b"batch1 a", b"batch1 b"]);
+        dispatcher.poll_once(&mut reader).unwrap();
+        assert_eq!(dispatcher.arena().allocated_bytes(), 16);
+
+        write(&[b"batch2 a"]);
+        dispatcher.poll_once(&mut reader).unwrap();
+        assert_eq!(dispatcher.arena().allocated_bytes(), 8);
+
+        assert_eq!(
+            *seen.borrow(),
+            vec![
+                (0, "BATCH1 A".to_string()),
+                (8, "BATCH1 B".to_string()),
+                (0, "BATCH2 A".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dispatcher_summary() {
+        let mut dispatcher = Dispatcher::new();
+        assert_eq!(dispatcher.summary(), DispatcherSummary::default());
+
+        struct Handler;
+        impl Handler {
+            fn handle(&mut self, _ring_index: usize, _data: &[u8]) {}
+        }
+
+        dispatcher.subscribe(1, |_, _| {});
+        dispatcher.subscribe(1, |_, _| {});
+        dispatcher.subscribe(2, |_, _| {});
+        dispatcher.subscribe_method(3, Rc::new(RefCell::new(Handler)), Handler::handle);
+
+        let summary = dispatcher.summary();
+        assert_eq!(summary.sample_message_types, 3);
+        assert_eq!(summary.total_callbacks, 4);
+        assert!(!summary.has_lost_subscribers);
+        assert!(dispatcher.has_subscribers(3));
+        assert!(!dispatcher.has_subscribers(4));
+
+        dispatcher.subscribe_lost_samples(|_, _| {});
+
+        let summary = dispatcher.summary();
+        assert_eq!(summary.sample_message_types, 3);
+        assert_eq!(summary.total_callbacks, 5);
+        assert!(summary.has_lost_subscribers);
+
+        dispatcher.subscribe_unknown_records(|_, _, _| {});
+        assert_eq!(dispatcher.summary().total_callbacks, 6);
+    }
+
+    #[test]
+    fn test_dispatcher_no_subscribers() {
+        // Setup test rings and reader
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        // Create the reader
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        // Create the dispatcher
+        let mut dispatcher = Dispatcher::new();
+
+        // Write a message with no subscribers
+        ring.start_write_batch();
+        write_sample(&mut ring, 999, 100, b"UNKNOWN ").unwrap();
+        ring.finish_write_batch();
+
+        // Start reading
+        reader.start().unwrap();
+
+        // Dispatch
+        dispatcher.dispatch_all(&mut reader).unwrap();
+
+        // Check statistics
+        let stats = dispatcher.stats();
+        assert_eq!(stats.dropped_messages, 1);
+        assert_eq!(stats.samples_processed, 0);
+
+        // Finish reading
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_dispatcher_using_instance_methods() {
+        // Setup test rings and reader
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        // Create the reader
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        // Handler with instance methods
+        struct MyHandler {
+            foo_counter: usize,
+            bar_counter: usize,
+        }
+
+        impl MyHandler {
+            fn handle_foo(&mut self, _ring_index: usize, data: &[u8]) {
+                self.foo_counter += 1;
+                let msg: &TestMessage = plain::from_bytes(data).unwrap();
+                assert_eq!(&msg.data, b"FOO DATA");
+            }
+
+            fn handle_bar(&mut self, _ring_index: usize, data: &[u8]) {
+                self.bar_counter += 1;
+                let msg: &TestMessage = plain::from_bytes(data).unwrap();
+                assert_eq!(&msg.data, b"BAR DATA");
+            }
+        }
+
+        // Create handler
+        let handler = Rc::new(RefCell::new(MyHandler {
+            foo_counter: 0,
+            bar_counter: 0,
+        }));
+
+        // Create the dispatcher
+        let mut dispatcher = Dispatcher::new();
+
+        // Register instance methods as callbacks
+        {
+            let handler_clone = handler.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |idx, data| {
+                handler_clone.borrow_mut().handle_foo(idx, data);
+            });
+        }
+
+        {
+            let handler_clone = handler.clone();
+            dispatcher.subscribe(MSG_TYPE_BAR, move |idx, data| {
+                handler_clone.borrow_mut().handle_bar(idx, data);
+            });
+        }
+
+        // Write test messages
+        ring.start_write_batch();
+        write_sample(&mut ring, MSG_TYPE_FOO, 100, b"FOO DATA").unwrap();
+
+        write_sample(&mut ring, MSG_TYPE_BAR, 200, b"BAR DATA").unwrap();
+        ring.finish_write_batch();
+
+        // Start reading
+        reader.start().unwrap();
+
+        // Dispatch all events
+        dispatcher.dispatch_all(&mut reader).unwrap();
+
+        // Check handler counters
+        let handler_ref = handler.borrow();
+        assert_eq!(handler_ref.foo_counter, 1);
+        assert_eq!(handler_ref.bar_counter, 1);
+
+        // Finish reading
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_invalid_message_format() {
+        // Setup test rings and reader
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        // Create the reader
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        // Create the dispatcher
+        let mut dispatcher = Dispatcher::new();
+
+        // Subscribe to ensure we're testing the message format check
+        dispatcher.subscribe(MSG_TYPE_FOO, |_, _| {});
+
+        // Write an incomplete message (missing timestamp)
+        ring.start_write_batch();
+        // Only write the message type, not the timestamp
+        let incomplete_msg = vec![1, 0, 0, 0]; // message type 1 in little-endian
+        ring.write(&incomplete_msg, PERF_RECORD_SAMPLE).unwrap();
+        ring.finish_write_batch();
+
+        // Start reading
+        reader.start().unwrap();
+
+        // Dispatch should fail with InvalidFormat
+        let result = dispatcher.dispatch(&mut reader);
+        assert!(result.is_err());
+        match result {
+            Err(DispatchError::InvalidFormat(_)) => {}
+            _ => panic!("Expected InvalidFormat error"),
+        }
+
+        // Finish reading
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn test_dispatch_skips_overwritten_records() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        let received = Rc::new(RefCell::new(0));
+        let received_clone = received.clone();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.subscribe(MSG_TYPE_FOO, move |_, _| {
+            *received_clone.borrow_mut() += 1;
+        });
+
+        ring.start_write_batch();
+        for timestamp in [1, 2] {
+            write_sample(&mut ring, MSG_TYPE_FOO, timestamp, b"overrun!").unwrap();
+        }
+        ring.finish_write_batch();
+
+        // The writer laps the reader after the batch started
+        reader.start().unwrap();
+        let meta = data.as_mut_ptr() as *mut crate::PerfEventMmapPage;
+        unsafe {
+            (*meta).data_head.store(
+                page_size * u64::from(n_pages) + 32,
+                std::sync::atomic::Ordering::Release,
+            );
+        }
+
+        // Neither torn record is delivered, and the loss is counted
+        dispatcher.dispatch_all(&mut reader).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(*received.borrow(), 0);
+        assert_eq!(reader.ring_stats()[0].overwritten, 1);
+    }
+
+    #[test]
+    fn test_dispatcher_chunked_message() {
+        // Setup test rings and reader
+        let page_size = 4096u64;
+        let n_pages = 128u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        // Create the reader
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        // Create the dispatcher
+        let mut dispatcher = Dispatcher::new();
+
+        let payload: Vec<u8> = (0..200 * 1024).map(|i| (i % 253) as u8).collect();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        {
+            let received = received.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |_, data| {
+                received.borrow_mut().push(data.to_vec());
+            });
+        }
+
+        // Write a complete chunked message, then one missing its middle chunk
+        ring.start_write_batch();
+        crate::write_chunked(&mut ring, MSG_TYPE_FOO, 100, 1, &payload).unwrap();
+        crate::write_chunked_with_fragment_size(&mut ring, MSG_TYPE_FOO, 200, 2, &[1u8; 48], 16)
+            .unwrap();
+        ring.finish_write_batch();
+
+        // Skip the middle chunk of the second message to simulate a lost chunk
+        reader.start().unwrap();
+        let mut event_index = 0;
+        while !reader.is_empty() {
+            // Events 0..4 are the first message, 4..7 the second
+            if event_index == 5 {
+                reader.pop().unwrap();
+            } else {
+                dispatcher.dispatch(&mut reader).unwrap();
+            }
+            event_index += 1;
+        }
+        reader.finish().unwrap();
+
+        let received = received.borrow();
+        assert_eq!(received.len(), 1);
+        let header: &SampleHeader = plain::from_bytes(&received[0]).unwrap();
+        assert_eq!(header.type_, MSG_TYPE_FOO);
+        assert_eq!(header.timestamp, 100);
+        assert_eq!(&received[0][size_of::<SampleHeader>()..], &payload[..]);
+
+        let stats = dispatcher.stats();
+        assert_eq!(stats.samples_processed, 1);
+        assert_eq!(stats.chunk_errors, 1);
+    }
+
+    #[test]
+    fn test_lost_record_discards_partial_message() {
+        let page_size = 4096u64;
+        let n_pages = 16u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        let mut dispatcher = Dispatcher::new();
+        let received = Rc::new(RefCell::new(0));
+        {
+            let received = received.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |_, _| *received.borrow_mut() += 1);
+        }
+
+        // A message whose last chunk was lost, followed by the lost record
+        ring.start_write_batch();
+        crate::write_chunked_with_fragment_size(&mut ring, MSG_TYPE_FOO, 100, 1, &[1u8; 48], 16)
+            .unwrap();
+        ring.finish_write_batch();
+
+        reader.start().unwrap();
+        let mut event_index = 0;
+        while !reader.is_empty() {
+            if event_index == 2 {
+                reader.pop().unwrap();
+            } else {
+                dispatcher.dispatch(&mut reader).unwrap();
+            }
+            event_index += 1;
+        }
+        reader.finish().unwrap();
+        assert_eq!(dispatcher.chunks.pending_count(), 1);
+
+        dispatcher
+            .dispatch_record(0, PerfRecordType::Lost, &lost_record(0, 1))
+            .unwrap();
+        assert_eq!(dispatcher.chunks.pending_count(), 0);
+        assert_eq!(dispatcher.stats().chunk_errors, 1);
+        assert_eq!(*received.borrow(), 0);
+    }
+
+    #[test]
+    fn test_dispatch_record_without_ring() {
+        let mut dispatcher = Dispatcher::new();
+
+        let received = Rc::new(RefCell::new(Vec::new()));
+        {
+            let received = received.clone();
+            dispatcher.subscribe(MSG_TYPE_FOO, move |ring_index, data| {
+                received.borrow_mut().push((ring_index, data.to_vec()));
+            });
+        }
+        let lost = Rc::new(RefCell::new(0));
+        {
+            let lost = lost.clone();
+            dispatcher.subscribe_lost_samples(move |_, _| *lost.borrow_mut() += 1);
+        }
+
+        // Records carry the same bytes subscribers receive
+        let bytes = sample_record(&sample_payload(MSG_TYPE_FOO, 42, b"RECORDED"));
+        dispatcher
+            .dispatch_record(3, PerfRecordType::Sample, &bytes)
+            .unwrap();
+        dispatcher
+            .dispatch_record(1, PerfRecordType::Lost, &lost_record(0, 1))
+            .unwrap();
+        dispatcher
+            .dispatch_record(0, PerfRecordType::Unknown(12345), &[])
+            .unwrap();
+
+        // Samples too short for a header are rejected
+        assert!(dispatcher
+            .dispatch_record(0, PerfRecordType::Sample, &[0u8; 4])
+            .is_err());
+
+        assert_eq!(*received.borrow(), vec![(3, bytes)]);
+        assert_eq!(*lost.borrow(), 1);
+
+        let stats = dispatcher.stats();
+        assert_eq!(stats.samples_processed, 1);
+        assert_eq!(stats.lost_events_processed, 1);
+        assert_eq!(stats.dropped_messages, 1);
+        assert_eq!(stats.unknown_records, 1);
+
+        // Types without deliveries are listed too
+        dispatcher.subscribe(MSG_TYPE_BAR, |_, _| {});
+        assert_eq!(
+            dispatcher.delivered_by_type(),
+            vec![(MSG_TYPE_FOO, 1), (MSG_TYPE_BAR, 0)]
+        );
+    }
+
+    #[test]
+    fn test_unknown_record_routing() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        let mut dispatcher = Dispatcher::new();
+
+        // Without subscribers, unknown records are counted as dropped
+        dispatcher
+            .dispatch_record(0, PerfRecordType::Throttle, &[0u8; 24])
+            .unwrap();
+        let stats = dispatcher.stats();
+        assert_eq!(stats.unknown_records, 1);
+        assert_eq!(stats.dropped_messages, 1);
+
+        let received = Rc::new(RefCell::new(Vec::new()));
+        {
+            let received = received.clone();
+            dispatcher.subscribe_unknown_records(move |ring_index, record_type, data| {
+                received
+                    .borrow_mut()
+                    .push((ring_index, record_type, data.to_vec()));
+            });
+        }
+
+        // A type value the kernel may add later reaches the subscriber from the ring
+        ring.start_write_batch();
+        ring.write(b"NEWTYPE!", 4242).unwrap();
+        write_sample(&mut ring, MSG_TYPE_FOO, 100, b"SAMPLE..").unwrap();
+        ring.finish_write_batch();
+        reader.start().unwrap();
+        dispatcher.dispatch_all(&mut reader).unwrap();
+        reader.finish().unwrap();
+
+        assert_eq!(
+            *received.borrow(),
+            vec![(0, PerfRecordType::Unknown(4242), b"NEWTYPE!".to_vec())]
+        );
+        let stats = dispatcher.stats();
+        assert_eq!(stats.unknown_records, 2);
+        // The sample has no subscribers and is dropped as before
+        assert_eq!(stats.dropped_messages, 2);
+        assert_eq!(stats.samples_processed, 0);
+    }
+
+    #[test]
+    fn test_dedup_after_resync() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.enable_dedup(MSG_TYPE_FOO);
+
+        let received = Rc::new(RefCell::new(Vec::new()));
+        for message_type in [MSG_TYPE_FOO, MSG_TYPE_BAR] {
+            let received = received.clone();
+            dispatcher.subscribe(message_type, move |_, data| {
+                let header: &SampleHeader = plain::from_bytes(data).unwrap();
+                received.borrow_mut().push((header.type_, header.timestamp));
+            });
+        }
+
+        let batch = [
+            sample_payload(MSG_TYPE_FOO, 100, b"FOO_0100"),
+            sample_payload(MSG_TYPE_FOO, 200, b"FOO_0200"),
+            sample_payload(MSG_TYPE_BAR, 150, b"BAR_0150"),
+        ];
+
+        // First delivery
+        ring.start_write_batch();
+        for msg in &batch {
+            ring.write(msg, PERF_RECORD_SAMPLE).unwrap();
+        }
+        ring.finish_write_batch();
+        reader.start().unwrap();
+        dispatcher.dispatch_all(&mut reader).unwrap();
+        reader.finish().unwrap();
+
+        // After a resync the batch is delivered again, followed by a new record
+        ring.start_write_batch();
+        for msg in &batch {
+            ring.write(msg, PERF_RECORD_SAMPLE).unwrap();
+        }
+        write_sample(&mut ring, MSG_TYPE_FOO, 200, b"FOO_0201").unwrap();
+        ring.finish_write_batch();
+        reader.start().unwrap();
+        dispatcher.dispatch_all(&mut reader).unwrap();
+        reader.finish().unwrap();
+
+        // FOO records arrive exactly once, BAR is not deduplicated
+        let mut received = received.borrow().clone();
+        received.sort();
+        assert_eq!(
+            received,
+            vec![
+                (MSG_TYPE_FOO, 100),
+                (MSG_TYPE_FOO, 200),
+                (MSG_TYPE_FOO, 200),
+                (MSG_TYPE_BAR, 150),
+                (MSG_TYPE_BAR, 150),
+            ]
+        );
+
+        let stats = dispatcher.stats();
+        assert_eq!(stats.duplicates_dropped, 2);
+        assert_eq!(stats.samples_processed, 5);
+    }
+
+    /// Fields of the spans and events emitted while a test runs
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct TraceCapture {
+        open: std::sync::Arc<std::sync::Mutex<HashMap<u64, (String, TraceFields)>>>,
+        closed: std::sync::Arc<std::sync::Mutex<Vec<(String, TraceFields)>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct TraceFields(std::collections::BTreeMap<String, String>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for TraceFields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for TraceCapture {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = TraceFields::default();
+            attrs.record(&mut fields);
+            let name = attrs.metadata().name().to_string();
+            self.open
+                .lock()
+                .unwrap()
+                .insert(id.into_u64(), (name, fields));
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if let Some((_, fields)) = self.open.lock().unwrap().get_mut(&id.into_u64()) {
+                values.record(fields);
+            }
+        }
+
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = TraceFields::default();
+            event.record(&mut fields);
+            self.closed
+                .lock()
+                .unwrap()
+                .push(("event".to_string(), fields));
+        }
+
+        fn on_close(&self, id: tracing::span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            if let Some(span) = self.open.lock().unwrap().remove(&id.into_u64()) {
+                self.closed.lock().unwrap().push(span);
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_spans() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.subscribe(MSG_TYPE_FOO, |_, _| {});
+        dispatcher.subscribe(MSG_TYPE_BAR, |_, _| {});
+
+        ring.start_write_batch();
+        for (msg_type, timestamp) in [(MSG_TYPE_FOO, 1), (MSG_TYPE_BAR, 2), (MSG_TYPE_FOO, 3)] {
+            write_sample(&mut ring, msg_type, timestamp, b"testdata").unwrap();
+        }
+        write_lost(&mut ring, 0, 1).unwrap();
+        ring.finish_write_batch();
+
+        let capture = TraceCapture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            dispatcher.poll_once(&mut reader).unwrap();
+        });
+
+        let closed = capture.closed.lock().unwrap();
+        let find = |name: &str| {
+            &closed
+                .iter()
+                .find(|(span_name, _)| span_name == name)
+                .unwrap_or_else(|| panic!("no {} captured", name))
+                .1
+                 .0
+        };
+
+        // Three samples padded to 24 bytes and a 16 byte lost record
+        let dispatch = find("dispatch_all");
+        assert_eq!(dispatch["events"], "4");
+        assert_eq!(dispatch["bytes"], "88");
+        assert_eq!(dispatch["samples"], "3");
+        assert_eq!(dispatch["lost_events"], "1");
+        assert_eq!(dispatch["dropped"], "0");
+        assert_eq!(dispatch["per_type"], "{1: 2, 2: 1}");
+        assert!(dispatch.contains_key("duration_us"));
+
+        let batch = find("reader_batch");
+        assert_eq!(batch["rings"], "1");
+        assert_eq!(batch["records"], "4");
+        assert_eq!(batch["lost_records"], "1");
+        assert_eq!(batch["overwritten"], "0");
+
+        let lost = find("event");
+        assert_eq!(lost["message"], "lost record");
+        assert_eq!(lost["ring_index"], "0");
+    }
+
+    #[test]
+    fn test_error_classification() {
+        use collector_errors::{Classified, ErrorCode};
+
+        // Errors from the reader and the rings keep their class when dispatching
+        let err = DispatchError::from(Reader::new().start().unwrap_err());
+        assert_eq!(err.code(), ErrorCode::Internal);
+        assert_eq!(
+            DispatchError::from(ReaderError::from(PerfRingError::Overwritten)).code(),
+            ErrorCode::RingLost
+        );
+        assert!(DispatchError::RingError(PerfRingError::Overwritten).retryable());
+        assert_eq!(
+            DispatchError::InvalidFormat("short".to_string()).code(),
+            ErrorCode::RingCorrupt
+        );
+        assert_eq!(
+            PerfRingError::InvalidBufferLength.code(),
+            ErrorCode::RingSetup
+        );
+        assert!(!PerfRingError::InvalidBufferLength.retryable());
     }
 }
@@ -0,0 +1,96 @@
+//! A single error type for code driving storage, rings, readers and the
+//! dispatcher together.
+//!
+//! Each stage keeps its own error, and [`PerfError`] wraps whichever one
+//! occurred, so a caller composing the stages can use `?` throughout and
+//! still match on the stage that failed.
+
+use collector_errors::{Classified, ErrorCode};
+use thiserror::Error;
+
+use crate::{DispatchError, PerfRingError, ReaderError, StorageError};
+
+/// Any error from the perf ring pipeline
+#[derive(Error, Debug)]
+pub enum PerfError {
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("ring error: {0}")]
+    Ring(#[from] PerfRingError),
+
+    #[error("reader error: {0}")]
+    Reader(#[from] ReaderError),
+
+    #[error("dispatch error: {0}")]
+    Dispatch(#[from] DispatchError),
+}
+
+impl Classified for PerfError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            PerfError::Storage(e) => e.code(),
+            PerfError::Ring(e) => e.code(),
+            PerfError::Reader(e) => e.code(),
+            PerfError::Dispatch(e) => e.code(),
+        }
+    }
+}
+
+/// Result of the perf ring pipeline's operations
+pub type Result<T> = std::result::Result<T, PerfError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Dispatcher, MemoryStorage, PerfRecordType, PerfRing, Reader, SampleHeader, Storage,
+    };
+
+    /// Open a memory ring, run a read batch on it, and dispatch `record`
+    fn read_and_dispatch(n_pages: u32, add_ring: bool, record: &[u8]) -> Result<()> {
+        let mut storage = MemoryStorage::new(n_pages)?;
+        let (n_pages, page_size) = (storage.num_data_pages(), storage.page_size());
+        let mut reader = Reader::new();
+        if add_ring {
+            reader.add_ring(unsafe {
+                PerfRing::init_contiguous(storage.data_mut(), n_pages, page_size)?
+            })?;
+        }
+        reader.start()?;
+        reader.finish()?;
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.dispatch_record(0, PerfRecordType::Sample, record)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_composed_errors() {
+        let sample = vec![0u8; std::mem::size_of::<SampleHeader>()];
+        assert!(read_and_dispatch(2, true, &sample).is_ok());
+
+        // Each stage's error comes out as its own variant
+        let err = read_and_dispatch(3, true, &sample).unwrap_err();
+        assert!(matches!(
+            err,
+            PerfError::Ring(PerfRingError::InvalidBufferLength)
+        ));
+        assert_eq!(err.code(), ErrorCode::RingSetup);
+
+        let err = read_and_dispatch(2, false, &sample).unwrap_err();
+        assert!(matches!(err, PerfError::Reader(ReaderError::NoRings)));
+
+        let err = read_and_dispatch(2, true, &sample[..4]).unwrap_err();
+        assert!(matches!(
+            err,
+            PerfError::Dispatch(DispatchError::InvalidFormat(_))
+        ));
+        assert_eq!(err.code(), ErrorCode::RingCorrupt);
+        assert!(err.to_string().starts_with("dispatch error: "));
+
+        let err: PerfError = StorageError::OsError(std::io::Error::other("mmap failed")).into();
+        assert!(matches!(err, PerfError::Storage(_)));
+        assert_eq!(err.code(), ErrorCode::PerfOpen);
+    }
+}
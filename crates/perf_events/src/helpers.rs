@@ -3,10 +3,13 @@
 //! This module provides functions for opening perf events and
 //! setting them up for use with eBPF maps.
 
-use libbpf_rs::{MapCore as _, MapMut};
+use collector_errors::{Classified, ErrorCode};
+use libbpf_rs::{MapCore, MapMut};
 use perf_event_open_sys as sys;
 use std::io;
 
+use crate::{open_perf_event, PerfEventArray, PerfEventAttrBuilder};
+
 /// Error type for perf event operations
 #[derive(Debug, thiserror::Error)]
 pub enum PerfEventError {
@@ -35,6 +38,32 @@ pub enum PerfEventError {
     /// Error getting map info
     #[error("failed to get map info: {0}")]
     MapInfoError(libbpf_rs::Error),
+
+    /// The map is not a perf event array
+    #[error("map {map} has type {actual}, expected PerfEventArray")]
+    WrongMapType {
+        /// Name of the map
+        map: String,
+        /// Actual map type
+        actual: String,
+    },
+
+    /// The map has fewer entries than there are possible CPUs
+    #[error("map {map} has max_entries {max_entries}, expected at least {num_cpus} (one per possible CPU)")]
+    TooFewEntries {
+        /// Name of the map
+        map: String,
+        /// The map's max_entries
+        max_entries: u32,
+        /// Number of possible CPUs
+        num_cpus: usize,
+    },
+}
+
+impl Classified for PerfEventError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::PerfOpen
+    }
 }
 
 /// Opens perf events for each CPU and returns a vector of file descriptors.
@@ -87,11 +116,11 @@ pub fn open_perf_events(
     Ok(fds)
 }
 
-/// Updates a map with file descriptors for each CPU.
+/// Updates a perf event array with file descriptors for each CPU.
 ///
 /// # Arguments
 ///
-/// * `map` - A mutable reference to a libbpf-rs map to store the file descriptors
+/// * `array` - The validated perf event array to store the file descriptors in
 /// * `fds` - Vector of file descriptors to store in the map
 ///
 /// # Returns
@@ -99,20 +128,13 @@ pub fn open_perf_events(
 /// * `Ok(())` on success
 /// * `Err(PerfEventError)` on failure
 ///
-pub fn update_map_with_fds(map: &mut MapMut, fds: &[i32]) -> Result<(), PerfEventError> {
+pub fn update_map_with_fds<M: MapCore>(
+    array: &mut PerfEventArray<'_, M>,
+    fds: &[i32],
+) -> Result<(), PerfEventError> {
     for (cpu, &fd) in fds.iter().enumerate() {
-        // Store FD in map
-        // Convert cpu to u32 for key and fd to u32 for value
-        let key = (cpu as u32).to_le_bytes();
-        let value = (fd as u32).to_le_bytes();
-
-        if let Err(err) = map.update(&key, &value, libbpf_rs::MapFlags::ANY) {
-            // Don't close FDs here as they are still owned by the caller
-            return Err(PerfEventError::MapUpdateError {
-                cpu: cpu as i32,
-                source: err,
-            });
-        }
+        // Don't close FDs on error as they are still owned by the caller
+        array.set_fd(cpu, fd)?;
     }
 
     Ok(())
@@ -164,17 +186,14 @@ pub fn open_events(
     map: &mut MapMut,
     attr: &mut sys::bindings::perf_event_attr,
 ) -> Result<(), PerfEventError> {
-    // Determine number of CPUs from map max entries
-    let n_cpu = map
-        .info()
-        .map(|info| info.info.max_entries as i32)
-        .map_err(|e| PerfEventError::MapInfoError(e))?;
+    // Check the map has an entry for every possible CPU
+    let mut array = PerfEventArray::new(&*map)?;
 
     // Open perf events for each CPU and get file descriptors
-    let fds = open_perf_events(n_cpu, attr)?;
+    let fds = open_perf_events(array.num_cpus() as i32, attr)?;
 
     // Update the map with the file descriptors
-    match update_map_with_fds(map, &fds) {
+    match update_map_with_fds(&mut array, &fds) {
         Ok(()) => Ok(()),
         Err(e) => {
             // Clean up file descriptors on error
@@ -203,6 +222,9 @@ pub enum HardwareCounter {
 
 /// Opens a hardware performance counter for each CPU and updates the provided map with the file descriptors.
 ///
+/// This is a convenience wrapper around [`open_perf_event`]; use
+/// [`PerfEventAttrBuilder`] directly for other attributes.
+///
 /// # Arguments
 ///
 /// * `map` - A mutable reference to a libbpf-rs map to store the file descriptors
@@ -237,33 +259,49 @@ pub fn open_perf_counter(
     map: &mut MapMut,
     counter_type: HardwareCounter,
 ) -> Result<(), PerfEventError> {
-    // Create and configure perf event attributes
-    let mut attr = sys::bindings::perf_event_attr::default();
-    attr.size = std::mem::size_of::<sys::bindings::perf_event_attr>() as u32;
+    open_perf_event(map, PerfEventAttrBuilder::hardware(counter_type).build())
+}
 
-    // Set common attributes
-    attr.type_ = sys::bindings::PERF_TYPE_HARDWARE;
-    attr.read_format = (sys::bindings::PERF_FORMAT_TOTAL_TIME_ENABLED
-        | sys::bindings::PERF_FORMAT_TOTAL_TIME_RUNNING) as u64;
+/// Checks whether a hardware counter can be opened on this machine.
+///
+/// The counter is opened for the calling thread on any CPU with kernel
+/// events excluded, so the probe succeeds without privileges on systems
+/// where `perf_event_paranoid` allows user-space profiling. The event is
+/// closed immediately.
+///
+/// # Returns
+///
+/// * `Ok(())` if the counter could be opened
+/// * `Err(PerfEventError::OpenError)` with the OS error otherwise (`cpu` is -1)
+pub fn probe_perf_counter(counter_type: HardwareCounter) -> Result<(), PerfEventError> {
+    let mut attr = PerfEventAttrBuilder::hardware(counter_type)
+        .disabled(true)
+        .exclude_kernel(true)
+        .exclude_hv(true)
+        .build();
 
-    // Set counter-specific configuration
-    match counter_type {
-        HardwareCounter::Cycles => {
-            attr.config = sys::bindings::PERF_COUNT_HW_CPU_CYCLES as u64;
-        }
-        HardwareCounter::Instructions => {
-            attr.config = sys::bindings::PERF_COUNT_HW_INSTRUCTIONS as u64;
-        }
-        HardwareCounter::LLCMisses => {
-            attr.config = sys::bindings::PERF_COUNT_HW_CACHE_MISSES as u64;
-        }
-        HardwareCounter::CacheReferences => {
-            attr.config = sys::bindings::PERF_COUNT_HW_CACHE_REFERENCES as u64;
-        }
+    let fd = unsafe {
+        sys::perf_event_open(
+            &mut attr,
+            0,  // pid (calling thread)
+            -1, // cpu (any)
+            -1, // group_fd
+            sys::bindings::PERF_FLAG_FD_CLOEXEC as u64,
+        )
+    };
+
+    if fd < 0 {
+        return Err(PerfEventError::OpenError {
+            cpu: -1,
+            source: io::Error::last_os_error(),
+        });
     }
 
-    // Open the events
-    open_events(map, &mut attr)
+    unsafe {
+        libc::close(fd);
+    }
+
+    Ok(())
 }
 
 /// Enables all perf events stored in the map.
@@ -277,27 +315,17 @@ pub fn open_perf_counter(
 /// * `Ok(())` on success
 /// * `Err(PerfEventError)` on failure
 pub fn start_events(map: &MapMut) -> Result<(), PerfEventError> {
-    // Determine number of CPUs from map max entries
-    let n_cpu = map
-        .info()
-        .map(|info| info.info.max_entries as i32)
-        .map_err(|e| PerfEventError::MapInfoError(e))?;
+    // Check the map has an entry for every possible CPU
+    let array = PerfEventArray::new(map)?;
 
     // Iterate through each CPU's file descriptor and enable the perf event
-    for cpu in 0..n_cpu {
-        let key = (cpu as u32).to_le_bytes();
+    for cpu in 0..array.num_cpus() {
+        if let Ok(Some(fd)) = array.get_fd(cpu) {
+            // Enable the perf event
+            let ret = unsafe { libc::ioctl(fd, sys::bindings::ENABLE as libc::c_ulong, 0) };
 
-        if let Some(value) = map.lookup(&key, libbpf_rs::MapFlags::ANY).unwrap_or(None) {
-            if value.len() >= 4 {
-                // Convert bytes to file descriptor
-                let fd = u32::from_le_bytes([value[0], value[1], value[2], value[3]]) as i32;
-
-                // Enable the perf event
-                let ret = unsafe { libc::ioctl(fd, sys::bindings::ENABLE as libc::c_ulong, 0) };
-
-                if ret < 0 {
-                    return Err(PerfEventError::EnableError(io::Error::last_os_error()));
-                }
+            if ret < 0 {
+                return Err(PerfEventError::EnableError(io::Error::last_os_error()));
             }
         }
     }
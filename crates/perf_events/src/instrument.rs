@@ -0,0 +1,130 @@
+//! Tracing spans and events for read batches and dispatch, compiled only with
+//! the `tracing` feature
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use tracing::field::{debug, Empty};
+use tracing::Span;
+
+use crate::{RingStats, Stats};
+
+/// Sum of the counters of all rings
+pub(crate) fn total_ring_stats(ring_stats: &[RingStats]) -> RingStats {
+    ring_stats
+        .iter()
+        .fold(RingStats::default(), |total, stats| RingStats {
+            records: total.records + stats.records,
+            lost_records: total.lost_records + stats.lost_records,
+            lost_samples: total.lost_samples + stats.lost_samples,
+            overwritten: total.overwritten + stats.overwritten,
+            corrupt: total.corrupt + stats.corrupt,
+        })
+}
+
+/// Span covering a reader batch, from `Reader::start` to `Reader::finish`
+pub(crate) struct ReaderBatchSpan {
+    span: Span,
+    started: Instant,
+    before: RingStats,
+}
+
+impl ReaderBatchSpan {
+    pub(crate) fn start(rings: usize, before: RingStats) -> Self {
+        let span = tracing::debug_span!(
+            "reader_batch",
+            rings,
+            records = Empty,
+            lost_records = Empty,
+            lost_samples = Empty,
+            overwritten = Empty,
+            duration_us = Empty,
+        );
+        Self {
+            span,
+            started: Instant::now(),
+            before,
+        }
+    }
+
+    /// Record what the batch consumed and close the span
+    pub(crate) fn finish(self, after: RingStats) {
+        let span = &self.span;
+        span.record("records", after.records - self.before.records);
+        span.record(
+            "lost_records",
+            after.lost_records - self.before.lost_records,
+        );
+        span.record(
+            "lost_samples",
+            after.lost_samples - self.before.lost_samples,
+        );
+        span.record("overwritten", after.overwritten - self.before.overwritten);
+        span.record("duration_us", self.started.elapsed().as_micros() as u64);
+    }
+}
+
+/// Bytes and messages per type delivered during one `dispatch_all`
+#[derive(Debug, Default)]
+pub(crate) struct DispatchCounts {
+    bytes: usize,
+    per_type: BTreeMap<u32, usize>,
+}
+
+impl DispatchCounts {
+    pub(crate) fn record(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    pub(crate) fn deliver(&mut self, message_type: u32) {
+        *self.per_type.entry(message_type).or_insert(0) += 1;
+    }
+}
+
+/// Span covering one `Dispatcher::dispatch_all`
+pub(crate) struct DispatchSpan {
+    span: Span,
+    started: Instant,
+    before: Stats,
+}
+
+impl DispatchSpan {
+    pub(crate) fn start(before: Stats) -> Self {
+        let span = tracing::debug_span!(
+            "dispatch_all",
+            events = Empty,
+            bytes = Empty,
+            samples = Empty,
+            lost_events = Empty,
+            dropped = Empty,
+            chunk_errors = Empty,
+            duplicates = Empty,
+            per_type = Empty,
+            duration_us = Empty,
+        );
+        Self {
+            span,
+            started: Instant::now(),
+            before,
+        }
+    }
+
+    pub(crate) fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Record the batch's counts as deltas of the dispatcher stats and close the span
+    pub(crate) fn finish(self, events: usize, after: Stats, counts: &DispatchCounts) {
+        let delta = after.since(&self.before);
+        let span = &self.span;
+        span.record("events", events);
+        span.record("bytes", counts.bytes);
+        span.record("samples", delta.samples_processed);
+        span.record("lost_events", delta.lost_events_processed);
+        span.record("dropped", delta.dropped_messages);
+        span.record("chunk_errors", delta.chunk_errors);
+        span.record("duplicates", delta.duplicates_dropped);
+        span.record("per_type", debug(&counts.per_type));
+        span.record("duration_us", self.started.elapsed().as_micros() as u64);
+    }
+}
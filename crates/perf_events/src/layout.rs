@@ -0,0 +1,212 @@
+//! Startup check of the sample layout shared with the BPF programs.
+//!
+//! Message structs are generated from C headers and read with `Plain`, so a
+//! layout that drifted from what the BPF programs write is not detected when
+//! reading: timestamps and types are silently misread. [`SampleLayout`]
+//! compares the sizes and offsets the reader relies on against expected
+//! values, so a mismatch fails when the dispatcher is created.
+
+use std::mem::{offset_of, size_of};
+use thiserror::Error;
+
+use crate::SampleHeader;
+
+/// A mismatch between the expected and actual sample layout
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    #[error("SampleHeader is {actual} bytes, expected {expected}")]
+    HeaderSize { expected: usize, actual: usize },
+
+    #[error("SampleHeader.type_ is at offset {actual}, expected {expected}")]
+    TypeOffset { expected: usize, actual: usize },
+
+    #[error("SampleHeader.timestamp is at offset {actual}, expected {expected}")]
+    TimestampOffset { expected: usize, actual: usize },
+
+    #[error("message {name} is {actual} bytes, expected {expected}")]
+    MessageSize {
+        name: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("message {name} has its timestamp at offset {actual}, expected {expected} as in SampleHeader")]
+    MessageTimestampOffset {
+        name: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// Size and timestamp offset of a message type, with the size it must have
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageLayout {
+    pub name: &'static str,
+    pub size: usize,
+    pub timestamp_offset: usize,
+    pub expected_size: usize,
+}
+
+/// Expected layout of samples and of the message types read from them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleLayout {
+    /// Expected size of `SampleHeader`
+    pub header_size: usize,
+    /// Expected offset of `SampleHeader::type_`
+    pub type_offset: usize,
+    /// Expected offset of `SampleHeader::timestamp`
+    pub timestamp_offset: usize,
+    /// Message types to check
+    pub messages: Vec<MessageLayout>,
+}
+
+impl SampleLayout {
+    /// Size of the header the BPF programs write, see `struct sample_header`
+    pub const HEADER_SIZE: usize = 16;
+    /// Offset of the message type in the header
+    pub const TYPE_OFFSET: usize = 4;
+    /// Offset of the timestamp in the header
+    pub const TIMESTAMP_OFFSET: usize = 8;
+
+    /// Add a message type `T` that must be `expected_size` bytes, with its
+    /// timestamp at `timestamp_offset` like in the `SampleHeader`
+    pub fn with_message<T>(
+        mut self,
+        name: &'static str,
+        timestamp_offset: usize,
+        expected_size: usize,
+    ) -> Self {
+        self.messages.push(MessageLayout {
+            name,
+            size: size_of::<T>(),
+            timestamp_offset,
+            expected_size,
+        });
+        self
+    }
+
+    /// Check the `SampleHeader` and every message type against the expected layout
+    pub fn validate(&self) -> Result<(), LayoutError> {
+        let header_size = size_of::<SampleHeader>();
+        if header_size != self.header_size {
+            return Err(LayoutError::HeaderSize {
+                expected: self.header_size,
+                actual: header_size,
+            });
+        }
+        let type_offset = offset_of!(SampleHeader, type_);
+        if type_offset != self.type_offset {
+            return Err(LayoutError::TypeOffset {
+                expected: self.type_offset,
+                actual: type_offset,
+            });
+        }
+        let timestamp_offset = offset_of!(SampleHeader, timestamp);
+        if timestamp_offset != self.timestamp_offset {
+            return Err(LayoutError::TimestampOffset {
+                expected: self.timestamp_offset,
+                actual: timestamp_offset,
+            });
+        }
+
+        for message in &self.messages {
+            if message.size != message.expected_size {
+                return Err(LayoutError::MessageSize {
+                    name: message.name,
+                    expected: message.expected_size,
+                    actual: message.size,
+                });
+            }
+            if message.timestamp_offset != timestamp_offset {
+                return Err(LayoutError::MessageTimestampOffset {
+                    name: message.name,
+                    expected: timestamp_offset,
+                    actual: message.timestamp_offset,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for SampleLayout {
+    fn default() -> Self {
+        Self {
+            header_size: Self::HEADER_SIZE,
+            type_offset: Self::TYPE_OFFSET,
+            timestamp_offset: Self::TIMESTAMP_OFFSET,
+            messages: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dispatcher;
+
+    #[repr(C)]
+    struct TestMsg {
+        header: SampleHeader,
+        pid: u32,
+        value: u64,
+    }
+
+    fn layout(expected_size: usize) -> SampleLayout {
+        SampleLayout::default().with_message::<TestMsg>(
+            "test_msg",
+            offset_of!(TestMsg, header) + offset_of!(SampleHeader, timestamp),
+            expected_size,
+        )
+    }
+
+    #[test]
+    fn test_layout_validation() {
+        assert_eq!(layout(32).validate(), Ok(()));
+        assert!(Dispatcher::with_layout(&layout(32)).is_ok());
+
+        // A changed message size fails
+        assert_eq!(
+            layout(28).validate(),
+            Err(LayoutError::MessageSize {
+                name: "test_msg",
+                expected: 28,
+                actual: 32,
+            })
+        );
+        assert!(matches!(
+            Dispatcher::with_layout(&layout(28)),
+            Err(LayoutError::MessageSize { .. })
+        ));
+
+        // So does a changed header
+        let moved_timestamp = SampleLayout {
+            timestamp_offset: 12,
+            ..layout(32)
+        };
+        assert_eq!(
+            moved_timestamp.validate(),
+            Err(LayoutError::TimestampOffset {
+                expected: 12,
+                actual: 8,
+            })
+        );
+        let larger_header = SampleLayout {
+            header_size: 24,
+            ..layout(32)
+        };
+        assert!(matches!(
+            larger_header.validate(),
+            Err(LayoutError::HeaderSize { .. })
+        ));
+
+        // A message whose header is not at the start reads the wrong timestamp
+        let misplaced = SampleLayout::default().with_message::<TestMsg>("test_msg", 16, 32);
+        let err = misplaced.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "message test_msg has its timestamp at offset 16, expected 8 as in SampleHeader"
+        );
+    }
+}
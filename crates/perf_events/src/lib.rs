@@ -5,24 +5,50 @@
 //! eBPF programs.
 //!
 
+mod arena;
+mod capture;
+mod chunk;
+mod dedup;
 mod dispatcher;
+mod error;
 mod helpers;
+#[cfg(feature = "tracing")]
+mod instrument;
+mod layout;
 mod map_reader;
 mod memory_storage;
 #[cfg(target_os = "linux")]
 mod mmap_storage;
+mod perf_event_array;
+mod perf_event_attr;
 mod reader;
 mod ring;
+mod ring_index;
+mod ring_source;
+mod tournament;
+mod wire;
 
+pub use arena::*;
+pub use capture::*;
+pub use chunk::*;
+pub use dedup::{fnv1a, DEFAULT_DEDUP_WINDOW};
 pub use dispatcher::*;
+pub use error::{PerfError, Result};
 pub use helpers::*;
+pub use layout::*;
 pub use map_reader::*;
 pub use memory_storage::*;
 #[cfg(target_os = "linux")]
 pub use mmap_storage::*;
+pub use perf_event_array::*;
+pub use perf_event_attr::*;
 pub use reader::*;
 pub use ring::*;
+pub use ring_index::*;
+pub use ring_source::*;
+pub use wire::*;
 
+use collector_errors::{Classified, ErrorCode};
 use std::os::unix::io::RawFd;
 use thiserror::Error;
 
@@ -33,6 +59,12 @@ pub enum StorageError {
     OsError(std::io::Error),
 }
 
+impl Classified for StorageError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::PerfOpen
+    }
+}
+
 /// Perf ring buffer storage trait
 pub trait Storage {
     /// Return the raw data buffer containing metadata page and data pages
@@ -46,4 +78,26 @@ pub trait Storage {
 
     /// Return the file descriptor if this is a perf event storage, or -1 otherwise
     fn file_descriptor(&self) -> RawFd;
+
+    /// Return only the data pages, without the metadata page
+    ///
+    /// The data starts at the metadata page's `data_offset`, or right after
+    /// the metadata page on older kernels that leave it zero.
+    fn data_region(&self) -> &[u8] {
+        let data = self.data();
+        let offset_field = std::mem::offset_of!(PerfEventMmapPage, data_offset);
+        let data_offset = data
+            .get(offset_field..offset_field + 8)
+            .map_or(0, |bytes| u64::from_ne_bytes(bytes.try_into().unwrap()));
+        let start = if data_offset == 0 {
+            self.page_size()
+        } else {
+            data_offset
+        };
+        let len = u64::from(self.num_data_pages()) * self.page_size();
+
+        let start = (start as usize).min(data.len());
+        let end = start.saturating_add(len as usize).min(data.len());
+        &data[start..end]
+    }
 }
@@ -2,14 +2,41 @@
 //!
 //! This module provides a PerfMapReader type that manages memory-mapped perf
 //! ring buffers connected to an eBPF map.
+//!
+//! # Events map sizing
+//!
+//! The events map must be a `BPF_MAP_TYPE_PERF_EVENT_ARRAY` with `max_entries`
+//! of at least the number of possible CPUs, so every CPU the BPF program runs
+//! on has a ring to output to. Construction checks this up front and fails
+//! with [`PerfEventError::TooFewEntries`] naming both numbers. With
+//! [`PerfMapReaderOptions::allow_partial`] set, the reader instead proceeds
+//! with the CPUs that have an entry, and CPUs whose ring cannot be opened are
+//! skipped rather than failing construction. [`PerfMapReader::cpu_setup`]
+//! reports the outcome for each CPU.
+//!
+//! Construction is transactional: if it fails, every file descriptor and
+//! mapping created so far is released and no map entries are left behind.
+//!
+//! # Ring indices
+//!
+//! Ring indices are CPU numbers, and by default each ring holds the events
+//! of its CPU. Programs that pass an explicit index to
+//! `bpf_perf_event_output`, or give indices a meaning of their own, are read
+//! with [`PerfMapReader::set_ring_index_semantics`], and consumers map ring
+//! indices back with [`PerfMapReader::ring_cpu`].
 
-use std::io;
+use std::fmt;
 use std::slice;
 
-use crate::{MmapStorage, PerfRing, PerfRingError, Reader, ReaderError, Storage, StorageError};
-use libbpf_rs::{MapCore as _, MapMut};
+use crate::{
+    validate_perf_event_array, MapInfo, MemoryStorage, MmapStorage, PerfEventArray, PerfRing,
+    PerfRingError, Reader, ReaderError, RingIndexError, RingIndexSemantics, RingSource, Storage,
+    StorageError,
+};
+use collector_errors::{Classified, ErrorCode};
+use libbpf_rs::{MapCore, MapMut};
 
-use crate::helpers::{self, PerfEventError};
+use crate::helpers::PerfEventError;
 
 /// Error type for perf map operations
 #[derive(Debug, thiserror::Error)]
@@ -43,14 +70,143 @@ pub enum PerfMapError {
     /// Error adding a ring to the reader
     #[error("failed to add ring to reader: {0}")]
     ReaderAddRingError(ReaderError),
+
+    /// The ring size cannot be mapped
+    #[error("buffer_pages must be a power of 2 and at least 1, got {buffer_pages}")]
+    InvalidBufferPages {
+        /// The requested size of each per-CPU buffer in pages
+        buffer_pages: u32,
+    },
+
+    /// The ring index semantics do not fit the rings
+    #[error("ring index semantics: {0}")]
+    RingIndex(#[from] RingIndexError),
+}
+
+impl Classified for PerfMapError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            PerfMapError::PerfEventError(_)
+            | PerfMapError::MapInfoError(_)
+            | PerfMapError::StorageError { .. } => ErrorCode::PerfOpen,
+            PerfMapError::RingInitError { source, .. } => source.code(),
+            PerfMapError::ReaderAddRingError(e) => e.code(),
+            PerfMapError::InvalidBufferPages { .. } => ErrorCode::RingSetup,
+            PerfMapError::RingIndex(e) => e.code(),
+        }
+    }
+}
+
+/// Options for [`PerfMapReader::with_options`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfMapReaderOptions {
+    /// The size of each per-CPU buffer in pages, a power of 2
+    pub buffer_pages: u32,
+    /// The number of bytes that must be written before waking up userspace.
+    /// A value of 0 means wake up on every event.
+    pub watermark_bytes: u32,
+    /// Proceed with the CPUs that could be set up, instead of failing when the
+    /// map is too small or a CPU's ring cannot be opened
+    pub allow_partial: bool,
+}
+
+/// Outcome of setting up a single CPU's ring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuSetup {
+    /// The ring is open and its file descriptor is in the map
+    Ready,
+    /// The map has no entry for the CPU
+    NoMapEntry,
+    /// Opening the ring failed; the CPU has an empty placeholder ring
+    Failed(String),
+}
+
+impl fmt::Display for CpuSetup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuSetup::Ready => write!(f, "ready"),
+            CpuSetup::NoMapEntry => write!(f, "no map entry"),
+            CpuSetup::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// Opens the storage backing each CPU's ring, behind a trait so setup
+/// failures can be tested without perf events
+pub trait StorageOpener {
+    /// Open the ring storage for `cpu`
+    fn open(
+        &mut self,
+        cpu: i32,
+        buffer_pages: u32,
+        watermark_bytes: u32,
+    ) -> Result<Box<dyn Storage>, StorageError>;
+}
+
+/// Opens BPF output perf events with [`MmapStorage`]
+pub struct MmapStorageOpener;
+
+impl StorageOpener for MmapStorageOpener {
+    fn open(
+        &mut self,
+        cpu: i32,
+        buffer_pages: u32,
+        watermark_bytes: u32,
+    ) -> Result<Box<dyn Storage>, StorageError> {
+        Ok(Box::new(MmapStorage::new(
+            cpu,
+            buffer_pages,
+            watermark_bytes,
+        )?))
+    }
+}
+
+/// Per-CPU file descriptor slots the rings are published to, behind a trait
+/// so publishing failures can be tested without BPF maps
+pub trait FdTable {
+    /// Store the file descriptor for `cpu`
+    fn set_fd(&mut self, cpu: usize, fd: i32) -> Result<(), PerfEventError>;
+    /// Remove the file descriptor for `cpu`
+    fn clear_fd(&mut self, cpu: usize) -> Result<(), PerfEventError>;
+}
+
+impl<M: MapCore> FdTable for PerfEventArray<'_, M> {
+    fn set_fd(&mut self, cpu: usize, fd: i32) -> Result<(), PerfEventError> {
+        PerfEventArray::set_fd(self, cpu, fd)
+    }
+
+    fn clear_fd(&mut self, cpu: usize) -> Result<(), PerfEventError> {
+        PerfEventArray::clear_fd(self, cpu)
+    }
+}
+
+/// Number of CPUs that will get a ring: all `num_cpus` possible CPUs, or with
+/// `allow_partial` as many as the map has entries for
+fn mapped_cpus(
+    map: &impl MapInfo,
+    num_cpus: usize,
+    allow_partial: bool,
+) -> Result<usize, PerfEventError> {
+    if allow_partial {
+        // Only check the map type
+        validate_perf_event_array(map, 0)?;
+        Ok(num_cpus.min(map.map_max_entries() as usize))
+    } else {
+        validate_perf_event_array(map, num_cpus)?;
+        Ok(num_cpus)
+    }
 }
 
 /// PerfMapReader manages perf ring buffers connected to an eBPF map
 pub struct PerfMapReader {
     /// Storage for each CPU
-    _storage: Vec<MmapStorage>,
+    _storage: Vec<Box<dyn Storage>>,
     /// Reader for the perf rings
     reader: Reader,
+    /// Setup outcome for each possible CPU
+    cpu_setup: Vec<CpuSetup>,
+    /// What the programs writing to the rings mean by a ring index
+    ring_index_semantics: RingIndexSemantics,
 }
 
 impl PerfMapReader {
@@ -58,8 +214,8 @@ impl PerfMapReader {
     ///
     /// # Arguments
     ///
-    /// * `map` - The eBPF map to connect to (should be a PERF_EVENT_ARRAY map)
-    /// * `buffer_pages` - The size of each per-CPU buffer in pages
+    /// * `map` - The eBPF map to connect to, a PERF_EVENT_ARRAY map with an entry per possible CPU
+    /// * `buffer_pages` - The size of each per-CPU buffer in pages, a power of 2
     /// * `watermark_bytes` - The number of bytes that must be written before waking up userspace.
     ///                       A value of 0 means wake up on every event.
     ///
@@ -71,28 +227,78 @@ impl PerfMapReader {
         buffer_pages: u32,
         watermark_bytes: u32,
     ) -> Result<Self, PerfMapError> {
-        // Get number of possible CPUs from the map
-        let n_cpu = map.info()?.info.max_entries as i32;
-        if n_cpu < 1 {
-            return Err(PerfMapError::MapInfoError(
-                io::Error::new(io::ErrorKind::InvalidInput, "invalid number of CPUs in map").into(),
-            ));
+        Self::with_options(
+            map,
+            PerfMapReaderOptions {
+                buffer_pages,
+                watermark_bytes,
+                allow_partial: false,
+            },
+        )
+    }
+
+    /// Creates a new PerfMapReader connected to the provided eBPF map, with
+    /// the option of proceeding on a subset of the CPUs
+    pub fn with_options(
+        map: &mut MapMut,
+        options: PerfMapReaderOptions,
+    ) -> Result<Self, PerfMapError> {
+        let num_cpus = libbpf_rs::num_possible_cpus()?;
+        let mapped_cpus = mapped_cpus(&*map, num_cpus, options.allow_partial)?;
+        let mut array = PerfEventArray::with_num_cpus(&*map, mapped_cpus)?;
+
+        Self::build(
+            &mut MmapStorageOpener,
+            &mut array,
+            num_cpus,
+            mapped_cpus,
+            options,
+        )
+    }
+
+    /// Opens a ring for each of the first `mapped_cpus` CPUs, then publishes
+    /// their file descriptors to `table`.
+    ///
+    /// On error, everything opened so far is dropped and any published file
+    /// descriptors are removed from the table again.
+    fn build(
+        opener: &mut impl StorageOpener,
+        table: &mut impl FdTable,
+        num_cpus: usize,
+        mapped_cpus: usize,
+        options: PerfMapReaderOptions,
+    ) -> Result<Self, PerfMapError> {
+        // The kernel only maps data areas of a power of 2 pages
+        if !options.buffer_pages.is_power_of_two() {
+            return Err(PerfMapError::InvalidBufferPages {
+                buffer_pages: options.buffer_pages,
+            });
         }
 
-        // Create storage, rings, and reader
-        let mut storage = Vec::with_capacity(n_cpu as usize);
+        let mut storage = Vec::with_capacity(mapped_cpus);
         let mut reader = Reader::new();
-        let mut fds = Vec::with_capacity(n_cpu as usize);
+        let mut cpu_setup = Vec::with_capacity(num_cpus);
 
         // Create storage and rings for each CPU
-        for cpu in 0..n_cpu {
-            // Create MmapStorage with the specified options
-            let cpu_storage = MmapStorage::new(cpu, buffer_pages, watermark_bytes)
-                .map_err(|e| PerfMapError::StorageError { cpu, source: e })?;
-
-            // Get file descriptor to store in the map
-            let fd = cpu_storage.file_descriptor();
-            fds.push(fd);
+        for cpu in 0..mapped_cpus as i32 {
+            let opened = opener
+                .open(cpu, options.buffer_pages, options.watermark_bytes)
+                .map_err(|source| PerfMapError::StorageError { cpu, source });
+            let cpu_storage = match opened {
+                Ok(cpu_storage) => {
+                    cpu_setup.push(CpuSetup::Ready);
+                    cpu_storage
+                }
+                Err(e) if options.allow_partial => {
+                    // An empty ring keeps ring indices equal to CPU numbers
+                    cpu_setup.push(CpuSetup::Failed(e.to_string()));
+                    Box::new(
+                        MemoryStorage::new(1)
+                            .map_err(|source| PerfMapError::StorageError { cpu, source })?,
+                    )
+                }
+                Err(e) => return Err(e),
+            };
 
             // Initialize a ring from the storage
             // Create a mutable slice for the ring (PerfRing needs a mutable slice)
@@ -110,21 +316,42 @@ impl PerfMapReader {
                 .map_err(|e| PerfMapError::RingInitError { cpu, source: e })?
             };
 
-            // Add the ring to the reader
+            // Add the ring to the reader. Placeholder rings have no perf
+            // event to wait on.
+            let mut source = RingSource::new(format!("cpu {}", cpu));
+            if cpu_setup[cpu as usize] == CpuSetup::Ready {
+                source = source.with_fd(cpu_storage.file_descriptor());
+            }
             reader
-                .add_ring(ring)
+                .add_ring_with_source(ring, source)
                 .map_err(PerfMapError::ReaderAddRingError)?;
 
             // Save the storage
             storage.push(cpu_storage);
         }
+        cpu_setup.resize(num_cpus, CpuSetup::NoMapEntry);
+
+        // Publish the file descriptors of the ready rings, all or nothing
+        let mut published = Vec::with_capacity(mapped_cpus);
+        for (cpu, cpu_storage) in storage.iter().enumerate() {
+            if cpu_setup[cpu] != CpuSetup::Ready {
+                continue;
+            }
 
-        // Update the map with all file descriptors at once
-        helpers::update_map_with_fds(map, &fds).map_err(PerfMapError::PerfEventError)?;
+            if let Err(e) = table.set_fd(cpu, cpu_storage.file_descriptor()) {
+                for &cpu in &published {
+                    let _ = table.clear_fd(cpu);
+                }
+                return Err(e.into());
+            }
+            published.push(cpu);
+        }
 
         Ok(PerfMapReader {
             _storage: storage,
             reader,
+            cpu_setup,
+            ring_index_semantics: RingIndexSemantics::default(),
         })
     }
 
@@ -137,11 +364,315 @@ impl PerfMapReader {
     pub fn reader_mut(&mut self) -> &mut Reader {
         &mut self.reader
     }
+
+    /// Returns the setup outcome for each possible CPU, indexed by CPU
+    pub fn cpu_setup(&self) -> &[CpuSetup] {
+        &self.cpu_setup
+    }
+
+    /// Sets what the programs writing to the rings mean by a ring index.
+    /// Fails if an index given a meaning has no ring.
+    pub fn set_ring_index_semantics(
+        &mut self,
+        semantics: RingIndexSemantics,
+    ) -> Result<(), PerfMapError> {
+        semantics.check_rings(self.reader.rings().len())?;
+        self.ring_index_semantics = semantics;
+        Ok(())
+    }
+
+    /// Returns what the programs writing to the rings mean by a ring index
+    pub fn ring_index_semantics(&self) -> &RingIndexSemantics {
+        &self.ring_index_semantics
+    }
+
+    /// Returns the CPU that events read from `ring_index` belong to, or None
+    /// when ring indices are not CPUs
+    pub fn ring_cpu(&self, ring_index: usize) -> Option<usize> {
+        self.ring_index_semantics.ring_cpu(ring_index)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+    use std::rc::Rc;
+
+    use libbpf_rs::MapType;
+
+    /// Ring storage holding a real file descriptor, counting how many are open
+    struct CountedStorage {
+        memory: MemoryStorage,
+        file: File,
+        open_fds: Rc<Cell<usize>>,
+    }
+
+    impl Storage for CountedStorage {
+        fn data(&self) -> &[u8] {
+            self.memory.data()
+        }
+
+        fn num_data_pages(&self) -> u32 {
+            self.memory.num_data_pages()
+        }
+
+        fn page_size(&self) -> u64 {
+            self.memory.page_size()
+        }
+
+        fn file_descriptor(&self) -> i32 {
+            self.file.as_raw_fd()
+        }
+    }
+
+    impl Drop for CountedStorage {
+        fn drop(&mut self) {
+            self.open_fds.set(self.open_fds.get() - 1);
+        }
+    }
+
+    /// Opener that fails on the given CPUs
+    struct FakeOpener {
+        fail_cpus: Vec<i32>,
+        open_fds: Rc<Cell<usize>>,
+    }
+
+    impl FakeOpener {
+        fn new(fail_cpus: &[i32]) -> Self {
+            Self {
+                fail_cpus: fail_cpus.to_vec(),
+                open_fds: Rc::new(Cell::new(0)),
+            }
+        }
+    }
+
+    impl StorageOpener for FakeOpener {
+        fn open(
+            &mut self,
+            cpu: i32,
+            buffer_pages: u32,
+            _watermark_bytes: u32,
+        ) -> Result<Box<dyn Storage>, StorageError> {
+            if self.fail_cpus.contains(&cpu) {
+                return Err(StorageError::OsError(std::io::Error::from_raw_os_error(
+                    libc::ENODEV,
+                )));
+            }
+
+            let file = File::open("/dev/null").map_err(StorageError::OsError)?;
+            self.open_fds.set(self.open_fds.get() + 1);
+            Ok(Box::new(CountedStorage {
+                memory: MemoryStorage::new(buffer_pages)?,
+                file,
+                open_fds: self.open_fds.clone(),
+            }))
+        }
+    }
+
+    /// In-memory fd table that can fail updates for one CPU
+    #[derive(Default)]
+    struct FakeTable {
+        fds: Rc<RefCell<BTreeMap<usize, i32>>>,
+        fail_cpu: Option<usize>,
+    }
+
+    impl FdTable for FakeTable {
+        fn set_fd(&mut self, cpu: usize, fd: i32) -> Result<(), PerfEventError> {
+            if self.fail_cpu == Some(cpu) {
+                return Err(PerfEventError::OpenError {
+                    cpu: cpu as i32,
+                    source: std::io::Error::from_raw_os_error(libc::E2BIG),
+                });
+            }
+            self.fds.borrow_mut().insert(cpu, fd);
+            Ok(())
+        }
+
+        fn clear_fd(&mut self, cpu: usize) -> Result<(), PerfEventError> {
+            self.fds.borrow_mut().remove(&cpu);
+            Ok(())
+        }
+    }
+
+    struct MockMap {
+        max_entries: u32,
+    }
+
+    impl MapInfo for MockMap {
+        fn map_name(&self) -> String {
+            "events".to_string()
+        }
+
+        fn map_kind(&self) -> MapType {
+            MapType::PerfEventArray
+        }
+
+        fn map_max_entries(&self) -> u32 {
+            self.max_entries
+        }
+    }
+
+    fn options(allow_partial: bool) -> PerfMapReaderOptions {
+        PerfMapReaderOptions {
+            buffer_pages: 1,
+            watermark_bytes: 0,
+            allow_partial,
+        }
+    }
+
+    #[test]
+    fn test_build_publishes_all_cpus() {
+        let mut opener = FakeOpener::new(&[]);
+        let mut table = FakeTable::default();
+
+        let reader = PerfMapReader::build(&mut opener, &mut table, 4, 4, options(false)).unwrap();
+
+        assert_eq!(reader.cpu_setup(), vec![CpuSetup::Ready; 4]);
+        assert_eq!(table.fds.borrow().len(), 4);
+        assert_eq!(opener.open_fds.get(), 4);
+
+        drop(reader);
+        assert_eq!(opener.open_fds.get(), 0);
+    }
+
+    #[test]
+    fn test_open_failure_releases_everything() {
+        let mut opener = FakeOpener::new(&[2]);
+        let mut table = FakeTable::default();
+
+        let result = PerfMapReader::build(&mut opener, &mut table, 4, 4, options(false));
+
+        assert!(matches!(
+            result,
+            Err(PerfMapError::StorageError { cpu: 2, .. })
+        ));
+        assert_eq!(opener.open_fds.get(), 0);
+        assert!(table.fds.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_publish_failure_rolls_back() {
+        let mut opener = FakeOpener::new(&[]);
+        let mut table = FakeTable {
+            fail_cpu: Some(3),
+            ..Default::default()
+        };
+
+        let result = PerfMapReader::build(&mut opener, &mut table, 4, 4, options(false));
+
+        assert!(matches!(result, Err(PerfMapError::PerfEventError(_))));
+        assert_eq!(opener.open_fds.get(), 0);
+        assert!(table.fds.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_partial_skips_failed_cpus() {
+        let mut opener = FakeOpener::new(&[1]);
+        let mut table = FakeTable::default();
+
+        let reader = PerfMapReader::build(&mut opener, &mut table, 4, 3, options(true)).unwrap();
+
+        assert_eq!(reader.cpu_setup()[0], CpuSetup::Ready);
+        assert!(matches!(reader.cpu_setup()[1], CpuSetup::Failed(_)));
+        assert_eq!(reader.cpu_setup()[2], CpuSetup::Ready);
+        assert_eq!(reader.cpu_setup()[3], CpuSetup::NoMapEntry);
+
+        // Ring indices stay equal to CPU numbers
+        assert_eq!(reader.reader().ring_stats().len(), 3);
+        let sources = reader.reader().sources();
+        assert_eq!(sources[1].label(), "cpu 1");
+        assert!(sources[0].fd().is_some());
+        assert_eq!(sources[1].fd(), None);
+        assert_eq!(sources[2].fd(), table.fds.borrow().get(&2).copied());
+        assert_eq!(
+            table.fds.borrow().keys().copied().collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(opener.open_fds.get(), 2);
+    }
+
+    #[test]
+    fn test_invalid_buffer_pages() {
+        for buffer_pages in [3, 0] {
+            let mut opener = FakeOpener::new(&[]);
+            let mut table = FakeTable::default();
+            let options = PerfMapReaderOptions {
+                buffer_pages,
+                ..options(false)
+            };
+
+            let err = PerfMapReader::build(&mut opener, &mut table, 4, 4, options)
+                .err()
+                .unwrap();
+
+            assert!(matches!(err, PerfMapError::InvalidBufferPages { .. }));
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "buffer_pages must be a power of 2 and at least 1, got {}",
+                    buffer_pages
+                )
+            );
+            assert_eq!(err.code(), ErrorCode::RingSetup);
+            assert_eq!(opener.open_fds.get(), 0);
+            assert!(table.fds.borrow().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_ring_index_semantics() {
+        let mut opener = FakeOpener::new(&[]);
+        let mut table = FakeTable::default();
+        let mut reader =
+            PerfMapReader::build(&mut opener, &mut table, 4, 4, options(false)).unwrap();
+        assert_eq!(
+            reader.ring_index_semantics(),
+            &RingIndexSemantics::CurrentCpu
+        );
+        assert_eq!(reader.ring_cpu(2), Some(2));
+
+        // Indices given a meaning need a ring
+        let semantics = RingIndexSemantics::Custom(BTreeMap::from([(4, "spare".to_string())]));
+        let err = reader.set_ring_index_semantics(semantics).unwrap_err();
+        assert!(matches!(
+            err,
+            PerfMapError::RingIndex(RingIndexError::OutOfRange { index: 4, rings: 4 })
+        ));
+        assert_eq!(err.code(), ErrorCode::RingSetup);
+        assert_eq!(
+            reader.ring_index_semantics(),
+            &RingIndexSemantics::CurrentCpu
+        );
+
+        let semantics = RingIndexSemantics::Custom(BTreeMap::from([(3, "timers".to_string())]));
+        reader.set_ring_index_semantics(semantics).unwrap();
+        assert_eq!(reader.ring_cpu(3), None);
+        assert_eq!(
+            reader.ring_index_semantics().ring_meaning(3),
+            Some("timers")
+        );
+    }
+
+    #[test]
+    fn test_mapped_cpus() {
+        let map = MockMap { max_entries: 2 };
+
+        // Too small a map is an error naming both numbers, unless partial
+        let err = mapped_cpus(&map, 4, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "map events has max_entries 2, expected at least 4 (one per possible CPU)"
+        );
+        assert_eq!(mapped_cpus(&map, 4, true).unwrap(), 2);
+
+        let map = MockMap { max_entries: 8 };
+        assert_eq!(mapped_cpus(&map, 4, false).unwrap(), 4);
+        assert_eq!(mapped_cpus(&map, 4, true).unwrap(), 4);
+    }
 
     #[test]
     #[ignore] // This test requires root, run with cargo test -- --ignored
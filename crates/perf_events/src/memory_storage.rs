@@ -1,5 +1,6 @@
 use std::os::fd::RawFd;
 
+use crate::PerfEventMmapPage;
 use crate::Storage;
 use crate::StorageError;
 
@@ -18,7 +19,23 @@ impl MemoryStorage {
         let page_size = page_size::get() as u64;
         let total_size = page_size * (1 + u64::from(n_pages)); // 1 metadata page + data pages
 
-        let data = vec![0; total_size as usize];
+        let mut data = vec![0; total_size as usize];
+
+        // Fill in the data area layout as the kernel does, so rings are
+        // initialized from the metadata page
+        let data_size = page_size * u64::from(n_pages);
+        for (offset, value) in [
+            (
+                std::mem::offset_of!(PerfEventMmapPage, data_offset),
+                page_size,
+            ),
+            (
+                std::mem::offset_of!(PerfEventMmapPage, data_size),
+                data_size,
+            ),
+        ] {
+            data[offset..offset + 8].copy_from_slice(&value.to_ne_bytes());
+        }
 
         Ok(MemoryStorage {
             data,
@@ -26,6 +43,12 @@ impl MemoryStorage {
             page_size,
         })
     }
+
+    /// Return the raw data buffer for writing, e.g. to create a
+    /// [`crate::PerfRing`] that produces events into this storage
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
 }
 
 impl Storage for MemoryStorage {
@@ -64,4 +87,50 @@ mod tests {
 
         assert_eq!(storage.file_descriptor(), -1);
     }
+
+    #[test]
+    fn test_memory_ring_storage_layout() {
+        let n_pages = 2;
+        let mut storage = MemoryStorage::new(n_pages).unwrap();
+        let page_size = storage.page_size();
+
+        let ring = unsafe {
+            crate::PerfRing::init_contiguous(&mut storage.data, n_pages, page_size).unwrap()
+        };
+        assert_eq!(
+            ring.layout(),
+            crate::RingLayout {
+                data_offset: page_size,
+                data_size: page_size * u64::from(n_pages),
+                source: crate::LayoutSource::MetadataPage,
+            }
+        );
+    }
+
+    #[test]
+    fn test_data_region() {
+        let n_pages = 2;
+        let mut storage = MemoryStorage::new(n_pages).unwrap();
+        let page_size = storage.page_size() as usize;
+
+        // Mark the first and last data bytes
+        storage.data[page_size] = 0xaa;
+        *storage.data.last_mut().unwrap() = 0xbb;
+
+        let check = |storage: &MemoryStorage| {
+            let region = storage.data_region();
+            assert_eq!(region.len(), page_size * n_pages as usize);
+            assert_eq!(region.as_ptr(), storage.data()[page_size..].as_ptr());
+            assert_eq!(region[0], 0xaa);
+            assert_eq!(*region.last().unwrap(), 0xbb);
+        };
+
+        // data_offset is set in the metadata page
+        check(&storage);
+
+        // Older kernels leave it zero, and the data follows the metadata page
+        let offset_field = std::mem::offset_of!(PerfEventMmapPage, data_offset);
+        storage.data[offset_field..offset_field + 8].fill(0);
+        check(&storage);
+    }
 }
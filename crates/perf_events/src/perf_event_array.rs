@@ -0,0 +1,213 @@
+//! Validated access to BPF_MAP_TYPE_PERF_EVENT_ARRAY maps.
+//!
+//! Passing the wrong map to the perf event helpers otherwise fails deep inside
+//! perf_event_open or the map update with an opaque libbpf error. The
+//! [`PerfEventArray`] wrapper checks the map up front and names the map and the
+//! expected values in its errors.
+
+use libbpf_rs::{MapCore, MapFlags, MapType};
+
+use crate::helpers::PerfEventError;
+
+/// Map properties checked when wrapping a map, behind a trait so validation
+/// can be tested without creating BPF maps
+pub trait MapInfo {
+    /// Map name, for error messages
+    fn map_name(&self) -> String;
+    /// Type of the map
+    fn map_kind(&self) -> MapType;
+    /// Maximum number of entries
+    fn map_max_entries(&self) -> u32;
+}
+
+impl<M: MapCore> MapInfo for M {
+    fn map_name(&self) -> String {
+        self.name().to_string_lossy().into_owned()
+    }
+
+    fn map_kind(&self) -> MapType {
+        self.map_type()
+    }
+
+    fn map_max_entries(&self) -> u32 {
+        self.max_entries()
+    }
+}
+
+/// Checks that a map is a perf event array with an entry for each of `num_cpus` CPUs
+pub fn validate_perf_event_array(
+    map: &impl MapInfo,
+    num_cpus: usize,
+) -> Result<(), PerfEventError> {
+    let map_type = map.map_kind();
+    if map_type != MapType::PerfEventArray {
+        return Err(PerfEventError::WrongMapType {
+            map: map.map_name(),
+            actual: format!("{:?}", map_type),
+        });
+    }
+
+    let max_entries = map.map_max_entries();
+    if (max_entries as usize) < num_cpus {
+        return Err(PerfEventError::TooFewEntries {
+            map: map.map_name(),
+            max_entries,
+            num_cpus,
+        });
+    }
+
+    Ok(())
+}
+
+/// A perf event array map, validated to have an entry for every possible CPU
+pub struct PerfEventArray<'a, M: MapCore> {
+    map: &'a M,
+    num_cpus: usize,
+}
+
+impl<'a, M: MapCore> PerfEventArray<'a, M> {
+    /// Wraps a map after checking its type and size against the number of possible CPUs
+    pub fn new(map: &'a M) -> Result<Self, PerfEventError> {
+        let num_cpus = libbpf_rs::num_possible_cpus().map_err(PerfEventError::MapInfoError)?;
+        Self::with_num_cpus(map, num_cpus)
+    }
+
+    /// Wraps a map after checking its type and that it has entries for the
+    /// first `num_cpus` CPUs
+    pub fn with_num_cpus(map: &'a M, num_cpus: usize) -> Result<Self, PerfEventError> {
+        validate_perf_event_array(map, num_cpus)?;
+        Ok(Self { map, num_cpus })
+    }
+
+    /// Number of CPUs with an entry in the map, normally all possible CPUs
+    pub fn num_cpus(&self) -> usize {
+        self.num_cpus
+    }
+
+    /// Stores the perf event file descriptor for a CPU
+    pub fn set_fd(&mut self, cpu: usize, fd: i32) -> Result<(), PerfEventError> {
+        let key = (cpu as u32).to_le_bytes();
+        let value = (fd as u32).to_le_bytes();
+
+        self.map
+            .update(&key, &value, MapFlags::ANY)
+            .map_err(|source| PerfEventError::MapUpdateError {
+                cpu: cpu as i32,
+                source,
+            })
+    }
+
+    /// Removes the perf event file descriptor stored for a CPU
+    pub fn clear_fd(&mut self, cpu: usize) -> Result<(), PerfEventError> {
+        let key = (cpu as u32).to_le_bytes();
+
+        self.map
+            .delete(&key)
+            .map_err(|source| PerfEventError::MapUpdateError {
+                cpu: cpu as i32,
+                source,
+            })
+    }
+
+    /// Returns the perf event file descriptor stored for a CPU, if any
+    pub fn get_fd(&self, cpu: usize) -> Result<Option<i32>, PerfEventError> {
+        let key = (cpu as u32).to_le_bytes();
+
+        let value = self
+            .map
+            .lookup(&key, MapFlags::ANY)
+            .map_err(PerfEventError::MapInfoError)?;
+
+        Ok(value
+            .filter(|value| value.len() >= 4)
+            .map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]) as i32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockMap {
+        map_type: MapType,
+        max_entries: u32,
+    }
+
+    impl MapInfo for MockMap {
+        fn map_name(&self) -> String {
+            "events".to_string()
+        }
+
+        fn map_kind(&self) -> MapType {
+            self.map_type
+        }
+
+        fn map_max_entries(&self) -> u32 {
+            self.max_entries
+        }
+    }
+
+    #[test]
+    fn test_validate_accepts_perf_event_array() {
+        let map = MockMap {
+            map_type: MapType::PerfEventArray,
+            max_entries: 8,
+        };
+        assert!(validate_perf_event_array(&map, 8).is_ok());
+        assert!(validate_perf_event_array(&map, 4).is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_wrong_type() {
+        let map = MockMap {
+            map_type: MapType::Hash,
+            max_entries: 8,
+        };
+        let err = validate_perf_event_array(&map, 8).unwrap_err();
+        assert!(matches!(err, PerfEventError::WrongMapType { .. }));
+        assert_eq!(
+            err.to_string(),
+            "map events has type Hash, expected PerfEventArray"
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_too_few_entries() {
+        let map = MockMap {
+            map_type: MapType::PerfEventArray,
+            max_entries: 2,
+        };
+        let err = validate_perf_event_array(&map, 4).unwrap_err();
+        assert!(matches!(
+            err,
+            PerfEventError::TooFewEntries {
+                max_entries: 2,
+                num_cpus: 4,
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "map events has max_entries 2, expected at least 4 (one per possible CPU)"
+        );
+    }
+
+    #[test]
+    #[ignore] // This test requires root, run with cargo test -- --ignored
+    fn test_wrong_map_type_is_rejected() {
+        let opts = libbpf_rs::libbpf_sys::bpf_map_create_opts {
+            sz: std::mem::size_of::<libbpf_rs::libbpf_sys::bpf_map_create_opts>() as _,
+            ..Default::default()
+        };
+        let map = libbpf_rs::MapHandle::create(MapType::Hash, Some("wrong_type"), 4, 4, 8, &opts)
+            .expect("creating a BPF map requires root");
+
+        match PerfEventArray::new(&map) {
+            Err(PerfEventError::WrongMapType { map, actual }) => {
+                assert_eq!(map, "wrong_type");
+                assert_eq!(actual, "Hash");
+            }
+            other => panic!("expected WrongMapType, got {:?}", other.err()),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Builder for custom perf event attributes.
+//!
+//! [`PerfEventAttrBuilder`] exposes the commonly tuned fields of
+//! `perf_event_attr`, and [`open_perf_event`] opens the result on every CPU
+//! and stores the file descriptors in a BPF perf event array.
+
+use libbpf_rs::MapMut;
+use perf_event_open_sys as sys;
+
+use crate::{open_events, HardwareCounter, PerfEventError};
+
+/// Builds a `perf_event_attr` for [`open_perf_event`].
+///
+/// # Example
+///
+/// ```no_run
+/// use libbpf_rs::MapMut;
+/// use perf_events::{HardwareCounter, PerfEventAttrBuilder};
+///
+/// fn example(map: &mut MapMut) -> Result<(), perf_events::PerfEventError> {
+///     // Count user-space cycles only, including child threads
+///     let attr = PerfEventAttrBuilder::hardware(HardwareCounter::Cycles)
+///         .exclude_kernel(true)
+///         .inherit(true)
+///         .build();
+///
+///     perf_events::open_perf_event(map, attr)?;
+///     perf_events::start_events(map)?;
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone, Copy)]
+pub struct PerfEventAttrBuilder {
+    attr: sys::bindings::perf_event_attr,
+}
+
+impl PerfEventAttrBuilder {
+    /// Start from an event of the given `PERF_TYPE_*` type and type-specific config
+    pub fn new(type_: u32, config: u64) -> Self {
+        let mut attr = sys::bindings::perf_event_attr::default();
+        attr.size = std::mem::size_of::<sys::bindings::perf_event_attr>() as u32;
+        attr.type_ = type_;
+        attr.config = config;
+        Self { attr }
+    }
+
+    /// Start from a hardware counter, reading total time enabled and running
+    pub fn hardware(counter: HardwareCounter) -> Self {
+        let config = match counter {
+            HardwareCounter::Cycles => sys::bindings::PERF_COUNT_HW_CPU_CYCLES,
+            HardwareCounter::Instructions => sys::bindings::PERF_COUNT_HW_INSTRUCTIONS,
+            HardwareCounter::LLCMisses => sys::bindings::PERF_COUNT_HW_CACHE_MISSES,
+            HardwareCounter::CacheReferences => sys::bindings::PERF_COUNT_HW_CACHE_REFERENCES,
+        };
+        Self::new(sys::bindings::PERF_TYPE_HARDWARE, config as u64).read_format(
+            (sys::bindings::PERF_FORMAT_TOTAL_TIME_ENABLED
+                | sys::bindings::PERF_FORMAT_TOTAL_TIME_RUNNING) as u64,
+        )
+    }
+
+    /// Start from a software event, one of the `PERF_COUNT_SW_*` constants
+    pub fn software(config: u32) -> Self {
+        Self::new(sys::bindings::PERF_TYPE_SOFTWARE, config as u64)
+    }
+
+    /// Sample every `period` events
+    pub fn sample_period(mut self, period: u64) -> Self {
+        self.attr.set_freq(0);
+        self.attr.__bindgen_anon_1.sample_period = period;
+        self
+    }
+
+    /// Sample `freq` times per second
+    pub fn sample_freq(mut self, freq: u64) -> Self {
+        self.attr.set_freq(1);
+        self.attr.__bindgen_anon_1.sample_freq = freq;
+        self
+    }
+
+    /// Set the `PERF_SAMPLE_*` fields included in each sample
+    pub fn sample_type(mut self, sample_type: u64) -> Self {
+        self.attr.sample_type = sample_type;
+        self
+    }
+
+    /// Set the `PERF_FORMAT_*` fields returned when reading the counter
+    pub fn read_format(mut self, read_format: u64) -> Self {
+        self.attr.read_format = read_format;
+        self
+    }
+
+    /// Start the event disabled, to be enabled with [`crate::start_events`]
+    pub fn disabled(mut self, disabled: bool) -> Self {
+        self.attr.set_disabled(disabled as u64);
+        self
+    }
+
+    /// Also count events of child tasks
+    pub fn inherit(mut self, inherit: bool) -> Self {
+        self.attr.set_inherit(inherit as u64);
+        self
+    }
+
+    /// Keep the event on the PMU at all times
+    pub fn pinned(mut self, pinned: bool) -> Self {
+        self.attr.set_pinned(pinned as u64);
+        self
+    }
+
+    /// Do not count events in user space
+    pub fn exclude_user(mut self, exclude: bool) -> Self {
+        self.attr.set_exclude_user(exclude as u64);
+        self
+    }
+
+    /// Do not count events in the kernel
+    pub fn exclude_kernel(mut self, exclude: bool) -> Self {
+        self.attr.set_exclude_kernel(exclude as u64);
+        self
+    }
+
+    /// Do not count events in the hypervisor
+    pub fn exclude_hv(mut self, exclude: bool) -> Self {
+        self.attr.set_exclude_hv(exclude as u64);
+        self
+    }
+
+    /// Do not count events while the CPU is idle
+    pub fn exclude_idle(mut self, exclude: bool) -> Self {
+        self.attr.set_exclude_idle(exclude as u64);
+        self
+    }
+
+    /// Timestamp samples with the given clock, e.g. `libc::CLOCK_MONOTONIC`
+    pub fn clock(mut self, clockid: i32) -> Self {
+        self.attr.set_use_clockid(1);
+        self.attr.clockid = clockid;
+        self
+    }
+
+    /// Wake up readers every `events` samples
+    pub fn wakeup_events(mut self, events: u32) -> Self {
+        self.attr.set_watermark(0);
+        self.attr.__bindgen_anon_2.wakeup_events = events;
+        self
+    }
+
+    /// Wake up readers once `bytes` of data are in the ring
+    pub fn wakeup_watermark(mut self, bytes: u32) -> Self {
+        self.attr.set_watermark(1);
+        self.attr.__bindgen_anon_2.wakeup_watermark = bytes;
+        self
+    }
+
+    /// Return the configured attributes
+    pub fn build(self) -> sys::bindings::perf_event_attr {
+        self.attr
+    }
+}
+
+/// Opens an event with the given attributes on each CPU and stores the file
+/// descriptors in the provided perf event array map.
+///
+/// # Arguments
+///
+/// * `map` - A mutable reference to a libbpf-rs map to store the file descriptors
+/// * `attr` - Perf event attributes, usually from [`PerfEventAttrBuilder`]
+///
+/// # Returns
+///
+/// * `Ok(())` on success
+/// * `Err(PerfEventError)` on failure
+pub fn open_perf_event(
+    map: &mut MapMut,
+    mut attr: sys::bindings::perf_event_attr,
+) -> Result<(), PerfEventError> {
+    open_events(map, &mut attr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_sets_fields() {
+        let attr = PerfEventAttrBuilder::hardware(HardwareCounter::Instructions)
+            .sample_period(1000)
+            .exclude_kernel(true)
+            .inherit(true)
+            .clock(libc::CLOCK_MONOTONIC)
+            .build();
+
+        assert_eq!(
+            attr.size as usize,
+            std::mem::size_of::<sys::bindings::perf_event_attr>()
+        );
+        assert_eq!(attr.type_, sys::bindings::PERF_TYPE_HARDWARE);
+        assert_eq!(
+            attr.config,
+            sys::bindings::PERF_COUNT_HW_INSTRUCTIONS as u64
+        );
+        assert_eq!(unsafe { attr.__bindgen_anon_1.sample_period }, 1000);
+        assert_eq!(attr.freq(), 0);
+        assert_eq!(attr.exclude_kernel(), 1);
+        assert_eq!(attr.exclude_user(), 0);
+        assert_eq!(attr.inherit(), 1);
+        assert_eq!(attr.use_clockid(), 1);
+        assert_eq!(attr.clockid, libc::CLOCK_MONOTONIC);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore] // This test requires root, run with cargo test -- --ignored
+    fn test_open_custom_attr() {
+        // Task clock for the calling thread needs no privileges with kernel
+        // events excluded, as long as perf_event_paranoid is at most 2
+        let mut attr = PerfEventAttrBuilder::software(sys::bindings::PERF_COUNT_SW_TASK_CLOCK)
+            .exclude_kernel(true)
+            .exclude_hv(true)
+            .inherit(true)
+            .build();
+
+        let fd = unsafe {
+            sys::perf_event_open(
+                &mut attr,
+                0,  // pid (calling thread)
+                -1, // cpu (any)
+                -1, // group_fd
+                sys::bindings::PERF_FLAG_FD_CLOEXEC as u64,
+            )
+        };
+        assert!(
+            fd >= 0,
+            "failed to open custom perf event: {}",
+            std::io::Error::last_os_error()
+        );
+
+        unsafe {
+            libc::close(fd);
+        }
+    }
+}
@@ -1,9 +1,15 @@
+use collector_errors::{Classified, ErrorCode};
 use plain::Plain;
+use std::cell::RefCell;
 use std::collections::BinaryHeap;
-use std::{cmp::Ordering as CmpOrdering, mem::offset_of};
+use std::{
+    cmp::Ordering as CmpOrdering,
+    mem::{offset_of, size_of},
+};
 use thiserror::Error;
 
-use crate::{PerfRing, PerfRingError, PERF_RECORD_SAMPLE};
+use crate::tournament::TournamentTree;
+use crate::{PerfRecordType, PerfRing, PerfRingError, RingPositions, RingSource};
 
 /// Errors that can occur when using the ring reader
 #[derive(Error, Debug)]
@@ -24,7 +30,30 @@ pub enum ReaderError {
     PerfRingError(#[from] PerfRingError),
 }
 
+impl Classified for ReaderError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ReaderError::NoRings | ReaderError::NotActive | ReaderError::AlreadyActive => {
+                ErrorCode::Internal
+            }
+            ReaderError::BufferEmpty => ErrorCode::RingCorrupt,
+            ReaderError::PerfRingError(e) => e.code(),
+        }
+    }
+}
+
 /// The header for RECORD_SAMPLE messages that we require from eBPF
+///
+/// A PERF_RECORD_SAMPLE record in the ring is laid out as:
+///
+/// ```text
+/// perf_event_header (8 bytes) | size: u32 | type_: u32 | timestamp: u64 | rest of message
+/// ```
+///
+/// `size` is the raw sample size the kernel prepends to the eBPF message (see
+/// [`SampleHeader::raw_size`]); the message itself starts at `type_`. Offsets
+/// passed to [`PerfRing::peek_copy`] start after the perf_event_header, so they
+/// line up with this struct's field offsets.
 #[repr(C)]
 pub struct SampleHeader {
     pub size: u32,
@@ -33,9 +62,115 @@ pub struct SampleHeader {
 }
 unsafe impl Plain for SampleHeader {}
 
+impl SampleHeader {
+    /// Length of the `size` field preceding the eBPF message
+    pub const SIZE_PREFIX_LEN: usize = size_of::<u32>();
+
+    /// The `size` field for a message of `data_len` bytes.
+    ///
+    /// Like the kernel, this pads the message so the size field plus the
+    /// message fill whole 8-byte words, and excludes the size field itself.
+    pub fn raw_size(data_len: usize) -> u32 {
+        (((data_len + Self::SIZE_PREFIX_LEN + 7) & !7) - Self::SIZE_PREFIX_LEN) as u32
+    }
+
+    /// Reads the timestamp of the sample at the ring's current position
+    pub fn peek_timestamp(ring: &PerfRing) -> Result<u64, PerfRingError> {
+        let mut buf = [0u8; 8];
+        ring.peek_copy(&mut buf, offset_of!(SampleHeader, timestamp) as u16)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// Per-ring counters maintained by the reader
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RingStats {
+    /// Records consumed from this ring
+    pub records: u64,
+    /// PERF_RECORD_LOST records consumed from this ring
+    pub lost_records: u64,
+    /// Samples the kernel reported as lost in those records
+    pub lost_samples: u64,
+    /// Times the writer overwrote unread records and the ring was resynced
+    pub overwritten: u64,
+    /// Times a batch limit scan found a corrupt record header and skipped
+    /// the ring's unread data
+    pub corrupt: u64,
+}
+
+/// State of a ring as the reader last saw it, see [`Reader::ring_states`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RingState {
+    /// Positions as of the last read batch
+    pub positions: RingPositions,
+    /// Timestamp of the ring's next record in the merge order, 0 if it has
+    /// none. None when no batch is active or the ring has nothing left in it.
+    pub next_timestamp: Option<u64>,
+    /// Counters of the records consumed from the ring
+    pub stats: RingStats,
+}
+
+/// What a read batch consumed, returned by [`Reader::finish_with_summary`]
+///
+/// The fields describe the batch as a whole, so a caller can decide how long
+/// to wait before the next one: a batch that popped many events or left rings
+/// nearly full calls for polling sooner.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BatchSummary {
+    /// Records consumed during the batch, across all rings
+    pub events_popped: u64,
+    /// Bytes released back to the writers, including records skipped when
+    /// resyncing after an overwrite
+    pub bytes_consumed: u64,
+    /// Rings still holding unread data once the batch finished, either left
+    /// behind by a batch limit or written while the batch was read
+    pub rings_nonempty_at_finish: usize,
+    /// Highest fill ratio across the rings once the batch finished
+    pub max_fill_ratio: f64,
+}
+
+/// Strategy used to order events across rings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReaderOrdering {
+    /// Tournament tree up to `HEAP_THRESHOLD` rings, binary heap above it
+    #[default]
+    Auto,
+    /// Fixed-size tournament tree; no allocations after `start()`
+    Tournament,
+    /// Binary heap of (timestamp, ring) entries
+    Heap,
+}
+
+/// Ring count above which `ReaderOrdering::Auto` uses the binary heap.
+///
+/// `benches/reader.rs` compares the two orderings across ring counts.
+pub const HEAP_THRESHOLD: usize = 128;
+
+/// Position of a ring's next record in the merge order
+///
+/// Records without a timestamp sort before every sample, including samples
+/// whose timestamp is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum OrderKey {
+    /// A non-sample record, or a sample whose timestamp could not be read
+    Untimestamped,
+    /// A sample with the given timestamp
+    Timestamp(u64),
+}
+
+impl OrderKey {
+    /// The timestamp reported for the record, 0 if it has none
+    fn timestamp(self) -> u64 {
+        match self {
+            OrderKey::Untimestamped => 0,
+            OrderKey::Timestamp(timestamp) => timestamp,
+        }
+    }
+}
+
 /// A perf entry represents a timestamped entry from a specific ring
 struct PerfEntry {
-    timestamp: u64,
+    key: OrderKey,
     ring_index: usize,
 }
 
@@ -43,7 +178,7 @@ impl Eq for PerfEntry {}
 
 impl PartialEq for PerfEntry {
     fn eq(&self, other: &Self) -> bool {
-        self.timestamp == other.timestamp
+        self.key == other.key && self.ring_index == other.ring_index
     }
 }
 
@@ -57,43 +192,230 @@ impl PartialOrd for PerfEntry {
 
 impl Ord for PerfEntry {
     fn cmp(&self, other: &Self) -> CmpOrdering {
-        // Reverse ordering for min-heap
-        other.timestamp.cmp(&self.timestamp)
+        // Reverse ordering for min-heap; ties go to the lower ring index
+        other
+            .key
+            .cmp(&self.key)
+            .then_with(|| other.ring_index.cmp(&self.ring_index))
     }
 }
 
-/// RingReader provides sorted access to events from multiple perf rings
+/// The structure tracking the next event of each ring
+enum RingOrder {
+    Tournament(TournamentTree<OrderKey>),
+    Heap {
+        heap: BinaryHeap<PerfEntry>,
+        in_heap: Vec<bool>,
+    },
+}
+
+impl RingOrder {
+    fn new(ordering: ReaderOrdering, n: usize) -> Self {
+        let use_heap = match ordering {
+            ReaderOrdering::Auto => n > HEAP_THRESHOLD,
+            ReaderOrdering::Tournament => false,
+            ReaderOrdering::Heap => true,
+        };
+
+        if use_heap {
+            RingOrder::Heap {
+                heap: BinaryHeap::with_capacity(n),
+                in_heap: vec![false; n],
+            }
+        } else {
+            RingOrder::Tournament(TournamentTree::new(n))
+        }
+    }
+
+    /// Makes room for an additional ring
+    fn add_ring(&mut self, n: usize) {
+        match self {
+            RingOrder::Tournament(tree) => tree.grow(n),
+            RingOrder::Heap { in_heap, .. } => in_heap.resize(n, false),
+        }
+    }
+
+    fn contains(&self, idx: usize) -> bool {
+        match self {
+            RingOrder::Tournament(tree) => tree.get(idx).is_some(),
+            RingOrder::Heap { in_heap, .. } => in_heap[idx],
+        }
+    }
+
+    /// The order key of ring `idx`, if it is tracked
+    fn key(&self, idx: usize) -> Option<OrderKey> {
+        match self {
+            RingOrder::Tournament(tree) => tree.get(idx),
+            RingOrder::Heap { heap, in_heap } => in_heap[idx]
+                .then(|| heap.iter().find(|entry| entry.ring_index == idx))
+                .flatten()
+                .map(|entry| entry.key),
+        }
+    }
+
+    fn peek(&self) -> Option<(OrderKey, usize)> {
+        match self {
+            RingOrder::Tournament(tree) => tree.min(),
+            RingOrder::Heap { heap, .. } => heap.peek().map(|entry| (entry.key, entry.ring_index)),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            RingOrder::Tournament(tree) => tree.is_empty(),
+            RingOrder::Heap { heap, .. } => heap.is_empty(),
+        }
+    }
+
+    /// Sets the order key for a ring that is not currently tracked
+    fn insert(&mut self, idx: usize, key: Option<OrderKey>) {
+        match self {
+            RingOrder::Tournament(tree) => tree.set(idx, key),
+            RingOrder::Heap { heap, in_heap } => {
+                if let Some(key) = key {
+                    heap.push(PerfEntry {
+                        key,
+                        ring_index: idx,
+                    });
+                    in_heap[idx] = true;
+                }
+            }
+        }
+    }
+
+    /// Replaces the minimum entry, which must belong to ring `idx`
+    fn replace_min(&mut self, idx: usize, key: Option<OrderKey>) {
+        match self {
+            RingOrder::Tournament(tree) => tree.set(idx, key),
+            RingOrder::Heap { heap, in_heap } => {
+                heap.pop();
+                in_heap[idx] = false;
+                if let Some(key) = key {
+                    heap.push(PerfEntry {
+                        key,
+                        ring_index: idx,
+                    });
+                    in_heap[idx] = true;
+                }
+            }
+        }
+    }
+}
+
+/// Per-ring cap on how much a single read batch consumes
+///
+/// Bounding the batch keeps one bursty ring from delaying dispatch of the
+/// others; whatever is left over is read by the next batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchLimit {
+    /// Maximum bytes of records read from each ring per batch
+    pub max_bytes: u64,
+    /// Maximum number of records read from each ring per batch
+    pub max_events: usize,
+}
+
+impl Default for BatchLimit {
+    fn default() -> Self {
+        Self {
+            max_bytes: u64::MAX,
+            max_events: usize::MAX,
+        }
+    }
+}
+
+/// Reader provides sorted access to events from multiple perf rings
+///
+/// Rings are ordered by the timestamp of their next record, read from the
+/// `timestamp` field of [`SampleHeader`]. Records other than samples have no
+/// timestamp and sort first, ahead of samples with a timestamp of 0. Records
+/// that sort equal are read from the ring added first, whatever the ordering
+/// strategy.
 pub struct Reader {
     rings: Vec<PerfRing>,
-    heap: BinaryHeap<PerfEntry>,
-    in_heap: Vec<bool>,
+    ring_stats: Vec<RingStats>,
+    sources: Vec<RingSource>,
+    ordering: ReaderOrdering,
+    order: Option<RingOrder>,
+    batch_limit: Option<BatchLimit>,
     active: bool,
+    /// Records consumed and sum of the rings' read positions when the batch
+    /// started, to summarize it without per-ring state
+    batch_start: (u64, u64),
+    /// Consumer position of each ring at the last [`Reader::stalled_rings`]
+    /// check, for rings that were above the threshold then
+    stall_checks: RefCell<Vec<Option<u64>>>,
+    #[cfg(feature = "tracing")]
+    batch_span: Option<crate::instrument::ReaderBatchSpan>,
 }
 
 impl Reader {
     /// Creates a new reader for accessing events
     pub fn new() -> Self {
+        Self::with_ordering(ReaderOrdering::Auto)
+    }
+
+    /// Creates a new reader using the given ordering strategy
+    pub fn with_ordering(ordering: ReaderOrdering) -> Self {
         Reader {
             rings: Vec::new(),
-            heap: BinaryHeap::new(),
-            in_heap: Vec::new(),
+            ring_stats: Vec::new(),
+            sources: Vec::new(),
+            ordering,
+            order: None,
+            batch_limit: None,
             active: false,
+            batch_start: (0, 0),
+            stall_checks: RefCell::new(Vec::new()),
+            #[cfg(feature = "tracing")]
+            batch_span: None,
         }
     }
 
-    /// Adds a ring to the collection
+    /// Sets the per-ring cap applied by subsequent batches
+    ///
+    /// `None` (the default) reads everything available in each ring.
+    pub fn set_batch_limit(&mut self, limit: Option<BatchLimit>) {
+        self.batch_limit = limit;
+    }
+
+    /// Returns the per-ring batch cap, if any
+    pub fn batch_limit(&self) -> Option<BatchLimit> {
+        self.batch_limit
+    }
+
+    /// Adds a ring to the collection, with an unlabeled source
     pub fn add_ring(&mut self, ring: PerfRing) -> Result<(), ReaderError> {
+        self.add_ring_with_source(ring, RingSource::default())
+    }
+
+    /// Adds a ring to the collection, along with where its data comes from
+    ///
+    /// Rings over different storage can be combined, e.g. perf event rings
+    /// with a file descriptor and a memory ring without one. Ring indices
+    /// follow the order rings are added.
+    pub fn add_ring_with_source(
+        &mut self,
+        ring: PerfRing,
+        source: RingSource,
+    ) -> Result<(), ReaderError> {
         if self.active {
             return Err(ReaderError::AlreadyActive);
         }
 
         self.rings.push(ring);
-        self.in_heap.push(false);
+        self.ring_stats.push(RingStats::default());
+        self.sources.push(source);
+        if let Some(order) = &mut self.order {
+            order.add_ring(self.rings.len());
+        }
 
         Ok(())
     }
 
-    /// Begins a read batch, initializing the heap with available entries
+    /// Begins a read batch, initializing the ordering with available entries
+    ///
+    /// The ordering structure is allocated on the first call; later batches
+    /// reuse it without allocating.
     pub fn start(&mut self) -> Result<(), ReaderError> {
         if self.rings.is_empty() {
             return Err(ReaderError::NoRings);
@@ -103,31 +425,80 @@ impl Reader {
             return Err(ReaderError::AlreadyActive);
         }
 
-        // Start read batches and initialize the heap
-        for i in 0..self.rings.len() {
-            self.rings[i].start_read_batch();
+        let ordering = self.ordering;
+        let n_rings = self.rings.len();
+        let order = self
+            .order
+            .get_or_insert_with(|| RingOrder::new(ordering, n_rings));
+
+        // Start read batches and track the next event of each ring
+        for (i, ring) in self.rings.iter_mut().enumerate() {
+            match self.batch_limit {
+                Some(limit) => {
+                    if ring.start_read_batch_capped(limit.max_bytes, limit.max_events) > 0 {
+                        self.ring_stats[i].corrupt += 1;
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(ring_index = i, "corrupt record header, ring skipped");
+                    }
+                }
+                None => ring.start_read_batch(),
+            }
 
-            if !self.in_heap[i] {
-                self.maintain_heap_entry(i)?;
+            if !order.contains(i) {
+                order.insert(i, Self::next_key(ring));
             }
         }
 
+        self.batch_start = (self.total_records(), self.total_read_position());
+
+        #[cfg(feature = "tracing")]
+        {
+            self.batch_span = Some(crate::instrument::ReaderBatchSpan::start(
+                n_rings,
+                crate::instrument::total_ring_stats(&self.ring_stats),
+            ));
+        }
+
         self.active = true;
         Ok(())
     }
 
     /// Ends the current read batch
     pub fn finish(&mut self) -> Result<(), ReaderError> {
+        self.finish_with_summary().map(|_| ())
+    }
+
+    /// Ends the current read batch, returning what it consumed
+    ///
+    /// Without an active batch this does nothing and returns an empty
+    /// summary.
+    pub fn finish_with_summary(&mut self) -> Result<BatchSummary, ReaderError> {
         if !self.active {
-            return Ok(());
+            return Ok(BatchSummary::default());
         }
 
         for ring in &mut self.rings {
             ring.finish_read_batch();
         }
 
+        #[cfg(feature = "tracing")]
+        if let Some(span) = self.batch_span.take() {
+            span.finish(crate::instrument::total_ring_stats(&self.ring_stats));
+        }
+
         self.active = false;
-        Ok(())
+
+        let (start_records, start_position) = self.batch_start;
+        Ok(BatchSummary {
+            events_popped: self.total_records() - start_records,
+            bytes_consumed: self.total_read_position().wrapping_sub(start_position),
+            rings_nonempty_at_finish: self
+                .rings
+                .iter()
+                .filter(|ring| ring.kernel_head() != ring.consumer_tail())
+                .count(),
+            max_fill_ratio: self.max_fill_ratio(),
+        })
     }
 
     /// Returns true if there are no more events to read
@@ -136,97 +507,254 @@ impl Reader {
             return true;
         }
 
-        self.heap.is_empty()
+        match &self.order {
+            Some(order) => order.is_empty(),
+            None => true,
+        }
+    }
+
+    /// Returns the highest fill ratio across all rings, 0.0 if there are none
+    pub fn max_fill_ratio(&self) -> f64 {
+        self.rings
+            .iter()
+            .map(PerfRing::fill_ratio)
+            .fold(0.0, f64::max)
     }
 
-    /// Returns the timestamp of the next event
+    /// Returns the timestamp of the next event, 0 if it has none
     pub fn peek_timestamp(&self) -> Result<u64, ReaderError> {
-        if !self.active {
-            return Err(ReaderError::NotActive);
-        }
-
-        self.heap
-            .peek()
-            .map(|entry| entry.timestamp)
-            .ok_or(ReaderError::BufferEmpty)
+        self.peek().map(|(key, _)| key.timestamp())
     }
 
     /// Returns the ring containing the next event and its index
     pub fn current_ring(&self) -> Result<(&PerfRing, usize), ReaderError> {
-        if !self.active {
-            return Err(ReaderError::NotActive);
+        let (_, ring_index) = self.peek()?;
+        Ok((&self.rings[ring_index], ring_index))
+    }
+
+    /// Consumes the current event and updates the ordering
+    pub fn pop(&mut self) -> Result<(), ReaderError> {
+        let (_, ring_index) = self.peek()?;
+
+        let ring = &self.rings[ring_index];
+        let stats = &mut self.ring_stats[ring_index];
+        stats.records += 1;
+        if ring.peek_record_type() == PerfRecordType::Lost {
+            // PERF_RECORD_LOST carries a u64 id followed by the u64 lost count
+            stats.lost_records += 1;
+            let mut buf = [0u8; 8];
+            if ring.peek_copy(&mut buf, 8).is_ok() {
+                stats.lost_samples += u64::from_le_bytes(buf);
+            }
         }
 
-        match self.heap.peek() {
-            Some(entry) => Ok((&self.rings[entry.ring_index], entry.ring_index)),
-            None => Err(ReaderError::BufferEmpty),
+        self.rings[ring_index].pop()?;
+
+        // Update the entry for this ring
+        let key = Self::next_key(&self.rings[ring_index]);
+        if let Some(order) = &mut self.order {
+            order.replace_min(ring_index, key);
         }
+
+        Ok(())
     }
 
-    /// Consumes the current event and updates the heap
-    pub fn pop(&mut self) -> Result<(), ReaderError> {
+    /// Copies the next event into `buf` and consumes it, returning its perf
+    /// record type and timestamp, or `None` when no events remain.
+    ///
+    /// `buf` is resized to the record after its perf_event_header, the layout
+    /// `Dispatcher::dispatch_record` takes: for PERF_RECORD_SAMPLE it starts
+    /// with the [`SampleHeader`]. Records without a timestamp return 0.
+    /// Records the writer overwrote are skipped with
+    /// [`Reader::resync_current`], like the dispatcher does.
+    pub fn next_event(&mut self, buf: &mut Vec<u8>) -> Result<Option<(u32, u64)>, ReaderError> {
         if !self.active {
             return Err(ReaderError::NotActive);
         }
 
-        let Some(entry) = self.heap.pop() else {
-            return Err(ReaderError::BufferEmpty);
-        };
-        self.in_heap[entry.ring_index] = false;
+        loop {
+            if self.is_empty() {
+                return Ok(None);
+            }
 
-        self.rings[entry.ring_index].pop()?;
+            let (key, ring_index) = self.peek()?;
+            let ring = &self.rings[ring_index];
+            let record_type = ring.peek_type();
+            let copied = ring.peek_size().and_then(|size| {
+                buf.resize(size, 0);
+                ring.peek_copy(buf, 0)
+            });
+            match copied {
+                Ok(()) => {
+                    self.pop()?;
+                    return Ok(Some((record_type, key.timestamp())));
+                }
+                Err(PerfRingError::Overwritten) => self.resync_current()?,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
-        // Update the heap entry for this ring
-        self.maintain_heap_entry(entry.ring_index)?;
+    /// Recovers the current ring after the writer overwrote its unread
+    /// records, as reported by `PerfRingError::Overwritten`.
+    ///
+    /// Like a lost record, the overwritten data cannot be recovered: the ring
+    /// skips to its oldest intact record, see [`PerfRing::resync`], and the
+    /// event is counted in its stats.
+    pub fn resync_current(&mut self) -> Result<(), ReaderError> {
+        let (_, ring_index) = self.peek()?;
+
+        self.rings[ring_index].resync();
+        self.ring_stats[ring_index].overwritten += 1;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(ring_index, "ring overwritten by the writer, resynced");
+
+        let key = Self::next_key(&self.rings[ring_index]);
+        if let Some(order) = &mut self.order {
+            order.replace_min(ring_index, key);
+        }
 
         Ok(())
     }
 
-    /// Manages the heap entry for a ring
-    /// For PERF_RECORD_SAMPLE records, the record is the size injected by the kernel (4 bytes),
-    /// then message type (4 bytes), then timestamp(8 bytes).
+    /// Returns the rings, in the order they were added
     ///
-    /// The ring must not be in the heap.
+    /// Access is read-only, so it cannot disturb an active batch; use
+    /// [`PerfRing::kernel_head`] and [`PerfRing::consumer_tail`] to compute
+    /// how far each ring lags behind the kernel.
+    pub fn rings(&self) -> &[PerfRing] {
+        &self.rings
+    }
+
+    /// Returns the counters of each ring, in the order the rings were added
+    pub fn ring_stats(&self) -> &[RingStats] {
+        &self.ring_stats
+    }
+
+    /// Returns the source of each ring, in the order the rings were added
+    pub fn sources(&self) -> &[RingSource] {
+        &self.sources
+    }
+
+    /// Returns the state of each ring, in the order the rings were added.
     ///
-    /// A timestamp of 0 is assigned in the following cases:
-    /// - Non-sample records (e.g., PERF_RECORD_LOST)
-    /// - Malformed sample records (less than 16 bytes including the size field)
-    /// - Failed timestamp reads
-    /// This ensures such records are processed as soon as possible.
-    fn maintain_heap_entry(&mut self, idx: usize) -> Result<(), ReaderError> {
-        // If the ring is empty, remove its entry if it's in the heap
-        let bytes_remaining = self.rings[idx].bytes_remaining();
-        if bytes_remaining == 0 {
-            // empty, will not add to the heap
-            return Ok(());
-        };
+    /// Positions come from each ring's last read batch rather than the memory
+    /// shared with the writer, so taking the state does not disturb reading.
+    pub fn ring_states(&self) -> Vec<RingState> {
+        self.rings
+            .iter()
+            .zip(&self.ring_stats)
+            .enumerate()
+            .map(|(idx, (ring, stats))| RingState {
+                positions: ring.cached_positions(),
+                next_timestamp: self
+                    .order
+                    .as_ref()
+                    .filter(|_| self.active)
+                    .and_then(|order| order.key(idx))
+                    .map(OrderKey::timestamp),
+                stats: *stats,
+            })
+            .collect()
+    }
 
-        // Get the timestamp for the current entry
-        let mut timestamp = 0;
-        if self.rings[idx].peek_type() == PERF_RECORD_SAMPLE {
-            // Sample records have an 8-byte timestamp after the header
-            // Skip the first 8 bytes (RECORD_SAMPLE's size and our message type) and read the timestamp
-            let mut buf = [0u8; 8];
-            if self.rings[idx]
-                .peek_copy(&mut buf, offset_of!(SampleHeader, timestamp) as u16)
-                .is_ok()
-            {
-                timestamp = u64::from_le_bytes(buf);
+    /// Returns the indices of rings whose consumer has stopped advancing
+    ///
+    /// A ring is stalled when more than `min_pending` bytes await the
+    /// consumer at this check and at the previous one, and the consumer did
+    /// not release anything in between. The first check only records the
+    /// rings, so a stall shows up on the second consecutive check. Data is
+    /// released when a batch finishes, so checks should be further apart
+    /// than the longest batch. Checking does not affect reading.
+    pub fn stalled_rings(&self, min_pending: u32) -> Vec<usize> {
+        let mut checks = self.stall_checks.borrow_mut();
+        checks.resize(self.rings.len(), None);
+
+        let mut stalled = Vec::new();
+        for (idx, (ring, check)) in self.rings.iter().zip(checks.iter_mut()).enumerate() {
+            let tail = ring.consumer_tail();
+            let pending = ring.kernel_head().wrapping_sub(tail);
+            if pending <= u64::from(min_pending) {
+                *check = None;
+                continue;
             }
+            if *check == Some(tail) {
+                stalled.push(idx);
+            }
+            *check = Some(tail);
         }
-        // if we cannot read the timestamp, leave it as 0 (most urgent to process)
+        stalled
+    }
 
-        // Update or add the entry
-        let entry = PerfEntry {
-            timestamp,
-            ring_index: idx,
-        };
+    fn total_records(&self) -> u64 {
+        self.ring_stats.iter().map(|stats| stats.records).sum()
+    }
 
-        // Add new entry
-        self.heap.push(entry);
-        self.in_heap[idx] = true;
+    /// Sum of the rings' read positions; positions only move forward, so the
+    /// wrapping difference between two sums is the bytes read in between
+    fn total_read_position(&self) -> u64 {
+        self.rings
+            .iter()
+            .map(|ring| ring.cached_positions().head)
+            .fold(0, u64::wrapping_add)
+    }
 
-        Ok(())
+    fn peek(&self) -> Result<(OrderKey, usize), ReaderError> {
+        if !self.active {
+            return Err(ReaderError::NotActive);
+        }
+
+        self.order
+            .as_ref()
+            .and_then(RingOrder::peek)
+            .ok_or(ReaderError::BufferEmpty)
+    }
+
+    /// Returns the order key of the ring's next record, or `None` if it is empty.
+    ///
+    /// PERF_RECORD_SAMPLE records are read with [`SampleHeader::peek_timestamp`].
+    ///
+    /// The following records are `OrderKey::Untimestamped`:
+    /// - Non-sample records (e.g., PERF_RECORD_LOST), including unknown types
+    /// - Malformed sample records (less than 16 bytes including the size field)
+    /// - Failed timestamp reads
+    ///
+    /// This ensures such records are processed as soon as possible, while
+    /// samples with a timestamp of 0 keep their place among other samples.
+    fn next_key(ring: &PerfRing) -> Option<OrderKey> {
+        if ring.bytes_remaining() == 0 {
+            // empty, will not be ordered
+            return None;
+        }
+
+        let key = match ring.peek_record_type() {
+            PerfRecordType::Sample => SampleHeader::peek_timestamp(ring)
+                .map_or(OrderKey::Untimestamped, OrderKey::Timestamp),
+            // Only samples are read with the SampleHeader layout
+            PerfRecordType::Mmap
+            | PerfRecordType::Lost
+            | PerfRecordType::Comm
+            | PerfRecordType::Exit
+            | PerfRecordType::Throttle
+            | PerfRecordType::Unthrottle
+            | PerfRecordType::Fork
+            | PerfRecordType::Read
+            | PerfRecordType::Mmap2
+            | PerfRecordType::Aux
+            | PerfRecordType::ItraceStart
+            | PerfRecordType::LostSamples
+            | PerfRecordType::Switch
+            | PerfRecordType::SwitchCpuWide
+            | PerfRecordType::Namespaces
+            | PerfRecordType::Ksymbol
+            | PerfRecordType::BpfEvent
+            | PerfRecordType::Cgroup
+            | PerfRecordType::TextPoke
+            | PerfRecordType::AuxOutputHwId
+            | PerfRecordType::Unknown(_) => OrderKey::Untimestamped,
+        };
+
+        Some(key)
     }
 }
 
@@ -238,9 +766,13 @@ impl Default for Reader {
 
 #[cfg(test)]
 mod tests {
-    use crate::PERF_RECORD_LOST;
+    use proptest::prelude::*;
 
     use super::*;
+    use crate::{
+        lost_record, sample_payload, write_lost, write_sample, PERF_RECORD_LOST,
+        PERF_RECORD_SAMPLE, PERF_RECORD_THROTTLE,
+    };
 
     #[test]
     fn test_ring_reader() {
@@ -290,13 +822,8 @@ mod tests {
         assert!(matches!(reader.pop(), Err(ReaderError::NotActive)));
 
         // Create events with timestamps
-        let mut event1 = vec![0u8; 20]; // 8 bytes for timestamp + "event1"
-        event1[4..12].copy_from_slice(&100u64.to_le_bytes()); // timestamp 100
-        event1[12..20].copy_from_slice(b"event1  ");
-
-        let mut event2 = vec![0u8; 20]; // 8 bytes for timestamp + "event2"
-        event2[4..12].copy_from_slice(&200u64.to_le_bytes()); // timestamp 200
-        event2[12..20].copy_from_slice(b"event2  ");
+        let event1 = sample_payload(0, 100, b"event1  ");
+        let event2 = sample_payload(0, 200, b"event2  ");
 
         // Write events to rings
         ring1.start_write_batch();
@@ -342,7 +869,8 @@ mod tests {
             );
 
             let mut ring_data = vec![0u8; expected_ring_data[i].len()];
-            ring.peek_copy(&mut ring_data, 4).unwrap();
+            ring.peek_copy(&mut ring_data, SampleHeader::SIZE_PREFIX_LEN as u16)
+                .unwrap();
 
             assert_eq!(
                 &ring_data[..],
@@ -374,6 +902,480 @@ mod tests {
         assert!(matches!(reader.pop(), Err(ReaderError::NotActive)));
     }
 
+    #[test]
+    fn test_peek_record_type() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        ring.start_write_batch();
+        write_lost(&mut ring, 0, 3).unwrap();
+        write_sample(&mut ring, 1, 100, &[0u8; 8]).unwrap();
+        ring.write(&[0u8; 16], PERF_RECORD_THROTTLE).unwrap();
+        ring.finish_write_batch();
+
+        // Each record's type is decoded
+        reader.start().unwrap();
+        let mut record_types = Vec::new();
+        while !reader.is_empty() {
+            let (ring, _) = reader.current_ring().unwrap();
+            record_types.push(ring.peek_record_type());
+            reader.pop().unwrap();
+        }
+        reader.finish().unwrap();
+        assert_eq!(
+            record_types,
+            vec![
+                PerfRecordType::Lost,
+                PerfRecordType::Sample,
+                PerfRecordType::Throttle,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rings_pending_bytes() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data1 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        let mut data2 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() })
+            .unwrap();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() })
+            .unwrap();
+
+        let mut ring1 =
+            unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() };
+        let mut ring2 =
+            unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() };
+
+        // Two records in the first ring, one in the second
+        ring1.start_write_batch();
+        write_sample(&mut ring1, 0, 100, &[0u8; 8]).unwrap();
+        write_sample(&mut ring1, 0, 100, &[0u8; 8]).unwrap();
+        ring1.finish_write_batch();
+        ring2.start_write_batch();
+        write_sample(&mut ring2, 0, 100, &[0u8; 8]).unwrap();
+        ring2.finish_write_batch();
+
+        let pending = |reader: &Reader| -> Vec<u64> {
+            reader
+                .rings()
+                .iter()
+                .map(|ring| ring.kernel_head() - ring.consumer_tail())
+                .collect()
+        };
+        assert_eq!(pending(&reader), vec![64, 32]);
+
+        // Consuming within a batch releases nothing until the batch finishes
+        reader.start().unwrap();
+        reader.pop().unwrap();
+        assert_eq!(pending(&reader), vec![64, 32]);
+
+        while !reader.is_empty() {
+            reader.pop().unwrap();
+        }
+        reader.finish().unwrap();
+        assert_eq!(pending(&reader), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_stalled_rings() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data1 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        let mut data2 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() })
+            .unwrap();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() })
+            .unwrap();
+        let mut ring1 =
+            unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() };
+        let mut ring2 =
+            unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() };
+
+        // Each round adds two 32 byte samples to the first ring and one to
+        // the second
+        let mut timestamp = 0u64;
+        let mut fill = || {
+            ring1.start_write_batch();
+            for _ in 0..2 {
+                timestamp += 1;
+                write_sample(&mut ring1, 0, timestamp, &[0u8; 8]).unwrap();
+            }
+            ring1.finish_write_batch();
+            ring2.start_write_batch();
+            write_sample(&mut ring2, 0, timestamp, &[0u8; 8]).unwrap();
+            ring2.finish_write_batch();
+        };
+
+        // Nothing is stalled before anything is pending, nor on the first
+        // check that sees data pending
+        assert!(reader.stalled_rings(40).is_empty());
+        fill();
+        assert!(reader.stalled_rings(40).is_empty());
+
+        // The rings keep filling without being read: the first ring is above
+        // the threshold on consecutive checks, the second only now
+        fill();
+        assert_eq!(reader.stalled_rings(40), vec![0]);
+        fill();
+        assert_eq!(reader.stalled_rings(40), vec![0, 1]);
+
+        // A consumer that advances is not stalled, even with data remaining
+        reader.set_batch_limit(Some(BatchLimit {
+            max_events: 1,
+            ..BatchLimit::default()
+        }));
+        reader.start().unwrap();
+        while !reader.is_empty() {
+            reader.pop().unwrap();
+        }
+        reader.finish().unwrap();
+        assert!(reader.stalled_rings(40).is_empty());
+        assert_eq!(reader.stalled_rings(40), vec![0, 1]);
+
+        // Draining the rings clears the stall
+        reader.set_batch_limit(None);
+        reader.start().unwrap();
+        while !reader.is_empty() {
+            reader.pop().unwrap();
+        }
+        reader.finish().unwrap();
+        assert!(reader.stalled_rings(40).is_empty());
+        assert!(reader.stalled_rings(0).is_empty());
+    }
+
+    #[test]
+    fn test_batch_summary() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data1 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        let mut data2 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() })
+            .unwrap();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() })
+            .unwrap();
+        let mut ring1 =
+            unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() };
+        let mut ring2 =
+            unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() };
+
+        // Not in a batch
+        assert_eq!(
+            reader.finish_with_summary().unwrap(),
+            BatchSummary::default()
+        );
+
+        // Three 32 byte samples on ring 0 and a 24 byte lost record on ring 1
+        ring1.start_write_batch();
+        for timestamp in 1..=3u64 {
+            write_sample(&mut ring1, 0, timestamp, &[0u8; 8]).unwrap();
+        }
+        ring1.finish_write_batch();
+        ring2.start_write_batch();
+        write_lost(&mut ring2, 0, 5).unwrap();
+        ring2.finish_write_batch();
+
+        reader.start().unwrap();
+        while !reader.is_empty() {
+            reader.pop().unwrap();
+        }
+        assert_eq!(
+            reader.finish_with_summary().unwrap(),
+            BatchSummary {
+                events_popped: 4,
+                bytes_consumed: 120,
+                rings_nonempty_at_finish: 0,
+                max_fill_ratio: 0.0,
+            }
+        );
+
+        // A batch limit leaves records behind, and a ring written during the
+        // batch is not empty at its end either
+        ring1.start_write_batch();
+        for timestamp in 4..=6u64 {
+            write_sample(&mut ring1, 0, timestamp, &[0u8; 8]).unwrap();
+        }
+        ring1.finish_write_batch();
+        reader.set_batch_limit(Some(BatchLimit {
+            max_events: 1,
+            ..Default::default()
+        }));
+        reader.start().unwrap();
+        ring2.start_write_batch();
+        write_sample(&mut ring2, 0, 10, &[0u8; 8]).unwrap();
+        ring2.finish_write_batch();
+        while !reader.is_empty() {
+            reader.pop().unwrap();
+        }
+        assert_eq!(
+            reader.finish_with_summary().unwrap(),
+            BatchSummary {
+                events_popped: 1,
+                bytes_consumed: 32,
+                rings_nonempty_at_finish: 2,
+                max_fill_ratio: 64.0 / 8192.0,
+            }
+        );
+
+        // An empty batch
+        reader.set_batch_limit(None);
+        reader.start().unwrap();
+        while !reader.is_empty() {
+            reader.pop().unwrap();
+        }
+        reader.finish().unwrap();
+        reader.start().unwrap();
+        let summary = reader.finish_with_summary().unwrap();
+        assert_eq!(summary.events_popped, 0);
+        assert_eq!(summary.bytes_consumed, 0);
+        assert_eq!(summary.rings_nonempty_at_finish, 0);
+    }
+
+    #[test]
+    fn test_ring_states() {
+        for ordering in [ReaderOrdering::Tournament, ReaderOrdering::Heap] {
+            let mut reader = Reader::with_ordering(ordering);
+            let page_size = 4096u64;
+            let n_pages = 2u32;
+            let mut data1 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+            let mut data2 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+            reader
+                .add_ring(unsafe {
+                    PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap()
+                })
+                .unwrap();
+            reader
+                .add_ring(unsafe {
+                    PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap()
+                })
+                .unwrap();
+
+            let mut ring1 =
+                unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() };
+            ring1.start_write_batch();
+            write_sample(&mut ring1, 0, 100, &[0u8; 8]).unwrap();
+            write_sample(&mut ring1, 0, 200, &[0u8; 8]).unwrap();
+            ring1.finish_write_batch();
+
+            // Before a batch, nothing is cached or ordered
+            let states = reader.ring_states();
+            assert_eq!(states.len(), 2);
+            assert_eq!(states[0].positions.tail, 0);
+            assert_eq!(states[0].next_timestamp, None);
+
+            reader.start().unwrap();
+            reader.pop().unwrap();
+            let states = reader.ring_states();
+            assert_eq!(
+                states[0].positions,
+                RingPositions {
+                    head: 32,
+                    tail: 64,
+                    size: page_size * u64::from(n_pages),
+                }
+            );
+            assert_eq!(states[0].positions.fill_ratio(), 32.0 / 8192.0);
+            assert_eq!(states[0].next_timestamp, Some(200));
+            assert_eq!(states[0].stats.records, 1);
+            // The empty ring is not in the merge order
+            assert_eq!(states[1].next_timestamp, None);
+
+            // Records the writer adds during the batch are not seen
+            let mut ring2 =
+                unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() };
+            ring2.start_write_batch();
+            write_sample(&mut ring2, 0, 50, &[0u8; 8]).unwrap();
+            ring2.finish_write_batch();
+            assert_eq!(reader.ring_states()[1].positions.tail, 0);
+            reader.finish().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_mixed_sources() {
+        use crate::{MemoryStorage, Storage};
+
+        let mut reader = Reader::new();
+
+        // An injection ring in memory storage, without a file descriptor
+        let mut memory = MemoryStorage::new(2).unwrap();
+        let (n_pages, page_size) = (memory.num_data_pages(), memory.page_size());
+        reader
+            .add_ring_with_source(
+                unsafe {
+                    PerfRing::init_contiguous(memory.data_mut(), n_pages, page_size).unwrap()
+                },
+                RingSource::new("markers"),
+            )
+            .unwrap();
+
+        // A kernel-like ring with the file descriptor of its perf event
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        reader
+            .add_ring_with_source(
+                unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() },
+                RingSource::new("cpu 0").with_fd(7),
+            )
+            .unwrap();
+
+        assert_eq!(reader.sources()[0].label(), "markers");
+        assert_eq!(reader.sources()[0].fd(), None);
+        assert_eq!(reader.sources()[1].label(), "cpu 0");
+        assert_eq!(reader.sources()[1].fd(), Some(7));
+
+        let mut markers =
+            unsafe { PerfRing::init_contiguous(memory.data_mut(), n_pages, page_size).unwrap() };
+        markers.start_write_batch();
+        write_sample(&mut markers, 0, 200, &[0u8; 8]).unwrap();
+        markers.finish_write_batch();
+        let mut kernel =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        kernel.start_write_batch();
+        write_sample(&mut kernel, 0, 100, &[0u8; 8]).unwrap();
+        kernel.finish_write_batch();
+
+        // Records from both rings are read in timestamp order
+        let mut buf = Vec::new();
+        let mut read = Vec::new();
+        reader.start().unwrap();
+        while !reader.is_empty() {
+            let (_, ring_index) = reader.current_ring().unwrap();
+            let (_, timestamp) = reader.next_event(&mut buf).unwrap().unwrap();
+            read.push((ring_index, timestamp));
+        }
+        reader.finish().unwrap();
+        assert_eq!(read, vec![(1, 100), (0, 200)]);
+
+        // Rings added without a source get an unlabeled one
+        let mut unlabeled = Reader::new();
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        unlabeled
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+        assert_eq!(unlabeled.sources()[0].label(), "");
+        assert_eq!(unlabeled.sources()[0].fd(), None);
+    }
+
+    #[test]
+    fn test_sample_framing() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        // Messages whose size field needs 0 to 7 bytes of padding
+        for (i, len) in (12..20).enumerate() {
+            let timestamp = 1000 + i as u64;
+            ring.start_write_batch();
+            write_sample(&mut ring, 7, timestamp, &vec![0u8; len - 12]).unwrap();
+            ring.finish_write_batch();
+
+            ring.start_read_batch();
+            assert_eq!(SampleHeader::peek_timestamp(&ring).unwrap(), timestamp);
+
+            let mut record = vec![0u8; ring.peek_size().unwrap()];
+            ring.peek_copy(&mut record, 0).unwrap();
+            let header: &SampleHeader = plain::from_bytes(&record).unwrap();
+            assert_eq!(header.type_, 7);
+            assert_eq!(header.timestamp, timestamp);
+
+            // The size field covers the padded message but not itself
+            let raw_size = header.size as usize;
+            assert_eq!(raw_size, SampleHeader::raw_size(len) as usize);
+            assert!(raw_size >= len && raw_size < len + 8);
+            assert_eq!(SampleHeader::SIZE_PREFIX_LEN + raw_size, record.len());
+
+            ring.pop().unwrap();
+            ring.finish_read_batch();
+        }
+    }
+
+    #[test]
+    fn test_next_event() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data1 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        let mut data2 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() })
+            .unwrap();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() })
+            .unwrap();
+        let mut ring1 =
+            unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() };
+        let mut ring2 =
+            unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() };
+
+        let mut buf = Vec::new();
+        assert!(matches!(
+            reader.next_event(&mut buf),
+            Err(ReaderError::NotActive)
+        ));
+
+        ring1.start_write_batch();
+        write_sample(&mut ring1, 1, 100, &[0u8; 8]).unwrap();
+        write_sample(&mut ring1, 1, 300, &[0u8; 8]).unwrap();
+        ring1.finish_write_batch();
+        ring2.start_write_batch();
+        write_lost(&mut ring2, 0, 1).unwrap();
+        write_sample(&mut ring2, 2, 200, &[0u8; 8]).unwrap();
+        write_sample(&mut ring2, 2, 400, &[0u8; 8]).unwrap();
+        ring2.finish_write_batch();
+
+        reader.start().unwrap();
+        let mut events = Vec::new();
+        while let Some((record_type, timestamp)) = reader.next_event(&mut buf).unwrap() {
+            let message_type = if record_type == PERF_RECORD_SAMPLE {
+                let header: &SampleHeader = plain::from_bytes(&buf).unwrap();
+                assert_eq!(header.timestamp, timestamp);
+                header.type_
+            } else {
+                assert_eq!(buf.len(), 16);
+                0
+            };
+            events.push((record_type, message_type, timestamp));
+        }
+        assert_eq!(
+            events,
+            vec![
+                (PERF_RECORD_LOST, 0, 0),
+                (PERF_RECORD_SAMPLE, 1, 100),
+                (PERF_RECORD_SAMPLE, 2, 200),
+                (PERF_RECORD_SAMPLE, 1, 300),
+                (PERF_RECORD_SAMPLE, 2, 400),
+            ]
+        );
+        assert!(reader.is_empty());
+        assert_eq!(reader.ring_stats()[0].records, 2);
+        assert_eq!(reader.ring_stats()[1].records, 3);
+        assert_eq!(reader.ring_stats()[1].lost_records, 1);
+        reader.finish().unwrap();
+    }
+
     #[test]
     fn test_lost_records() {
         let mut reader = Reader::new();
@@ -396,18 +1398,10 @@ mod tests {
             unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() };
 
         // Test 1: Show that events within a single ring maintain their order regardless of type
-        let mut event1 = vec![0u8; 20];
-        event1[4..12].copy_from_slice(&100u64.to_le_bytes()); // timestamp 100
-        event1[12..20].copy_from_slice(b"event1  ");
-
-        let mut event2 = vec![0u8; 20]; // Lost event data
-        event2[4..12].copy_from_slice(&0u64.to_le_bytes()); // timestamp doesn't matter for lost events
-        event2[12..20].copy_from_slice(b"lost!   ");
-
         // Write both events to ring1
         ring1.start_write_batch();
-        ring1.write(&event1, PERF_RECORD_SAMPLE).unwrap();
-        ring1.write(&event2, PERF_RECORD_LOST).unwrap();
+        write_sample(&mut ring1, 0, 100, b"event1  ").unwrap();
+        write_lost(&mut ring1, 0, 1).unwrap();
         ring1.finish_write_batch();
 
         // Start reader and verify events come in ring order (not by type)
@@ -444,20 +1438,13 @@ mod tests {
         // Test 2: Show that lost events from one ring are processed before normal events from another ring
         // Ring1: Normal event with timestamp 100
         // Ring2: Lost event (should get timestamp 0)
-        let mut normal_event = vec![0u8; 20];
-        normal_event[4..12].copy_from_slice(&100u64.to_le_bytes()); // timestamp 100
-        normal_event[12..20].copy_from_slice(b"normal  ");
-
-        let mut lost_event = vec![0u8; 16];
-        lost_event[8..16].copy_from_slice(b"lost!   ");
-
         // Write events to rings
         ring1.start_write_batch();
-        ring1.write(&normal_event, PERF_RECORD_SAMPLE).unwrap();
+        write_sample(&mut ring1, 0, 100, b"normal  ").unwrap();
         ring1.finish_write_batch();
 
         ring2.start_write_batch();
-        ring2.write(&lost_event, PERF_RECORD_LOST).unwrap();
+        write_lost(&mut ring2, 0, 1).unwrap();
         ring2.finish_write_batch();
 
         // Start reader and verify lost event comes first
@@ -500,5 +1487,429 @@ mod tests {
         );
 
         reader.finish().unwrap();
+
+        // Ring 0 delivered two samples and one lost record, ring 1 one lost record
+        assert_eq!(reader.ring_stats()[0].records, 3);
+        assert_eq!(reader.ring_stats()[0].lost_records, 1);
+        assert_eq!(reader.ring_stats()[1].records, 1);
+        assert_eq!(reader.ring_stats()[1].lost_records, 1);
+    }
+
+    #[test]
+    fn test_zero_timestamp_samples() {
+        for ordering in [ReaderOrdering::Tournament, ReaderOrdering::Heap] {
+            let page_size = 4096u64;
+            let n_pages = 2u32;
+            let mut buffers: Vec<Vec<u8>> = (0..3)
+                .map(|_| vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize])
+                .collect();
+
+            let mut reader = Reader::with_ordering(ordering);
+            let mut writers = Vec::new();
+            for buffer in buffers.iter_mut() {
+                reader
+                    .add_ring(unsafe {
+                        PerfRing::init_contiguous(buffer, n_pages, page_size).unwrap()
+                    })
+                    .unwrap();
+                writers.push(unsafe {
+                    PerfRing::init_contiguous(buffer, n_pages, page_size).unwrap()
+                });
+            }
+
+            // Ring 0: a valid sample at timestamp 0, ring 1: a lost record,
+            // ring 2: a sample at timestamp 1
+            for (writer, (event, event_type)) in writers.iter_mut().zip([
+                (sample_payload(0, 0, &[0u8; 8]), PERF_RECORD_SAMPLE),
+                (lost_record(0, 1).to_vec(), PERF_RECORD_LOST),
+                (sample_payload(0, 1, &[0u8; 8]), PERF_RECORD_SAMPLE),
+            ]) {
+                writer.start_write_batch();
+                writer.write(&event, event_type).unwrap();
+                writer.finish_write_batch();
+            }
+
+            // The lost record comes first despite its higher ring index, and
+            // the zero timestamp sample still precedes the later sample
+            reader.start().unwrap();
+            let mut delivered = Vec::new();
+            while !reader.is_empty() {
+                let (ring, ring_index) = reader.current_ring().unwrap();
+                delivered.push((
+                    ring_index,
+                    ring.peek_record_type(),
+                    reader.peek_timestamp().unwrap(),
+                ));
+                reader.pop().unwrap();
+            }
+            reader.finish().unwrap();
+
+            assert_eq!(
+                delivered,
+                vec![
+                    (1, PerfRecordType::Lost, 0),
+                    (0, PerfRecordType::Sample, 0),
+                    (2, PerfRecordType::Sample, 1),
+                ],
+                "{:?}",
+                ordering
+            );
+        }
+    }
+
+    #[test]
+    fn test_batch_limit() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data1 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        let mut data2 = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let ring1 = unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() };
+        let ring2 = unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() };
+        reader.add_ring(ring1).unwrap();
+        reader.add_ring(ring2).unwrap();
+
+        let mut ring1 =
+            unsafe { PerfRing::init_contiguous(&mut data1, n_pages, page_size).unwrap() };
+        let mut ring2 =
+            unsafe { PerfRing::init_contiguous(&mut data2, n_pages, page_size).unwrap() };
+
+        // A burst of 10 events on ring 0 and a single event on ring 1. Each
+        // record is 32 bytes: 8 byte header, 4 byte size and 20 bytes of data.
+        ring1.start_write_batch();
+        for timestamp in 1..=10u64 {
+            write_sample(&mut ring1, 0, timestamp, &[0u8; 8]).unwrap();
+        }
+        ring1.finish_write_batch();
+
+        ring2.start_write_batch();
+        write_sample(&mut ring2, 0, 100, &[0u8; 8]).unwrap();
+        ring2.finish_write_batch();
+
+        let drain = |reader: &mut Reader| {
+            let mut delivered = Vec::new();
+            reader.start().unwrap();
+            while !reader.is_empty() {
+                let timestamp = reader.peek_timestamp().unwrap();
+                let (_, ring_index) = reader.current_ring().unwrap();
+                delivered.push((ring_index, timestamp));
+                reader.pop().unwrap();
+            }
+            reader.finish().unwrap();
+            delivered
+        };
+
+        // An event cap leaves the rest of the burst behind, but the other
+        // ring is still read in the same batch
+        reader.set_batch_limit(Some(BatchLimit {
+            max_events: 4,
+            ..Default::default()
+        }));
+        assert_eq!(
+            drain(&mut reader),
+            vec![(0, 1), (0, 2), (0, 3), (0, 4), (1, 100)]
+        );
+
+        // A byte cap admits only whole records that fit
+        reader.set_batch_limit(Some(BatchLimit {
+            max_bytes: 80,
+            ..Default::default()
+        }));
+        assert_eq!(drain(&mut reader), vec![(0, 5), (0, 6)]);
+
+        // A record larger than the byte cap is still read, one per batch
+        reader.set_batch_limit(Some(BatchLimit {
+            max_bytes: 1,
+            ..Default::default()
+        }));
+        assert_eq!(drain(&mut reader), vec![(0, 7)]);
+
+        // Removing the cap drains the remainder
+        reader.set_batch_limit(None);
+        assert_eq!(drain(&mut reader), vec![(0, 8), (0, 9), (0, 10)]);
+        assert!(drain(&mut reader).is_empty());
+    }
+
+    #[test]
+    fn test_batch_limit_skips_corrupt_header() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+        let mut writer =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        reader.set_batch_limit(Some(BatchLimit::default()));
+
+        writer.start_write_batch();
+        for timestamp in 1..=3u64 {
+            write_sample(&mut writer, 0, timestamp, &[0u8; 8]).unwrap();
+        }
+        writer.finish_write_batch();
+
+        // The second 32 byte record's header claims a size of 0
+        let size_offset = (page_size + 32 + 6) as usize;
+        unsafe { std::ptr::write_unaligned(data.as_mut_ptr().add(size_offset) as *mut u16, 0) };
+
+        let mut timestamps = Vec::new();
+        for _ in 0..2 {
+            reader.start().unwrap();
+            while !reader.is_empty() {
+                timestamps.push(reader.peek_timestamp().unwrap());
+                reader.pop().unwrap();
+            }
+            reader.finish().unwrap();
+        }
+        assert_eq!(timestamps, vec![1]);
+        assert_eq!(reader.ring_stats()[0].corrupt, 1);
+        assert_eq!(reader.ring_stats()[0].records, 1);
+    }
+
+    /// Writes `events` (lost flag, timestamp) to a fresh set of rings and drains
+    /// them with the given ordering, returning (ring index, timestamp) in delivery order
+    fn delivery_order(
+        ordering: ReaderOrdering,
+        rings: &[Vec<(bool, u64)>],
+        batches: usize,
+    ) -> Vec<(usize, u64)> {
+        let page_size = 4096u64;
+        let n_pages = 4u32;
+        let mut buffers: Vec<Vec<u8>> = rings
+            .iter()
+            .map(|_| vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize])
+            .collect();
+
+        let mut reader = Reader::with_ordering(ordering);
+        let mut writers = Vec::new();
+        for buffer in buffers.iter_mut() {
+            reader
+                .add_ring(unsafe { PerfRing::init_contiguous(buffer, n_pages, page_size).unwrap() })
+                .unwrap();
+            writers.push(unsafe { PerfRing::init_contiguous(buffer, n_pages, page_size).unwrap() });
+        }
+
+        let mut delivered = Vec::new();
+        for batch in 0..batches {
+            // Write this batch's share of each ring's events
+            for (writer, events) in writers.iter_mut().zip(rings) {
+                let per_batch = events.len().div_ceil(batches);
+                writer.start_write_batch();
+                for &(lost, timestamp) in events.iter().skip(batch * per_batch).take(per_batch) {
+                    if lost {
+                        write_lost(writer, 0, 1).unwrap();
+                    } else {
+                        write_sample(writer, 0, timestamp, &[0u8; 8]).unwrap();
+                    }
+                }
+                writer.finish_write_batch();
+            }
+
+            // Stop early in all but the final batch, so entries carry over
+            // between batches
+            let limit = if batch + 1 < batches {
+                delivered.len() + 8
+            } else {
+                usize::MAX
+            };
+            reader.start().unwrap();
+            while !reader.is_empty() && delivered.len() < limit {
+                let timestamp = reader.peek_timestamp().unwrap();
+                let (_, ring_index) = reader.current_ring().unwrap();
+                delivered.push((ring_index, timestamp));
+                reader.pop().unwrap();
+            }
+            reader.finish().unwrap();
+        }
+        delivered
+    }
+
+    #[test]
+    fn test_resync_after_overwrite() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+        let mut writer =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+
+        writer.start_write_batch();
+        for timestamp in [1u64, 2] {
+            write_sample(&mut writer, 0, timestamp, &[0u8; 8]).unwrap();
+        }
+        writer.finish_write_batch();
+
+        reader.start().unwrap();
+        let (ring, _) = reader.current_ring().unwrap();
+        let size = ring.peek_size().unwrap();
+
+        // The writer laps the reader between peeking and copying
+        let meta = data.as_mut_ptr() as *mut crate::PerfEventMmapPage;
+        let buf_len = page_size * u64::from(n_pages);
+        unsafe {
+            (*meta)
+                .data_head
+                .store(buf_len + 64, std::sync::atomic::Ordering::Release);
+        }
+
+        let (ring, _) = reader.current_ring().unwrap();
+        let mut buf = vec![0u8; size];
+        assert!(matches!(
+            ring.peek_copy(&mut buf, 0),
+            Err(PerfRingError::Overwritten)
+        ));
+
+        reader.resync_current().unwrap();
+        assert!(reader.is_empty());
+        reader.finish().unwrap();
+
+        assert_eq!(reader.ring_stats()[0].overwritten, 1);
+        assert_eq!(reader.ring_stats()[0].records, 0);
+    }
+
+    #[test]
+    fn test_next_event_after_lap() {
+        let mut reader = Reader::new();
+
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() })
+            .unwrap();
+        let mut writer =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let meta = data.as_mut_ptr() as *mut crate::PerfEventMmapPage;
+
+        // The writer ignores the reader, like one in overwrite mode, and laps it
+        writer.start_write_batch();
+        for timestamp in 1u64..=500 {
+            if write_sample(&mut writer, 0, timestamp, &[0u8; 8]).is_err() {
+                writer.finish_write_batch();
+                let head = writer.kernel_head();
+                unsafe {
+                    (*meta)
+                        .data_tail
+                        .store(head, std::sync::atomic::Ordering::Release)
+                };
+                writer.start_write_batch();
+                write_sample(&mut writer, 0, timestamp, &[0u8; 8]).unwrap();
+            }
+        }
+        writer.finish_write_batch();
+
+        // The records the writer did not overwrite are still read, in order
+        reader.start().unwrap();
+        let mut buf = Vec::new();
+        let mut timestamps = Vec::new();
+        while let Some((_, timestamp)) = reader.next_event(&mut buf).unwrap() {
+            timestamps.push(timestamp);
+        }
+        reader.finish().unwrap();
+
+        let first = timestamps[0];
+        assert!(first > 1);
+        assert_eq!(timestamps, (first..=500).collect::<Vec<_>>());
+        assert_eq!(reader.ring_stats()[0].overwritten, 1);
+        assert_eq!(reader.ring_stats()[0].records, timestamps.len() as u64);
+    }
+
+    fn ring_events() -> impl Strategy<Value = Vec<Vec<(bool, u64)>>> {
+        prop::collection::vec(
+            prop::collection::vec((prop::bool::weighted(0.05), 0u64..50), 0..40),
+            1..40,
+        )
+        .prop_map(|rings| {
+            // Timestamps are non-decreasing within a ring, with frequent ties across rings
+            rings
+                .into_iter()
+                .map(|events| {
+                    let mut timestamp = 1;
+                    events
+                        .into_iter()
+                        .map(|(lost, delta)| {
+                            timestamp += delta;
+                            (lost, timestamp)
+                        })
+                        .collect()
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_equal_timestamp_order() {
+        // Ties, including between records without a timestamp, go to the
+        // lower ring index
+        let rings = vec![
+            vec![(false, 10), (false, 20)],
+            vec![(true, 0), (false, 10), (false, 20)],
+            vec![(true, 0), (false, 10)],
+        ];
+        let expected = vec![(1, 0), (2, 0), (0, 10), (1, 10), (2, 10), (0, 20), (1, 20)];
+        for ordering in [
+            ReaderOrdering::Auto,
+            ReaderOrdering::Tournament,
+            ReaderOrdering::Heap,
+        ] {
+            for batches in 1..=2 {
+                assert_eq!(
+                    delivery_order(ordering, &rings, batches),
+                    expected,
+                    "{:?} in {} batches",
+                    ordering,
+                    batches
+                );
+            }
+        }
+    }
+
+    /// Order of `rings` drained in one batch, merged by repeatedly taking the
+    /// head with the lowest (timestamp, ring index). Lost records have no
+    /// timestamp and sort as 0.
+    fn reference_order(rings: &[Vec<(bool, u64)>]) -> Vec<(usize, u64)> {
+        let mut heads = vec![0; rings.len()];
+        let mut order = Vec::new();
+        loop {
+            let next = rings
+                .iter()
+                .enumerate()
+                .filter_map(|(ring, events)| {
+                    let &(lost, timestamp) = events.get(heads[ring])?;
+                    Some((if lost { 0 } else { timestamp }, ring))
+                })
+                .min();
+            let Some((timestamp, ring)) = next else {
+                return order;
+            };
+            heads[ring] += 1;
+            order.push((ring, timestamp));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_tournament_matches_heap(rings in ring_events(), batches in 1usize..4) {
+            let heap = delivery_order(ReaderOrdering::Heap, &rings, batches);
+            let tournament = delivery_order(ReaderOrdering::Tournament, &rings, batches);
+            prop_assert_eq!(heap.len(), rings.iter().map(Vec::len).sum::<usize>());
+            prop_assert_eq!(heap, tournament);
+        }
+
+        #[test]
+        fn test_orderings_match_reference(rings in ring_events()) {
+            let expected = reference_order(&rings);
+            for ordering in [ReaderOrdering::Tournament, ReaderOrdering::Heap] {
+                prop_assert_eq!(delivery_order(ordering, &rings, 1), expected.clone());
+            }
+        }
     }
 }
@@ -1,7 +1,11 @@
+use collector_errors::{Classified, ErrorCode};
+use std::marker::PhantomData;
 use std::ptr::{self, NonNull};
 use std::sync::atomic::{AtomicU64, Ordering};
 use thiserror::Error;
 
+use crate::SampleHeader;
+
 /// Errors that can occur when using the perf ring buffer
 #[derive(Error, Debug)]
 pub enum PerfRingError {
@@ -25,6 +29,33 @@ pub enum PerfRingError {
 
     #[error("requested read larger than data")]
     SizeExceeded,
+
+    #[error("record overwritten by the writer while reading")]
+    Overwritten,
+
+    #[error("inconsistent ring layout: metadata page has data_offset {data_offset} and data_size {data_size}, expected {expected_size} data bytes in a {buffer_len} byte mapping")]
+    LayoutMismatch {
+        data_offset: u64,
+        data_size: u64,
+        expected_size: u64,
+        buffer_len: usize,
+    },
+}
+
+impl Classified for PerfRingError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            PerfRingError::InvalidBufferLength
+            | PerfRingError::NilBuffer
+            | PerfRingError::LayoutMismatch { .. } => ErrorCode::RingSetup,
+            PerfRingError::NoSpace | PerfRingError::CannotFit | PerfRingError::Overwritten => {
+                ErrorCode::RingLost
+            }
+            PerfRingError::BufferEmpty
+            | PerfRingError::EmptyWrite
+            | PerfRingError::SizeExceeded => ErrorCode::RingCorrupt,
+        }
+    }
 }
 
 /// PerfEventHeader represents the header of a perf event
@@ -49,12 +80,252 @@ pub struct PerfEventMmapPage {
     pub aux_size: u64,
 }
 
-/// Type constants for perf events
-pub const PERF_RECORD_SAMPLE: u32 = 9;
+/// Type constants for perf events, from `enum perf_event_type` in the kernel's perf_event.h
+pub const PERF_RECORD_MMAP: u32 = 1;
 pub const PERF_RECORD_LOST: u32 = 2;
+pub const PERF_RECORD_COMM: u32 = 3;
+pub const PERF_RECORD_EXIT: u32 = 4;
+pub const PERF_RECORD_THROTTLE: u32 = 5;
+pub const PERF_RECORD_UNTHROTTLE: u32 = 6;
+pub const PERF_RECORD_FORK: u32 = 7;
+pub const PERF_RECORD_READ: u32 = 8;
+pub const PERF_RECORD_SAMPLE: u32 = 9;
+pub const PERF_RECORD_MMAP2: u32 = 10;
+pub const PERF_RECORD_AUX: u32 = 11;
+pub const PERF_RECORD_ITRACE_START: u32 = 12;
+pub const PERF_RECORD_LOST_SAMPLES: u32 = 13;
+pub const PERF_RECORD_SWITCH: u32 = 14;
+pub const PERF_RECORD_SWITCH_CPU_WIDE: u32 = 15;
+pub const PERF_RECORD_NAMESPACES: u32 = 16;
+pub const PERF_RECORD_KSYMBOL: u32 = 17;
+pub const PERF_RECORD_BPF_EVENT: u32 = 18;
+pub const PERF_RECORD_CGROUP: u32 = 19;
+pub const PERF_RECORD_TEXT_POKE: u32 = 20;
+pub const PERF_RECORD_AUX_OUTPUT_HW_ID: u32 = 21;
+
+/// Bits of a record's `misc` field holding the CPU mode it was recorded in,
+/// from `PERF_RECORD_MISC_*` in the kernel's perf_event.h
+pub const PERF_RECORD_MISC_CPUMODE_MASK: u16 = 0x7;
+pub const PERF_RECORD_MISC_KERNEL: u16 = 1;
+pub const PERF_RECORD_MISC_USER: u16 = 2;
+pub const PERF_RECORD_MISC_HYPERVISOR: u16 = 3;
+pub const PERF_RECORD_MISC_GUEST_KERNEL: u16 = 4;
+pub const PERF_RECORD_MISC_GUEST_USER: u16 = 5;
+/// Set on samples whose instruction pointer is the exact one that
+/// triggered the event
+pub const PERF_RECORD_MISC_EXACT_IP: u16 = 1 << 14;
+
+/// Whether a record's `misc` field says it was recorded in kernel mode
+pub const fn is_kernel(misc: u16) -> bool {
+    misc & PERF_RECORD_MISC_CPUMODE_MASK == PERF_RECORD_MISC_KERNEL
+}
+
+/// Whether a record's `misc` field says it was recorded in user mode
+pub const fn is_user(misc: u16) -> bool {
+    misc & PERF_RECORD_MISC_CPUMODE_MASK == PERF_RECORD_MISC_USER
+}
+
+/// Whether a record's `misc` field says it was recorded in the hypervisor
+pub const fn is_hypervisor(misc: u16) -> bool {
+    misc & PERF_RECORD_MISC_CPUMODE_MASK == PERF_RECORD_MISC_HYPERVISOR
+}
+
+/// Whether a record's `misc` field marks its instruction pointer as exact
+pub const fn is_exact_ip(misc: u16) -> bool {
+    misc & PERF_RECORD_MISC_EXACT_IP != 0
+}
+
+/// Typed form of a perf record's `type_` field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfRecordType {
+    /// A memory mapping was created
+    Mmap,
+    /// Records were dropped because the ring was full
+    Lost,
+    /// A task changed its name
+    Comm,
+    /// A task exited
+    Exit,
+    /// The event was throttled by the kernel
+    Throttle,
+    /// The event was unthrottled
+    Unthrottle,
+    /// A task was forked
+    Fork,
+    /// Counter values read on task exit
+    Read,
+    /// A sample, e.g. written by bpf_perf_event_output
+    Sample,
+    /// A memory mapping was created, with its inode
+    Mmap2,
+    /// Data was written to the aux area
+    Aux,
+    /// Instruction tracing started for a task
+    ItraceStart,
+    /// Samples were dropped before reaching the ring
+    LostSamples,
+    /// A context switch in or out of the monitored task
+    Switch,
+    /// A context switch on a CPU-wide event
+    SwitchCpuWide,
+    /// A task's namespaces
+    Namespaces,
+    /// A kernel symbol was registered or unregistered
+    Ksymbol,
+    /// A BPF program was loaded or unloaded
+    BpfEvent,
+    /// A cgroup was created
+    Cgroup,
+    /// Kernel text was modified
+    TextPoke,
+    /// Hardware id of the aux output
+    AuxOutputHwId,
+    /// A type this crate does not know, e.g. from a newer kernel
+    Unknown(u32),
+}
+
+impl PerfRecordType {
+    /// Convert a raw record type
+    pub const fn from_u32(value: u32) -> Self {
+        match value {
+            PERF_RECORD_MMAP => PerfRecordType::Mmap,
+            PERF_RECORD_LOST => PerfRecordType::Lost,
+            PERF_RECORD_COMM => PerfRecordType::Comm,
+            PERF_RECORD_EXIT => PerfRecordType::Exit,
+            PERF_RECORD_THROTTLE => PerfRecordType::Throttle,
+            PERF_RECORD_UNTHROTTLE => PerfRecordType::Unthrottle,
+            PERF_RECORD_FORK => PerfRecordType::Fork,
+            PERF_RECORD_READ => PerfRecordType::Read,
+            PERF_RECORD_SAMPLE => PerfRecordType::Sample,
+            PERF_RECORD_MMAP2 => PerfRecordType::Mmap2,
+            PERF_RECORD_AUX => PerfRecordType::Aux,
+            PERF_RECORD_ITRACE_START => PerfRecordType::ItraceStart,
+            PERF_RECORD_LOST_SAMPLES => PerfRecordType::LostSamples,
+            PERF_RECORD_SWITCH => PerfRecordType::Switch,
+            PERF_RECORD_SWITCH_CPU_WIDE => PerfRecordType::SwitchCpuWide,
+            PERF_RECORD_NAMESPACES => PerfRecordType::Namespaces,
+            PERF_RECORD_KSYMBOL => PerfRecordType::Ksymbol,
+            PERF_RECORD_BPF_EVENT => PerfRecordType::BpfEvent,
+            PERF_RECORD_CGROUP => PerfRecordType::Cgroup,
+            PERF_RECORD_TEXT_POKE => PerfRecordType::TextPoke,
+            PERF_RECORD_AUX_OUTPUT_HW_ID => PerfRecordType::AuxOutputHwId,
+            other => PerfRecordType::Unknown(other),
+        }
+    }
+
+    /// Convert back to the raw record type
+    pub const fn as_u32(self) -> u32 {
+        match self {
+            PerfRecordType::Mmap => PERF_RECORD_MMAP,
+            PerfRecordType::Lost => PERF_RECORD_LOST,
+            PerfRecordType::Comm => PERF_RECORD_COMM,
+            PerfRecordType::Exit => PERF_RECORD_EXIT,
+            PerfRecordType::Throttle => PERF_RECORD_THROTTLE,
+            PerfRecordType::Unthrottle => PERF_RECORD_UNTHROTTLE,
+            PerfRecordType::Fork => PERF_RECORD_FORK,
+            PerfRecordType::Read => PERF_RECORD_READ,
+            PerfRecordType::Sample => PERF_RECORD_SAMPLE,
+            PerfRecordType::Mmap2 => PERF_RECORD_MMAP2,
+            PerfRecordType::Aux => PERF_RECORD_AUX,
+            PerfRecordType::ItraceStart => PERF_RECORD_ITRACE_START,
+            PerfRecordType::LostSamples => PERF_RECORD_LOST_SAMPLES,
+            PerfRecordType::Switch => PERF_RECORD_SWITCH,
+            PerfRecordType::SwitchCpuWide => PERF_RECORD_SWITCH_CPU_WIDE,
+            PerfRecordType::Namespaces => PERF_RECORD_NAMESPACES,
+            PerfRecordType::Ksymbol => PERF_RECORD_KSYMBOL,
+            PerfRecordType::BpfEvent => PERF_RECORD_BPF_EVENT,
+            PerfRecordType::Cgroup => PERF_RECORD_CGROUP,
+            PerfRecordType::TextPoke => PERF_RECORD_TEXT_POKE,
+            PerfRecordType::AuxOutputHwId => PERF_RECORD_AUX_OUTPUT_HW_ID,
+            PerfRecordType::Unknown(value) => value,
+        }
+    }
+}
+
+impl From<u32> for PerfRecordType {
+    fn from(value: u32) -> Self {
+        PerfRecordType::from_u32(value)
+    }
+}
+
+impl From<PerfRecordType> for u32 {
+    fn from(record_type: PerfRecordType) -> Self {
+        record_type.as_u32()
+    }
+}
+
+/// Where a ring's data area layout came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutSource {
+    /// The metadata page's data_offset and data_size
+    MetadataPage,
+    /// The caller's page count and size, for kernels that leave the metadata
+    /// page's layout fields zero
+    Caller,
+}
 
-/// PerfRing represents a perf ring buffer with shared metadata and data pages
-pub struct PerfRing {
+/// Location of a ring's data area within its mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingLayout {
+    /// Offset of the data area from the start of the mapping
+    pub data_offset: u64,
+    /// Size of the data area in bytes
+    pub data_size: u64,
+    /// Where the offset and size were taken from
+    pub source: LayoutSource,
+}
+
+/// Positions of a ring as of its last read batch
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RingPositions {
+    /// Position of the next record to read
+    pub head: u64,
+    /// Writer's position when the read batch started
+    pub tail: u64,
+    /// Size of the data area in bytes
+    pub size: u64,
+}
+
+impl RingPositions {
+    /// Fraction of the data area the batch had left to read
+    pub fn fill_ratio(&self) -> f64 {
+        self.tail.wrapping_sub(self.head) as f64 / self.size as f64
+    }
+}
+
+/// Bytes past the oldest intact byte of a lapped ring tried as the start of
+/// its first intact record. The record torn by the writer is rarely longer;
+/// when it is, [`PerfRing::resync`] skips to the writer's position.
+const RESYNC_SCAN_LEN: u64 = 4096;
+
+/// Buffer of a ring created over the caller's memory with
+/// [`PerfRing::init_contiguous`]. The caller keeps the memory alive, which a
+/// ring on another thread could not be sure of, so such a ring is neither
+/// `Send` nor `Sync`.
+pub struct Borrowed(PhantomData<*mut u8>);
+
+/// Buffer of a ring created with [`PerfRing::new_owned`], freed with the ring
+pub struct Owned {
+    _words: Vec<u64>,
+}
+
+/// PerfRing represents a perf ring buffer with shared metadata and data pages.
+///
+/// `B` holds the ring's buffer, or marks it as borrowed. The ring keeps raw
+/// pointers into the buffer, so it may only move between threads along with
+/// the buffer: a ring that owns its buffer is `Send` and `Sync`, and one over
+/// a borrowed buffer is neither.
+///
+/// ```compile_fail,E0277
+/// use perf_events::PerfRing;
+///
+/// let mut data = vec![0u8; 4096 * 3];
+/// let ring = unsafe { PerfRing::init_contiguous(&mut data, 2, 4096).unwrap() };
+/// std::thread::spawn(move || {
+///     let _ring = ring;
+/// });
+/// ```
+pub struct PerfRing<B = Borrowed> {
     // Shared metadata page
     meta: NonNull<PerfEventMmapPage>,
     // Data buffer
@@ -67,12 +338,18 @@ pub struct PerfRing {
     head: u64,
     // Current tail position for writing
     tail: u64,
+    // Data area layout and where it came from
+    layout: RingLayout,
+    // Owner of the buffer, or a marker that it is borrowed
+    _buffer: B,
 }
 
-// Safety: PerfRing needs to be Send+Sync because it's shared between threads
-// We're manually ensuring thread safety with atomics
-unsafe impl Send for PerfRing {}
-unsafe impl Sync for PerfRing {}
+// Safety: the pointers of an owning ring point into its heap buffer, which
+// does not move with the ring and lives as long as it does. Position updates
+// take `&mut self`, and the metadata page shared with the writer is only
+// accessed through atomics, so `&self` methods can run on several threads.
+unsafe impl Send for PerfRing<Owned> {}
+unsafe impl Sync for PerfRing<Owned> {}
 
 impl PerfRing {
     /// Initializes a PerfRing using contiguous memory
@@ -85,6 +362,38 @@ impl PerfRing {
         data: &mut [u8],
         n_pages: u32,
         page_size: u64,
+    ) -> Result<Self, PerfRingError> {
+        Self::init_with_buffer(data, n_pages, page_size, Borrowed(PhantomData))
+    }
+}
+
+impl PerfRing<Owned> {
+    /// Creates a ring over a zeroed buffer of its own, with a metadata page
+    /// followed by `n_pages` data pages. The ring can be sent to other threads.
+    pub fn new_owned(n_pages: u32, page_size: u64) -> Result<Self, PerfRingError> {
+        let total_len = page_size
+            .checked_mul(1 + u64::from(n_pages))
+            .ok_or(PerfRingError::InvalidBufferLength)? as usize;
+
+        // Allocated as u64 so the metadata page's atomics are aligned
+        let mut words = vec![0u64; total_len.div_ceil(8)];
+        let data =
+            unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, total_len) };
+
+        // Safety: the buffer is moved into the ring, and moving the vector
+        // does not move the memory the ring points into
+        unsafe { Self::init_with_buffer(data, n_pages, page_size, Owned { _words: words }) }
+    }
+}
+
+impl<B> PerfRing<B> {
+    /// Initializes a ring over `data`, whose memory `buffer` owns or whose
+    /// lifetime the caller guarantees
+    unsafe fn init_with_buffer(
+        data: &mut [u8],
+        n_pages: u32,
+        page_size: u64,
+        buffer: B,
     ) -> Result<Self, PerfRingError> {
         if data.is_empty() {
             return Err(PerfRingError::NilBuffer);
@@ -99,15 +408,38 @@ impl PerfRing {
         let meta_ptr = data.as_mut_ptr() as *mut PerfEventMmapPage;
         let meta = NonNull::new(meta_ptr).unwrap();
 
-        // If data_offset is not given (older kernels), we need to skip a full page,
-        // otherwise we skip data_offset bytes
-        let data_start = if (*meta_ptr).data_offset == 0 {
-            page_size
+        // Older kernels leave data_offset and data_size zero, and the data
+        // starts after a full metadata page. Otherwise the metadata page is
+        // authoritative and must agree with the caller's size.
+        let (data_offset, data_size) = ((*meta_ptr).data_offset, (*meta_ptr).data_size);
+        let layout = if data_offset == 0 && data_size == 0 {
+            RingLayout {
+                data_offset: page_size,
+                data_size: buf_len,
+                source: LayoutSource::Caller,
+            }
         } else {
-            (*meta_ptr).data_offset
+            RingLayout {
+                data_offset,
+                data_size,
+                source: LayoutSource::MetadataPage,
+            }
         };
 
-        let data_ptr = data.as_mut_ptr().add(data_start as usize);
+        let fits = layout
+            .data_offset
+            .checked_add(layout.data_size)
+            .is_some_and(|end| end <= data.len() as u64);
+        if layout.data_size != buf_len || layout.data_offset == 0 || !fits {
+            return Err(PerfRingError::LayoutMismatch {
+                data_offset,
+                data_size,
+                expected_size: buf_len,
+                buffer_len: data.len(),
+            });
+        }
+
+        let data_ptr = data.as_mut_ptr().add(layout.data_offset as usize);
         let data_tail = (*meta_ptr).data_tail.load(Ordering::Acquire);
         let data_head = (*meta_ptr).data_head.load(Ordering::Acquire);
 
@@ -118,9 +450,16 @@ impl PerfRing {
             buf_mask: buf_len - 1,
             head: data_tail,
             tail: data_head,
+            layout,
+            _buffer: buffer,
         })
     }
 
+    /// Returns the data area layout, for debugging
+    pub fn layout(&self) -> RingLayout {
+        self.layout
+    }
+
     /// Starts a write batch operation
     pub fn start_write_batch(&mut self) {
         // Get the current tail position from shared memory using atomic load
@@ -131,6 +470,17 @@ impl PerfRing {
 
     /// Writes data to the ring buffer with the given type
     pub fn write(&mut self, data: &[u8], event_type: u32) -> Result<usize, PerfRingError> {
+        self.write_with_misc(data, event_type, 0)
+    }
+
+    /// Writes data to the ring buffer with the given type and `misc` flags,
+    /// as the kernel sets them for the context a sample was taken in
+    pub fn write_with_misc(
+        &mut self,
+        data: &[u8],
+        event_type: u32,
+        misc: u16,
+    ) -> Result<usize, PerfRingError> {
         if data.is_empty() {
             return Err(PerfRingError::EmptyWrite);
         }
@@ -138,7 +488,7 @@ impl PerfRing {
         let mut unaligned_len = data.len() as u32 + std::mem::size_of::<PerfEventHeader>() as u32;
 
         if event_type == PERF_RECORD_SAMPLE {
-            unaligned_len += 4; // add the u32 size field
+            unaligned_len += SampleHeader::SIZE_PREFIX_LEN as u32; // add the u32 size field
         }
 
         // Calculate total size including header, aligned to 8 bytes
@@ -156,7 +506,7 @@ impl PerfRing {
             // Write header
             let header = PerfEventHeader {
                 type_: event_type,
-                misc: 0,
+                misc,
                 size: aligned_len as u16,
             };
             let header_pos = (self.tail & self.buf_mask) as usize;
@@ -168,9 +518,9 @@ impl PerfRing {
 
             if event_type == PERF_RECORD_SAMPLE {
                 // write the u32 size field
-                let size_value = ((data.len() + 4 + 7) & !7) as u32;
+                let size_value = SampleHeader::raw_size(data.len());
                 ptr::write(self.data.add(data_pos as usize) as *mut u32, size_value);
-                data_pos = (data_pos + 4) & self.buf_mask;
+                data_pos = (data_pos + SampleHeader::SIZE_PREFIX_LEN as u64) & self.buf_mask;
             }
 
             if data_pos as usize + data.len() <= self.data_len {
@@ -219,17 +569,65 @@ impl PerfRing {
         }
     }
 
+    /// Starts a read batch that covers at most `max_bytes` and `max_events` of
+    /// the available records
+    ///
+    /// Records past the cap stay in the ring for the next batch. At least one
+    /// record is included whenever the ring is non-empty, so a single record
+    /// larger than `max_bytes` cannot stall the ring.
+    ///
+    /// A header whose size is smaller than a header or runs past the writer's
+    /// position is corrupt, and the records after it cannot be located. The
+    /// batch ends before it, and once it is the next record, everything up to
+    /// the writer's position is skipped. Returns the number of bytes skipped.
+    pub fn start_read_batch_capped(&mut self, max_bytes: u64, max_events: usize) -> u64 {
+        let data_head = unsafe { self.meta.as_ref().data_head.load(Ordering::Acquire) };
+        let header_size = std::mem::size_of::<PerfEventHeader>() as u64;
+
+        let mut tail = self.head;
+        let mut events = 0;
+        while tail != data_head {
+            let size = unsafe {
+                let header =
+                    &*(self.data.add((tail & self.buf_mask) as usize) as *const PerfEventHeader);
+                u64::from(header.size)
+            };
+            if size < header_size || size > data_head.wrapping_sub(tail) {
+                if events == 0 {
+                    let skipped = data_head.wrapping_sub(self.head);
+                    self.head = data_head;
+                    self.tail = data_head;
+                    return skipped;
+                }
+                break;
+            }
+            if events > 0 && (events >= max_events || tail + size - self.head > max_bytes) {
+                break;
+            }
+            tail += size;
+            events += 1;
+        }
+
+        self.tail = tail;
+        0
+    }
+
     /// Returns the size of the next event in the ring buffer
     pub fn peek_size(&self) -> Result<usize, PerfRingError> {
         if self.tail == self.head {
             return Err(PerfRingError::BufferEmpty);
         }
 
-        unsafe {
+        let size = unsafe {
             let header =
                 &*(self.data.add((self.head & self.buf_mask) as usize) as *const PerfEventHeader);
-            Ok(header.size as usize - std::mem::size_of::<PerfEventHeader>())
-        }
+            header.size as usize
+        };
+
+        // The header is only trustworthy if the writer did not reach it while we read it
+        self.check_overwritten()?;
+
+        Ok(size.saturating_sub(std::mem::size_of::<PerfEventHeader>()))
     }
 
     /// Returns the type of the next event
@@ -241,6 +639,21 @@ impl PerfRing {
         }
     }
 
+    /// Returns the `misc` flags of the next event, see [`is_kernel`] and
+    /// the other helpers
+    pub fn peek_misc(&self) -> u16 {
+        unsafe {
+            let header =
+                &*(self.data.add((self.head & self.buf_mask) as usize) as *const PerfEventHeader);
+            header.misc
+        }
+    }
+
+    /// Returns the typed record type of the next event
+    pub fn peek_record_type(&self) -> PerfRecordType {
+        PerfRecordType::from_u32(self.peek_type())
+    }
+
     /// Copies data from the ring buffer without consuming it
     pub fn peek_copy(&self, buf: &mut [u8], offset: u16) -> Result<(), PerfRingError> {
         let size = self.peek_size()?;
@@ -277,9 +690,91 @@ impl PerfRing {
             }
         }
 
+        // If the writer wrapped around onto the record during the copy, the
+        // copied bytes may be torn
+        self.check_overwritten()
+    }
+
+    /// Checks that the writer has not overtaken the current read position.
+    ///
+    /// The writer only reuses bytes once `data_head` is more than a buffer
+    /// length past them, so as long as the distance from the read position is
+    /// within the buffer, the current record is intact.
+    fn check_overwritten(&self) -> Result<(), PerfRingError> {
+        let data_head = unsafe { self.meta.as_ref().data_head.load(Ordering::Acquire) };
+        if data_head.wrapping_sub(self.head) > self.data_len as u64 {
+            return Err(PerfRingError::Overwritten);
+        }
         Ok(())
     }
 
+    /// Recovers the read position after the writer lapped the reader, as
+    /// reported by [`PerfRingError::Overwritten`]. Returns the number of
+    /// bytes skipped, 0 if the writer has not lapped the reader.
+    ///
+    /// The data area still holds the buffer length of bytes before the
+    /// writer's position, but the oldest record in it may have lost its
+    /// start. Scanning forward from the oldest intact byte, the read position
+    /// moves to the first header within [`RESYNC_SCAN_LEN`] bytes from which
+    /// a chain of plausible records ends exactly at the writer's position. If
+    /// there is none, everything up to the writer's position is skipped.
+    pub fn resync(&mut self) -> u64 {
+        let data_head = unsafe { self.meta.as_ref().data_head.load(Ordering::Acquire) };
+        let capacity = self.data_len as u64;
+        if data_head.wrapping_sub(self.head) <= capacity {
+            return 0;
+        }
+
+        // Records are 8-byte aligned. The record at exactly a buffer length
+        // back is skipped too, as the writer's next record overwrites it.
+        let header_size = std::mem::size_of::<PerfEventHeader>() as u64;
+        let oldest = (data_head - capacity + header_size) & !(header_size - 1);
+        let mut visited = vec![false; ((data_head - oldest) / header_size) as usize];
+        let new_head = (oldest..data_head.min(oldest + RESYNC_SCAN_LEN))
+            .step_by(header_size as usize)
+            .find(|&position| self.is_record_chain(position, oldest, data_head, &mut visited))
+            .unwrap_or(data_head);
+
+        let skipped = new_head.wrapping_sub(self.head);
+        self.head = new_head;
+        self.tail = data_head;
+        skipped
+    }
+
+    /// Whether the records starting at `position` have plausible headers and
+    /// end exactly at `end`. `visited` marks the headers from `start` on that
+    /// earlier chains walked; as those chains failed, reaching one fails this
+    /// chain too, so each header is read at most once per resync.
+    fn is_record_chain(
+        &self,
+        mut position: u64,
+        start: u64,
+        end: u64,
+        visited: &mut [bool],
+    ) -> bool {
+        let header_size = std::mem::size_of::<PerfEventHeader>() as u64;
+        while position < end {
+            let slot = ((position - start) / header_size) as usize;
+            if std::mem::replace(&mut visited[slot], true) {
+                return false;
+            }
+            let (type_, size) = unsafe {
+                let header = &*(self.data.add((position & self.buf_mask) as usize)
+                    as *const PerfEventHeader);
+                (header.type_, u64::from(header.size))
+            };
+            if size < header_size
+                || size % header_size != 0
+                || size > end - position
+                || matches!(PerfRecordType::from_u32(type_), PerfRecordType::Unknown(_))
+            {
+                return false;
+            }
+            position += size;
+        }
+        true
+    }
+
     /// Consumes the current event
     pub fn pop(&mut self) -> Result<(), PerfRingError> {
         if self.tail == self.head {
@@ -310,6 +805,40 @@ impl PerfRing {
     pub fn bytes_remaining(&self) -> u32 {
         ((self.tail - self.head) & self.buf_mask) as u32
     }
+
+    /// Returns the kernel's current write position (`data_head`)
+    pub fn kernel_head(&self) -> u64 {
+        unsafe { self.meta.as_ref().data_head.load(Ordering::Acquire) }
+    }
+
+    /// Returns the position up to which records were released back to the
+    /// kernel (`data_tail`), as of the last finished read batch
+    pub fn consumer_tail(&self) -> u64 {
+        unsafe { self.meta.as_ref().data_tail.load(Ordering::Acquire) }
+    }
+
+    /// Returns the positions cached by the last read batch, without touching
+    /// the metadata page shared with the writer
+    pub fn cached_positions(&self) -> RingPositions {
+        RingPositions {
+            head: self.head,
+            tail: self.tail,
+            size: self.buf_mask + 1,
+        }
+    }
+
+    /// Returns the fraction of the ring occupied by records not yet released
+    /// to the writer, between 0.0 (empty) and 1.0 (full)
+    pub fn fill_ratio(&self) -> f64 {
+        let (data_head, data_tail) = unsafe {
+            let meta = self.meta.as_ref();
+            (
+                meta.data_head.load(Ordering::Acquire),
+                meta.data_tail.load(Ordering::Acquire),
+            )
+        };
+        data_head.wrapping_sub(data_tail) as f64 / (self.buf_mask + 1) as f64
+    }
 }
 
 #[cfg(test)]
@@ -317,6 +846,28 @@ mod tests {
     use super::*;
     use std::mem::size_of;
 
+    #[test]
+    fn test_record_type_round_trip() {
+        // Every type in the kernel's enum perf_event_type is known
+        for value in PERF_RECORD_MMAP..=PERF_RECORD_AUX_OUTPUT_HW_ID {
+            let record_type = PerfRecordType::from(value);
+            assert!(!matches!(record_type, PerfRecordType::Unknown(_)));
+            assert_eq!(u32::from(record_type), value);
+        }
+
+        assert_eq!(PerfRecordType::from_u32(2), PerfRecordType::Lost);
+        assert_eq!(PerfRecordType::from_u32(9), PerfRecordType::Sample);
+        assert_eq!(PerfRecordType::from_u32(1), PerfRecordType::Mmap);
+
+        // Unknown types are preserved
+        assert_eq!(PerfRecordType::from_u32(0), PerfRecordType::Unknown(0));
+        assert_eq!(
+            PerfRecordType::from_u32(1000),
+            PerfRecordType::Unknown(1000)
+        );
+        assert_eq!(PerfRecordType::Unknown(1000).as_u32(), 1000);
+    }
+
     #[test]
     fn test_init_contiguous() {
         let page_size = 4096u64;
@@ -340,6 +891,13 @@ mod tests {
             }
         }
 
+        // Buffer too small for the metadata page and data pages
+        let mut short_data = vec![0u8; (page_size * u64::from(n_pages)) as usize];
+        unsafe {
+            let result = PerfRing::init_contiguous(&mut short_data, n_pages, page_size);
+            assert!(matches!(result, Err(PerfRingError::LayoutMismatch { .. })));
+        }
+
         // Nil buffer
         let mut empty_data = vec![];
         unsafe {
@@ -352,6 +910,75 @@ mod tests {
         }
     }
 
+    /// Writes a metadata page's data_offset and data_size
+    fn set_layout_fields(data: &mut [u8], data_offset: u64, data_size: u64) {
+        let offset = std::mem::offset_of!(PerfEventMmapPage, data_offset);
+        data[offset..offset + 8].copy_from_slice(&data_offset.to_ne_bytes());
+        let offset = std::mem::offset_of!(PerfEventMmapPage, data_size);
+        data[offset..offset + 8].copy_from_slice(&data_size.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_layout_sources() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let buf_len = page_size * u64::from(n_pages);
+        let mut data = vec![0u8; (page_size + buf_len) as usize];
+
+        // Both fields zero: older kernels, the caller's layout is used
+        let ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        assert_eq!(
+            ring.layout(),
+            RingLayout {
+                data_offset: page_size,
+                data_size: buf_len,
+                source: LayoutSource::Caller,
+            }
+        );
+
+        // Both set and consistent with the caller: the metadata page is used,
+        // including a data_offset other than a full page
+        let mut data = vec![0u8; (page_size + 1024 + buf_len) as usize];
+        set_layout_fields(&mut data, page_size + 1024, buf_len);
+        let ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        assert_eq!(
+            ring.layout(),
+            RingLayout {
+                data_offset: page_size + 1024,
+                data_size: buf_len,
+                source: LayoutSource::MetadataPage,
+            }
+        );
+
+        // Inconsistent: the metadata page disagrees with the caller's size,
+        // or only one of the fields is set
+        for (data_offset, data_size) in [
+            (page_size, buf_len / 2),
+            (page_size, buf_len * 2),
+            (page_size, 0),
+            (0, buf_len),
+        ] {
+            let mut data = vec![0u8; (page_size + buf_len) as usize];
+            set_layout_fields(&mut data, data_offset, data_size);
+            let result = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size) };
+            match result {
+                Err(PerfRingError::LayoutMismatch {
+                    data_offset: offset,
+                    data_size: size,
+                    expected_size,
+                    ..
+                }) => {
+                    assert_eq!((offset, size), (data_offset, data_size));
+                    assert_eq!(expected_size, buf_len);
+                }
+                _ => panic!(
+                    "Expected LayoutMismatch for data_offset {} data_size {}",
+                    data_offset, data_size
+                ),
+            }
+        }
+    }
+
     #[test]
     fn test_write_and_read() {
         let page_size = 4096u64;
@@ -403,6 +1030,38 @@ mod tests {
         ring.finish_read_batch();
     }
 
+    #[test]
+    fn test_owned_ring_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PerfRing<Owned>>();
+
+        let mut ring = PerfRing::new_owned(2, 4096).unwrap();
+        assert_eq!(ring.layout().source, LayoutSource::Caller);
+        ring.start_write_batch();
+        ring.write(b"from another thread", PERF_RECORD_SAMPLE)
+            .unwrap();
+        ring.finish_write_batch();
+
+        // The buffer moves with the ring
+        let ring = std::thread::spawn(move || {
+            ring.start_read_batch();
+            let mut buf = vec![0u8; ring.peek_size().unwrap()];
+            ring.peek_copy(&mut buf, 0).unwrap();
+            assert_eq!(&buf[4..23], b"from another thread");
+            ring.pop().unwrap();
+            ring.finish_read_batch();
+            ring
+        })
+        .join()
+        .unwrap();
+        assert_eq!(ring.bytes_remaining(), 0);
+
+        assert!(matches!(
+            PerfRing::new_owned(3, 4096),
+            Err(PerfRingError::InvalidBufferLength)
+        ));
+    }
+
     #[test]
     fn test_bytes_remaining() {
         let page_size = 4096u64;
@@ -415,6 +1074,221 @@ mod tests {
         assert_eq!(remaining, 0);
     }
 
+    #[test]
+    fn test_fill_ratio() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        assert_eq!(ring.fill_ratio(), 0.0);
+
+        // 1020 bytes of data plus the header and size field make a 1032 byte record
+        ring.start_write_batch();
+        ring.write(&[0u8; 1020], PERF_RECORD_SAMPLE).unwrap();
+        ring.finish_write_batch();
+        assert_eq!(ring.fill_ratio(), 1032.0 / 8192.0);
+
+        // Consuming the record releases its space
+        ring.start_read_batch();
+        ring.pop().unwrap();
+        ring.finish_read_batch();
+        assert_eq!(ring.fill_ratio(), 0.0);
+    }
+
+    /// Moves the writer's position `bytes` past the reader's, as if it
+    /// wrapped around without waiting for the reader
+    fn overtake(data: &mut [u8], ring: &PerfRing, bytes: u64) {
+        let meta = data.as_mut_ptr() as *mut PerfEventMmapPage;
+        unsafe {
+            (*meta)
+                .data_head
+                .store(ring.head + bytes, Ordering::Release);
+        }
+    }
+
+    #[test]
+    fn test_overwrite_detection() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let buf_len = page_size * u64::from(n_pages);
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        ring.start_write_batch();
+        ring.write(b"record 1", 1).unwrap();
+        ring.write(b"record 2", 1).unwrap();
+        ring.finish_write_batch();
+        ring.start_read_batch();
+
+        // A writer exactly one buffer ahead has not touched the record yet
+        let size = ring.peek_size().unwrap();
+        overtake(&mut data, &ring, buf_len);
+        let mut buf = vec![0u8; size];
+        ring.peek_copy(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"record 1");
+
+        // Overtaken between peeking the size and copying the record
+        overtake(&mut data, &ring, buf_len + 8);
+        assert!(matches!(
+            ring.peek_copy(&mut buf, 0),
+            Err(PerfRingError::Overwritten)
+        ));
+        assert!(matches!(ring.peek_size(), Err(PerfRingError::Overwritten)));
+
+        // Resyncing skips everything up to the writer's position
+        assert_eq!(ring.resync(), buf_len + 8);
+        assert!(matches!(ring.peek_size(), Err(PerfRingError::BufferEmpty)));
+        ring.finish_read_batch();
+
+        // Reading continues normally from the new position
+        ring.start_write_batch();
+        ring.write(b"record 3", 1).unwrap();
+        ring.finish_write_batch();
+        ring.start_read_batch();
+        ring.peek_copy(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"record 3");
+    }
+
+    #[test]
+    fn test_capped_batch_skips_corrupt_header() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut writer =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        writer.start_write_batch();
+        for record in [b"record 1", b"record 2", b"record 3"] {
+            writer.write(record, 1).unwrap();
+        }
+        writer.finish_write_batch();
+        let data_head = writer.tail;
+
+        // The second 16 byte record's header claims a size of 0
+        let size_offset = (page_size + 16 + 6) as usize;
+        unsafe { ptr::write_unaligned(data.as_mut_ptr().add(size_offset) as *mut u16, 0) };
+
+        // The batch ends before the corrupt record
+        assert_eq!(ring.start_read_batch_capped(u64::MAX, usize::MAX), 0);
+        let mut buf = [0u8; 8];
+        ring.peek_copy(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"record 1");
+        ring.pop().unwrap();
+        assert!(matches!(ring.pop(), Err(PerfRingError::BufferEmpty)));
+        ring.finish_read_batch();
+
+        // The next batch skips the rest rather than spinning on it
+        assert_eq!(ring.start_read_batch_capped(u64::MAX, usize::MAX), 32);
+        assert!(matches!(ring.peek_size(), Err(PerfRingError::BufferEmpty)));
+        ring.finish_read_batch();
+        assert_eq!(ring.head, data_head);
+    }
+
+    #[test]
+    fn test_resync_after_lap() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let buf_len = page_size * u64::from(n_pages);
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut reader =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut writer =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let meta = data.as_mut_ptr() as *mut PerfEventMmapPage;
+
+        // Records of 16 to 48 bytes, carrying their sequence number. The
+        // writer ignores the reader, like one in overwrite mode.
+        let mut starts = Vec::new();
+        writer.start_write_batch();
+        for seq in 0u64..600 {
+            let mut record = seq.to_le_bytes().to_vec();
+            record.resize(8 * (1 + seq as usize % 5), 0xa5);
+            if writer.write(&record, PERF_RECORD_THROTTLE).is_err() {
+                writer.finish_write_batch();
+                unsafe { (*meta).data_tail.store(writer.tail, Ordering::Release) };
+                writer.start_write_batch();
+                writer.write(&record, PERF_RECORD_THROTTLE).unwrap();
+            }
+            starts.push(writer.tail - (record.len() as u64 + 8));
+        }
+        writer.finish_write_batch();
+        let data_head = writer.tail;
+
+        reader.start_read_batch();
+        assert!(data_head > buf_len + 8);
+        assert!(matches!(
+            reader.peek_size(),
+            Err(PerfRingError::Overwritten)
+        ));
+
+        // The oldest record entirely past the overwritten bytes
+        let first = starts
+            .iter()
+            .position(|&start| start > data_head - buf_len)
+            .unwrap();
+        assert_eq!(reader.resync(), starts[first]);
+        assert_eq!(reader.resync(), 0);
+
+        // Reading continues from there to the last record
+        let mut buf = [0u8; 8];
+        for seq in first as u64..600 {
+            assert_eq!(reader.peek_record_type(), PerfRecordType::Throttle);
+            reader.peek_copy(&mut buf, 0).unwrap();
+            assert_eq!(u64::from_le_bytes(buf), seq);
+            reader.pop().unwrap();
+        }
+        assert!(matches!(reader.pop(), Err(PerfRingError::BufferEmpty)));
+    }
+
+    #[test]
+    fn test_resync_scan_bounded() {
+        let page_size = 4096u64;
+        let n_pages = 2u32;
+        let buf_len = page_size * u64::from(n_pages);
+        let mut data = vec![0u8; (page_size * (1 + u64::from(n_pages))) as usize];
+
+        let mut reader =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let mut writer =
+            unsafe { PerfRing::init_contiguous(&mut data, n_pages, page_size).unwrap() };
+        let meta = data.as_mut_ptr() as *mut PerfEventMmapPage;
+
+        // A record longer than the scan, followed by short records until the
+        // writer tears the long one
+        let long_record = vec![0xa5; RESYNC_SCAN_LEN as usize + 64];
+        writer.start_write_batch();
+        writer.write(&long_record, PERF_RECORD_THROTTLE).unwrap();
+        let mut intact = None;
+        while writer.tail < buf_len + 16 {
+            if writer.write(b"short!!!", PERF_RECORD_THROTTLE).is_err() {
+                writer.finish_write_batch();
+                unsafe { (*meta).data_tail.store(writer.tail, Ordering::Release) };
+                writer.start_write_batch();
+                writer.write(b"short!!!", PERF_RECORD_THROTTLE).unwrap();
+            }
+            intact.get_or_insert(writer.tail - 16);
+        }
+        writer.finish_write_batch();
+        let data_head = writer.tail;
+
+        // The short records still end at the writer's position, but start too
+        // far past the torn record to be found
+        reader.start_read_batch();
+        assert!(intact.unwrap() > data_head - buf_len + RESYNC_SCAN_LEN);
+        assert!(matches!(
+            reader.peek_size(),
+            Err(PerfRingError::Overwritten)
+        ));
+        assert_eq!(reader.resync(), data_head);
+        assert!(matches!(
+            reader.peek_size(),
+            Err(PerfRingError::BufferEmpty)
+        ));
+    }
+
     #[test]
     fn test_wraparound() {
         let page_size = 4096u64;
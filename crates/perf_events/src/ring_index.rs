@@ -0,0 +1,187 @@
+//! What the index of a ring means to the BPF programs writing to it.
+//!
+//! `bpf_perf_event_output` picks the ring of a perf event array by index.
+//! With `BPF_F_CURRENT_CPU` that is the CPU the program runs on, but a
+//! program can pass an explicit CPU instead, such as a timer callback
+//! attributing its output to another CPU, or give indices a meaning of its
+//! own. Consumers interpret ring indices through [`RingIndexSemantics`], and
+//! the semantics the BPF object declares are checked against the ones
+//! userspace was configured with, so a mismatch fails at startup rather
+//! than as misattributed events.
+
+use collector_errors::{Classified, ErrorCode};
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+/// How BPF programs choose the ring they output to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RingIndexSemantics {
+    /// Programs output with `BPF_F_CURRENT_CPU`: the ring index is the CPU
+    /// the event happened on
+    #[default]
+    CurrentCpu,
+    /// Programs pass a CPU as the index: the ring index is the CPU the event
+    /// is attributed to, which may not be the one that produced it
+    Explicit,
+    /// Programs give ring indices a meaning of their own, described by index
+    Custom(BTreeMap<u32, String>),
+}
+
+impl RingIndexSemantics {
+    /// Value the BPF object declares for these semantics in its
+    /// `ring_index_semantics` read-only global
+    pub fn code(&self) -> u32 {
+        match self {
+            RingIndexSemantics::CurrentCpu => 0,
+            RingIndexSemantics::Explicit => 1,
+            RingIndexSemantics::Custom(_) => 2,
+        }
+    }
+
+    /// The CPU that events in the ring at `ring_index` belong to, None when
+    /// indices are not CPUs
+    pub fn ring_cpu(&self, ring_index: usize) -> Option<usize> {
+        match self {
+            RingIndexSemantics::CurrentCpu | RingIndexSemantics::Explicit => Some(ring_index),
+            RingIndexSemantics::Custom(_) => None,
+        }
+    }
+
+    /// The program-defined meaning of `ring_index`, for custom semantics
+    pub fn ring_meaning(&self, ring_index: usize) -> Option<&str> {
+        match self {
+            RingIndexSemantics::Custom(meanings) => u32::try_from(ring_index)
+                .ok()
+                .and_then(|index| meanings.get(&index))
+                .map(String::as_str),
+            _ => None,
+        }
+    }
+
+    /// Check that the semantics a BPF object declares, as its
+    /// `ring_index_semantics` value, are these
+    pub fn check_declared(&self, declared: u32) -> Result<(), RingIndexError> {
+        if declared == self.code() {
+            return Ok(());
+        }
+        let declared = match declared {
+            0 => RingIndexSemantics::CurrentCpu.to_string(),
+            1 => RingIndexSemantics::Explicit.to_string(),
+            2 => "custom".to_string(),
+            unknown => return Err(RingIndexError::Unknown(unknown)),
+        };
+        Err(RingIndexError::Mismatch {
+            declared,
+            configured: self.to_string(),
+        })
+    }
+
+    /// Check that every index given a meaning has a ring, out of `rings`
+    pub fn check_rings(&self, rings: usize) -> Result<(), RingIndexError> {
+        if let RingIndexSemantics::Custom(meanings) = self {
+            if let Some(&index) = meanings.keys().find(|&&index| index as usize >= rings) {
+                return Err(RingIndexError::OutOfRange { index, rings });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RingIndexSemantics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RingIndexSemantics::CurrentCpu => write!(f, "current CPU"),
+            RingIndexSemantics::Explicit => write!(f, "explicit CPU"),
+            RingIndexSemantics::Custom(meanings) => {
+                write!(f, "custom ({} indices)", meanings.len())
+            }
+        }
+    }
+}
+
+/// Errors from checking ring index semantics
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RingIndexError {
+    #[error(
+        "BPF programs index rings by {declared}, but the reader is configured for {configured}"
+    )]
+    Mismatch {
+        declared: String,
+        configured: String,
+    },
+
+    #[error("BPF programs declare unknown ring index semantics {0}")]
+    Unknown(u32),
+
+    #[error("ring index {index} has a meaning, but there are only {rings} rings")]
+    OutOfRange { index: u32, rings: usize },
+}
+
+impl Classified for RingIndexError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::RingSetup
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom() -> RingIndexSemantics {
+        RingIndexSemantics::Custom(BTreeMap::from([
+            (0, "scheduler".to_string()),
+            (1, "timers".to_string()),
+        ]))
+    }
+
+    #[test]
+    fn test_ring_cpu_and_meaning() {
+        assert_eq!(
+            RingIndexSemantics::default(),
+            RingIndexSemantics::CurrentCpu
+        );
+        assert_eq!(RingIndexSemantics::CurrentCpu.ring_cpu(3), Some(3));
+        assert_eq!(RingIndexSemantics::Explicit.ring_cpu(3), Some(3));
+        assert_eq!(RingIndexSemantics::Explicit.ring_meaning(3), None);
+
+        let custom = custom();
+        assert_eq!(custom.ring_cpu(1), None);
+        assert_eq!(custom.ring_meaning(1), Some("timers"));
+        assert_eq!(custom.ring_meaning(2), None);
+
+        assert_eq!(custom.check_rings(2), Ok(()));
+        let err = custom.check_rings(1).unwrap_err();
+        assert_eq!(err, RingIndexError::OutOfRange { index: 1, rings: 1 });
+        assert_eq!(err.code(), ErrorCode::RingSetup);
+        assert_eq!(RingIndexSemantics::Explicit.check_rings(0), Ok(()));
+    }
+
+    #[test]
+    fn test_declared_semantics() {
+        // Values as read from the BPF object's read-only data
+        assert_eq!(RingIndexSemantics::CurrentCpu.check_declared(0), Ok(()));
+        assert_eq!(RingIndexSemantics::Explicit.check_declared(1), Ok(()));
+        assert_eq!(custom().check_declared(2), Ok(()));
+
+        let err = RingIndexSemantics::CurrentCpu
+            .check_declared(1)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "BPF programs index rings by explicit CPU, but the reader is configured for current CPU"
+        );
+        let err = custom().check_declared(0).unwrap_err();
+        assert_eq!(
+            err,
+            RingIndexError::Mismatch {
+                declared: "current CPU".to_string(),
+                configured: "custom (2 indices)".to_string(),
+            }
+        );
+        assert_eq!(
+            RingIndexSemantics::Explicit.check_declared(7),
+            Err(RingIndexError::Unknown(7))
+        );
+    }
+}
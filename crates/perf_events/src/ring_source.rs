@@ -0,0 +1,40 @@
+use std::os::unix::io::RawFd;
+
+/// Where a ring's records come from
+///
+/// Rings backed by perf events carry the event's file descriptor, which
+/// becomes readable when the kernel wakes up userspace. Rings written by
+/// userspace, such as a [`crate::MemoryStorage`] ring used to inject
+/// synthetic records, have no file descriptor.
+#[derive(Debug, Clone, Default)]
+pub struct RingSource {
+    fd: Option<RawFd>,
+    label: String,
+}
+
+impl RingSource {
+    /// Creates a source without a file descriptor, named `label` in logs and
+    /// debug output
+    pub fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the file descriptor that becomes readable when the ring has data
+    pub fn with_fd(mut self, fd: RawFd) -> Self {
+        self.fd = Some(fd);
+        self
+    }
+
+    /// Returns the file descriptor, if the ring has one
+    pub fn fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
+    /// Returns the label given when the source was created
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+}
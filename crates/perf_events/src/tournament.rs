@@ -0,0 +1,142 @@
+//! Fixed-size tournament (winner) tree for finding the ring with the earliest event.
+//!
+//! The tree holds one optional key per slot and keeps the index of the minimum
+//! slot at the root. Updating a slot replays the matches on its path to the
+//! root, so all operations after construction are allocation-free and cost
+//! O(log N). Ties are broken in favor of the lower slot index.
+
+/// A tournament tree over a fixed number of slots
+pub(crate) struct TournamentTree<K> {
+    /// Number of leaves, a power of two
+    leaves: usize,
+    /// Key per slot, `None` when the slot has no event
+    keys: Vec<Option<K>>,
+    /// Winning slot per node, in heap layout with the root at index 1 and
+    /// leaf `i` at index `leaves + i`
+    nodes: Vec<usize>,
+}
+
+impl<K: Copy + Ord> TournamentTree<K> {
+    /// Creates a tree with `n` empty slots
+    pub(crate) fn new(n: usize) -> Self {
+        let leaves = n.max(1).next_power_of_two();
+        let mut tree = TournamentTree {
+            leaves,
+            keys: vec![None; leaves],
+            nodes: vec![0; 2 * leaves],
+        };
+        for i in 0..leaves {
+            tree.nodes[leaves + i] = i;
+        }
+        for node in (1..leaves).rev() {
+            tree.nodes[node] = tree.winner(tree.nodes[2 * node], tree.nodes[2 * node + 1]);
+        }
+        tree
+    }
+
+    /// Grows the tree to hold at least `n` slots, keeping existing keys
+    pub(crate) fn grow(&mut self, n: usize) {
+        if n <= self.leaves {
+            return;
+        }
+        let mut grown = TournamentTree::new(n);
+        for (slot, key) in self.keys.iter().enumerate() {
+            grown.set(slot, *key);
+        }
+        *self = grown;
+    }
+
+    /// Returns the key of a slot
+    pub(crate) fn get(&self, slot: usize) -> Option<K> {
+        self.keys[slot]
+    }
+
+    /// Sets the key of a slot and replays its matches up to the root
+    pub(crate) fn set(&mut self, slot: usize, key: Option<K>) {
+        self.keys[slot] = key;
+        let mut node = (self.leaves + slot) / 2;
+        while node >= 1 {
+            self.nodes[node] = self.winner(self.nodes[2 * node], self.nodes[2 * node + 1]);
+            node /= 2;
+        }
+    }
+
+    /// Returns the minimum key and its slot, or `None` if all slots are empty
+    pub(crate) fn min(&self) -> Option<(K, usize)> {
+        let slot = self.nodes[1];
+        self.keys[slot].map(|key| (key, slot))
+    }
+
+    /// Returns true if no slot holds a key
+    pub(crate) fn is_empty(&self) -> bool {
+        self.min().is_none()
+    }
+
+    fn winner(&self, a: usize, b: usize) -> usize {
+        match (self.keys[a], self.keys[b]) {
+            (Some(ka), Some(kb)) => {
+                if kb < ka {
+                    b
+                } else {
+                    a
+                }
+            }
+            (None, Some(_)) => b,
+            _ => a,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_tree() {
+        for n in [0, 1, 3, 4, 7] {
+            let tree = TournamentTree::<u64>::new(n);
+            assert!(tree.is_empty());
+            assert_eq!(tree.min(), None);
+        }
+    }
+
+    #[test]
+    fn test_min_and_ties() {
+        let mut tree = TournamentTree::new(5);
+        tree.set(3, Some(30));
+        tree.set(1, Some(10));
+        tree.set(4, Some(10));
+        assert_eq!(tree.min(), Some((10, 1)));
+
+        tree.set(1, None);
+        assert_eq!(tree.min(), Some((10, 4)));
+
+        tree.set(4, Some(40));
+        assert_eq!(tree.min(), Some((30, 3)));
+
+        tree.set(3, None);
+        tree.set(4, None);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_single_slot() {
+        let mut tree = TournamentTree::new(1);
+        tree.set(0, Some(5));
+        assert_eq!(tree.min(), Some((5, 0)));
+        tree.set(0, None);
+        assert_eq!(tree.min(), None);
+    }
+
+    #[test]
+    fn test_grow_keeps_keys() {
+        let mut tree = TournamentTree::new(2);
+        tree.set(0, Some(20));
+        tree.set(1, Some(15));
+        tree.grow(5);
+        assert_eq!(tree.get(0), Some(20));
+        assert_eq!(tree.min(), Some((15, 1)));
+        tree.set(4, Some(1));
+        assert_eq!(tree.min(), Some((1, 4)));
+    }
+}
@@ -0,0 +1,224 @@
+//! Canonical encoding of the records the BPF programs write to the perf rings.
+//!
+//! A BPF message struct starts with a [`SampleHeader`]. The programs pass the
+//! struct to `bpf_perf_event_output` without its `size` field, and the kernel
+//! writes the sample's raw size in its place, padding the record to 8 bytes.
+//! Tests, fake event sources and generators use these helpers rather than
+//! laying out records by hand, so they cannot drift from what the
+//! [`Reader`](crate::Reader) and [`Dispatcher`](crate::Dispatcher) expect.
+
+use plain::Plain;
+use std::mem::size_of;
+
+use crate::{PerfRing, PerfRingError, SampleHeader, PERF_RECORD_LOST, PERF_RECORD_SAMPLE};
+
+/// Bytes a BPF program passes to `bpf_perf_event_output` for `message`, with
+/// the header's type and timestamp set to `msg_type` and `timestamp`.
+///
+/// # Panics
+///
+/// If `T` is smaller than a [`SampleHeader`].
+pub fn collector_sample_payload<T: Plain>(msg_type: u32, timestamp: u64, message: &T) -> Vec<u8> {
+    assert!(
+        size_of::<T>() >= size_of::<SampleHeader>(),
+        "message structs start with a SampleHeader"
+    );
+    let bytes = unsafe { plain::as_bytes(message) };
+    sample_payload(msg_type, timestamp, &bytes[size_of::<SampleHeader>()..])
+}
+
+/// Bytes passed to `bpf_perf_event_output` for a message of type `msg_type`
+/// whose fields after the header are `body`
+pub fn sample_payload(msg_type: u32, timestamp: u64, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(size_of::<SampleHeader>() + body.len());
+    payload.extend_from_slice(&msg_type.to_le_bytes());
+    payload.extend_from_slice(&timestamp.to_le_bytes());
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// The sample record holding `payload` as the reader copies it from the ring
+/// and [`Dispatcher::dispatch_record`](crate::Dispatcher::dispatch_record)
+/// takes it: the kernel's size field, the payload and its padding
+pub fn sample_record(payload: &[u8]) -> Vec<u8> {
+    let raw_size = SampleHeader::raw_size(payload.len());
+    let mut record = Vec::with_capacity(SampleHeader::SIZE_PREFIX_LEN + raw_size as usize);
+    record.extend_from_slice(&raw_size.to_le_bytes());
+    record.extend_from_slice(payload);
+    record.resize(SampleHeader::SIZE_PREFIX_LEN + raw_size as usize, 0);
+    record
+}
+
+/// The sample record for `message`, see [`sample_record`]
+pub fn collector_sample_record<T: Plain>(msg_type: u32, timestamp: u64, message: &T) -> Vec<u8> {
+    sample_record(&collector_sample_payload(msg_type, timestamp, message))
+}
+
+/// Write `message` to the ring as a BPF program would
+pub fn write_collector_sample<T: Plain>(
+    ring: &mut PerfRing,
+    msg_type: u32,
+    timestamp: u64,
+    message: &T,
+) -> Result<usize, PerfRingError> {
+    ring.write(
+        &collector_sample_payload(msg_type, timestamp, message),
+        PERF_RECORD_SAMPLE,
+    )
+}
+
+/// Write a sample of type `msg_type` whose fields after the header are `body`
+pub fn write_sample(
+    ring: &mut PerfRing,
+    msg_type: u32,
+    timestamp: u64,
+    body: &[u8],
+) -> Result<usize, PerfRingError> {
+    ring.write(
+        &sample_payload(msg_type, timestamp, body),
+        PERF_RECORD_SAMPLE,
+    )
+}
+
+/// Body of a PERF_RECORD_LOST record, as lost sample subscribers receive it
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LostRecord {
+    /// Id of the event whose samples were lost
+    pub id: u64,
+    /// Number of samples lost
+    pub lost: u64,
+}
+unsafe impl Plain for LostRecord {}
+
+impl LostRecord {
+    /// Decode a record from the bytes following its perf_event_header.
+    /// Returns None if `data` is too short.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut record = Self::default();
+        record.copy_from_bytes(data).ok()?;
+        Some(record)
+    }
+}
+
+/// A PERF_RECORD_LOST record as the kernel writes it: the event id and the
+/// number of samples lost
+pub fn lost_record(id: u64, lost: u64) -> [u8; 16] {
+    let mut record = [0u8; 16];
+    record[..8].copy_from_slice(&id.to_le_bytes());
+    record[8..].copy_from_slice(&lost.to_le_bytes());
+    record
+}
+
+/// Write a PERF_RECORD_LOST record reporting `lost` lost samples
+pub fn write_lost(ring: &mut PerfRing, id: u64, lost: u64) -> Result<usize, PerfRingError> {
+    ring.write(&lost_record(id, lost), PERF_RECORD_LOST)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Dispatcher, Reader};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Laid out like a BPF message struct
+    #[repr(C)]
+    struct TestMsg {
+        header: SampleHeader,
+        pid: u32,
+        __pad: u32,
+        value: u64,
+    }
+    unsafe impl Plain for TestMsg {}
+
+    #[test]
+    fn test_golden_layout() {
+        let msg = TestMsg {
+            header: SampleHeader {
+                size: 0xdead,
+                type_: 0,
+                timestamp: 0,
+            },
+            pid: 0x11223344,
+            __pad: 0,
+            value: 0x0102030405060708,
+        };
+        let timestamp = 0xa0a1a2a3a4a5a6a7;
+
+        #[rustfmt::skip]
+        let payload: [u8; 28] = [
+            4, 0, 0, 0,                                     // type_
+            0xa7, 0xa6, 0xa5, 0xa4, 0xa3, 0xa2, 0xa1, 0xa0, // timestamp
+            0x44, 0x33, 0x22, 0x11,                         // pid
+            0, 0, 0, 0,                                     // padding before value
+            8, 7, 6, 5, 4, 3, 2, 1,                         // value
+        ];
+        assert_eq!(collector_sample_payload(4, timestamp, &msg), payload);
+
+        // The kernel's size field replaces the struct's, and covers the payload
+        let mut record = vec![28, 0, 0, 0];
+        record.extend_from_slice(&payload);
+        assert_eq!(collector_sample_record(4, timestamp, &msg), record);
+        assert_eq!(
+            sample_record(&payload[..27]),
+            record[..31].iter().chain(&[0]).copied().collect::<Vec<_>>()
+        );
+
+        // In the ring the record follows an 8-byte perf_event_header
+        let page_size = 4096u64;
+        let mut data = vec![0u8; (page_size * 3) as usize];
+        let mut ring = unsafe { PerfRing::init_contiguous(&mut data, 2, page_size).unwrap() };
+        ring.start_write_batch();
+        write_collector_sample(&mut ring, 4, timestamp, &msg).unwrap();
+        write_lost(&mut ring, 1, 5).unwrap();
+        ring.finish_write_batch();
+
+        let mut expected = vec![
+            9, 0, 0, 0, // type: PERF_RECORD_SAMPLE
+            0, 0, // misc
+            40, 0, // size
+        ];
+        expected.extend_from_slice(&record);
+        expected.extend_from_slice(&[
+            2, 0, 0, 0, // type: PERF_RECORD_LOST
+            0, 0, // misc
+            24, 0, // size
+        ]);
+        expected.extend_from_slice(&lost_record(1, 5));
+        let start = page_size as usize;
+        assert_eq!(&data[start..start + expected.len()], &expected[..]);
+
+        // The reader and dispatcher see the message as written
+        let mut reader = Reader::new();
+        reader
+            .add_ring(unsafe { PerfRing::init_contiguous(&mut data, 2, page_size).unwrap() })
+            .unwrap();
+        let mut dispatcher = Dispatcher::new();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let sink = received.clone();
+        dispatcher.subscribe(4, move |_, data| {
+            let msg: &TestMsg = plain::from_bytes(data).unwrap();
+            sink.borrow_mut()
+                .push((msg.header.timestamp, msg.pid, msg.value, data.to_vec()));
+        });
+        let lost = Rc::new(RefCell::new(Vec::new()));
+        let lost_sink = lost.clone();
+        dispatcher
+            .subscribe_lost_samples(move |_, data| lost_sink.borrow_mut().push(data.to_vec()));
+
+        reader.start().unwrap();
+        dispatcher.dispatch_all(&mut reader).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(
+            *received.borrow(),
+            vec![(timestamp, 0x11223344, 0x0102030405060708, record)]
+        );
+        assert_eq!(*lost.borrow(), vec![lost_record(1, 5).to_vec()]);
+        assert_eq!(
+            LostRecord::parse(&lost.borrow()[0]),
+            Some(LostRecord { id: 1, lost: 5 })
+        );
+        assert_eq!(LostRecord::parse(&[0u8; 12]), None);
+    }
+}
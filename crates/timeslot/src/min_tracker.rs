@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use thiserror::Error;
 
@@ -11,6 +12,30 @@ pub enum Error {
     /// A timestamp update was attempted that would go backward in time
     #[error("Non-monotonic timestamp update for CPU {0}: previous={1}, new={2}")]
     NonMonotonicTimestamp(usize, u64, u64),
+
+    /// A snapshot was too far from the current time slot to be restored
+    #[error("Snapshot slot {0} is more than {2} slots away from current slot {1}")]
+    StaleSnapshot(u64, u64, u64),
+
+    /// A snapshot had a time slot size of zero
+    #[error("Snapshot has a time slot size of zero")]
+    ZeroTimeSlotSize,
+
+    /// A snapshot did not track any CPU
+    #[error("Snapshot has no CPUs")]
+    NoCpus,
+}
+
+/// Serializable per-CPU progress of a [`MinTracker`].
+///
+/// Produced by [`MinTracker::snapshot`] and consumed by [`MinTracker::restore`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackerState {
+    /// Size of each time slot in nanoseconds
+    pub time_slot_size: u64,
+
+    /// Latest timestamp reported by each CPU, `None` if the CPU has not reported
+    pub cpu_timestamps: Vec<Option<u64>>,
 }
 
 /// Tracks the minimum time slot that all CPUs have reported as complete.
@@ -188,6 +213,134 @@ impl MinTracker {
         Ok(())
     }
 
+    /// Captures the per-CPU progress so it can be persisted across restarts.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use timeslot::MinTracker;
+    ///
+    /// let mut tracker = MinTracker::new(1000, 2);
+    /// tracker.update(0, 5000).unwrap();
+    /// tracker.update(1, 3000).unwrap();
+    ///
+    /// let restored = MinTracker::restore(tracker.snapshot()).unwrap();
+    /// assert_eq!(restored.get_min(), Some(3000));
+    /// ```
+    pub fn snapshot(&self) -> TrackerState {
+        TrackerState {
+            time_slot_size: self.time_slot_size,
+            cpu_timestamps: self.cpu_timestamps.clone(),
+        }
+    }
+
+    /// Rebuilds a tracker from a snapshot.
+    ///
+    /// # Guarantees
+    ///
+    /// * The restored tracker reports the same `get_min()` as the tracker the
+    ///   snapshot was taken from, and behaves identically for any later sequence
+    ///   of updates.
+    /// * Monotonicity is enforced against the restored timestamps: an update
+    ///   older than a CPU's snapshot timestamp is rejected with
+    ///   `NonMonotonicTimestamp`. Callers replaying data from before the snapshot
+    ///   should treat this as "already accounted for" and drop the event.
+    /// * The snapshot only reflects progress up to the moment it was taken. If
+    ///   time slots were emitted after the last snapshot and before a crash,
+    ///   they are emitted again after restore; persisting the snapshot after
+    ///   emitting a slot makes emission at-least-once with no skipped slots.
+    ///
+    /// The number of CPUs is taken from the snapshot. If the CPU count changed
+    /// across the restart, start from a fresh tracker instead.
+    ///
+    /// # Errors
+    ///
+    /// Snapshots are usually read back from disk, so one with a zero
+    /// `time_slot_size` is rejected with `ZeroTimeSlotSize`, and one without
+    /// CPUs with `NoCpus`.
+    pub fn restore(state: TrackerState) -> Result<Self, Error> {
+        Self::validate(&state)?;
+
+        let mut time_slot_counts = BTreeMap::new();
+        let mut uninitialized_cpus = 0;
+
+        for timestamp in &state.cpu_timestamps {
+            match timestamp {
+                Some(timestamp) => {
+                    *time_slot_counts
+                        .entry(timestamp / state.time_slot_size)
+                        .or_insert(0) += 1;
+                }
+                None => uninitialized_cpus += 1,
+            }
+        }
+
+        Ok(Self {
+            time_slot_size: state.time_slot_size,
+            cpu_timestamps: state.cpu_timestamps,
+            time_slot_counts,
+            uninitialized_cpus,
+        })
+    }
+
+    /// Checks that a snapshot can be restored
+    fn validate(state: &TrackerState) -> Result<(), Error> {
+        if state.time_slot_size == 0 {
+            return Err(Error::ZeroTimeSlotSize);
+        }
+        if state.cpu_timestamps.is_empty() {
+            return Err(Error::NoCpus);
+        }
+        Ok(())
+    }
+
+    /// Rebuilds a tracker from a snapshot, if the snapshot is recent enough.
+    ///
+    /// `now` is the current timestamp, on the same clock as the updates. The
+    /// snapshot is rejected with `StaleSnapshot` if the slot of any CPU is more
+    /// than `max_staleness_slots` behind the current slot, or ahead of it, as
+    /// happens when the clock restarted with a reboot. Callers should start
+    /// from a fresh tracker in that case. Snapshots that [`MinTracker::restore`]
+    /// rejects are rejected the same way.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use timeslot::MinTracker;
+    ///
+    /// let mut tracker = MinTracker::new(1000, 2);
+    /// tracker.update(0, 5000).unwrap();
+    /// tracker.update(1, 3000).unwrap();
+    ///
+    /// // Two slots after the snapshot's oldest slot
+    /// let restored = MinTracker::restore_checked(tracker.snapshot(), 5500, 10).unwrap();
+    /// assert_eq!(restored.get_min(), Some(3000));
+    ///
+    /// // Too long ago
+    /// assert!(MinTracker::restore_checked(tracker.snapshot(), 50_000, 10).is_err());
+    /// ```
+    pub fn restore_checked(
+        state: TrackerState,
+        now: u64,
+        max_staleness_slots: u64,
+    ) -> Result<Self, Error> {
+        Self::validate(&state)?;
+        let current_slot = now / state.time_slot_size;
+
+        for timestamp in state.cpu_timestamps.iter().flatten() {
+            let slot = timestamp / state.time_slot_size;
+            if slot > current_slot || current_slot - slot > max_staleness_slots {
+                return Err(Error::StaleSnapshot(
+                    slot,
+                    current_slot,
+                    max_staleness_slots,
+                ));
+            }
+        }
+
+        Self::restore(state)
+    }
+
     /// Gets the minimum time slot that all CPUs have completed.
     ///
     /// This returns the lowest timestamp (aligned to a time slot boundary) that
@@ -380,4 +533,114 @@ mod tests {
         // Minimum should now be 5000
         assert_eq!(tracker.get_min(), Some(5000));
     }
+
+    #[test]
+    fn test_snapshot_restore_round_trip() {
+        let mut tracker = MinTracker::new(1000, 3);
+        tracker.update(0, 5432).unwrap();
+        tracker.update(1, 3789).unwrap();
+        tracker.update(2, 9100).unwrap();
+        tracker.update(1, 6100).unwrap();
+
+        let json = serde_json::to_string(&tracker.snapshot()).unwrap();
+        let state: TrackerState = serde_json::from_str(&json).unwrap();
+        let mut restored = MinTracker::restore(state).unwrap();
+
+        assert_eq!(restored.get_min(), tracker.get_min());
+        assert_eq!(restored.get_min(), Some(5000));
+        assert_eq!(restored.snapshot(), tracker.snapshot());
+
+        // Updates after restore behave like on the original tracker
+        assert!(matches!(
+            restored.update(0, 5000),
+            Err(Error::NonMonotonicTimestamp(0, 5432, 5000))
+        ));
+        tracker.update(0, 8000).unwrap();
+        restored.update(0, 8000).unwrap();
+        assert_eq!(restored.get_min(), tracker.get_min());
+        assert_eq!(restored.get_min(), Some(6000));
+    }
+
+    #[test]
+    fn test_restore_partially_initialized() {
+        let mut tracker = MinTracker::new(1000, 2);
+        tracker.update(0, 5000).unwrap();
+
+        let mut restored = MinTracker::restore(tracker.snapshot()).unwrap();
+        assert_eq!(restored.get_min(), None);
+
+        restored.update(1, 2000).unwrap();
+        assert_eq!(restored.get_min(), Some(2000));
+    }
+
+    #[test]
+    fn test_restore_rejects_invalid_snapshot() {
+        // As a corrupted state file could hold
+        let zero_slots = TrackerState {
+            time_slot_size: 0,
+            cpu_timestamps: vec![Some(5000), None],
+        };
+        assert_eq!(
+            MinTracker::restore(zero_slots.clone()).err(),
+            Some(Error::ZeroTimeSlotSize)
+        );
+        assert_eq!(
+            MinTracker::restore_checked(zero_slots, 5000, 10).err(),
+            Some(Error::ZeroTimeSlotSize)
+        );
+
+        let no_cpus = TrackerState {
+            time_slot_size: 1000,
+            cpu_timestamps: Vec::new(),
+        };
+        assert_eq!(
+            MinTracker::restore(no_cpus.clone()).err(),
+            Some(Error::NoCpus)
+        );
+        assert_eq!(
+            MinTracker::restore_checked(no_cpus, 5000, 10).err(),
+            Some(Error::NoCpus)
+        );
+    }
+
+    #[test]
+    fn test_restore_checked_rejects_stale_snapshot() {
+        let mut tracker = MinTracker::new(1000, 2);
+        tracker.update(0, 5000).unwrap();
+        tracker.update(1, 3000).unwrap();
+
+        // Within the bound of the oldest CPU
+        assert!(MinTracker::restore_checked(tracker.snapshot(), 13_999, 10).is_ok());
+
+        // Oldest CPU is one slot too old
+        assert_eq!(
+            MinTracker::restore_checked(tracker.snapshot(), 14_000, 10).err(),
+            Some(Error::StaleSnapshot(3, 14, 10))
+        );
+
+        // Snapshot from the future, e.g. taken before a reboot
+        assert_eq!(
+            MinTracker::restore_checked(tracker.snapshot(), 4000, 10).err(),
+            Some(Error::StaleSnapshot(5, 4, 10))
+        );
+    }
+
+    #[test]
+    fn test_restore_checked_with_cpu_ahead() {
+        let mut tracker = MinTracker::new(1000, 3);
+        tracker.update(0, 4100).unwrap();
+        tracker.update(1, 4200).unwrap();
+        tracker.update(2, 9500).unwrap();
+
+        let mut restored = MinTracker::restore_checked(tracker.snapshot(), 10_000, 100).unwrap();
+        assert_eq!(restored.get_min(), Some(4000));
+
+        // The minimum follows the lagging CPUs, not the one ahead
+        restored.update(0, 7000).unwrap();
+        assert_eq!(restored.get_min(), Some(4000));
+        restored.update(1, 12_000).unwrap();
+        assert_eq!(restored.get_min(), Some(7000));
+        restored.update(0, 13_000).unwrap();
+        assert_eq!(restored.get_min(), Some(9000));
+    }
 }
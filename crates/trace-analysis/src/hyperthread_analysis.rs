@@ -3,10 +3,70 @@ use arrow_array::{Array, ArrayRef, BooleanArray, Int32Array, Int64Array, RecordB
 use arrow_schema::{DataType, Field, Schema};
 use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
 use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
 use std::fs::File;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use crate::topology::CpuTopology;
+
+/// Compression codec for the output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputCompression {
+    #[default]
+    Snappy,
+    Zstd,
+    None,
+}
+
+impl OutputCompression {
+    fn writer_properties(self) -> WriterProperties {
+        let compression = match self {
+            OutputCompression::Snappy => Compression::SNAPPY,
+            OutputCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+            OutputCompression::None => Compression::UNCOMPRESSED,
+        };
+        WriterProperties::builder()
+            .set_compression(compression)
+            .build()
+    }
+}
+
+/// Names of the input columns the analysis reads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnNames {
+    pub timestamp: String,
+    pub cpu_id: String,
+    pub is_context_switch: String,
+    pub next_tgid: String,
+}
+
+impl Default for ColumnNames {
+    fn default() -> Self {
+        Self {
+            timestamp: "timestamp".to_string(),
+            cpu_id: "cpu_id".to_string(),
+            is_context_switch: "is_context_switch".to_string(),
+            next_tgid: "next_tgid".to_string(),
+        }
+    }
+}
+
+/// Look up column `name` of `batch` as an array of type `T`
+fn typed_column<'a, T: Array + 'static>(
+    batch: &'a RecordBatch,
+    name: &str,
+    type_name: &str,
+) -> Result<&'a T> {
+    batch
+        .column_by_name(name)
+        .ok_or_else(|| anyhow::anyhow!("{} column not found", name))?
+        .as_any()
+        .downcast_ref::<T>()
+        .ok_or_else(|| anyhow::anyhow!("{} column is not {}", name, type_name))
+}
+
 #[derive(Debug, Clone)]
 struct CpuState {
     current_pid: Option<i32>,
@@ -37,26 +97,39 @@ impl CpuState {
 pub struct HyperthreadAnalysis {
     num_cpus: usize,
     cpu_states: Vec<CpuState>,
+    // Hyperthread peer of each CPU, precomputed from the topology
+    hyperthread_peers: Vec<Option<usize>>,
     output_filename: PathBuf,
+    compression: OutputCompression,
+    columns: ColumnNames,
 }
 
 impl HyperthreadAnalysis {
     pub fn new(num_cpus: usize, output_filename: PathBuf) -> Result<Self> {
+        Self::with_topology(&CpuTopology::split_halves(num_cpus), output_filename)
+    }
+
+    pub fn with_topology(topology: &CpuTopology, output_filename: PathBuf) -> Result<Self> {
+        let num_cpus = topology.num_cpus();
         let cpu_states = vec![CpuState::new(); num_cpus];
 
         Ok(Self {
             num_cpus,
             cpu_states,
+            hyperthread_peers: topology.peer_table(),
             output_filename,
+            compression: OutputCompression::default(),
+            columns: ColumnNames::default(),
         })
     }
 
-    fn get_hyperthread_peer(&self, cpu_id: usize) -> usize {
-        if cpu_id < self.num_cpus / 2 {
-            cpu_id + self.num_cpus / 2
-        } else {
-            cpu_id - self.num_cpus / 2
-        }
+    pub fn set_compression(&mut self, compression: OutputCompression) {
+        self.compression = compression;
+    }
+
+    /// Read the input columns under `columns` instead of their default names
+    pub fn set_column_names(&mut self, columns: ColumnNames) {
+        self.columns = columns;
     }
 
     fn update_hyperthread(&mut self, cpu_a: usize, cpu_b: usize, event_timestamp: i64) {
@@ -121,6 +194,26 @@ impl HyperthreadAnalysis {
         builder: ParquetRecordBatchReaderBuilder<File>,
     ) -> Result<()> {
         let input_schema = builder.schema().clone();
+        // Fail before creating the output when the input lacks a column
+        for name in [
+            &self.columns.timestamp,
+            &self.columns.cpu_id,
+            &self.columns.is_context_switch,
+            &self.columns.next_tgid,
+        ] {
+            if input_schema.column_with_name(name).is_none() {
+                return Err(anyhow::anyhow!(
+                    "{} column not found in the input, columns are: {}",
+                    name,
+                    input_schema
+                        .fields()
+                        .iter()
+                        .map(|field| field.name().as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
         let mut arrow_reader = builder
             .build()
             .with_context(|| "Failed to build Arrow reader")?;
@@ -136,8 +229,10 @@ impl HyperthreadAnalysis {
             )
         })?;
 
-        let mut writer = ArrowWriter::try_new(output_file, Arc::new(output_schema.clone()), None)
-            .with_context(|| "Failed to create Arrow writer")?;
+        let props = self.compression.writer_properties();
+        let mut writer =
+            ArrowWriter::try_new(output_file, Arc::new(output_schema.clone()), Some(props))
+                .with_context(|| "Failed to create Arrow writer")?;
 
         // Process record batches
         while let Some(batch) = arrow_reader.next() {
@@ -184,33 +279,13 @@ impl HyperthreadAnalysis {
         let num_rows = batch.num_rows();
 
         // Extract required columns
-        let timestamp_col = batch
-            .column_by_name("timestamp")
-            .ok_or_else(|| anyhow::anyhow!("timestamp column not found"))?
-            .as_any()
-            .downcast_ref::<Int64Array>()
-            .ok_or_else(|| anyhow::anyhow!("timestamp column is not Int64Array"))?;
-
-        let cpu_id_col = batch
-            .column_by_name("cpu_id")
-            .ok_or_else(|| anyhow::anyhow!("cpu_id column not found"))?
-            .as_any()
-            .downcast_ref::<Int32Array>()
-            .ok_or_else(|| anyhow::anyhow!("cpu_id column is not Int32Array"))?;
-
-        let is_context_switch_col = batch
-            .column_by_name("is_context_switch")
-            .ok_or_else(|| anyhow::anyhow!("is_context_switch column not found"))?
-            .as_any()
-            .downcast_ref::<BooleanArray>()
-            .ok_or_else(|| anyhow::anyhow!("is_context_switch column is not BooleanArray"))?;
-
-        let next_tgid_col = batch
-            .column_by_name("next_tgid")
-            .ok_or_else(|| anyhow::anyhow!("next_tgid column not found"))?
-            .as_any()
-            .downcast_ref::<Int32Array>()
-            .ok_or_else(|| anyhow::anyhow!("next_tgid column is not Int32Array"))?;
+        let timestamp_col =
+            typed_column::<Int64Array>(batch, &self.columns.timestamp, "Int64Array")?;
+        let cpu_id_col = typed_column::<Int32Array>(batch, &self.columns.cpu_id, "Int32Array")?;
+        let is_context_switch_col =
+            typed_column::<BooleanArray>(batch, &self.columns.is_context_switch, "BooleanArray")?;
+        let next_tgid_col =
+            typed_column::<Int32Array>(batch, &self.columns.next_tgid, "Int32Array")?;
 
         // Prepare output arrays for hyperthread counters
         let mut ns_peer_same_process = Vec::with_capacity(num_rows);
@@ -219,6 +294,14 @@ impl HyperthreadAnalysis {
 
         // Process each row
         for i in 0..num_rows {
+            // Rows from rings that are not CPUs run on no peer
+            if cpu_id_col.is_null(i) {
+                ns_peer_same_process.push(0);
+                ns_peer_different_process.push(0);
+                ns_peer_kernel.push(0);
+                continue;
+            }
+
             let timestamp = timestamp_col.value(i);
             let cpu_id = cpu_id_col.value(i) as usize;
             let is_context_switch = is_context_switch_col.value(i);
@@ -227,10 +310,10 @@ impl HyperthreadAnalysis {
                 return Err(anyhow::anyhow!("Invalid CPU ID: {}", cpu_id));
             }
 
-            let peer_cpu = self.get_hyperthread_peer(cpu_id);
-
-            // Update hyperthread counters
-            self.update_hyperthread(cpu_id, peer_cpu, timestamp);
+            // Update hyperthread counters, unless the CPU has no peer
+            if let Some(peer_cpu) = self.hyperthread_peers[cpu_id] {
+                self.update_hyperthread(cpu_id, peer_cpu, timestamp);
+            }
 
             // Get current counter values
             let same_process = self.cpu_states[cpu_id].ns_peer_same_process;
@@ -246,7 +329,8 @@ impl HyperthreadAnalysis {
             if is_context_switch {
                 if next_tgid_col.is_null(i) {
                     return Err(anyhow::anyhow!(
-                        "next_tgid is null for context switch at row {}",
+                        "{} is null for context switch at row {}",
+                        self.columns.next_tgid,
                         i
                     ));
                 }
@@ -488,4 +572,235 @@ mod tests {
         let result = analysis.process_record_batch(&batch, &output_schema);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_null_cpu_id_has_no_peer() {
+        let mut analysis = HyperthreadAnalysis::new(4, PathBuf::from("/tmp/test.parquet")).unwrap();
+        let input_schema = Schema::new(vec![
+            Field::new("timestamp", DataType::Int64, false),
+            Field::new("cpu_id", DataType::Int32, true),
+            Field::new("is_context_switch", DataType::Boolean, false),
+            Field::new("next_tgid", DataType::Int32, true),
+        ]);
+        let output_schema = analysis.create_output_schema(&input_schema).unwrap();
+
+        // The second row is from a ring that is not a CPU
+        let batch = RecordBatch::try_new(
+            Arc::new(input_schema),
+            vec![
+                Arc::new(Int64Array::from(vec![1000, 2000, 3000])),
+                Arc::new(Int32Array::from(vec![Some(0), None, Some(1)])),
+                Arc::new(BooleanArray::from(vec![true, true, false])),
+                Arc::new(Int32Array::from(vec![Some(100), Some(200), None])),
+            ],
+        )
+        .unwrap();
+
+        let result = analysis
+            .process_record_batch(&batch, &output_schema)
+            .unwrap();
+        let same_process = result
+            .column_by_name("ns_peer_same_process")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(result.num_rows(), 3);
+        assert_eq!(same_process.value(1), 0);
+    }
+
+    #[test]
+    fn test_custom_topology() {
+        // CPUs 0 and 1 share a core; CPU 2 has no sibling
+        let topology = CpuTopology::from_siblings(vec![vec![0, 1], vec![0, 1], vec![2]]);
+        let mut analysis =
+            HyperthreadAnalysis::with_topology(&topology, PathBuf::from("/tmp/test.parquet"))
+                .unwrap();
+        let input_schema = create_test_schema();
+        let output_schema = analysis.create_output_schema(&input_schema).unwrap();
+
+        let batch = create_test_batch(
+            vec![1000, 2000, 3000, 4000, 5000],
+            vec![0, 1, 0, 2, 2],
+            vec![true, true, true, true, true],
+            vec![Some(100), Some(100), Some(100), Some(300), Some(300)],
+        );
+
+        let result = analysis
+            .process_record_batch(&batch, &output_schema)
+            .unwrap();
+
+        let same_process_col = result
+            .column_by_name("ns_peer_same_process")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+
+        // Event 2 (t=3000, CPU 0): CPU 1 runs the same process since t=2000
+        assert_eq!(same_process_col.value(2), 1000);
+
+        // CPU 2 has no peer, so its counters stay zero
+        assert_eq!(same_process_col.value(3), 0);
+        assert_eq!(same_process_col.value(4), 0);
+    }
+
+    #[test]
+    fn test_renamed_columns() {
+        let mut analysis = HyperthreadAnalysis::new(4, PathBuf::from("/tmp/test.parquet")).unwrap();
+        analysis.set_column_names(ColumnNames {
+            timestamp: "ts".to_string(),
+            cpu_id: "cpu".to_string(),
+            is_context_switch: "switch".to_string(),
+            next_tgid: "next_pid".to_string(),
+        });
+
+        // The columns of the usual test batch, under other names
+        let batch = create_test_batch(
+            vec![1000, 2000, 3000],
+            vec![0, 2, 0],
+            vec![true, true, true],
+            vec![Some(100), Some(100), Some(100)],
+        );
+        let renamed_schema = Arc::new(Schema::new(vec![
+            Arc::new(Field::new("ts", DataType::Int64, false)),
+            Arc::new(Field::new("cpu", DataType::Int32, false)),
+            Arc::new(Field::new("switch", DataType::Boolean, false)),
+            Arc::new(Field::new("next_pid", DataType::Int32, true)),
+        ]));
+        let renamed =
+            RecordBatch::try_new(renamed_schema.clone(), batch.columns().to_vec()).unwrap();
+        let output_schema = analysis.create_output_schema(&renamed_schema).unwrap();
+
+        let result = analysis
+            .process_record_batch(&renamed, &output_schema)
+            .unwrap();
+        let same_process_col = result
+            .column_by_name("ns_peer_same_process")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(same_process_col.value(2), 1000);
+
+        // The default names are no longer looked up, and missing names are reported
+        let error = analysis
+            .process_record_batch(&batch, &output_schema)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "ts column not found");
+
+        let dir =
+            std::env::temp_dir().join(format!("trace_analysis_columns_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("trace.parquet");
+        let mut writer =
+            ArrowWriter::try_new(File::create(&input).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        let output = dir.join("output.parquet");
+        analysis.output_filename = output.clone();
+        let builder =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&input).unwrap()).unwrap();
+        let error = analysis.process_parquet_file(builder).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "ts column not found in the input, columns are: timestamp, cpu_id, is_context_switch, next_tgid"
+        );
+        assert!(!output.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore] // This test processes a million rows, run with cargo test -- --ignored
+    fn test_many_rows_throughput() {
+        const NUM_CPUS: usize = 16;
+        const NUM_ROWS: usize = 1_000_000;
+        const BATCH_SIZE: usize = 8192;
+
+        let mut analysis =
+            HyperthreadAnalysis::new(NUM_CPUS, PathBuf::from("/tmp/test.parquet")).unwrap();
+        let input_schema = create_test_schema();
+        let output_schema = analysis.create_output_schema(&input_schema).unwrap();
+
+        let mut total_ns = 0i64;
+        for batch_start in (0..NUM_ROWS).step_by(BATCH_SIZE) {
+            let rows = batch_start..(batch_start + BATCH_SIZE).min(NUM_ROWS);
+            let batch = create_test_batch(
+                rows.clone().map(|row| 1000 + row as i64 * 100).collect(),
+                rows.clone()
+                    .map(|row| (row * 7 % NUM_CPUS) as i32)
+                    .collect(),
+                rows.clone().map(|row| row % 3 == 0).collect(),
+                rows.map(|row| Some((row % 5) as i32)).collect(),
+            );
+            let result = analysis
+                .process_record_batch(&batch, &output_schema)
+                .unwrap();
+            assert_eq!(result.num_rows(), batch.num_rows());
+
+            for name in [
+                "ns_peer_same_process",
+                "ns_peer_different_process",
+                "ns_peer_kernel",
+            ] {
+                let col = result
+                    .column_by_name(name)
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap();
+                total_ns += col.values().iter().sum::<i64>();
+            }
+        }
+
+        // Every CPU has a peer, so time is attributed once the peers are running
+        assert!(total_ns > 0);
+    }
+
+    #[test]
+    fn test_output_compression() {
+        let dir =
+            std::env::temp_dir().join(format!("trace_analysis_compression_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // A trace of repetitive rows, which compresses well
+        let input = dir.join("trace.parquet");
+        let num_rows = 100_000;
+        let batch = create_test_batch(
+            (0..num_rows).map(|row| 1000 + row as i64 * 100).collect(),
+            (0..num_rows).map(|row| (row % 4) as i32).collect(),
+            (0..num_rows).map(|row| row % 3 == 0).collect(),
+            (0..num_rows).map(|row| Some((row % 5) as i32)).collect(),
+        );
+        let mut writer =
+            ArrowWriter::try_new(File::create(&input).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let analyze = |compression: OutputCompression| {
+            let output = dir.join(format!("{:?}.parquet", compression));
+            let mut analysis = HyperthreadAnalysis::new(4, output.clone()).unwrap();
+            analysis.set_compression(compression);
+            let builder =
+                ParquetRecordBatchReaderBuilder::try_new(File::open(&input).unwrap()).unwrap();
+            analysis.process_parquet_file(builder).unwrap();
+            output
+        };
+        let uncompressed = analyze(OutputCompression::None);
+        let zstd = analyze(OutputCompression::Zstd);
+
+        let rows: usize = ParquetRecordBatchReaderBuilder::try_new(File::open(&zstd).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap().num_rows())
+            .sum();
+        assert_eq!(rows, num_rows);
+
+        let size = |path: &PathBuf| std::fs::metadata(path).unwrap().len();
+        assert!(size(&zstd) < size(&uncompressed));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -5,7 +5,9 @@ use std::fs::File;
 use std::path::{Path, PathBuf};
 
 mod hyperthread_analysis;
-use hyperthread_analysis::HyperthreadAnalysis;
+mod topology;
+use hyperthread_analysis::{ColumnNames, HyperthreadAnalysis, OutputCompression};
+use topology::CpuTopology;
 
 #[derive(Parser)]
 #[command(name = "trace-analysis")]
@@ -19,6 +21,48 @@ struct Cli {
         help = "Output file prefix (defaults to base name of input file)"
     )]
     output_prefix: Option<String>,
+
+    #[arg(
+        long,
+        help = "sysfs CPU directory of the traced machine (e.g. /sys/devices/system/cpu) to read hyperthread siblings from; defaults to pairing CPU i with i + num_cpus/2"
+    )]
+    topology: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "snappy",
+        help = "Compression codec for the output file"
+    )]
+    compression: OutputCompression,
+
+    #[arg(
+        long,
+        default_value = "timestamp",
+        help = "Name of the input column holding event timestamps in nanoseconds"
+    )]
+    timestamp_column: String,
+
+    #[arg(
+        long,
+        default_value = "cpu_id",
+        help = "Name of the input column holding the CPU of each event"
+    )]
+    cpu_id_column: String,
+
+    #[arg(
+        long,
+        default_value = "is_context_switch",
+        help = "Name of the input column marking context switches"
+    )]
+    is_context_switch_column: String,
+
+    #[arg(
+        long,
+        default_value = "next_tgid",
+        help = "Name of the input column holding the process switched to"
+    )]
+    next_tgid_column: String,
 }
 
 fn main() -> Result<()> {
@@ -58,8 +102,22 @@ fn main() -> Result<()> {
         output_filename.display()
     );
 
-    // Create hyperthread analysis module
-    let mut analysis = HyperthreadAnalysis::new(num_cpus, output_filename)?;
+    // Create hyperthread analysis module, reading CPU siblings if given
+    let mut analysis = match &cli.topology {
+        Some(cpu_dir) => {
+            let topology = CpuTopology::from_sysfs(cpu_dir, num_cpus)?;
+            HyperthreadAnalysis::with_topology(&topology, output_filename)?
+        }
+        None => HyperthreadAnalysis::new(num_cpus, output_filename)?,
+    };
+
+    analysis.set_compression(cli.compression);
+    analysis.set_column_names(ColumnNames {
+        timestamp: cli.timestamp_column,
+        cpu_id: cli.cpu_id_column,
+        is_context_switch: cli.is_context_switch_column,
+        next_tgid: cli.next_tgid_column,
+    });
 
     // Process the Parquet file
     analysis.process_parquet_file(builder)?;
@@ -0,0 +1,167 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Hyperthread sibling table for the traced machine.
+///
+/// `siblings[cpu]` lists every logical CPU sharing a physical core with `cpu`,
+/// including `cpu` itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuTopology {
+    siblings: Vec<Vec<usize>>,
+}
+
+impl CpuTopology {
+    /// Topology where CPU `i` shares a core with CPU `i + num_cpus / 2`.
+    ///
+    /// This matches the numbering Linux uses on most x86 machines with SMT
+    /// enabled. With an odd CPU count the last CPU has no sibling.
+    pub fn split_halves(num_cpus: usize) -> Self {
+        let half = num_cpus / 2;
+        let siblings = (0..num_cpus)
+            .map(|cpu| {
+                if cpu < half {
+                    vec![cpu, cpu + half]
+                } else if cpu < 2 * half {
+                    vec![cpu - half, cpu]
+                } else {
+                    vec![cpu]
+                }
+            })
+            .collect();
+        Self::from_siblings(siblings)
+    }
+
+    /// Topology from an explicit sibling list per CPU
+    pub fn from_siblings(siblings: Vec<Vec<usize>>) -> Self {
+        Self { siblings }
+    }
+
+    /// Read the topology from a sysfs CPU directory, usually
+    /// `/sys/devices/system/cpu`, using each CPU's `thread_siblings_list`.
+    pub fn from_sysfs(cpu_dir: &Path, num_cpus: usize) -> Result<Self> {
+        let siblings = (0..num_cpus)
+            .map(|cpu| {
+                let path = cpu_dir.join(format!("cpu{}/topology/thread_siblings_list", cpu));
+                let list = fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                parse_cpu_list(&list).with_context(|| format!("Failed to parse {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::from_siblings(siblings))
+    }
+
+    /// Number of CPUs in the table
+    pub fn num_cpus(&self) -> usize {
+        self.siblings.len()
+    }
+
+    /// CPUs sharing a core with `cpu`, including `cpu` itself
+    pub fn siblings(&self, cpu: usize) -> &[usize] {
+        &self.siblings[cpu]
+    }
+
+    /// Precompute each CPU's hyperthread peer: the lowest-numbered other
+    /// sibling within range, or None for CPUs without one.
+    pub fn peer_table(&self) -> Vec<Option<usize>> {
+        (0..self.num_cpus())
+            .map(|cpu| {
+                self.siblings(cpu)
+                    .iter()
+                    .copied()
+                    .filter(|&sibling| sibling != cpu && sibling < self.num_cpus())
+                    .min()
+            })
+            .collect()
+    }
+}
+
+/// Parse a kernel CPU list such as "0-3,8,10-11"
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',').filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start
+                    .parse()
+                    .with_context(|| format!("Bad CPU {}", start))?;
+                let end: usize = end.parse().with_context(|| format!("Bad CPU {}", end))?;
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(part.parse().with_context(|| format!("Bad CPU {}", part))?),
+        }
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write_sysfs(name: &str, lists: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "trace-analysis-topology-{}-{}",
+            name,
+            std::process::id()
+        ));
+        for (cpu, list) in lists.iter().enumerate() {
+            let topology_dir = dir.join(format!("cpu{}/topology", cpu));
+            fs::create_dir_all(&topology_dir).unwrap();
+            fs::write(
+                topology_dir.join("thread_siblings_list"),
+                format!("{}\n", list),
+            )
+            .unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert_eq!(parse_cpu_list("0,4\n").unwrap(), vec![0, 4]);
+        assert_eq!(parse_cpu_list("0-1,8-9").unwrap(), vec![0, 1, 8, 9]);
+        assert!(parse_cpu_list("0-x").is_err());
+    }
+
+    #[test]
+    fn test_split_halves() {
+        let topology = CpuTopology::split_halves(5);
+        assert_eq!(
+            topology.peer_table(),
+            vec![Some(2), Some(3), Some(0), Some(1), None]
+        );
+    }
+
+    #[test]
+    fn test_peer_table_matches_sysfs() {
+        // Adjacent siblings, as on many AMD machines, plus one CPU without SMT
+        let lists = ["0-1", "0-1", "2-3", "2-3", "4"];
+        let dir = write_sysfs("adjacent", &lists);
+        let topology = CpuTopology::from_sysfs(&dir, lists.len()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let peers = topology.peer_table();
+        assert_eq!(peers, vec![Some(1), Some(0), Some(3), Some(2), None]);
+
+        // Every cached peer is a sibling in the source table, and peers are mutual
+        for (cpu, peer) in peers.iter().enumerate() {
+            match peer {
+                Some(peer) => {
+                    assert!(topology.siblings(cpu).contains(peer));
+                    assert_eq!(peers[*peer], Some(cpu));
+                }
+                None => assert_eq!(topology.siblings(cpu), &[cpu]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_sysfs_errors() {
+        let dir = write_sysfs("missing", &["0-1", "0-1"]);
+        let result = CpuTopology::from_sysfs(&dir, 4);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+}